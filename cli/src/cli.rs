@@ -9,6 +9,7 @@ use server::storage::AnalyticsService as StorageAnalyticsService;
 
 use crate::analytics::AnalyticsCommands;
 use crate::config::ConfigCommands;
+use crate::media::MediaCommands;
 use crate::users::UserCommands;
 use crate::wordlist::WordlistCommands;
 
@@ -48,6 +49,9 @@ pub enum Commands {
     /// Wordlist management for invite codes
     #[command(subcommand)]
     Wordlist(WordlistCommands),
+    /// Media storage management
+    #[command(subcommand)]
+    Media(MediaCommands),
 }
 
 impl Cli {
@@ -77,6 +81,10 @@ impl Cli {
                 analytics_command.handle(&analytics, &db).await
             }
             Commands::Wordlist(ref wordlist_command) => wordlist_command.handle().await,
+            Commands::Media(ref media_command) => {
+                let (config, db) = self.setup_database().await?;
+                media_command.handle(&config, &db).await
+            }
         }
     }
 
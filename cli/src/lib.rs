@@ -1,6 +1,7 @@
 pub mod analytics;
 pub mod cli;
 pub mod config;
+pub mod media;
 pub mod users;
 pub mod wordlist;
 
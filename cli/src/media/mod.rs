@@ -0,0 +1,747 @@
+//! Media module
+//!
+//! This module handles media-related CLI commands including:
+//! - Moving stored content between storage backends
+//! - Garbage collection of unreferenced content
+//! - Re-running background processing, such as audio/video metadata extraction
+//! - Reviewing media quarantined by malware scanning
+//! - Viewing storage usage and setting per-user quotas
+//! - Reporting media statistics
+//! - Checking stored files against the database, and repairing them
+//! - Purging expired trash and media past its retention, and reviewing the
+//!   audit log of removals
+//! - Exporting media to tar archives and importing it elsewhere
+
+use clap::Subcommand;
+use server::auth::AuthRepository;
+use server::config::{AppConfig, MediaConfig, MediaStorageBackend};
+use server::database::DatabaseConnection;
+use server::media::{
+    export_media, import_media, normalize_tags, run_fsck, run_pending_jobs, run_retention,
+    runnable_job_kinds, FsckOptions, ImportOptions, MediaBlobQuery, MediaJobKind,
+    MediaJobRepository, MediaRepository, MediaService, MediaStatsQuery, MediaStorage, MediaUsage,
+    ScanStatus,
+};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+#[derive(Subcommand, Clone)]
+pub enum MediaCommands {
+    /// Move stored media content to another storage backend
+    MigrateStorage {
+        /// Backend to move content to (database, filesystem or s3)
+        #[arg(long)]
+        to: MediaStorageBackend,
+        /// Number of objects to load per batch
+        #[arg(long, default_value = "100")]
+        batch_size: i64,
+        /// Actually move the content (dry run by default)
+        #[arg(long)]
+        execute: bool,
+    },
+    /// Remove stored content that no media blob references
+    Gc,
+    /// Queue processing jobs again, e.g. after a parser improvement
+    Reprocess {
+        /// Only reprocess this media blob
+        #[arg(long)]
+        blob: Option<Uuid>,
+        /// Job kind to queue (image_derivatives, av_metadata or malware_scan;
+        /// all by default, with malware_scan only while scanning is enabled)
+        #[arg(long)]
+        kind: Option<MediaJobKind>,
+        /// Only queue blobs whose previous job failed
+        #[arg(long)]
+        failed: bool,
+        /// Run the queued jobs now instead of leaving them to the server
+        #[arg(long)]
+        run: bool,
+    },
+    /// List media blobs withheld by malware scanning
+    Quarantine {
+        /// Scan status to list (infected or pending)
+        #[arg(long, default_value = "infected")]
+        status: ScanStatus,
+        /// Maximum number of blobs to list
+        #[arg(long, default_value = "100")]
+        limit: i64,
+    },
+    /// Show storage usage and quotas
+    Usage {
+        /// Only show this user (all users by default)
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Set a user's storage quota, overriding their role's
+    SetQuota {
+        /// Username to set the quota for
+        username: String,
+        /// Bytes the user may store; omit to use the role quota again
+        bytes: Option<u64>,
+    },
+    /// Show media statistics and storage reporting
+    Stats {
+        /// Days of upload growth to show (0 to skip)
+        #[arg(long, default_value = "30")]
+        days: i64,
+        /// Number of users and clients to list (0 to skip)
+        #[arg(long, default_value = "10")]
+        top: i64,
+    },
+    /// Check stored files against the database: orphaned files, missing or
+    /// corrupt content and stale local paths
+    Fsck {
+        /// Read all content back and verify its SHA256
+        #[arg(long)]
+        verify: bool,
+        /// Fix the problems found (report only by default)
+        #[arg(long)]
+        repair: bool,
+        /// Ignore files younger than this, which may belong to uploads in
+        /// progress (from the configuration by default)
+        #[arg(long)]
+        min_age_seconds: Option<u64>,
+    },
+    /// Purge blobs past the trash window or their retention now
+    Purge,
+    /// Show the audit log of media moved to the trash, restored or purged
+    Audit {
+        /// Only show entries for this media blob
+        #[arg(long)]
+        blob: Option<Uuid>,
+        /// Maximum number of entries to show
+        #[arg(long, default_value = "100")]
+        limit: i64,
+    },
+    /// Write media blobs and their content to a tar archive with a JSON
+    /// manifest
+    Export {
+        /// Archive file to write
+        output: PathBuf,
+        /// Only export blobs owned by this user
+        #[arg(long)]
+        user: Option<String>,
+        /// Only export blobs in this collection
+        #[arg(long)]
+        collection: Option<Uuid>,
+        /// Only export blobs carrying this tag (repeatable)
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        /// Only export blobs whose MIME type contains this text
+        #[arg(long)]
+        mime: Option<String>,
+        /// Only export blobs uploaded by this client
+        #[arg(long)]
+        client: Option<String>,
+    },
+    /// Create media blobs from an archive written by `media export`
+    Import {
+        /// Archive file to read
+        input: PathBuf,
+        /// Give every blob to this user instead of matching owners by username
+        #[arg(long)]
+        owner: Option<String>,
+    },
+}
+
+impl MediaCommands {
+    pub async fn handle(
+        &self,
+        app_config: &AppConfig,
+        db: &DatabaseConnection,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let config = &app_config.media;
+        let storage = MediaStorage::from_config(&config.storage, db)?;
+        let service = MediaService::new(MediaRepository::new(db), &storage);
+
+        match self {
+            MediaCommands::MigrateStorage {
+                to,
+                batch_size,
+                execute,
+            } => Self::migrate_storage(&service, *to, *batch_size, *execute).await,
+            MediaCommands::Gc => Self::collect_garbage(&service).await,
+            MediaCommands::Reprocess {
+                blob,
+                kind,
+                failed,
+                run,
+            } => Self::reprocess(db, &storage, config, *blob, *kind, *failed, *run).await,
+            MediaCommands::Quarantine { status, limit } => {
+                Self::list_quarantine(db, *status, *limit).await
+            }
+            MediaCommands::Usage { user } => {
+                Self::show_usage(db, &service, config, user.as_deref()).await
+            }
+            MediaCommands::SetQuota { username, bytes } => {
+                Self::set_quota(db, &service, config, username, *bytes).await
+            }
+            MediaCommands::Stats { days, top } => {
+                Self::show_stats(
+                    &service,
+                    MediaStatsQuery {
+                        days: (*days).max(0),
+                        top: (*top).max(0),
+                    },
+                )
+                .await
+            }
+            MediaCommands::Fsck {
+                verify,
+                repair,
+                min_age_seconds,
+            } => {
+                let mut options = FsckOptions::from_config(app_config);
+                options.verify_hashes = *verify;
+                options.repair = *repair;
+                if let Some(seconds) = min_age_seconds {
+                    options.min_orphan_age = std::time::Duration::from_secs(*seconds);
+                }
+                Self::fsck(db, &storage, &options).await
+            }
+            MediaCommands::Purge => Self::purge(db, &storage, config).await,
+            MediaCommands::Audit { blob, limit } => Self::show_audit(&service, *blob, *limit).await,
+            MediaCommands::Export {
+                output,
+                user,
+                collection,
+                tags,
+                mime,
+                client,
+            } => {
+                let owner_user_id = match user {
+                    Some(username) => Some(Self::find_user_id(db, username).await?),
+                    None => None,
+                };
+                let query = MediaBlobQuery {
+                    owner_user_id,
+                    collection_id: *collection,
+                    tags: normalize_tags(tags)?,
+                    mime_pattern: mime.clone(),
+                    source_client_id: client.clone(),
+                    ..Default::default()
+                };
+                Self::export(db, &storage, query, output).await
+            }
+            MediaCommands::Import { input, owner } => {
+                let owner_user_id = match owner {
+                    Some(username) => Some(Self::find_user_id(db, username).await?),
+                    None => None,
+                };
+                Self::import(db, &storage, config, input, ImportOptions { owner_user_id }).await
+            }
+        }
+    }
+
+    async fn migrate_storage(
+        service: &MediaService<'_>,
+        to: MediaStorageBackend,
+        batch_size: i64,
+        execute: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        println!("📦 Media Storage Migration");
+        println!("  Target backend: {}", to);
+
+        if !execute {
+            println!("  DRY RUN - Use --execute to actually move content");
+        }
+        println!();
+
+        let mut after: Option<String> = None;
+        let mut moved = 0u64;
+        let mut failed = 0u64;
+        let mut bytes = 0i64;
+
+        loop {
+            let objects = service
+                .list_objects_not_in(to, after.as_deref(), batch_size.max(1))
+                .await?;
+            let Some(last) = objects.last() else {
+                break;
+            };
+            after = Some(last.sha256.clone());
+
+            for object in objects {
+                if !execute {
+                    println!(
+                        "  Would move {} ({} bytes) from {}",
+                        object.sha256, object.size, object.storage_backend
+                    );
+                    moved += 1;
+                    bytes += object.size;
+                    continue;
+                }
+
+                match service.move_object(&object.sha256, to).await {
+                    Ok(true) => {
+                        moved += 1;
+                        bytes += object.size;
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        eprintln!("  ❌ Failed to move {}: {}", object.sha256, e);
+                        failed += 1;
+                    }
+                }
+            }
+        }
+
+        println!();
+        if execute {
+            println!("✅ Moved {} objects ({} bytes) to {}", moved, bytes, to);
+        } else {
+            println!(
+                "{} objects ({} bytes) would be moved to {}",
+                moved, bytes, to
+            );
+        }
+
+        if failed > 0 {
+            return Err(format!("{} objects could not be moved", failed).into());
+        }
+
+        Ok(())
+    }
+
+    async fn collect_garbage(service: &MediaService<'_>) -> Result<(), Box<dyn std::error::Error>> {
+        println!("🧹 Media Garbage Collection");
+
+        let report = service.collect_garbage().await?;
+        println!(
+            "✅ Removed {} unreferenced objects ({} bytes)",
+            report.objects_removed, report.bytes_freed
+        );
+
+        Ok(())
+    }
+
+    async fn reprocess(
+        db: &DatabaseConnection,
+        storage: &MediaStorage,
+        config: &MediaConfig,
+        blob: Option<Uuid>,
+        kind: Option<MediaJobKind>,
+        failed: bool,
+        run: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        println!("🔁 Media Reprocessing");
+
+        let jobs = MediaJobRepository::new(db);
+        let kinds = match kind {
+            Some(kind) => vec![kind],
+            None => runnable_job_kinds(config),
+        };
+
+        let mut queued = 0;
+        for kind in kinds {
+            let count = jobs.requeue(kind, blob, failed).await?;
+            println!("  Queued {} {} jobs", count, kind);
+            queued += count;
+        }
+
+        if !run {
+            println!();
+            println!("✅ Queued {} jobs; the server will run them", queued);
+            return Ok(());
+        }
+
+        let mut claimed = 0;
+        loop {
+            let batch = run_pending_jobs(db, storage, config).await?;
+            if batch == 0 {
+                break;
+            }
+            claimed += batch;
+        }
+
+        println!();
+        println!("✅ Ran {} jobs", claimed);
+        Ok(())
+    }
+
+    async fn list_quarantine(
+        db: &DatabaseConnection,
+        status: ScanStatus,
+        limit: i64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        println!("🦠 Quarantined Media ({})", status);
+
+        let blobs = MediaRepository::new(db)
+            .query(MediaBlobQuery {
+                scan_status: Some(status),
+                limit: Some(limit),
+                ..Default::default()
+            })
+            .await?;
+
+        for blob in &blobs {
+            let signature = blob.metadata["scan"]["signature"].as_str().unwrap_or("-");
+            println!(
+                "  {}  {}  owner {}  {}  {}",
+                blob.id,
+                blob.sha256,
+                blob.owner_user_id
+                    .map(|id| id.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                blob.mime.as_deref().unwrap_or("-"),
+                signature
+            );
+        }
+
+        println!();
+        println!("{} blobs", blobs.len());
+        Ok(())
+    }
+
+    async fn show_usage(
+        db: &DatabaseConnection,
+        service: &MediaService<'_>,
+        config: &MediaConfig,
+        username: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        println!("💾 Media Storage Usage");
+
+        let usage = match username {
+            Some(username) => {
+                let user_id = Self::find_user_id(db, username).await?;
+                vec![service.usage(user_id, &config.quotas).await?]
+            }
+            None => service.list_usage(&config.quotas).await?,
+        };
+
+        println!(
+            "  {:<20} {:<8} {:>14} {:>8} {:>14} {:>6}",
+            "Username", "Role", "Bytes", "Blobs", "Quota", "Used"
+        );
+        for usage in &usage {
+            Self::print_usage(usage);
+        }
+
+        println!();
+        println!(
+            "{} users, {} bytes",
+            usage.len(),
+            usage.iter().map(|usage| usage.bytes).sum::<i64>()
+        );
+        Ok(())
+    }
+
+    async fn set_quota(
+        db: &DatabaseConnection,
+        service: &MediaService<'_>,
+        config: &MediaConfig,
+        username: &str,
+        bytes: Option<u64>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let user_id = Self::find_user_id(db, username).await?;
+        let bytes = bytes.map(i64::try_from).transpose()?;
+        let usage = service.set_quota(user_id, bytes, &config.quotas).await?;
+
+        match bytes {
+            Some(bytes) => println!("✓ Set storage quota of '{}' to {} bytes", username, bytes),
+            None => println!("✓ Storage quota of '{}' follows their role again", username),
+        }
+        Self::print_usage(&usage);
+        Ok(())
+    }
+
+    async fn show_stats(
+        service: &MediaService<'_>,
+        query: MediaStatsQuery,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        println!("📊 Media Statistics");
+
+        let stats = service.get_stats(query).await?;
+        println!(
+            "  Blobs: {} ({} bytes, {} distinct contents)",
+            stats.total_count,
+            stats.total_size.unwrap_or(0),
+            stats.unique_sha256_count
+        );
+        println!(
+            "  Derived blobs: {} ({} bytes)",
+            stats.derived_count, stats.derived_size
+        );
+        println!(
+            "  Saved by sharing content: {} bytes",
+            stats.duplicate_savings
+        );
+        println!(
+            "  Awaiting garbage collection: {} bytes",
+            stats.unreferenced_size
+        );
+
+        println!();
+        println!("🗄️  Storage");
+        for backend in &stats.storage {
+            println!(
+                "  {:<12} {:>10} objects {:>16} bytes",
+                backend.backend, backend.object_count, backend.size
+            );
+        }
+
+        println!();
+        println!("📁 MIME Types");
+        for mime in &stats.mime_type_distribution {
+            println!(
+                "  {:<40} {:>10} blobs {:>16} bytes",
+                mime.mime_type.as_deref().unwrap_or("(unknown)"),
+                mime.count,
+                mime.size
+            );
+        }
+
+        if query.top > 0 {
+            println!();
+            println!("👤 Top Users");
+            for user in &stats.top_users {
+                println!(
+                    "  {:<40} {:>10} blobs {:>16} bytes",
+                    user.username, user.blob_count, user.size
+                );
+            }
+
+            println!();
+            println!("💻 Top Clients");
+            for client in &stats.top_clients {
+                println!(
+                    "  {:<40} {:>10} blobs {:>16} bytes",
+                    client.source_client_id.as_deref().unwrap_or("(none)"),
+                    client.blob_count,
+                    client.size
+                );
+            }
+        }
+
+        if query.days > 0 {
+            println!();
+            println!("📈 Uploads per Day (last {} days)", query.days);
+            for day in &stats.growth {
+                println!(
+                    "  {}  {:>10} blobs {:>16} bytes",
+                    day.day.date(),
+                    day.blob_count,
+                    day.size
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn export(
+        db: &DatabaseConnection,
+        storage: &MediaStorage,
+        query: MediaBlobQuery,
+        output: &Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        println!("📤 Media Export");
+        println!("  Writing {}", output.display());
+        println!();
+
+        let file = tokio::fs::File::create(output).await?;
+        let report = match export_media(db, storage, query, tokio::io::BufWriter::new(file)).await {
+            Ok(report) => report,
+            Err(e) => {
+                // Leave no partial archive behind
+                let _ = tokio::fs::remove_file(output).await;
+                return Err(e.into());
+            }
+        };
+
+        println!(
+            "✅ Exported {} blobs with {} distinct contents ({} bytes)",
+            report.blobs, report.contents, report.bytes
+        );
+        Ok(())
+    }
+
+    async fn import(
+        db: &DatabaseConnection,
+        storage: &MediaStorage,
+        config: &MediaConfig,
+        input: &Path,
+        options: ImportOptions,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        println!("📥 Media Import");
+        println!("  Reading {}", input.display());
+        println!();
+
+        let file = tokio::fs::File::open(input).await?;
+        let report = import_media(
+            db,
+            storage,
+            config,
+            tokio::io::BufReader::new(file),
+            &options,
+        )
+        .await?;
+
+        for failure in &report.failed {
+            println!(
+                "  ❌ {} ({}): {}",
+                failure.id, failure.sha256, failure.reason
+            );
+        }
+        if !report.failed.is_empty() {
+            println!();
+        }
+
+        println!(
+            "  Imported {} blobs, stored {} new contents, skipped {} already present",
+            report.imported, report.contents_stored, report.skipped
+        );
+        if !report.failed.is_empty() {
+            return Err(format!("{} blobs could not be imported", report.failed.len()).into());
+        }
+
+        println!("✅ Import complete");
+        Ok(())
+    }
+
+    async fn find_user_id(
+        db: &DatabaseConnection,
+        username: &str,
+    ) -> Result<Uuid, Box<dyn std::error::Error>> {
+        match AuthRepository::new(db)
+            .get_user_by_username(username)
+            .await?
+        {
+            Some(user) => Ok(user.id),
+            None => Err(format!("User '{}' not found", username).into()),
+        }
+    }
+
+    fn print_usage(usage: &MediaUsage) {
+        let quota = match (usage.quota_bytes, usage.quota_override) {
+            (Some(quota), Some(_)) => format!("{}*", quota),
+            (Some(quota), None) => quota.to_string(),
+            (None, _) => "unlimited".to_string(),
+        };
+        let used = usage
+            .quota_bytes
+            .filter(|quota| *quota > 0)
+            .map(|quota| format!("{}%", usage.bytes * 100 / quota))
+            .unwrap_or_else(|| "-".to_string());
+
+        println!(
+            "  {:<20} {:<8} {:>14} {:>8} {:>14} {:>6}",
+            usage.username,
+            format!("{:?}", usage.role),
+            usage.bytes,
+            usage.blob_count,
+            quota,
+            used
+        );
+    }
+
+    async fn fsck(
+        db: &DatabaseConnection,
+        storage: &MediaStorage,
+        options: &FsckOptions,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        println!("🩺 Media Consistency Check");
+        if options.verify_hashes {
+            println!("  Verifying content hashes");
+        }
+        if !options.repair {
+            println!("  REPORT ONLY - Use --repair to fix problems");
+        }
+        println!();
+
+        let report = run_fsck(db, storage, options).await?;
+        for issue in &report.issues {
+            println!(
+                "  {} {}: {} ({})",
+                if issue.repaired { "🔧" } else { "❌" },
+                issue.kind,
+                issue
+                    .path
+                    .as_deref()
+                    .or(issue.sha256.as_deref())
+                    .unwrap_or("-"),
+                issue.detail
+            );
+        }
+
+        println!();
+        println!(
+            "  Checked {} objects, {} blobs with local paths and {} files",
+            report.objects_checked, report.blobs_checked, report.files_checked
+        );
+        if report.objects_skipped > 0 {
+            println!(
+                "  ⚠️  Skipped {} objects in unconfigured storage backends",
+                report.objects_skipped
+            );
+        }
+
+        let unrepaired = report.unrepaired();
+        if report.issues.is_empty() {
+            println!("✅ No issues found");
+        } else if unrepaired == 0 {
+            println!("✅ Repaired {} issues", report.issues.len());
+        } else {
+            return Err(format!(
+                "{} of {} issues left unrepaired",
+                unrepaired,
+                report.issues.len()
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    async fn purge(
+        db: &DatabaseConnection,
+        storage: &MediaStorage,
+        config: &MediaConfig,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        println!("🗑️  Media Retention");
+        println!(
+            "  Trash window: {} days, {} MIME type policies",
+            config.retention.trash_days,
+            config.retention.mime_types.len()
+        );
+        println!();
+
+        let report = run_retention(db, storage, config, None).await?;
+        println!(
+            "✅ Purged {} blobs from the trash and {} past their retention",
+            report.trash_purged, report.expired_purged
+        );
+
+        Ok(())
+    }
+
+    async fn show_audit(
+        service: &MediaService<'_>,
+        blob: Option<Uuid>,
+        limit: i64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        println!("📜 Media Audit Log");
+
+        let entries = service.list_audit(blob, limit).await?;
+        for entry in &entries {
+            println!(
+                "  {}  {:<8} {:<13} {}  {}  {} bytes  {}  by {}",
+                entry.created_at,
+                entry.action.as_str(),
+                entry.reason.as_str(),
+                entry.blob_id,
+                entry.sha256,
+                entry.size,
+                entry.mime.as_deref().unwrap_or("-"),
+                entry
+                    .actor_user_id
+                    .map(|id| id.to_string())
+                    .unwrap_or_else(|| "system".to_string())
+            );
+        }
+
+        println!();
+        println!("{} entries", entries.len());
+        Ok(())
+    }
+}
@@ -129,6 +129,7 @@ impl UserCommands {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn generate_invite(
         db: &DatabaseConnection,
         count: Option<u32>,
@@ -230,7 +231,7 @@ impl UserCommands {
             }
 
             // Validate word count
-            if word_count < 2 || word_count > 6 {
+            if !(2..=6).contains(&word_count) {
                 return Err("Word count must be between 2 and 6".into());
             }
 
@@ -278,7 +279,7 @@ impl UserCommands {
         let filtered_codes: Vec<_> = if active_only {
             invite_codes
                 .into_iter()
-                .filter(|code| !code.used_at.is_some())
+                .filter(|code| code.used_at.is_none())
                 .collect()
         } else {
             invite_codes
@@ -79,17 +79,17 @@ impl WordlistCommands {
         let mut word_pool = Vec::new();
 
         if silly || use_all {
-            word_pool.extend_from_slice(&SILLY_WORDS);
+            word_pool.extend_from_slice(SILLY_WORDS);
             println!("  📝 Added silly/fun words");
         }
 
         if animals || use_all {
-            word_pool.extend_from_slice(&ANIMAL_WORDS);
+            word_pool.extend_from_slice(ANIMAL_WORDS);
             println!("  🐾 Added animal words");
         }
 
         if food || use_all {
-            word_pool.extend_from_slice(&FOOD_WORDS);
+            word_pool.extend_from_slice(FOOD_WORDS);
             println!("  🍕 Added food words");
         }
 
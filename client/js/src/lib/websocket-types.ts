@@ -11,6 +11,13 @@ import { z } from "zod";
 const UuidSchema = z.string().uuid();
 const DateTimeSchema = z.string().datetime();
 
+/**
+ * Who may access a media blob, matching the server-side MediaVisibility
+ */
+export const MediaVisibilitySchema = z.enum(["private", "shared", "members"]);
+
+export type MediaVisibility = z.infer<typeof MediaVisibilitySchema>;
+
 /**
  * Media blob data structure matching the server-side MediaBlob
  */
@@ -21,6 +28,8 @@ export const MediaBlobSchema = z.object({
   size: z.number().int().optional(),
   mime: z.string().optional(),
   source_client_id: z.string().optional(),
+  owner_user_id: UuidSchema.nullish(), // Always set by the server
+  visibility: MediaVisibilitySchema.default("private"),
   local_path: z.string().nullish(),
   metadata: z.record(z.any()).default({}), // JSONB as Record<string, any>
   created_at: DateTimeSchema,
@@ -29,6 +38,19 @@ export const MediaBlobSchema = z.object({
 
 export type MediaBlob = z.infer<typeof MediaBlobSchema>;
 
+/**
+ * Sharing grant for a media blob matching the server-side MediaBlobShare
+ */
+export const MediaBlobShareSchema = z.object({
+  blob_id: UuidSchema,
+  user_id: UuidSchema,
+  username: z.string(),
+  granted_by: UuidSchema.nullish(),
+  granted_at: DateTimeSchema,
+});
+
+export type MediaBlobShare = z.infer<typeof MediaBlobShareSchema>;
+
 /**
  * Messages sent from client to server
  */
@@ -63,6 +85,39 @@ export const WebSocketMessageSchema = z.discriminatedUnion("type", [
       id: UuidSchema,
     }),
   }),
+  z.object({
+    type: z.literal("DeleteMediaBlob"),
+    data: z.object({
+      id: UuidSchema,
+    }),
+  }),
+  z.object({
+    type: z.literal("SetMediaBlobVisibility"),
+    data: z.object({
+      id: UuidSchema,
+      visibility: MediaVisibilitySchema,
+    }),
+  }),
+  z.object({
+    type: z.literal("ShareMediaBlob"),
+    data: z.object({
+      id: UuidSchema,
+      usernames: z.array(z.string()),
+    }),
+  }),
+  z.object({
+    type: z.literal("UnshareMediaBlob"),
+    data: z.object({
+      id: UuidSchema,
+      username: z.string(),
+    }),
+  }),
+  z.object({
+    type: z.literal("GetMediaBlobShares"),
+    data: z.object({
+      id: UuidSchema,
+    }),
+  }),
 ]);
 
 export type WebSocketMessage = z.infer<typeof WebSocketMessageSchema>;
@@ -103,6 +158,19 @@ export const WebSocketResponseSchema = z.discriminatedUnion("type", [
       mime: z.string().optional(),
     }),
   }),
+  z.object({
+    type: z.literal("MediaBlobDeleted"),
+    data: z.object({
+      id: UuidSchema,
+    }),
+  }),
+  z.object({
+    type: z.literal("MediaBlobShares"),
+    data: z.object({
+      id: UuidSchema,
+      shares: z.array(MediaBlobShareSchema),
+    }),
+  }),
   z.object({
     type: z.literal("Error"),
     data: z.object({
@@ -156,6 +224,34 @@ export const createMessage = {
     type: "UploadMediaBlob",
     data: { blob },
   }),
+
+  deleteMediaBlob: (id: string): WebSocketMessage => ({
+    type: "DeleteMediaBlob",
+    data: { id },
+  }),
+
+  setMediaBlobVisibility: (
+    id: string,
+    visibility: MediaVisibility
+  ): WebSocketMessage => ({
+    type: "SetMediaBlobVisibility",
+    data: { id, visibility },
+  }),
+
+  shareMediaBlob: (id: string, usernames: string[]): WebSocketMessage => ({
+    type: "ShareMediaBlob",
+    data: { id, usernames },
+  }),
+
+  unshareMediaBlob: (id: string, username: string): WebSocketMessage => ({
+    type: "UnshareMediaBlob",
+    data: { id, username },
+  }),
+
+  getMediaBlobShares: (id: string): WebSocketMessage => ({
+    type: "GetMediaBlobShares",
+    data: { id },
+  }),
 };
 
 /**
@@ -220,6 +316,12 @@ export const isMediaBlobDataMessage = (
   return response.type === "MediaBlobData";
 };
 
+export const isMediaBlobSharesMessage = (
+  response: WebSocketResponse
+): response is Extract<WebSocketResponse, { type: "MediaBlobShares" }> => {
+  return response.type === "MediaBlobShares";
+};
+
 /**
  * Validates and parses incoming WebSocket message with detailed error info
  */
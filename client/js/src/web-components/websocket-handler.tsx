@@ -281,6 +281,7 @@ const WebSocketHandler = (props: WebSocketHandlerProps) => {
       size: file.size,
       mime: file.type || "application/octet-stream",
       source_client_id: "web-component",
+      visibility: "private",
       local_path: file.name,
       metadata: {
         originalName: file.name,
@@ -0,0 +1,46 @@
+-- Media Blob Ownership and Sharing
+-- Adds an owning user, a visibility setting and per-user sharing grants to media_blobs
+
+-- Timestamps and metadata are always written by the application; make that explicit
+UPDATE media_blobs SET metadata = '{}'::jsonb WHERE metadata IS NULL;
+UPDATE media_blobs SET created_at = now() WHERE created_at IS NULL;
+UPDATE media_blobs SET updated_at = created_at WHERE updated_at IS NULL;
+
+ALTER TABLE media_blobs
+    ALTER COLUMN metadata SET NOT NULL,
+    ALTER COLUMN created_at SET NOT NULL,
+    ALTER COLUMN updated_at SET NOT NULL;
+
+-- Owner of the blob; legacy blobs have no owner and are only manageable by admins
+ALTER TABLE media_blobs
+    ADD COLUMN IF NOT EXISTS owner_user_id UUID REFERENCES users(id) ON DELETE SET NULL;
+
+-- Existing blobs were visible to every member, so keep them that way;
+-- new blobs default to private
+ALTER TABLE media_blobs
+    ADD COLUMN IF NOT EXISTS visibility TEXT NOT NULL DEFAULT 'members';
+ALTER TABLE media_blobs
+    ALTER COLUMN visibility SET DEFAULT 'private';
+ALTER TABLE media_blobs
+    ADD CONSTRAINT media_blobs_visibility_check
+    CHECK (visibility IN ('private', 'shared', 'members'));
+
+CREATE INDEX IF NOT EXISTS idx_media_blobs_owner ON media_blobs (owner_user_id);
+CREATE INDEX IF NOT EXISTS idx_media_blobs_visibility ON media_blobs (visibility);
+
+-- Sharing grants for blobs with visibility = 'shared'
+CREATE TABLE IF NOT EXISTS media_blob_shares (
+    blob_id UUID NOT NULL REFERENCES media_blobs(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    granted_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    granted_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    PRIMARY KEY (blob_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_media_blob_shares_user ON media_blob_shares (user_id);
+
+-- Comments for documentation
+COMMENT ON COLUMN media_blobs.owner_user_id IS 'User who uploaded and owns this blob (NULL for legacy blobs)';
+COMMENT ON COLUMN media_blobs.visibility IS 'Who may access the blob: private (owner only), shared (owner and granted users), members (all authenticated users)';
+COMMENT ON TABLE media_blob_shares IS 'Per-user access grants for media blobs with shared visibility';
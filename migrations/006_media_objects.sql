@@ -0,0 +1,64 @@
+-- Content-Addressed Media Objects
+-- Stores each distinct piece of content once, keyed by its SHA256 hash.
+-- media_blobs rows become logical records that reference an object.
+
+CREATE TABLE IF NOT EXISTS media_objects (
+    sha256 TEXT PRIMARY KEY,
+    size BIGINT NOT NULL,
+    data BYTEA,
+    storage_path TEXT,
+    ref_count BIGINT NOT NULL DEFAULT 0 CHECK (ref_count >= 0),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Move existing content into objects. Rows with inline data keep it in the
+-- database; uploads stored on disk reference their file under assets/.
+INSERT INTO media_objects (sha256, size, data, storage_path, ref_count)
+SELECT
+    sha256,
+    COALESCE(MAX(size), MAX(octet_length(data)), 0),
+    (array_agg(data) FILTER (WHERE data IS NOT NULL))[1],
+    CASE
+        WHEN bool_or(data IS NOT NULL) THEN NULL
+        ELSE 'assets/' || (array_agg(local_path ORDER BY created_at) FILTER (WHERE local_path IS NOT NULL))[1]
+    END,
+    COUNT(*)
+FROM media_blobs
+GROUP BY sha256
+ON CONFLICT (sha256) DO NOTHING;
+
+ALTER TABLE media_blobs
+    ADD CONSTRAINT media_blobs_sha256_fkey
+    FOREIGN KEY (sha256) REFERENCES media_objects(sha256);
+
+ALTER TABLE media_blobs DROP COLUMN data;
+
+-- Keep reference counts in step with the logical records
+CREATE OR REPLACE FUNCTION update_media_objects_ref_count()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        UPDATE media_objects SET ref_count = ref_count + 1 WHERE sha256 = NEW.sha256;
+    ELSIF TG_OP = 'DELETE' THEN
+        UPDATE media_objects SET ref_count = ref_count - 1 WHERE sha256 = OLD.sha256;
+    ELSIF NEW.sha256 IS DISTINCT FROM OLD.sha256 THEN
+        UPDATE media_objects SET ref_count = ref_count - 1 WHERE sha256 = OLD.sha256;
+        UPDATE media_objects SET ref_count = ref_count + 1 WHERE sha256 = NEW.sha256;
+    END IF;
+    RETURN NULL;
+END;
+$$ language 'plpgsql';
+
+CREATE TRIGGER trigger_media_objects_ref_count
+    AFTER INSERT OR DELETE OR UPDATE OF sha256 ON media_blobs
+    FOR EACH ROW
+    EXECUTE FUNCTION update_media_objects_ref_count();
+
+-- Garbage collection looks for objects nothing references any more
+CREATE INDEX IF NOT EXISTS idx_media_objects_unreferenced ON media_objects (sha256) WHERE ref_count = 0;
+
+-- Comments for documentation
+COMMENT ON TABLE media_objects IS 'Content-addressed storage: one row per distinct SHA256, shared by many media_blobs';
+COMMENT ON COLUMN media_objects.data IS 'Binary content when stored in the database';
+COMMENT ON COLUMN media_objects.storage_path IS 'Filesystem path of the content when stored on disk';
+COMMENT ON COLUMN media_objects.ref_count IS 'Number of media_blobs referencing this object, maintained by trigger';
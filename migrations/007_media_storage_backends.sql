@@ -0,0 +1,36 @@
+-- Pluggable Media Storage Backends
+-- Each media object records which backend holds its content and the key it is
+-- stored under, so content stays readable when the configured backend changes.
+
+-- Content held by the database backend. Kept apart from media_objects so
+-- writing content never contends with the row locks taken on objects.
+CREATE TABLE IF NOT EXISTS media_object_data (
+    sha256 TEXT PRIMARY KEY,
+    data BYTEA NOT NULL
+);
+
+INSERT INTO media_object_data (sha256, data)
+SELECT sha256, data FROM media_objects WHERE data IS NOT NULL
+ON CONFLICT (sha256) DO NOTHING;
+
+ALTER TABLE media_objects ADD COLUMN storage_backend TEXT;
+
+-- Inline content moves to the database backend under its hash; files already
+-- on disk keep their path as the filesystem key
+UPDATE media_objects
+SET storage_backend = CASE WHEN data IS NOT NULL THEN 'database' ELSE 'filesystem' END,
+    storage_path = CASE WHEN data IS NOT NULL THEN sha256 ELSE COALESCE(storage_path, sha256) END;
+
+ALTER TABLE media_objects RENAME COLUMN storage_path TO storage_key;
+ALTER TABLE media_objects ALTER COLUMN storage_backend SET NOT NULL;
+ALTER TABLE media_objects ALTER COLUMN storage_key SET NOT NULL;
+ALTER TABLE media_objects ADD CONSTRAINT media_objects_storage_backend_check
+    CHECK (storage_backend IN ('database', 'filesystem', 's3'));
+ALTER TABLE media_objects DROP COLUMN data;
+
+CREATE INDEX IF NOT EXISTS idx_media_objects_storage_backend ON media_objects (storage_backend);
+
+-- Comments for documentation
+COMMENT ON TABLE media_object_data IS 'Content of media objects stored by the database backend';
+COMMENT ON COLUMN media_objects.storage_backend IS 'Backend holding the content: database, filesystem or s3';
+COMMENT ON COLUMN media_objects.storage_key IS 'Backend-specific key: hash, file path or object key';
@@ -0,0 +1,33 @@
+-- Resumable Media Uploads
+-- Upload sessions track a partially received file so a client can continue
+-- after a dropped connection or a server restart.
+
+CREATE TABLE IF NOT EXISTS media_upload_sessions (
+    id UUID PRIMARY KEY,
+    owner_user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    filename TEXT NOT NULL,
+    mime_type TEXT,
+    sha256 TEXT NOT NULL,
+    size BIGINT NOT NULL CHECK (size >= 0),
+    upload_offset BIGINT NOT NULL DEFAULT 0,
+    visibility TEXT NOT NULL DEFAULT 'private',
+    metadata JSONB NOT NULL DEFAULT '{}'::jsonb,
+    staging_path TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+
+    CONSTRAINT media_upload_sessions_offset_check
+        CHECK (upload_offset >= 0 AND upload_offset <= size),
+    CONSTRAINT media_upload_sessions_visibility_check
+        CHECK (visibility IN ('private', 'shared', 'members'))
+);
+
+CREATE INDEX IF NOT EXISTS idx_media_upload_sessions_owner ON media_upload_sessions (owner_user_id);
+CREATE INDEX IF NOT EXISTS idx_media_upload_sessions_expires_at ON media_upload_sessions (expires_at);
+
+-- Comments for documentation
+COMMENT ON TABLE media_upload_sessions IS 'Resumable uploads in progress; finished sessions become media blobs';
+COMMENT ON COLUMN media_upload_sessions.upload_offset IS 'Bytes received and durably written to staging_path';
+COMMENT ON COLUMN media_upload_sessions.staging_path IS 'Partial file holding the bytes received so far';
+COMMENT ON COLUMN media_upload_sessions.expires_at IS 'Sessions without activity until then are removed with their partial file';
@@ -0,0 +1,15 @@
+-- Single-Use Media Links
+-- Signed download links are verified without database state; only the nonces
+-- of single-use links are recorded so a second request can be refused.
+
+CREATE TABLE IF NOT EXISTS media_link_uses (
+    nonce TEXT PRIMARY KEY,
+    used_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_media_link_uses_expires_at ON media_link_uses (expires_at);
+
+-- Comments for documentation
+COMMENT ON TABLE media_link_uses IS 'Nonces of single-use download links that have been used';
+COMMENT ON COLUMN media_link_uses.expires_at IS 'Expiry of the link; the row can be removed afterwards';
@@ -0,0 +1,39 @@
+-- Media Processing
+-- Uploaded media is analysed in the background. Derived blobs (such as image
+-- thumbnails) reference the blob they were made from and are removed with it.
+
+ALTER TABLE media_blobs
+    ADD COLUMN IF NOT EXISTS parent_blob_id UUID REFERENCES media_blobs(id) ON DELETE CASCADE,
+    ADD COLUMN IF NOT EXISTS derivative TEXT;
+
+ALTER TABLE media_blobs DROP CONSTRAINT IF EXISTS media_blobs_derivative_check;
+ALTER TABLE media_blobs ADD CONSTRAINT media_blobs_derivative_check
+    CHECK ((parent_blob_id IS NULL) = (derivative IS NULL));
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_media_blobs_derivative
+    ON media_blobs (parent_blob_id, derivative) WHERE parent_blob_id IS NOT NULL;
+
+CREATE TABLE IF NOT EXISTS media_jobs (
+    id BIGSERIAL PRIMARY KEY,
+    blob_id UUID NOT NULL REFERENCES media_blobs(id) ON DELETE CASCADE,
+    kind TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    run_after TIMESTAMPTZ NOT NULL DEFAULT now(),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    CONSTRAINT media_jobs_blob_kind_unique UNIQUE (blob_id, kind),
+    CONSTRAINT media_jobs_status_check
+        CHECK (status IN ('pending', 'running', 'done', 'failed'))
+);
+
+CREATE INDEX IF NOT EXISTS idx_media_jobs_pending
+    ON media_jobs (run_after) WHERE status IN ('pending', 'running');
+
+-- Comments for documentation
+COMMENT ON COLUMN media_blobs.parent_blob_id IS 'Blob this one was derived from; NULL for uploaded blobs';
+COMMENT ON COLUMN media_blobs.derivative IS 'Kind of derivative, e.g. thumbnail_256; unique per parent';
+COMMENT ON TABLE media_jobs IS 'Background processing queue for media blobs';
+COMMENT ON COLUMN media_jobs.run_after IS 'Earliest time the job is picked up; pushed back after failures';
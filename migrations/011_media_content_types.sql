@@ -0,0 +1,7 @@
+-- Detected Media Content Types
+-- Records the content type recognised from each object's leading bytes, so
+-- uploads referencing stored content can be checked without reading it again.
+-- Objects stored before detection existed have no recorded type.
+
+ALTER TABLE media_objects
+    ADD COLUMN IF NOT EXISTS content_type TEXT;
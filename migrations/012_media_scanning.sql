@@ -0,0 +1,18 @@
+-- Media Malware Scanning
+-- Uploaded blobs are quarantined until a malware scanner has checked their
+-- content. Blobs stored before scanning, and derived blobs such as thumbnails,
+-- have no scan status.
+
+ALTER TABLE media_blobs
+    ADD COLUMN IF NOT EXISTS scan_status TEXT;
+
+ALTER TABLE media_blobs DROP CONSTRAINT IF EXISTS media_blobs_scan_status_check;
+ALTER TABLE media_blobs ADD CONSTRAINT media_blobs_scan_status_check
+    CHECK (scan_status IN ('pending', 'clean', 'infected'));
+
+-- Quarantined content is looked up by hash when serving files
+CREATE INDEX IF NOT EXISTS idx_media_blobs_quarantined
+    ON media_blobs (sha256) WHERE scan_status IN ('pending', 'infected');
+
+-- Comments for documentation
+COMMENT ON COLUMN media_blobs.scan_status IS 'pending, clean or infected; NULL if never scanned. Pending and infected blobs cannot be downloaded';
@@ -0,0 +1,61 @@
+-- Media Storage Quotas
+-- Usage of each user's uploaded media is kept in media_usage by trigger, so
+-- quotas can be checked without summing their blobs. Derived blobs such as
+-- thumbnails are created by the server and do not count.
+
+CREATE TABLE IF NOT EXISTS media_usage (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    bytes BIGINT NOT NULL DEFAULT 0 CHECK (bytes >= 0),
+    blob_count BIGINT NOT NULL DEFAULT 0 CHECK (blob_count >= 0),
+    quota_bytes BIGINT CHECK (quota_bytes >= 0),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+INSERT INTO media_usage (user_id, bytes, blob_count)
+SELECT owner_user_id, COALESCE(SUM(size), 0), COUNT(*)
+FROM media_blobs
+WHERE owner_user_id IS NOT NULL AND parent_blob_id IS NULL
+GROUP BY owner_user_id
+ON CONFLICT (user_id) DO UPDATE
+SET bytes = EXCLUDED.bytes, blob_count = EXCLUDED.blob_count, updated_at = now();
+
+-- Keep usage in step with the blobs each user owns
+CREATE OR REPLACE FUNCTION update_media_usage()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'UPDATE'
+        AND NEW.owner_user_id IS NOT DISTINCT FROM OLD.owner_user_id
+        AND NEW.size IS NOT DISTINCT FROM OLD.size
+        AND NEW.parent_blob_id IS NOT DISTINCT FROM OLD.parent_blob_id THEN
+        RETURN NULL;
+    END IF;
+
+    IF TG_OP IN ('UPDATE', 'DELETE')
+        AND OLD.owner_user_id IS NOT NULL AND OLD.parent_blob_id IS NULL THEN
+        UPDATE media_usage
+        SET bytes = bytes - COALESCE(OLD.size, 0), blob_count = blob_count - 1, updated_at = now()
+        WHERE user_id = OLD.owner_user_id;
+    END IF;
+
+    IF TG_OP IN ('INSERT', 'UPDATE')
+        AND NEW.owner_user_id IS NOT NULL AND NEW.parent_blob_id IS NULL THEN
+        INSERT INTO media_usage (user_id, bytes, blob_count)
+        VALUES (NEW.owner_user_id, COALESCE(NEW.size, 0), 1)
+        ON CONFLICT (user_id) DO UPDATE
+        SET bytes = media_usage.bytes + EXCLUDED.bytes,
+            blob_count = media_usage.blob_count + 1,
+            updated_at = now();
+    END IF;
+
+    RETURN NULL;
+END;
+$$ language 'plpgsql';
+
+CREATE TRIGGER trigger_media_usage
+    AFTER INSERT OR DELETE OR UPDATE OF owner_user_id, size, parent_blob_id ON media_blobs
+    FOR EACH ROW
+    EXECUTE FUNCTION update_media_usage();
+
+-- Comments for documentation
+COMMENT ON TABLE media_usage IS 'Bytes and number of uploaded media blobs each user owns, maintained by trigger';
+COMMENT ON COLUMN media_usage.quota_bytes IS 'Quota set for this user, overriding their role''s quota; NULL uses the role quota';
@@ -0,0 +1,43 @@
+-- Media Collections and Tags
+-- Users group their media into named, ordered collections and attach
+-- free-form tags to blobs they own.
+
+CREATE TABLE IF NOT EXISTS media_collections (
+    id UUID PRIMARY KEY,
+    owner_user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name TEXT NOT NULL CHECK (length(name) BETWEEN 1 AND 100),
+    description TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    CONSTRAINT media_collections_owner_name_key UNIQUE (owner_user_id, name)
+);
+
+-- Blobs in a collection, in the order their owner arranged them
+CREATE TABLE IF NOT EXISTS media_collection_items (
+    collection_id UUID NOT NULL REFERENCES media_collections(id) ON DELETE CASCADE,
+    blob_id UUID NOT NULL REFERENCES media_blobs(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    added_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    PRIMARY KEY (collection_id, blob_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_media_collection_items_position
+    ON media_collection_items (collection_id, position);
+CREATE INDEX IF NOT EXISTS idx_media_collection_items_blob ON media_collection_items (blob_id);
+
+-- Tags of a blob, normalised to lower case; one row per tagged blob
+CREATE TABLE IF NOT EXISTS media_blob_tags (
+    blob_id UUID PRIMARY KEY REFERENCES media_blobs(id) ON DELETE CASCADE,
+    tags TEXT[] NOT NULL DEFAULT '{}',
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Tag filters use array containment (tags @> ARRAY[...])
+CREATE INDEX IF NOT EXISTS idx_media_blob_tags_tags ON media_blob_tags USING GIN (tags);
+
+-- Comments for documentation
+COMMENT ON TABLE media_collections IS 'Named, ordered groups of media blobs owned by a user';
+COMMENT ON COLUMN media_collection_items.position IS 'Sort key within the collection; gaps are allowed';
+COMMENT ON TABLE media_blob_tags IS 'Free-form tags of media blobs, searchable through a GIN index';
@@ -0,0 +1,72 @@
+-- Media Search
+-- Each blob carries a full-text search document built from its file name,
+-- tags, MIME type and the strings in its metadata. Triggers keep the document
+-- current as blobs and their tags change.
+
+-- Split file names and MIME types into words: "IMG_01-beach.jpg" -> "IMG 01 beach jpg"
+CREATE OR REPLACE FUNCTION media_search_words(value TEXT) RETURNS TEXT
+LANGUAGE sql IMMUTABLE AS $$
+    SELECT regexp_replace(COALESCE(value, ''), '[^[:alnum:]]+', ' ', 'g')
+$$;
+
+-- File names and tags weigh most, then the MIME type, then other metadata
+CREATE OR REPLACE FUNCTION media_search_document(metadata JSONB, mime TEXT, tags TEXT[])
+RETURNS tsvector LANGUAGE sql IMMUTABLE AS $$
+    SELECT setweight(to_tsvector('simple', media_search_words(metadata->>'filename')), 'A')
+        || setweight(to_tsvector('simple', media_search_words(array_to_string(tags, ' '))), 'A')
+        || setweight(to_tsvector('simple', media_search_words(mime)), 'B')
+        || setweight(jsonb_to_tsvector('simple', metadata - 'filename', '["string"]'), 'C')
+$$;
+
+ALTER TABLE media_blobs ADD COLUMN IF NOT EXISTS search_document tsvector NOT NULL DEFAULT ''::tsvector;
+
+UPDATE media_blobs b
+SET search_document = media_search_document(
+    b.metadata, b.mime,
+    COALESCE((SELECT t.tags FROM media_blob_tags t WHERE t.blob_id = b.id), '{}')
+);
+
+CREATE OR REPLACE FUNCTION update_media_blob_search_document()
+RETURNS TRIGGER AS $$
+BEGIN
+    NEW.search_document := media_search_document(
+        NEW.metadata, NEW.mime,
+        COALESCE((SELECT t.tags FROM media_blob_tags t WHERE t.blob_id = NEW.id), '{}')
+    );
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS trigger_media_blobs_search_document ON media_blobs;
+CREATE TRIGGER trigger_media_blobs_search_document
+    BEFORE INSERT OR UPDATE OF metadata, mime ON media_blobs
+    FOR EACH ROW
+    EXECUTE FUNCTION update_media_blob_search_document();
+
+CREATE OR REPLACE FUNCTION update_media_tags_search_document()
+RETURNS TRIGGER AS $$
+BEGIN
+    UPDATE media_blobs
+    SET search_document = media_search_document(
+        metadata, mime,
+        CASE WHEN TG_OP = 'DELETE' THEN '{}'::text[] ELSE NEW.tags END
+    )
+    WHERE id = CASE WHEN TG_OP = 'DELETE' THEN OLD.blob_id ELSE NEW.blob_id END;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS trigger_media_blob_tags_search_document ON media_blob_tags;
+CREATE TRIGGER trigger_media_blob_tags_search_document
+    AFTER INSERT OR UPDATE OR DELETE ON media_blob_tags
+    FOR EACH ROW
+    EXECUTE FUNCTION update_media_tags_search_document();
+
+CREATE INDEX IF NOT EXISTS idx_media_blobs_search_document
+    ON media_blobs USING GIN (search_document);
+-- Serves equality metadata filters; range filters are checked row by row
+CREATE INDEX IF NOT EXISTS idx_media_blobs_metadata
+    ON media_blobs USING GIN (metadata jsonb_path_ops);
+CREATE INDEX IF NOT EXISTS idx_media_blobs_size ON media_blobs (size);
+
+COMMENT ON COLUMN media_blobs.search_document IS 'Full-text search document from file name, tags, MIME type and metadata strings';
@@ -0,0 +1,40 @@
+-- Media Trash, Retention and Audit Log
+-- Deleted blobs move to a trash, where their owner can restore them until
+-- the trash window passes and they are purged with their stored bytes.
+-- Retention policies purge blobs of some MIME types, or in some collections,
+-- once they reach a given age. Every removal is recorded in an audit log.
+
+ALTER TABLE media_blobs
+    ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ,
+    ADD COLUMN IF NOT EXISTS deleted_by UUID REFERENCES users(id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS idx_media_blobs_deleted_at
+    ON media_blobs (deleted_at) WHERE deleted_at IS NOT NULL;
+
+-- Blobs in a collection with a retention period are purged once they are
+-- older; unset keeps them
+ALTER TABLE media_collections
+    ADD COLUMN IF NOT EXISTS retention_days INTEGER CHECK (retention_days > 0);
+
+-- Entries outlive the blobs and users they name, so nothing is a foreign key
+CREATE TABLE IF NOT EXISTS media_audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    action TEXT NOT NULL CHECK (action IN ('trashed', 'restored', 'purged')),
+    reason TEXT NOT NULL
+        CHECK (reason IN ('user', 'cleanup', 'trash_expired', 'retention', 'fsck')),
+    blob_id UUID NOT NULL,
+    sha256 TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    mime TEXT,
+    owner_user_id UUID,
+    actor_user_id UUID,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_media_audit_log_created_at ON media_audit_log (created_at DESC);
+CREATE INDEX IF NOT EXISTS idx_media_audit_log_blob ON media_audit_log (blob_id);
+
+-- Comments for documentation
+COMMENT ON COLUMN media_blobs.deleted_at IS 'When the blob was moved to the trash; NULL for live blobs';
+COMMENT ON COLUMN media_collections.retention_days IS 'Days blobs in the collection are kept before being purged';
+COMMENT ON TABLE media_audit_log IS 'Media blobs moved to the trash, restored or purged, and why';
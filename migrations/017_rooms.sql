@@ -0,0 +1,44 @@
+-- Rooms and Messaging
+-- Users talk in named rooms over the WebSocket. Public rooms are open to
+-- everyone; private rooms only to members added by the owner or a
+-- moderator. Messages carry text, a shared media blob, or both, and are
+-- kept so members joining later can catch up.
+
+CREATE TABLE IF NOT EXISTS rooms (
+    id UUID PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE CHECK (length(name) BETWEEN 1 AND 100),
+    topic TEXT CHECK (length(topic) <= 500),
+    visibility TEXT NOT NULL DEFAULT 'public' CHECK (visibility IN ('public', 'private')),
+    created_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS room_members (
+    room_id UUID NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role TEXT NOT NULL DEFAULT 'member' CHECK (role IN ('member', 'moderator', 'owner')),
+    joined_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    PRIMARY KEY (room_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_room_members_user ON room_members (user_id);
+
+-- Messages stay when their author or blob is deleted
+CREATE TABLE IF NOT EXISTS room_messages (
+    id BIGSERIAL PRIMARY KEY,
+    room_id UUID NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    author_user_id UUID REFERENCES users(id) ON DELETE SET NULL,
+    body TEXT CHECK (length(body) BETWEEN 1 AND 4000),
+    blob_id UUID REFERENCES media_blobs(id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- History is read newest first from a cursor
+CREATE INDEX IF NOT EXISTS idx_room_messages_room ON room_messages (room_id, id DESC);
+
+-- Comments for documentation
+COMMENT ON TABLE rooms IS 'Named rooms users post messages in over the WebSocket';
+COMMENT ON COLUMN room_members.role IS 'member posts, moderator also manages members, owner also manages moderators and the room';
+COMMENT ON TABLE room_messages IS 'Text messages and media blob shares posted in rooms, oldest first by id';
@@ -0,0 +1,50 @@
+-- Collaborative Drawing Boards
+-- Members of a room draw together on its boards over the WebSocket. Every
+-- change is an operation in the board's log, numbered in the order the
+-- server accepted it; snapshots of the drawing are taken every so often so
+-- clients opening a board only replay the operations since the latest one.
+
+CREATE TABLE IF NOT EXISTS boards (
+    id UUID PRIMARY KEY,
+    room_id UUID NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    name TEXT NOT NULL CHECK (length(name) BETWEEN 1 AND 100),
+    created_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    -- Sequence number of the latest operation; 0 for an empty board
+    seq BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    UNIQUE (room_id, name)
+);
+
+-- Operations stay when their author is deleted
+CREATE TABLE IF NOT EXISTS board_ops (
+    board_id UUID NOT NULL REFERENCES boards(id) ON DELETE CASCADE,
+    seq BIGINT NOT NULL,
+    author_user_id UUID REFERENCES users(id) ON DELETE SET NULL,
+    client_op_id TEXT CHECK (length(client_op_id) BETWEEN 1 AND 64),
+    op JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    PRIMARY KEY (board_id, seq)
+);
+
+-- Clients resend operations they got no answer for; each is applied once
+CREATE UNIQUE INDEX IF NOT EXISTS idx_board_ops_client
+    ON board_ops (board_id, author_user_id, client_op_id)
+    WHERE client_op_id IS NOT NULL;
+
+CREATE TABLE IF NOT EXISTS board_snapshots (
+    board_id UUID NOT NULL REFERENCES boards(id) ON DELETE CASCADE,
+    seq BIGINT NOT NULL,
+    state JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    PRIMARY KEY (board_id, seq)
+);
+
+-- Comments for documentation
+COMMENT ON TABLE boards IS 'Shared drawing boards, each belonging to a room';
+COMMENT ON COLUMN boards.seq IS 'Sequence number of the latest operation in board_ops';
+COMMENT ON TABLE board_ops IS 'Log of drawing operations (add, update, delete) in the order they were applied';
+COMMENT ON TABLE board_snapshots IS 'Board state as of an operation, so opening a board only replays later operations';
@@ -2,11 +2,15 @@
 //!
 //! HTTP handlers for analytics and metrics endpoints
 
+use crate::database::DatabaseConnection;
 use crate::error::WebauthnError;
+use crate::media::{render_prometheus, MediaRepository, MediaStatsQuery};
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Json},
+    Extension,
 };
+use tracing::warn;
 
 /// Simple metrics endpoint
 /// Returns basic system information and status
@@ -24,14 +28,25 @@ pub async fn get_metrics() -> Result<impl IntoResponse, WebauthnError> {
 }
 
 /// Prometheus-style metrics endpoint (simplified)
-/// Returns metrics in Prometheus text format
-pub async fn get_prometheus_metrics() -> Result<impl IntoResponse, WebauthnError> {
-    let metrics = r#"# HELP webauthn_status Server status
+/// Returns metrics in Prometheus text format, including media statistics
+/// when the database can be reached
+pub async fn get_prometheus_metrics(
+    Extension(db): Extension<DatabaseConnection>,
+) -> Result<impl IntoResponse, WebauthnError> {
+    let mut metrics = r#"# HELP webauthn_status Server status
 # TYPE webauthn_status gauge
 webauthn_status{service="webauthn"} 1
 
-# Note: Detailed metrics will be available after analytics migration
-"#;
+"#
+    .to_string();
+
+    match MediaRepository::new(&db)
+        .get_stats(MediaStatsQuery::totals())
+        .await
+    {
+        Ok(stats) => metrics.push_str(&render_prometheus(&stats)),
+        Err(e) => warn!("Leaving media statistics out of metrics: {}", e),
+    }
 
     Ok((
         StatusCode::OK,
@@ -52,7 +67,12 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_prometheus_metrics() {
-        let result = get_prometheus_metrics().await;
+        // Media statistics are left out when the database is unreachable
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .acquire_timeout(std::time::Duration::from_millis(100))
+            .connect_lazy("postgres://invalid@127.0.0.1:1/none")
+            .unwrap();
+        let result = get_prometheus_metrics(Extension(DatabaseConnection::new(pool))).await;
         assert!(result.is_ok());
     }
 }
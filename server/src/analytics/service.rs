@@ -94,7 +94,7 @@ impl<'a> AnalyticsService<'a> {
         let mut requests = self.repo.get_user_requests(user_id, from, to).await?;
 
         // Sort by timestamp descending and take only the requested number
-        requests.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+        requests.sort_by_key(|r| std::cmp::Reverse(r.timestamp));
         requests.truncate(limit as usize);
 
         Ok(requests)
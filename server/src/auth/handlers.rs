@@ -1,4 +1,4 @@
-use crate::auth::{AuthRepository, UserRole};
+use crate::auth::{AuthError, AuthRepository};
 use crate::error::WebauthnError;
 use crate::startup::AppState;
 use axum::{
@@ -297,7 +297,7 @@ pub async fn finish_register(
                             }
                             Err(e) => {
                                 error!("Failed to link credential to user: {:?}", e);
-                                Err(WebauthnError::DatabaseError)
+                                Err(registration_error(e))
                             }
                         }
                     }
@@ -307,38 +307,13 @@ pub async fn finish_register(
                     }
                 }
             } else {
-                // New user registration
-                // Check if this is the first user (make them admin)
-                let is_first_user = match auth_repo.list_users().await {
-                    Ok(users) => users.is_empty(),
-                    Err(_) => false,
-                };
-
-                let role = if is_first_user {
-                    UserRole::Admin
-                } else {
-                    UserRole::Member
-                };
-
+                // New user registration: user, credential and invite redemption
+                // are written in a single transaction
                 match auth_repo
-                    .create_user_with_role(&username, invite_code.as_deref(), role)
+                    .register_user(&username, invite_code.as_deref(), &sk)
                     .await
                 {
                     Ok(user) => {
-                        // Save the credential
-                        if let Err(e) = auth_repo.save_credential(user.id, &sk).await {
-                            error!("Failed to save credential: {:?}", e);
-                            return Err(WebauthnError::DatabaseError);
-                        }
-
-                        // Mark the invite code as used (if one was provided)
-                        if let Some(ref code) = invite_code {
-                            if let Err(e) = auth_repo.use_invite_code(code, user.id).await {
-                                error!("Failed to mark invite code as used: {:?}", e);
-                                // Don't fail the registration for this, but log it
-                            }
-                        }
-
                         // Set user_id in session to automatically log in the user
                         session
                             .insert("user_id", user.id)
@@ -363,8 +338,8 @@ pub async fn finish_register(
                         }))
                     }
                     Err(e) => {
-                        error!("Failed to create user: {:?}", e);
-                        Err(WebauthnError::DatabaseError)
+                        error!("Failed to register user {}: {:?}", username, e);
+                        Err(registration_error(e))
                     }
                 }
             }
@@ -376,6 +351,19 @@ pub async fn finish_register(
     }
 }
 
+/// Map a repository error from the registration transaction to a response error
+fn registration_error(err: AuthError) -> WebauthnError {
+    match err {
+        AuthError::InvalidInviteCode | AuthError::InviteCodeAlreadyUsed => {
+            WebauthnError::InvalidInviteCode
+        }
+        AuthError::UsernameAlreadyExists => WebauthnError::UserAlreadyExists,
+        AuthError::UserNotFound => WebauthnError::UserNotFound,
+        AuthError::CredentialAlreadyRegistered => WebauthnError::BadRequest,
+        _ => WebauthnError::DatabaseError,
+    }
+}
+
 // 4. Now that our public key has been registered, we can authenticate a user and verify
 // that they are the holder of that security token. The work flow is similar to registration.
 //
@@ -139,16 +139,14 @@ pub fn can_access_analytics(request: &Request) -> bool {
         .unwrap_or(false)
 }
 
+/// Boxed future returned by role-checking middleware
+type RoleCheckFuture =
+    std::pin::Pin<Box<dyn std::future::Future<Output = Result<Response, StatusCode>> + Send>>;
+
 /// Role-based access control middleware factory
 pub fn require_role(
     required_role: UserRole,
-) -> impl Fn(
-    Extension<AuthenticatedUser>,
-    Request,
-    Next,
-) -> std::pin::Pin<
-    Box<dyn std::future::Future<Output = Result<Response, StatusCode>> + Send>,
-> + Clone {
+) -> impl Fn(Extension<AuthenticatedUser>, Request, Next) -> RoleCheckFuture + Clone {
     move |Extension(user): Extension<AuthenticatedUser>, request: Request, next: Next| {
         let user_role = user.user().role;
         let username = user.user().username.clone();
@@ -3,21 +3,16 @@ use time::OffsetDateTime;
 use uuid::Uuid;
 
 /// User roles in the system
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum UserRole {
     /// System administrator with full access
     Admin,
     /// Regular authenticated user
+    #[default]
     Member,
 }
 
-impl Default for UserRole {
-    fn default() -> Self {
-        UserRole::Member
-    }
-}
-
 impl UserRole {
     /// Check if this role has admin privileges
     pub fn is_admin(&self) -> bool {
@@ -148,6 +143,8 @@ pub enum AuthError {
     InviteCodeAlreadyUsed,
     #[error("Username already exists")]
     UsernameAlreadyExists,
+    #[error("Credential already registered")]
+    CredentialAlreadyRegistered,
     #[error("Authentication required")]
     AuthenticationRequired,
     #[error("Insufficient permissions")]
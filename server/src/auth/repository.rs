@@ -1,9 +1,16 @@
 use super::models::{AuthError, InviteCode, User, UserRole};
 use crate::database::DatabaseConnection;
+use sqlx::{PgConnection, PgExecutor};
 use uuid::Uuid;
 use webauthn_rs::prelude::Passkey;
 
+/// Advisory lock key held while a registration decides the new user's role
+const REGISTRATION_ROLE_LOCK: i64 = 0x5245_4749_5354_4552;
+
 /// Repository for authentication-related database operations
+///
+/// Methods suffixed with `_in` take an explicit executor so they can run inside
+/// a caller-owned transaction; the plain variants run directly on the pool.
 pub struct AuthRepository<'a> {
     db: &'a DatabaseConnection,
 }
@@ -73,8 +80,56 @@ impl<'a> AuthRepository<'a> {
         }))
     }
 
+    /// Get an invite code and lock its row until the surrounding transaction ends
+    ///
+    /// Concurrent callers block on the row lock, so only one of them can see the
+    /// code as unused and redeem it.
+    pub async fn lock_invite_code_in(
+        &self,
+        conn: &mut PgConnection,
+        code: &str,
+    ) -> Result<Option<InviteCode>, AuthError> {
+        let row = sqlx::query!(
+            r#"
+            SELECT id, code, created_at, used_at, used_by_user_id, is_active,
+                   code_type, link_for_user_id, link_expires_at
+            FROM invite_codes
+            WHERE code = $1
+            FOR UPDATE
+            "#,
+            code
+        )
+        .fetch_optional(conn)
+        .await?;
+
+        Ok(row.map(|r| InviteCode {
+            id: r.id,
+            code: r.code,
+            created_at: r.created_at,
+            used_at: r.used_at,
+            used_by_user_id: r.used_by_user_id,
+            is_active: r.is_active,
+            code_type: r.code_type,
+            link_for_user_id: r.link_for_user_id,
+            link_expires_at: r.link_expires_at,
+        }))
+    }
+
     /// Mark an invite code as used by a user
     pub async fn use_invite_code(&self, code: &str, user_id: Uuid) -> Result<bool, AuthError> {
+        self.use_invite_code_in(self.db.pool(), code, user_id).await
+    }
+
+    /// Mark an invite code as used by a user using the given executor
+    pub async fn use_invite_code_in<'e, E>(
+        &self,
+        executor: E,
+        code: &str,
+        user_id: Uuid,
+    ) -> Result<bool, AuthError>
+    where
+        E: PgExecutor<'e>,
+    {
         let result = sqlx::query!(
             r#"
             UPDATE invite_codes
@@ -84,21 +139,26 @@ impl<'a> AuthRepository<'a> {
             code,
             user_id
         )
-        .execute(self.db.pool())
+        .execute(executor)
         .await?;
 
         Ok(result.rows_affected() > 0)
     }
 
     /// Link a new credential to an existing user using an account link code
+    ///
+    /// Saving the credential and consuming the link code happen in a single
+    /// transaction, with the code row locked for the duration.
     pub async fn link_credential_to_user(
         &self,
         account_link_code: &str,
         credential: &webauthn_rs::prelude::Passkey,
     ) -> Result<User, AuthError> {
-        // Get and validate the account link code
+        let mut tx = self.db.begin().await?;
+
+        // Get, lock and validate the account link code
         let invite_code = self
-            .get_invite_code(account_link_code)
+            .lock_invite_code_in(&mut tx, account_link_code)
             .await?
             .ok_or(AuthError::InvalidInviteCode)?;
 
@@ -106,6 +166,10 @@ impl<'a> AuthRepository<'a> {
             return Err(AuthError::InvalidInviteCode);
         }
 
+        if invite_code.used_at.is_some() {
+            return Err(AuthError::InviteCodeAlreadyUsed);
+        }
+
         if !invite_code.is_valid_for_use() {
             return Err(AuthError::InvalidInviteCode);
         }
@@ -116,22 +180,100 @@ impl<'a> AuthRepository<'a> {
 
         // Get the target user
         let user = self
-            .get_user_by_id(target_user_id)
+            .get_user_by_id_in(&mut *tx, target_user_id)
             .await?
             .ok_or(AuthError::UserNotFound)?;
 
         // Save the new credential for this user
-        self.save_credential(target_user_id, credential).await?;
+        self.add_credential_in(&mut *tx, target_user_id, credential)
+            .await?;
 
         // Mark the account link code as used
-        self.use_invite_code(account_link_code, target_user_id)
+        if !self
+            .use_invite_code_in(&mut *tx, account_link_code, target_user_id)
+            .await?
+        {
+            return Err(AuthError::InviteCodeAlreadyUsed);
+        }
+
+        tx.commit().await?;
+
+        Ok(user)
+    }
+
+    /// Register a new user together with their first passkey
+    ///
+    /// Creating the user, saving the credential and redeeming the invite code
+    /// either all succeed or all roll back. The invite code row is locked so two
+    /// concurrent registrations cannot both redeem the same code. The first user
+    /// ever registered is made an admin; registrations take turns deciding the
+    /// role, so two concurrent first registrations cannot both become admin.
+    pub async fn register_user(
+        &self,
+        username: &str,
+        invite_code: Option<&str>,
+        credential: &Passkey,
+    ) -> Result<User, AuthError> {
+        let mut tx = self.db.begin().await?;
+
+        if let Some(code) = invite_code {
+            let invite = self
+                .lock_invite_code_in(&mut tx, code)
+                .await?
+                .ok_or(AuthError::InvalidInviteCode)?;
+
+            if invite.is_account_link_code() {
+                return Err(AuthError::InvalidInviteCode);
+            }
+
+            if invite.used_at.is_some() {
+                return Err(AuthError::InviteCodeAlreadyUsed);
+            }
+
+            if !invite.is_valid_for_use() {
+                return Err(AuthError::InvalidInviteCode);
+            }
+        }
+
+        self.lock_registration_role_in(&mut tx).await?;
+        let role = if self.has_users_in(&mut *tx).await? {
+            UserRole::Member
+        } else {
+            UserRole::Admin
+        };
+
+        let user = self
+            .create_user_with_role_in(&mut *tx, username, invite_code, role)
             .await?;
 
+        self.add_credential_in(&mut *tx, user.id, credential)
+            .await?;
+
+        if let Some(code) = invite_code {
+            if !self.use_invite_code_in(&mut *tx, code, user.id).await? {
+                return Err(AuthError::InviteCodeAlreadyUsed);
+            }
+        }
+
+        tx.commit().await?;
+
         Ok(user)
     }
 
     /// Get a user by their ID
     pub async fn get_user_by_id(&self, user_id: Uuid) -> Result<Option<User>, AuthError> {
+        self.get_user_by_id_in(self.db.pool(), user_id).await
+    }
+
+    /// Get a user by their ID using the given executor
+    pub async fn get_user_by_id_in<'e, E>(
+        &self,
+        executor: E,
+        user_id: Uuid,
+    ) -> Result<Option<User>, AuthError>
+    where
+        E: PgExecutor<'e>,
+    {
         let row = sqlx::query!(
             r#"
             SELECT id, username, role, created_at, invite_code_used
@@ -140,7 +282,7 @@ impl<'a> AuthRepository<'a> {
             "#,
             user_id
         )
-        .fetch_optional(self.db.pool())
+        .fetch_optional(executor)
         .await?;
 
         Ok(row.map(|r| User {
@@ -203,6 +345,21 @@ impl<'a> AuthRepository<'a> {
         invite_code: Option<&str>,
         role: UserRole,
     ) -> Result<User, AuthError> {
+        self.create_user_with_role_in(self.db.pool(), username, invite_code, role)
+            .await
+    }
+
+    /// Create a new user account with a specific role using the given executor
+    pub async fn create_user_with_role_in<'e, E>(
+        &self,
+        executor: E,
+        username: &str,
+        invite_code: Option<&str>,
+        role: UserRole,
+    ) -> Result<User, AuthError>
+    where
+        E: PgExecutor<'e>,
+    {
         let role_str = match role {
             UserRole::Admin => "admin",
             UserRole::Member => "member",
@@ -218,8 +375,14 @@ impl<'a> AuthRepository<'a> {
             role_str,
             invite_code
         )
-        .fetch_one(self.db.pool())
-        .await?;
+        .fetch_one(executor)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(ref db_err) if db_err.is_unique_violation() => {
+                AuthError::UsernameAlreadyExists
+            }
+            e => AuthError::Database(e),
+        })?;
 
         let role = match row.role.as_str() {
             "admin" => UserRole::Admin,
@@ -288,6 +451,34 @@ impl<'a> AuthRepository<'a> {
         Ok(())
     }
 
+    /// Take the lock deciding a new user's role until the surrounding
+    /// transaction ends
+    ///
+    /// Concurrent registrations wait here until the one holding the lock has
+    /// committed its user, so only one of them sees no users yet.
+    pub async fn lock_registration_role_in(
+        &self,
+        conn: &mut PgConnection,
+    ) -> Result<(), AuthError> {
+        sqlx::query!("SELECT pg_advisory_xact_lock($1)", REGISTRATION_ROLE_LOCK)
+            .execute(conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Check whether any user account exists yet using the given executor
+    pub async fn has_users_in<'e, E>(&self, executor: E) -> Result<bool, AuthError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let exists = sqlx::query_scalar!(r#"SELECT EXISTS (SELECT 1 FROM users) AS "exists!""#)
+            .fetch_one(executor)
+            .await?;
+
+        Ok(exists)
+    }
+
     /// List all users (admin function)
     pub async fn list_users(&self) -> Result<Vec<User>, AuthError> {
         let rows = sqlx::query!(
@@ -324,6 +515,20 @@ impl<'a> AuthRepository<'a> {
 
     /// Save a WebAuthn credential for a user
     pub async fn save_credential(&self, user_id: Uuid, passkey: &Passkey) -> Result<(), AuthError> {
+        self.save_credential_in(self.db.pool(), user_id, passkey)
+            .await
+    }
+
+    /// Save a WebAuthn credential for a user using the given executor
+    pub async fn save_credential_in<'e, E>(
+        &self,
+        executor: E,
+        user_id: Uuid,
+        passkey: &Passkey,
+    ) -> Result<(), AuthError>
+    where
+        E: PgExecutor<'e>,
+    {
         let credential_id = passkey.cred_id().as_ref().to_vec();
         let credential_data = serde_json::to_string(passkey)?;
 
@@ -338,12 +543,49 @@ impl<'a> AuthRepository<'a> {
             credential_id,
             credential_data
         )
-        .execute(self.db.pool())
+        .execute(executor)
         .await?;
 
         Ok(())
     }
 
+    /// Add a newly registered WebAuthn credential for a user using the given executor
+    ///
+    /// Unlike [`Self::save_credential_in`], a credential ID that is already
+    /// stored is refused rather than overwritten.
+    pub async fn add_credential_in<'e, E>(
+        &self,
+        executor: E,
+        user_id: Uuid,
+        passkey: &Passkey,
+    ) -> Result<(), AuthError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let credential_id = passkey.cred_id().as_ref().to_vec();
+        let credential_data = serde_json::to_string(passkey)?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO webauthn_credentials (user_id, credential_id, credential_data)
+            VALUES ($1, $2, $3)
+            "#,
+            user_id,
+            credential_id,
+            credential_data
+        )
+        .execute(executor)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(ref db_err) if db_err.is_unique_violation() => {
+                AuthError::CredentialAlreadyRegistered
+            }
+            e => AuthError::Database(e),
+        })?;
+
+        Ok(())
+    }
+
     /// Get all WebAuthn credentials for a user
     pub async fn get_user_credentials(&self, user_id: Uuid) -> Result<Vec<Passkey>, AuthError> {
         let rows = sqlx::query!(
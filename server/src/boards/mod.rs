@@ -0,0 +1,202 @@
+//! Collaborative drawing boards
+//!
+//! A board belongs to a room, and the room's members draw on it together
+//! over the WebSocket. Each change is a [`BoardOperation`]: adding, updating
+//! or deleting an element. The server numbers operations in the order it
+//! accepts them, stores them in the board's log and passes them on to every
+//! client with the board open, so all clients apply the same operations in
+//! the same order. [`BoardState`] describes how they merge.
+//!
+//! Every [`SNAPSHOT_INTERVAL`] operations the drawing is stored as a
+//! snapshot. Clients opening a board get the latest snapshot and replay the
+//! operations after it; clients reopening a board after a dropped connection
+//! can instead ask for just the operations they missed.
+
+pub mod models;
+pub mod repository;
+
+pub use models::{
+    Board, BoardElement, BoardOp, BoardOperation, BoardState, ElementKind, MAX_OP_SIZE,
+};
+pub use repository::{BoardError, BoardRepository};
+
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::auth::User;
+use crate::error::WebauthnError;
+use crate::rooms::{RoomRole, RoomService};
+
+/// Operations between snapshots of a board
+pub const SNAPSHOT_INTERVAL: i64 = 100;
+
+/// What happened to a board
+#[derive(Debug, Clone)]
+pub enum BoardEventKind {
+    /// The board was created
+    Created(Board),
+    /// An operation was applied
+    Op(BoardOp),
+    /// The board was deleted
+    Deleted,
+}
+
+/// A change to a board, as broadcast to connections
+#[derive(Debug, Clone)]
+pub struct BoardEvent {
+    pub board_id: Uuid,
+    pub room_id: Uuid,
+    /// Connection that caused the event and was answered directly
+    pub origin: Option<String>,
+    pub kind: BoardEventKind,
+}
+
+/// What a client needs to draw a board it opens
+#[derive(Debug, Clone)]
+pub struct BoardReplay {
+    pub board: Board,
+    /// The drawing to start from; `None` when the client catches up on the
+    /// drawing it already has
+    pub snapshot: Option<BoardState>,
+    /// Operations to apply after the snapshot, in order
+    pub ops: Vec<BoardOp>,
+}
+
+/// Board service combining repository operations with room access rules
+///
+/// Boards are for the members of their room, with the same rules as the
+/// room's messages. Any member may create boards and draw; boards are
+/// deleted by their creator or the room's moderators and owner.
+pub struct BoardService<'a> {
+    repository: BoardRepository<'a>,
+    rooms: RoomService<'a>,
+}
+
+impl<'a> BoardService<'a> {
+    /// Create a service; the room service checks membership
+    pub fn new(repository: BoardRepository<'a>, rooms: RoomService<'a>) -> Self {
+        Self { repository, rooms }
+    }
+
+    /// Boards in a room the viewer is in, by name
+    pub async fn list(&self, room_id: Uuid, viewer: &User) -> Result<Vec<Board>, WebauthnError> {
+        self.rooms.acting_role(room_id, viewer).await?;
+        self.repository.list(room_id).await
+    }
+
+    /// Create a board in a room the viewer is in
+    pub async fn create(
+        &self,
+        room_id: Uuid,
+        name: &str,
+        viewer: &User,
+    ) -> Result<Board, WebauthnError> {
+        self.rooms.acting_role(room_id, viewer).await?;
+        let name = Board::normalize_name(name).map_err(BoardError::Validation)?;
+        self.repository.create(room_id, &name, viewer.id).await
+    }
+
+    /// A board in a room the viewer is in, with their role there
+    pub async fn get(&self, id: Uuid, viewer: &User) -> Result<(Board, RoomRole), WebauthnError> {
+        let board = self
+            .repository
+            .find(id)
+            .await?
+            .ok_or(BoardError::NotFound)?;
+        let role = self.rooms.acting_role(board.room_id, viewer).await?;
+        Ok((board, role))
+    }
+
+    /// Delete a board; its creator and the room's moderators may
+    pub async fn delete(&self, id: Uuid, viewer: &User) -> Result<Board, WebauthnError> {
+        let (board, role) = self.get(id, viewer).await?;
+        if board.created_by != Some(viewer.id) && role < RoomRole::Moderator {
+            return Err(BoardError::Forbidden.into());
+        }
+
+        if !self.repository.delete(id).await? {
+            return Err(BoardError::NotFound.into());
+        }
+        Ok(board)
+    }
+
+    /// Open a board for drawing
+    ///
+    /// With `since`, the sequence number of the last operation the client
+    /// applied, only later operations are sent, unless a newer snapshot
+    /// makes that the longer way round.
+    pub async fn open(
+        &self,
+        id: Uuid,
+        since: Option<i64>,
+        viewer: &User,
+    ) -> Result<BoardReplay, WebauthnError> {
+        let (board, _) = self.get(id, viewer).await?;
+        let snapshot = self.repository.latest_snapshot(id).await?;
+        let snapshot_seq = snapshot.as_ref().map_or(0, |snapshot| snapshot.seq);
+
+        let (snapshot, after) = match since {
+            Some(since) if since >= snapshot_seq && since <= board.seq => (None, since),
+            _ => (Some(snapshot.unwrap_or_default()), snapshot_seq),
+        };
+        let ops = self.repository.ops_after(id, after).await?;
+
+        Ok(BoardReplay {
+            board,
+            snapshot,
+            ops,
+        })
+    }
+
+    /// Apply an operation to a board, returning it with its sequence number
+    ///
+    /// Operations resent with a `client_op_id` the viewer already used are
+    /// not applied again; the flag is then false.
+    pub async fn apply(
+        &self,
+        id: Uuid,
+        client_op_id: Option<&str>,
+        op: &BoardOperation,
+        viewer: &User,
+    ) -> Result<(BoardOp, bool), WebauthnError> {
+        op.validate().map_err(BoardError::Validation)?;
+        if let Some(client_op_id) = client_op_id {
+            BoardOperation::check_client_op_id(client_op_id).map_err(BoardError::Validation)?;
+        }
+        self.get(id, viewer).await?;
+
+        let (op, applied) = self
+            .repository
+            .append(id, viewer.id, client_op_id, op)
+            .await?;
+        if applied && op.seq % SNAPSHOT_INTERVAL == 0 {
+            // The operation is in the log either way; a later snapshot
+            // covers it if this one fails
+            if let Err(e) = self.snapshot(id).await {
+                warn!("Failed to snapshot board {}: {}", id, e);
+            }
+        }
+        Ok((op, applied))
+    }
+
+    /// Store the board's current drawing as a snapshot
+    pub async fn snapshot(&self, id: Uuid) -> Result<BoardState, WebauthnError> {
+        let mut state = self
+            .repository
+            .latest_snapshot(id)
+            .await?
+            .unwrap_or_default();
+        for op in self.repository.ops_after(id, state.seq).await? {
+            state.apply(&op);
+        }
+
+        self.repository.save_snapshot(id, &state).await?;
+        info!(
+            "Board {} snapshot at {} with {} elements",
+            id,
+            state.seq,
+            state.elements.len()
+        );
+        Ok(state)
+    }
+}
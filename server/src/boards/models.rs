@@ -0,0 +1,313 @@
+//! Board data models and the drawing document they share
+
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+/// Longest board name, in characters
+pub const MAX_BOARD_NAME_LENGTH: usize = 100;
+
+/// Longest client operation ID, in characters
+pub const MAX_CLIENT_OP_ID_LENGTH: usize = 64;
+
+/// Largest operation, in bytes of JSON
+pub const MAX_OP_SIZE: usize = 64 * 1024;
+
+/// A shared drawing board in a room
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Board {
+    pub id: Uuid,
+    pub room_id: Uuid,
+    pub name: String,
+    pub created_by: Option<Uuid>,
+    /// Sequence number of the latest operation; 0 for an empty board
+    pub seq: i64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub updated_at: OffsetDateTime,
+}
+
+impl Board {
+    /// Trim a board name and check its length
+    pub fn normalize_name(name: &str) -> Result<String, String> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err("Board name must not be empty".to_string());
+        }
+        if name.chars().count() > MAX_BOARD_NAME_LENGTH {
+            return Err(format!(
+                "Board name must be at most {} characters long",
+                MAX_BOARD_NAME_LENGTH
+            ));
+        }
+        Ok(name.to_string())
+    }
+}
+
+/// What an element on a board is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ElementKind {
+    /// A freehand stroke through a list of points
+    Stroke,
+    Line,
+    Rect,
+    Ellipse,
+    Text,
+}
+
+/// An element drawn on a board
+///
+/// Clients choose the ID, so they can draw without waiting for the server.
+/// `props` holds the geometry and style, such as points, position, size and
+/// colour; the server does not interpret it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BoardElement {
+    pub id: Uuid,
+    pub kind: ElementKind,
+    #[serde(default)]
+    pub props: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A change to a board's drawing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BoardOperation {
+    /// Draw a new element on top of the others
+    Add { element: BoardElement },
+    /// Set some of an element's props; `null` removes a prop
+    Update {
+        id: Uuid,
+        props: serde_json::Map<String, serde_json::Value>,
+    },
+    /// Remove an element for good
+    Delete { id: Uuid },
+}
+
+impl BoardOperation {
+    /// Check an operation is well-formed and not too large
+    pub fn validate(&self) -> Result<(), String> {
+        if let BoardOperation::Update { props, .. } = self {
+            if props.is_empty() {
+                return Err("Updates must set at least one prop".to_string());
+            }
+        }
+
+        let size = serde_json::to_vec(self).map(|json| json.len()).unwrap_or(0);
+        if size > MAX_OP_SIZE {
+            return Err(format!(
+                "Operations must be at most {} bytes of JSON",
+                MAX_OP_SIZE
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check a client's ID for an operation
+    pub fn check_client_op_id(client_op_id: &str) -> Result<(), String> {
+        if client_op_id.is_empty() || client_op_id.chars().count() > MAX_CLIENT_OP_ID_LENGTH {
+            return Err(format!(
+                "Client operation IDs must be 1 to {} characters long",
+                MAX_CLIENT_OP_ID_LENGTH
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// An operation applied to a board, numbered in the order it was accepted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BoardOp {
+    pub board_id: Uuid,
+    pub seq: i64,
+    /// `None` once the author's account is deleted
+    pub author_user_id: Option<Uuid>,
+    /// The ID the client gave the operation, if any
+    pub client_op_id: Option<String>,
+    pub op: BoardOperation,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+/// A board's drawing as of an operation
+///
+/// Applying the same operations in sequence order always gives the same
+/// drawing, whatever order clients made them in:
+///
+/// - elements are named by client-chosen IDs, so adds never clash, and an
+///   ID is only ever added once;
+/// - updates merge prop by prop, so concurrent edits of different props
+///   both apply, and the later edit of the same prop wins;
+/// - deletes leave a tombstone, so an update or re-add of a deleted element
+///   that raced the delete is dropped.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BoardState {
+    /// Sequence number of the last operation applied
+    pub seq: i64,
+    /// Elements from bottom to top
+    pub elements: Vec<BoardElement>,
+    /// IDs of deleted elements
+    #[serde(default)]
+    pub deleted: BTreeSet<Uuid>,
+}
+
+impl BoardState {
+    /// Apply an operation; false if it was already applied or changed nothing
+    pub fn apply(&mut self, op: &BoardOp) -> bool {
+        if op.seq <= self.seq {
+            return false;
+        }
+        self.seq = op.seq;
+
+        match &op.op {
+            BoardOperation::Add { element } => {
+                if self.deleted.contains(&element.id) || self.position(element.id).is_some() {
+                    return false;
+                }
+                self.elements.push(element.clone());
+                true
+            }
+            BoardOperation::Update { id, props } => {
+                let Some(index) = self.position(*id) else {
+                    return false;
+                };
+                let element = &mut self.elements[index];
+                for (key, value) in props {
+                    if value.is_null() {
+                        element.props.remove(key);
+                    } else {
+                        element.props.insert(key.clone(), value.clone());
+                    }
+                }
+                true
+            }
+            BoardOperation::Delete { id } => {
+                self.deleted.insert(*id);
+                match self.position(*id) {
+                    Some(index) => {
+                        self.elements.remove(index);
+                        true
+                    }
+                    None => false,
+                }
+            }
+        }
+    }
+
+    fn position(&self, id: Uuid) -> Option<usize> {
+        self.elements.iter().position(|element| element.id == id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn op(seq: i64, op: BoardOperation) -> BoardOp {
+        BoardOp {
+            board_id: Uuid::nil(),
+            seq,
+            author_user_id: None,
+            client_op_id: None,
+            op,
+            created_at: OffsetDateTime::now_utc(),
+        }
+    }
+
+    fn props(value: serde_json::Value) -> serde_json::Map<String, serde_json::Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_operations_parse() {
+        let parsed: BoardOperation = serde_json::from_value(json!({
+            "op": "add",
+            "element": {
+                "id": Uuid::nil(),
+                "kind": "stroke",
+                "props": { "points": [[0, 0], [4, 2]], "color": "#000" }
+            }
+        }))
+        .unwrap();
+        assert!(
+            matches!(parsed, BoardOperation::Add { ref element } if element.kind == ElementKind::Stroke)
+        );
+        assert!(parsed.validate().is_ok());
+
+        let empty = BoardOperation::Update {
+            id: Uuid::nil(),
+            props: serde_json::Map::new(),
+        };
+        assert!(empty.validate().is_err());
+        assert!(BoardOperation::check_client_op_id("").is_err());
+        assert!(Board::normalize_name("  ").is_err());
+    }
+
+    #[test]
+    fn test_concurrent_edits_merge() {
+        let id = Uuid::new_v4();
+        let mut state = BoardState::default();
+        let add = BoardOperation::Add {
+            element: BoardElement {
+                id,
+                kind: ElementKind::Rect,
+                props: props(json!({ "x": 0, "y": 0, "color": "red" })),
+            },
+        };
+        assert!(state.apply(&op(1, add.clone())));
+
+        // Two clients edit different props, then the same one
+        state.apply(&op(
+            2,
+            BoardOperation::Update {
+                id,
+                props: props(json!({ "x": 10 })),
+            },
+        ));
+        state.apply(&op(
+            3,
+            BoardOperation::Update {
+                id,
+                props: props(json!({ "y": 5, "color": "blue" })),
+            },
+        ));
+        state.apply(&op(
+            4,
+            BoardOperation::Update {
+                id,
+                props: props(json!({ "color": "green", "label": null })),
+            },
+        ));
+        assert_eq!(
+            state.elements[0].props,
+            props(json!({ "x": 10, "y": 5, "color": "green" }))
+        );
+
+        // Operations already applied are skipped when replayed
+        assert!(!state.apply(&op(4, BoardOperation::Delete { id })));
+        assert_eq!(state.seq, 4);
+
+        // A delete wins over an update and a re-add that raced it
+        assert!(state.apply(&op(5, BoardOperation::Delete { id })));
+        assert!(!state.apply(&op(
+            6,
+            BoardOperation::Update {
+                id,
+                props: props(json!({ "x": 1 }))
+            }
+        )));
+        assert!(!state.apply(&op(7, add)));
+        assert!(state.elements.is_empty());
+        assert_eq!(state.seq, 7);
+
+        // The state survives a snapshot round trip
+        let snapshot: BoardState =
+            serde_json::from_value(serde_json::to_value(&state).unwrap()).unwrap();
+        assert_eq!(snapshot, state);
+    }
+}
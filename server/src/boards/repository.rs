@@ -0,0 +1,309 @@
+//! Board repository for database operations
+
+use sqlx::error::ErrorKind;
+use sqlx::types::Json;
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use crate::database::DatabaseConnection;
+use crate::error::WebauthnError;
+
+use super::models::{Board, BoardOp, BoardOperation, BoardState};
+
+#[derive(Debug, thiserror::Error)]
+pub enum BoardError {
+    #[error("Board not found")]
+    NotFound,
+    #[error("A board with this name already exists in the room")]
+    DuplicateName,
+    #[error("Validation error: {0}")]
+    Validation(String),
+    #[error("Access to board denied")]
+    Forbidden,
+}
+
+impl From<BoardError> for WebauthnError {
+    fn from(err: BoardError) -> Self {
+        match err {
+            BoardError::NotFound => WebauthnError::UserNotFound,
+            BoardError::DuplicateName => WebauthnError::BadRequest,
+            BoardError::Validation(_) => WebauthnError::BadRequest,
+            BoardError::Forbidden => WebauthnError::Forbidden,
+        }
+    }
+}
+
+/// Board repository for database operations
+pub struct BoardRepository<'a> {
+    db: &'a DatabaseConnection,
+}
+
+impl<'a> BoardRepository<'a> {
+    /// Create a new repository instance
+    pub fn new(db: &'a DatabaseConnection) -> Self {
+        Self { db }
+    }
+
+    /// Create an empty board; names are unique within a room
+    pub async fn create(
+        &self,
+        room_id: Uuid,
+        name: &str,
+        created_by: Uuid,
+    ) -> Result<Board, WebauthnError> {
+        info!("Creating board {:?} in room {}", name, room_id);
+
+        let board = sqlx::query_as!(
+            Board,
+            r#"
+            INSERT INTO boards (id, room_id, name, created_by)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id, room_id, name, created_by, seq, created_at, updated_at
+            "#,
+            Uuid::new_v4(),
+            room_id,
+            name,
+            created_by
+        )
+        .fetch_one(self.db.pool())
+        .await
+        .map_err(duplicate_name)?;
+
+        Ok(board)
+    }
+
+    /// Find a board by ID
+    pub async fn find(&self, id: Uuid) -> Result<Option<Board>, WebauthnError> {
+        let board = sqlx::query_as!(
+            Board,
+            r#"
+            SELECT id, room_id, name, created_by, seq, created_at, updated_at
+            FROM boards
+            WHERE id = $1
+            "#,
+            id
+        )
+        .fetch_optional(self.db.pool())
+        .await?;
+
+        Ok(board)
+    }
+
+    /// Boards in a room, by name
+    pub async fn list(&self, room_id: Uuid) -> Result<Vec<Board>, WebauthnError> {
+        let boards = sqlx::query_as!(
+            Board,
+            r#"
+            SELECT id, room_id, name, created_by, seq, created_at, updated_at
+            FROM boards
+            WHERE room_id = $1
+            ORDER BY name
+            "#,
+            room_id
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(boards)
+    }
+
+    /// Delete a board with its operations and snapshots
+    pub async fn delete(&self, id: Uuid) -> Result<bool, WebauthnError> {
+        info!("Deleting board: {}", id);
+
+        let result = sqlx::query!("DELETE FROM boards WHERE id = $1", id)
+            .execute(self.db.pool())
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Append an operation to a board's log under the next sequence number
+    ///
+    /// The board's row is locked meanwhile, so operations are numbered in
+    /// the order they commit. An operation whose `client_op_id` the author
+    /// already used is not applied again; the earlier one is returned, and
+    /// the flag is false.
+    pub async fn append(
+        &self,
+        board_id: Uuid,
+        author_user_id: Uuid,
+        client_op_id: Option<&str>,
+        op: &BoardOperation,
+    ) -> Result<(BoardOp, bool), WebauthnError> {
+        let mut tx = self.db.begin().await?;
+
+        let locked =
+            sqlx::query_scalar!("SELECT seq FROM boards WHERE id = $1 FOR UPDATE", board_id)
+                .fetch_optional(&mut *tx)
+                .await?;
+        if locked.is_none() {
+            return Err(BoardError::NotFound.into());
+        }
+
+        if let Some(client_op_id) = client_op_id {
+            let existing = sqlx::query!(
+                r#"
+                SELECT board_id, seq, author_user_id, client_op_id,
+                       op as "op: Json<BoardOperation>", created_at
+                FROM board_ops
+                WHERE board_id = $1 AND author_user_id = $2 AND client_op_id = $3
+                "#,
+                board_id,
+                author_user_id,
+                client_op_id
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+
+            if let Some(row) = existing {
+                debug!(
+                    "Board {} operation {:?} already applied as {}",
+                    board_id, client_op_id, row.seq
+                );
+                let op = BoardOp {
+                    board_id: row.board_id,
+                    seq: row.seq,
+                    author_user_id: row.author_user_id,
+                    client_op_id: row.client_op_id,
+                    op: row.op.0,
+                    created_at: row.created_at,
+                };
+                return Ok((op, false));
+            }
+        }
+
+        let seq = sqlx::query_scalar!(
+            "UPDATE boards SET seq = seq + 1, updated_at = now() WHERE id = $1 RETURNING seq",
+            board_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let created_at = sqlx::query_scalar!(
+            r#"
+            INSERT INTO board_ops (board_id, seq, author_user_id, client_op_id, op)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING created_at
+            "#,
+            board_id,
+            seq,
+            author_user_id,
+            client_op_id,
+            Json(op) as _
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok((
+            BoardOp {
+                board_id,
+                seq,
+                author_user_id: Some(author_user_id),
+                client_op_id: client_op_id.map(str::to_string),
+                op: op.clone(),
+                created_at,
+            },
+            true,
+        ))
+    }
+
+    /// Operations after sequence number `after`, in order
+    pub async fn ops_after(
+        &self,
+        board_id: Uuid,
+        after: i64,
+    ) -> Result<Vec<BoardOp>, WebauthnError> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT board_id, seq, author_user_id, client_op_id,
+                   op as "op: Json<BoardOperation>", created_at
+            FROM board_ops
+            WHERE board_id = $1 AND seq > $2
+            ORDER BY seq
+            "#,
+            board_id,
+            after
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| BoardOp {
+                board_id: row.board_id,
+                seq: row.seq,
+                author_user_id: row.author_user_id,
+                client_op_id: row.client_op_id,
+                op: row.op.0,
+                created_at: row.created_at,
+            })
+            .collect())
+    }
+
+    /// The newest snapshot of a board, if one was taken
+    pub async fn latest_snapshot(
+        &self,
+        board_id: Uuid,
+    ) -> Result<Option<BoardState>, WebauthnError> {
+        let state = sqlx::query_scalar!(
+            r#"
+            SELECT state as "state: Json<BoardState>"
+            FROM board_snapshots
+            WHERE board_id = $1
+            ORDER BY seq DESC
+            LIMIT 1
+            "#,
+            board_id
+        )
+        .fetch_optional(self.db.pool())
+        .await?;
+
+        Ok(state.map(|state| state.0))
+    }
+
+    /// Store a snapshot of a board, replacing older ones
+    pub async fn save_snapshot(
+        &self,
+        board_id: Uuid,
+        state: &BoardState,
+    ) -> Result<(), WebauthnError> {
+        let mut tx = self.db.begin().await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO board_snapshots (board_id, seq, state)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (board_id, seq) DO NOTHING
+            "#,
+            board_id,
+            state.seq,
+            Json(state) as _
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "DELETE FROM board_snapshots WHERE board_id = $1 AND seq < $2",
+            board_id,
+            state.seq
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        debug!("Board {} snapshot taken at {}", board_id, state.seq);
+        Ok(())
+    }
+}
+
+/// Report a board name taken by another board in the room
+fn duplicate_name(err: sqlx::Error) -> WebauthnError {
+    match &err {
+        sqlx::Error::Database(e) if e.kind() == ErrorKind::UniqueViolation => {
+            BoardError::DuplicateName.into()
+        }
+        _ => err.into(),
+    }
+}
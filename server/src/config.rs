@@ -3,20 +3,17 @@ use serde::{Deserialize, Serialize};
 use std::path::Path;
 use thiserror::Error;
 
+use crate::auth::UserRole;
+
 /// Storage backend configuration
-#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum StorageBackend {
+    #[default]
     Memory,
     Postgres,
 }
 
-impl Default for StorageBackend {
-    fn default() -> Self {
-        Self::Memory
-    }
-}
-
 #[derive(Error, Debug)]
 pub enum ConfigError {
     #[error("Config file not found: {0}")]
@@ -150,6 +147,11 @@ pub struct ServerConfig {
     /// Server port to bind to
     #[serde(default = "default_server_port")]
     pub port: u16,
+    /// Addresses of reverse proxies whose `X-Forwarded-For` and `X-Real-IP`
+    /// headers are trusted; requests from any other peer are attributed to
+    /// the peer itself
+    #[serde(default)]
+    pub trusted_proxies: Vec<std::net::IpAddr>,
 }
 
 /// Media and file upload configuration
@@ -161,6 +163,451 @@ pub struct MediaConfig {
     /// Maximum size for files stored on filesystem (in bytes)
     #[serde(default = "default_max_fs_file_size")]
     pub max_fs_file_size: u64,
+    /// Where media content is stored
+    #[serde(default)]
+    pub storage: MediaStorageConfig,
+    /// Resumable upload session settings
+    #[serde(default)]
+    pub upload_sessions: UploadSessionConfig,
+    /// Signed download link settings
+    #[serde(default)]
+    pub links: MediaLinksConfig,
+    /// Background processing of uploaded media
+    #[serde(default)]
+    pub processing: MediaProcessingConfig,
+    /// Content types accepted for uploads
+    #[serde(default)]
+    pub mime_types: MediaMimeTypesConfig,
+    /// Malware scanning of uploads
+    #[serde(default)]
+    pub scanning: MediaScanningConfig,
+    /// Storage quotas for uploaded media
+    #[serde(default)]
+    pub quotas: MediaQuotaConfig,
+    /// Scheduled consistency checks of stored media
+    #[serde(default)]
+    pub fsck: MediaFsckConfig,
+    /// Trash window and retention policies
+    #[serde(default)]
+    pub retention: MediaRetentionConfig,
+}
+
+/// Trash and retention of uploaded media
+///
+/// Deleted blobs stay in the trash for `trash_days`, then are purged with
+/// their stored bytes. Retention policies purge blobs once they are older
+/// than the longest retention that applies to them; collections can set a
+/// retention of their own.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MediaRetentionConfig {
+    /// Days deleted blobs can be restored before they are purged
+    #[serde(default = "default_trash_days")]
+    pub trash_days: u32,
+    /// Purge expired trash and blobs in the background
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Minutes between purges
+    #[serde(default = "default_retention_interval_minutes")]
+    pub interval_minutes: u64,
+    /// Retention by MIME type
+    #[serde(default)]
+    pub mime_types: Vec<MediaRetentionRule>,
+}
+
+impl Default for MediaRetentionConfig {
+    fn default() -> Self {
+        Self {
+            trash_days: default_trash_days(),
+            enabled: true,
+            interval_minutes: default_retention_interval_minutes(),
+            mime_types: Vec::new(),
+        }
+    }
+}
+
+/// How long blobs of some MIME types are kept
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MediaRetentionRule {
+    /// An exact type, `type/*` or `*`
+    pub pattern: String,
+    /// Days blobs are kept after upload
+    pub days: u32,
+}
+
+/// Scheduled consistency checks between stored files and media records
+///
+/// The same check can be run by hand with `cli media fsck`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MediaFsckConfig {
+    /// Run the check periodically in the background
+    #[serde(default)]
+    pub enabled: bool,
+    /// Hours between checks
+    #[serde(default = "default_fsck_interval_hours")]
+    pub interval_hours: u64,
+    /// Read all stored content back and verify its SHA256
+    #[serde(default)]
+    pub verify_hashes: bool,
+    /// Fix problems found instead of only reporting them
+    #[serde(default)]
+    pub repair: bool,
+    /// Files without a record are only treated as orphans once they are this
+    /// old, so uploads in progress are left alone
+    #[serde(default = "default_fsck_min_orphan_age_seconds")]
+    pub min_orphan_age_seconds: u64,
+}
+
+impl Default for MediaFsckConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_hours: default_fsck_interval_hours(),
+            verify_hashes: false,
+            repair: false,
+            min_orphan_age_seconds: default_fsck_min_orphan_age_seconds(),
+        }
+    }
+}
+
+/// Storage quotas per role
+///
+/// A quota caps the total size of the media a user has uploaded. Quotas set
+/// for individual users override these; unset means unlimited.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct MediaQuotaConfig {
+    /// Bytes each member may store
+    #[serde(default)]
+    pub member_bytes: Option<u64>,
+    /// Bytes each admin may store
+    #[serde(default)]
+    pub admin_bytes: Option<u64>,
+}
+
+impl MediaQuotaConfig {
+    /// Quota for users with `role`, in bytes
+    pub fn for_role(&self, role: UserRole) -> Option<u64> {
+        match role {
+            UserRole::Admin => self.admin_bytes,
+            UserRole::Member => self.member_bytes,
+        }
+    }
+}
+
+/// Content types accepted for uploads, per upload path
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct MediaMimeTypesConfig {
+    /// Multipart and resumable uploads over HTTP
+    #[serde(default)]
+    pub http: MimeTypeRules,
+    /// Blobs uploaded over the WebSocket
+    #[serde(default)]
+    pub websocket: MimeTypeRules,
+}
+
+/// Allow and deny lists of MIME types
+///
+/// Entries are exact types, `type/*` or `*`. An empty allow list allows every
+/// type; the deny list wins over the allow list.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct MimeTypeRules {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl MimeTypeRules {
+    /// Check whether `mime` may be uploaded
+    pub fn permits(&self, mime: &str) -> bool {
+        !self.denies(mime)
+            && (self.allow.is_empty() || self.allow.iter().any(|p| mime_pattern_matches(p, mime)))
+    }
+
+    /// Check whether `mime` is on the deny list
+    pub fn denies(&self, mime: &str) -> bool {
+        self.deny.iter().any(|p| mime_pattern_matches(p, mime))
+    }
+
+    /// Entries that are not a MIME type or pattern
+    fn invalid_patterns(&self) -> impl Iterator<Item = &String> {
+        self.allow
+            .iter()
+            .chain(&self.deny)
+            .filter(|pattern| !is_mime_pattern(pattern))
+    }
+}
+
+/// Whether `pattern` is an exact MIME type, `type/*` or `*`
+fn is_mime_pattern(pattern: &str) -> bool {
+    pattern == "*"
+        || pattern
+            .split_once('/')
+            .is_some_and(|(kind, sub)| !kind.is_empty() && kind != "*" && !sub.is_empty())
+}
+
+fn mime_pattern_matches(pattern: &str, mime: &str) -> bool {
+    let pattern = pattern.trim().to_ascii_lowercase();
+    let mime = mime
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+
+    match pattern.strip_suffix("/*") {
+        _ if pattern == "*" => true,
+        Some(kind) => mime
+            .split_once('/')
+            .is_some_and(|(mime_kind, _)| mime_kind == kind),
+        None => pattern == mime,
+    }
+}
+
+/// Background media processing configuration
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MediaProcessingConfig {
+    /// Queue jobs for new uploads and run the background worker
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Seconds between checks for new jobs
+    #[serde(default = "default_processing_poll_interval_seconds")]
+    pub poll_interval_seconds: u64,
+    /// Jobs claimed per check
+    #[serde(default = "default_processing_batch_size")]
+    pub batch_size: u32,
+    /// Attempts before a job is marked as failed
+    #[serde(default = "default_processing_max_attempts")]
+    pub max_attempts: u32,
+    /// Larger files are not decoded
+    #[serde(default = "default_processing_max_source_size")]
+    pub max_source_size: u64,
+    /// Edge lengths of the square boxes image thumbnails are fitted into
+    #[serde(default = "default_thumbnail_sizes")]
+    pub thumbnail_sizes: Vec<u32>,
+}
+
+impl Default for MediaProcessingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            poll_interval_seconds: default_processing_poll_interval_seconds(),
+            batch_size: default_processing_batch_size(),
+            max_attempts: default_processing_max_attempts(),
+            max_source_size: default_processing_max_source_size(),
+            thumbnail_sizes: default_thumbnail_sizes(),
+        }
+    }
+}
+
+/// Malware scanning configuration
+///
+/// While enabled, new uploads are quarantined until the scanner has checked
+/// them; scans run on the background processing worker.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct MediaScanningConfig {
+    /// Quarantine and scan new uploads
+    #[serde(default)]
+    pub enabled: bool,
+    /// Scanner content is handed to
+    #[serde(default)]
+    pub scanner: MediaScannerKind,
+    /// Connection settings for the clamd scanner
+    #[serde(default)]
+    pub clamd: ClamdScannerConfig,
+    /// Program run by the command scanner
+    #[serde(default)]
+    pub command: Option<CommandScannerConfig>,
+}
+
+/// Malware scanner implementation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaScannerKind {
+    /// A ClamAV daemon reached over TCP
+    #[default]
+    Clamd,
+    /// An external program run once per file, such as `clamscan`
+    Command,
+    /// Only detects the EICAR test file; for tests and trying out quarantine
+    Eicar,
+}
+
+/// ClamAV daemon connection settings
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ClamdScannerConfig {
+    /// Address clamd listens on (its `TCPAddr` and `TCPSocket`)
+    #[serde(default = "default_clamd_address")]
+    pub address: String,
+    /// Seconds a single scan may take, including the transfer
+    #[serde(default = "default_scan_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+impl Default for ClamdScannerConfig {
+    fn default() -> Self {
+        Self {
+            address: default_clamd_address(),
+            timeout_seconds: default_scan_timeout_seconds(),
+        }
+    }
+}
+
+/// External scanner program settings
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct CommandScannerConfig {
+    /// Program to run
+    pub program: String,
+    /// Arguments; `{path}` is replaced by the file to scan, which is appended
+    /// if no argument mentions it
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Exit codes meaning malware was found; `0` means clean and anything
+    /// else is an error
+    #[serde(default = "default_infected_exit_codes")]
+    pub infected_exit_codes: Vec<i32>,
+    /// Seconds a single scan may take
+    #[serde(default = "default_scan_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+/// Signed, expiring download link configuration
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MediaLinksConfig {
+    /// Key links are signed with; a random key is generated at startup if
+    /// unset, which invalidates all links on restart
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Lifetime of a link when the request does not specify one
+    #[serde(default = "default_link_expiry_seconds")]
+    pub default_expiry_seconds: u64,
+    /// Longest lifetime a link may be given
+    #[serde(default = "default_link_max_expiry_seconds")]
+    pub max_expiry_seconds: u64,
+    /// Seconds after its first use during which a single-use link still
+    /// answers range requests, so players can seek and resume
+    #[serde(default = "default_link_single_use_grace_seconds")]
+    pub single_use_grace_seconds: u64,
+}
+
+impl Default for MediaLinksConfig {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            default_expiry_seconds: default_link_expiry_seconds(),
+            max_expiry_seconds: default_link_max_expiry_seconds(),
+            single_use_grace_seconds: default_link_single_use_grace_seconds(),
+        }
+    }
+}
+
+/// Resumable upload session configuration
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct UploadSessionConfig {
+    /// Seconds without activity after which an upload session expires
+    #[serde(default = "default_upload_session_expiry_seconds")]
+    pub expiry_seconds: u64,
+    /// Seconds between sweeps removing expired sessions and their partial files
+    #[serde(default = "default_upload_session_cleanup_interval_seconds")]
+    pub cleanup_interval_seconds: u64,
+}
+
+impl Default for UploadSessionConfig {
+    fn default() -> Self {
+        Self {
+            expiry_seconds: default_upload_session_expiry_seconds(),
+            cleanup_interval_seconds: default_upload_session_cleanup_interval_seconds(),
+        }
+    }
+}
+
+/// Media content storage backend
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaStorageBackend {
+    /// Content stored in PostgreSQL
+    Database,
+    /// Content stored in sharded directories on the local filesystem
+    #[default]
+    Filesystem,
+    /// Content stored in an S3-compatible object store
+    S3,
+}
+
+impl MediaStorageBackend {
+    /// Name of this backend as recorded in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaStorageBackend::Database => "database",
+            MediaStorageBackend::Filesystem => "filesystem",
+            MediaStorageBackend::S3 => "s3",
+        }
+    }
+}
+
+impl std::fmt::Display for MediaStorageBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for MediaStorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "database" => Ok(MediaStorageBackend::Database),
+            "filesystem" => Ok(MediaStorageBackend::Filesystem),
+            "s3" => Ok(MediaStorageBackend::S3),
+            _ => Err(format!("Invalid media storage backend: {}", s)),
+        }
+    }
+}
+
+/// Media content storage configuration
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MediaStorageConfig {
+    /// Backend new content is written to
+    #[serde(default)]
+    pub backend: MediaStorageBackend,
+    /// Root directory for the filesystem backend
+    #[serde(default = "default_media_storage_directory")]
+    pub directory: String,
+    /// Connection settings for the S3 backend
+    #[serde(default)]
+    pub s3: Option<S3StorageConfig>,
+}
+
+impl Default for MediaStorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: MediaStorageBackend::default(),
+            directory: default_media_storage_directory(),
+            s3: None,
+        }
+    }
+}
+
+/// S3-compatible object storage configuration
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct S3StorageConfig {
+    /// Endpoint URL, e.g. https://s3.us-east-1.amazonaws.com or http://localhost:9000;
+    /// a path on it is kept in front of the bucket and key
+    pub endpoint: String,
+    /// Bucket holding media content
+    pub bucket: String,
+    /// Region used for request signing
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    /// Access key ID
+    pub access_key_id: String,
+    /// Secret access key
+    pub secret_access_key: String,
+    /// Prefix prepended to every object key
+    #[serde(default)]
+    pub prefix: String,
+    /// Address buckets as endpoint/bucket rather than bucket.endpoint (needed for MinIO)
+    #[serde(default = "default_true")]
+    pub path_style: bool,
 }
 
 /// Session configuration
@@ -362,6 +809,82 @@ fn default_max_fs_file_size() -> u64 {
     1024 * 1024 * 1024 // 1GB
 }
 
+fn default_media_storage_directory() -> String {
+    "storage/media".to_string()
+}
+
+fn default_upload_session_expiry_seconds() -> u64 {
+    24 * 60 * 60 // 1 day
+}
+
+fn default_upload_session_cleanup_interval_seconds() -> u64 {
+    15 * 60 // 15 minutes
+}
+
+fn default_link_expiry_seconds() -> u64 {
+    60 * 60 // 1 hour
+}
+
+fn default_link_single_use_grace_seconds() -> u64 {
+    5 * 60 // 5 minutes
+}
+
+fn default_link_max_expiry_seconds() -> u64 {
+    7 * 24 * 60 * 60 // 1 week
+}
+
+fn default_clamd_address() -> String {
+    "127.0.0.1:3310".to_string()
+}
+
+fn default_scan_timeout_seconds() -> u64 {
+    120
+}
+
+fn default_infected_exit_codes() -> Vec<i32> {
+    vec![1] // clamscan's "virus found"
+}
+
+fn default_processing_poll_interval_seconds() -> u64 {
+    5
+}
+
+fn default_processing_batch_size() -> u32 {
+    4
+}
+
+fn default_processing_max_attempts() -> u32 {
+    3
+}
+
+fn default_trash_days() -> u32 {
+    30
+}
+
+fn default_retention_interval_minutes() -> u64 {
+    60
+}
+
+fn default_fsck_interval_hours() -> u64 {
+    24
+}
+
+fn default_fsck_min_orphan_age_seconds() -> u64 {
+    3600
+}
+
+fn default_processing_max_source_size() -> u64 {
+    64 * 1024 * 1024 // 64MB
+}
+
+fn default_thumbnail_sizes() -> Vec<u32> {
+    vec![256, 1024]
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
 fn default_session_same_site() -> String {
     "strict".to_string()
 }
@@ -467,6 +990,7 @@ impl AppConfig {
             server: ServerConfig {
                 host: default_server_host(),
                 port: default_server_port(),
+                trusted_proxies: Vec::new(),
             },
             sessions: SessionConfig {
                 max_age_seconds: default_session_max_age(),
@@ -501,6 +1025,15 @@ impl AppConfig {
             media: MediaConfig {
                 max_blob_file_size: default_max_blob_file_size(),
                 max_fs_file_size: default_max_fs_file_size(),
+                storage: MediaStorageConfig::default(),
+                upload_sessions: UploadSessionConfig::default(),
+                links: MediaLinksConfig::default(),
+                processing: MediaProcessingConfig::default(),
+                mime_types: MediaMimeTypesConfig::default(),
+                scanning: MediaScanningConfig::default(),
+                quotas: MediaQuotaConfig::default(),
+                fsck: MediaFsckConfig::default(),
+                retention: MediaRetentionConfig::default(),
             },
             development: DevelopmentConfig {
                 auto_generate_invites: false,
@@ -572,6 +1105,92 @@ impl AppConfig {
             );
         }
 
+        if self.media.storage.backend == MediaStorageBackend::S3 && self.media.storage.s3.is_none()
+        {
+            errors.push("S3 media storage requires media.storage.s3 settings".to_string());
+        }
+
+        if let Some(secret) = &self.media.links.secret {
+            if secret.len() < 32 {
+                errors.push("Media link secret must be at least 32 characters".to_string());
+            }
+        }
+
+        // Content is only served through the media API and signed links,
+        // never by the static file server
+        let static_files = &self.static_files;
+        if [
+            &static_files.assets_directory,
+            &static_files.public_directory,
+            &static_files.private_directory,
+        ]
+        .iter()
+        .any(|dir| is_within(&self.media.storage.directory, dir))
+        {
+            errors.push(
+                "Media storage directory must be outside the static file directories".to_string(),
+            );
+        }
+
+        if self.media.links.default_expiry_seconds > self.media.links.max_expiry_seconds {
+            errors.push(
+                "Media link default_expiry_seconds cannot exceed max_expiry_seconds".to_string(),
+            );
+        }
+
+        let processing = &self.media.processing;
+        if processing.batch_size == 0 || processing.max_attempts == 0 {
+            errors.push(
+                "Media processing batch_size and max_attempts must be at least 1".to_string(),
+            );
+        }
+
+        if processing
+            .thumbnail_sizes
+            .iter()
+            .any(|size| !(16..=4096).contains(size))
+        {
+            errors.push("Media thumbnail sizes must be between 16 and 4096 pixels".to_string());
+        }
+
+        let mime_types = &self.media.mime_types;
+        for pattern in mime_types
+            .http
+            .invalid_patterns()
+            .chain(mime_types.websocket.invalid_patterns())
+        {
+            errors.push(format!("Invalid media MIME type pattern: {}", pattern));
+        }
+
+        for rule in &self.media.retention.mime_types {
+            if !is_mime_pattern(&rule.pattern) {
+                errors.push(format!(
+                    "Invalid media retention MIME type pattern: {}",
+                    rule.pattern
+                ));
+            }
+            if rule.days == 0 {
+                errors.push(format!(
+                    "Media retention for {} must be at least one day",
+                    rule.pattern
+                ));
+            }
+        }
+
+        let scanning = &self.media.scanning;
+        if scanning.enabled {
+            match scanning.scanner {
+                MediaScannerKind::Command if scanning.command.is_none() => errors.push(
+                    "The command malware scanner requires media.scanning.command settings"
+                        .to_string(),
+                ),
+                MediaScannerKind::Clamd if scanning.clamd.address.is_empty() => {
+                    errors.push("The clamd malware scanner requires an address".to_string())
+                }
+                _ => {}
+            }
+        }
+
         if matches!(self.storage.analytics, StorageBackend::Postgres)
             && self.database.host.is_empty()
         {
@@ -633,6 +1252,7 @@ impl Default for AppConfig {
             server: ServerConfig {
                 host: default_server_host(),
                 port: default_server_port(),
+                trusted_proxies: Vec::new(),
             },
             sessions: SessionConfig {
                 max_age_seconds: default_session_max_age(),
@@ -661,6 +1281,15 @@ impl Default for AppConfig {
             media: MediaConfig {
                 max_blob_file_size: default_max_blob_file_size(),
                 max_fs_file_size: default_max_fs_file_size(),
+                storage: MediaStorageConfig::default(),
+                upload_sessions: UploadSessionConfig::default(),
+                links: MediaLinksConfig::default(),
+                processing: MediaProcessingConfig::default(),
+                mime_types: MediaMimeTypesConfig::default(),
+                scanning: MediaScanningConfig::default(),
+                quotas: MediaQuotaConfig::default(),
+                fsck: MediaFsckConfig::default(),
+                retention: MediaRetentionConfig::default(),
             },
             development: DevelopmentConfig {
                 auto_generate_invites: false,
@@ -674,6 +1303,17 @@ impl Default for AppConfig {
     }
 }
 
+/// Whether `path` is `dir` or below it, comparing the paths as written
+fn is_within(path: &str, dir: &str) -> bool {
+    fn components(path: &str) -> Vec<std::path::Component<'_>> {
+        Path::new(path)
+            .components()
+            .filter(|c| !matches!(c, std::path::Component::CurDir))
+            .collect()
+    }
+    components(path).starts_with(&components(dir))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -706,4 +1346,42 @@ mod tests {
             assert!(msg.contains("RP ID cannot be empty"));
         }
     }
+
+    #[test]
+    fn test_media_storage_outside_static_files() {
+        let mut config = AppConfig::default();
+        assert!(!is_within(
+            &config.media.storage.directory,
+            &config.static_files.assets_directory
+        ));
+
+        config.media.storage.directory = "./assets/private/uploads".to_string();
+        let result = config.validate();
+        assert!(
+            matches!(result, Err(ConfigError::ValidationError(msg)) if msg.contains("outside the static file directories"))
+        );
+
+        assert!(is_within("assets/private", "assets"));
+        assert!(!is_within("assets-media", "assets"));
+    }
+
+    #[test]
+    fn test_mime_type_rules() {
+        let rules = MimeTypeRules {
+            allow: vec!["image/*".to_string(), "application/pdf".to_string()],
+            deny: vec!["image/svg+xml".to_string()],
+        };
+        assert!(rules.permits("image/png"));
+        assert!(rules.permits("Application/PDF; version=1.7"));
+        assert!(!rules.permits("image/svg+xml"));
+        assert!(!rules.permits("video/mp4"));
+        assert!(MimeTypeRules::default().permits("video/mp4"));
+
+        let mut config = AppConfig::default();
+        config.media.mime_types.websocket.deny = vec!["*/png".to_string(), "video".to_string()];
+        let Err(ConfigError::ValidationError(msg)) = config.validate() else {
+            panic!("invalid MIME type patterns were accepted");
+        };
+        assert!(msg.contains("*/png") && msg.contains("video"));
+    }
 }
@@ -1,4 +1,4 @@
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, Transaction};
 
 /// Core database connection wrapper
 #[derive(Clone)]
@@ -17,6 +17,13 @@ impl DatabaseConnection {
         &self.pool
     }
 
+    /// Begin a new transaction on the pool
+    ///
+    /// The transaction rolls back when dropped unless `commit` is called.
+    pub async fn begin(&self) -> Result<Transaction<'static, Postgres>, sqlx::Error> {
+        self.pool.begin().await
+    }
+
     /// Simple migration status check
     /// For actual migrations, use sqlx-cli or the migration files directly
     pub async fn migrate(&self) -> Result<(), sqlx::Error> {
@@ -24,6 +24,22 @@ pub enum WebauthnError {
     DatabaseError,
     #[error("Bad Request")]
     BadRequest,
+    #[error("Forbidden")]
+    Forbidden,
+    #[error("Media Content Not Stored")]
+    MediaContentMissing,
+    #[error("Media Storage Error: {0}")]
+    MediaStorage(#[from] crate::media::BlobStorageError),
+    #[error("{0}")]
+    MediaContentType(#[from] crate::media::ContentTypeError),
+    #[error("Media Quarantined: {0}")]
+    MediaQuarantined(crate::media::ScanStatus),
+    #[error("Malware Scan Error: {0}")]
+    MediaScan(#[from] crate::media::ScanError),
+    #[error("{0}")]
+    MediaQuota(#[from] crate::media::QuotaExceeded),
+    #[error("{0}")]
+    MediaSearch(#[from] crate::media::SearchError),
     #[error("Deserialising Session failed: {0}")]
     InvalidSessionState(#[from] tower_sessions::session::Error),
     #[error("Database operation failed: {0}")]
@@ -53,6 +69,33 @@ impl IntoResponse for WebauthnError {
                 "Database operation failed",
             ),
             WebauthnError::BadRequest => (StatusCode::BAD_REQUEST, "Bad Request"),
+            WebauthnError::Forbidden => (StatusCode::FORBIDDEN, "Forbidden"),
+            WebauthnError::MediaContentMissing => (
+                StatusCode::NOT_FOUND,
+                "Media content not stored; upload the data",
+            ),
+            WebauthnError::MediaStorage(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "Media storage error")
+            }
+            WebauthnError::MediaQuarantined(crate::media::ScanStatus::Infected) => (
+                StatusCode::FORBIDDEN,
+                "Media was quarantined by the malware scanner",
+            ),
+            WebauthnError::MediaQuarantined(_) => {
+                (StatusCode::CONFLICT, "Media is waiting for a malware scan")
+            }
+            WebauthnError::MediaScan(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "Malware scan failed")
+            }
+            WebauthnError::MediaContentType(e) => {
+                return (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string()).into_response();
+            }
+            WebauthnError::MediaQuota(e) => {
+                return (StatusCode::INSUFFICIENT_STORAGE, e.to_string()).into_response();
+            }
+            WebauthnError::MediaSearch(e) => {
+                return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+            }
         };
 
         // its often easiest to implement `IntoResponse` by calling other implementations
@@ -72,6 +115,14 @@ pub enum AppError {
     NotFound(String),
     #[error("Conflict: {0}")]
     Conflict(String),
+    #[error("Gone: {0}")]
+    Gone(String),
+    #[error("Payload Too Large: {0}")]
+    PayloadTooLarge(String),
+    #[error("Unsupported Media Type: {0}")]
+    UnsupportedMediaType(String),
+    #[error("Insufficient Storage: {0}")]
+    InsufficientStorage(String),
     #[error("Internal Server Error: {0}")]
     InternalServerError(String),
     #[error("Database Error: {0}")]
@@ -88,6 +139,12 @@ impl IntoResponse for AppError {
             AppError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg.clone()),
             AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg.clone()),
             AppError::Conflict(msg) => (StatusCode::CONFLICT, msg.clone()),
+            AppError::Gone(msg) => (StatusCode::GONE, msg.clone()),
+            AppError::PayloadTooLarge(msg) => (StatusCode::PAYLOAD_TOO_LARGE, msg.clone()),
+            AppError::UnsupportedMediaType(msg) => {
+                (StatusCode::UNSUPPORTED_MEDIA_TYPE, msg.clone())
+            }
+            AppError::InsufficientStorage(msg) => (StatusCode::INSUFFICIENT_STORAGE, msg.clone()),
             AppError::InternalServerError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg.clone()),
             AppError::Database(_) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
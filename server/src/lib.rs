@@ -1,11 +1,13 @@
 pub mod analytics;
 pub mod auth;
+pub mod boards;
 pub mod config;
 pub mod database;
 pub mod error;
 pub mod health;
 pub mod logging;
 pub mod media;
+pub mod rooms;
 pub mod routes;
 pub mod startup;
 pub mod static_filez;
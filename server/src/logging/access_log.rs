@@ -89,6 +89,7 @@ impl AccessLogger {
     }
 
     /// Format a log entry based on the configured format
+    #[allow(clippy::too_many_arguments)]
     fn format_log_entry(
         &self,
         remote_addr: String,
@@ -171,7 +172,7 @@ pub async fn access_log_middleware(request: Request, next: Next) -> Response {
     // Get remote address - this is tricky with Axum as it depends on your deployment
     // You might need to configure this based on your setup (reverse proxy, etc.)
     let remote_addr =
-        extract_remote_addr(&headers, &request).unwrap_or_else(|| "unknown".to_string());
+        extract_remote_addr(headers, &request).unwrap_or_else(|| "unknown".to_string());
 
     // Process the request
     let response = next.run(request).await;
@@ -232,7 +233,7 @@ pub fn access_log_middleware_with_logger(
             let user_agent = extract_header_value(headers, "user-agent");
             let referer = extract_header_value(headers, "referer");
             let remote_addr =
-                extract_remote_addr(&headers, &request).unwrap_or_else(|| "unknown".to_string());
+                extract_remote_addr(headers, &request).unwrap_or_else(|| "unknown".to_string());
 
             // Process the request
             let response = next.run(request).await;
@@ -142,6 +142,43 @@ async fn main() {
         .await
         .expect("Failed to initialize app state");
 
+    // Remove abandoned resumable uploads in the background
+    server::upload::spawn_upload_session_cleanup(
+        app_state.database.clone(),
+        std::time::Duration::from_secs(
+            config.media.upload_sessions.cleanup_interval_seconds.max(1),
+        ),
+    );
+
+    // Analyse uploaded media, generate thumbnails and scan for malware in the background
+    if config.media.processing.enabled || config.media.scanning.enabled {
+        server::media::spawn_media_processor(
+            app_state.database.clone(),
+            app_state.media_storage.clone(),
+            config.media.clone(),
+        );
+    }
+
+    // Check stored media against the database on a schedule
+    if config.media.fsck.enabled {
+        server::media::spawn_media_fsck(
+            app_state.database.clone(),
+            app_state.media_storage.clone(),
+            server::media::FsckOptions::from_config(&config),
+            std::time::Duration::from_secs(config.media.fsck.interval_hours * 3600),
+        );
+    }
+
+    // Purge expired trash and media past its retention in the background
+    if config.media.retention.enabled {
+        server::media::spawn_media_retention(
+            app_state.database.clone(),
+            app_state.media_storage.clone(),
+            config.media.clone(),
+            app_state.media_events.clone(),
+        );
+    }
+
     // Get analytics service for middleware
     let analytics_service = app_state.analytics.clone();
 
@@ -203,6 +240,9 @@ async fn main() {
     let mut app = build_routes(&config)
         .layer(Extension(config.clone()))
         .layer(Extension(app_state.database.clone()))
+        .layer(Extension(app_state.media_storage.clone()))
+        .layer(Extension(app_state.media_links.clone()))
+        .layer(Extension(app_state.media_events.clone()))
         .layer(Extension(app_state.clone()))
         .layer(axum_middleware::from_fn(security_logging));
 
@@ -279,5 +319,11 @@ async fn main() {
         .await
         .expect("Unable to spawn tcp listener");
 
-    axum::serve(listener, app).await.unwrap();
+    // Peer addresses are needed to bind signed media links to a client
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
@@ -0,0 +1,767 @@
+//! Export and import of media blobs as tar archives
+//!
+//! An archive starts with `manifest.json`, describing every exported blob,
+//! followed by one `content/<sha256>` entry per distinct content. Both
+//! directions stream: content is copied between storage and the archive in
+//! chunks, so archives of any size never have to fit in memory. Importing
+//! verifies each content's SHA256, stores only content that is not stored
+//! yet and skips blobs whose owner already has the same content.
+
+use std::collections::{HashMap, HashSet};
+
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::io::StreamReader;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::auth::{AuthError, AuthRepository};
+use crate::config::MediaConfig;
+use crate::database::DatabaseConnection;
+use crate::error::WebauthnError;
+
+use super::models::{normalize_tags, CreateMediaBlob, MediaBlob, MediaBlobQuery, MediaVisibility};
+use super::storage::{BlobStorageError, MediaStorage, StagedFile, StagingError};
+use super::{MediaRepository, MediaService};
+
+/// Version of the manifest format written by this server
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Name of the manifest, the first entry of every archive
+const MANIFEST_NAME: &str = "manifest.json";
+
+/// Directory of the content entries, which are named by SHA256
+const CONTENT_PREFIX: &str = "content/";
+
+/// Largest manifest accepted on import
+const MAX_MANIFEST_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Blobs loaded per query while exporting
+const BATCH_SIZE: i64 = 1000;
+
+/// Size of a tar block; headers and padded content fill whole blocks
+const BLOCK_SIZE: usize = 512;
+
+/// Metadata the server derives from the content; background processing
+/// derives it again after an import
+const DERIVED_METADATA_KEYS: &[&str] = &["image", "thumbnails", "av", "scan"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid manifest: {0}")]
+    Manifest(#[from] serde_json::Error),
+    #[error("Invalid archive: {0}")]
+    Invalid(String),
+    #[error("Content {0} is not stored; run `media fsck`")]
+    ContentMissing(String),
+    #[error("No users named {}", .0.join(", "))]
+    UnknownOwners(Vec<String>),
+    #[error("Staging error: {0}")]
+    Staging(#[from] StagingError),
+    #[error("User lookup failed: {0}")]
+    Auth(#[from] AuthError),
+    #[error(transparent)]
+    Media(#[from] WebauthnError),
+}
+
+impl From<BlobStorageError> for ArchiveError {
+    fn from(err: BlobStorageError) -> Self {
+        ArchiveError::Media(err.into())
+    }
+}
+
+/// The first entry of an archive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaManifest {
+    pub version: u32,
+    #[serde(with = "time::serde::rfc3339")]
+    pub exported_at: OffsetDateTime,
+    pub blobs: Vec<ManifestBlob>,
+}
+
+/// An exported blob
+///
+/// Shares and collections are not exported, and imported blobs get new IDs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestBlob {
+    /// ID on the exporting server
+    pub id: Uuid,
+    pub sha256: String,
+    pub size: i64,
+    pub mime: Option<String>,
+    pub source_client_id: Option<String>,
+    /// Username of the owner; owners are matched by username on import
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub visibility: MediaVisibility,
+    #[serde(default)]
+    pub metadata: serde_json::Value,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+impl ManifestBlob {
+    fn new(blob: MediaBlob, owner: Option<String>) -> Self {
+        Self {
+            id: blob.id,
+            sha256: blob.sha256,
+            size: blob.size.unwrap_or_default(),
+            mime: blob.mime,
+            source_client_id: blob.source_client_id,
+            owner,
+            visibility: blob.visibility,
+            metadata: blob.metadata,
+            tags: blob.tags,
+            created_at: blob.created_at,
+        }
+    }
+
+    /// Parameters recreating the blob for `owner`, without derived metadata
+    fn create_params(&self, owner_user_id: Option<Uuid>) -> CreateMediaBlob {
+        let mut metadata = match &self.metadata {
+            serde_json::Value::Object(_) => self.metadata.clone(),
+            _ => serde_json::json!({}),
+        };
+        if let Some(map) = metadata.as_object_mut() {
+            for key in DERIVED_METADATA_KEYS {
+                map.remove(*key);
+            }
+        }
+
+        CreateMediaBlob {
+            data: None,
+            sha256: self.sha256.clone(),
+            size: Some(self.size),
+            mime: self.mime.clone(),
+            source_client_id: self.source_client_id.clone(),
+            owner_user_id,
+            visibility: self.visibility,
+            local_path: None,
+            metadata,
+        }
+    }
+}
+
+/// Outcome of an export
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExportReport {
+    /// Blobs listed in the manifest
+    pub blobs: u64,
+    /// Distinct contents written
+    pub contents: u64,
+    /// Bytes of content written
+    pub bytes: u64,
+}
+
+/// How to import an archive
+#[derive(Debug, Clone, Default)]
+pub struct ImportOptions {
+    /// Give every blob to this user instead of the owners in the manifest
+    pub owner_user_id: Option<Uuid>,
+}
+
+/// A blob that could not be imported
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportFailure {
+    /// ID on the exporting server
+    pub id: Uuid,
+    pub sha256: String,
+    pub reason: String,
+}
+
+/// Outcome of an import
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImportReport {
+    /// Blobs created
+    pub imported: u64,
+    /// Blobs skipped because their owner already has the content
+    pub skipped: u64,
+    /// Distinct contents stored; the rest were stored already
+    pub contents_stored: u64,
+    pub failed: Vec<ImportFailure>,
+}
+
+/// Write the blobs matching `query` and their content to a tar archive
+///
+/// `limit` and `offset` of the query are ignored; every matching blob is
+/// exported. Blobs in the trash and derived blobs such as thumbnails are
+/// not.
+pub async fn export_media<W: AsyncWrite + Unpin>(
+    db: &DatabaseConnection,
+    storage: &MediaStorage,
+    query: MediaBlobQuery,
+    writer: W,
+) -> Result<ExportReport, ArchiveError> {
+    let repository = MediaRepository::new(db);
+    let users = AuthRepository::new(db);
+    let mut owners: HashMap<Uuid, Option<String>> = HashMap::new();
+    let mut blobs = Vec::new();
+
+    loop {
+        let page = repository
+            .query(MediaBlobQuery {
+                limit: Some(BATCH_SIZE),
+                offset: Some(blobs.len() as i64),
+                ..query.clone()
+            })
+            .await?;
+        let last_page = (page.len() as i64) < BATCH_SIZE;
+
+        for blob in page {
+            let owner = match blob.owner_user_id {
+                Some(id) => match owners.get(&id) {
+                    Some(username) => username.clone(),
+                    None => {
+                        let username = users.get_user_by_id(id).await?.map(|user| user.username);
+                        owners.insert(id, username.clone());
+                        username
+                    }
+                },
+                None => None,
+            };
+            blobs.push(ManifestBlob::new(blob, owner));
+        }
+
+        if last_page {
+            break;
+        }
+    }
+
+    let manifest = MediaManifest {
+        version: MANIFEST_VERSION,
+        exported_at: OffsetDateTime::now_utc(),
+        blobs,
+    };
+    let mut archive = TarWriter::new(writer);
+    let json = serde_json::to_vec_pretty(&manifest)?;
+    archive
+        .append(MANIFEST_NAME, json.len() as u64, json.as_slice())
+        .await?;
+
+    let mut report = ExportReport {
+        blobs: manifest.blobs.len() as u64,
+        ..Default::default()
+    };
+    let mut written = HashSet::new();
+    for blob in &manifest.blobs {
+        if !written.insert(blob.sha256.as_str()) {
+            continue;
+        }
+
+        let object = repository
+            .find_object(&blob.sha256)
+            .await?
+            .ok_or_else(|| ArchiveError::ContentMissing(blob.sha256.clone()))?;
+        let backend = storage.backend(object.storage_backend)?;
+        let name = format!("{}{}", CONTENT_PREFIX, object.sha256);
+        let size = object.size as u64;
+
+        match backend.local_path(&object.storage_key) {
+            Some(path) => {
+                let file = match tokio::fs::File::open(&path).await {
+                    Ok(file) => file,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        return Err(ArchiveError::ContentMissing(object.sha256));
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                archive.append(&name, size, file).await?;
+            }
+            None => {
+                let content = backend
+                    .get_range(&object.storage_key, 0, size)
+                    .await?
+                    .ok_or_else(|| ArchiveError::ContentMissing(object.sha256.clone()))?;
+                let reader = StreamReader::new(content.map_err(std::io::Error::other));
+                archive.append(&name, size, reader).await?;
+            }
+        }
+
+        report.contents += 1;
+        report.bytes += size;
+    }
+
+    archive.finish().await?;
+    info!(
+        "Exported {} media blobs with {} contents ({} bytes)",
+        report.blobs, report.contents, report.bytes
+    );
+
+    Ok(report)
+}
+
+/// Create the blobs described by an archive written by [`export_media`]
+///
+/// Owners are matched by username unless `options` names one owner for
+/// everything; unknown usernames fail the import before anything is
+/// stored. Blobs that cannot be created, for example because content does
+/// not match its SHA256 or a quota is exceeded, are reported as failed
+/// while the others are imported.
+pub async fn import_media<R: AsyncRead + Unpin>(
+    db: &DatabaseConnection,
+    storage: &MediaStorage,
+    config: &MediaConfig,
+    reader: R,
+    options: &ImportOptions,
+) -> Result<ImportReport, ArchiveError> {
+    let repository = MediaRepository::new(db);
+    let service = MediaService::new(MediaRepository::new(db), storage);
+    let mut archive = TarReader::new(reader);
+    let mut report = ImportReport::default();
+
+    let manifest = read_manifest(&mut archive).await?;
+    let owners = resolve_owners(db, &manifest, options).await?;
+    let owner_of = |blob: &ManifestBlob| {
+        options.owner_user_id.or_else(|| {
+            blob.owner
+                .as_deref()
+                .and_then(|name| owners.get(name).copied())
+        })
+    };
+
+    // Blobs still to create, by content
+    let mut pending: HashMap<&str, Vec<&ManifestBlob>> = HashMap::new();
+    for blob in &manifest.blobs {
+        let existing = repository
+            .query(MediaBlobQuery {
+                sha256: Some(blob.sha256.clone()),
+                owner_user_id: owner_of(blob),
+                limit: Some(1),
+                ..Default::default()
+            })
+            .await?;
+        if existing.is_empty() {
+            pending.entry(blob.sha256.as_str()).or_default().push(blob);
+        } else {
+            report.skipped += 1;
+        }
+    }
+
+    let mut buffer = vec![0u8; 64 * 1024];
+    while let Some((name, size)) = archive.next_entry().await? {
+        let Some(sha256) = name.strip_prefix(CONTENT_PREFIX) else {
+            return Err(ArchiveError::Invalid(format!("unexpected entry {}", name)));
+        };
+        let Some(blobs) = pending.remove(sha256) else {
+            continue;
+        };
+
+        // Content stored already is not read again
+        let mut staged = None;
+        if repository.find_object(sha256).await?.is_none() {
+            let mut file = StagedFile::create(&storage.staging_directory(), size).await?;
+            loop {
+                let read = archive.read_chunk(&mut buffer).await?;
+                if read == 0 {
+                    break;
+                }
+                file.write_chunk(&buffer[..read]).await?;
+            }
+            file.finish().await?;
+
+            if file.sha256() != Some(sha256) {
+                warn!("Archived content does not match SHA256 {}", sha256);
+                for blob in blobs {
+                    report.fail(blob, "content does not match its SHA256");
+                }
+                continue;
+            }
+            staged = Some(file);
+        }
+
+        // The staged content is offered to each blob in turn until one is
+        // created, so a blob refused by its owner's quota does not leave
+        // the others without content
+        for blob in blobs {
+            let params = blob.create_params(owner_of(blob));
+            let created = match &staged {
+                Some(file) => service.create_blob_from_file(params, file, config).await,
+                None => service.create_blob(params, config).await,
+            };
+            if created.is_ok() && staged.take().is_some() {
+                report.contents_stored += 1;
+            }
+            report.record(&repository, blob, created).await;
+        }
+    }
+
+    // The archive may leave out content that is stored already
+    for blobs in pending.into_values() {
+        for blob in blobs {
+            let created = service
+                .create_blob(blob.create_params(owner_of(blob)), config)
+                .await;
+            report.record(&repository, blob, created).await;
+        }
+    }
+
+    info!(
+        "Imported {} media blobs, skipped {}, {} failed",
+        report.imported,
+        report.skipped,
+        report.failed.len()
+    );
+
+    Ok(report)
+}
+
+impl ImportReport {
+    fn fail(&mut self, blob: &ManifestBlob, reason: impl Into<String>) {
+        self.failed.push(ImportFailure {
+            id: blob.id,
+            sha256: blob.sha256.clone(),
+            reason: reason.into(),
+        });
+    }
+
+    /// Count a created blob and restore its tags, or record why it failed
+    async fn record(
+        &mut self,
+        repository: &MediaRepository<'_>,
+        blob: &ManifestBlob,
+        created: Result<MediaBlob, WebauthnError>,
+    ) {
+        let result = match created {
+            Ok(_) if blob.tags.is_empty() => Ok(()),
+            Ok(created) => match normalize_tags(&blob.tags) {
+                Ok(tags) => repository.set_tags(created.id, &tags).await,
+                Err(reason) => {
+                    warn!(
+                        "Dropped invalid tags of imported blob {}: {}",
+                        created.id, reason
+                    );
+                    Ok(())
+                }
+            },
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => self.imported += 1,
+            Err(WebauthnError::MediaContentMissing) => {
+                self.fail(blob, "content is neither in the archive nor stored")
+            }
+            Err(e) => self.fail(blob, e.to_string()),
+        }
+    }
+}
+
+/// Read and check the manifest at the start of an archive
+async fn read_manifest<R: AsyncRead + Unpin>(
+    archive: &mut TarReader<R>,
+) -> Result<MediaManifest, ArchiveError> {
+    let size = match archive.next_entry().await? {
+        Some((name, size)) if name == MANIFEST_NAME => size,
+        _ => {
+            return Err(ArchiveError::Invalid(format!(
+                "the archive does not start with {}",
+                MANIFEST_NAME
+            )))
+        }
+    };
+    if size > MAX_MANIFEST_SIZE {
+        return Err(ArchiveError::Invalid(
+            "the manifest is too large".to_string(),
+        ));
+    }
+
+    let mut json = vec![0u8; size as usize];
+    let mut filled = 0;
+    while filled < json.len() {
+        filled += archive.read_chunk(&mut json[filled..]).await?;
+    }
+    let manifest: MediaManifest = serde_json::from_slice(&json)?;
+
+    if manifest.version > MANIFEST_VERSION {
+        return Err(ArchiveError::Invalid(format!(
+            "manifest version {} is newer than this server supports",
+            manifest.version
+        )));
+    }
+    if let Some(blob) = manifest.blobs.iter().find(|blob| !is_sha256(&blob.sha256)) {
+        return Err(ArchiveError::Invalid(format!(
+            "blob {} has an invalid SHA256",
+            blob.id
+        )));
+    }
+
+    Ok(manifest)
+}
+
+/// The importing server's users for the owners named in the manifest
+async fn resolve_owners(
+    db: &DatabaseConnection,
+    manifest: &MediaManifest,
+    options: &ImportOptions,
+) -> Result<HashMap<String, Uuid>, ArchiveError> {
+    let mut owners = HashMap::new();
+    if options.owner_user_id.is_some() {
+        return Ok(owners);
+    }
+
+    let users = AuthRepository::new(db);
+    let mut unknown = Vec::new();
+    for name in manifest
+        .blobs
+        .iter()
+        .filter_map(|blob| blob.owner.as_deref())
+    {
+        if owners.contains_key(name) || unknown.iter().any(|unknown| unknown == name) {
+            continue;
+        }
+        match users.get_user_by_username(name).await? {
+            Some(user) => {
+                owners.insert(name.to_string(), user.id);
+            }
+            None => unknown.push(name.to_string()),
+        }
+    }
+
+    if !unknown.is_empty() {
+        return Err(ArchiveError::UnknownOwners(unknown));
+    }
+    Ok(owners)
+}
+
+fn is_sha256(sha256: &str) -> bool {
+    sha256.len() == 64
+        && sha256
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+/// Zero bytes needed after `size` bytes of content to fill the last block
+fn padding(size: u64) -> usize {
+    (BLOCK_SIZE - (size % BLOCK_SIZE as u64) as usize) % BLOCK_SIZE
+}
+
+/// Writes a tar archive entry by entry
+struct TarWriter<W> {
+    inner: W,
+    mtime: u64,
+}
+
+impl<W: AsyncWrite + Unpin> TarWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            mtime: OffsetDateTime::now_utc().unix_timestamp().max(0) as u64,
+        }
+    }
+
+    /// Append a file of exactly `size` bytes read from `content`
+    async fn append<R: AsyncRead + Unpin>(
+        &mut self,
+        name: &str,
+        size: u64,
+        content: R,
+    ) -> Result<(), ArchiveError> {
+        let mut header = tar::Header::new_ustar();
+        header.set_path(name)?;
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_mtime(self.mtime);
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_cksum();
+        self.inner.write_all(header.as_bytes()).await?;
+
+        let copied = tokio::io::copy(&mut content.take(size), &mut self.inner).await?;
+        if copied != size {
+            return Err(ArchiveError::Invalid(format!(
+                "{} has {} bytes instead of {}",
+                name, copied, size
+            )));
+        }
+        self.inner
+            .write_all(&[0; BLOCK_SIZE][..padding(size)])
+            .await?;
+
+        Ok(())
+    }
+
+    /// End the archive with two empty blocks and flush it
+    async fn finish(mut self) -> Result<W, ArchiveError> {
+        self.inner.write_all(&[0; 2 * BLOCK_SIZE]).await?;
+        self.inner.flush().await?;
+        Ok(self.inner)
+    }
+}
+
+/// Reads a tar archive entry by entry
+struct TarReader<R> {
+    inner: R,
+    /// Content bytes of the current entry not read yet
+    remaining: u64,
+    /// Padding after the current entry's content
+    padding: usize,
+}
+
+impl<R: AsyncRead + Unpin> TarReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            padding: 0,
+        }
+    }
+
+    /// Move to the next entry, skipping what is left of the current one,
+    /// and return its name and size; `None` at the end of the archive
+    async fn next_entry(&mut self) -> Result<Option<(String, u64)>, ArchiveError> {
+        let skip = self.remaining + self.padding as u64;
+        let skipped =
+            tokio::io::copy(&mut (&mut self.inner).take(skip), &mut tokio::io::sink()).await?;
+        if skipped != skip {
+            return Err(truncated());
+        }
+        self.remaining = 0;
+        self.padding = 0;
+
+        let mut block = [0u8; BLOCK_SIZE];
+        match self.inner.read_exact(&mut block).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Err(truncated()),
+            Err(e) => return Err(e.into()),
+        }
+        if block.iter().all(|byte| *byte == 0) {
+            return Ok(None);
+        }
+
+        let header = tar::Header::from_byte_slice(&block);
+        let mut expected = header.clone();
+        expected.set_cksum();
+        if header.cksum()? != expected.cksum()? {
+            return Err(ArchiveError::Invalid("corrupt entry header".to_string()));
+        }
+
+        let name = header.path()?.to_string_lossy().into_owned();
+        if !header.entry_type().is_file() {
+            return Err(ArchiveError::Invalid(format!(
+                "{} is not a regular file",
+                name
+            )));
+        }
+        let size = header.entry_size()?;
+        self.remaining = size;
+        self.padding = padding(size);
+
+        Ok(Some((name, size)))
+    }
+
+    /// Read the next chunk of the current entry's content; 0 at its end
+    async fn read_chunk(&mut self, buffer: &mut [u8]) -> Result<usize, ArchiveError> {
+        let wanted = (buffer.len() as u64).min(self.remaining) as usize;
+        if wanted == 0 {
+            return Ok(0);
+        }
+
+        let read = self.inner.read(&mut buffer[..wanted]).await?;
+        if read == 0 {
+            return Err(truncated());
+        }
+        self.remaining -= read as u64;
+
+        Ok(read)
+    }
+}
+
+fn truncated() -> ArchiveError {
+    ArchiveError::Invalid("the archive ends early".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tar_round_trip() {
+        let mut writer = TarWriter::new(Vec::new());
+        writer.append("a", 3, &b"abc"[..]).await.unwrap();
+        writer.append("content/b", 0, &b""[..]).await.unwrap();
+        writer.append("c", 600, &[7u8; 600][..]).await.unwrap();
+        let bytes = writer.finish().await.unwrap();
+        assert_eq!(bytes.len() % BLOCK_SIZE, 0);
+
+        // Other tar readers accept the archive
+        let names: Vec<String> = tar::Archive::new(bytes.as_slice())
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect();
+        assert_eq!(names, vec!["a", "content/b", "c"]);
+
+        let mut reader = TarReader::new(bytes.as_slice());
+        let mut buffer = [0u8; 2];
+        assert_eq!(
+            reader.next_entry().await.unwrap(),
+            Some(("a".to_string(), 3))
+        );
+        assert_eq!(reader.read_chunk(&mut buffer).await.unwrap(), 2);
+        assert_eq!(&buffer, b"ab");
+        // The rest of an entry is skipped
+        assert_eq!(
+            reader.next_entry().await.unwrap(),
+            Some(("content/b".to_string(), 0))
+        );
+        assert_eq!(reader.read_chunk(&mut buffer).await.unwrap(), 0);
+        assert_eq!(
+            reader.next_entry().await.unwrap(),
+            Some(("c".to_string(), 600))
+        );
+        assert_eq!(reader.next_entry().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_tar_reader_rejects_damage() {
+        let mut writer = TarWriter::new(Vec::new());
+        writer.append("a", 3, &b"abc"[..]).await.unwrap();
+        let bytes = writer.finish().await.unwrap();
+
+        let mut corrupt = bytes.clone();
+        corrupt[0] = b'b';
+        assert!(matches!(
+            TarReader::new(corrupt.as_slice()).next_entry().await,
+            Err(ArchiveError::Invalid(_))
+        ));
+
+        let mut reader = TarReader::new(&bytes[..BLOCK_SIZE + 1]);
+        reader.next_entry().await.unwrap();
+        assert!(matches!(
+            reader.next_entry().await,
+            Err(ArchiveError::Invalid(_))
+        ));
+
+        // Content shorter than announced is refused while writing
+        let mut writer = TarWriter::new(Vec::new());
+        assert!(writer.append("a", 4, &b"abc"[..]).await.is_err());
+    }
+
+    #[test]
+    fn test_create_params_drop_derived_metadata() {
+        let blob = ManifestBlob {
+            id: Uuid::new_v4(),
+            sha256: "a".repeat(64),
+            size: 3,
+            mime: Some("image/png".to_string()),
+            source_client_id: None,
+            owner: Some("alice".to_string()),
+            visibility: MediaVisibility::Members,
+            metadata: serde_json::json!({"title": "x", "image": {}, "thumbnails": []}),
+            tags: Vec::new(),
+            created_at: OffsetDateTime::now_utc(),
+        };
+
+        let params = blob.create_params(None);
+        assert_eq!(params.metadata, serde_json::json!({"title": "x"}));
+        assert_eq!(params.size, Some(3));
+        assert_eq!(params.visibility, MediaVisibility::Members);
+        assert!(is_sha256(&blob.sha256));
+        assert!(!is_sha256(&"A".repeat(64)));
+    }
+}
@@ -0,0 +1,109 @@
+//! Native FLAC files
+
+use super::{id3v2_len, read_at, AvError, AvSource, ByteReader};
+use crate::media::models::{AvMetadata, AvTrack, AvTrackKind};
+
+/// Offset of the `fLaC` marker, after an ID3v2 tag some taggers prepend
+fn stream_start(source: &mut dyn AvSource) -> Result<u64, AvError> {
+    Ok(id3v2_len(&read_at(source, 0, 10)?))
+}
+
+pub(super) fn detect(source: &mut dyn AvSource) -> Result<bool, AvError> {
+    let start = stream_start(source)?;
+    Ok(read_at(source, start, 4)? == b"fLaC")
+}
+
+pub(super) fn parse(source: &mut dyn AvSource) -> Result<AvMetadata, AvError> {
+    let start = stream_start(source)?;
+
+    // STREAMINFO is always the first metadata block
+    let header = read_at(source, start + 4, 4 + 34)?;
+    let mut reader = ByteReader::new(&header, "FLAC");
+    if reader.u8()? & 0x7f != 0 || reader.u24()? < 34 {
+        return Err(AvError::Malformed("FLAC"));
+    }
+
+    let track = parse_stream_info(reader.take(34)?)?;
+    Ok(AvMetadata {
+        container: "flac".to_string(),
+        duration: track.duration,
+        tracks: vec![track],
+        ..Default::default()
+    })
+}
+
+/// Decode a STREAMINFO block, as found in FLAC files and Ogg FLAC streams
+pub(super) fn parse_stream_info(block: &[u8]) -> Result<AvTrack, AvError> {
+    let mut reader = ByteReader::new(block, "FLAC");
+    reader.skip(10)?; // block and frame size bounds
+    let packed = reader.u64()?;
+
+    let sample_rate = (packed >> 44) as u32;
+    let channels = ((packed >> 41) & 0x7) as u16 + 1;
+    let bits_per_sample = ((packed >> 36) & 0x1f) as u16 + 1;
+    let total_samples = packed & 0xf_ffff_ffff;
+
+    let mut track = AvTrack::new(AvTrackKind::Audio);
+    track.codec = Some("flac".to_string());
+    track.sample_rate = Some(sample_rate);
+    track.channels = Some(channels);
+    track.bits_per_sample = Some(bits_per_sample);
+    // A total of zero means the encoder did not know it
+    track.duration =
+        (sample_rate > 0 && total_samples > 0).then(|| total_samples as f64 / sample_rate as f64);
+    Ok(track)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::super::extract_av_metadata;
+    use super::*;
+    use std::io::Cursor;
+
+    /// A STREAMINFO block body
+    pub(in crate::media::av) fn stream_info(
+        sample_rate: u32,
+        channels: u16,
+        bits: u16,
+        samples: u64,
+    ) -> Vec<u8> {
+        let mut block = vec![0x10, 0x00, 0x10, 0x00, 0, 0, 0, 0, 0, 0];
+        let packed = (sample_rate as u64) << 44
+            | ((channels - 1) as u64) << 41
+            | ((bits - 1) as u64) << 36
+            | samples;
+        block.extend_from_slice(&packed.to_be_bytes());
+        block.extend_from_slice(&[0; 16]); // MD5 of the decoded audio
+        block
+    }
+
+    #[test]
+    fn test_parse_flac() {
+        // An ID3v2 tag in front of the stream is skipped
+        let mut bytes = b"ID3\x03\x00\x00\x00\x00\x00\x04tag!".to_vec();
+        bytes.extend_from_slice(b"fLaC");
+        bytes.extend_from_slice(&[0x80, 0, 0, 34]);
+        bytes.extend_from_slice(&stream_info(44100, 2, 24, 441000));
+        bytes.resize(bytes.len() + 1000, 0);
+
+        let metadata = extract_av_metadata(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(metadata.container, "flac");
+        assert_eq!(metadata.duration, Some(10.0));
+        assert!(metadata.bitrate.is_some());
+
+        let track = &metadata.tracks[0];
+        assert_eq!(track.codec.as_deref(), Some("flac"));
+        assert_eq!(track.sample_rate, Some(44100));
+        assert_eq!(track.channels, Some(2));
+        assert_eq!(track.bits_per_sample, Some(24));
+    }
+
+    #[test]
+    fn test_flac_without_stream_info() {
+        let bytes = b"fLaC\x84\x00\x00\x10vorbis comments!".to_vec();
+        assert!(matches!(
+            extract_av_metadata(&mut Cursor::new(bytes)),
+            Err(AvError::Malformed("FLAC"))
+        ));
+    }
+}
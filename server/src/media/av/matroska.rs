@@ -0,0 +1,454 @@
+//! Matroska and WebM files
+//!
+//! Only the segment's `Info` and `Tracks` elements are read; clusters of
+//! media data are skipped by seeking past them.
+
+use super::{read_at, AvError, AvSource};
+use crate::media::models::{AvMetadata, AvTrack, AvTrackKind};
+
+const EBML: u32 = 0x1a45dfa3;
+const DOC_TYPE: u32 = 0x4282;
+const SEGMENT: u32 = 0x18538067;
+const INFO: u32 = 0x1549a966;
+const TIMESTAMP_SCALE: u32 = 0x2ad7b1;
+const DURATION: u32 = 0x4489;
+const TRACKS: u32 = 0x1654ae6b;
+const TRACK_ENTRY: u32 = 0xae;
+const TRACK_TYPE: u32 = 0x83;
+const CODEC_ID: u32 = 0x86;
+const LANGUAGE: u32 = 0x22b59c;
+const LANGUAGE_BCP47: u32 = 0x22b59d;
+const DEFAULT_DURATION: u32 = 0x23e383;
+const VIDEO: u32 = 0xe0;
+const PIXEL_WIDTH: u32 = 0xb0;
+const PIXEL_HEIGHT: u32 = 0xba;
+const AUDIO: u32 = 0xe1;
+const SAMPLING_FREQUENCY: u32 = 0xb5;
+const CHANNELS: u32 = 0x9f;
+const BIT_DEPTH: u32 = 0x6264;
+const CLUSTER: u32 = 0x1f43b675;
+
+/// Largest `Info` or `Tracks` element read into memory
+const MAX_HEADER_ELEMENT: u64 = 16 * 1024 * 1024;
+
+/// An element header: ID, offset of the data and its size if known
+struct Element {
+    id: u32,
+    data_offset: u64,
+    size: Option<u64>,
+}
+
+pub(super) fn detect(head: &[u8]) -> bool {
+    head.starts_with(&EBML.to_be_bytes())
+}
+
+pub(super) fn parse(source: &mut dyn AvSource, size: u64) -> Result<AvMetadata, AvError> {
+    let header = read_element(source, 0)?.ok_or(AvError::Malformed("Matroska"))?;
+    let header_size = header.size.ok_or(AvError::Malformed("Matroska"))?;
+    let doc_type = children(&read_data(source, &header)?)?
+        .into_iter()
+        .find(|(id, _)| *id == DOC_TYPE)
+        .map(|(_, data)| string(data))
+        .unwrap_or_else(|| "matroska".to_string());
+
+    let segment = read_element(source, header.data_offset + header_size)?
+        .filter(|element| element.id == SEGMENT)
+        .ok_or(AvError::Malformed("Matroska"))?;
+    // Live recordings leave the segment size unknown
+    let segment_end = segment
+        .size
+        .map_or(size, |len| (segment.data_offset + len).min(size));
+
+    let mut metadata = AvMetadata {
+        container: if doc_type == "webm" {
+            "webm"
+        } else {
+            "matroska"
+        }
+        .to_string(),
+        ..Default::default()
+    };
+    let (mut seen_info, mut seen_tracks) = (false, false);
+
+    let mut offset = segment.data_offset;
+    while offset < segment_end && !(seen_info && seen_tracks) {
+        let Some(element) = read_element(source, offset)? else {
+            break;
+        };
+
+        match element.id {
+            INFO => {
+                metadata.duration = parse_info(&read_data(source, &element)?)?;
+                seen_info = true;
+            }
+            TRACKS => {
+                metadata.tracks = parse_tracks(&read_data(source, &element)?)?;
+                seen_tracks = true;
+            }
+            _ => {}
+        }
+
+        // Clusters of unknown size cannot be skipped, and headers do not
+        // follow them anyway
+        match element.size {
+            Some(len) => offset = element.data_offset + len,
+            None if element.id == CLUSTER => break,
+            None => return Err(AvError::Malformed("Matroska")),
+        }
+    }
+
+    if !seen_tracks {
+        return Err(AvError::Malformed("Matroska"));
+    }
+
+    Ok(metadata)
+}
+
+/// Duration in seconds from the segment `Info`
+fn parse_info(data: &[u8]) -> Result<Option<f64>, AvError> {
+    let mut scale = 1_000_000; // nanoseconds per timestamp tick
+    let mut duration = None;
+
+    for (id, value) in children(data)? {
+        match id {
+            TIMESTAMP_SCALE => scale = uint(value),
+            DURATION => duration = float(value),
+            _ => {}
+        }
+    }
+
+    Ok(duration.map(|ticks| ticks * scale as f64 / 1e9))
+}
+
+fn parse_tracks(data: &[u8]) -> Result<Vec<AvTrack>, AvError> {
+    let mut tracks = Vec::new();
+
+    for (id, entry) in children(data)? {
+        if id != TRACK_ENTRY {
+            continue;
+        }
+
+        let mut track = AvTrack::new(AvTrackKind::Other);
+        let mut language = None;
+        let mut language_bcp47 = None;
+
+        for (id, value) in children(entry)? {
+            match id {
+                TRACK_TYPE => {
+                    track.kind = match uint(value) {
+                        1 => AvTrackKind::Video,
+                        2 => AvTrackKind::Audio,
+                        17 => AvTrackKind::Subtitle,
+                        _ => AvTrackKind::Other,
+                    }
+                }
+                CODEC_ID => track.codec = Some(codec_name(&string(value))),
+                LANGUAGE => language = Some(string(value)),
+                LANGUAGE_BCP47 => language_bcp47 = Some(string(value)),
+                DEFAULT_DURATION => {
+                    let frame_ns = uint(value);
+                    track.frame_rate = (frame_ns > 0).then(|| 1e9 / frame_ns as f64);
+                }
+                VIDEO => {
+                    for (id, value) in children(value)? {
+                        match id {
+                            PIXEL_WIDTH => track.width = Some(uint(value) as u32),
+                            PIXEL_HEIGHT => track.height = Some(uint(value) as u32),
+                            _ => {}
+                        }
+                    }
+                }
+                AUDIO => {
+                    track.sample_rate = Some(8000);
+                    track.channels = Some(1);
+                    for (id, value) in children(value)? {
+                        match id {
+                            SAMPLING_FREQUENCY => {
+                                track.sample_rate = float(value).map(|rate| rate as u32)
+                            }
+                            CHANNELS => track.channels = Some(uint(value) as u16),
+                            BIT_DEPTH => track.bits_per_sample = Some(uint(value) as u16),
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Frame rates only make sense for video; audio uses DefaultDuration per block
+        if track.kind != AvTrackKind::Video {
+            track.frame_rate = None;
+        }
+        // The BCP 47 form wins; without either, Matroska implies English
+        track.language = language_bcp47
+            .or(language)
+            .or_else(|| Some("eng".to_string()))
+            .filter(|language| language != "und");
+        tracks.push(track);
+    }
+
+    Ok(tracks)
+}
+
+/// Read the element header at `offset`, or `None` at the end of the file
+fn read_element(source: &mut dyn AvSource, offset: u64) -> Result<Option<Element>, AvError> {
+    let head = read_at(source, offset, 12)?;
+    if head.is_empty() {
+        return Ok(None);
+    }
+
+    let (id, id_len) = vint(&head, true).ok_or(AvError::Malformed("Matroska"))?;
+    let (size, size_len) = vint(&head[id_len..], false).ok_or(AvError::Malformed("Matroska"))?;
+
+    // A size with every value bit set means unknown
+    let unknown = size == (1u64 << (7 * size_len)) - 1;
+    Ok(Some(Element {
+        id: id as u32,
+        data_offset: offset + (id_len + size_len) as u64,
+        size: (!unknown).then_some(size),
+    }))
+}
+
+fn read_data(source: &mut dyn AvSource, element: &Element) -> Result<Vec<u8>, AvError> {
+    match element.size {
+        Some(len) if len <= MAX_HEADER_ELEMENT => {
+            let data = read_at(source, element.data_offset, len as usize)?;
+            if (data.len() as u64) < len {
+                return Err(AvError::Malformed("Matroska"));
+            }
+            Ok(data)
+        }
+        _ => Err(AvError::Malformed("Matroska")),
+    }
+}
+
+/// Split the data of a master element into its children
+fn children(data: &[u8]) -> Result<Vec<(u32, &[u8])>, AvError> {
+    let mut children = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let (id, id_len) = vint(&data[pos..], true).ok_or(AvError::Malformed("Matroska"))?;
+        let (len, len_len) =
+            vint(&data[pos + id_len..], false).ok_or(AvError::Malformed("Matroska"))?;
+        let start = pos + id_len + len_len;
+        let end = start
+            .checked_add(len as usize)
+            .filter(|end| *end <= data.len())
+            .ok_or(AvError::Malformed("Matroska"))?;
+
+        children.push((id as u32, &data[start..end]));
+        pos = end;
+    }
+
+    Ok(children)
+}
+
+/// Decode a variable-length integer, keeping the length marker for IDs
+fn vint(bytes: &[u8], keep_marker: bool) -> Option<(u64, usize)> {
+    let first = *bytes.first()?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 || bytes.len() < len || (keep_marker && len > 4) {
+        return None;
+    }
+
+    let first = if keep_marker {
+        first as u64
+    } else {
+        // An 8-byte integer has no value bits in its first byte
+        (first & 0xffu8.checked_shr(len as u32).unwrap_or(0)) as u64
+    };
+    let value = bytes[1..len]
+        .iter()
+        .fold(first, |acc, byte| (acc << 8) | *byte as u64);
+    Some((value, len))
+}
+
+fn uint(data: &[u8]) -> u64 {
+    data.iter()
+        .take(8)
+        .fold(0, |acc, byte| (acc << 8) | *byte as u64)
+}
+
+fn float(data: &[u8]) -> Option<f64> {
+    match data.len() {
+        4 => Some(f32::from_be_bytes(data.try_into().unwrap()) as f64),
+        8 => Some(f64::from_be_bytes(data.try_into().unwrap())),
+        _ => None,
+    }
+}
+
+fn string(data: &[u8]) -> String {
+    String::from_utf8_lossy(data)
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+fn codec_name(codec_id: &str) -> String {
+    match codec_id {
+        "V_VP8" => "vp8",
+        "V_VP9" => "vp9",
+        "V_AV1" => "av1",
+        "V_MPEG4/ISO/AVC" => "h264",
+        "V_MPEGH/ISO/HEVC" => "hevc",
+        "V_THEORA" => "theora",
+        "A_OPUS" => "opus",
+        "A_VORBIS" => "vorbis",
+        "A_FLAC" => "flac",
+        "A_MPEG/L3" => "mp3",
+        "A_AC3" => "ac3",
+        "A_EAC3" => "eac3",
+        "S_TEXT/UTF8" => "subrip",
+        "S_TEXT/WEBVTT" => "webvtt",
+        id if id.starts_with("A_AAC") => "aac",
+        id if id.starts_with("A_PCM") => "pcm",
+        id => return id.to_string(),
+    }
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::extract_av_metadata;
+    use super::*;
+    use std::io::Cursor;
+
+    /// An element with a one-byte size, or a four-byte one for larger data
+    fn element(id: u32, data: &[u8]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = id
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|byte| *byte == 0)
+            .collect();
+        if data.len() < 0x7f {
+            bytes.push(0x80 | data.len() as u8);
+        } else {
+            bytes.extend_from_slice(&(0x1000_0000 | data.len() as u32).to_be_bytes());
+        }
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn webm(duration_ms: f64, cluster_size: Option<usize>) -> Vec<u8> {
+        let header = element(EBML, &element(DOC_TYPE, b"webm"));
+
+        let info = element(
+            INFO,
+            &[
+                element(TIMESTAMP_SCALE, &[0x0f, 0x42, 0x40]),
+                element(DURATION, &duration_ms.to_be_bytes()),
+            ]
+            .concat(),
+        );
+        let video = element(
+            TRACK_ENTRY,
+            &[
+                element(TRACK_TYPE, &[1]),
+                element(CODEC_ID, b"V_VP9"),
+                element(DEFAULT_DURATION, &40_000_000u32.to_be_bytes()),
+                element(
+                    VIDEO,
+                    &[
+                        element(PIXEL_WIDTH, &[0x07, 0x80]),
+                        element(PIXEL_HEIGHT, &[0x04, 0x38]),
+                    ]
+                    .concat(),
+                ),
+            ]
+            .concat(),
+        );
+        let audio = element(
+            TRACK_ENTRY,
+            &[
+                element(TRACK_TYPE, &[2]),
+                element(CODEC_ID, b"A_OPUS"),
+                element(LANGUAGE, b"ger"),
+                element(
+                    AUDIO,
+                    &[
+                        element(SAMPLING_FREQUENCY, &48000f32.to_be_bytes()),
+                        element(CHANNELS, &[6]),
+                    ]
+                    .concat(),
+                ),
+            ]
+            .concat(),
+        );
+        let tracks = element(TRACKS, &[video, audio].concat());
+
+        // Media data comes first here, so the parser has to skip it
+        let cluster = match cluster_size {
+            Some(len) => element(CLUSTER, &vec![0; len]),
+            None => [&CLUSTER.to_be_bytes()[..], &[0xff], &[0; 64]].concat(),
+        };
+
+        // The segment size is unknown, as in live recordings
+        let mut bytes = header;
+        bytes.extend_from_slice(&SEGMENT.to_be_bytes());
+        bytes.push(0xff);
+        bytes.extend(info);
+        bytes.extend(cluster);
+        bytes.extend(tracks);
+        bytes
+    }
+
+    #[test]
+    fn test_vint() {
+        assert_eq!(vint(&[0x81], false), Some((1, 1)));
+        assert_eq!(vint(&[0x40, 0x02], false), Some((2, 2)));
+        assert_eq!(
+            vint(&[0x1a, 0x45, 0xdf, 0xa3], true),
+            Some((EBML as u64, 4))
+        );
+        assert_eq!(vint(&[0x00], false), None);
+        assert_eq!(vint(&[0x40], false), None);
+        assert_eq!(vint(&[0x01, 0, 0, 0, 0, 0, 0, 0x05], false), Some((5, 8)));
+        assert_eq!(vint(&[0x01, 0, 0, 0, 0, 0, 0, 0x05], true), None);
+    }
+
+    #[test]
+    fn test_eight_byte_size_is_not_a_crash() {
+        // EBML header whose size is an 8-byte integer larger than the file
+        let bytes = [
+            0x1a, 0x45, 0xdf, 0xa3, 0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        ];
+        assert!(extract_av_metadata(&mut Cursor::new(bytes.to_vec())).is_err());
+    }
+
+    #[test]
+    fn test_parse_webm() {
+        let mut source = Cursor::new(webm(12_500.0, Some(1000)));
+        let metadata = extract_av_metadata(&mut source).unwrap();
+
+        assert_eq!(metadata.container, "webm");
+        assert_eq!(metadata.duration, Some(12.5));
+        assert_eq!((metadata.width, metadata.height), (Some(1920), Some(1080)));
+        assert!(metadata.bitrate.is_some());
+        assert_eq!(metadata.tracks.len(), 2);
+
+        let video = &metadata.tracks[0];
+        assert_eq!(video.kind, AvTrackKind::Video);
+        assert_eq!(video.codec.as_deref(), Some("vp9"));
+        assert_eq!(video.frame_rate, Some(25.0));
+        assert_eq!(video.language.as_deref(), Some("eng"));
+
+        let audio = &metadata.tracks[1];
+        assert_eq!(audio.kind, AvTrackKind::Audio);
+        assert_eq!(audio.codec.as_deref(), Some("opus"));
+        assert_eq!(audio.sample_rate, Some(48000));
+        assert_eq!(audio.channels, Some(6));
+        assert_eq!(audio.language.as_deref(), Some("ger"));
+        assert_eq!(audio.frame_rate, None);
+    }
+
+    #[test]
+    fn test_tracks_behind_unknown_size_cluster() {
+        // Tracks after a cluster of unknown size cannot be found
+        let mut source = Cursor::new(webm(1000.0, None));
+        assert!(matches!(
+            extract_av_metadata(&mut source),
+            Err(AvError::Malformed("Matroska"))
+        ));
+    }
+}
@@ -0,0 +1,196 @@
+//! Audio and video metadata extraction
+//!
+//! Small parsers for the common containers read only the headers they need,
+//! seeking past media data, so large files are never read in full:
+//! - MP4/MOV (`mp4`)
+//! - WebM/Matroska (`matroska`)
+//! - MP3 (`mpeg`), FLAC (`flac`), WAV (`wav`) and Ogg (`ogg`)
+//!
+//! The container is detected from the content, not the MIME type.
+
+mod flac;
+mod matroska;
+mod mp4;
+mod mpeg;
+mod ogg;
+mod wav;
+
+use std::io::{Read, Seek, SeekFrom};
+
+use super::models::{AvMetadata, AvTrackKind};
+
+/// Why metadata could not be extracted
+#[derive(Debug, thiserror::Error)]
+pub enum AvError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Unrecognised audio or video format")]
+    Unsupported,
+    #[error("Malformed {0} file")]
+    Malformed(&'static str),
+}
+
+/// A seekable source of media content
+pub trait AvSource: Read + Seek {}
+
+impl<T: Read + Seek> AvSource for T {}
+
+/// Detect the container of `source` and read its metadata
+///
+/// The overall duration falls back to the longest track, and the bitrate to
+/// the average over the whole file.
+pub fn extract_av_metadata(source: &mut dyn AvSource) -> Result<AvMetadata, AvError> {
+    let size = source.seek(SeekFrom::End(0))?;
+    let head = read_at(source, 0, 64)?;
+
+    let mut metadata = if mp4::detect(&head) {
+        mp4::parse(source, size)?
+    } else if matroska::detect(&head) {
+        matroska::parse(source, size)?
+    } else if ogg::detect(&head) {
+        ogg::parse(source, size)?
+    } else if wav::detect(&head) {
+        wav::parse(source, size)?
+    } else if flac::detect(source)? {
+        flac::parse(source)?
+    } else if mpeg::detect(source)? {
+        mpeg::parse(source, size)?
+    } else {
+        return Err(AvError::Unsupported);
+    };
+
+    if metadata.duration.is_none() {
+        metadata.duration = metadata
+            .tracks
+            .iter()
+            .filter_map(|track| track.duration)
+            .reduce(f64::max);
+    }
+
+    if metadata.bitrate.is_none() {
+        metadata.bitrate = metadata
+            .duration
+            .filter(|duration| *duration > 0.0)
+            .map(|duration| (size as f64 * 8.0 / duration).round() as u64);
+    }
+
+    if let Some(video) = metadata
+        .tracks
+        .iter()
+        .find(|track| track.kind == AvTrackKind::Video)
+    {
+        metadata.width = metadata.width.or(video.width);
+        metadata.height = metadata.height.or(video.height);
+    }
+
+    Ok(metadata)
+}
+
+/// Read up to `len` bytes at `offset`; fewer at the end of the source
+fn read_at(source: &mut dyn AvSource, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+    source.seek(SeekFrom::Start(offset))?;
+    let mut buf = Vec::with_capacity(len);
+    source.take(len as u64).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Length of an ID3v2 tag at the start of `head`, or 0 without one
+fn id3v2_len(head: &[u8]) -> u64 {
+    if head.len() < 10 || &head[..3] != b"ID3" {
+        return 0;
+    }
+
+    let size = head[6..10]
+        .iter()
+        .fold(0u64, |acc, byte| (acc << 7) | (*byte & 0x7f) as u64);
+    let footer = if head[5] & 0x10 != 0 { 10 } else { 0 };
+    10 + size + footer
+}
+
+/// Bounds-checked big-endian reader over a header held in memory
+struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    format: &'static str,
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(data: &'a [u8], format: &'static str) -> Self {
+        Self {
+            data,
+            pos: 0,
+            format,
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], AvError> {
+        if self.remaining() < len {
+            return Err(AvError::Malformed(self.format));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), AvError> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Result<u8, AvError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, AvError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u24(&mut self) -> Result<u32, AvError> {
+        let bytes = self.take(3)?;
+        Ok(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, AvError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, AvError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_unrecognised_content() {
+        let mut source = Cursor::new(b"just some text, not media at all".to_vec());
+        assert!(matches!(
+            extract_av_metadata(&mut source),
+            Err(AvError::Unsupported)
+        ));
+    }
+
+    #[test]
+    fn test_id3v2_len() {
+        assert_eq!(id3v2_len(b"ID3\x04\x00\x00\x00\x00\x02\x01rest"), 10 + 257);
+        assert_eq!(
+            id3v2_len(b"ID3\x04\x00\x10\x00\x00\x00\x05rest"),
+            10 + 5 + 10
+        );
+        assert_eq!(id3v2_len(b"fLaC"), 0);
+    }
+
+    #[test]
+    fn test_byte_reader_bounds() {
+        let mut reader = ByteReader::new(&[0, 1, 2, 3, 4], "test");
+        assert_eq!(reader.u16().unwrap(), 1);
+        assert_eq!(reader.u24().unwrap(), 0x020304);
+        assert!(matches!(reader.u8(), Err(AvError::Malformed("test"))));
+    }
+}
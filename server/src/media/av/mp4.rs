@@ -0,0 +1,469 @@
+//! MP4 and QuickTime files
+//!
+//! Top-level boxes are walked by seeking, so the `moov` box is found whether
+//! the encoder wrote it before or after the media data. Only `moov` is read
+//! into memory.
+
+use super::{read_at, AvError, AvSource, ByteReader};
+use crate::media::models::{AvMetadata, AvTrack, AvTrackKind};
+
+/// A child box: its type and body
+type Mp4Box<'a> = (&'a [u8; 4], &'a [u8]);
+
+/// Largest `moov` box read into memory
+const MAX_MOOV_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Boxes an MP4 or QuickTime file may start with
+const FIRST_BOXES: [&[u8; 4]; 4] = [b"ftyp", b"moov", b"mdat", b"wide"];
+
+pub(super) fn detect(head: &[u8]) -> bool {
+    head.len() >= 8 && FIRST_BOXES.iter().any(|kind| &head[4..8] == *kind)
+}
+
+pub(super) fn parse(source: &mut dyn AvSource, size: u64) -> Result<AvMetadata, AvError> {
+    let mut container = "mp4";
+    let mut moov = None;
+
+    let mut offset = 0;
+    while offset + 8 <= size {
+        let header = read_at(source, offset, 16)?;
+        let mut reader = ByteReader::new(&header, "MP4");
+        let len = reader.u32()? as u64;
+        let kind: [u8; 4] = reader.take(4)?.try_into().unwrap();
+
+        // A length of 1 means a 64-bit length follows, 0 means up to the end
+        let (header_len, len) = match len {
+            1 => (16, reader.u64()?),
+            0 => (8, size - offset),
+            len => (8, len),
+        };
+        // Boxes must hold their header and end within the file
+        if len < header_len || len > size - offset {
+            return Err(AvError::Malformed("MP4"));
+        }
+
+        match &kind {
+            b"ftyp" => {
+                let brand = read_at(source, offset + header_len, 4)?;
+                if brand == b"qt  " {
+                    container = "quicktime";
+                }
+            }
+            b"moov" => {
+                if len > MAX_MOOV_SIZE {
+                    return Err(AvError::Malformed("MP4"));
+                }
+                let data = read_at(source, offset + header_len, (len - header_len) as usize)?;
+                moov = Some(data);
+                break;
+            }
+            _ => {}
+        }
+
+        offset = offset.checked_add(len).ok_or(AvError::Malformed("MP4"))?;
+    }
+
+    let moov = moov.ok_or(AvError::Malformed("MP4"))?;
+    let mut metadata = parse_moov(&moov)?;
+    metadata.container = container.to_string();
+    Ok(metadata)
+}
+
+fn parse_moov(data: &[u8]) -> Result<AvMetadata, AvError> {
+    let mut metadata = AvMetadata::default();
+
+    for (kind, body) in boxes(data)? {
+        match kind {
+            b"mvhd" => {
+                let (timescale, duration) = parse_header_times(body)?;
+                metadata.duration = seconds(duration, timescale);
+            }
+            b"trak" => metadata.tracks.push(parse_trak(body)?),
+            _ => {}
+        }
+    }
+
+    Ok(metadata)
+}
+
+fn parse_trak(data: &[u8]) -> Result<AvTrack, AvError> {
+    let mut track = AvTrack::new(AvTrackKind::Other);
+    let mut display_size = None;
+
+    for (kind, body) in boxes(data)? {
+        match kind {
+            b"tkhd" if body.len() >= 8 => {
+                // Width and height in 16.16 fixed point close the box
+                let mut reader = ByteReader::new(&body[body.len() - 8..], "MP4");
+                display_size = Some((reader.u32()? >> 16, reader.u32()? >> 16));
+            }
+            b"mdia" => parse_mdia(body, &mut track)?,
+            _ => {}
+        }
+    }
+
+    if track.kind == AvTrackKind::Video {
+        if let Some((width, height)) = display_size.filter(|(w, h)| *w > 0 && *h > 0) {
+            track.width = track.width.or(Some(width));
+            track.height = track.height.or(Some(height));
+        }
+    }
+
+    Ok(track)
+}
+
+fn parse_mdia(data: &[u8], track: &mut AvTrack) -> Result<(), AvError> {
+    let mut timescale = 0;
+    let mut stbl = None;
+
+    for (kind, body) in boxes(data)? {
+        match kind {
+            b"mdhd" => {
+                let (scale, duration) = parse_header_times(body)?;
+                timescale = scale;
+                track.duration = seconds(duration, scale);
+                track.language = parse_language(body)?;
+            }
+            b"hdlr" => {
+                let mut reader = ByteReader::new(body, "MP4");
+                reader.skip(8)?; // version, flags and pre-defined
+                track.kind = match reader.take(4)? {
+                    b"vide" => AvTrackKind::Video,
+                    b"soun" => AvTrackKind::Audio,
+                    b"text" | b"sbtl" | b"subt" => AvTrackKind::Subtitle,
+                    _ => AvTrackKind::Other,
+                };
+            }
+            b"minf" => {
+                stbl = boxes(body)?
+                    .into_iter()
+                    .find(|(kind, _)| *kind == b"stbl")
+                    .map(|(_, body)| body);
+            }
+            _ => {}
+        }
+    }
+
+    // The sample table is read last, since sample entries depend on the handler
+    if let Some(stbl) = stbl {
+        for (kind, body) in boxes(stbl)? {
+            match kind {
+                b"stsd" => parse_sample_entry(body, track)?,
+                b"stts" if track.kind == AvTrackKind::Video => {
+                    let frames = sample_count(body)?;
+                    track.frame_rate = track
+                        .duration
+                        .filter(|duration| *duration > 0.0 && frames > 0 && timescale > 0)
+                        .map(|duration| frames as f64 / duration);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Codec and format details from the first sample description
+fn parse_sample_entry(data: &[u8], track: &mut AvTrack) -> Result<(), AvError> {
+    let mut reader = ByteReader::new(data, "MP4");
+    reader.skip(4)?; // version and flags
+    if reader.u32()? == 0 {
+        return Ok(());
+    }
+
+    let entry_len = reader.u32()? as usize;
+    let fourcc: [u8; 4] = reader.take(4)?.try_into().unwrap();
+    let entry = reader.take(entry_len.saturating_sub(8).min(reader.remaining()))?;
+    track.codec = Some(codec_name(&fourcc));
+
+    let mut entry = ByteReader::new(entry, "MP4");
+    entry.skip(8)?; // reserved and data reference index
+    match track.kind {
+        AvTrackKind::Video => {
+            entry.skip(16)?; // version, revision, vendor and quality
+            track.width = Some(entry.u16()? as u32);
+            track.height = Some(entry.u16()? as u32);
+        }
+        AvTrackKind::Audio => {
+            entry.skip(8)?; // version, revision and vendor
+            track.channels = Some(entry.u16()?);
+            let bits = entry.u16()?;
+            entry.skip(4)?; // compression ID and packet size
+            track.sample_rate = Some(entry.u32()? >> 16);
+            // Compressed formats carry a placeholder sample size
+            if is_uncompressed(&fourcc) {
+                track.bits_per_sample = Some(bits);
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Timescale and duration of an `mvhd` or `mdhd` box
+fn parse_header_times(data: &[u8]) -> Result<(u32, u64), AvError> {
+    let mut reader = ByteReader::new(data, "MP4");
+    let version = reader.u8()?;
+    reader.skip(3)?; // flags
+
+    if version == 1 {
+        reader.skip(16)?; // creation and modification times
+        let timescale = reader.u32()?;
+        let duration = reader.u64()?;
+        Ok((timescale, duration))
+    } else {
+        reader.skip(8)?;
+        let timescale = reader.u32()?;
+        // All ones means the duration is unknown
+        let duration = match reader.u32()? {
+            u32::MAX => u64::MAX,
+            duration => duration as u64,
+        };
+        Ok((timescale, duration))
+    }
+}
+
+/// ISO 639-2 language code packed into three 5-bit letters in `mdhd`
+fn parse_language(data: &[u8]) -> Result<Option<String>, AvError> {
+    let offset = if data.first() == Some(&1) { 32 } else { 20 };
+    let mut reader = ByteReader::new(data.get(offset..).unwrap_or_default(), "MP4");
+    let packed = reader.u16()?;
+
+    // QuickTime stores Macintosh language codes below 0x400 instead
+    if packed < 0x400 {
+        return Ok(None);
+    }
+
+    let language: String = [10, 5, 0]
+        .iter()
+        .map(|shift| (((packed >> shift) & 0x1f) as u8 + 0x60) as char)
+        .collect();
+    Ok(Some(language).filter(|language| language != "und"))
+}
+
+/// Total number of samples in an `stts` box
+fn sample_count(data: &[u8]) -> Result<u64, AvError> {
+    let mut reader = ByteReader::new(data, "MP4");
+    reader.skip(4)?; // version and flags
+    let entries = reader.u32()?;
+
+    let mut total = 0u64;
+    for _ in 0..entries {
+        total += reader.u32()? as u64;
+        reader.skip(4)?; // sample delta
+    }
+    Ok(total)
+}
+
+/// Split the body of a container box into its children
+fn boxes(data: &[u8]) -> Result<Vec<Mp4Box<'_>>, AvError> {
+    let mut children = Vec::new();
+    let mut reader = ByteReader::new(data, "MP4");
+
+    while reader.remaining() >= 8 {
+        let len = reader.u32()? as u64;
+        let kind: &[u8; 4] = reader.take(4)?.try_into().unwrap();
+        let body_len = match len {
+            1 => reader.u64()?.checked_sub(16),
+            0 => Some(reader.remaining() as u64),
+            len => len.checked_sub(8),
+        }
+        .ok_or(AvError::Malformed("MP4"))?;
+
+        let body_len = usize::try_from(body_len).map_err(|_| AvError::Malformed("MP4"))?;
+        children.push((kind, reader.take(body_len)?));
+    }
+
+    Ok(children)
+}
+
+fn seconds(duration: u64, timescale: u32) -> Option<f64> {
+    (timescale > 0 && duration > 0 && duration != u64::MAX)
+        .then(|| duration as f64 / timescale as f64)
+}
+
+fn is_uncompressed(fourcc: &[u8; 4]) -> bool {
+    matches!(
+        fourcc,
+        b"lpcm" | b"sowt" | b"twos" | b"in24" | b"in32" | b"fl32" | b"fl64" | b"ipcm" | b"fLaC"
+    )
+}
+
+fn codec_name(fourcc: &[u8; 4]) -> String {
+    match fourcc {
+        b"avc1" | b"avc3" => "h264",
+        b"hvc1" | b"hev1" => "hevc",
+        b"vp08" => "vp8",
+        b"vp09" => "vp9",
+        b"av01" => "av1",
+        b"mp4v" => "mpeg4",
+        b"apch" | b"apcn" | b"apcs" | b"apco" | b"ap4h" => "prores",
+        b"mp4a" => "aac",
+        b"Opus" => "opus",
+        b"fLaC" => "flac",
+        b"alac" => "alac",
+        b"ac-3" => "ac3",
+        b"ec-3" => "eac3",
+        b".mp3" => "mp3",
+        b"lpcm" | b"sowt" | b"twos" | b"in24" | b"in32" | b"fl32" | b"fl64" | b"ipcm" => "pcm",
+        b"tx3g" => "mov_text",
+        b"wvtt" => "webvtt",
+        other => return String::from_utf8_lossy(other).trim().to_string(),
+    }
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::extract_av_metadata;
+    use super::*;
+    use std::io::Cursor;
+
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut bytes = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(kind);
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    /// A version 0 `mvhd` or `mdhd` body with an optional packed language
+    fn header_times(timescale: u32, duration: u32, language: u16) -> Vec<u8> {
+        let mut body = vec![0; 12];
+        body.extend_from_slice(&timescale.to_be_bytes());
+        body.extend_from_slice(&duration.to_be_bytes());
+        body.extend_from_slice(&language.to_be_bytes());
+        body.extend_from_slice(&[0; 2]);
+        body
+    }
+
+    fn hdlr(handler: &[u8; 4]) -> Vec<u8> {
+        let mut body = vec![0; 8];
+        body.extend_from_slice(handler);
+        body.extend_from_slice(&[0; 13]);
+        mp4_box(b"hdlr", &body)
+    }
+
+    fn stsd(fourcc: &[u8; 4], entry: &[u8]) -> Vec<u8> {
+        let mut body = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        body.extend(mp4_box(fourcc, &[&[0; 8], entry].concat()));
+        mp4_box(b"stsd", &body)
+    }
+
+    fn trak(mdhd: Vec<u8>, handler: &[u8; 4], stbl: Vec<u8>) -> Vec<u8> {
+        let minf = mp4_box(b"minf", &mp4_box(b"stbl", &stbl));
+        let mdia = mp4_box(
+            b"mdia",
+            &[mp4_box(b"mdhd", &mdhd), hdlr(handler), minf].concat(),
+        );
+        mp4_box(b"trak", &mdia)
+    }
+
+    fn movie(brand: &[u8; 4]) -> Vec<u8> {
+        // "eng" packed into 5-bit letters
+        let eng = (5 << 10) | (14 << 5) | 7;
+
+        let mut visual = vec![0; 16];
+        visual.extend_from_slice(&1280u16.to_be_bytes());
+        visual.extend_from_slice(&720u16.to_be_bytes());
+        visual.extend_from_slice(&[0; 50]);
+        let mut stts = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        stts.extend_from_slice(&300u32.to_be_bytes());
+        stts.extend_from_slice(&512u32.to_be_bytes());
+        let video = trak(
+            header_times(15360, 153600, eng),
+            b"vide",
+            [stsd(b"avc1", &visual), mp4_box(b"stts", &stts)].concat(),
+        );
+
+        let mut sound = vec![0; 8];
+        sound.extend_from_slice(&2u16.to_be_bytes());
+        sound.extend_from_slice(&16u16.to_be_bytes());
+        sound.extend_from_slice(&[0; 4]);
+        sound.extend_from_slice(&(44100u32 << 16).to_be_bytes());
+        let audio = trak(
+            header_times(44100, 441000, 0x55c4), // "und"
+            b"soun",
+            stsd(b"mp4a", &sound),
+        );
+
+        let moov = mp4_box(
+            b"moov",
+            &[
+                mp4_box(b"mvhd", &header_times(1000, 10_000, 0)),
+                video,
+                audio,
+            ]
+            .concat(),
+        );
+
+        // The index comes after the media data, as most encoders write it
+        let mut bytes = mp4_box(b"ftyp", &[brand.as_slice(), &[0; 4], b"isom"].concat());
+        bytes.extend(mp4_box(b"mdat", &vec![0; 4096]));
+        bytes.extend(moov);
+        bytes
+    }
+
+    #[test]
+    fn test_parse_mp4() {
+        let metadata = extract_av_metadata(&mut Cursor::new(movie(b"isom"))).unwrap();
+
+        assert_eq!(metadata.container, "mp4");
+        assert_eq!(metadata.duration, Some(10.0));
+        assert_eq!((metadata.width, metadata.height), (Some(1280), Some(720)));
+        assert!(metadata.bitrate.is_some());
+        assert_eq!(metadata.tracks.len(), 2);
+
+        let video = &metadata.tracks[0];
+        assert_eq!(video.kind, AvTrackKind::Video);
+        assert_eq!(video.codec.as_deref(), Some("h264"));
+        assert_eq!(video.duration, Some(10.0));
+        assert_eq!(video.frame_rate, Some(30.0));
+        assert_eq!(video.language.as_deref(), Some("eng"));
+
+        let audio = &metadata.tracks[1];
+        assert_eq!(audio.kind, AvTrackKind::Audio);
+        assert_eq!(audio.codec.as_deref(), Some("aac"));
+        assert_eq!(audio.sample_rate, Some(44100));
+        assert_eq!(audio.channels, Some(2));
+        assert_eq!(audio.bits_per_sample, None);
+        assert_eq!(audio.language, None);
+    }
+
+    #[test]
+    fn test_quicktime_brand() {
+        let metadata = extract_av_metadata(&mut Cursor::new(movie(b"qt  "))).unwrap();
+        assert_eq!(metadata.container, "quicktime");
+    }
+
+    #[test]
+    fn test_oversized_box() {
+        // A 64-bit size near u64::MAX must not wrap the offset around
+        let mut bytes = mp4_box(b"ftyp", b"isom\0\0\0\0");
+        bytes.extend_from_slice(&1u32.to_be_bytes());
+        bytes.extend_from_slice(b"mdat");
+        bytes.extend_from_slice(&(u64::MAX - 8).to_be_bytes());
+        bytes.extend_from_slice(&[0; 64]);
+        assert!(matches!(
+            extract_av_metadata(&mut Cursor::new(bytes)),
+            Err(AvError::Malformed("MP4"))
+        ));
+
+        // A box smaller than its own header
+        let mut bytes = mp4_box(b"ftyp", b"isom\0\0\0\0");
+        bytes.extend_from_slice(&4u32.to_be_bytes());
+        bytes.extend_from_slice(b"mdat");
+        assert!(extract_av_metadata(&mut Cursor::new(bytes)).is_err());
+    }
+
+    #[test]
+    fn test_missing_moov() {
+        let mut bytes = mp4_box(b"ftyp", b"isom\0\0\0\0");
+        bytes.extend(mp4_box(b"mdat", &[0; 64]));
+        assert!(matches!(
+            extract_av_metadata(&mut Cursor::new(bytes)),
+            Err(AvError::Malformed("MP4"))
+        ));
+    }
+}
@@ -0,0 +1,285 @@
+//! MPEG audio (MP3) files
+//!
+//! The duration comes from a Xing/Info or VBRI header when the encoder wrote
+//! one, and is otherwise estimated from the first frame's bitrate.
+
+use super::{id3v2_len, read_at, AvError, AvSource, ByteReader};
+use crate::media::models::{AvMetadata, AvTrack, AvTrackKind};
+
+/// How far past the ID3v2 tag to look for the first frame
+const SYNC_WINDOW: usize = 64 * 1024;
+
+/// Bitrates in kbit/s by bitrate index, for MPEG-1 layers I-III and MPEG-2/2.5 layers I and II/III
+const BITRATES: [[u16; 15]; 5] = [
+    [
+        0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+    ],
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+    ],
+    [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ],
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+    ],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+
+/// A decoded MPEG audio frame header
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FrameHeader {
+    /// 1 for MPEG-1, 2 for MPEG-2 and 25 for MPEG-2.5
+    version: u8,
+    layer: u8,
+    bitrate: u32,
+    sample_rate: u32,
+    channels: u16,
+}
+
+impl FrameHeader {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 4 || bytes[0] != 0xff || bytes[1] & 0xe0 != 0xe0 {
+            return None;
+        }
+
+        let version = match (bytes[1] >> 3) & 0x3 {
+            0 => 25,
+            2 => 2,
+            3 => 1,
+            _ => return None,
+        };
+        let layer = match (bytes[1] >> 1) & 0x3 {
+            1 => 3,
+            2 => 2,
+            3 => 1,
+            _ => return None,
+        };
+
+        let bitrate_index = (bytes[2] >> 4) as usize;
+        let rate_index = ((bytes[2] >> 2) & 0x3) as usize;
+        if bitrate_index == 0 || bitrate_index == 15 || rate_index == 3 {
+            return None;
+        }
+
+        let table = match (version, layer) {
+            (1, layer) => layer as usize - 1,
+            (_, 1) => 3,
+            _ => 4,
+        };
+        let base_rate = [44100, 48000, 32000][rate_index];
+
+        Some(Self {
+            version,
+            layer,
+            bitrate: BITRATES[table][bitrate_index] as u32 * 1000,
+            sample_rate: match version {
+                1 => base_rate,
+                2 => base_rate / 2,
+                _ => base_rate / 4,
+            },
+            channels: if bytes[3] >> 6 == 3 { 1 } else { 2 },
+        })
+    }
+
+    fn samples_per_frame(&self) -> u32 {
+        match (self.version, self.layer) {
+            (_, 1) => 384,
+            (1, _) | (_, 2) => 1152,
+            _ => 576,
+        }
+    }
+
+    /// Offset of a Xing/Info header from the start of the frame
+    fn xing_offset(&self) -> usize {
+        4 + match (self.version == 1, self.channels == 1) {
+            (true, false) => 32,
+            (true, true) | (false, false) => 17,
+            (false, true) => 9,
+        }
+    }
+}
+
+/// Find the first of two consecutive valid frame headers after the ID3v2 tag
+fn find_first_frame(source: &mut dyn AvSource) -> Result<Option<(u64, Vec<u8>)>, AvError> {
+    let start = id3v2_len(&read_at(source, 0, 10)?);
+    let window = read_at(source, start, SYNC_WINDOW)?;
+
+    for pos in 0..window.len().saturating_sub(4) {
+        let Some(header) = FrameHeader::parse(&window[pos..]) else {
+            continue;
+        };
+
+        // A lone sync word in other data is common; insist on a second frame
+        // where the first one ends, unless the file is a single frame
+        let padding = (window[pos + 2] >> 1) & 1;
+        let frame_len = match header.layer {
+            1 => (12 * header.bitrate / header.sample_rate + padding as u32) * 4,
+            _ => {
+                header.samples_per_frame() / 8 * header.bitrate / header.sample_rate
+                    + padding as u32
+            }
+        } as usize;
+        let next = pos + frame_len;
+        let confirmed = match window.get(next..next + 4) {
+            Some(bytes) => FrameHeader::parse(bytes).is_some(),
+            None => pos == 0,
+        };
+        if !confirmed {
+            continue;
+        }
+
+        return Ok(Some((start + pos as u64, window[pos..].to_vec())));
+    }
+
+    Ok(None)
+}
+
+pub(super) fn detect(source: &mut dyn AvSource) -> Result<bool, AvError> {
+    Ok(find_first_frame(source)?.is_some())
+}
+
+pub(super) fn parse(source: &mut dyn AvSource, size: u64) -> Result<AvMetadata, AvError> {
+    let (offset, frame) = find_first_frame(source)?.ok_or(AvError::Malformed("MP3"))?;
+    let header = FrameHeader::parse(&frame).ok_or(AvError::Malformed("MP3"))?;
+
+    let mut track = AvTrack::new(AvTrackKind::Audio);
+    track.codec = Some(format!("mp{}", header.layer));
+    track.sample_rate = Some(header.sample_rate);
+    track.channels = Some(header.channels);
+
+    let frames = xing_frames(&frame, header.xing_offset())?.or(vbri_frames(&frame)?);
+    let (duration, bitrate) = match frames {
+        Some(frames) => {
+            let duration =
+                frames as f64 * header.samples_per_frame() as f64 / header.sample_rate as f64;
+            (Some(duration), None)
+        }
+        None => {
+            // Constant bitrate: everything after the first frame except an
+            // ID3v1 tag at the end is audio
+            let id3v1 = if size >= 128 && read_at(source, size - 128, 3)? == b"TAG" {
+                128
+            } else {
+                0
+            };
+            let audio_len = size.saturating_sub(offset + id3v1);
+            (
+                Some(audio_len as f64 * 8.0 / header.bitrate as f64),
+                Some(header.bitrate as u64),
+            )
+        }
+    };
+    track.duration = duration;
+
+    Ok(AvMetadata {
+        container: "mp3".to_string(),
+        duration,
+        bitrate,
+        tracks: vec![track],
+        ..Default::default()
+    })
+}
+
+/// Frame count from a Xing (VBR) or Info (CBR) header
+fn xing_frames(frame: &[u8], offset: usize) -> Result<Option<u32>, AvError> {
+    let Some(tag) = frame.get(offset..) else {
+        return Ok(None);
+    };
+    if !tag.starts_with(b"Xing") && !tag.starts_with(b"Info") {
+        return Ok(None);
+    }
+
+    let mut reader = ByteReader::new(&tag[4..], "MP3");
+    let flags = reader.u32()?;
+    (flags & 1 != 0).then(|| reader.u32()).transpose()
+}
+
+/// Frame count from a VBRI header, which Fraunhofer encoders place 32 bytes in
+fn vbri_frames(frame: &[u8]) -> Result<Option<u32>, AvError> {
+    match frame.get(36..) {
+        Some(tag) if tag.starts_with(b"VBRI") => {
+            let mut reader = ByteReader::new(&tag[4..], "MP3");
+            reader.skip(10)?; // version, delay, quality and byte count
+            Ok(Some(reader.u32()?))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::extract_av_metadata;
+    use super::*;
+    use std::io::Cursor;
+
+    /// MPEG-1 layer III, 128 kbit/s, 44.1kHz, joint stereo: 417 byte frames
+    const HEADER: [u8; 4] = [0xff, 0xfb, 0x90, 0x40];
+    const FRAME_LEN: usize = 417;
+
+    fn frames(count: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for _ in 0..count {
+            let start = bytes.len();
+            bytes.extend_from_slice(&HEADER);
+            bytes.resize(start + FRAME_LEN, 0);
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_frame_header() {
+        let header = FrameHeader::parse(&HEADER).unwrap();
+        assert_eq!(
+            header,
+            FrameHeader {
+                version: 1,
+                layer: 3,
+                bitrate: 128_000,
+                sample_rate: 44100,
+                channels: 2,
+            }
+        );
+        assert_eq!(header.samples_per_frame(), 1152);
+        assert!(FrameHeader::parse(&[0xff, 0xfb, 0xf0, 0x40]).is_none());
+    }
+
+    #[test]
+    fn test_constant_bitrate_estimate() {
+        let mut bytes = b"ID3\x04\x00\x00\x00\x00\x00\x02\x00\x00".to_vec();
+        bytes.extend_from_slice(&frames(100));
+
+        let metadata = extract_av_metadata(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(metadata.container, "mp3");
+        assert_eq!(metadata.bitrate, Some(128_000));
+        let duration = metadata.duration.unwrap();
+        assert!((duration - 100.0 * 417.0 * 8.0 / 128_000.0).abs() < 1e-9);
+
+        let track = &metadata.tracks[0];
+        assert_eq!(track.codec.as_deref(), Some("mp3"));
+        assert_eq!(track.sample_rate, Some(44100));
+        assert_eq!(track.channels, Some(2));
+    }
+
+    #[test]
+    fn test_xing_frame_count() {
+        let mut bytes = frames(3);
+        let offset = 4 + 32;
+        bytes[offset..offset + 4].copy_from_slice(b"Xing");
+        bytes[offset + 4..offset + 8].copy_from_slice(&1u32.to_be_bytes());
+        bytes[offset + 8..offset + 12].copy_from_slice(&1000u32.to_be_bytes());
+
+        let metadata = extract_av_metadata(&mut Cursor::new(bytes)).unwrap();
+        let duration = metadata.duration.unwrap();
+        assert!((duration - 1000.0 * 1152.0 / 44100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_stray_sync_word_is_not_mp3() {
+        let bytes = [b"some text ".as_slice(), &HEADER, b" and more text"].concat();
+        assert!(matches!(
+            extract_av_metadata(&mut Cursor::new(bytes)),
+            Err(AvError::Unsupported)
+        ));
+    }
+}
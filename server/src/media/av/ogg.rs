@@ -0,0 +1,313 @@
+//! Ogg files with Vorbis, Opus, FLAC or Theora streams
+//!
+//! Each logical stream announces its codec in the first packet of its
+//! beginning-of-stream page. Durations come from the granule position of
+//! the stream's last page, found near the end of the file.
+
+use std::collections::HashMap;
+
+use super::flac::parse_stream_info;
+use super::{read_at, AvError, AvSource};
+use crate::media::models::{AvMetadata, AvTrack, AvTrackKind};
+
+/// How much of the end of the file is searched for the last pages
+const TAIL_WINDOW: u64 = 64 * 1024;
+
+/// Beginning-of-stream flag in the page header type
+const BOS: u8 = 0x02;
+
+/// The fixed part of a page header
+struct PageHeader {
+    header_type: u8,
+    granule: u64,
+    serial: u32,
+    /// Header length including the segment table
+    header_len: usize,
+    /// Length of the first packet starting on this page
+    first_packet_len: usize,
+    /// Length of all data on this page
+    data_len: usize,
+}
+
+impl PageHeader {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 27 || &bytes[..4] != b"OggS" || bytes[4] != 0 {
+            return None;
+        }
+
+        let segments = bytes[26] as usize;
+        let table = bytes.get(27..27 + segments)?;
+        let first_packet_len = table
+            .iter()
+            .position(|lacing| *lacing < 255)
+            .map_or(segments * 255, |end| {
+                table[..=end].iter().map(|l| *l as usize).sum()
+            });
+
+        Some(Self {
+            header_type: bytes[5],
+            granule: u64::from_le_bytes(bytes[6..14].try_into().unwrap()),
+            serial: u32::from_le_bytes(bytes[14..18].try_into().unwrap()),
+            header_len: 27 + segments,
+            first_packet_len,
+            data_len: table.iter().map(|l| *l as usize).sum(),
+        })
+    }
+}
+
+/// How a stream's granule positions translate into time
+enum Timing {
+    /// Granules count samples at this rate, after `pre_skip` priming samples
+    Samples {
+        rate: u32,
+        pre_skip: u64,
+    },
+    /// Theora packs keyframe and delta frame numbers into the granule
+    Frames {
+        shift: u32,
+        fps: f64,
+        offset: u64,
+    },
+    Unknown,
+}
+
+pub(super) fn detect(head: &[u8]) -> bool {
+    head.starts_with(b"OggS")
+}
+
+pub(super) fn parse(source: &mut dyn AvSource, size: u64) -> Result<AvMetadata, AvError> {
+    // Beginning-of-stream pages all come first
+    let mut streams = Vec::new();
+    let mut offset = 0;
+    loop {
+        let head = read_at(source, offset, 27 + 255)?;
+        let Some(page) = PageHeader::parse(&head) else {
+            break;
+        };
+        if page.header_type & BOS == 0 {
+            break;
+        }
+
+        let packet = read_at(
+            source,
+            offset + page.header_len as u64,
+            page.first_packet_len.min(128),
+        )?;
+        streams.push((page.serial, identify(&packet)));
+        offset += (page.header_len + page.data_len) as u64;
+    }
+
+    if streams.is_empty() {
+        return Err(AvError::Malformed("Ogg"));
+    }
+
+    let last_granules = last_granules(source, size)?;
+    let tracks = streams
+        .into_iter()
+        .map(|(serial, (mut track, timing))| {
+            track.duration = last_granules
+                .get(&serial)
+                .and_then(|granule| duration(&timing, *granule));
+            track
+        })
+        .collect();
+
+    Ok(AvMetadata {
+        container: "ogg".to_string(),
+        tracks,
+        ..Default::default()
+    })
+}
+
+/// Describe a stream from its identification header
+fn identify(packet: &[u8]) -> (AvTrack, Timing) {
+    let le16 = |at: usize| u16::from_le_bytes([packet[at], packet[at + 1]]);
+    let le32 = |at: usize| u32::from_le_bytes(packet[at..at + 4].try_into().unwrap());
+    let be24 = |at: usize| u32::from_be_bytes([0, packet[at], packet[at + 1], packet[at + 2]]);
+    let be32 = |at: usize| u32::from_be_bytes(packet[at..at + 4].try_into().unwrap());
+
+    if packet.starts_with(b"\x01vorbis") && packet.len() >= 16 {
+        let mut track = AvTrack::new(AvTrackKind::Audio);
+        track.codec = Some("vorbis".to_string());
+        track.channels = Some(packet[11] as u16);
+        track.sample_rate = Some(le32(12));
+        let timing = Timing::Samples {
+            rate: le32(12),
+            pre_skip: 0,
+        };
+        return (track, timing);
+    }
+
+    if packet.starts_with(b"OpusHead") && packet.len() >= 19 {
+        // Opus always decodes at 48kHz, whatever rate the input had
+        let mut track = AvTrack::new(AvTrackKind::Audio);
+        track.codec = Some("opus".to_string());
+        track.channels = Some(packet[9] as u16);
+        track.sample_rate = Some(48000);
+        let timing = Timing::Samples {
+            rate: 48000,
+            pre_skip: le16(10) as u64,
+        };
+        return (track, timing);
+    }
+
+    if packet.starts_with(b"\x7fFLAC") && packet.len() >= 17 + 34 {
+        if let Ok(mut track) = parse_stream_info(&packet[17..17 + 34]) {
+            let rate = track.sample_rate.unwrap_or(0);
+            track.duration = None;
+            return (track, Timing::Samples { rate, pre_skip: 0 });
+        }
+    }
+
+    if packet.starts_with(b"\x80theora") && packet.len() >= 42 {
+        let mut track = AvTrack::new(AvTrackKind::Video);
+        track.codec = Some("theora".to_string());
+        track.width = Some(be24(14));
+        track.height = Some(be24(17));
+
+        let (numerator, denominator) = (be32(22), be32(26));
+        let fps = (denominator > 0).then(|| numerator as f64 / denominator as f64);
+        track.frame_rate = fps;
+
+        // Granules count from frame 1 since bitstream version 3.2.1
+        let shift = (((packet[40] & 0x03) << 3) | (packet[41] >> 5)) as u32;
+        let offset = u64::from(packet[9] >= 1);
+        let timing = match fps {
+            Some(fps) if fps > 0.0 => Timing::Frames { shift, fps, offset },
+            _ => Timing::Unknown,
+        };
+        return (track, timing);
+    }
+
+    (AvTrack::new(AvTrackKind::Other), Timing::Unknown)
+}
+
+fn duration(timing: &Timing, granule: u64) -> Option<f64> {
+    match timing {
+        Timing::Samples { rate, pre_skip } if *rate > 0 => {
+            Some(granule.saturating_sub(*pre_skip) as f64 / *rate as f64)
+        }
+        Timing::Frames { shift, fps, offset } => {
+            let keyframe = granule.checked_shr(*shift).unwrap_or(0);
+            let delta = granule & ((1u64 << shift) - 1);
+            Some((keyframe + delta + offset) as f64 / fps)
+        }
+        _ => None,
+    }
+}
+
+/// The last known granule position of every stream with a page near the end
+fn last_granules(source: &mut dyn AvSource, size: u64) -> Result<HashMap<u32, u64>, AvError> {
+    let start = size.saturating_sub(TAIL_WINDOW);
+    let tail = read_at(source, start, TAIL_WINDOW as usize)?;
+
+    let mut granules = HashMap::new();
+    let mut pos = 0;
+    while let Some(found) = tail[pos..].windows(4).position(|w| w == b"OggS") {
+        pos += found;
+        if let Some(page) = PageHeader::parse(&tail[pos..]) {
+            // -1 marks pages on which no packet ends
+            if page.granule != u64::MAX {
+                granules.insert(page.serial, page.granule);
+            }
+        }
+        pos += 4;
+    }
+
+    Ok(granules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::extract_av_metadata;
+    use super::super::flac::tests::stream_info;
+    use super::*;
+    use std::io::Cursor;
+
+    /// A page holding a single packet
+    fn page(header_type: u8, granule: u64, serial: u32, packet: &[u8]) -> Vec<u8> {
+        let mut bytes = b"OggS\x00".to_vec();
+        bytes.push(header_type);
+        bytes.extend_from_slice(&granule.to_le_bytes());
+        bytes.extend_from_slice(&serial.to_le_bytes());
+        bytes.extend_from_slice(&[0; 8]); // sequence number and CRC
+        let mut lacing = vec![255; packet.len() / 255];
+        lacing.push((packet.len() % 255) as u8);
+        bytes.push(lacing.len() as u8);
+        bytes.extend_from_slice(&lacing);
+        bytes.extend_from_slice(packet);
+        bytes
+    }
+
+    fn opus_head(channels: u8, pre_skip: u16) -> Vec<u8> {
+        let mut head = b"OpusHead\x01".to_vec();
+        head.push(channels);
+        head.extend_from_slice(&pre_skip.to_le_bytes());
+        head.extend_from_slice(&44100u32.to_le_bytes());
+        head.extend_from_slice(&[0, 0, 0]);
+        head
+    }
+
+    #[test]
+    fn test_parse_opus() {
+        let mut bytes = page(BOS, 0, 7, &opus_head(2, 312));
+        bytes.extend(page(0, 0, 7, b"OpusTags"));
+        bytes.extend(page(0, 48000, 7, &[0; 300]));
+        // Pages with no packet ending on them do not carry a position
+        bytes.extend(page(0, 96312, 7, &[0; 300]));
+        bytes.extend(page(0, u64::MAX, 7, &[0; 100]));
+
+        let metadata = extract_av_metadata(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(metadata.container, "ogg");
+        assert_eq!(metadata.duration, Some(2.0));
+        assert!(metadata.bitrate.is_some());
+
+        let track = &metadata.tracks[0];
+        assert_eq!(track.codec.as_deref(), Some("opus"));
+        assert_eq!(track.channels, Some(2));
+        assert_eq!(track.sample_rate, Some(48000));
+    }
+
+    #[test]
+    fn test_parse_theora_with_flac() {
+        let mut theora = b"\x80theora\x03\x02\x01".to_vec();
+        theora.extend_from_slice(&[0, 40, 0, 30]); // frame size in macroblocks
+        theora.extend_from_slice(&[0, 2, 128, 0, 1, 224, 0, 0]); // 640x480 picture
+        theora.extend_from_slice(&25u32.to_be_bytes());
+        theora.extend_from_slice(&1u32.to_be_bytes());
+        theora.extend_from_slice(&[0; 10]);
+        theora.extend_from_slice(&[0x00, 0xc0]); // keyframe granule shift of 6
+        theora.extend_from_slice(&[0; 2]);
+
+        let mut flac = b"\x7fFLAC\x01\x00\x00\x01fLaC\x00\x00\x00\x22".to_vec();
+        flac.extend(stream_info(48000, 1, 16, 0));
+
+        let mut bytes = page(BOS, 0, 1, &theora);
+        bytes.extend(page(BOS, 0, 2, &flac));
+        bytes.extend(page(0, 0, 1, &[0; 50]));
+        bytes.extend(page(0, (240 << 6) | 9, 1, &[0; 50]));
+        bytes.extend(page(0, 480000, 2, &[0; 50]));
+
+        let metadata = extract_av_metadata(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(metadata.tracks.len(), 2);
+        assert_eq!((metadata.width, metadata.height), (Some(640), Some(480)));
+        assert_eq!(metadata.duration, Some(10.0));
+
+        let video = &metadata.tracks[0];
+        assert_eq!(video.kind, AvTrackKind::Video);
+        assert_eq!(video.frame_rate, Some(25.0));
+        assert_eq!(video.duration, Some(250.0 / 25.0));
+
+        let audio = &metadata.tracks[1];
+        assert_eq!(audio.codec.as_deref(), Some("flac"));
+        assert_eq!(audio.duration, Some(10.0));
+    }
+
+    #[test]
+    fn test_unknown_stream() {
+        let bytes = page(BOS, 0, 3, b"\x80someothercodec");
+        let metadata = extract_av_metadata(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(metadata.tracks[0].kind, AvTrackKind::Other);
+        assert_eq!(metadata.duration, None);
+    }
+}
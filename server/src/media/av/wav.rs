@@ -0,0 +1,133 @@
+//! WAV (RIFF WAVE) files
+
+use super::{read_at, AvError, AvSource, ByteReader};
+use crate::media::models::{AvMetadata, AvTrack, AvTrackKind};
+
+pub(super) fn detect(head: &[u8]) -> bool {
+    head.len() >= 12 && &head[..4] == b"RIFF" && &head[8..12] == b"WAVE"
+}
+
+pub(super) fn parse(source: &mut dyn AvSource, size: u64) -> Result<AvMetadata, AvError> {
+    let mut track = AvTrack::new(AvTrackKind::Audio);
+    let mut byte_rate = None;
+    let mut data_len = None;
+
+    // Chunks are word-aligned and follow the 12 byte RIFF header
+    let mut offset = 12;
+    while offset + 8 <= size && (byte_rate.is_none() || data_len.is_none()) {
+        let header = read_at(source, offset, 8)?;
+        let mut reader = ByteReader::new(&header, "WAV");
+        let id = reader.take(4)?;
+        let len = u32::from_le_bytes(reader.take(4)?.try_into().unwrap()) as u64;
+
+        match id {
+            b"fmt " => {
+                let body = read_at(source, offset + 8, len.min(40) as usize)?;
+                if body.len() < 16 {
+                    return Err(AvError::Malformed("WAV"));
+                }
+                let le16 = |at: usize| u16::from_le_bytes([body[at], body[at + 1]]);
+                let le32 = |at: usize| u32::from_le_bytes(body[at..at + 4].try_into().unwrap());
+
+                // WAVE_FORMAT_EXTENSIBLE keeps the real format in its sub-format GUID
+                let format = match le16(0) {
+                    0xfffe if body.len() >= 26 => le16(24),
+                    format => format,
+                };
+                track.codec = Some(codec_name(format));
+                track.channels = Some(le16(2));
+                track.sample_rate = Some(le32(4));
+                track.bits_per_sample = Some(le16(14));
+                byte_rate = Some(le32(8));
+            }
+            b"data" => data_len = Some(len.min(size.saturating_sub(offset + 8))),
+            _ => {}
+        }
+
+        offset += 8 + len + (len & 1);
+    }
+
+    let byte_rate = byte_rate.ok_or(AvError::Malformed("WAV"))?;
+    track.duration = data_len
+        .filter(|_| byte_rate > 0)
+        .map(|len| len as f64 / byte_rate as f64);
+
+    Ok(AvMetadata {
+        container: "wav".to_string(),
+        duration: track.duration,
+        bitrate: Some(byte_rate as u64 * 8),
+        tracks: vec![track],
+        ..Default::default()
+    })
+}
+
+fn codec_name(format: u16) -> String {
+    match format {
+        0x0001 => "pcm".to_string(),
+        0x0003 => "pcm_float".to_string(),
+        0x0006 => "alaw".to_string(),
+        0x0007 => "mulaw".to_string(),
+        0x0011 => "adpcm_ima".to_string(),
+        0x0055 => "mp3".to_string(),
+        other => format!("0x{:04x}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::extract_av_metadata;
+    use super::*;
+    use std::io::Cursor;
+
+    /// A PCM WAV file holding `samples` frames of silence
+    fn wav(sample_rate: u32, channels: u16, samples: u32) -> Vec<u8> {
+        let block_align = channels * 2;
+        let data_len = samples * block_align as u32;
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data_len).to_le_bytes());
+        bytes.extend_from_slice(b"WAVE");
+        bytes.extend_from_slice(b"fmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&channels.to_le_bytes());
+        bytes.extend_from_slice(&sample_rate.to_le_bytes());
+        bytes.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        bytes.extend_from_slice(&block_align.to_le_bytes());
+        bytes.extend_from_slice(&16u16.to_le_bytes());
+        // Unknown chunks with an odd length are padded and skipped
+        bytes.extend_from_slice(b"junk");
+        bytes.extend_from_slice(&3u32.to_le_bytes());
+        bytes.extend_from_slice(&[1, 2, 3, 0]);
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&data_len.to_le_bytes());
+        bytes.resize(bytes.len() + data_len as usize, 0);
+        bytes
+    }
+
+    #[test]
+    fn test_parse_wav() {
+        let mut source = Cursor::new(wav(8000, 2, 12000));
+        let metadata = extract_av_metadata(&mut source).unwrap();
+
+        assert_eq!(metadata.container, "wav");
+        assert_eq!(metadata.duration, Some(1.5));
+        assert_eq!(metadata.bitrate, Some(8000 * 2 * 16));
+        assert_eq!(metadata.width, None);
+
+        let track = &metadata.tracks[0];
+        assert_eq!(track.kind, AvTrackKind::Audio);
+        assert_eq!(track.codec.as_deref(), Some("pcm"));
+        assert_eq!(track.sample_rate, Some(8000));
+        assert_eq!(track.channels, Some(2));
+        assert_eq!(track.bits_per_sample, Some(16));
+    }
+
+    #[test]
+    fn test_truncated_wav() {
+        let mut bytes = wav(8000, 1, 10);
+        bytes.truncate(20);
+        assert!(extract_av_metadata(&mut Cursor::new(bytes)).is_err());
+    }
+}
@@ -0,0 +1,269 @@
+//! Media collection repository for database operations
+//!
+//! Collections are named groups of media blobs owned by a user. Blobs keep a
+//! position within each collection; positions may have gaps, and blobs added
+//! together are appended in the order given. Blobs in the trash stay in their
+//! collections but are not counted or listed until restored.
+
+use sqlx::error::ErrorKind;
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use crate::database::DatabaseConnection;
+use crate::error::WebauthnError;
+
+use super::models::MediaCollection;
+use super::repository::MediaError;
+
+/// Media collection repository for database operations
+pub struct MediaCollectionRepository<'a> {
+    db: &'a DatabaseConnection,
+}
+
+impl<'a> MediaCollectionRepository<'a> {
+    /// Create a new repository instance
+    pub fn new(db: &'a DatabaseConnection) -> Self {
+        Self { db }
+    }
+
+    /// Create a collection; names must be unique per owner
+    pub async fn create(
+        &self,
+        owner_user_id: Uuid,
+        name: &str,
+        description: Option<&str>,
+        retention_days: Option<i32>,
+    ) -> Result<MediaCollection, WebauthnError> {
+        info!("Creating media collection {:?} for {}", name, owner_user_id);
+
+        let collection = sqlx::query_as!(
+            MediaCollection,
+            r#"
+            INSERT INTO media_collections (id, owner_user_id, name, description, retention_days)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, owner_user_id, name, description, retention_days,
+                      0::bigint as "blob_count!", created_at, updated_at
+            "#,
+            Uuid::new_v4(),
+            owner_user_id,
+            name,
+            description,
+            retention_days
+        )
+        .fetch_one(self.db.pool())
+        .await
+        .map_err(duplicate_name)?;
+
+        Ok(collection)
+    }
+
+    /// Find a collection by ID
+    pub async fn find(&self, id: Uuid) -> Result<Option<MediaCollection>, WebauthnError> {
+        let collection = sqlx::query_as!(
+            MediaCollection,
+            r#"
+            SELECT c.id, c.owner_user_id, c.name, c.description, c.retention_days,
+                   (SELECT COUNT(*) FROM media_collection_items i
+                    JOIN media_blobs b ON b.id = i.blob_id
+                    WHERE i.collection_id = c.id AND b.deleted_at IS NULL) as "blob_count!",
+                   c.created_at, c.updated_at
+            FROM media_collections c
+            WHERE c.id = $1
+            "#,
+            id
+        )
+        .fetch_optional(self.db.pool())
+        .await?;
+
+        Ok(collection)
+    }
+
+    /// List a user's collections by name
+    pub async fn list_for_owner(
+        &self,
+        owner_user_id: Uuid,
+    ) -> Result<Vec<MediaCollection>, WebauthnError> {
+        let collections = sqlx::query_as!(
+            MediaCollection,
+            r#"
+            SELECT c.id, c.owner_user_id, c.name, c.description, c.retention_days,
+                   (SELECT COUNT(*) FROM media_collection_items i
+                    JOIN media_blobs b ON b.id = i.blob_id
+                    WHERE i.collection_id = c.id AND b.deleted_at IS NULL) as "blob_count!",
+                   c.created_at, c.updated_at
+            FROM media_collections c
+            WHERE c.owner_user_id = $1
+            ORDER BY c.name
+            "#,
+            owner_user_id
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(collections)
+    }
+
+    /// Rename a collection or change its description or retention
+    ///
+    /// `None` leaves a field unchanged; an empty description clears it, as
+    /// does a retention of 0 days.
+    pub async fn update(
+        &self,
+        id: Uuid,
+        name: Option<&str>,
+        description: Option<&str>,
+        retention_days: Option<i32>,
+    ) -> Result<(), WebauthnError> {
+        sqlx::query!(
+            r#"
+            UPDATE media_collections
+            SET name = COALESCE($2, name),
+                description = CASE WHEN $3::text IS NULL THEN description
+                                   ELSE NULLIF($3, '') END,
+                retention_days = CASE WHEN $4::int IS NULL THEN retention_days
+                                      ELSE NULLIF($4, 0) END,
+                updated_at = now()
+            WHERE id = $1
+            "#,
+            id,
+            name,
+            description,
+            retention_days
+        )
+        .execute(self.db.pool())
+        .await
+        .map_err(duplicate_name)?;
+
+        Ok(())
+    }
+
+    /// Delete a collection; its blobs are not affected
+    pub async fn delete(&self, id: Uuid) -> Result<bool, WebauthnError> {
+        info!("Deleting media collection: {}", id);
+
+        let result = sqlx::query!("DELETE FROM media_collections WHERE id = $1", id)
+            .execute(self.db.pool())
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// IDs of the blobs in a collection, in order, leaving out trashed blobs
+    pub async fn blob_ids(&self, id: Uuid) -> Result<Vec<Uuid>, WebauthnError> {
+        let ids = sqlx::query_scalar!(
+            r#"
+            SELECT i.blob_id FROM media_collection_items i
+            JOIN media_blobs b ON b.id = i.blob_id
+            WHERE i.collection_id = $1 AND b.deleted_at IS NULL
+            ORDER BY i.position, i.added_at
+            "#,
+            id
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(ids)
+    }
+
+    /// IDs of the collections a blob is in
+    pub async fn ids_for_blob(&self, blob_id: Uuid) -> Result<Vec<Uuid>, WebauthnError> {
+        let ids = sqlx::query_scalar!(
+            "SELECT collection_id FROM media_collection_items WHERE blob_id = $1",
+            blob_id
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(ids)
+    }
+
+    /// Append blobs to a collection, skipping those already in it
+    pub async fn add_blobs(&self, id: Uuid, blob_ids: &[Uuid]) -> Result<u64, WebauthnError> {
+        let mut tx = self.db.begin().await?;
+
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO media_collection_items (collection_id, blob_id, position)
+            SELECT $1, b.blob_id,
+                   COALESCE((SELECT MAX(position) FROM media_collection_items
+                             WHERE collection_id = $1), -1) + b.ord::int
+            FROM unnest($2::uuid[]) WITH ORDINALITY AS b(blob_id, ord)
+            ON CONFLICT (collection_id, blob_id) DO NOTHING
+            "#,
+            id,
+            blob_ids
+        )
+        .execute(&mut *tx)
+        .await?;
+        touch(&mut tx, id).await?;
+        tx.commit().await?;
+
+        debug!(
+            "Added {} blobs to media collection {}",
+            result.rows_affected(),
+            id
+        );
+        Ok(result.rows_affected())
+    }
+
+    /// Remove a blob from a collection
+    pub async fn remove_blob(&self, id: Uuid, blob_id: Uuid) -> Result<bool, WebauthnError> {
+        let mut tx = self.db.begin().await?;
+
+        let result = sqlx::query!(
+            "DELETE FROM media_collection_items WHERE collection_id = $1 AND blob_id = $2",
+            id,
+            blob_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        touch(&mut tx, id).await?;
+        tx.commit().await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Give the listed blobs positions in the order given
+    pub async fn reorder(&self, id: Uuid, blob_ids: &[Uuid]) -> Result<(), WebauthnError> {
+        let mut tx = self.db.begin().await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE media_collection_items i
+            SET position = o.ord::int - 1
+            FROM unnest($2::uuid[]) WITH ORDINALITY AS o(blob_id, ord)
+            WHERE i.collection_id = $1 AND i.blob_id = o.blob_id
+            "#,
+            id,
+            blob_ids
+        )
+        .execute(&mut *tx)
+        .await?;
+        touch(&mut tx, id).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+/// Mark a collection as changed
+async fn touch(conn: &mut sqlx::PgConnection, id: Uuid) -> Result<(), WebauthnError> {
+    sqlx::query!(
+        "UPDATE media_collections SET updated_at = now() WHERE id = $1",
+        id
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Report a collection name taken by another of the owner's collections
+fn duplicate_name(err: sqlx::Error) -> WebauthnError {
+    match &err {
+        sqlx::Error::Database(e) if e.kind() == ErrorKind::UniqueViolation => {
+            MediaError::DuplicateCollection.into()
+        }
+        _ => err.into(),
+    }
+}
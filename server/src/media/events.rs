@@ -0,0 +1,237 @@
+//! Real-time media events
+//!
+//! A [`MediaEvents`] hub broadcasts blob changes to subscribers, such as
+//! WebSocket connections. Events carry the blob without its data, along with
+//! the collections it is in and the users it is shared with, so subscribers
+//! can decide who may see an event and whether it matches a subscription
+//! without going back to the database. Subscribers that fall too far behind
+//! miss events and are told how many they lost.
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::auth::User;
+
+use super::models::MediaBlob;
+
+/// Events buffered for subscribers that have not caught up yet
+pub const DEFAULT_CAPACITY: usize = 256;
+
+/// What happened to a blob
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaEventKind {
+    /// Uploaded, or restored from the trash
+    Created,
+    /// Visibility, tags, shares or metadata changed
+    Updated,
+    /// Moved to the trash, or purged while live
+    Deleted,
+}
+
+/// A change to a blob, as broadcast to subscribers
+#[derive(Clone)]
+pub struct MediaEvent {
+    pub kind: MediaEventKind,
+    /// The blob after the change, without data
+    pub blob: MediaBlob,
+    /// Collections the blob is in
+    pub collection_ids: Vec<Uuid>,
+    /// Users the blob is shared with
+    pub shared_with: Vec<Uuid>,
+}
+
+impl MediaEvent {
+    /// Whether a user may see the blob, following the rules of
+    /// [`MediaService::can_view`](super::MediaService::can_view)
+    pub fn visible_to(&self, user: &User) -> bool {
+        self.blob.visible_to(user, &self.shared_with)
+    }
+}
+
+/// Which events a subscriber wants
+///
+/// Empty lists do not filter: the default subscription matches every event.
+/// A blob matches when it is in one of the collections (if any are given)
+/// and its MIME type starts with one of the prefixes (if any are given).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MediaSubscription {
+    #[serde(default)]
+    pub collection_ids: Vec<Uuid>,
+    #[serde(default)]
+    pub mime_prefixes: Vec<String>,
+}
+
+impl MediaSubscription {
+    /// Build a subscription, trimming and lower-casing the MIME prefixes
+    pub fn new(collection_ids: Vec<Uuid>, mime_prefixes: Vec<String>) -> Self {
+        let mut mime_prefixes: Vec<String> = mime_prefixes
+            .iter()
+            .map(|prefix| prefix.trim().to_ascii_lowercase())
+            .filter(|prefix| !prefix.is_empty())
+            .collect();
+        mime_prefixes.dedup();
+
+        Self {
+            collection_ids,
+            mime_prefixes,
+        }
+    }
+
+    /// Whether an event matches the subscription
+    pub fn matches(&self, event: &MediaEvent) -> bool {
+        let in_collection = self.collection_ids.is_empty()
+            || self
+                .collection_ids
+                .iter()
+                .any(|id| event.collection_ids.contains(id));
+
+        let mime = event.blob.mime.as_deref().unwrap_or_default();
+        let has_mime = self.mime_prefixes.is_empty()
+            || self
+                .mime_prefixes
+                .iter()
+                .any(|prefix| mime.to_ascii_lowercase().starts_with(prefix.as_str()));
+
+        in_collection && has_mime
+    }
+}
+
+/// Broadcast hub for media events
+///
+/// Cloning shares the hub. Publishing without subscribers drops the event.
+#[derive(Clone)]
+pub struct MediaEvents {
+    sender: broadcast::Sender<Arc<MediaEvent>>,
+}
+
+impl Default for MediaEvents {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl MediaEvents {
+    /// Create a hub buffering up to `capacity` events per subscriber
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity.max(1));
+        Self { sender }
+    }
+
+    /// Receive every event published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<MediaEvent>> {
+        self.sender.subscribe()
+    }
+
+    /// Whether anyone is listening, so publishers can skip building events
+    pub fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    /// Send an event to every current subscriber
+    pub fn publish(&self, event: MediaEvent) {
+        // Only fails when there are no subscribers
+        let _ = self.sender.send(Arc::new(event));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::UserRole;
+    use crate::media::{CreateMediaBlob, MediaVisibility};
+
+    fn user(role: UserRole) -> User {
+        User {
+            id: Uuid::new_v4(),
+            username: "events".to_string(),
+            role,
+            created_at: time::OffsetDateTime::now_utc(),
+            invite_code_used: None,
+        }
+    }
+
+    fn event(owner: &User, visibility: MediaVisibility, mime: &str) -> MediaEvent {
+        let blob = MediaBlob::new(CreateMediaBlob {
+            data: None,
+            sha256: "0".repeat(64),
+            size: Some(1),
+            mime: Some(mime.to_string()),
+            source_client_id: None,
+            owner_user_id: Some(owner.id),
+            visibility,
+            local_path: None,
+            metadata: serde_json::Value::Null,
+        });
+        MediaEvent {
+            kind: MediaEventKind::Created,
+            blob,
+            collection_ids: Vec::new(),
+            shared_with: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_visible_to() {
+        let owner = user(UserRole::Member);
+        let other = user(UserRole::Member);
+        let admin = user(UserRole::Admin);
+
+        let private = event(&owner, MediaVisibility::Private, "image/png");
+        assert!(private.visible_to(&owner));
+        assert!(private.visible_to(&admin));
+        assert!(!private.visible_to(&other));
+
+        let mut shared = event(&owner, MediaVisibility::Shared, "image/png");
+        assert!(!shared.visible_to(&other));
+        shared.shared_with.push(other.id);
+        assert!(shared.visible_to(&other));
+
+        assert!(event(&owner, MediaVisibility::Members, "image/png").visible_to(&other));
+    }
+
+    #[test]
+    fn test_subscription_matches() {
+        let owner = user(UserRole::Member);
+        let collection = Uuid::new_v4();
+        let mut image = event(&owner, MediaVisibility::Private, "Image/PNG");
+        let video = event(&owner, MediaVisibility::Private, "video/mp4");
+
+        assert!(MediaSubscription::default().matches(&image));
+
+        let images = MediaSubscription::new(Vec::new(), vec![" image/ ".to_string()]);
+        assert_eq!(images.mime_prefixes, vec!["image/"]);
+        assert!(images.matches(&image));
+        assert!(!images.matches(&video));
+
+        let in_collection = MediaSubscription::new(vec![collection], vec!["image/".to_string()]);
+        assert!(!in_collection.matches(&image));
+        image.collection_ids.push(collection);
+        assert!(in_collection.matches(&image));
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let events = MediaEvents::new(1);
+        assert!(!events.has_subscribers());
+        let owner = user(UserRole::Member);
+
+        // Dropped without subscribers
+        events.publish(event(&owner, MediaVisibility::Private, "text/plain"));
+
+        let mut receiver = events.subscribe();
+        assert!(events.has_subscribers());
+        events.publish(event(&owner, MediaVisibility::Private, "text/plain"));
+        events.publish(event(&owner, MediaVisibility::Private, "image/png"));
+
+        assert!(matches!(
+            receiver.recv().await,
+            Err(broadcast::error::RecvError::Lagged(1))
+        ));
+        let received = receiver.recv().await.unwrap();
+        assert_eq!(received.blob.mime.as_deref(), Some("image/png"));
+    }
+}
@@ -0,0 +1,668 @@
+//! Consistency checks between stored media content and the database
+//!
+//! A check walks every media object and confirms its content is where the
+//! object says, with the recorded size and, on request, the recorded SHA256.
+//! Blob local paths are compared with the current location of their content,
+//! and the upload directories are walked for files no object or blob refers
+//! to.
+//!
+//! Repairs are opt-in. Orphaned files are deleted. Blobs whose content is
+//! corrupt are deleted, together with their derivatives, since they can no
+//! longer be served. Missing content is only reported: it cannot be told
+//! apart from storage that is unmounted or unreachable, so a check finding
+//! the filesystem root gone, or every object missing, fails instead. Stale
+//! local paths are pointed at the content's current location, or cleared
+//! when it has no local file.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::config::{AppConfig, MediaStorageBackend};
+use crate::database::DatabaseConnection;
+use crate::error::WebauthnError;
+use crate::upload::handlers::{served_file, served_path};
+use crate::upload::repository::UploadSessionRepository;
+
+use super::models::{MediaAuditReason, MediaObject};
+use super::storage::{BlobStorageError, ContentStream, MediaStorage};
+use super::{MediaRepository, MediaService};
+
+/// Rows loaded per database round trip
+const BATCH_SIZE: i64 = 500;
+
+/// Bytes read at a time when hashing a file
+const HASH_CHUNK_SIZE: usize = 64 * 1024;
+
+/// What a consistency check looks at and whether it repairs problems
+#[derive(Debug, Clone)]
+pub struct FsckOptions {
+    /// Read all stored content back and verify its SHA256
+    pub verify_hashes: bool,
+    /// Fix problems found instead of only reporting them
+    pub repair: bool,
+    /// Files younger than this are never treated as orphans
+    pub min_orphan_age: Duration,
+    /// Directories walked for files without a record
+    pub directories: Vec<PathBuf>,
+}
+
+impl FsckOptions {
+    /// Options for the scheduled check, walking the media storage and upload
+    /// directories
+    pub fn from_config(config: &AppConfig) -> Self {
+        let fsck = &config.media.fsck;
+        Self {
+            verify_hashes: fsck.verify_hashes,
+            repair: fsck.repair,
+            min_orphan_age: Duration::from_secs(fsck.min_orphan_age_seconds),
+            directories: vec![
+                PathBuf::from(&config.media.storage.directory),
+                PathBuf::from(&config.static_files.upload_directory),
+            ],
+        }
+    }
+}
+
+/// Kinds of inconsistency a check reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FsckIssueKind {
+    /// A file no media object or blob refers to
+    OrphanFile,
+    /// An object whose content is not in its storage backend
+    MissingContent,
+    /// Stored content whose size differs from the object's
+    SizeMismatch,
+    /// Stored content whose SHA256 differs from the object's
+    HashMismatch,
+    /// A blob local path that no longer names its content
+    StaleLocalPath,
+}
+
+impl fmt::Display for FsckIssueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FsckIssueKind::OrphanFile => "orphan file",
+            FsckIssueKind::MissingContent => "missing content",
+            FsckIssueKind::SizeMismatch => "size mismatch",
+            FsckIssueKind::HashMismatch => "hash mismatch",
+            FsckIssueKind::StaleLocalPath => "stale local path",
+        })
+    }
+}
+
+/// One inconsistency found by a check
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FsckIssue {
+    pub kind: FsckIssueKind,
+    /// Content the issue concerns, if any
+    pub sha256: Option<String>,
+    /// Blob the issue concerns, for stale local paths
+    pub blob_id: Option<Uuid>,
+    /// File or storage key involved
+    pub path: Option<String>,
+    pub detail: String,
+    /// Whether the issue was fixed
+    pub repaired: bool,
+}
+
+/// Result of a consistency check
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FsckReport {
+    pub objects_checked: u64,
+    /// Objects in backends that are not configured, which cannot be checked
+    pub objects_skipped: u64,
+    /// Blobs with local paths below the checked directories
+    pub blobs_checked: u64,
+    pub files_checked: u64,
+    pub issues: Vec<FsckIssue>,
+}
+
+impl FsckReport {
+    /// Number of issues left unrepaired
+    pub fn unrepaired(&self) -> usize {
+        self.issues.iter().filter(|issue| !issue.repaired).count()
+    }
+
+    fn object_issue(&mut self, kind: FsckIssueKind, object: &MediaObject, detail: String) {
+        self.issues.push(FsckIssue {
+            kind,
+            sha256: Some(object.sha256.clone()),
+            blob_id: None,
+            path: Some(object.storage_key.clone()),
+            detail,
+            repaired: false,
+        });
+    }
+}
+
+/// Check stored media content against the database, repairing if asked to
+pub async fn run_fsck(
+    db: &DatabaseConnection,
+    storage: &MediaStorage,
+    options: &FsckOptions,
+) -> Result<FsckReport, WebauthnError> {
+    let repository = MediaRepository::new(db);
+    let service = MediaService::new(MediaRepository::new(db), storage);
+    let mut report = FsckReport::default();
+    let mut referenced = HashSet::new();
+
+    check_objects(&repository, storage, options, &mut report, &mut referenced).await?;
+    check_local_paths(&repository, options, &mut report, &mut referenced).await?;
+
+    for path in UploadSessionRepository::new(db).staging_paths().await? {
+        if let Ok(path) = tokio::fs::canonicalize(&path).await {
+            referenced.insert(path);
+        }
+    }
+    check_files(&repository, options, &mut report, &referenced).await?;
+
+    if options.repair {
+        repair_content(&repository, &service, &mut report).await?;
+    }
+
+    Ok(report)
+}
+
+/// Confirm every object's content is stored with the recorded size and hash
+async fn check_objects(
+    repository: &MediaRepository<'_>,
+    storage: &MediaStorage,
+    options: &FsckOptions,
+    report: &mut FsckReport,
+    referenced: &mut HashSet<PathBuf>,
+) -> Result<(), WebauthnError> {
+    let mut after: Option<String> = None;
+    let mut missing = 0;
+    let mut root_checked = false;
+
+    loop {
+        let objects = repository
+            .list_objects(after.as_deref(), BATCH_SIZE)
+            .await?;
+        let Some(last) = objects.last() else {
+            break;
+        };
+        after = Some(last.sha256.clone());
+
+        for object in &objects {
+            let backend = match storage.backend(object.storage_backend) {
+                Ok(backend) => backend,
+                Err(e) => {
+                    warn!("Cannot check media object {}: {}", object.sha256, e);
+                    report.objects_skipped += 1;
+                    continue;
+                }
+            };
+            report.objects_checked += 1;
+
+            if object.storage_backend == MediaStorageBackend::Filesystem && !root_checked {
+                check_root(storage.filesystem_root()).await?;
+                root_checked = true;
+            }
+
+            let (size, sha256) = match backend.local_path(&object.storage_key) {
+                Some(path) => {
+                    let metadata = match tokio::fs::metadata(&path).await {
+                        Ok(metadata) => metadata,
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                            report.object_issue(
+                                FsckIssueKind::MissingContent,
+                                object,
+                                format!("no file in {} storage", object.storage_backend),
+                            );
+                            missing += 1;
+                            continue;
+                        }
+                        Err(e) => return Err(io_error(e)),
+                    };
+                    referenced.insert(tokio::fs::canonicalize(&path).await.map_err(io_error)?);
+
+                    let sha256 = if options.verify_hashes && metadata.len() == object.size as u64 {
+                        Some(hash_file(&path).await.map_err(io_error)?)
+                    } else {
+                        None
+                    };
+                    (metadata.len(), sha256)
+                }
+                None if options.verify_hashes => match backend
+                    .get_range(&object.storage_key, 0, object.size as u64)
+                    .await?
+                {
+                    Some(content) => match hash_content(content).await {
+                        Ok((size, sha256)) => (size, Some(sha256)),
+                        // The database backend fails once it runs out of content
+                        Err(BlobStorageError::Missing(_)) => {
+                            report.object_issue(
+                                FsckIssueKind::SizeMismatch,
+                                object,
+                                format!("fewer than {} bytes stored", object.size),
+                            );
+                            continue;
+                        }
+                        Err(e) => return Err(e.into()),
+                    },
+                    None => {
+                        report.object_issue(
+                            FsckIssueKind::MissingContent,
+                            object,
+                            format!("nothing stored in {} storage", object.storage_backend),
+                        );
+                        missing += 1;
+                        continue;
+                    }
+                },
+                None => {
+                    if !backend.exists(&object.storage_key).await? {
+                        report.object_issue(
+                            FsckIssueKind::MissingContent,
+                            object,
+                            format!("nothing stored in {} storage", object.storage_backend),
+                        );
+                        missing += 1;
+                    }
+                    continue;
+                }
+            };
+
+            if size != object.size as u64 {
+                report.object_issue(
+                    FsckIssueKind::SizeMismatch,
+                    object,
+                    format!("{} bytes stored, {} recorded", size, object.size),
+                );
+            } else if let Some(sha256) = sha256.filter(|sha256| *sha256 != object.sha256) {
+                report.object_issue(
+                    FsckIssueKind::HashMismatch,
+                    object,
+                    format!("stored content hashes to {}", sha256),
+                );
+            }
+        }
+    }
+
+    // Storage that is unmounted or unreachable looks like content gone missing
+    if missing > 0 && missing == report.objects_checked {
+        return Err(BlobStorageError::Unavailable(format!(
+            "none of the {} media objects checked has content",
+            missing
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Fail unless the filesystem backend's root is a directory
+async fn check_root(root: &Path) -> Result<(), WebauthnError> {
+    match tokio::fs::metadata(root).await {
+        Ok(metadata) if metadata.is_dir() => Ok(()),
+        Ok(_) => Err(BlobStorageError::Unavailable(format!(
+            "{} is not a directory",
+            root.display()
+        ))
+        .into()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Err(BlobStorageError::Unavailable(format!("{} does not exist", root.display())).into())
+        }
+        Err(e) => Err(io_error(e)),
+    }
+}
+
+/// Compare blob local paths with where their content is stored now
+///
+/// Only local paths below the checked directories are considered; clients may
+/// record paths of their own. Such a path is stale when it names no existing
+/// file and differs from the content's current location. Files named by local
+/// paths count as referenced.
+async fn check_local_paths(
+    repository: &MediaRepository<'_>,
+    options: &FsckOptions,
+    report: &mut FsckReport,
+    referenced: &mut HashSet<PathBuf>,
+) -> Result<(), WebauthnError> {
+    let mut after: Option<Uuid> = None;
+
+    loop {
+        let locations = repository.list_blob_locations(after, BATCH_SIZE).await?;
+        let Some(last) = locations.last() else {
+            break;
+        };
+        after = Some(last.id);
+
+        for location in locations {
+            let file = served_file(&location.local_path);
+            if !options
+                .directories
+                .iter()
+                .any(|directory| file.starts_with(directory))
+            {
+                continue;
+            }
+            report.blobs_checked += 1;
+
+            if let Ok(path) = tokio::fs::canonicalize(&file).await {
+                referenced.insert(path);
+                continue;
+            }
+
+            let expected = served_path(location.storage_backend, &location.storage_key);
+            if expected.as_deref() == Some(location.local_path.as_str()) {
+                // The content itself is missing, which the object check reports
+                continue;
+            }
+
+            let mut repaired = false;
+            if options.repair {
+                repository
+                    .set_local_path(location.id, expected.as_deref())
+                    .await?;
+                repaired = true;
+            }
+
+            report.issues.push(FsckIssue {
+                kind: FsckIssueKind::StaleLocalPath,
+                sha256: Some(location.sha256),
+                blob_id: Some(location.id),
+                path: Some(location.local_path),
+                detail: match expected {
+                    Some(expected) => format!("content is now at {}", expected),
+                    None => "content has no local file".to_string(),
+                },
+                repaired,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Walk the configured directories for files nothing refers to
+async fn check_files(
+    repository: &MediaRepository<'_>,
+    options: &FsckOptions,
+    report: &mut FsckReport,
+    referenced: &HashSet<PathBuf>,
+) -> Result<(), WebauthnError> {
+    let mut roots: Vec<PathBuf> = Vec::new();
+    for directory in &options.directories {
+        match tokio::fs::canonicalize(directory).await {
+            Ok(root) => roots.push(root),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(io_error(e)),
+        }
+    }
+
+    // A directory inside another is walked as part of it
+    roots.sort();
+    roots.dedup_by(|root, parent| root.starts_with(parent));
+
+    let now = SystemTime::now();
+    for root in roots {
+        for path in walk(&root).await.map_err(io_error)? {
+            report.files_checked += 1;
+            if referenced.contains(&path) {
+                continue;
+            }
+
+            // Files are written before their records are committed, so young
+            // files may belong to an upload in progress
+            let metadata = match tokio::fs::metadata(&path).await {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(io_error(e)),
+            };
+            let modified = metadata.modified().map_err(io_error)?;
+            if now.duration_since(modified).unwrap_or_default() < options.min_orphan_age {
+                continue;
+            }
+            if is_stored_since(repository, &path).await? {
+                continue;
+            }
+
+            let mut repaired = false;
+            if options.repair {
+                match tokio::fs::remove_file(&path).await {
+                    Ok(()) => repaired = true,
+                    Err(e) => error!("Failed to remove orphaned file {}: {}", path.display(), e),
+                }
+            }
+
+            report.issues.push(FsckIssue {
+                kind: FsckIssueKind::OrphanFile,
+                sha256: None,
+                blob_id: None,
+                path: Some(path.to_string_lossy().into_owned()),
+                detail: format!("{} bytes", metadata.len()),
+                repaired,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether a file became the content of an object after objects were listed
+async fn is_stored_since(
+    repository: &MediaRepository<'_>,
+    path: &Path,
+) -> Result<bool, WebauthnError> {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return Ok(false);
+    };
+    if name.len() != 64 || !name.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Ok(false);
+    }
+
+    let Some(object) = repository.find_object(name).await? else {
+        return Ok(false);
+    };
+    Ok(tokio::fs::canonicalize(&object.storage_key)
+        .await
+        .is_ok_and(|key| key == path))
+}
+
+/// Delete the blobs of corrupt content, then the content itself
+///
+/// Missing content is only reported, as unreachable storage looks the same.
+async fn repair_content(
+    repository: &MediaRepository<'_>,
+    service: &MediaService<'_>,
+    report: &mut FsckReport,
+) -> Result<(), WebauthnError> {
+    for issue in &mut report.issues {
+        let (FsckIssueKind::SizeMismatch | FsckIssueKind::HashMismatch) = issue.kind else {
+            continue;
+        };
+        let Some(sha256) = issue.sha256.as_deref() else {
+            continue;
+        };
+
+        for id in repository.blob_ids_for_sha256(sha256).await? {
+            service.purge(id, MediaAuditReason::Fsck, None).await?;
+        }
+
+        // Objects still referenced, e.g. by an upload that just arrived, or
+        // locked by one, are left for the next check
+        service.collect_garbage_for(Some(sha256)).await?;
+        issue.repaired = repository.find_object(sha256).await?.is_none();
+        if issue.repaired {
+            info!("Removed broken media content {} ({})", sha256, issue.kind);
+        }
+    }
+
+    Ok(())
+}
+
+/// Every regular file below `root`; symbolic links are not followed
+async fn walk(root: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut directories = vec![root.to_path_buf()];
+
+    while let Some(directory) = directories.pop() {
+        let mut entries = match tokio::fs::read_dir(&directory).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                directories.push(entry.path());
+            } else if file_type.is_file() {
+                files.push(entry.path());
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// SHA256 of a file, read in chunks
+pub(crate) async fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; HASH_CHUNK_SIZE];
+
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Size and SHA256 of content streamed from a backend
+async fn hash_content(mut content: ContentStream) -> Result<(u64, String), BlobStorageError> {
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+
+    while let Some(chunk) = content.try_next().await? {
+        size += chunk.len() as u64;
+        hasher.update(&chunk);
+    }
+
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+fn io_error(e: std::io::Error) -> WebauthnError {
+    BlobStorageError::from(e).into()
+}
+
+/// Periodically check stored media in the background
+pub fn spawn_media_fsck(
+    db: DatabaseConnection,
+    storage: MediaStorage,
+    options: FsckOptions,
+    interval: Duration,
+) {
+    let interval = interval.max(Duration::from_secs(60));
+
+    tokio::spawn(async move {
+        // The first check waits a full interval rather than slowing startup
+        let start = tokio::time::Instant::now() + interval;
+        let mut ticker = tokio::time::interval_at(start, interval);
+        loop {
+            ticker.tick().await;
+
+            match run_fsck(&db, &storage, &options).await {
+                Ok(report) if report.issues.is_empty() => info!(
+                    "Media fsck checked {} objects and {} files, no issues",
+                    report.objects_checked, report.files_checked
+                ),
+                Ok(report) => warn!(
+                    "Media fsck found {} issues, {} left unrepaired",
+                    report.issues.len(),
+                    report.unrepaired()
+                ),
+                Err(e) => error!("Media fsck failed: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_walk_and_hash_file() {
+        let root = std::env::temp_dir().join(format!("media-fsck-{}", Uuid::new_v4()));
+        tokio::fs::create_dir_all(root.join("ab/cd")).await.unwrap();
+        tokio::fs::write(root.join("ab/cd/one"), b"hello")
+            .await
+            .unwrap();
+        tokio::fs::write(root.join("two"), vec![7u8; HASH_CHUNK_SIZE + 1])
+            .await
+            .unwrap();
+
+        let files = walk(&root).await.unwrap();
+        assert_eq!(files, vec![root.join("ab/cd/one"), root.join("two")]);
+        assert!(walk(&root.join("missing")).await.unwrap().is_empty());
+
+        assert_eq!(
+            hash_file(&root.join("ab/cd/one")).await.unwrap(),
+            format!("{:x}", Sha256::digest(b"hello"))
+        );
+        assert_eq!(
+            hash_file(&root.join("two")).await.unwrap(),
+            format!("{:x}", Sha256::digest(vec![7u8; HASH_CHUNK_SIZE + 1]))
+        );
+
+        tokio::fs::remove_dir_all(&root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_hash_content() {
+        use axum::body::Bytes;
+        use futures_util::{stream, StreamExt};
+
+        let chunks = [Bytes::from_static(b"hel"), Bytes::from_static(b"lo")];
+        let content = stream::iter(chunks.map(Ok)).boxed();
+        assert_eq!(
+            hash_content(content).await.unwrap(),
+            (5, format!("{:x}", Sha256::digest(b"hello")))
+        );
+
+        let failing = stream::iter([Err(BlobStorageError::Missing("key".to_string()))]).boxed();
+        assert!(matches!(
+            hash_content(failing).await,
+            Err(BlobStorageError::Missing(_))
+        ));
+    }
+
+    #[test]
+    fn test_unrepaired() {
+        let issue = |repaired| FsckIssue {
+            kind: FsckIssueKind::OrphanFile,
+            sha256: None,
+            blob_id: None,
+            path: None,
+            detail: String::new(),
+            repaired,
+        };
+        let report = FsckReport {
+            issues: vec![issue(true), issue(false)],
+            ..Default::default()
+        };
+
+        assert_eq!(report.unrepaired(), 1);
+        assert_eq!(
+            FsckIssueKind::StaleLocalPath.to_string(),
+            "stale local path"
+        );
+    }
+}
@@ -0,0 +1,631 @@
+//! HTTP handlers for media content
+//!
+//! Authenticated users can fetch the content of media blobs they can see,
+//! and previews of images among them. They can also mint signed download
+//! links for those blobs and for files below the private static directory.
+//! Signed links are served without a session.
+//! All content is served with range and conditional request support.
+//! Content quarantined by malware scanning is withheld on every path, and
+//! admins can list it. Users can see their storage usage and quota; admins
+//! can see everyone's and set quotas for individual users. Users can search
+//! the blobs they can see, and admins can get statistics over all media.
+
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use axum::{
+    body::Body,
+    extract::{Query, Request},
+    http::{header, Extensions, HeaderMap},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+    Extension,
+};
+use serde::Deserialize;
+use time::OffsetDateTime;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::auth::AuthenticatedUser;
+use crate::config::AppConfig;
+use crate::database::DatabaseConnection;
+use crate::error::{AppError, WebauthnError};
+use crate::static_filez::range_handler::{ContentInfo, RangeContent, RangeHandler, RangeSource};
+
+use super::links::{client_ip, new_nonce, LinkError, LinkParams, LinkSigner, LinkTarget};
+use super::models::{
+    CreateMediaLink, MediaBlob, MediaBlobQuery, MediaBlobStats, MediaLink, MediaStatsQuery,
+    PreviewSource, ScanStatus,
+};
+use super::{
+    MediaContent, MediaRepository, MediaSearch, MediaSearchPage, MediaService, MediaStorage,
+    MediaUsage, StoredContent,
+};
+
+/// Stream a media blob's content (authenticated users who can see the blob)
+///
+/// Filesystem content is streamed from disk; content in other backends is
+/// streamed from the backend, fetching only the requested range. Responses
+/// are revalidated with the SHA256 ETag, since the blob's visibility may
+/// change while its content does not.
+pub async fn get_media_content(
+    Extension(db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<MediaStorage>,
+    Extension(user): Extension<AuthenticatedUser>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let service = MediaService::new(MediaRepository::new(&db), &storage);
+    let blob = service
+        .get_blob(id, false, user.user())
+        .await
+        .map_err(|e| media_access_error(id, e))?;
+    MediaService::ensure_released(&blob).map_err(|e| media_access_error(id, e))?;
+    let content = service
+        .content(&blob)
+        .await
+        .map_err(|e| media_access_error(id, e))?;
+
+    blob_response(&headers, &blob, content, "private, no-cache").await
+}
+
+/// Query parameters for image previews
+#[derive(Debug, Deserialize)]
+pub struct PreviewQuery {
+    /// Edge length of the box the preview should fill
+    pub size: Option<u32>,
+}
+
+/// Serve a preview of an image blob (authenticated users who can see the blob)
+///
+/// Access is checked on the image itself; the preview is its smallest
+/// thumbnail covering `size`, or the image if it is small enough. Images that
+/// have not been processed yet have no preview.
+pub async fn get_media_preview(
+    Extension(db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<MediaStorage>,
+    Extension(user): Extension<AuthenticatedUser>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+    Query(query): Query<PreviewQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let service = MediaService::new(MediaRepository::new(&db), &storage);
+    let blob = service
+        .get_blob(id, false, user.user())
+        .await
+        .map_err(|e| media_access_error(id, e))?;
+    MediaService::ensure_released(&blob).map_err(|e| media_access_error(id, e))?;
+
+    let preview = match blob.preview(query.size) {
+        Some(PreviewSource::Original) => blob,
+        Some(PreviewSource::Thumbnail(thumbnail_id)) => service
+            .find_blob(thumbnail_id)
+            .await
+            .map_err(|e| media_access_error(thumbnail_id, e))?,
+        None => return Err(AppError::NotFound("No preview available".to_string())),
+    };
+
+    let content = service
+        .content(&preview)
+        .await
+        .map_err(|e| media_access_error(preview.id, e))?;
+
+    blob_response(&headers, &preview, content, "private, no-cache").await
+}
+
+/// Create a signed download link (authenticated users)
+///
+/// Blob links require that the caller can see the blob; private file links
+/// require only authentication, like `/private` itself.
+#[allow(clippy::too_many_arguments)]
+pub async fn create_media_link(
+    Extension(db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<MediaStorage>,
+    Extension(config): Extension<AppConfig>,
+    Extension(signer): Extension<LinkSigner>,
+    Extension(user): Extension<AuthenticatedUser>,
+    headers: HeaderMap,
+    extensions: Extensions,
+    Json(request): Json<CreateMediaLink>,
+) -> Result<Json<MediaLink>, AppError> {
+    let target = match (request.blob_id, request.path.as_deref()) {
+        (Some(id), None) => {
+            let service = MediaService::new(MediaRepository::new(&db), &storage);
+            service
+                .get_blob(id, false, user.user())
+                .await
+                .map_err(|e| media_access_error(id, e))?;
+            LinkTarget::Blob(id)
+        }
+        (None, Some(path)) => {
+            let (relative, _) = resolve_private_file(&config, path)?;
+            LinkTarget::PrivateFile(relative)
+        }
+        _ => {
+            return Err(AppError::BadRequest(
+                "Specify exactly one of blob_id and path".to_string(),
+            ))
+        }
+    };
+
+    let links = &config.media.links;
+    let expires_in = request
+        .expires_in_seconds
+        .unwrap_or(links.default_expiry_seconds);
+    if expires_in == 0 || expires_in > links.max_expiry_seconds {
+        return Err(AppError::BadRequest(format!(
+            "Link lifetime must be between 1 and {} seconds",
+            links.max_expiry_seconds
+        )));
+    }
+
+    let ip = if request.bind_ip {
+        let ip = client_ip(&headers, &extensions, &config.server.trusted_proxies);
+        Some(ip.ok_or_else(|| {
+            AppError::BadRequest("Cannot bind link: client address unknown".to_string())
+        })?)
+    } else {
+        None
+    };
+
+    let expires_at = OffsetDateTime::now_utc() + time::Duration::seconds(expires_in as i64);
+    let params = signer.sign(
+        &target,
+        LinkParams {
+            expires: expires_at.unix_timestamp(),
+            nonce: request.single_use.then(new_nonce),
+            ip,
+            sig: String::new(),
+        },
+    );
+
+    info!(
+        "User {} created a signed link to {} valid for {}s",
+        user.user().username,
+        target.path(),
+        expires_in
+    );
+
+    Ok(Json(MediaLink {
+        url: format!(
+            "{}{}",
+            config.webauthn.rp_origin.trim_end_matches('/'),
+            signer.url(&target, &params)
+        ),
+        expires_at,
+        single_use: request.single_use,
+        ip,
+    }))
+}
+
+/// Serve a media blob through a signed link
+#[allow(clippy::too_many_arguments)]
+pub async fn serve_signed_blob(
+    Extension(db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<MediaStorage>,
+    Extension(config): Extension<AppConfig>,
+    Extension(signer): Extension<LinkSigner>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+    Query(params): Query<LinkParams>,
+    headers: HeaderMap,
+    extensions: Extensions,
+) -> Result<Response, AppError> {
+    check_link(
+        &signer,
+        &LinkTarget::Blob(id),
+        &params,
+        &headers,
+        &extensions,
+        &config,
+    )?;
+
+    let service = MediaService::new(MediaRepository::new(&db), &storage);
+    let blob = service
+        .find_blob(id)
+        .await
+        .map_err(|e| media_access_error(id, e))?;
+    MediaService::ensure_released(&blob).map_err(|e| media_access_error(id, e))?;
+    consume_link(&service, &params, &headers, &config).await?;
+
+    let content = service
+        .content(&blob)
+        .await
+        .map_err(|e| media_access_error(id, e))?;
+
+    blob_response(&headers, &blob, content, &link_cache_control(&params)).await
+}
+
+/// Serve a file below the private static directory through a signed link
+#[allow(clippy::too_many_arguments)]
+pub async fn serve_signed_private_file(
+    Extension(db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<MediaStorage>,
+    Extension(config): Extension<AppConfig>,
+    Extension(signer): Extension<LinkSigner>,
+    axum::extract::Path(path): axum::extract::Path<String>,
+    Query(params): Query<LinkParams>,
+    headers: HeaderMap,
+    extensions: Extensions,
+) -> Result<Response, AppError> {
+    check_link(
+        &signer,
+        &LinkTarget::PrivateFile(path.clone()),
+        &params,
+        &headers,
+        &extensions,
+        &config,
+    )?;
+
+    let (relative, file) = resolve_private_file(&config, &path)?;
+    let service = MediaService::new(MediaRepository::new(&db), &storage);
+    service
+        .ensure_file_released(&relative)
+        .await
+        .map_err(quarantine_error)?;
+    consume_link(&service, &params, &headers, &config).await?;
+
+    let handler = RangeHandler::new(&config.static_files.private_directory, 0)
+        .with_cache_control(link_cache_control(&params));
+    Ok(match handler.serve_file(&headers, file).await {
+        Ok(response) => response,
+        Err(e) => e.into_response(),
+    })
+}
+
+/// Query parameters for the quarantine listing
+#[derive(Debug, Deserialize)]
+pub struct QuarantineQuery {
+    /// `infected` (default) or `pending`
+    pub status: Option<ScanStatus>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+/// List blobs withheld by malware scanning (admin only)
+///
+/// Infected blobs carry the scanner's findings in their `scan` metadata.
+pub async fn list_quarantined_media(
+    Extension(db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<MediaStorage>,
+    Extension(user): Extension<AuthenticatedUser>,
+    Query(query): Query<QuarantineQuery>,
+) -> Result<Json<Vec<MediaBlob>>, AppError> {
+    let service = MediaService::new(MediaRepository::new(&db), &storage);
+    let blobs = service
+        .list_blobs(
+            MediaBlobQuery {
+                scan_status: Some(query.status.unwrap_or(ScanStatus::Infected)),
+                limit: Some(query.limit.unwrap_or(100).min(1000)),
+                offset: query.offset,
+                ..Default::default()
+            },
+            user.user(),
+        )
+        .await
+        .map_err(|e| {
+            error!("Failed to list quarantined media: {}", e);
+            AppError::InternalServerError("Failed to list quarantined media".to_string())
+        })?;
+
+    Ok(Json(blobs))
+}
+
+/// Withhold stored media files that are quarantined from the static server
+///
+/// Filesystem content below `/private` is reachable by path, so the scan
+/// status is checked by the content hash in the file name.
+pub async fn block_quarantined_files(
+    Extension(db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<MediaStorage>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let service = MediaService::new(MediaRepository::new(&db), &storage);
+    service
+        .ensure_file_released(request.uri().path())
+        .await
+        .map_err(quarantine_error)?;
+
+    Ok(next.run(request).await)
+}
+
+/// Search the media blobs the signed-in user can see
+///
+/// Pass `next_cursor` from a page as `cursor` to get the next one, keeping
+/// the other parameters unchanged.
+pub async fn search_media(
+    Extension(db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<MediaStorage>,
+    Extension(user): Extension<AuthenticatedUser>,
+    Json(search): Json<MediaSearch>,
+) -> Result<Json<MediaSearchPage>, AppError> {
+    let service = MediaService::new(MediaRepository::new(&db), &storage);
+    let page = service
+        .search(&search, user.user())
+        .await
+        .map_err(|e| match e {
+            WebauthnError::MediaSearch(e) => AppError::BadRequest(e.to_string()),
+            e => {
+                error!("Failed to search media: {}", e);
+                AppError::InternalServerError("Failed to search media".to_string())
+            }
+        })?;
+
+    Ok(Json(page))
+}
+
+/// Media statistics and storage reporting (admin only)
+///
+/// `days` sets how many days of growth to report and `top` how many users
+/// and clients to list; either may be 0 to skip them.
+pub async fn get_media_stats(
+    Extension(db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<MediaStorage>,
+    Query(query): Query<MediaStatsQuery>,
+) -> Result<Json<MediaBlobStats>, AppError> {
+    let query = MediaStatsQuery {
+        days: query.days.clamp(0, 3660),
+        top: query.top.clamp(0, 1000),
+    };
+
+    let service = MediaService::new(MediaRepository::new(&db), &storage);
+    let stats = service.get_stats(query).await.map_err(|e| {
+        error!("Failed to gather media statistics: {}", e);
+        AppError::InternalServerError("Failed to gather media statistics".to_string())
+    })?;
+
+    Ok(Json(stats))
+}
+
+/// Storage usage and quota of the signed-in user
+pub async fn get_media_usage(
+    Extension(db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<MediaStorage>,
+    Extension(config): Extension<AppConfig>,
+    Extension(user): Extension<AuthenticatedUser>,
+) -> Result<Json<MediaUsage>, AppError> {
+    let service = MediaService::new(MediaRepository::new(&db), &storage);
+    let usage = service
+        .usage(user.user().id, &config.media.quotas)
+        .await
+        .map_err(usage_error)?;
+
+    Ok(Json(usage))
+}
+
+/// Storage usage and quotas of every user, largest first (admin only)
+pub async fn list_media_usage(
+    Extension(db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<MediaStorage>,
+    Extension(config): Extension<AppConfig>,
+) -> Result<Json<Vec<MediaUsage>>, AppError> {
+    let service = MediaService::new(MediaRepository::new(&db), &storage);
+    let usage = service
+        .list_usage(&config.media.quotas)
+        .await
+        .map_err(usage_error)?;
+
+    Ok(Json(usage))
+}
+
+/// Quota to set for a user
+#[derive(Debug, Deserialize)]
+pub struct SetQuotaRequest {
+    /// Bytes the user may store; `null` reverts to the role quota
+    pub quota_bytes: Option<i64>,
+}
+
+/// Set or clear a user's quota (admin only)
+pub async fn set_media_quota(
+    Extension(db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<MediaStorage>,
+    Extension(config): Extension<AppConfig>,
+    axum::extract::Path(user_id): axum::extract::Path<Uuid>,
+    Json(request): Json<SetQuotaRequest>,
+) -> Result<Json<MediaUsage>, AppError> {
+    if request.quota_bytes.is_some_and(|bytes| bytes < 0) {
+        return Err(AppError::BadRequest(
+            "quota_bytes must not be negative".to_string(),
+        ));
+    }
+
+    let service = MediaService::new(MediaRepository::new(&db), &storage);
+    let usage = service
+        .set_quota(user_id, request.quota_bytes, &config.media.quotas)
+        .await
+        .map_err(usage_error)?;
+
+    Ok(Json(usage))
+}
+
+/// Respond with a blob's content, honouring range and conditional headers
+///
+/// The ETag is the content's SHA256, so it stays valid across storage
+/// backends and for every blob sharing the content.
+pub(crate) async fn blob_response(
+    headers: &HeaderMap,
+    blob: &MediaBlob,
+    content: MediaContent,
+    cache_control: &str,
+) -> Result<Response, AppError> {
+    let (content, size) = match content {
+        MediaContent::File(path) => {
+            let size = tokio::fs::metadata(&path)
+                .await
+                .map_err(|e| {
+                    error!("Failed to read content of media blob {}: {}", blob.id, e);
+                    AppError::InternalServerError("Failed to read media content".to_string())
+                })?
+                .len();
+            (RangeContent::File(path), size)
+        }
+        MediaContent::Stored(content) => {
+            let size = content.size();
+            (RangeContent::Source(Box::new(content)), size)
+        }
+    };
+
+    let info = ContentInfo {
+        size,
+        etag: format!("\"{}\"", blob.sha256),
+        last_modified: Some(SystemTime::from(blob.created_at)),
+        content_type: blob
+            .mime
+            .clone()
+            .unwrap_or_else(|| "application/octet-stream".to_string()),
+    };
+
+    // The base path only matters for serving by request path
+    let handler = RangeHandler::new(PathBuf::new(), 0).with_cache_control(cache_control);
+    Ok(match handler.serve(headers, content, &info).await {
+        Ok(response) => response,
+        Err(e) => e.into_response(),
+    })
+}
+
+#[async_trait]
+impl RangeSource for StoredContent {
+    async fn open(self: Box<Self>, start: u64, length: u64) -> std::io::Result<Body> {
+        let stream = StoredContent::open(&self, start, length)
+            .await
+            .map_err(std::io::Error::other)?;
+        Ok(Body::from_stream(stream))
+    }
+}
+
+/// Resolve a path below the private static directory to an existing file
+///
+/// Returns the normalized relative path, which links are signed for, and
+/// the canonical file path. Anything outside the directory is not found.
+fn resolve_private_file(config: &AppConfig, path: &str) -> Result<(String, PathBuf), AppError> {
+    let not_found = || AppError::NotFound("File not found".to_string());
+
+    let base = Path::new(&config.static_files.private_directory)
+        .canonicalize()
+        .map_err(|_| not_found())?;
+    let file = base
+        .join(path.trim_start_matches('/'))
+        .canonicalize()
+        .map_err(|_| not_found())?;
+
+    if !file.starts_with(&base) || !file.is_file() {
+        return Err(not_found());
+    }
+
+    let relative = file
+        .strip_prefix(&base)
+        .map_err(|_| not_found())?
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    Ok((relative, file))
+}
+
+fn check_link(
+    signer: &LinkSigner,
+    target: &LinkTarget,
+    params: &LinkParams,
+    headers: &HeaderMap,
+    extensions: &Extensions,
+    config: &AppConfig,
+) -> Result<(), AppError> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let ip = client_ip(headers, extensions, &config.server.trusted_proxies);
+    signer.verify(target, params, now, ip).map_err(|e| {
+        warn!("Rejected signed link to {}: {}", target.path(), e);
+        match e {
+            LinkError::Expired => AppError::Gone(e.to_string()),
+            LinkError::InvalidSignature | LinkError::WrongAddress => {
+                AppError::Forbidden(e.to_string())
+            }
+        }
+    })
+}
+
+/// Mark a single-use link as used, refusing it if it was used before
+///
+/// Range requests are still answered for a short while after the first use,
+/// so a player can fetch a file in parts and seek. A full download is only
+/// ever served once.
+async fn consume_link(
+    service: &MediaService<'_>,
+    params: &LinkParams,
+    headers: &HeaderMap,
+    config: &AppConfig,
+) -> Result<(), AppError> {
+    let Some(nonce) = &params.nonce else {
+        return Ok(());
+    };
+
+    let expires_at = OffsetDateTime::from_unix_timestamp(params.expires)
+        .map_err(|_| AppError::BadRequest("Invalid link expiry".to_string()))?;
+    let first_used_at = service
+        .record_link_use(nonce, expires_at)
+        .await
+        .map_err(|e| {
+            error!("Failed to record use of signed link: {}", e);
+            AppError::InternalServerError("Failed to check link".to_string())
+        })?;
+
+    let grace = time::Duration::seconds(config.media.links.single_use_grace_seconds as i64);
+    match first_used_at {
+        None => Ok(()),
+        Some(used_at)
+            if headers.contains_key(header::RANGE)
+                && OffsetDateTime::now_utc() - used_at <= grace =>
+        {
+            Ok(())
+        }
+        Some(_) => Err(AppError::Gone("Link has already been used".to_string())),
+    }
+}
+
+/// Shared caches must not keep link responses; single-use ones not at all
+fn link_cache_control(params: &LinkParams) -> String {
+    if params.nonce.is_some() {
+        return "private, no-store".to_string();
+    }
+
+    let remaining = params.expires - OffsetDateTime::now_utc().unix_timestamp();
+    format!("private, max-age={}", remaining.max(0))
+}
+
+fn media_access_error(id: Uuid, err: WebauthnError) -> AppError {
+    match err {
+        WebauthnError::UserNotFound => AppError::NotFound("Media not found".to_string()),
+        WebauthnError::MediaQuarantined(_) => quarantine_error(err),
+        e => {
+            error!("Failed to load media blob {}: {}", id, e);
+            AppError::InternalServerError("Failed to load media".to_string())
+        }
+    }
+}
+
+fn usage_error(err: WebauthnError) -> AppError {
+    match err {
+        WebauthnError::UserNotFound => AppError::NotFound("User not found".to_string()),
+        e => {
+            error!("Failed to load media usage: {}", e);
+            AppError::InternalServerError("Failed to load media usage".to_string())
+        }
+    }
+}
+
+/// Map a failure to release quarantined content onto an HTTP error
+fn quarantine_error(err: WebauthnError) -> AppError {
+    match err {
+        WebauthnError::MediaQuarantined(ScanStatus::Infected) => {
+            AppError::Forbidden("Media was quarantined by the malware scanner".to_string())
+        }
+        WebauthnError::MediaQuarantined(_) => {
+            AppError::Conflict("Media is waiting for a malware scan".to_string())
+        }
+        e => {
+            error!("Failed to check media quarantine: {}", e);
+            AppError::InternalServerError("Failed to load media".to_string())
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! Image analysis and thumbnail generation
+//!
+//! Decoding and resizing are CPU-bound; callers run them on the blocking
+//! thread pool.
+
+use std::io::Cursor;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageReader};
+
+use super::models::ImageMetadata;
+
+/// Quality of JPEG thumbnails
+const JPEG_QUALITY: u8 = 85;
+
+/// An encoded thumbnail, already rotated upright
+pub struct Thumbnail {
+    /// Edge length of the square box the thumbnail was fitted into
+    pub size: u32,
+    pub width: u32,
+    pub height: u32,
+    pub mime: &'static str,
+    pub bytes: Vec<u8>,
+}
+
+/// Result of decoding an image and deriving its thumbnails
+pub struct ImageAnalysis {
+    pub info: ImageMetadata,
+    pub thumbnails: Vec<Thumbnail>,
+}
+
+/// Decode an image and build a thumbnail for every box size it exceeds
+///
+/// Images already fitting a box get no thumbnail for it; the original
+/// serves as its own preview. Thumbnails with transparency are PNG, all
+/// others JPEG.
+pub fn analyze_image(bytes: &[u8], sizes: &[u32]) -> Result<ImageAnalysis, ImageError> {
+    let reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    let format = reader.format().ok_or_else(|| {
+        ImageError::Unsupported(image::error::UnsupportedError::from_format_and_kind(
+            image::error::ImageFormatHint::Unknown,
+            image::error::UnsupportedErrorKind::Format(image::error::ImageFormatHint::Unknown),
+        ))
+    })?;
+
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut image = DynamicImage::from_decoder(decoder)?;
+
+    let info = ImageMetadata {
+        width: image.width(),
+        height: image.height(),
+        orientation: orientation.to_exif(),
+        format: format.to_mime_type().to_string(),
+    };
+
+    image.apply_orientation(orientation);
+
+    let mut sizes = sizes.to_vec();
+    sizes.sort_unstable();
+    sizes.dedup();
+
+    let thumbnails = sizes
+        .into_iter()
+        .filter(|size| image.width().max(image.height()) > *size)
+        .map(|size| encode_thumbnail(&image.thumbnail(size, size), size))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(ImageAnalysis { info, thumbnails })
+}
+
+fn encode_thumbnail(image: &DynamicImage, size: u32) -> Result<Thumbnail, ImageError> {
+    let mut bytes = Vec::new();
+    let mime = if image.color().has_alpha() {
+        image.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)?;
+        ImageFormat::Png.to_mime_type()
+    } else {
+        image
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, JPEG_QUALITY))?;
+        ImageFormat::Jpeg.to_mime_type()
+    };
+
+    Ok(Thumbnail {
+        size,
+        width: image.width(),
+        height: image.height(),
+        mime,
+        bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    fn encode(image: DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_analyze_opaque_image() {
+        let image = RgbImage::from_pixel(600, 300, Rgb([200, 10, 10]));
+        let bytes = encode(DynamicImage::ImageRgb8(image), ImageFormat::Png);
+
+        let analysis = analyze_image(&bytes, &[512, 128, 1024, 128]).unwrap();
+        assert_eq!(
+            analysis.info,
+            ImageMetadata {
+                width: 600,
+                height: 300,
+                orientation: 1,
+                format: "image/png".to_string(),
+            }
+        );
+
+        // Sizes are deduplicated and boxes the image already fits are skipped
+        let thumbnails: Vec<_> = analysis
+            .thumbnails
+            .iter()
+            .map(|t| (t.size, t.width, t.height, t.mime))
+            .collect();
+        assert_eq!(
+            thumbnails,
+            vec![(128, 128, 64, "image/jpeg"), (512, 512, 256, "image/jpeg")]
+        );
+
+        let decoded = image::load_from_memory(&analysis.thumbnails[0].bytes).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (128, 64));
+    }
+
+    #[test]
+    fn test_transparent_thumbnails_are_png() {
+        let image = RgbaImage::from_pixel(300, 400, Rgba([0, 0, 255, 100]));
+        let bytes = encode(DynamicImage::ImageRgba8(image), ImageFormat::Png);
+
+        let analysis = analyze_image(&bytes, &[100]).unwrap();
+        assert_eq!(analysis.thumbnails.len(), 1);
+        assert_eq!(analysis.thumbnails[0].mime, "image/png");
+        assert_eq!(
+            (analysis.thumbnails[0].width, analysis.thumbnails[0].height),
+            (75, 100)
+        );
+    }
+
+    #[test]
+    fn test_analyze_rejects_non_images() {
+        assert!(analyze_image(b"definitely not an image", &[128]).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+//! Media job repository for the background processing queue
+//!
+//! Jobs are claimed with `FOR UPDATE SKIP LOCKED`, so several server
+//! instances can share the queue. A job left `running` by a crashed worker
+//! is claimed again once it has not been touched for a while.
+
+use sqlx::PgExecutor;
+use time::OffsetDateTime;
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::database::DatabaseConnection;
+use crate::error::WebauthnError;
+
+use super::models::{MediaJob, MediaJobKind};
+
+/// Media job repository for database operations
+pub struct MediaJobRepository<'a> {
+    db: &'a DatabaseConnection,
+}
+
+impl<'a> MediaJobRepository<'a> {
+    /// Create a new repository instance
+    pub fn new(db: &'a DatabaseConnection) -> Self {
+        Self { db }
+    }
+
+    /// Queue a job for a blob unless one of the same kind exists
+    pub async fn enqueue_in<'e, E>(
+        &self,
+        executor: E,
+        blob_id: Uuid,
+        kind: MediaJobKind,
+    ) -> Result<(), WebauthnError>
+    where
+        E: PgExecutor<'e>,
+    {
+        debug!("Queueing {} job for media blob {}", kind, blob_id);
+
+        sqlx::query!(
+            r#"
+            INSERT INTO media_jobs (blob_id, kind)
+            VALUES ($1, $2)
+            ON CONFLICT (blob_id, kind) DO NOTHING
+            "#,
+            blob_id,
+            kind.as_str()
+        )
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Claim up to `limit` due jobs of the given kinds, marking them running
+    ///
+    /// Running jobs last updated before `stale_before` are claimed again.
+    pub async fn claim(
+        &self,
+        limit: i64,
+        stale_before: OffsetDateTime,
+        kinds: &[MediaJobKind],
+    ) -> Result<Vec<MediaJob>, WebauthnError> {
+        let kinds: Vec<String> = kinds.iter().map(|kind| kind.to_string()).collect();
+
+        let jobs = sqlx::query_as!(
+            MediaJob,
+            r#"
+            UPDATE media_jobs
+            SET status = 'running', attempts = attempts + 1, updated_at = now()
+            WHERE id IN (
+                SELECT id FROM media_jobs
+                WHERE kind = ANY($3)
+                  AND ((status = 'pending' AND run_after <= now())
+                    OR (status = 'running' AND updated_at < $2))
+                ORDER BY run_after
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, blob_id, kind, status, attempts, last_error,
+                      run_after, created_at, updated_at
+            "#,
+            limit,
+            stale_before,
+            &kinds
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(jobs)
+    }
+
+    /// Mark a job as finished
+    pub async fn complete(&self, id: i64) -> Result<(), WebauthnError> {
+        sqlx::query!(
+            r#"
+            UPDATE media_jobs
+            SET status = 'done', last_error = NULL, updated_at = now()
+            WHERE id = $1
+            "#,
+            id
+        )
+        .execute(self.db.pool())
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record a failed attempt, retrying at `retry_at` or giving up if `None`
+    pub async fn fail(
+        &self,
+        id: i64,
+        error: &str,
+        retry_at: Option<OffsetDateTime>,
+    ) -> Result<(), WebauthnError> {
+        sqlx::query!(
+            r#"
+            UPDATE media_jobs
+            SET status = CASE WHEN $3::timestamptz IS NULL THEN 'failed' ELSE 'pending' END,
+                last_error = $2,
+                run_after = COALESCE($3, run_after),
+                updated_at = now()
+            WHERE id = $1
+            "#,
+            id,
+            error,
+            retry_at
+        )
+        .execute(self.db.pool())
+        .await?;
+
+        Ok(())
+    }
+
+    /// Find the job of a kind for a blob
+    pub async fn find(
+        &self,
+        blob_id: Uuid,
+        kind: MediaJobKind,
+    ) -> Result<Option<MediaJob>, WebauthnError> {
+        let job = sqlx::query_as!(
+            MediaJob,
+            r#"
+            SELECT id, blob_id, kind, status, attempts, last_error,
+                   run_after, created_at, updated_at
+            FROM media_jobs
+            WHERE blob_id = $1 AND kind = $2
+            "#,
+            blob_id,
+            kind.as_str()
+        )
+        .fetch_optional(self.db.pool())
+        .await?;
+
+        Ok(job)
+    }
+
+    /// Queue a job of `kind` again for matching blobs, returning how many
+    ///
+    /// Covers every original blob whose MIME type the job applies to, or only
+    /// `blob_id`. Existing jobs are reset to pending with a fresh attempt count;
+    /// with `only_failed`, only blobs whose job failed are queued.
+    pub async fn requeue(
+        &self,
+        kind: MediaJobKind,
+        blob_id: Option<Uuid>,
+        only_failed: bool,
+    ) -> Result<u64, WebauthnError> {
+        let patterns: Vec<String> = kind
+            .mime_prefixes()
+            .iter()
+            .map(|prefix| format!("{}%", prefix))
+            .collect();
+
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO media_jobs (blob_id, kind)
+            SELECT b.id, $1
+            FROM media_blobs b
+            LEFT JOIN media_jobs j ON j.blob_id = b.id AND j.kind = $1
+            WHERE b.parent_blob_id IS NULL AND b.deleted_at IS NULL
+              AND COALESCE(b.mime, '') LIKE ANY($2)
+              AND ($3::uuid IS NULL OR b.id = $3)
+              AND (NOT $4::bool OR j.status = 'failed')
+            ON CONFLICT (blob_id, kind) DO UPDATE
+            SET status = 'pending', attempts = 0, last_error = NULL,
+                run_after = now(), updated_at = now()
+            "#,
+            kind.as_str(),
+            &patterns,
+            blob_id,
+            only_failed
+        )
+        .execute(self.db.pool())
+        .await?;
+
+        debug!("Requeued {} {} jobs", result.rows_affected(), kind);
+        Ok(result.rows_affected())
+    }
+}
@@ -0,0 +1,344 @@
+//! Signed, expiring download links
+//!
+//! A link names a media blob or a file below the private static directory
+//! together with its expiry, and optionally a nonce (single use) and the
+//! client IP it is bound to. All of these are part of the URL and covered by
+//! an HMAC-SHA256 signature, so the link can be checked without a session
+//! and without storing anything, except the nonces of used single-use links.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use axum::http::{Extensions, HeaderMap};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::config::MediaLinksConfig;
+
+/// Path signed links are served under
+pub const SIGNED_LINK_PREFIX: &str = "/api/media/signed";
+
+/// What a signed link grants access to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkTarget {
+    /// A media blob, served from whichever backend holds its content
+    Blob(Uuid),
+    /// A file relative to the private static directory
+    PrivateFile(String),
+}
+
+impl LinkTarget {
+    /// Path of the target below [`SIGNED_LINK_PREFIX`]
+    pub fn path(&self) -> String {
+        match self {
+            LinkTarget::Blob(id) => format!("blob/{}", id),
+            LinkTarget::PrivateFile(path) => format!("private/{}", path),
+        }
+    }
+}
+
+/// Everything a link signature covers, as carried in the URL query
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkParams {
+    /// Unix timestamp after which the link is rejected
+    pub expires: i64,
+    /// Set for single-use links; recorded when the link is used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    /// Client IP the link is bound to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    /// Hex-encoded HMAC-SHA256 of the target and the fields above
+    #[serde(default)]
+    pub sig: String,
+}
+
+/// Why a presented link is not accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum LinkError {
+    #[error("Invalid link signature")]
+    InvalidSignature,
+    #[error("Link has expired")]
+    Expired,
+    #[error("Link is bound to another address")]
+    WrongAddress,
+}
+
+/// Signs and verifies download links
+#[derive(Clone)]
+pub struct LinkSigner {
+    key: Arc<Vec<u8>>,
+}
+
+impl LinkSigner {
+    /// Create a signer from the configured secret, or a random one
+    pub fn from_config(config: &MediaLinksConfig) -> Self {
+        match &config.secret {
+            Some(secret) => Self::new(secret.as_bytes().to_vec()),
+            None => {
+                tracing::warn!(
+                    "No media.links.secret configured; signed links will not survive a restart"
+                );
+                let mut key = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                Self::new(key)
+            }
+        }
+    }
+
+    pub fn new(key: Vec<u8>) -> Self {
+        Self { key: Arc::new(key) }
+    }
+
+    /// Sign a link to `target`, filling in `params.sig`
+    pub fn sign(&self, target: &LinkTarget, mut params: LinkParams) -> LinkParams {
+        params.sig = self.signature(target, &params);
+        params
+    }
+
+    /// URL path and query of a signed link, relative to the server origin
+    pub fn url(&self, target: &LinkTarget, params: &LinkParams) -> String {
+        let mut query = format!("expires={}", params.expires);
+        if let Some(nonce) = &params.nonce {
+            query.push_str(&format!("&nonce={}", nonce));
+        }
+        if let Some(ip) = params.ip {
+            query.push_str(&format!("&ip={}", ip));
+        }
+        query.push_str(&format!("&sig={}", params.sig));
+
+        format!(
+            "{}/{}?{}",
+            SIGNED_LINK_PREFIX,
+            encode_path(&target.path()),
+            query
+        )
+    }
+
+    /// Check a presented link's signature, expiry and address binding
+    pub fn verify(
+        &self,
+        target: &LinkTarget,
+        params: &LinkParams,
+        now: i64,
+        client_ip: Option<IpAddr>,
+    ) -> Result<(), LinkError> {
+        let expected = self.signature(target, params);
+        if !constant_time_eq(expected.as_bytes(), params.sig.as_bytes()) {
+            return Err(LinkError::InvalidSignature);
+        }
+
+        if now >= params.expires {
+            return Err(LinkError::Expired);
+        }
+
+        if let Some(ip) = params.ip {
+            if client_ip != Some(ip) {
+                return Err(LinkError::WrongAddress);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn signature(&self, target: &LinkTarget, params: &LinkParams) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        let message = format!(
+            "v1\n{}\n{}\n{}\n{}",
+            target.path(),
+            params.expires,
+            params.nonce.as_deref().unwrap_or(""),
+            params.ip.map(|ip| ip.to_string()).unwrap_or_default()
+        );
+        mac.update(message.as_bytes());
+        format!("{:x}", mac.finalize().into_bytes())
+    }
+}
+
+/// Compare signatures without revealing how long a matching prefix is
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Percent-encode a path, keeping `/` and unreserved characters
+fn encode_path(path: &str) -> String {
+    path.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Random nonce for a single-use link
+pub fn new_nonce() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+/// Address of the client making a request
+///
+/// This is the connection's peer address, unless the peer is one of the
+/// `trusted_proxies`. Then the client is the last address in
+/// `X-Forwarded-For` that is not a trusted proxy, or else `X-Real-IP`.
+/// Headers from other peers are ignored, as any client can set them.
+pub fn client_ip(
+    headers: &HeaderMap,
+    extensions: &Extensions,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    let peer = extensions
+        .get::<axum::extract::ConnectInfo<std::net::SocketAddr>>()
+        .map(|info| info.0.ip())?;
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+
+    // Each proxy appends the address it received the request from
+    let forwarded: Vec<_> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|address| address.trim().parse::<IpAddr>().ok())
+        .collect();
+    for address in forwarded.into_iter().rev() {
+        match address {
+            Some(ip) if trusted_proxies.contains(&ip) => continue,
+            Some(ip) => return Some(ip),
+            None => break,
+        }
+    }
+
+    headers
+        .get("x-real-ip")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .or(Some(peer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(expires: i64) -> LinkParams {
+        LinkParams {
+            expires,
+            nonce: None,
+            ip: None,
+            sig: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_signed_link_round_trip() {
+        let signer = LinkSigner::new(b"0123456789abcdef0123456789abcdef".to_vec());
+        let target = LinkTarget::Blob(Uuid::new_v4());
+        let signed = signer.sign(&target, params(1_000));
+
+        assert_eq!(signer.verify(&target, &signed, 999, None), Ok(()));
+        assert_eq!(
+            signer.verify(&target, &signed, 1_000, None),
+            Err(LinkError::Expired)
+        );
+
+        // The signature covers the target and every parameter
+        let other = LinkTarget::Blob(Uuid::new_v4());
+        assert_eq!(
+            signer.verify(&other, &signed, 999, None),
+            Err(LinkError::InvalidSignature)
+        );
+        let mut extended = signed.clone();
+        extended.expires = 2_000;
+        assert_eq!(
+            signer.verify(&target, &extended, 999, None),
+            Err(LinkError::InvalidSignature)
+        );
+
+        let other_signer = LinkSigner::new(b"fedcba9876543210fedcba9876543210".to_vec());
+        assert_eq!(
+            other_signer.verify(&target, &signed, 999, None),
+            Err(LinkError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_signed_link_ip_binding_and_url() {
+        let signer = LinkSigner::new(b"0123456789abcdef0123456789abcdef".to_vec());
+        let target = LinkTarget::PrivateFile("docs/q3 report.pdf".to_string());
+        let ip: IpAddr = "192.0.2.7".parse().unwrap();
+        let signed = signer.sign(
+            &target,
+            LinkParams {
+                ip: Some(ip),
+                nonce: Some(new_nonce()),
+                ..params(1_000)
+            },
+        );
+
+        assert_eq!(signer.verify(&target, &signed, 0, Some(ip)), Ok(()));
+        assert_eq!(
+            signer.verify(&target, &signed, 0, "192.0.2.8".parse().ok()),
+            Err(LinkError::WrongAddress)
+        );
+        assert_eq!(
+            signer.verify(&target, &signed, 0, None),
+            Err(LinkError::WrongAddress)
+        );
+
+        // The query parses back into the same parameters
+        let url = signer.url(&target, &signed);
+        assert!(url.starts_with("/api/media/signed/private/docs/q3%20report.pdf?"));
+        let uri: axum::http::Uri = url.parse().unwrap();
+        let parsed = axum::extract::Query::<LinkParams>::try_from_uri(&uri)
+            .unwrap()
+            .0;
+        assert_eq!(signer.verify(&target, &parsed, 0, Some(ip)), Ok(()));
+    }
+
+    #[test]
+    fn test_client_ip_trusts_only_configured_proxies() {
+        let from = |peer: &str, forwarded: &[(&str, &str)], trusted: &[&str]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in forwarded {
+                let name: axum::http::HeaderName = name.parse().unwrap();
+                headers.append(name, value.parse().unwrap());
+            }
+            let mut extensions = Extensions::new();
+            let peer: IpAddr = peer.parse().unwrap();
+            extensions.insert(axum::extract::ConnectInfo(std::net::SocketAddr::new(
+                peer, 443,
+            )));
+            let trusted: Vec<IpAddr> = trusted.iter().map(|ip| ip.parse().unwrap()).collect();
+            client_ip(&headers, &extensions, &trusted).map(|ip| ip.to_string())
+        };
+        let spoofed = [("x-forwarded-for", "192.0.2.1"), ("x-real-ip", "192.0.2.1")];
+
+        // Headers from untrusted peers are ignored
+        assert_eq!(from("198.51.100.9", &spoofed, &[]).unwrap(), "198.51.100.9");
+        assert_eq!(
+            from("198.51.100.9", &spoofed, &["10.0.0.1"]).unwrap(),
+            "198.51.100.9"
+        );
+
+        // Behind trusted proxies, the nearest untrusted forwarded address wins
+        let chain = [("x-forwarded-for", "192.0.2.66, 192.0.2.1, 10.0.0.2")];
+        assert_eq!(
+            from("10.0.0.1", &chain, &["10.0.0.1", "10.0.0.2"]).unwrap(),
+            "192.0.2.1"
+        );
+        assert_eq!(
+            from("10.0.0.1", &[("x-real-ip", "192.0.2.5")], &["10.0.0.1"]).unwrap(),
+            "192.0.2.5"
+        );
+        assert_eq!(from("10.0.0.1", &[], &["10.0.0.1"]).unwrap(), "10.0.0.1");
+
+        // Without a peer address the client is unknown
+        assert_eq!(client_ip(&HeaderMap::new(), &Extensions::new(), &[]), None);
+    }
+}
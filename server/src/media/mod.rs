@@ -3,73 +3,1534 @@
 //! This module provides a complete system for managing media blobs including:
 //! - Database models and repository layer
 //! - WebSocket integration for real-time sharing
-//! - File storage and retrieval operations
+//! - Pluggable content storage (database, filesystem, S3-compatible)
+//! - Signed, expiring download links
+//! - Background processing, such as image thumbnails and audio/video metadata
+//! - Content type detection and MIME allow/deny lists
+//! - Malware scanning, with downloads withheld until content is found clean
+//! - Per-user storage usage and quotas
+//! - Collections and tags for organising blobs
+//! - Full-text and metadata search
+//! - Statistics and storage reporting, also for Prometheus
+//! - Consistency checks between stored files and the database
+//! - A trash for deleted blobs, retention policies and an audit log
+//! - Export and import of blobs as tar archives with a manifest
+//! - Real-time events for created, updated and deleted blobs
 //!
 //! The media system is designed to work with the existing authentication
 //! and analytics systems to provide secure, trackable file sharing.
 
+pub mod archive;
+pub mod av;
+pub mod collections;
+pub mod events;
+pub mod fsck;
+pub mod handlers;
+pub mod images;
+pub mod jobs;
+pub mod links;
 pub mod models;
+pub mod processing;
+pub mod quota;
 pub mod repository;
+pub mod retention;
+pub mod routes;
+pub mod scan;
+pub mod search;
+pub mod sniff;
+pub mod stats;
+pub mod storage;
 
 // Re-export commonly used types
-use crate::config::MediaConfig;
+use crate::auth::User;
+use crate::config::{MediaConfig, MediaQuotaConfig, MediaStorageBackend, MimeTypeRules};
 use crate::error::WebauthnError;
-pub use models::{CreateMediaBlob, MediaBlob, MediaBlobQuery, MediaBlobStats};
+pub use archive::{
+    export_media, import_media, ArchiveError, ExportReport, ImportFailure, ImportOptions,
+    ImportReport, ManifestBlob, MediaManifest,
+};
+pub use av::{extract_av_metadata, AvError};
+pub use collections::MediaCollectionRepository;
+pub use events::{MediaEvent, MediaEventKind, MediaEvents, MediaSubscription};
+pub use fsck::{run_fsck, spawn_media_fsck, FsckIssue, FsckIssueKind, FsckOptions, FsckReport};
+pub use jobs::MediaJobRepository;
+pub use links::{LinkSigner, LinkTarget};
+pub use models::{
+    normalize_tags, AvMetadata, AvTrack, AvTrackKind, CreateMediaBlob, CreateMediaLink,
+    ImageMetadata, MediaAuditAction, MediaAuditEntry, MediaAuditReason, MediaBlob,
+    MediaBlobLocation, MediaBlobQuery, MediaBlobShare, MediaBlobStats, MediaClientStats,
+    MediaCollection, MediaGcReport, MediaGrowthStats, MediaJob, MediaJobKind, MediaLink,
+    MediaObject, MediaOwnerStats, MediaRetentionReport, MediaStatsQuery, MediaTagCount,
+    MediaVisibility, MimeTypeCount, PreviewSource, ScanStatus, StorageBackendStats, ThumbnailRef,
+    TrashedMediaBlob,
+};
+pub use processing::{run_pending_jobs, runnable_job_kinds, spawn_media_processor};
+pub use quota::{MediaUsage, QuotaExceeded};
 pub use repository::{MediaError, MediaRepository};
+pub use retention::{run_retention, spawn_media_retention};
+pub use routes::build_media_routes;
+pub use scan::{scanner_from_config, MalwareScanner, ScanError, ScanVerdict};
+pub use search::{MediaSearch, MediaSearchPage, MetadataFilter, SearchError};
+pub use sniff::{check_content_type, detect_content_type, ContentTypeError, Detection};
+pub use stats::render_prometheus;
+pub use storage::{
+    BlobStorage, BlobStorageError, ContentStream, MediaStorage, StagedFile, StagingError,
+    StoredContent,
+};
+
+use std::path::PathBuf;
+
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use tracing::{debug, error, info, warn};
+
+/// Content for an object that is not stored yet
+enum NewContent<'a> {
+    /// Bytes held in memory
+    Bytes(Vec<u8>),
+    /// A finished staging file, linked or streamed into storage
+    Staged(&'a StagedFile),
+}
+
+impl NewContent<'_> {
+    /// SHA256, size and leading bytes of the content
+    fn describe(&self) -> (String, u64, &[u8]) {
+        match self {
+            NewContent::Bytes(bytes) => (
+                format!("{:x}", Sha256::digest(bytes)),
+                bytes.len() as u64,
+                bytes.as_slice(),
+            ),
+            NewContent::Staged(staged) => (
+                staged.sha256().unwrap_or_default().to_string(),
+                staged.size(),
+                staged.head(),
+            ),
+        }
+    }
+}
+
+/// Where the content of a blob can be served from
+pub enum MediaContent {
+    /// A local file, which can be streamed
+    File(PathBuf),
+    /// Content in a backend that does not keep local files, streamed from it
+    Stored(StoredContent),
+}
 
 /// Media blob service that combines repository operations with business logic
+///
+/// Content is stored once per SHA256 in a media object; each blob is a logical
+/// record referencing it. The object records which storage backend holds the
+/// bytes; new content goes to the configured default backend. Deleting the
+/// last blob for some content removes the object and its stored bytes.
+///
+/// Access rules: admins and the owner can always see and manage a blob. Other
+/// users can see it when its visibility is `Members`, or when it is `Shared`
+/// and they hold a sharing grant. Only the owner or an admin may change or
+/// delete a blob.
+///
+/// A service given an event hub with [`with_events`](Self::with_events)
+/// publishes blob uploads, changes and deletions to it.
 pub struct MediaService<'a> {
     repository: MediaRepository<'a>,
+    storage: &'a MediaStorage,
+    events: Option<&'a MediaEvents>,
 }
 
 impl<'a> MediaService<'a> {
     /// Create a new MediaService
-    pub fn new(repository: MediaRepository<'a>) -> Self {
-        Self { repository }
+    pub fn new(repository: MediaRepository<'a>, storage: &'a MediaStorage) -> Self {
+        Self {
+            repository,
+            storage,
+            events: None,
+        }
+    }
+
+    /// Publish blob changes made through this service to `events`
+    pub fn with_events(mut self, events: &'a MediaEvents) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// The hub to publish to, if anyone is listening
+    fn listened_events(&self) -> Option<&'a MediaEvents> {
+        self.events.filter(|events| events.has_subscribers())
+    }
+
+    /// Describe a change to a blob, with the collections and shares
+    /// subscribers need to filter it
+    async fn event(
+        &self,
+        kind: MediaEventKind,
+        blob: &MediaBlob,
+    ) -> Result<MediaEvent, WebauthnError> {
+        let collection_ids = self.repository.collections().ids_for_blob(blob.id).await?;
+        let shared_with = self.shared_with(blob.id).await?;
+
+        let mut blob = blob.clone();
+        blob.data = None;
+        Ok(MediaEvent {
+            kind,
+            blob,
+            collection_ids,
+            shared_with,
+        })
+    }
+
+    /// Tell subscribers about a change to a blob
+    ///
+    /// Failing to describe the change only loses the event; the change
+    /// itself has already been made.
+    async fn publish(&self, kind: MediaEventKind, blob: &MediaBlob) {
+        let Some(events) = self.listened_events() else {
+            return;
+        };
+
+        match self.event(kind, blob).await {
+            Ok(event) => events.publish(event),
+            Err(e) => error!("Failed to publish event for media blob {}: {}", blob.id, e),
+        }
     }
 
-    /// Create a new media blob with deduplication check
+    /// Create a new media blob
+    ///
+    /// Every call creates a new record. If the content is already stored,
+    /// `params.data` may be omitted and the record references the existing
+    /// object; data that is given must match `params.sha256` either way. New
+    /// content is written to the default storage backend; without
+    /// `params.data` `WebauthnError::MediaContentMissing` is returned.
+    ///
+    /// Blobs uploaded by users go through [`Self::create_upload`] instead, so
+    /// content can only be referenced by hash by those who may already see it.
     pub async fn create_blob(
+        &self,
+        mut params: CreateMediaBlob,
+        media_config: &MediaConfig,
+    ) -> Result<MediaBlob, WebauthnError> {
+        let content = params
+            .data
+            .take()
+            .filter(|d| !d.is_empty())
+            .map(NewContent::Bytes);
+        self.create_with_content(params, content, None, media_config)
+            .await
+    }
+
+    /// Create a new media blob uploaded by `uploader`
+    ///
+    /// Like [`Self::create_blob`], except that without `params.data` the
+    /// record may only reference content the uploader can already see (see
+    /// [`Self::can_reference`]). Otherwise `WebauthnError::MediaContentMissing`
+    /// is returned, as for content that is not stored at all, so the reply
+    /// does not reveal whether someone else stored it.
+    pub async fn create_upload(
+        &self,
+        params: CreateMediaBlob,
+        uploader: &User,
+        media_config: &MediaConfig,
+    ) -> Result<MediaBlob, WebauthnError> {
+        let has_data = params.data.as_ref().is_some_and(|data| !data.is_empty());
+        if !has_data && !self.can_reference(&params.sha256, uploader).await? {
+            debug!(
+                "User {} may not reference content {} by hash",
+                uploader.id, params.sha256
+            );
+            return Err(WebauthnError::MediaContentMissing);
+        }
+        self.create_blob(params, media_config).await
+    }
+
+    /// Check whether a user may create blobs for stored content by its hash
+    ///
+    /// Only content the user can already see, through any blob that holds
+    /// it, may be referenced without sending the bytes again; admins may
+    /// reference any stored content. False is also returned when the
+    /// content is not stored.
+    pub async fn can_reference(&self, sha256: &str, viewer: &User) -> Result<bool, WebauthnError> {
+        if self.repository.find_object(sha256).await?.is_none() {
+            return Ok(false);
+        }
+        if viewer.is_admin() {
+            return Ok(true);
+        }
+        self.repository.has_visible_content(sha256, viewer.id).await
+    }
+
+    /// Create a new media blob from a finished staging file
+    ///
+    /// The file is only stored if the content is new; it must match
+    /// `params.sha256`. Large files are never read into memory unless the
+    /// default backend is the database. The file is left to the caller, who
+    /// may use it again if the blob could not be created.
+    pub async fn create_blob_from_file(
+        &self,
+        mut params: CreateMediaBlob,
+        staged: &StagedFile,
+        media_config: &MediaConfig,
+    ) -> Result<MediaBlob, WebauthnError> {
+        if staged.sha256() != Some(params.sha256.as_str()) {
+            warn!("Staged content does not match SHA256 {}", params.sha256);
+            return Err(MediaError::InvalidHash.into());
+        }
+
+        params.data = None;
+        params.size = Some(staged.size() as i64);
+        self.create_with_content(params, Some(NewContent::Staged(staged)), None, media_config)
+            .await
+    }
+
+    /// Store a blob derived from `parent`, such as a thumbnail
+    ///
+    /// The derived blob has the parent's owner and visibility, is not listed
+    /// on its own and is deleted with the parent. `derivative` names it
+    /// uniquely among the parent's derivatives.
+    pub async fn create_derivative(
+        &self,
+        parent: &MediaBlob,
+        derivative: &str,
+        bytes: Vec<u8>,
+        mime: &str,
+        metadata: serde_json::Value,
+        media_config: &MediaConfig,
+    ) -> Result<MediaBlob, WebauthnError> {
+        let params = CreateMediaBlob {
+            data: None,
+            sha256: format!("{:x}", Sha256::digest(&bytes)),
+            size: Some(bytes.len() as i64),
+            mime: Some(mime.to_string()),
+            source_client_id: parent.source_client_id.clone(),
+            owner_user_id: parent.owner_user_id,
+            visibility: parent.visibility,
+            local_path: None,
+            metadata,
+        };
+
+        self.create_with_content(
+            params,
+            Some(NewContent::Bytes(bytes)),
+            Some((parent.id, derivative)),
+            media_config,
+        )
+        .await
+    }
+
+    /// Delete the blobs derived from a blob and their unreferenced content
+    pub async fn delete_derivatives(&self, parent_id: uuid::Uuid) -> Result<u64, WebauthnError> {
+        let sha256s = self.repository.delete_derivatives(parent_id).await?;
+        for sha256 in &sha256s {
+            self.collect_garbage_for(Some(sha256)).await?;
+        }
+        Ok(sha256s.len() as u64)
+    }
+
+    /// Insert a logical record, storing its content first if it is new
+    ///
+    /// Blobs derived from another are linked to their `(parent, derivative)`;
+    /// other blobs get their background processing job queued.
+    async fn create_with_content(
         &self,
         params: CreateMediaBlob,
+        content: Option<NewContent<'_>>,
+        derived_from: Option<(uuid::Uuid, &str)>,
         media_config: &MediaConfig,
     ) -> Result<MediaBlob, WebauthnError> {
-        // Check if blob with same SHA256 already exists
-        if let Ok(existing) = self.repository.get_by_sha256(&params.sha256).await {
-            tracing::info!("Found existing blob with SHA256: {}", params.sha256);
-            return Ok(existing);
+        params
+            .validate_reference(
+                media_config.max_blob_file_size,
+                media_config.max_fs_file_size,
+            )
+            .map_err(|e| {
+                warn!("Rejected media blob {}: {}", params.sha256, e);
+                WebauthnError::BadRequest
+            })?;
+
+        info!("Creating media blob with SHA256: {}", params.sha256);
+
+        // Content that is sent must be what it claims to be, even when the
+        // object is stored already and the bytes are not needed
+        let described = content.as_ref().map(NewContent::describe);
+        if let Some((sha256, _, _)) = &described {
+            if *sha256 != params.sha256 {
+                warn!("Content does not match SHA256 {}", params.sha256);
+                return Err(MediaError::InvalidHash.into());
+            }
+        }
+
+        let mut blob = MediaBlob::new(params);
+        let mut tx = self.repository.begin().await?;
+        let mut written = None;
+
+        // Holding a key-share lock on an existing object keeps garbage
+        // collection from removing it before our reference is inserted
+        match self
+            .repository
+            .lock_object_in(&mut tx, &blob.sha256)
+            .await?
+        {
+            Some(object) => {
+                debug!("Content {} already stored, adding reference", object.sha256);
+
+                if blob.size.is_some_and(|size| size != object.size) {
+                    warn!(
+                        "Size mismatch for existing content {}: {:?} != {}",
+                        object.sha256, blob.size, object.size
+                    );
+                    return Err(MediaError::Validation(
+                        "size does not match stored content".into(),
+                    )
+                    .into());
+                }
+                blob.size = Some(object.size);
+            }
+            None => {
+                let (Some(content), Some((sha256, size, head))) = (&content, described) else {
+                    return Err(WebauthnError::MediaContentMissing);
+                };
+                blob.size = Some(size as i64);
+
+                let backend = self.storage.default_backend();
+                let key = backend.key_for(&blob.sha256);
+                let content_type = detect_content_type(head).unwrap_or(sniff::UNRECOGNISED);
+                let inserted = self
+                    .repository
+                    .insert_object_in(
+                        &mut *tx,
+                        &blob.sha256,
+                        size as i64,
+                        backend.backend(),
+                        &key,
+                        content_type,
+                    )
+                    .await?;
+
+                // The object row is locked by this transaction until commit, so
+                // concurrent uploads of the same content wait rather than race
+                // on the stored bytes
+                if inserted {
+                    match &content {
+                        NewContent::Bytes(bytes) => backend.put(&key, bytes).await?,
+                        NewContent::Staged(staged) => {
+                            backend.put_file(&key, staged.path(), &sha256).await?
+                        }
+                    }
+                    written = Some((backend, key));
+                }
+            }
+        }
+
+        let result = async {
+            // Uploads are quarantined until scanned, unless the content
+            // already has a verdict
+            if derived_from.is_none() && media_config.scanning.enabled {
+                let known = self
+                    .repository
+                    .known_scan_status_in(&mut *tx, &blob.sha256)
+                    .await?;
+                if known == Some(ScanStatus::Infected) {
+                    warn!(
+                        "Upload of media blob {} by {:?} matches quarantined content {}",
+                        blob.id, blob.owner_user_id, blob.sha256
+                    );
+                }
+                blob.scan_status = Some(known.unwrap_or(ScanStatus::Pending));
+            }
+
+            self.repository.insert_blob_in(&mut *tx, &blob).await?;
+
+            // The trigger has counted the new blob and holds the owner's
+            // usage row until commit, so concurrent uploads are checked in turn
+            if let (None, Some(owner)) = (derived_from, blob.owner_user_id) {
+                if let Some(usage) = self.repository.usage_in(&mut *tx, owner).await? {
+                    usage
+                        .with_quota(&media_config.quotas)
+                        .check_counted(blob.size.unwrap_or_default())?;
+                }
+            }
+
+            match derived_from {
+                Some((parent_id, derivative)) => {
+                    self.repository
+                        .set_derivative_in(&mut *tx, blob.id, parent_id, derivative)
+                        .await?
+                }
+                None => {
+                    let processing = media_config
+                        .processing
+                        .enabled
+                        .then(|| MediaJobKind::for_mime(blob.mime.as_deref()))
+                        .flatten();
+                    let scan = (blob.scan_status == Some(ScanStatus::Pending))
+                        .then_some(MediaJobKind::MalwareScan);
+                    for kind in processing.into_iter().chain(scan) {
+                        self.repository
+                            .jobs()
+                            .enqueue_in(&mut *tx, blob.id, kind)
+                            .await?;
+                    }
+                }
+            }
+            tx.commit().await?;
+            Ok::<_, WebauthnError>(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            match &e {
+                WebauthnError::MediaQuota(quota) => {
+                    warn!("Refusing media blob {}: {}", blob.sha256, quota)
+                }
+                e => error!("Failed to create media blob: {}", e),
+            }
+            if let Some((backend, key)) = written {
+                if let Err(cleanup_err) = backend.delete(&key).await {
+                    error!(
+                        "Failed to clean up content {} after database error: {}",
+                        key, cleanup_err
+                    );
+                }
+            }
+            return Err(e);
+        }
+
+        info!("Successfully created media blob: {}", blob.id);
+        if derived_from.is_none() {
+            self.publish(MediaEventKind::Created, &blob).await;
         }
+        Ok(blob)
+    }
+
+    /// Look up stored content by SHA256
+    pub async fn find_object(&self, sha256: &str) -> Result<Option<MediaObject>, WebauthnError> {
+        self.repository.find_object(sha256).await
+    }
+
+    /// Check an upload's declared type against its content and the given rules
+    ///
+    /// Content that is already stored is judged by the type recorded when it
+    /// was first stored; otherwise `head`, the leading bytes of the content,
+    /// is sniffed. Objects stored before detection existed, and uploads whose
+    /// bytes are not at hand, are only checked against the rules. Returns the
+    /// effective MIME type to record on the blob.
+    pub async fn check_content_type(
+        &self,
+        declared: Option<&str>,
+        head: Option<&[u8]>,
+        sha256: &str,
+        rules: &MimeTypeRules,
+    ) -> Result<Option<String>, WebauthnError> {
+        let object = self.repository.find_object(sha256).await?;
+        let detection = match (&object, head) {
+            (Some(object), _) => Detection::from_recorded(object.content_type.as_deref()),
+            (None, Some(head)) => Detection::Sniffed(detect_content_type(head)),
+            (None, None) => Detection::Unchecked,
+        };
 
-        // Create new blob
-        self.repository.create(params, media_config).await
+        check_content_type(declared, detection, rules).map_err(|e| {
+            warn!("Rejected content for {}: {}", sha256, e);
+            e.into()
+        })
     }
 
-    /// Get a blob by ID, optionally including data
+    /// Read the stored bytes of an object from the backend that holds them
+    pub async fn read_object(&self, object: &MediaObject) -> Result<Vec<u8>, WebauthnError> {
+        let backend = self.storage.backend(object.storage_backend)?;
+
+        backend.get(&object.storage_key).await?.ok_or_else(|| {
+            error!(
+                "Content {} missing from {} storage at {}",
+                object.sha256, object.storage_backend, object.storage_key
+            );
+            WebauthnError::MediaContentMissing
+        })
+    }
+
+    /// Locate the content of a blob for serving
+    ///
+    /// Content on the local filesystem is referenced in place; content in
+    /// other backends is checked to exist and read only as it is served.
+    pub async fn content(&self, blob: &MediaBlob) -> Result<MediaContent, WebauthnError> {
+        let object = self
+            .repository
+            .find_object(&blob.sha256)
+            .await?
+            .ok_or(WebauthnError::MediaContentMissing)?;
+        let backend = self.storage.backend(object.storage_backend)?;
+
+        match backend.local_path(&object.storage_key) {
+            Some(path) => {
+                if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+                    error!(
+                        "Content {} missing from {} storage at {}",
+                        object.sha256, object.storage_backend, object.storage_key
+                    );
+                    return Err(WebauthnError::MediaContentMissing);
+                }
+                Ok(MediaContent::File(path))
+            }
+            None => {
+                if !backend.exists(&object.storage_key).await? {
+                    error!(
+                        "Content {} missing from {} storage at {}",
+                        object.sha256, object.storage_backend, object.storage_key
+                    );
+                    return Err(WebauthnError::MediaContentMissing);
+                }
+                Ok(MediaContent::Stored(self.storage.stored(
+                    object.storage_backend,
+                    &object.storage_key,
+                    object.size as u64,
+                )?))
+            }
+        }
+    }
+
+    /// List objects whose content is not yet stored in `target`
+    pub async fn list_objects_not_in(
+        &self,
+        target: MediaStorageBackend,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<MediaObject>, WebauthnError> {
+        self.repository
+            .list_objects_not_in(target, after, limit)
+            .await
+    }
+
+    /// Move an object's content to another storage backend
+    ///
+    /// The object row stays locked while the content is copied, so uploads
+    /// and garbage collection wait for the move. The source copy is removed
+    /// only after the new location is committed. Returns false if the object
+    /// no longer exists or already lives in `target`.
+    pub async fn move_object(
+        &self,
+        sha256: &str,
+        target: MediaStorageBackend,
+    ) -> Result<bool, WebauthnError> {
+        let target = self.storage.backend(target)?;
+        let mut tx = self.repository.begin().await?;
+
+        // A dropped transaction is only rolled back once its pooled
+        // connection is used again, so it is rolled back here to release the
+        // object at once
+        let object = match self
+            .repository
+            .lock_object_for_update_in(&mut tx, sha256)
+            .await
+        {
+            Ok(Some(object)) if object.storage_backend != target.backend() => object,
+            locked => {
+                if let Err(e) = tx.rollback().await {
+                    warn!("Failed to roll back move of {}: {}", sha256, e);
+                }
+                return locked.map(|_| false);
+            }
+        };
+
+        let key = target.key_for(&object.sha256);
+        let copied = match self.storage.backend(object.storage_backend) {
+            Ok(source) => self.copy_object(&object, source, target, &key).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = copied {
+            if let Err(rollback_err) = tx.rollback().await {
+                warn!("Failed to roll back move of {}: {}", sha256, rollback_err);
+            }
+            return Err(e);
+        }
+
+        let result = async {
+            self.repository
+                .update_object_location_in(&mut *tx, &object.sha256, target.backend(), &key)
+                .await?;
+            tx.commit().await?;
+            Ok::<_, WebauthnError>(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            error!("Failed to record new location of {}: {}", object.sha256, e);
+            if let Err(cleanup_err) = target.delete(&key).await {
+                error!("Failed to clean up copied content {}: {}", key, cleanup_err);
+            }
+            return Err(e);
+        }
+
+        // The object now points at the new copy; a leftover source copy is
+        // harmless, so failing to remove it is only logged
+        let source = self.storage.backend(object.storage_backend)?;
+        if let Err(e) = source.delete(&object.storage_key).await {
+            warn!(
+                "Failed to remove {} from {} storage after moving it: {}",
+                object.storage_key, object.storage_backend, e
+            );
+        }
+
+        info!(
+            "Moved media object {} from {} to {} storage",
+            object.sha256,
+            object.storage_backend,
+            target.backend()
+        );
+        Ok(true)
+    }
+
+    /// Store an object's content in `target` under `key`, checking its hash
+    ///
+    /// Content kept in a local file is stored from that file. Other content
+    /// is streamed into a staging file first, so it is never held in memory.
+    async fn copy_object(
+        &self,
+        object: &MediaObject,
+        source: &dyn BlobStorage,
+        target: &dyn BlobStorage,
+        key: &str,
+    ) -> Result<(), WebauthnError> {
+        use futures_util::StreamExt;
+
+        let missing = || {
+            error!(
+                "Content {} missing from {} storage at {}",
+                object.sha256, object.storage_backend, object.storage_key
+            );
+            WebauthnError::MediaContentMissing
+        };
+        let mismatch = || {
+            error!(
+                "Content {} in {} storage does not match its hash",
+                object.sha256, object.storage_backend
+            );
+            WebauthnError::from(MediaError::InvalidHash)
+        };
+
+        if let Some(path) = source.local_path(&object.storage_key) {
+            let sha256 = match fsck::hash_file(&path).await {
+                Ok(sha256) => sha256,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(missing()),
+                Err(e) => return Err(BlobStorageError::from(e).into()),
+            };
+            if sha256 != object.sha256 {
+                return Err(mismatch());
+            }
+            target.put_file(key, &path, &object.sha256).await?;
+            return Ok(());
+        }
+
+        let size = object.size as u64;
+        let mut content = source
+            .get_range(&object.storage_key, 0, size)
+            .await?
+            .ok_or_else(missing)?;
+        let mut staged = StagedFile::create(&self.storage.staging_directory(), size)
+            .await
+            .map_err(|e| staging_error(e, mismatch))?;
+        while let Some(chunk) = content.next().await {
+            staged
+                .write_chunk(&chunk?)
+                .await
+                .map_err(|e| staging_error(e, mismatch))?;
+        }
+        staged
+            .finish()
+            .await
+            .map_err(|e| staging_error(e, mismatch))?;
+        if staged.sha256() != Some(object.sha256.as_str()) {
+            return Err(mismatch());
+        }
+
+        target.put_file(key, staged.path(), &object.sha256).await?;
+        Ok(())
+    }
+
+    /// Remove all stored objects that no blob references
+    pub async fn collect_garbage(&self) -> Result<MediaGcReport, WebauthnError> {
+        self.collect_garbage_for(None).await
+    }
+
+    /// Remove unreferenced objects, optionally only the one with `sha256`
+    ///
+    /// Objects currently locked by an in-flight upload are skipped and picked
+    /// up by a later pass.
+    async fn collect_garbage_for(
+        &self,
+        sha256: Option<&str>,
+    ) -> Result<MediaGcReport, WebauthnError> {
+        let mut tx = self.repository.begin().await?;
+        let objects = self
+            .repository
+            .lock_unreferenced_objects_in(&mut tx, sha256)
+            .await?;
+
+        let mut report = MediaGcReport::default();
+        let mut removed = Vec::with_capacity(objects.len());
+
+        // Content is removed while the rows are still locked, so a concurrent
+        // upload of the same content waits and then stores a fresh copy
+        for object in objects {
+            let result = match self.storage.backend(object.storage_backend) {
+                Ok(backend) => backend.delete(&object.storage_key).await,
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = result {
+                error!(
+                    "Failed to remove content {} from {} storage: {}",
+                    object.sha256, object.storage_backend, e
+                );
+                continue;
+            }
+
+            report.bytes_freed += object.size;
+            removed.push(object.sha256);
+        }
+
+        report.objects_removed = self
+            .repository
+            .delete_objects_in(&mut *tx, &removed)
+            .await?;
+        tx.commit().await?;
+
+        if report.objects_removed > 0 {
+            info!(
+                "Garbage collected {} media objects ({} bytes)",
+                report.objects_removed, report.bytes_freed
+            );
+        }
+
+        Ok(report)
+    }
+
+    /// Check whether a user may see a blob and read its data
+    pub async fn can_view(&self, blob: &MediaBlob, viewer: &User) -> Result<bool, WebauthnError> {
+        if Self::can_manage(blob, viewer) {
+            return Ok(true);
+        }
+
+        match blob.visibility {
+            MediaVisibility::Members => Ok(true),
+            MediaVisibility::Private => Ok(false),
+            MediaVisibility::Shared => self.repository.is_shared_with(blob.id, viewer.id).await,
+        }
+    }
+
+    /// Check whether a user may change, share or delete a blob
+    pub fn can_manage(blob: &MediaBlob, viewer: &User) -> bool {
+        viewer.is_admin() || blob.is_owned_by(viewer.id)
+    }
+
+    /// Get a blob by ID if the viewer may see it, optionally including data
+    ///
+    /// Blobs the viewer may not see are reported as not found so their
+    /// existence is not leaked.
     pub async fn get_blob(
         &self,
         id: uuid::Uuid,
         include_data: bool,
+        viewer: &User,
     ) -> Result<MediaBlob, WebauthnError> {
+        let mut blob = self.repository.get_by_id_without_data(id).await?;
+
+        if !self.can_view(&blob, viewer).await? {
+            tracing::warn!("User {} denied access to media blob {}", viewer.id, id);
+            return Err(MediaError::NotFound.into());
+        }
+
         if include_data {
-            self.repository.find_by_id(id).await
-        } else {
-            self.repository.get_by_id_without_data(id).await
+            Self::ensure_released(&blob)?;
+            let object = self
+                .repository
+                .find_object(&blob.sha256)
+                .await?
+                .ok_or(WebauthnError::MediaContentMissing)?;
+            blob.data = Some(self.read_object(&object).await?);
+        }
+
+        Ok(blob)
+    }
+
+    /// Refuse to hand out content that is quarantined by malware scanning
+    pub fn ensure_released(blob: &MediaBlob) -> Result<(), WebauthnError> {
+        match blob.scan_status {
+            Some(status) if status.blocks_download() => {
+                warn!("Withholding {} media blob {}", status, blob.id);
+                Err(WebauthnError::MediaQuarantined(status))
+            }
+            _ => Ok(()),
         }
     }
 
-    /// List blobs with pagination and filtering
-    pub async fn list_blobs(&self, query: MediaBlobQuery) -> Result<Vec<MediaBlob>, WebauthnError> {
+    /// Refuse to serve a stored file whose content is quarantined
+    ///
+    /// Files are recognised by their content-addressed name; other files are
+    /// not media content and always released.
+    pub async fn ensure_file_released(&self, path: &str) -> Result<(), WebauthnError> {
+        let name = path.rsplit('/').next().unwrap_or_default();
+        if name.len() != 64 || !name.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(());
+        }
+
+        match self.repository.quarantine_status(name).await? {
+            Some(status) => {
+                warn!("Withholding {} media file {}", status, path);
+                Err(WebauthnError::MediaQuarantined(status))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Record a scan verdict on every uploaded blob sharing the content
+    ///
+    /// Infected content is logged as an error, so it reaches admins through
+    /// the logs as well as the quarantine listing.
+    pub async fn record_scan(
+        &self,
+        blob: &MediaBlob,
+        scanner: &str,
+        verdict: &ScanVerdict,
+    ) -> Result<u64, WebauthnError> {
+        let (status, signature) = match verdict {
+            ScanVerdict::Clean => (ScanStatus::Clean, None),
+            ScanVerdict::Infected { signature } => (ScanStatus::Infected, Some(signature)),
+        };
+        let report = serde_json::json!({
+            "scan": {
+                "scanner": scanner,
+                "signature": signature,
+                "scanned_at": OffsetDateTime::now_utc()
+                    .format(&time::format_description::well_known::Rfc3339)
+                    .unwrap_or_default(),
+            }
+        });
+
+        let updated = self
+            .repository
+            .set_scan_status(&blob.sha256, status, report)
+            .await?;
+        if let Some(signature) = signature {
+            error!(
+                "Malware {} found in media blob {} (owner {:?}, {} blobs quarantined)",
+                signature, blob.id, blob.owner_user_id, updated
+            );
+        }
+
+        Ok(updated)
+    }
+
+    /// Get a blob by ID without checking who is asking
+    ///
+    /// Only for callers that authorized access some other way, such as a
+    /// signed link minted by someone who could see the blob.
+    pub async fn find_blob(&self, id: uuid::Uuid) -> Result<MediaBlob, WebauthnError> {
+        self.repository.get_by_id_without_data(id).await
+    }
+
+    /// Record the use of a single-use link, returning when it was first
+    /// used if that was before
+    pub async fn record_link_use(
+        &self,
+        nonce: &str,
+        expires_at: OffsetDateTime,
+    ) -> Result<Option<OffsetDateTime>, WebauthnError> {
+        self.repository.record_link_use(nonce, expires_at).await
+    }
+
+    /// Get a blob the viewer is allowed to manage
+    async fn get_managed_blob(
+        &self,
+        id: uuid::Uuid,
+        viewer: &User,
+    ) -> Result<MediaBlob, WebauthnError> {
+        let blob = self.get_blob(id, false, viewer).await?;
+
+        if !Self::can_manage(&blob, viewer) {
+            tracing::warn!("User {} may not manage media blob {}", viewer.id, id);
+            return Err(MediaError::Forbidden.into());
+        }
+
+        Ok(blob)
+    }
+
+    /// List blobs visible to the viewer with pagination and filtering
+    ///
+    /// Listing a collection requires access to it; its blobs come in
+    /// collection order.
+    pub async fn list_blobs(
+        &self,
+        mut query: MediaBlobQuery,
+        viewer: &User,
+    ) -> Result<Vec<MediaBlob>, WebauthnError> {
+        if let Some(collection_id) = query.collection_id {
+            self.get_collection(collection_id, viewer).await?;
+        }
+        query.tags = normalize_tags(&query.tags).map_err(MediaError::Validation)?;
+        query.visible_to = if viewer.is_admin() {
+            None
+        } else {
+            Some(viewer.id)
+        };
+
         self.repository.query(query).await
     }
 
+    /// Search the blobs visible to the viewer
+    pub async fn search(
+        &self,
+        search: &MediaSearch,
+        viewer: &User,
+    ) -> Result<MediaSearchPage, WebauthnError> {
+        let mut prepared = search.prepare()?;
+        prepared.visible_to = if viewer.is_admin() {
+            None
+        } else {
+            Some(viewer.id)
+        };
+
+        let (blobs, next) = self.repository.search(&prepared).await?;
+        Ok(MediaSearchPage {
+            blobs,
+            next_cursor: next.map(|cursor| cursor.encode()),
+        })
+    }
+
+    /// Replace the tags of a blob the viewer manages
+    pub async fn set_tags(
+        &self,
+        id: uuid::Uuid,
+        tags: &[String],
+        viewer: &User,
+    ) -> Result<MediaBlob, WebauthnError> {
+        let mut blob = self.get_managed_blob(id, viewer).await?;
+        let tags = normalize_tags(tags).map_err(MediaError::Validation)?;
+
+        self.repository.set_tags(id, &tags).await?;
+        blob.tags = tags;
+        self.publish(MediaEventKind::Updated, &blob).await;
+        Ok(blob)
+    }
+
+    /// Tags on the viewer's blobs starting with `prefix`, most used first
+    pub async fn list_tags(
+        &self,
+        prefix: Option<&str>,
+        limit: i64,
+        viewer: &User,
+    ) -> Result<Vec<MediaTagCount>, WebauthnError> {
+        let prefix = prefix.map(|prefix| prefix.trim().to_lowercase());
+        self.repository
+            .list_tags(viewer.id, prefix.as_deref(), limit.clamp(1, 1000))
+            .await
+    }
+
+    /// Create a collection owned by the viewer
+    pub async fn create_collection(
+        &self,
+        name: &str,
+        description: Option<&str>,
+        retention_days: Option<i32>,
+        viewer: &User,
+    ) -> Result<MediaCollection, WebauthnError> {
+        let name = MediaCollection::normalize_name(name).map_err(MediaError::Validation)?;
+        let description = description.map(str::trim).filter(|d| !d.is_empty());
+        let retention_days = retention_days
+            .map(retention::check_collection_retention)
+            .transpose()
+            .map_err(MediaError::Validation)?
+            .filter(|days| *days > 0);
+
+        self.repository
+            .collections()
+            .create(viewer.id, &name, description, retention_days)
+            .await
+    }
+
+    /// The viewer's collections, by name
+    pub async fn list_collections(
+        &self,
+        viewer: &User,
+    ) -> Result<Vec<MediaCollection>, WebauthnError> {
+        self.repository
+            .collections()
+            .list_for_owner(viewer.id)
+            .await
+    }
+
+    /// Get a collection if the viewer owns it (or is an admin)
+    ///
+    /// Other users' collections are reported as not found.
+    pub async fn get_collection(
+        &self,
+        id: uuid::Uuid,
+        viewer: &User,
+    ) -> Result<MediaCollection, WebauthnError> {
+        match self.repository.collections().find(id).await? {
+            Some(collection) if viewer.is_admin() || collection.owner_user_id == viewer.id => {
+                Ok(collection)
+            }
+            _ => Err(MediaError::NotFound.into()),
+        }
+    }
+
+    /// IDs of the blobs in a collection, in order
+    pub async fn collection_blob_ids(
+        &self,
+        id: uuid::Uuid,
+        viewer: &User,
+    ) -> Result<Vec<uuid::Uuid>, WebauthnError> {
+        self.get_collection(id, viewer).await?;
+        self.repository.collections().blob_ids(id).await
+    }
+
+    /// Rename a collection or change its description or retention
+    ///
+    /// `None` leaves a field unchanged; an empty description clears it, as
+    /// does a retention of 0 days.
+    pub async fn update_collection(
+        &self,
+        id: uuid::Uuid,
+        name: Option<&str>,
+        description: Option<&str>,
+        retention_days: Option<i32>,
+        viewer: &User,
+    ) -> Result<MediaCollection, WebauthnError> {
+        self.get_collection(id, viewer).await?;
+        let name = name
+            .map(MediaCollection::normalize_name)
+            .transpose()
+            .map_err(MediaError::Validation)?;
+        let retention_days = retention_days
+            .map(retention::check_collection_retention)
+            .transpose()
+            .map_err(MediaError::Validation)?;
+
+        self.repository
+            .collections()
+            .update(
+                id,
+                name.as_deref(),
+                description.map(str::trim),
+                retention_days,
+            )
+            .await?;
+        self.get_collection(id, viewer).await
+    }
+
+    /// Delete a collection; its blobs are kept
+    pub async fn delete_collection(
+        &self,
+        id: uuid::Uuid,
+        viewer: &User,
+    ) -> Result<bool, WebauthnError> {
+        self.get_collection(id, viewer).await?;
+        self.repository.collections().delete(id).await
+    }
+
+    /// Append blobs the viewer can see to a collection
+    ///
+    /// Blobs already in the collection keep their place.
+    pub async fn add_to_collection(
+        &self,
+        id: uuid::Uuid,
+        blob_ids: &[uuid::Uuid],
+        viewer: &User,
+    ) -> Result<MediaCollection, WebauthnError> {
+        self.get_collection(id, viewer).await?;
+
+        let mut unique = Vec::with_capacity(blob_ids.len());
+        for blob_id in blob_ids {
+            if !unique.contains(blob_id) {
+                self.get_blob(*blob_id, false, viewer).await?;
+                unique.push(*blob_id);
+            }
+        }
+
+        self.repository.collections().add_blobs(id, &unique).await?;
+        self.get_collection(id, viewer).await
+    }
+
+    /// Remove a blob from a collection
+    pub async fn remove_from_collection(
+        &self,
+        id: uuid::Uuid,
+        blob_id: uuid::Uuid,
+        viewer: &User,
+    ) -> Result<MediaCollection, WebauthnError> {
+        self.get_collection(id, viewer).await?;
+        self.repository
+            .collections()
+            .remove_blob(id, blob_id)
+            .await?;
+        self.get_collection(id, viewer).await
+    }
+
+    /// Reorder a collection
+    ///
+    /// `blob_ids` must list every blob in the collection exactly once.
+    pub async fn reorder_collection(
+        &self,
+        id: uuid::Uuid,
+        blob_ids: &[uuid::Uuid],
+        viewer: &User,
+    ) -> Result<Vec<uuid::Uuid>, WebauthnError> {
+        let collections = self.repository.collections();
+        let mut current = self.collection_blob_ids(id, viewer).await?;
+        let mut requested = blob_ids.to_vec();
+        current.sort();
+        requested.sort();
+        if current != requested {
+            return Err(MediaError::Validation(
+                "Reordering must list every blob in the collection exactly once".to_string(),
+            )
+            .into());
+        }
+
+        collections.reorder(id, blob_ids).await?;
+        collections.blob_ids(id).await
+    }
+
     /// Get media statistics
-    pub async fn get_stats(&self) -> Result<MediaBlobStats, WebauthnError> {
-        self.repository.get_stats().await
+    pub async fn get_stats(&self, query: MediaStatsQuery) -> Result<MediaBlobStats, WebauthnError> {
+        self.repository.get_stats(query).await
+    }
+
+    /// A user's storage usage and the quota in effect for them
+    pub async fn usage(
+        &self,
+        user_id: uuid::Uuid,
+        quotas: &MediaQuotaConfig,
+    ) -> Result<MediaUsage, WebauthnError> {
+        let usage = self
+            .repository
+            .usage(user_id)
+            .await?
+            .ok_or(WebauthnError::UserNotFound)?;
+        Ok(usage.with_quota(quotas))
+    }
+
+    /// Storage usage of every user, largest first
+    pub async fn list_usage(
+        &self,
+        quotas: &MediaQuotaConfig,
+    ) -> Result<Vec<MediaUsage>, WebauthnError> {
+        let usage = self.repository.list_usage().await?;
+        Ok(usage
+            .into_iter()
+            .map(|usage| usage.with_quota(quotas))
+            .collect())
+    }
+
+    /// Refuse an upload of `size` bytes that would take a user over quota
+    ///
+    /// Quotas are enforced when blobs are created; checking first lets
+    /// uploads fail before their content is sent.
+    pub async fn check_quota(
+        &self,
+        user_id: uuid::Uuid,
+        size: i64,
+        quotas: &MediaQuotaConfig,
+    ) -> Result<MediaUsage, WebauthnError> {
+        let usage = self.usage(user_id, quotas).await?;
+        if let Err(e) = usage.check(size) {
+            warn!("Refusing upload by {}: {}", usage.username, e);
+            return Err(e.into());
+        }
+        Ok(usage)
+    }
+
+    /// Set or clear the quota overriding a user's role quota
+    pub async fn set_quota(
+        &self,
+        user_id: uuid::Uuid,
+        quota_bytes: Option<i64>,
+        quotas: &MediaQuotaConfig,
+    ) -> Result<MediaUsage, WebauthnError> {
+        // Look the user up first, for a clear error rather than a foreign key violation
+        self.usage(user_id, quotas).await?;
+        self.repository
+            .set_quota_override(user_id, quota_bytes)
+            .await?;
+        self.usage(user_id, quotas).await
+    }
+
+    /// Delete a blob by ID for good if the viewer owns it (or is an admin)
+    ///
+    /// Blobs derived from it are deleted with it. Users normally move blobs
+    /// to the trash with [`trash_blob`](Self::trash_blob) instead.
+    pub async fn delete_blob(&self, id: uuid::Uuid, viewer: &User) -> Result<bool, WebauthnError> {
+        self.get_managed_blob(id, viewer).await?;
+        self.purge(id, MediaAuditReason::User, Some(viewer.id))
+            .await
+    }
+
+    /// Delete a blob, live or trashed, with its derived blobs and their
+    /// stored bytes, and record why in the audit log
+    async fn purge(
+        &self,
+        id: uuid::Uuid,
+        reason: MediaAuditReason,
+        actor: Option<uuid::Uuid>,
+    ) -> Result<bool, WebauthnError> {
+        let mut sha256s = self.repository.derivative_sha256s(id).await?;
+
+        // Subscribers were told about trashed blobs when they were trashed,
+        // and the blob's collections and shares go with it
+        let deleted = match self.listened_events() {
+            Some(events) => match self.repository.get_by_id_without_data(id).await {
+                Ok(blob) => Some((events, self.event(MediaEventKind::Deleted, &blob).await?)),
+                Err(WebauthnError::UserNotFound) => None,
+                Err(e) => return Err(e),
+            },
+            None => None,
+        };
+
+        let mut tx = self.repository.begin().await?;
+        self.repository
+            .record_audit_in(&mut *tx, MediaAuditAction::Purged, reason, &[id], actor)
+            .await?;
+        let Some(sha256) = self.repository.delete_in(&mut *tx, id).await? else {
+            return Ok(false);
+        };
+        tx.commit().await?;
+
+        if let Some((events, event)) = deleted {
+            events.publish(event);
+        }
+
+        sha256s.push(sha256);
+        for sha256 in &sha256s {
+            self.collect_garbage_for(Some(sha256)).await?;
+        }
+
+        Ok(true)
+    }
+
+    /// Move a blob to the trash if the viewer owns it (or is an admin)
+    ///
+    /// Trashed blobs disappear from listings, searches and downloads but
+    /// keep their stored bytes, collections, tags and shares until restored
+    /// or purged.
+    pub async fn trash_blob(&self, id: uuid::Uuid, viewer: &User) -> Result<bool, WebauthnError> {
+        let blob = self.get_managed_blob(id, viewer).await?;
+
+        let mut tx = self.repository.begin().await?;
+        let trashed = self
+            .repository
+            .trash_in(&mut *tx, id, Some(viewer.id))
+            .await?;
+        if trashed {
+            self.repository
+                .record_audit_in(
+                    &mut *tx,
+                    MediaAuditAction::Trashed,
+                    MediaAuditReason::User,
+                    &[id],
+                    Some(viewer.id),
+                )
+                .await?;
+        }
+        tx.commit().await?;
+
+        info!("Moved media blob {} to the trash", id);
+        if trashed {
+            self.publish(MediaEventKind::Deleted, &blob).await;
+        }
+        Ok(trashed)
+    }
+
+    /// A blob in the trash the viewer owns (or any, for admins)
+    ///
+    /// Blobs past the trash window can no longer be restored and are
+    /// reported as not found, like blobs of other users.
+    async fn get_trashed_blob(
+        &self,
+        id: uuid::Uuid,
+        viewer: &User,
+        config: &MediaConfig,
+    ) -> Result<TrashedMediaBlob, WebauthnError> {
+        let trashed = self
+            .repository
+            .find_trashed(id, retention::window_days(config.retention.trash_days))
+            .await?
+            .ok_or(MediaError::NotFound)?;
+
+        if !Self::can_manage(&trashed.blob, viewer) {
+            return Err(MediaError::NotFound.into());
+        }
+
+        Ok(trashed)
+    }
+
+    /// Take a blob out of the trash
+    pub async fn restore_blob(
+        &self,
+        id: uuid::Uuid,
+        viewer: &User,
+        config: &MediaConfig,
+    ) -> Result<MediaBlob, WebauthnError> {
+        self.get_trashed_blob(id, viewer, config).await?;
+
+        let mut tx = self.repository.begin().await?;
+        let restored = self
+            .repository
+            .restore_in(
+                &mut *tx,
+                id,
+                retention::window_days(config.retention.trash_days),
+            )
+            .await?;
+        if !restored {
+            return Err(MediaError::NotFound.into());
+        }
+        self.repository
+            .record_audit_in(
+                &mut *tx,
+                MediaAuditAction::Restored,
+                MediaAuditReason::User,
+                &[id],
+                Some(viewer.id),
+            )
+            .await?;
+        tx.commit().await?;
+
+        info!("Restored media blob {} from the trash", id);
+        let blob = self.find_blob(id).await?;
+        self.publish(MediaEventKind::Created, &blob).await;
+        Ok(blob)
+    }
+
+    /// The viewer's trash, most recently deleted first
+    pub async fn list_trash(
+        &self,
+        viewer: &User,
+        config: &MediaConfig,
+        limit: Option<i64>,
+        offset: Option<i64>,
+    ) -> Result<Vec<TrashedMediaBlob>, WebauthnError> {
+        self.repository
+            .list_trash(
+                viewer.id,
+                retention::window_days(config.retention.trash_days),
+                limit.unwrap_or(50).clamp(1, 1000),
+                offset.unwrap_or(0).max(0),
+            )
+            .await
     }
 
-    /// Delete a blob by ID
-    pub async fn delete_blob(&self, id: uuid::Uuid) -> Result<bool, WebauthnError> {
-        self.repository.delete(id).await
+    /// Delete a blob in the trash for good
+    pub async fn purge_trashed_blob(
+        &self,
+        id: uuid::Uuid,
+        viewer: &User,
+        config: &MediaConfig,
+    ) -> Result<bool, WebauthnError> {
+        self.get_trashed_blob(id, viewer, config).await?;
+        self.purge(id, MediaAuditReason::User, Some(viewer.id))
+            .await
+    }
+
+    /// Delete everything in the viewer's trash for good, returning how many
+    /// blobs were purged
+    pub async fn empty_trash(
+        &self,
+        viewer: &User,
+        config: &MediaConfig,
+    ) -> Result<u64, WebauthnError> {
+        let trash_days = retention::window_days(config.retention.trash_days);
+        let mut purged = 0;
+
+        loop {
+            let blobs = self
+                .repository
+                .list_trash(viewer.id, trash_days, 1000, 0)
+                .await?;
+            if blobs.is_empty() {
+                break;
+            }
+            for trashed in blobs {
+                if self
+                    .purge(trashed.blob.id, MediaAuditReason::User, Some(viewer.id))
+                    .await?
+                {
+                    purged += 1;
+                }
+            }
+        }
+
+        Ok(purged)
+    }
+
+    /// Audit log entries, newest first, optionally only for one blob
+    pub async fn list_audit(
+        &self,
+        blob_id: Option<uuid::Uuid>,
+        limit: i64,
+    ) -> Result<Vec<MediaAuditEntry>, WebauthnError> {
+        self.repository
+            .list_audit(blob_id, limit.clamp(1, 10_000))
+            .await
+    }
+
+    /// Change a blob's visibility
+    pub async fn set_visibility(
+        &self,
+        id: uuid::Uuid,
+        visibility: MediaVisibility,
+        viewer: &User,
+    ) -> Result<MediaBlob, WebauthnError> {
+        self.get_managed_blob(id, viewer).await?;
+        let blob = self.repository.set_visibility(id, visibility).await?;
+        self.publish(MediaEventKind::Updated, &blob).await;
+        Ok(blob)
+    }
+
+    /// Grant users access to a blob
+    ///
+    /// Sharing only takes effect while the blob's visibility is `Shared`.
+    pub async fn share_blob(
+        &self,
+        id: uuid::Uuid,
+        user_ids: &[uuid::Uuid],
+        viewer: &User,
+    ) -> Result<Vec<MediaBlobShare>, WebauthnError> {
+        let blob = self.get_managed_blob(id, viewer).await?;
+
+        for user_id in user_ids {
+            self.repository.add_share(id, *user_id, viewer.id).await?;
+        }
+
+        self.publish(MediaEventKind::Updated, &blob).await;
+        self.repository.list_shares(id).await
+    }
+
+    /// Revoke a user's access grant for a blob
+    pub async fn unshare_blob(
+        &self,
+        id: uuid::Uuid,
+        user_id: uuid::Uuid,
+        viewer: &User,
+    ) -> Result<Vec<MediaBlobShare>, WebauthnError> {
+        let blob = self.get_managed_blob(id, viewer).await?;
+        self.repository.remove_share(id, user_id).await?;
+        self.publish(MediaEventKind::Updated, &blob).await;
+        self.repository.list_shares(id).await
+    }
+
+    /// IDs of the users a blob is shared with, so access can be checked
+    /// with [`MediaBlob::visible_to`] away from the database
+    pub async fn shared_with(&self, id: uuid::Uuid) -> Result<Vec<uuid::Uuid>, WebauthnError> {
+        let shares = self.repository.list_shares(id).await?;
+        Ok(shares.into_iter().map(|share| share.user_id).collect())
+    }
+
+    /// List the users a blob has been shared with
+    pub async fn list_shares(
+        &self,
+        id: uuid::Uuid,
+        viewer: &User,
+    ) -> Result<Vec<MediaBlobShare>, WebauthnError> {
+        self.get_managed_blob(id, viewer).await?;
+        self.repository.list_shares(id).await
     }
 
     /// Update blob metadata
@@ -78,11 +1539,52 @@ impl<'a> MediaService<'a> {
         id: uuid::Uuid,
         metadata: serde_json::Value,
     ) -> Result<MediaBlob, WebauthnError> {
-        self.repository.update_metadata(id, metadata).await
+        let blob = self.repository.update_metadata(id, metadata).await?;
+        self.publish(MediaEventKind::Updated, &blob).await;
+        Ok(blob)
     }
 
-    /// Clean up old blobs
+    /// Merge keys into a blob's metadata
+    pub async fn merge_metadata(
+        &self,
+        id: uuid::Uuid,
+        metadata: serde_json::Value,
+    ) -> Result<(), WebauthnError> {
+        self.repository.merge_metadata(id, metadata).await
+    }
+
+    /// Move blobs older than `days` to the trash
+    ///
+    /// They are purged with their stored bytes once the trash window passes.
     pub async fn cleanup_old_blobs(&self, days: i32) -> Result<u64, WebauthnError> {
-        self.repository.cleanup_old_blobs(days).await
+        let cutoff = OffsetDateTime::now_utc() - time::Duration::days(days as i64);
+
+        let mut tx = self.repository.begin().await?;
+        let ids = self
+            .repository
+            .trash_created_before_in(&mut *tx, cutoff)
+            .await?;
+        self.repository
+            .record_audit_in(
+                &mut *tx,
+                MediaAuditAction::Trashed,
+                MediaAuditReason::Cleanup,
+                &ids,
+                None,
+            )
+            .await?;
+        tx.commit().await?;
+
+        info!("Moved {} old media blobs to the trash", ids.len());
+        Ok(ids.len() as u64)
+    }
+}
+
+/// Map a staging failure while copying content; content longer than its
+/// recorded size does not match its hash
+fn staging_error(err: StagingError, mismatch: impl Fn() -> WebauthnError) -> WebauthnError {
+    match err {
+        StagingError::TooLarge { .. } => mismatch(),
+        StagingError::Io(e) => BlobStorageError::from(e).into(),
     }
 }
@@ -8,6 +8,292 @@ use sqlx::FromRow;
 use time::OffsetDateTime;
 use uuid::Uuid;
 
+use crate::auth::User;
+use crate::config::MediaStorageBackend;
+
+/// Who besides the owner may access a media blob
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaVisibility {
+    /// Only the owner (and admins)
+    #[default]
+    Private,
+    /// The owner and users with an explicit sharing grant
+    Shared,
+    /// Every authenticated member
+    Members,
+}
+
+impl MediaVisibility {
+    /// Database representation of this visibility
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaVisibility::Private => "private",
+            MediaVisibility::Shared => "shared",
+            MediaVisibility::Members => "members",
+        }
+    }
+}
+
+impl std::str::FromStr for MediaVisibility {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "private" => Ok(MediaVisibility::Private),
+            "shared" => Ok(MediaVisibility::Shared),
+            "members" => Ok(MediaVisibility::Members),
+            _ => Err(format!("Invalid media visibility: {}", s)),
+        }
+    }
+}
+
+// Stored as TEXT with a CHECK constraint rather than a Postgres enum type
+impl sqlx::Type<sqlx::Postgres> for MediaVisibility {
+    fn type_info() -> sqlx::postgres::PgTypeInfo {
+        <String as sqlx::Type<sqlx::Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &sqlx::postgres::PgTypeInfo) -> bool {
+        <String as sqlx::Type<sqlx::Postgres>>::compatible(ty)
+    }
+}
+
+impl<'r> sqlx::Decode<'r, sqlx::Postgres> for MediaVisibility {
+    fn decode(value: sqlx::postgres::PgValueRef<'r>) -> Result<Self, sqlx::error::BoxDynError> {
+        let s = <&str as sqlx::Decode<sqlx::Postgres>>::decode(value)?;
+        Ok(s.parse()?)
+    }
+}
+
+impl sqlx::Encode<'_, sqlx::Postgres> for MediaVisibility {
+    fn encode_by_ref(
+        &self,
+        buf: &mut sqlx::postgres::PgArgumentBuffer,
+    ) -> Result<sqlx::encode::IsNull, sqlx::error::BoxDynError> {
+        <&str as sqlx::Encode<sqlx::Postgres>>::encode(self.as_str(), buf)
+    }
+}
+
+/// Outcome of scanning a blob's content for malware
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanStatus {
+    /// Waiting for the scanner; the content cannot be downloaded yet
+    Pending,
+    /// No malware found
+    Clean,
+    /// Malware found; the content cannot be downloaded
+    Infected,
+}
+
+impl ScanStatus {
+    /// Database representation of this status
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScanStatus::Pending => "pending",
+            ScanStatus::Clean => "clean",
+            ScanStatus::Infected => "infected",
+        }
+    }
+
+    /// Whether content with this status is withheld from downloads
+    pub fn blocks_download(&self) -> bool {
+        !matches!(self, ScanStatus::Clean)
+    }
+}
+
+impl std::fmt::Display for ScanStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for ScanStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(ScanStatus::Pending),
+            "clean" => Ok(ScanStatus::Clean),
+            "infected" => Ok(ScanStatus::Infected),
+            _ => Err(format!("Invalid scan status: {}", s)),
+        }
+    }
+}
+
+// Stored as TEXT with a CHECK constraint, like `MediaVisibility`
+impl sqlx::Type<sqlx::Postgres> for ScanStatus {
+    fn type_info() -> sqlx::postgres::PgTypeInfo {
+        <String as sqlx::Type<sqlx::Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &sqlx::postgres::PgTypeInfo) -> bool {
+        <String as sqlx::Type<sqlx::Postgres>>::compatible(ty)
+    }
+}
+
+impl<'r> sqlx::Decode<'r, sqlx::Postgres> for ScanStatus {
+    fn decode(value: sqlx::postgres::PgValueRef<'r>) -> Result<Self, sqlx::error::BoxDynError> {
+        let s = <&str as sqlx::Decode<sqlx::Postgres>>::decode(value)?;
+        Ok(s.parse()?)
+    }
+}
+
+impl sqlx::Encode<'_, sqlx::Postgres> for ScanStatus {
+    fn encode_by_ref(
+        &self,
+        buf: &mut sqlx::postgres::PgArgumentBuffer,
+    ) -> Result<sqlx::encode::IsNull, sqlx::error::BoxDynError> {
+        <&str as sqlx::Encode<sqlx::Postgres>>::encode(self.as_str(), buf)
+    }
+}
+
+/// What happened to a blob, as recorded in the media audit log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaAuditAction {
+    /// Moved to the trash
+    Trashed,
+    /// Taken back out of the trash
+    Restored,
+    /// Deleted for good, with its stored bytes unless other blobs share them
+    Purged,
+}
+
+impl MediaAuditAction {
+    /// Database representation of this action
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaAuditAction::Trashed => "trashed",
+            MediaAuditAction::Restored => "restored",
+            MediaAuditAction::Purged => "purged",
+        }
+    }
+}
+
+impl std::fmt::Display for MediaAuditAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for MediaAuditAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trashed" => Ok(MediaAuditAction::Trashed),
+            "restored" => Ok(MediaAuditAction::Restored),
+            "purged" => Ok(MediaAuditAction::Purged),
+            _ => Err(format!("Invalid media audit action: {}", s)),
+        }
+    }
+}
+
+// Stored as TEXT with a CHECK constraint, like `ScanStatus`
+impl sqlx::Type<sqlx::Postgres> for MediaAuditAction {
+    fn type_info() -> sqlx::postgres::PgTypeInfo {
+        <String as sqlx::Type<sqlx::Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &sqlx::postgres::PgTypeInfo) -> bool {
+        <String as sqlx::Type<sqlx::Postgres>>::compatible(ty)
+    }
+}
+
+impl<'r> sqlx::Decode<'r, sqlx::Postgres> for MediaAuditAction {
+    fn decode(value: sqlx::postgres::PgValueRef<'r>) -> Result<Self, sqlx::error::BoxDynError> {
+        let s = <&str as sqlx::Decode<sqlx::Postgres>>::decode(value)?;
+        Ok(s.parse()?)
+    }
+}
+
+impl sqlx::Encode<'_, sqlx::Postgres> for MediaAuditAction {
+    fn encode_by_ref(
+        &self,
+        buf: &mut sqlx::postgres::PgArgumentBuffer,
+    ) -> Result<sqlx::encode::IsNull, sqlx::error::BoxDynError> {
+        <&str as sqlx::Encode<sqlx::Postgres>>::encode(self.as_str(), buf)
+    }
+}
+
+/// Why a blob was trashed, restored or purged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaAuditReason {
+    /// A user asked for it
+    User,
+    /// Old blobs were cleaned up
+    Cleanup,
+    /// The blob stayed in the trash past the trash window
+    TrashExpired,
+    /// A retention policy expired the blob
+    Retention,
+    /// A consistency check found its content missing or corrupt
+    Fsck,
+}
+
+impl MediaAuditReason {
+    /// Database representation of this reason
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaAuditReason::User => "user",
+            MediaAuditReason::Cleanup => "cleanup",
+            MediaAuditReason::TrashExpired => "trash_expired",
+            MediaAuditReason::Retention => "retention",
+            MediaAuditReason::Fsck => "fsck",
+        }
+    }
+}
+
+impl std::fmt::Display for MediaAuditReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for MediaAuditReason {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user" => Ok(MediaAuditReason::User),
+            "cleanup" => Ok(MediaAuditReason::Cleanup),
+            "trash_expired" => Ok(MediaAuditReason::TrashExpired),
+            "retention" => Ok(MediaAuditReason::Retention),
+            "fsck" => Ok(MediaAuditReason::Fsck),
+            _ => Err(format!("Invalid media audit reason: {}", s)),
+        }
+    }
+}
+
+impl sqlx::Type<sqlx::Postgres> for MediaAuditReason {
+    fn type_info() -> sqlx::postgres::PgTypeInfo {
+        <String as sqlx::Type<sqlx::Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &sqlx::postgres::PgTypeInfo) -> bool {
+        <String as sqlx::Type<sqlx::Postgres>>::compatible(ty)
+    }
+}
+
+impl<'r> sqlx::Decode<'r, sqlx::Postgres> for MediaAuditReason {
+    fn decode(value: sqlx::postgres::PgValueRef<'r>) -> Result<Self, sqlx::error::BoxDynError> {
+        let s = <&str as sqlx::Decode<sqlx::Postgres>>::decode(value)?;
+        Ok(s.parse()?)
+    }
+}
+
+impl sqlx::Encode<'_, sqlx::Postgres> for MediaAuditReason {
+    fn encode_by_ref(
+        &self,
+        buf: &mut sqlx::postgres::PgArgumentBuffer,
+    ) -> Result<sqlx::encode::IsNull, sqlx::error::BoxDynError> {
+        <&str as sqlx::Encode<sqlx::Postgres>>::encode(self.as_str(), buf)
+    }
+}
+
 /// Common validation for media blob data
 fn validate_media_blob_common(
     sha256: &str,
@@ -17,20 +303,32 @@ fn validate_media_blob_common(
     max_blob_size: u64,
     max_fs_size: u64,
 ) -> Result<(), String> {
-    if sha256.is_empty() {
-        return Err("SHA256 hash is required".to_string());
-    }
-
-    if sha256.len() != 64 {
-        return Err("SHA256 hash must be 64 characters".to_string());
-    }
-
     // Check that we have either data or local_path
     let has_data = data.is_some() && !data.unwrap().is_empty();
     if !has_data && local_path.is_none() {
         return Err("Either data or local_path must be provided".to_string());
     }
 
+    validate_media_blob_fields(sha256, data, size, local_path, max_blob_size, max_fs_size)
+}
+
+/// Validate hash and size limits without requiring the content itself
+fn validate_media_blob_fields(
+    sha256: &str,
+    data: Option<&Vec<u8>>,
+    size: Option<i64>,
+    local_path: Option<&String>,
+    max_blob_size: u64,
+    max_fs_size: u64,
+) -> Result<(), String> {
+    if sha256.is_empty() {
+        return Err("SHA256 hash is required".to_string());
+    }
+
+    if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("SHA256 hash must be 64 hex characters".to_string());
+    }
+
     // Determine which limit to use based on storage type
     let max_file_size = if local_path.is_some() {
         max_fs_size
@@ -71,15 +369,41 @@ pub struct MediaBlob {
     pub size: Option<i64>,
     pub mime: Option<String>,
     pub source_client_id: Option<String>,
+    /// Owning user; `None` for legacy blobs created before ownership tracking
+    #[serde(default)]
+    pub owner_user_id: Option<Uuid>,
+    #[serde(default)]
+    pub visibility: MediaVisibility,
     pub local_path: Option<String>,
     #[serde(default)]
     pub metadata: serde_json::Value, // JSONB
+    /// Malware scan outcome; `None` for blobs that are never scanned
+    #[serde(default)]
+    pub scan_status: Option<ScanStatus>,
+    /// Free-form tags, in lower case
+    #[serde(default)]
+    pub tags: Vec<String>,
     #[serde(with = "time::serde::rfc3339")]
     pub created_at: OffsetDateTime,
     #[serde(with = "time::serde::rfc3339")]
     pub updated_at: OffsetDateTime,
 }
 
+/// A blob in the trash
+#[derive(Clone, Serialize, Deserialize, FromRow)]
+pub struct TrashedMediaBlob {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub blob: MediaBlob,
+    #[serde(with = "time::serde::rfc3339")]
+    pub deleted_at: OffsetDateTime,
+    /// User who deleted the blob; `None` for automatic cleanups
+    pub deleted_by: Option<Uuid>,
+    /// When the blob is purged unless restored first
+    #[serde(with = "time::serde::rfc3339")]
+    pub purge_at: OffsetDateTime,
+}
+
 /// Parameters for creating a new media blob
 #[derive(Clone, Serialize, Deserialize)]
 pub struct CreateMediaBlob {
@@ -88,6 +412,10 @@ pub struct CreateMediaBlob {
     pub size: Option<i64>,
     pub mime: Option<String>,
     pub source_client_id: Option<String>,
+    #[serde(default)]
+    pub owner_user_id: Option<Uuid>,
+    #[serde(default)]
+    pub visibility: MediaVisibility,
     pub local_path: Option<String>,
     #[serde(default)]
     pub metadata: serde_json::Value,
@@ -101,15 +429,187 @@ pub struct MediaBlobQuery {
     pub sha256: Option<String>,
     pub source_client_id: Option<String>,
     pub mime_pattern: Option<String>,
+    /// Only return blobs with this malware scan outcome
+    pub scan_status: Option<ScanStatus>,
+    /// Only return blobs owned by this user
+    pub owner_user_id: Option<Uuid>,
+    /// Only return blobs in this collection, in collection order
+    pub collection_id: Option<Uuid>,
+    /// Only return blobs carrying all of these tags
+    pub tags: Vec<String>,
+    /// Only return blobs this user may view (None = no visibility filtering)
+    pub visible_to: Option<Uuid>,
+}
+
+/// Most tags a blob may carry
+pub const MAX_TAGS: usize = 32;
+/// Longest tag, in characters
+pub const MAX_TAG_LENGTH: usize = 64;
+
+/// Normalise tags: trimmed, lower case, without duplicates, in order
+pub fn normalize_tags(tags: &[String]) -> Result<Vec<String>, String> {
+    let mut normalized: Vec<String> = Vec::with_capacity(tags.len());
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() {
+            return Err("Tags must not be empty".to_string());
+        }
+        if tag.chars().count() > MAX_TAG_LENGTH {
+            return Err(format!(
+                "Tags must be at most {} characters long",
+                MAX_TAG_LENGTH
+            ));
+        }
+        if tag.chars().any(char::is_control) {
+            return Err("Tags must not contain control characters".to_string());
+        }
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+
+    if normalized.len() > MAX_TAGS {
+        return Err(format!("A blob may carry at most {} tags", MAX_TAGS));
+    }
+    Ok(normalized)
+}
+
+/// Longest collection name, in characters
+pub const MAX_COLLECTION_NAME_LENGTH: usize = 100;
+
+/// A named, ordered group of media blobs owned by a user
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct MediaCollection {
+    pub id: Uuid,
+    pub owner_user_id: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+    /// Days blobs in the collection are kept before being purged; `None`
+    /// keeps them
+    #[serde(default)]
+    pub retention_days: Option<i32>,
+    /// Number of blobs in the collection
+    pub blob_count: i64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub updated_at: OffsetDateTime,
+}
+
+impl MediaCollection {
+    /// Trim a collection name and check its length
+    pub fn normalize_name(name: &str) -> Result<String, String> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err("Collection name must not be empty".to_string());
+        }
+        if name.chars().count() > MAX_COLLECTION_NAME_LENGTH {
+            return Err(format!(
+                "Collection name must be at most {} characters long",
+                MAX_COLLECTION_NAME_LENGTH
+            ));
+        }
+        Ok(name.to_string())
+    }
+}
+
+/// A sharing grant giving a user access to a blob with `Shared` visibility
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct MediaBlobShare {
+    pub blob_id: Uuid,
+    pub user_id: Uuid,
+    pub username: String,
+    pub granted_by: Option<Uuid>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub granted_at: OffsetDateTime,
+}
+
+/// Stored content shared by every media blob with the same SHA256
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct MediaObject {
+    pub sha256: String,
+    pub size: i64,
+    /// Backend holding the content
+    pub storage_backend: MediaStorageBackend,
+    /// Backend-specific key the content is stored under
+    pub storage_key: String,
+    pub ref_count: i64,
+    /// Type detected from the leading bytes; `None` for content stored before
+    /// detection, `application/octet-stream` for unrecognised content
+    pub content_type: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+/// A blob's local path next to where its content is actually stored
+#[derive(Debug, Clone, FromRow)]
+pub struct MediaBlobLocation {
+    pub id: Uuid,
+    pub sha256: String,
+    pub local_path: String,
+    pub storage_backend: MediaStorageBackend,
+    pub storage_key: String,
+}
+
+/// Result of a garbage collection pass over unreferenced media objects
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MediaGcReport {
+    pub objects_removed: u64,
+    pub bytes_freed: i64,
+}
+
+/// Result of a pass purging expired trash and blobs past their retention
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MediaRetentionReport {
+    /// Blobs purged after staying in the trash past the trash window
+    pub trash_purged: u64,
+    /// Blobs purged by retention policies
+    pub expired_purged: u64,
+}
+
+/// An entry of the media audit log
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct MediaAuditEntry {
+    pub id: i64,
+    pub action: MediaAuditAction,
+    pub reason: MediaAuditReason,
+    pub blob_id: Uuid,
+    pub sha256: String,
+    pub size: i64,
+    pub mime: Option<String>,
+    pub owner_user_id: Option<Uuid>,
+    /// User who acted; `None` for automatic removals
+    pub actor_user_id: Option<Uuid>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
 }
 
 /// Media blob statistics
+///
+/// Counts and sizes cover uploaded blobs; derived blobs such as thumbnails
+/// are counted separately. Storage figures cover stored content, which
+/// blobs with the same SHA256 share.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MediaBlobStats {
     pub total_count: i64,
     pub total_size: Option<i64>,
     pub unique_sha256_count: i64,
     pub mime_type_distribution: Vec<MimeTypeCount>,
+    /// Number and total size of derived blobs
+    pub derived_count: i64,
+    pub derived_size: i64,
+    /// Stored content by storage backend
+    pub storage: Vec<StorageBackendStats>,
+    /// Bytes not stored twice because blobs share content
+    pub duplicate_savings: i64,
+    /// Stored bytes no blob references, awaiting garbage collection
+    pub unreferenced_size: i64,
+    /// Users with the most stored bytes
+    pub top_users: Vec<MediaOwnerStats>,
+    /// Clients that uploaded the most bytes
+    pub top_clients: Vec<MediaClientStats>,
+    /// Uploads per day, oldest first; days without uploads are left out
+    pub growth: Vec<MediaGrowthStats>,
 }
 
 /// Count of blobs by MIME type
@@ -117,6 +617,304 @@ pub struct MediaBlobStats {
 pub struct MimeTypeCount {
     pub mime_type: Option<String>,
     pub count: i64,
+    #[serde(default)]
+    pub size: i64,
+}
+
+/// Stored content in one storage backend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageBackendStats {
+    pub backend: String,
+    pub object_count: i64,
+    pub size: i64,
+}
+
+/// Uploaded blobs owned by one user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaOwnerStats {
+    pub user_id: Uuid,
+    pub username: String,
+    pub blob_count: i64,
+    pub size: i64,
+}
+
+/// Uploaded blobs from one client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaClientStats {
+    /// `None` for blobs that did not name a client
+    pub source_client_id: Option<String>,
+    pub blob_count: i64,
+    pub size: i64,
+}
+
+/// Blobs uploaded on one day (UTC)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaGrowthStats {
+    #[serde(with = "time::serde::rfc3339")]
+    pub day: OffsetDateTime,
+    pub blob_count: i64,
+    pub size: i64,
+}
+
+/// How much detail to gather for [`MediaBlobStats`]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MediaStatsQuery {
+    /// Days of growth to report; 0 skips it
+    pub days: i64,
+    /// Users and clients to report; 0 skips them
+    pub top: i64,
+}
+
+impl Default for MediaStatsQuery {
+    fn default() -> Self {
+        Self { days: 30, top: 10 }
+    }
+}
+
+impl MediaStatsQuery {
+    /// Totals only, cheap enough for every metrics scrape
+    pub fn totals() -> Self {
+        Self { days: 0, top: 0 }
+    }
+}
+
+/// Count of blobs carrying a tag
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaTagCount {
+    pub tag: String,
+    pub count: i64,
+}
+
+/// Request for a signed download link to a media blob or private file
+///
+/// Exactly one of `blob_id` and `path` must be given. `path` is relative to
+/// the private static directory, as in `/private/<path>`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CreateMediaLink {
+    #[serde(default)]
+    pub blob_id: Option<Uuid>,
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Lifetime of the link; the configured default if omitted
+    #[serde(default)]
+    pub expires_in_seconds: Option<u64>,
+    /// Refuse the link after its first use
+    #[serde(default)]
+    pub single_use: bool,
+    /// Only accept the link from the address requesting it
+    #[serde(default)]
+    pub bind_ip: bool,
+}
+
+/// A signed download link
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaLink {
+    pub url: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub expires_at: OffsetDateTime,
+    pub single_use: bool,
+    pub ip: Option<std::net::IpAddr>,
+}
+
+/// Image facts recorded in a blob's metadata under `image`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageMetadata {
+    /// Width of the stored pixels, before applying `orientation`
+    pub width: u32,
+    /// Height of the stored pixels, before applying `orientation`
+    pub height: u32,
+    /// EXIF orientation (1-8)
+    pub orientation: u8,
+    /// MIME type of the decoded format
+    pub format: String,
+}
+
+/// A thumbnail listed in its parent's metadata under `thumbnails`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThumbnailRef {
+    /// Edge length of the square box the thumbnail fits into
+    pub size: u32,
+    pub blob_id: Uuid,
+    pub width: u32,
+    pub height: u32,
+    pub mime: String,
+}
+
+/// Duration, bitrate and stream facts recorded in a blob's metadata under `av`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AvMetadata {
+    /// Container format, e.g. `mp4`, `webm` or `mp3`
+    pub container: String,
+    /// Playing time in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    /// Overall bitrate in bits per second
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bitrate: Option<u64>,
+    /// Dimensions of the first video track
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(default)]
+    pub tracks: Vec<AvTrack>,
+}
+
+/// What a track in an audio or video file carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AvTrackKind {
+    Audio,
+    Video,
+    Subtitle,
+    Other,
+}
+
+/// A single track of an audio or video file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AvTrack {
+    pub kind: AvTrackKind,
+    /// Short codec name such as `h264`, `opus` or `aac`; the container's own
+    /// identifier when the codec is not recognised
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame_rate: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channels: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bits_per_sample: Option<u16>,
+    /// ISO 639-2 or BCP 47 language code
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+}
+
+impl AvTrack {
+    /// A track of `kind` with nothing else known yet
+    pub fn new(kind: AvTrackKind) -> Self {
+        Self {
+            kind,
+            codec: None,
+            duration: None,
+            width: None,
+            height: None,
+            frame_rate: None,
+            sample_rate: None,
+            channels: None,
+            bits_per_sample: None,
+            language: None,
+        }
+    }
+}
+
+/// Which blob to serve as a preview of an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreviewSource {
+    /// The image itself, which is small enough
+    Original,
+    /// A derived thumbnail blob
+    Thumbnail(Uuid),
+}
+
+/// Kind of background processing a media job performs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaJobKind {
+    /// Record image dimensions and orientation and generate thumbnails
+    ImageDerivatives,
+    /// Record duration, bitrate and track information of audio and video
+    AvMetadata,
+    /// Scan the content for malware and release or quarantine the blob
+    MalwareScan,
+}
+
+impl MediaJobKind {
+    /// Every kind of job
+    pub const ALL: [MediaJobKind; 3] = [
+        MediaJobKind::ImageDerivatives,
+        MediaJobKind::AvMetadata,
+        MediaJobKind::MalwareScan,
+    ];
+
+    /// Database representation of this kind
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaJobKind::ImageDerivatives => "image_derivatives",
+            MediaJobKind::AvMetadata => "av_metadata",
+            MediaJobKind::MalwareScan => "malware_scan",
+        }
+    }
+
+    /// MIME type prefixes of the blobs this kind of job processes
+    pub fn mime_prefixes(&self) -> &'static [&'static str] {
+        match self {
+            MediaJobKind::ImageDerivatives => &["image/"],
+            MediaJobKind::AvMetadata => &["audio/", "video/"],
+            MediaJobKind::MalwareScan => &[""],
+        }
+    }
+
+    /// The processing job a new blob with this MIME type needs, if any
+    ///
+    /// Malware scans are queued separately, for every blob, when scanning is
+    /// enabled.
+    pub fn for_mime(mime: Option<&str>) -> Option<Self> {
+        let mime = mime?;
+        [MediaJobKind::ImageDerivatives, MediaJobKind::AvMetadata]
+            .into_iter()
+            .find(|kind| {
+                kind.mime_prefixes()
+                    .iter()
+                    .any(|prefix| mime.starts_with(prefix))
+            })
+    }
+}
+
+impl std::fmt::Display for MediaJobKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for MediaJobKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "image_derivatives" => Ok(MediaJobKind::ImageDerivatives),
+            "av_metadata" => Ok(MediaJobKind::AvMetadata),
+            "malware_scan" => Ok(MediaJobKind::MalwareScan),
+            _ => Err(format!("Invalid media job kind: {}", s)),
+        }
+    }
+}
+
+/// A queued background processing job for a media blob
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct MediaJob {
+    pub id: i64,
+    pub blob_id: Uuid,
+    /// Stored as text; see `MediaJobKind`
+    pub kind: String,
+    /// `pending`, `running`, `done` or `failed`
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub run_after: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub updated_at: OffsetDateTime,
 }
 
 impl MediaBlob {
@@ -130,8 +928,12 @@ impl MediaBlob {
             size: params.size,
             mime: params.mime,
             source_client_id: params.source_client_id,
+            owner_user_id: params.owner_user_id,
+            visibility: params.visibility,
             local_path: params.local_path,
             metadata: params.metadata,
+            scan_status: None,
+            tags: Vec::new(),
             created_at: now,
             updated_at: now,
         }
@@ -150,16 +952,97 @@ impl MediaBlob {
         }
     }
 
+    /// Check whether the given user owns this blob
+    pub fn is_owned_by(&self, user_id: Uuid) -> bool {
+        self.owner_user_id == Some(user_id)
+    }
+
+    /// Check whether a user may see this blob, given who it is shared with,
+    /// following the rules of [`MediaService::can_view`](super::MediaService::can_view)
+    pub fn visible_to(&self, user: &User, shared_with: &[Uuid]) -> bool {
+        if user.is_admin() || self.is_owned_by(user.id) {
+            return true;
+        }
+
+        match self.visibility {
+            MediaVisibility::Members => true,
+            MediaVisibility::Private => false,
+            MediaVisibility::Shared => shared_with.contains(&user.id),
+        }
+    }
+
     /// Check if this blob is a large file stored on disk
     pub fn is_large_file(&self) -> bool {
         self.local_path.is_some() && self.data.is_none()
     }
 
+    /// Check if this blob was created through the large file upload endpoint
+    ///
+    /// Uploads stored outside the static asset tree have no `local_path`, so
+    /// they are recognised by their source instead.
+    pub fn is_upload(&self) -> bool {
+        self.local_path.is_some()
+            || self
+                .source_client_id
+                .as_deref()
+                .is_some_and(|source| source.starts_with("admin_upload_"))
+    }
+
     /// Check if this blob is a small file stored in database
     pub fn is_small_file(&self) -> bool {
         self.data.is_some() && self.local_path.is_none()
     }
 
+    /// Image facts recorded by background processing, if any
+    pub fn image_metadata(&self) -> Option<ImageMetadata> {
+        serde_json::from_value(self.metadata.get("image")?.clone()).ok()
+    }
+
+    /// Audio or video facts recorded by background processing, if any
+    pub fn av_metadata(&self) -> Option<AvMetadata> {
+        serde_json::from_value(self.metadata.get("av")?.clone()).ok()
+    }
+
+    /// Thumbnails derived from this blob, smallest first
+    pub fn thumbnails(&self) -> Vec<ThumbnailRef> {
+        let mut thumbnails: Vec<ThumbnailRef> = self
+            .metadata
+            .get("thumbnails")
+            .cloned()
+            .and_then(|value| serde_json::from_value(value).ok())
+            .unwrap_or_default();
+        thumbnails.sort_by_key(|thumbnail| thumbnail.size);
+        thumbnails
+    }
+
+    /// Pick the preview for a box of `size` pixels
+    ///
+    /// This is the smallest thumbnail at least as large as the box, or the
+    /// largest one if none is. Images fitting the box, or too small for any
+    /// thumbnail, are their own preview. Without `size` the smallest
+    /// thumbnail is chosen. Returns `None` until the image has been processed.
+    pub fn preview(&self, size: Option<u32>) -> Option<PreviewSource> {
+        let image = self.image_metadata()?;
+        let thumbnails = self.thumbnails();
+
+        let Some(size) = size.or_else(|| thumbnails.first().map(|t| t.size)) else {
+            return Some(PreviewSource::Original);
+        };
+        if image.width.max(image.height) <= size {
+            return Some(PreviewSource::Original);
+        }
+
+        Some(
+            thumbnails
+                .iter()
+                .find(|thumbnail| thumbnail.size >= size)
+                .or(thumbnails.last())
+                .map_or(PreviewSource::Original, |thumbnail| {
+                    PreviewSource::Thumbnail(thumbnail.blob_id)
+                }),
+        )
+    }
+
     /// Get the blob without the binary data (for efficient serialization)
     pub fn without_data(&self) -> Self {
         let mut blob = self.clone();
@@ -220,8 +1103,11 @@ impl std::fmt::Debug for MediaBlob {
             .field("size", &self.size)
             .field("mime", &self.mime)
             .field("source_client_id", &self.source_client_id)
+            .field("owner_user_id", &self.owner_user_id)
+            .field("visibility", &self.visibility)
             .field("local_path", &self.local_path)
             .field("metadata", &self.metadata)
+            .field("tags", &self.tags)
             .field("created_at", &self.created_at)
             .field("updated_at", &self.updated_at)
             .finish()
@@ -240,6 +1126,21 @@ impl CreateMediaBlob {
             max_fs_size,
         )
     }
+
+    /// Validate parameters for a record whose content may already be stored
+    ///
+    /// Unlike [`validate`](Self::validate) this does not require `data` or
+    /// `local_path`, since an existing object can be referenced by hash alone.
+    pub fn validate_reference(&self, max_blob_size: u64, max_fs_size: u64) -> Result<(), String> {
+        validate_media_blob_fields(
+            &self.sha256,
+            self.data.as_ref(),
+            self.size,
+            self.local_path.as_ref(),
+            max_blob_size,
+            max_fs_size,
+        )
+    }
 }
 
 impl std::fmt::Debug for CreateMediaBlob {
@@ -250,6 +1151,8 @@ impl std::fmt::Debug for CreateMediaBlob {
             .field("size", &self.size)
             .field("mime", &self.mime)
             .field("source_client_id", &self.source_client_id)
+            .field("owner_user_id", &self.owner_user_id)
+            .field("visibility", &self.visibility)
             .field("local_path", &self.local_path)
             .field("metadata", &self.metadata)
             .finish()
@@ -268,6 +1171,8 @@ mod tests {
             size: Some(4),
             mime: Some("image/png".to_string()),
             source_client_id: Some("test-client".to_string()),
+            owner_user_id: None,
+            visibility: MediaVisibility::Private,
             local_path: None,
             metadata: serde_json::json!({"test": true}),
         };
@@ -286,6 +1191,8 @@ mod tests {
             size: Some(4),
             mime: Some("image/png".to_string()),
             source_client_id: None,
+            owner_user_id: None,
+            visibility: MediaVisibility::Private,
             local_path: None,
             metadata: serde_json::Value::Null,
         });
@@ -305,6 +1212,8 @@ mod tests {
             size: None,
             mime: Some("image/png".to_string()),
             source_client_id: None,
+            owner_user_id: None,
+            visibility: MediaVisibility::Private,
             local_path: Some("/path/to/file".to_string()),
             metadata: serde_json::Value::Null,
         });
@@ -312,6 +1221,39 @@ mod tests {
         assert_eq!(blob.file_extension(), Some("png"));
     }
 
+    #[test]
+    fn test_visibility_round_trip() {
+        for visibility in [
+            MediaVisibility::Private,
+            MediaVisibility::Shared,
+            MediaVisibility::Members,
+        ] {
+            let parsed: MediaVisibility = visibility.as_str().parse().unwrap();
+            assert_eq!(parsed, visibility);
+
+            let json = serde_json::to_string(&visibility).unwrap();
+            assert_eq!(json, format!("\"{}\"", visibility.as_str()));
+        }
+
+        assert!("public".parse::<MediaVisibility>().is_err());
+    }
+
+    #[test]
+    fn test_missing_visibility_defaults_to_private() {
+        let params: CreateMediaBlob = serde_json::from_value(serde_json::json!({
+            "data": null,
+            "sha256": "a".repeat(64),
+            "size": null,
+            "mime": null,
+            "source_client_id": null,
+            "local_path": "uploads/file.bin",
+        }))
+        .unwrap();
+
+        assert_eq!(params.visibility, MediaVisibility::Private);
+        assert_eq!(params.owner_user_id, None);
+    }
+
     #[test]
     fn test_validation() {
         // Valid blob
@@ -321,6 +1263,8 @@ mod tests {
             size: Some(3),
             mime: Some("image/png".to_string()),
             source_client_id: None,
+            owner_user_id: None,
+            visibility: MediaVisibility::Private,
             local_path: None,
             metadata: serde_json::Value::Null,
         });
@@ -335,6 +1279,13 @@ mod tests {
             .validate(10 * 1024 * 1024, 1024 * 1024 * 1024)
             .is_err());
 
+        // Non-hex SHA256
+        let mut non_hex_blob = valid_blob.clone();
+        non_hex_blob.sha256 = "z".repeat(64);
+        assert!(non_hex_blob
+            .validate(10 * 1024 * 1024, 1024 * 1024 * 1024)
+            .is_err());
+
         // No data or path
         let mut no_data_blob = valid_blob.clone();
         no_data_blob.data = None;
@@ -343,4 +1294,129 @@ mod tests {
             .validate(10 * 1024 * 1024, 1024 * 1024 * 1024)
             .is_err());
     }
+
+    #[test]
+    fn test_preview_selection() {
+        let small = Uuid::new_v4();
+        let large = Uuid::new_v4();
+        let mut blob = MediaBlob::new(CreateMediaBlob {
+            data: None,
+            sha256: "c".repeat(64),
+            size: Some(3),
+            mime: Some("image/jpeg".to_string()),
+            source_client_id: None,
+            owner_user_id: None,
+            visibility: MediaVisibility::Private,
+            local_path: None,
+            metadata: serde_json::json!({}),
+        });
+
+        // Nothing to show before processing
+        assert_eq!(blob.preview(Some(256)), None);
+
+        blob.metadata = serde_json::json!({
+            "image": {"width": 2000, "height": 1500, "orientation": 6, "format": "image/jpeg"},
+            "thumbnails": [
+                {"size": 1024, "blob_id": large, "width": 1024, "height": 768, "mime": "image/jpeg"},
+                {"size": 256, "blob_id": small, "width": 256, "height": 192, "mime": "image/jpeg"},
+            ],
+        });
+
+        assert_eq!(blob.image_metadata().unwrap().orientation, 6);
+        assert_eq!(blob.thumbnails()[0].blob_id, small);
+        assert_eq!(blob.preview(None), Some(PreviewSource::Thumbnail(small)));
+        assert_eq!(
+            blob.preview(Some(100)),
+            Some(PreviewSource::Thumbnail(small))
+        );
+        assert_eq!(
+            blob.preview(Some(257)),
+            Some(PreviewSource::Thumbnail(large))
+        );
+        assert_eq!(
+            blob.preview(Some(1500)),
+            Some(PreviewSource::Thumbnail(large))
+        );
+        assert_eq!(blob.preview(Some(2000)), Some(PreviewSource::Original));
+
+        // Images smaller than every thumbnail box have no thumbnails
+        blob.metadata = serde_json::json!({
+            "image": {"width": 64, "height": 64, "orientation": 1, "format": "image/png"},
+            "thumbnails": [],
+        });
+        assert_eq!(blob.preview(None), Some(PreviewSource::Original));
+        assert_eq!(blob.preview(Some(32)), Some(PreviewSource::Original));
+    }
+
+    #[test]
+    fn test_job_kind_for_mime() {
+        assert_eq!(
+            MediaJobKind::for_mime(Some("image/webp")),
+            Some(MediaJobKind::ImageDerivatives)
+        );
+        assert_eq!(
+            MediaJobKind::for_mime(Some("video/mp4")),
+            Some(MediaJobKind::AvMetadata)
+        );
+        assert_eq!(
+            MediaJobKind::for_mime(Some("audio/ogg")),
+            Some(MediaJobKind::AvMetadata)
+        );
+        assert_eq!(MediaJobKind::for_mime(Some("application/pdf")), None);
+        assert_eq!(MediaJobKind::for_mime(Some("")), None);
+        assert_eq!(MediaJobKind::for_mime(None), None);
+
+        for kind in MediaJobKind::ALL {
+            assert_eq!(kind.as_str().parse::<MediaJobKind>().unwrap(), kind);
+        }
+    }
+
+    #[test]
+    fn test_reference_validation_allows_missing_content() {
+        let params = CreateMediaBlob {
+            data: None,
+            sha256: "b".repeat(64),
+            size: Some(3),
+            mime: None,
+            source_client_id: None,
+            owner_user_id: None,
+            visibility: MediaVisibility::Private,
+            local_path: None,
+            metadata: serde_json::Value::Null,
+        };
+
+        assert!(params.validate(1024, 1024).is_err());
+        assert!(params.validate_reference(1024, 1024).is_ok());
+        assert!(params.validate_reference(2, 2).is_err());
+    }
+
+    #[test]
+    fn test_normalize_tags() {
+        let tags = vec![
+            " Holiday ".to_string(),
+            "beach".to_string(),
+            "HOLIDAY".to_string(),
+        ];
+        assert_eq!(normalize_tags(&tags).unwrap(), vec!["holiday", "beach"]);
+
+        assert!(normalize_tags(&["  ".to_string()]).is_err());
+        assert!(normalize_tags(&["a".repeat(MAX_TAG_LENGTH + 1)]).is_err());
+        assert!(normalize_tags(&["tab\there".to_string()]).is_err());
+
+        let many: Vec<String> = (0..=MAX_TAGS).map(|i| format!("tag{}", i)).collect();
+        assert!(normalize_tags(&many).is_err());
+        assert_eq!(normalize_tags(&many[..MAX_TAGS]).unwrap().len(), MAX_TAGS);
+    }
+
+    #[test]
+    fn test_collection_name() {
+        assert_eq!(
+            MediaCollection::normalize_name("  Summer 2026 ").unwrap(),
+            "Summer 2026"
+        );
+        assert!(MediaCollection::normalize_name(" ").is_err());
+        assert!(
+            MediaCollection::normalize_name(&"x".repeat(MAX_COLLECTION_NAME_LENGTH + 1)).is_err()
+        );
+    }
 }
@@ -0,0 +1,361 @@
+//! Background processing of uploaded media
+//!
+//! New blobs get a job queued in the same transaction that creates them. A
+//! worker claims due jobs, runs them and records the results in the blob's
+//! metadata, or for malware scans, in the scan status of the blobs sharing
+//! the content. Failed jobs are retried with increasing delays until
+//! `max_attempts` is reached; content that cannot be decoded fails at once.
+
+use std::time::Duration;
+
+use serde_json::json;
+use time::OffsetDateTime;
+use tracing::{debug, error, info, warn};
+
+use crate::config::MediaConfig;
+use crate::database::DatabaseConnection;
+use crate::error::WebauthnError;
+
+use super::av::{extract_av_metadata, AvError};
+use super::images::analyze_image;
+use super::jobs::MediaJobRepository;
+use super::models::{MediaBlob, MediaJob, MediaJobKind, ThumbnailRef};
+use super::scan::{scanner_from_config, ScanVerdict};
+use super::{BlobStorageError, MediaContent, MediaRepository, MediaService, MediaStorage};
+
+/// Running jobs untouched for this long are assumed abandoned by a crashed worker
+const STALE_JOB_SECONDS: i64 = 15 * 60;
+
+/// Delay before the first retry; doubled for every further attempt
+const RETRY_BASE_SECONDS: i64 = 30;
+
+/// Longest delay between retries
+const RETRY_MAX_SECONDS: i64 = 60 * 60;
+
+/// Why a job did not finish
+enum JobError {
+    /// Retrying cannot help, e.g. the content is not a valid image
+    Permanent(String),
+    /// A storage or database problem that may go away
+    Transient(WebauthnError),
+}
+
+impl From<WebauthnError> for JobError {
+    fn from(err: WebauthnError) -> Self {
+        JobError::Transient(err)
+    }
+}
+
+impl std::fmt::Display for JobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobError::Permanent(message) => f.write_str(message),
+            JobError::Transient(err) => err.fmt(f),
+        }
+    }
+}
+
+/// Job kinds a worker with this configuration runs
+///
+/// Malware scans are left queued unless scanning is enabled, so content
+/// stays quarantined until a worker that can scan it picks them up.
+pub fn runnable_job_kinds(config: &MediaConfig) -> Vec<MediaJobKind> {
+    MediaJobKind::ALL
+        .into_iter()
+        .filter(|kind| *kind != MediaJobKind::MalwareScan || config.scanning.enabled)
+        .collect()
+}
+
+/// Claim and run one batch of due jobs, returning how many were claimed
+pub async fn run_pending_jobs(
+    db: &DatabaseConnection,
+    storage: &MediaStorage,
+    config: &MediaConfig,
+) -> Result<usize, WebauthnError> {
+    let jobs_repo = MediaJobRepository::new(db);
+    let stale_before = OffsetDateTime::now_utc() - time::Duration::seconds(STALE_JOB_SECONDS);
+    let jobs = jobs_repo
+        .claim(
+            config.processing.batch_size as i64,
+            stale_before,
+            &runnable_job_kinds(config),
+        )
+        .await?;
+
+    let service = MediaService::new(MediaRepository::new(db), storage);
+    for job in &jobs {
+        let result = if job.attempts > config.processing.max_attempts as i32 {
+            Err(JobError::Permanent("Too many attempts".to_string()))
+        } else {
+            run_job(&service, job, config).await
+        };
+
+        match result {
+            Ok(()) => {
+                debug!("Finished {} job for media blob {}", job.kind, job.blob_id);
+                jobs_repo.complete(job.id).await?;
+            }
+            Err(e) => {
+                let retry_at = match &e {
+                    JobError::Transient(_)
+                        if job.attempts < config.processing.max_attempts as i32 =>
+                    {
+                        Some(OffsetDateTime::now_utc() + retry_delay(job.attempts))
+                    }
+                    _ => None,
+                };
+                warn!(
+                    "{} job for media blob {} failed (attempt {}{}): {}",
+                    job.kind,
+                    job.blob_id,
+                    job.attempts,
+                    if retry_at.is_some() {
+                        ", will retry"
+                    } else {
+                        ""
+                    },
+                    e
+                );
+                jobs_repo.fail(job.id, &e.to_string(), retry_at).await?;
+            }
+        }
+    }
+
+    Ok(jobs.len())
+}
+
+/// Periodically run due media jobs in the background
+pub fn spawn_media_processor(db: DatabaseConnection, storage: MediaStorage, config: MediaConfig) {
+    let interval = Duration::from_secs(config.processing.poll_interval_seconds.max(1));
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+
+            // Keep going while full batches are claimed, so a backlog drains
+            // without waiting for the next tick
+            loop {
+                match run_pending_jobs(&db, &storage, &config).await {
+                    Ok(claimed) if claimed >= config.processing.batch_size as usize => continue,
+                    Ok(_) => break,
+                    Err(e) => {
+                        error!("Failed to run media jobs: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+async fn run_job(
+    service: &MediaService<'_>,
+    job: &MediaJob,
+    config: &MediaConfig,
+) -> Result<(), JobError> {
+    let kind: MediaJobKind = job.kind.parse().map_err(JobError::Permanent)?;
+
+    // Jobs are deleted with their blob, so a missing blob was deleted while
+    // the job ran
+    let blob = match service.find_blob(job.blob_id).await {
+        Ok(blob) => blob,
+        Err(WebauthnError::UserNotFound) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    match kind {
+        MediaJobKind::ImageDerivatives => derive_image(service, &blob, config).await,
+        MediaJobKind::AvMetadata => extract_av(service, &blob, config).await,
+        MediaJobKind::MalwareScan => scan_blob(service, &blob, config).await,
+    }
+}
+
+/// Scan a blob's content and release or quarantine every blob sharing it
+///
+/// Scanner failures are retried; a blob whose scan never succeeds stays
+/// quarantined until it is scanned again.
+async fn scan_blob(
+    service: &MediaService<'_>,
+    blob: &MediaBlob,
+    config: &MediaConfig,
+) -> Result<(), JobError> {
+    if !config.scanning.enabled {
+        return Err(JobError::Permanent(
+            "Malware scanning is disabled".to_string(),
+        ));
+    }
+    let scanner = scanner_from_config(&config.scanning).map_err(WebauthnError::from)?;
+
+    let verdict = match service.content(blob).await? {
+        MediaContent::File(path) => scanner.scan_file(&path).await,
+        MediaContent::Stored(content) => {
+            let bytes = content.read().await.map_err(WebauthnError::from)?;
+            scanner.scan_bytes(&bytes).await
+        }
+    }
+    .map_err(WebauthnError::from)?;
+
+    let updated = service.record_scan(blob, scanner.name(), &verdict).await?;
+    if verdict == ScanVerdict::Clean {
+        info!(
+            "Media blob {} scanned clean by {}, released {} blobs",
+            blob.id,
+            scanner.name(),
+            updated
+        );
+    }
+    Ok(())
+}
+
+/// Record an image's dimensions and orientation and regenerate its thumbnails
+async fn derive_image(
+    service: &MediaService<'_>,
+    blob: &MediaBlob,
+    config: &MediaConfig,
+) -> Result<(), JobError> {
+    let bytes = load_source(service, blob, config.processing.max_source_size).await?;
+    let sizes = config.processing.thumbnail_sizes.clone();
+
+    let analysis = tokio::task::spawn_blocking(move || analyze_image(&bytes, &sizes))
+        .await
+        .map_err(|e| JobError::Permanent(format!("Image processing panicked: {}", e)))?
+        .map_err(|e| JobError::Permanent(format!("Failed to decode image: {}", e)))?;
+
+    service.delete_derivatives(blob.id).await?;
+
+    let mut thumbnails = Vec::with_capacity(analysis.thumbnails.len());
+    for thumbnail in analysis.thumbnails {
+        let derived = service
+            .create_derivative(
+                blob,
+                &format!("thumbnail_{}", thumbnail.size),
+                thumbnail.bytes,
+                thumbnail.mime,
+                json!({
+                    "thumbnail": {
+                        "size": thumbnail.size,
+                        "width": thumbnail.width,
+                        "height": thumbnail.height,
+                    }
+                }),
+                config,
+            )
+            .await?;
+
+        thumbnails.push(ThumbnailRef {
+            size: thumbnail.size,
+            blob_id: derived.id,
+            width: thumbnail.width,
+            height: thumbnail.height,
+            mime: thumbnail.mime.to_string(),
+        });
+    }
+
+    let image = analysis.info;
+    service
+        .merge_metadata(blob.id, json!({ "image": image, "thumbnails": thumbnails }))
+        .await?;
+
+    info!(
+        "Processed image {} ({}x{}, {} thumbnails)",
+        blob.id,
+        image.width,
+        image.height,
+        thumbnails.len()
+    );
+    Ok(())
+}
+
+/// Record the container, duration, bitrate and tracks of audio or video
+///
+/// Local files are parsed in place, reading only the headers; content from
+/// other backends is loaded into memory and subject to `max_source_size`.
+async fn extract_av(
+    service: &MediaService<'_>,
+    blob: &MediaBlob,
+    config: &MediaConfig,
+) -> Result<(), JobError> {
+    let result = match service.content(blob).await? {
+        MediaContent::File(path) => {
+            tokio::task::spawn_blocking(move || {
+                let mut file = std::fs::File::open(path)?;
+                extract_av_metadata(&mut file)
+            })
+            .await
+        }
+        MediaContent::Stored(_) => {
+            let bytes = load_source(service, blob, config.processing.max_source_size).await?;
+            tokio::task::spawn_blocking(move || {
+                extract_av_metadata(&mut std::io::Cursor::new(bytes))
+            })
+            .await
+        }
+    }
+    .map_err(|e| JobError::Permanent(format!("Metadata extraction panicked: {}", e)))?;
+
+    let av = match result {
+        Ok(av) => av,
+        Err(AvError::Io(e)) => {
+            return Err(JobError::Transient(BlobStorageError::from(e).into()));
+        }
+        Err(e) => {
+            return Err(JobError::Permanent(format!(
+                "Failed to read media metadata: {}",
+                e
+            )))
+        }
+    };
+
+    service.merge_metadata(blob.id, json!({ "av": av })).await?;
+
+    info!(
+        "Extracted {} metadata for media blob {} ({} tracks, {:.1}s)",
+        av.container,
+        blob.id,
+        av.tracks.len(),
+        av.duration.unwrap_or_default()
+    );
+    Ok(())
+}
+
+/// Load a blob's content for decoding, refusing content above `max_size`
+async fn load_source(
+    service: &MediaService<'_>,
+    blob: &MediaBlob,
+    max_size: u64,
+) -> Result<Vec<u8>, JobError> {
+    if blob.size.is_some_and(|size| size as u64 > max_size) {
+        return Err(JobError::Permanent(format!(
+            "Content exceeds the processing limit of {} bytes",
+            max_size
+        )));
+    }
+
+    match service.content(blob).await? {
+        MediaContent::File(path) => Ok(tokio::fs::read(&path)
+            .await
+            .map_err(BlobStorageError::from)
+            .map_err(WebauthnError::from)?),
+        MediaContent::Stored(content) => Ok(content.read().await.map_err(WebauthnError::from)?),
+    }
+}
+
+/// Delay before retrying a job that failed its `attempts`th attempt
+fn retry_delay(attempts: i32) -> time::Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 16) as u32;
+    time::Duration::seconds((RETRY_BASE_SECONDS << exponent).min(RETRY_MAX_SECONDS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay_backs_off() {
+        assert_eq!(retry_delay(1), time::Duration::seconds(30));
+        assert_eq!(retry_delay(2), time::Duration::seconds(60));
+        assert_eq!(retry_delay(3), time::Duration::seconds(120));
+        assert_eq!(retry_delay(100), time::Duration::seconds(RETRY_MAX_SECONDS));
+    }
+}
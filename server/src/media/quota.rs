@@ -0,0 +1,154 @@
+//! Per-user storage quotas
+//!
+//! The size and number of the blobs each user has uploaded are kept in
+//! `media_usage` by a database trigger, so quotas are checked without summing
+//! blobs. A user's quota is the one set for them, or else the quota of their
+//! role. Derived blobs such as thumbnails are created by the server and do
+//! not count.
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::auth::UserRole;
+use crate::config::MediaQuotaConfig;
+
+/// An upload was refused because it would exceed the owner's quota
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error(
+    "Storage quota exceeded: {requested} more bytes would bring usage of {used} bytes over the quota of {quota} bytes"
+)]
+pub struct QuotaExceeded {
+    pub used: i64,
+    pub requested: i64,
+    pub quota: i64,
+}
+
+/// A user's storage usage and the quota that applies to them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaUsage {
+    pub user_id: Uuid,
+    pub username: String,
+    pub role: UserRole,
+    /// Total size of the user's uploaded blobs
+    pub bytes: i64,
+    /// Number of uploaded blobs the user owns
+    pub blob_count: i64,
+    /// Quota set for this user, overriding the role quota
+    pub quota_override: Option<i64>,
+    /// Quota in effect; `None` means unlimited
+    pub quota_bytes: Option<i64>,
+    /// When usage last changed; `None` if the user never uploaded anything
+    pub updated_at: Option<OffsetDateTime>,
+}
+
+impl MediaUsage {
+    /// Resolve the quota in effect from the override or the role quota
+    pub fn with_quota(mut self, config: &MediaQuotaConfig) -> Self {
+        self.quota_bytes = self.quota_override.or_else(|| {
+            config
+                .for_role(self.role)
+                .map(|bytes| i64::try_from(bytes).unwrap_or(i64::MAX))
+        });
+        self
+    }
+
+    /// Bytes left before the quota is reached
+    pub fn remaining(&self) -> Option<i64> {
+        self.quota_bytes.map(|quota| (quota - self.bytes).max(0))
+    }
+
+    /// Refuse `additional` bytes that would take usage over the quota
+    pub fn check(&self, additional: i64) -> Result<(), QuotaExceeded> {
+        match self.quota_bytes {
+            Some(quota) if self.bytes.saturating_add(additional) > quota => Err(QuotaExceeded {
+                used: self.bytes,
+                requested: additional,
+                quota,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Like [`check`](Self::check), for a blob of `size` bytes that is
+    /// already counted in the usage
+    pub fn check_counted(&self, size: i64) -> Result<(), QuotaExceeded> {
+        Self {
+            bytes: self.bytes - size,
+            ..self.clone()
+        }
+        .check(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(role: UserRole, bytes: i64, quota_override: Option<i64>) -> MediaUsage {
+        MediaUsage {
+            user_id: Uuid::new_v4(),
+            username: "someone".to_string(),
+            role,
+            bytes,
+            blob_count: 1,
+            quota_override,
+            quota_bytes: None,
+            updated_at: None,
+        }
+    }
+
+    #[test]
+    fn test_quota_resolution() {
+        let config = MediaQuotaConfig {
+            member_bytes: Some(1000),
+            admin_bytes: None,
+        };
+
+        let member = usage(UserRole::Member, 400, None).with_quota(&config);
+        assert_eq!(member.quota_bytes, Some(1000));
+        assert_eq!(member.remaining(), Some(600));
+
+        let admin = usage(UserRole::Admin, 400, None).with_quota(&config);
+        assert_eq!(admin.quota_bytes, None);
+        assert_eq!(admin.remaining(), None);
+
+        let overridden = usage(UserRole::Member, 400, Some(5000)).with_quota(&config);
+        assert_eq!(overridden.quota_bytes, Some(5000));
+        let overridden = usage(UserRole::Admin, 400, Some(100)).with_quota(&config);
+        assert_eq!(overridden.remaining(), Some(0));
+    }
+
+    #[test]
+    fn test_quota_check() {
+        let config = MediaQuotaConfig {
+            member_bytes: Some(1000),
+            admin_bytes: None,
+        };
+        let member = usage(UserRole::Member, 400, None).with_quota(&config);
+
+        assert!(member.check(600).is_ok());
+        assert_eq!(
+            member.check(601),
+            Err(QuotaExceeded {
+                used: 400,
+                requested: 601,
+                quota: 1000
+            })
+        );
+        assert!(member.check_counted(400).is_ok());
+        assert_eq!(
+            usage(UserRole::Member, 1200, None)
+                .with_quota(&config)
+                .check_counted(300),
+            Err(QuotaExceeded {
+                used: 900,
+                requested: 300,
+                quota: 1000
+            })
+        );
+
+        let admin = usage(UserRole::Admin, 400, None).with_quota(&config);
+        assert!(admin.check(i64::MAX).is_ok());
+    }
+}
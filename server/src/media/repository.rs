@@ -3,14 +3,23 @@
 //! This module provides database access layer for media blobs,
 //! including CRUD operations and queries.
 
-use crate::config::MediaConfig;
+use crate::auth::UserRole;
+use crate::config::MediaStorageBackend;
 use crate::database::DatabaseConnection;
 use crate::error::WebauthnError;
-use crate::media::models::{CreateMediaBlob, MediaBlob, MediaBlobQuery, MediaBlobStats};
-use sqlx::postgres::PgRow;
-use sqlx::Row;
+use crate::media::collections::MediaCollectionRepository;
+use crate::media::jobs::MediaJobRepository;
+use crate::media::models::{
+    MediaAuditAction, MediaAuditEntry, MediaAuditReason, MediaBlob, MediaBlobLocation,
+    MediaBlobQuery, MediaBlobShare, MediaBlobStats, MediaClientStats, MediaGrowthStats,
+    MediaObject, MediaOwnerStats, MediaStatsQuery, MediaTagCount, MediaVisibility, MimeTypeCount,
+    ScanStatus, StorageBackendStats, TrashedMediaBlob,
+};
+use crate::media::quota::MediaUsage;
+use crate::media::search::{PreparedSearch, SearchCursor};
+use sqlx::{PgConnection, PgExecutor, Postgres, Transaction};
 use time::OffsetDateTime;
-use tracing::{debug, error, info};
+use tracing::{debug, info};
 use uuid::Uuid;
 
 /// Media blob repository for database operations
@@ -30,6 +39,10 @@ pub enum MediaError {
     Validation(String),
     #[error("Duplicate SHA256 hash")]
     Duplicate,
+    #[error("A collection with this name already exists")]
+    DuplicateCollection,
+    #[error("Access to media blob denied")]
+    Forbidden,
 }
 
 impl From<MediaError> for WebauthnError {
@@ -40,6 +53,8 @@ impl From<MediaError> for WebauthnError {
             MediaError::InvalidHash => WebauthnError::BadRequest,
             MediaError::Validation(_) => WebauthnError::BadRequest,
             MediaError::Duplicate => WebauthnError::BadRequest,
+            MediaError::DuplicateCollection => WebauthnError::BadRequest,
+            MediaError::Forbidden => WebauthnError::Forbidden,
         }
     }
 }
@@ -50,360 +65,1545 @@ impl<'a> MediaRepository<'a> {
         Self { db }
     }
 
-    /// Create a new media blob
-    pub async fn create(
+    /// Job queue sharing this repository's connection
+    pub fn jobs(&self) -> MediaJobRepository<'a> {
+        MediaJobRepository::new(self.db)
+    }
+
+    /// Collections sharing this repository's connection
+    pub fn collections(&self) -> MediaCollectionRepository<'a> {
+        MediaCollectionRepository::new(self.db)
+    }
+
+    /// Start a transaction for multi-step media operations
+    pub async fn begin(&self) -> Result<Transaction<'static, Postgres>, WebauthnError> {
+        Ok(self.db.begin().await?)
+    }
+
+    /// Lock a stored object against garbage collection for the rest of the transaction
+    pub async fn lock_object_in(
         &self,
-        params: CreateMediaBlob,
-        media_config: &MediaConfig,
-    ) -> Result<MediaBlob, WebauthnError> {
-        // Validate input with config-based limits
-        params
-            .validate(
-                media_config.max_blob_file_size,
-                media_config.max_fs_file_size,
+        conn: &mut PgConnection,
+        sha256: &str,
+    ) -> Result<Option<MediaObject>, WebauthnError> {
+        let object = sqlx::query_as!(
+            MediaObject,
+            r#"
+            SELECT sha256, size, storage_backend as "storage_backend: MediaStorageBackend",
+                   storage_key, ref_count, content_type, created_at
+            FROM media_objects
+            WHERE sha256 = $1
+            FOR KEY SHARE
+            "#,
+            sha256
+        )
+        .fetch_optional(conn)
+        .await?;
+
+        Ok(object)
+    }
+
+    /// Lock a stored object exclusively, e.g. while moving its content
+    pub async fn lock_object_for_update_in(
+        &self,
+        conn: &mut PgConnection,
+        sha256: &str,
+    ) -> Result<Option<MediaObject>, WebauthnError> {
+        let object = sqlx::query_as!(
+            MediaObject,
+            r#"
+            SELECT sha256, size, storage_backend as "storage_backend: MediaStorageBackend",
+                   storage_key, ref_count, content_type, created_at
+            FROM media_objects
+            WHERE sha256 = $1
+            FOR UPDATE
+            "#,
+            sha256
+        )
+        .fetch_optional(conn)
+        .await?;
+
+        Ok(object)
+    }
+
+    /// Insert a new object, returning false if one with the same hash already exists
+    ///
+    /// `content_type` is the type detected from the content's leading bytes.
+    pub async fn insert_object_in<'e, E>(
+        &self,
+        executor: E,
+        sha256: &str,
+        size: i64,
+        storage_backend: MediaStorageBackend,
+        storage_key: &str,
+        content_type: &str,
+    ) -> Result<bool, WebauthnError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let inserted = sqlx::query_scalar!(
+            r#"
+            INSERT INTO media_objects (sha256, size, storage_backend, storage_key, content_type)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (sha256) DO NOTHING
+            RETURNING sha256
+            "#,
+            sha256,
+            size,
+            storage_backend as MediaStorageBackend,
+            storage_key,
+            content_type
+        )
+        .fetch_optional(executor)
+        .await?;
+
+        Ok(inserted.is_some())
+    }
+
+    /// Record that an object's content now lives in another backend
+    pub async fn update_object_location_in<'e, E>(
+        &self,
+        executor: E,
+        sha256: &str,
+        storage_backend: MediaStorageBackend,
+        storage_key: &str,
+    ) -> Result<(), WebauthnError>
+    where
+        E: PgExecutor<'e>,
+    {
+        sqlx::query!(
+            "UPDATE media_objects SET storage_backend = $2, storage_key = $3 WHERE sha256 = $1",
+            sha256,
+            storage_backend as MediaStorageBackend,
+            storage_key
+        )
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+
+    /// List objects stored outside `target`, in hash order starting after `after`
+    pub async fn list_objects_not_in(
+        &self,
+        target: MediaStorageBackend,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<MediaObject>, WebauthnError> {
+        let objects = sqlx::query_as!(
+            MediaObject,
+            r#"
+            SELECT sha256, size, storage_backend as "storage_backend: MediaStorageBackend",
+                   storage_key, ref_count, content_type, created_at
+            FROM media_objects
+            WHERE storage_backend <> $1 AND ($2::text IS NULL OR sha256 > $2)
+            ORDER BY sha256
+            LIMIT $3
+            "#,
+            target as MediaStorageBackend,
+            after,
+            limit
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(objects)
+    }
+
+    /// List all objects in hash order, starting after `after`
+    pub async fn list_objects(
+        &self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<MediaObject>, WebauthnError> {
+        let objects = sqlx::query_as!(
+            MediaObject,
+            r#"
+            SELECT sha256, size, storage_backend as "storage_backend: MediaStorageBackend",
+                   storage_key, ref_count, content_type, created_at
+            FROM media_objects
+            WHERE $1::text IS NULL OR sha256 > $1
+            ORDER BY sha256
+            LIMIT $2
+            "#,
+            after,
+            limit
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(objects)
+    }
+
+    /// List blobs with a local path and where their content is stored, in ID
+    /// order starting after `after`
+    pub async fn list_blob_locations(
+        &self,
+        after: Option<Uuid>,
+        limit: i64,
+    ) -> Result<Vec<MediaBlobLocation>, WebauthnError> {
+        let locations = sqlx::query_as!(
+            MediaBlobLocation,
+            r#"
+            SELECT b.id, b.sha256, b.local_path as "local_path!",
+                   o.storage_backend as "storage_backend: MediaStorageBackend", o.storage_key
+            FROM media_blobs b
+            JOIN media_objects o ON o.sha256 = b.sha256
+            WHERE b.local_path IS NOT NULL AND ($1::uuid IS NULL OR b.id > $1)
+            ORDER BY b.id
+            LIMIT $2
+            "#,
+            after,
+            limit
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(locations)
+    }
+
+    /// Point a blob's local path elsewhere, or clear it
+    pub async fn set_local_path(
+        &self,
+        id: Uuid,
+        local_path: Option<&str>,
+    ) -> Result<(), WebauthnError> {
+        sqlx::query!(
+            "UPDATE media_blobs SET local_path = $2, updated_at = now() WHERE id = $1",
+            id,
+            local_path
+        )
+        .execute(self.db.pool())
+        .await?;
+
+        Ok(())
+    }
+
+    /// IDs of the blobs referencing the content with `sha256`
+    pub async fn blob_ids_for_sha256(&self, sha256: &str) -> Result<Vec<Uuid>, WebauthnError> {
+        let ids = sqlx::query_scalar!("SELECT id FROM media_blobs WHERE sha256 = $1", sha256)
+            .fetch_all(self.db.pool())
+            .await?;
+
+        Ok(ids)
+    }
+
+    /// Insert a logical media record referencing an existing object
+    pub async fn insert_blob_in<'e, E>(
+        &self,
+        executor: E,
+        blob: &MediaBlob,
+    ) -> Result<(), WebauthnError>
+    where
+        E: PgExecutor<'e>,
+    {
+        sqlx::query!(
+            r#"
+            INSERT INTO media_blobs (
+                id, sha256, size, mime, source_client_id, owner_user_id,
+                visibility, local_path, metadata, scan_status, created_at, updated_at
+            ) VALUES (
+                $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12
             )
-            .map_err(|e| {
-                warn!("zomg>>>> bad media_blob {}", e);
+            "#,
+            blob.id,
+            blob.sha256,
+            blob.size,
+            blob.mime,
+            blob.source_client_id,
+            blob.owner_user_id,
+            blob.visibility as MediaVisibility,
+            blob.local_path,
+            blob.metadata,
+            blob.scan_status as Option<ScanStatus>,
+            blob.created_at,
+            blob.updated_at
+        )
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Scan outcome already known for content, from other blobs sharing it
+    ///
+    /// An infected verdict wins over a clean one; content that has not been
+    /// scanned to the end yet has no known outcome.
+    pub async fn known_scan_status_in<'e, E>(
+        &self,
+        executor: E,
+        sha256: &str,
+    ) -> Result<Option<ScanStatus>, WebauthnError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let status = sqlx::query_scalar!(
+            r#"
+            SELECT scan_status as "scan_status!: ScanStatus"
+            FROM media_blobs
+            WHERE sha256 = $1 AND scan_status IN ('clean', 'infected')
+            ORDER BY scan_status = 'infected' DESC
+            LIMIT 1
+            "#,
+            sha256
+        )
+        .fetch_optional(executor)
+        .await?;
+
+        Ok(status)
+    }
+
+    /// Record a scan outcome on every uploaded blob with this content,
+    /// merging `report` into their metadata
+    pub async fn set_scan_status(
+        &self,
+        sha256: &str,
+        status: ScanStatus,
+        report: serde_json::Value,
+    ) -> Result<u64, WebauthnError> {
+        info!("Marking media content {} as {}", sha256, status);
+
+        let result = sqlx::query!(
+            r#"
+            UPDATE media_blobs
+            SET scan_status = $2, metadata = metadata || $3, updated_at = now()
+            WHERE sha256 = $1 AND parent_blob_id IS NULL
+            "#,
+            sha256,
+            status as ScanStatus,
+            report
+        )
+        .execute(self.db.pool())
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Why content is withheld from downloads, if any blob with it is
+    pub async fn quarantine_status(
+        &self,
+        sha256: &str,
+    ) -> Result<Option<ScanStatus>, WebauthnError> {
+        let status = sqlx::query_scalar!(
+            r#"
+            SELECT scan_status as "scan_status!: ScanStatus"
+            FROM media_blobs
+            WHERE sha256 = $1 AND scan_status IN ('pending', 'infected')
+            ORDER BY scan_status = 'infected' DESC
+            LIMIT 1
+            "#,
+            sha256
+        )
+        .fetch_optional(self.db.pool())
+        .await?;
+
+        Ok(status)
+    }
+
+    /// Replace a blob's tags, which must already be normalised
+    pub async fn set_tags(&self, blob_id: Uuid, tags: &[String]) -> Result<(), WebauthnError> {
+        debug!("Setting tags of media blob {}: {:?}", blob_id, tags);
+
+        if tags.is_empty() {
+            sqlx::query!("DELETE FROM media_blob_tags WHERE blob_id = $1", blob_id)
+                .execute(self.db.pool())
+                .await?;
+            return Ok(());
+        }
+
+        sqlx::query!(
+            r#"
+            INSERT INTO media_blob_tags (blob_id, tags)
+            VALUES ($1, $2)
+            ON CONFLICT (blob_id) DO UPDATE
+            SET tags = EXCLUDED.tags, updated_at = now()
+            "#,
+            blob_id,
+            tags
+        )
+        .execute(self.db.pool())
+        .await?;
+
+        Ok(())
+    }
+
+    /// Tags on a user's blobs starting with `prefix`, most used first
+    pub async fn list_tags(
+        &self,
+        owner_user_id: Uuid,
+        prefix: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<MediaTagCount>, WebauthnError> {
+        let pattern = prefix.map(|prefix| format!("{}%", escape_like(prefix)));
+
+        let tags = sqlx::query_as!(
+            MediaTagCount,
+            r#"
+            SELECT tag as "tag!", COUNT(*) as "count!"
+            FROM media_blob_tags t
+            JOIN media_blobs b ON b.id = t.blob_id
+            CROSS JOIN LATERAL unnest(t.tags) AS tag
+            WHERE b.owner_user_id = $1 AND b.deleted_at IS NULL
+              AND ($2::text IS NULL OR tag LIKE $2)
+            GROUP BY tag
+            ORDER BY COUNT(*) DESC, tag
+            LIMIT $3
+            "#,
+            owner_user_id,
+            pattern,
+            limit
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(tags)
+    }
+
+    /// A user's storage usage, or `None` if the user does not exist
+    pub async fn usage(&self, user_id: Uuid) -> Result<Option<MediaUsage>, WebauthnError> {
+        self.usage_in(self.db.pool(), user_id).await
+    }
+
+    /// A user's storage usage, or `None` if the user does not exist
+    ///
+    /// `quota_bytes` is left unset; see [`MediaUsage::with_quota`].
+    pub async fn usage_in<'e, E>(
+        &self,
+        executor: E,
+        user_id: Uuid,
+    ) -> Result<Option<MediaUsage>, WebauthnError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let row = sqlx::query!(
+            r#"
+            SELECT u.id, u.username, u.role,
+                   COALESCE(m.bytes, 0) as "bytes!", COALESCE(m.blob_count, 0) as "blob_count!",
+                   m.quota_bytes as "quota_bytes?", m.updated_at as "updated_at?"
+            FROM users u
+            LEFT JOIN media_usage m ON m.user_id = u.id
+            WHERE u.id = $1
+            "#,
+            user_id
+        )
+        .fetch_optional(executor)
+        .await?;
+
+        Ok(row.map(|r| MediaUsage {
+            user_id: r.id,
+            username: r.username,
+            role: parse_role(&r.role),
+            bytes: r.bytes,
+            blob_count: r.blob_count,
+            quota_override: r.quota_bytes,
+            quota_bytes: None,
+            updated_at: r.updated_at,
+        }))
+    }
+
+    /// Storage usage of every user, largest first
+    pub async fn list_usage(&self) -> Result<Vec<MediaUsage>, WebauthnError> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT u.id, u.username, u.role,
+                   COALESCE(m.bytes, 0) as "bytes!", COALESCE(m.blob_count, 0) as "blob_count!",
+                   m.quota_bytes as "quota_bytes?", m.updated_at as "updated_at?"
+            FROM users u
+            LEFT JOIN media_usage m ON m.user_id = u.id
+            ORDER BY COALESCE(m.bytes, 0) DESC, u.username
+            "#
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|r| MediaUsage {
+                user_id: r.id,
+                username: r.username,
+                role: parse_role(&r.role),
+                bytes: r.bytes,
+                blob_count: r.blob_count,
+                quota_override: r.quota_bytes,
+                quota_bytes: None,
+                updated_at: r.updated_at,
+            })
+            .collect())
+    }
+
+    /// Set or clear the quota overriding a user's role quota
+    pub async fn set_quota_override(
+        &self,
+        user_id: Uuid,
+        quota_bytes: Option<i64>,
+    ) -> Result<(), WebauthnError> {
+        info!(
+            "Setting media quota of user {} to {:?}",
+            user_id, quota_bytes
+        );
+
+        sqlx::query!(
+            r#"
+            INSERT INTO media_usage (user_id, quota_bytes)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE
+            SET quota_bytes = EXCLUDED.quota_bytes, updated_at = now()
+            "#,
+            user_id,
+            quota_bytes
+        )
+        .execute(self.db.pool())
+        .await?;
+
+        Ok(())
+    }
+
+    /// Mark a blob as derived from another, e.g. as its thumbnail
+    pub async fn set_derivative_in<'e, E>(
+        &self,
+        executor: E,
+        id: Uuid,
+        parent_id: Uuid,
+        derivative: &str,
+    ) -> Result<(), WebauthnError>
+    where
+        E: PgExecutor<'e>,
+    {
+        sqlx::query!(
+            "UPDATE media_blobs SET parent_blob_id = $2, derivative = $3 WHERE id = $1",
+            id,
+            parent_id,
+            derivative
+        )
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Hashes of the content of blobs derived from `parent_id`
+    pub async fn derivative_sha256s(&self, parent_id: Uuid) -> Result<Vec<String>, WebauthnError> {
+        let sha256s = sqlx::query_scalar!(
+            "SELECT sha256 FROM media_blobs WHERE parent_blob_id = $1",
+            parent_id
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(sha256s)
+    }
+
+    /// Delete the blobs derived from `parent_id`, returning their content hashes
+    pub async fn delete_derivatives(&self, parent_id: Uuid) -> Result<Vec<String>, WebauthnError> {
+        let sha256s = sqlx::query_scalar!(
+            "DELETE FROM media_blobs WHERE parent_blob_id = $1 RETURNING sha256",
+            parent_id
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(sha256s)
+    }
+
+    /// Find a stored object by SHA256 hash
+    pub async fn find_object(&self, sha256: &str) -> Result<Option<MediaObject>, WebauthnError> {
+        debug!("Finding media object by SHA256: {}", sha256);
+
+        let object = sqlx::query_as!(
+            MediaObject,
+            r#"
+            SELECT sha256, size, storage_backend as "storage_backend: MediaStorageBackend",
+                   storage_key, ref_count, content_type, created_at
+            FROM media_objects
+            WHERE sha256 = $1
+            "#,
+            sha256
+        )
+        .fetch_optional(self.db.pool())
+        .await?;
+
+        Ok(object)
+    }
+
+    /// Find a media blob by ID
+    ///
+    /// Content lives in a storage backend, so `data` is always `None` here;
+    /// `MediaService::get_blob` loads it when asked.
+    pub async fn find_by_id(&self, id: Uuid) -> Result<MediaBlob, WebauthnError> {
+        debug!("Finding media blob by ID: {}", id);
+
+        sqlx::query_as!(
+            MediaBlob,
+            r#"
+            SELECT id, NULL::bytea as "data?", sha256, size, mime, source_client_id,
+                   owner_user_id, visibility as "visibility: MediaVisibility",
+                   local_path, metadata, scan_status as "scan_status: ScanStatus",
+                   COALESCE((SELECT t.tags FROM media_blob_tags t WHERE t.blob_id = media_blobs.id),
+                            '{}') as "tags!",
+                   created_at, updated_at
+            FROM media_blobs
+            WHERE id = $1 AND deleted_at IS NULL
+            "#,
+            id
+        )
+        .fetch_optional(self.db.pool())
+        .await?
+        .ok_or(WebauthnError::UserNotFound)
+    }
+
+    /// Find a media blob by SHA256 hash
+    pub async fn get_by_sha256(&self, sha256: &str) -> Result<MediaBlob, WebauthnError> {
+        debug!("Finding media blob by SHA256: {}", sha256);
+
+        sqlx::query_as!(
+            MediaBlob,
+            r#"
+            SELECT id, NULL::bytea as "data?", sha256, size, mime, source_client_id,
+                   owner_user_id, visibility as "visibility: MediaVisibility",
+                   local_path, metadata, scan_status as "scan_status: ScanStatus",
+                   COALESCE((SELECT t.tags FROM media_blob_tags t WHERE t.blob_id = media_blobs.id),
+                            '{}') as "tags!",
+                   created_at, updated_at
+            FROM media_blobs
+            WHERE sha256 = $1 AND deleted_at IS NULL
+            LIMIT 1
+            "#,
+            sha256
+        )
+        .fetch_optional(self.db.pool())
+        .await?
+        .ok_or(WebauthnError::UserNotFound)
+    }
+
+    /// Get a media blob by ID without data field for efficiency
+    pub async fn get_by_id_without_data(&self, id: Uuid) -> Result<MediaBlob, WebauthnError> {
+        debug!("Finding media blob by ID without data: {}", id);
+
+        sqlx::query_as!(
+            MediaBlob,
+            r#"
+            SELECT id, NULL::bytea as "data?", sha256, size, mime, source_client_id,
+                   owner_user_id, visibility as "visibility: MediaVisibility",
+                   local_path, metadata, scan_status as "scan_status: ScanStatus",
+                   COALESCE((SELECT t.tags FROM media_blob_tags t WHERE t.blob_id = media_blobs.id),
+                            '{}') as "tags!",
+                   created_at, updated_at
+            FROM media_blobs
+            WHERE id = $1 AND deleted_at IS NULL
+            "#,
+            id
+        )
+        .fetch_optional(self.db.pool())
+        .await?
+        .ok_or(WebauthnError::UserNotFound)
+    }
+
+    /// Query media blobs with filtering and pagination
+    pub async fn query(&self, params: MediaBlobQuery) -> Result<Vec<MediaBlob>, WebauthnError> {
+        debug!("Querying media blobs with params: {:?}", params);
+
+        let limit = params.limit.unwrap_or(50).min(1000); // Cap at 1000
+        let offset = params.offset.unwrap_or(0);
+
+        // Build the query manually since the filters are optional; derived
+        // blobs such as thumbnails are only reached through their parent
+        let mut sql = String::from(
+            "SELECT id, NULL::bytea AS data, sha256, size, mime, source_client_id, owner_user_id, \
+             visibility, local_path, metadata, scan_status, \
+             COALESCE((SELECT t.tags FROM media_blob_tags t WHERE t.blob_id = media_blobs.id), \
+             '{}') AS tags, created_at, updated_at \
+             FROM media_blobs",
+        );
+        let mut param_count = 0;
+
+        if params.collection_id.is_some() {
+            param_count += 1;
+            sql.push_str(&format!(
+                " JOIN media_collection_items ci ON ci.blob_id = media_blobs.id \
+                 AND ci.collection_id = ${}",
+                param_count
+            ));
+        }
+        sql.push_str(" WHERE parent_blob_id IS NULL AND deleted_at IS NULL");
+
+        if let Some(ref _sha256) = params.sha256 {
+            param_count += 1;
+            sql.push_str(&format!(" AND sha256 = ${}", param_count));
+        }
+
+        if let Some(ref _client_id) = params.source_client_id {
+            param_count += 1;
+            sql.push_str(&format!(" AND source_client_id = ${}", param_count));
+        }
+
+        if let Some(ref _mime_pattern) = params.mime_pattern {
+            param_count += 1;
+            sql.push_str(&format!(" AND mime LIKE ${}", param_count));
+        }
+
+        if let Some(ref _scan_status) = params.scan_status {
+            param_count += 1;
+            sql.push_str(&format!(" AND scan_status = ${}", param_count));
+        }
+
+        if let Some(ref _owner_user_id) = params.owner_user_id {
+            param_count += 1;
+            sql.push_str(&format!(" AND owner_user_id = ${}", param_count));
+        }
+
+        if !params.tags.is_empty() {
+            param_count += 1;
+            sql.push_str(&format!(
+                " AND EXISTS (SELECT 1 FROM media_blob_tags t \
+                 WHERE t.blob_id = media_blobs.id AND t.tags @> ${})",
+                param_count
+            ));
+        }
+
+        if params.visible_to.is_some() {
+            param_count += 1;
+            sql.push_str(&visible_to_filter(param_count));
+        }
+
+        let order = if params.collection_id.is_some() {
+            "ci.position, ci.added_at"
+        } else {
+            "created_at DESC"
+        };
+        param_count += 1;
+        sql.push_str(&format!(" ORDER BY {} LIMIT ${}", order, param_count));
+        param_count += 1;
+        sql.push_str(&format!(" OFFSET ${}", param_count));
+
+        // Execute the query manually
+        let mut query = sqlx::query_as::<_, MediaBlob>(&sql);
+
+        if let Some(collection_id) = params.collection_id {
+            query = query.bind(collection_id);
+        }
+        if let Some(ref sha256) = params.sha256 {
+            query = query.bind(sha256);
+        }
+        if let Some(ref client_id) = params.source_client_id {
+            query = query.bind(client_id);
+        }
+        if let Some(ref mime_pattern) = params.mime_pattern {
+            query = query.bind(format!("%{}%", mime_pattern));
+        }
+        if let Some(scan_status) = params.scan_status {
+            query = query.bind(scan_status);
+        }
+        if let Some(owner_user_id) = params.owner_user_id {
+            query = query.bind(owner_user_id);
+        }
+        if !params.tags.is_empty() {
+            query = query.bind(&params.tags);
+        }
+        if let Some(user_id) = params.visible_to {
+            query = query.bind(user_id);
+        }
+        query = query.bind(limit).bind(offset);
+
+        let blobs = query.fetch_all(self.db.pool()).await?;
+
+        Ok(blobs)
+    }
+
+    /// Search blobs, returning one page and the position after its last blob
+    ///
+    /// Text matches come best first; otherwise, and among equal matches,
+    /// blobs come newest first. The returned cursor is `None` on the last
+    /// page.
+    pub async fn search(
+        &self,
+        search: &PreparedSearch,
+    ) -> Result<(Vec<MediaBlob>, Option<SearchCursor>), WebauthnError> {
+        debug!("Searching media blobs: {:?}", search);
+
+        let mut param_count = 0;
+        let rank = match search.text {
+            Some(_) => {
+                param_count += 1;
+                format!(
+                    "ts_rank(search_document, websearch_to_tsquery('simple', ${}))",
+                    param_count
+                )
+            }
+            None => "0".to_string(),
+        };
+        let mut sql = format!(
+            "SELECT * FROM (SELECT id, NULL::bytea AS data, sha256, size, mime, source_client_id, \
+             owner_user_id, visibility, local_path, metadata, scan_status, \
+             COALESCE((SELECT t.tags FROM media_blob_tags t WHERE t.blob_id = media_blobs.id), \
+             '{{}}') AS tags, created_at, updated_at, ({})::real AS rank \
+             FROM media_blobs WHERE parent_blob_id IS NULL AND deleted_at IS NULL",
+            rank
+        );
+
+        if search.text.is_some() {
+            sql.push_str(" AND search_document @@ websearch_to_tsquery('simple', $1)");
+        }
+        if !search.tags.is_empty() {
+            param_count += 1;
+            sql.push_str(&format!(
+                " AND EXISTS (SELECT 1 FROM media_blob_tags t \
+                 WHERE t.blob_id = media_blobs.id AND t.tags @> ${})",
+                param_count
+            ));
+        }
+        let mime = search
+            .mime
+            .as_deref()
+            .map(|mime| match mime.strip_suffix('/') {
+                Some(prefix) => (format!("{}/%", escape_like(prefix)), "LIKE"),
+                None => (mime.to_string(), "="),
+            });
+        if let Some((_, op)) = mime {
+            param_count += 1;
+            sql.push_str(&format!(" AND lower(mime) {} ${}", op, param_count));
+        }
+        if search.min_size.is_some() {
+            param_count += 1;
+            sql.push_str(&format!(" AND size >= ${}", param_count));
+        }
+        if search.max_size.is_some() {
+            param_count += 1;
+            sql.push_str(&format!(" AND size <= ${}", param_count));
+        }
+        if search.created_after.is_some() {
+            param_count += 1;
+            sql.push_str(&format!(" AND created_at >= ${}", param_count));
+        }
+        if search.created_before.is_some() {
+            param_count += 1;
+            sql.push_str(&format!(" AND created_at < ${}", param_count));
+        }
+        for _ in &search.metadata_paths {
+            param_count += 1;
+            sql.push_str(&format!(" AND metadata @? ${}::jsonpath", param_count));
+        }
+        if search.visible_to.is_some() {
+            param_count += 1;
+            sql.push_str(&visible_to_filter(param_count));
+        }
+        sql.push_str(") found");
+
+        if search.after.is_some() {
+            sql.push_str(&format!(
+                " WHERE (rank, created_at, id) < (${}, ${}, ${})",
+                param_count + 1,
+                param_count + 2,
+                param_count + 3
+            ));
+            param_count += 3;
+        }
+        // One row beyond the page tells whether another page follows
+        param_count += 1;
+        sql.push_str(&format!(
+            " ORDER BY rank DESC, created_at DESC, id DESC LIMIT ${}",
+            param_count
+        ));
+
+        let mut query = sqlx::query_as::<_, SearchRow>(&sql);
+        if let Some(ref text) = search.text {
+            query = query.bind(text);
+        }
+        if !search.tags.is_empty() {
+            query = query.bind(&search.tags);
+        }
+        if let Some((pattern, _)) = mime {
+            query = query.bind(pattern);
+        }
+        if let Some(min_size) = search.min_size {
+            query = query.bind(min_size);
+        }
+        if let Some(max_size) = search.max_size {
+            query = query.bind(max_size);
+        }
+        if let Some(created_after) = search.created_after {
+            query = query.bind(created_after);
+        }
+        if let Some(created_before) = search.created_before {
+            query = query.bind(created_before);
+        }
+        for path in &search.metadata_paths {
+            query = query.bind(path);
+        }
+        if let Some(user_id) = search.visible_to {
+            query = query.bind(user_id);
+        }
+        if let Some(after) = search.after {
+            query = query.bind(after.rank).bind(after.created_at).bind(after.id);
+        }
+        query = query.bind(search.limit + 1);
+
+        let mut rows = query.fetch_all(self.db.pool()).await?;
+        let more = rows.len() as i64 > search.limit;
+        rows.truncate(search.limit as usize);
+        let next = rows.last().filter(|_| more).map(|row| SearchCursor {
+            rank: row.rank,
+            created_at: row.blob.created_at,
+            id: row.blob.id,
+        });
+
+        Ok((rows.into_iter().map(|row| row.blob).collect(), next))
+    }
+
+    /// Get media blob statistics
+    pub async fn get_stats(&self, query: MediaStatsQuery) -> Result<MediaBlobStats, WebauthnError> {
+        debug!("Getting media blob statistics: {:?}", query);
+
+        let totals = sqlx::query!(
+            r#"
+            SELECT
+                COUNT(*) FILTER (WHERE parent_blob_id IS NULL) as "total_count!",
+                SUM(size) FILTER (WHERE parent_blob_id IS NULL)::bigint as total_size,
+                COUNT(DISTINCT sha256) FILTER (WHERE parent_blob_id IS NULL)
+                    as "unique_sha256_count!",
+                COUNT(*) FILTER (WHERE parent_blob_id IS NOT NULL) as "derived_count!",
+                COALESCE(SUM(size) FILTER (WHERE parent_blob_id IS NOT NULL), 0)::bigint
+                    as "derived_size!"
+            FROM media_blobs
+            WHERE deleted_at IS NULL
+            "#
+        )
+        .fetch_one(self.db.pool())
+        .await?;
+
+        let mime_type_distribution = sqlx::query_as!(
+            MimeTypeCount,
+            r#"
+            SELECT mime as mime_type, COUNT(*) as "count!",
+                   COALESCE(SUM(size), 0)::bigint as "size!"
+            FROM media_blobs
+            WHERE parent_blob_id IS NULL AND deleted_at IS NULL
+            GROUP BY mime
+            ORDER BY 2 DESC, 1
+            "#
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        let storage = sqlx::query_as!(
+            StorageBackendStats,
+            r#"
+            SELECT storage_backend as backend, COUNT(*) as "object_count!",
+                   COALESCE(SUM(size), 0)::bigint as "size!"
+            FROM media_objects
+            GROUP BY storage_backend
+            ORDER BY storage_backend
+            "#
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        // Each object is stored once but counted once per referencing blob
+        let objects = sqlx::query!(
+            r#"
+            SELECT
+                COALESCE(SUM(size * (ref_count - 1)) FILTER (WHERE ref_count > 1), 0)::bigint
+                    as "duplicate_savings!",
+                COALESCE(SUM(size) FILTER (WHERE ref_count = 0), 0)::bigint
+                    as "unreferenced_size!"
+            FROM media_objects
+            "#
+        )
+        .fetch_one(self.db.pool())
+        .await?;
+
+        let (top_users, top_clients) = if query.top > 0 {
+            let users = sqlx::query_as!(
+                MediaOwnerStats,
+                r#"
+                SELECT m.user_id, u.username, m.blob_count, m.bytes as size
+                FROM media_usage m
+                JOIN users u ON u.id = m.user_id
+                WHERE m.blob_count > 0
+                ORDER BY m.bytes DESC, u.username
+                LIMIT $1
+                "#,
+                query.top
+            )
+            .fetch_all(self.db.pool())
+            .await?;
+
+            let clients = sqlx::query_as!(
+                MediaClientStats,
+                r#"
+                SELECT source_client_id, COUNT(*) as "blob_count!",
+                       COALESCE(SUM(size), 0)::bigint as "size!"
+                FROM media_blobs
+                WHERE parent_blob_id IS NULL AND deleted_at IS NULL
+                GROUP BY source_client_id
+                ORDER BY 3 DESC, 2 DESC
+                LIMIT $1
+                "#,
+                query.top
+            )
+            .fetch_all(self.db.pool())
+            .await?;
+
+            (users, clients)
+        } else {
+            (Vec::new(), Vec::new())
+        };
+
+        let growth = if query.days > 0 {
+            sqlx::query_as!(
+                MediaGrowthStats,
+                r#"
+                SELECT date_trunc('day', created_at, 'UTC') as "day!",
+                       COUNT(*) as "blob_count!", COALESCE(SUM(size), 0)::bigint as "size!"
+                FROM media_blobs
+                WHERE parent_blob_id IS NULL AND deleted_at IS NULL
+                  AND created_at >= date_trunc('day', now(), 'UTC') - make_interval(days => $1)
+                GROUP BY 1
+                ORDER BY 1
+                "#,
+                (query.days - 1) as i32
+            )
+            .fetch_all(self.db.pool())
+            .await?
+        } else {
+            Vec::new()
+        };
+
+        Ok(MediaBlobStats {
+            total_count: totals.total_count,
+            total_size: totals.total_size,
+            unique_sha256_count: totals.unique_sha256_count,
+            mime_type_distribution,
+            derived_count: totals.derived_count,
+            derived_size: totals.derived_size,
+            storage,
+            duplicate_savings: objects.duplicate_savings,
+            unreferenced_size: objects.unreferenced_size,
+            top_users,
+            top_clients,
+            growth,
+        })
+    }
+
+    /// Update blob metadata
+    pub async fn update_metadata(
+        &self,
+        id: Uuid,
+        _metadata: serde_json::Value,
+    ) -> Result<MediaBlob, WebauthnError> {
+        debug!("Updating metadata for media blob: {}", id);
+
+        // TODO: Uncomment after running sqlx prepare
+        /*
+        let updated_at = OffsetDateTime::now_utc();
+
+        sqlx::query!(
+            r#"
+            UPDATE media_blobs
+            SET metadata = $1, updated_at = $2
+            WHERE id = $3
+            "#,
+            metadata,
+            updated_at,
+            id
+        )
+        .execute(self.db.pool())
+        .await?;
+
+        // Return the updated blob
+        self.find_by_id(id).await
+        */
+
+        // Temporary mock implementation
+        Err(WebauthnError::UserNotFound)
+    }
+
+    /// Merge keys into a blob's metadata, replacing existing top-level keys
+    pub async fn merge_metadata(
+        &self,
+        id: Uuid,
+        metadata: serde_json::Value,
+    ) -> Result<(), WebauthnError> {
+        sqlx::query!(
+            r#"
+            UPDATE media_blobs
+            SET metadata = COALESCE(metadata, '{}'::jsonb) || $2, updated_at = now()
+            WHERE id = $1
+            "#,
+            id,
+            metadata
+        )
+        .execute(self.db.pool())
+        .await?;
+
+        Ok(())
+    }
+
+    /// Change who may access a blob
+    pub async fn set_visibility(
+        &self,
+        id: Uuid,
+        visibility: MediaVisibility,
+    ) -> Result<MediaBlob, WebauthnError> {
+        info!(
+            "Setting visibility of media blob {} to {:?}",
+            id, visibility
+        );
+
+        let result = sqlx::query!(
+            "UPDATE media_blobs SET visibility = $2 WHERE id = $1",
+            id,
+            visibility as MediaVisibility
+        )
+        .execute(self.db.pool())
+        .await?;
 
-                WebauthnError::BadRequest
-            })?;
+        if result.rows_affected() == 0 {
+            return Err(WebauthnError::UserNotFound);
+        }
 
-        info!("Creating media blob with SHA256: {}", params.sha256);
+        self.get_by_id_without_data(id).await
+    }
 
-        let blob = MediaBlob::new(params);
+    /// Grant a user access to a shared blob
+    pub async fn add_share(
+        &self,
+        blob_id: Uuid,
+        user_id: Uuid,
+        granted_by: Uuid,
+    ) -> Result<(), WebauthnError> {
+        info!("Sharing media blob {} with user {}", blob_id, user_id);
 
-        let result = sqlx::query!(
+        sqlx::query!(
             r#"
-            INSERT INTO media_blobs (
-                id, data, sha256, size, mime, source_client_id,
-                local_path, metadata, created_at, updated_at
-            ) VALUES (
-                $1, $2, $3, $4, $5, $6, $7, $8, $9, $10
-            )
+            INSERT INTO media_blob_shares (blob_id, user_id, granted_by)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (blob_id, user_id) DO NOTHING
             "#,
-            blob.id,
-            blob.data,
-            blob.sha256,
-            blob.size,
-            blob.mime,
-            blob.source_client_id,
-            blob.local_path,
-            blob.metadata,
-            blob.created_at,
-            blob.updated_at
+            blob_id,
+            user_id,
+            granted_by
         )
         .execute(self.db.pool())
-        .await;
+        .await?;
 
-        match result {
-            Ok(_) => {
-                info!("Successfully created media blob: {}", blob.id);
-                Ok(blob)
-            }
-            Err(sqlx::Error::Database(db_err)) if db_err.is_unique_violation() => {
-                warn!("Duplicate SHA256 hash: {}", blob.sha256);
-                Err(WebauthnError::BadRequest)
-            }
-            Err(e) => {
-                error!("Failed to create media blob: {}", e);
-                println!("onoz blob Failed to create media blob: {}", e);
-                Err(WebauthnError::SqlxError(e))
-            }
-        }
+        Ok(())
     }
 
-    /// Find a media blob by ID
-    pub async fn find_by_id(&self, id: Uuid) -> Result<MediaBlob, WebauthnError> {
-        debug!("Finding media blob by ID: {}", id);
+    /// Revoke a user's access grant for a blob
+    pub async fn remove_share(&self, blob_id: Uuid, user_id: Uuid) -> Result<bool, WebauthnError> {
+        info!("Unsharing media blob {} from user {}", blob_id, user_id);
 
-        let row = sqlx::query!(
+        let result = sqlx::query!(
+            "DELETE FROM media_blob_shares WHERE blob_id = $1 AND user_id = $2",
+            blob_id,
+            user_id
+        )
+        .execute(self.db.pool())
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// List the users a blob has been shared with
+    pub async fn list_shares(&self, blob_id: Uuid) -> Result<Vec<MediaBlobShare>, WebauthnError> {
+        let shares = sqlx::query_as!(
+            MediaBlobShare,
             r#"
-            SELECT id, data, sha256, size, mime, source_client_id,
-                   local_path, metadata, created_at, updated_at
-            FROM media_blobs
-            WHERE id = $1
+            SELECT s.blob_id, s.user_id, u.username, s.granted_by, s.granted_at
+            FROM media_blob_shares s
+            JOIN users u ON u.id = s.user_id
+            WHERE s.blob_id = $1
+            ORDER BY s.granted_at
             "#,
-            id
+            blob_id
         )
-        .fetch_optional(self.db.pool())
+        .fetch_all(self.db.pool())
         .await?;
 
-        match row {
-            Some(r) => Ok(MediaBlob {
-                id: r.id,
-                data: r.data,
-                sha256: r.sha256,
-                size: r.size,
-                mime: r.mime,
-                source_client_id: r.source_client_id,
-                local_path: r.local_path,
-                metadata: r.metadata.unwrap_or_else(|| serde_json::json!({})),
-                created_at: r.created_at.unwrap_or_else(OffsetDateTime::now_utc),
-                updated_at: r.updated_at.unwrap_or_else(OffsetDateTime::now_utc),
-            }),
-            None => Err(WebauthnError::UserNotFound),
-        }
+        Ok(shares)
     }
 
-    /// Find a media blob by SHA256 hash
-    pub async fn get_by_sha256(&self, sha256: &str) -> Result<MediaBlob, WebauthnError> {
-        debug!("Finding media blob by SHA256: {}", sha256);
+    /// Check whether a blob has been shared with a user
+    pub async fn is_shared_with(
+        &self,
+        blob_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<bool, WebauthnError> {
+        let result = sqlx::query!(
+            "SELECT 1 as exists FROM media_blob_shares WHERE blob_id = $1 AND user_id = $2",
+            blob_id,
+            user_id
+        )
+        .fetch_optional(self.db.pool())
+        .await?;
 
-        let row = sqlx::query!(
+        Ok(result.is_some())
+    }
+
+    /// Check whether a user may view some blob with the given content
+    pub async fn has_visible_content(
+        &self,
+        sha256: &str,
+        user_id: Uuid,
+    ) -> Result<bool, WebauthnError> {
+        let result = sqlx::query!(
             r#"
-            SELECT id, data, sha256, size, mime, source_client_id,
-                   local_path, metadata, created_at, updated_at
-            FROM media_blobs
-            WHERE sha256 = $1
+            SELECT 1 as exists
+            FROM media_blobs b
+            WHERE b.sha256 = $1 AND b.deleted_at IS NULL
+              AND (b.owner_user_id = $2 OR b.visibility = 'members'
+                   OR (b.visibility = 'shared' AND EXISTS (
+                       SELECT 1 FROM media_blob_shares s
+                       WHERE s.blob_id = b.id AND s.user_id = $2)))
+            LIMIT 1
             "#,
-            sha256
+            sha256,
+            user_id
         )
         .fetch_optional(self.db.pool())
         .await?;
 
-        match row {
-            Some(r) => Ok(MediaBlob {
-                id: r.id,
-                data: r.data,
-                sha256: r.sha256,
-                size: r.size,
-                mime: r.mime,
-                source_client_id: r.source_client_id,
-                local_path: r.local_path,
-                metadata: r.metadata.unwrap_or_else(|| serde_json::json!({})),
-                created_at: r.created_at.unwrap_or_else(OffsetDateTime::now_utc),
-                updated_at: r.updated_at.unwrap_or_else(OffsetDateTime::now_utc),
-            }),
-            None => Err(WebauthnError::UserNotFound),
-        }
+        Ok(result.is_some())
     }
 
-    /// Find a media blob by ID without data (for efficient responses)
-    /// Get a media blob by ID without data field for efficiency
-    pub async fn get_by_id_without_data(&self, id: Uuid) -> Result<MediaBlob, WebauthnError> {
-        debug!("Finding media blob by ID without data: {}", id);
+    /// Record the use of a single-use link, returning when it was first
+    /// used if that was before
+    ///
+    /// Nonces of links that have expired are pruned on the way.
+    pub async fn record_link_use(
+        &self,
+        nonce: &str,
+        expires_at: OffsetDateTime,
+    ) -> Result<Option<OffsetDateTime>, WebauthnError> {
+        sqlx::query!("DELETE FROM media_link_uses WHERE expires_at < now()")
+            .execute(self.db.pool())
+            .await?;
 
-        let row = sqlx::query!(
+        let inserted = sqlx::query!(
             r#"
-            SELECT id, sha256, size, mime, source_client_id,
-                   local_path, metadata, created_at, updated_at
-            FROM media_blobs
-            WHERE id = $1
+            INSERT INTO media_link_uses (nonce, expires_at)
+            VALUES ($1, $2)
+            ON CONFLICT (nonce) DO NOTHING
             "#,
-            id
+            nonce,
+            expires_at
         )
-        .fetch_optional(self.db.pool())
+        .execute(self.db.pool())
         .await?;
-
-        match row {
-            Some(r) => Ok(MediaBlob {
-                id: r.id,
-                data: None, // Explicitly exclude data
-                sha256: r.sha256,
-                size: r.size,
-                mime: r.mime,
-                source_client_id: r.source_client_id,
-                local_path: r.local_path,
-                metadata: r.metadata.unwrap_or_else(|| serde_json::json!({})),
-                created_at: r.created_at.unwrap_or_else(OffsetDateTime::now_utc),
-                updated_at: r.updated_at.unwrap_or_else(OffsetDateTime::now_utc),
-            }),
-            None => Err(WebauthnError::UserNotFound),
+        if inserted.rows_affected() > 0 {
+            return Ok(None);
         }
-    }
 
-    /// Query media blobs with filtering and pagination
-    pub async fn query(&self, params: MediaBlobQuery) -> Result<Vec<MediaBlob>, WebauthnError> {
-        debug!("Querying media blobs with params: {:?}", params);
+        let used_at = sqlx::query_scalar!(
+            "SELECT used_at FROM media_link_uses WHERE nonce = $1",
+            nonce
+        )
+        .fetch_one(self.db.pool())
+        .await?;
 
-        let limit = params.limit.unwrap_or(50).min(1000); // Cap at 1000
-        let offset = params.offset.unwrap_or(0);
+        Ok(Some(used_at))
+    }
 
-        // Build the query manually since we need to handle type conversion
-        let mut sql = String::from(
-            "SELECT id, sha256, size, mime, source_client_id, local_path, metadata, created_at, updated_at FROM media_blobs WHERE 1=1"
-        );
-        let mut param_count = 0;
+    /// Delete a blob, live or trashed, returning its content hash
+    pub async fn delete_in<'e, E>(
+        &self,
+        executor: E,
+        id: Uuid,
+    ) -> Result<Option<String>, WebauthnError>
+    where
+        E: PgExecutor<'e>,
+    {
+        info!("Deleting media blob: {}", id);
 
-        if let Some(ref _sha256) = params.sha256 {
-            param_count += 1;
-            sql.push_str(&format!(" AND sha256 = ${}", param_count));
-        }
+        let sha256 =
+            sqlx::query_scalar!("DELETE FROM media_blobs WHERE id = $1 RETURNING sha256", id)
+                .fetch_optional(executor)
+                .await?;
 
-        if let Some(ref _client_id) = params.source_client_id {
-            param_count += 1;
-            sql.push_str(&format!(" AND source_client_id = ${}", param_count));
-        }
+        Ok(sha256)
+    }
 
-        if let Some(ref _mime_pattern) = params.mime_pattern {
-            param_count += 1;
-            sql.push_str(&format!(" AND mime LIKE ${}", param_count));
-        }
+    /// Move a blob to the trash
+    pub async fn trash_in<'e, E>(
+        &self,
+        executor: E,
+        id: Uuid,
+        deleted_by: Option<Uuid>,
+    ) -> Result<bool, WebauthnError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let result = sqlx::query!(
+            r#"
+            UPDATE media_blobs SET deleted_at = now(), deleted_by = $2
+            WHERE id = $1 AND deleted_at IS NULL
+            "#,
+            id,
+            deleted_by
+        )
+        .execute(executor)
+        .await?;
 
-        param_count += 1;
-        sql.push_str(&format!(" ORDER BY created_at DESC LIMIT ${}", param_count));
-        param_count += 1;
-        sql.push_str(&format!(" OFFSET ${}", param_count));
+        Ok(result.rows_affected() > 0)
+    }
 
-        // Execute the query manually
-        let mut query = sqlx::query(&sql);
+    /// Take a blob out of the trash, unless it has been there longer than
+    /// `trash_days`
+    pub async fn restore_in<'e, E>(
+        &self,
+        executor: E,
+        id: Uuid,
+        trash_days: i32,
+    ) -> Result<bool, WebauthnError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let result = sqlx::query!(
+            r#"
+            UPDATE media_blobs SET deleted_at = NULL, deleted_by = NULL
+            WHERE id = $1 AND deleted_at > now() - make_interval(days => $2)
+            "#,
+            id,
+            trash_days
+        )
+        .execute(executor)
+        .await?;
 
-        if let Some(ref sha256) = params.sha256 {
-            query = query.bind(sha256);
-        }
-        if let Some(ref client_id) = params.source_client_id {
-            query = query.bind(client_id);
-        }
-        if let Some(ref mime_pattern) = params.mime_pattern {
-            query = query.bind(format!("%{}%", mime_pattern));
-        }
-        query = query.bind(limit).bind(offset);
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Find a blob in the trash that is still within `trash_days`
+    pub async fn find_trashed(
+        &self,
+        id: Uuid,
+        trash_days: i32,
+    ) -> Result<Option<TrashedMediaBlob>, WebauthnError> {
+        let blob = sqlx::query_as::<_, TrashedMediaBlob>(&format!(
+            "{} WHERE id = $1 AND deleted_at > now() - make_interval(days => $2)",
+            TRASH_SELECT
+        ))
+        .bind(id)
+        .bind(trash_days)
+        .fetch_optional(self.db.pool())
+        .await?;
 
-        let rows = query.fetch_all(self.db.pool()).await?;
+        Ok(blob)
+    }
 
-        let blobs = rows
-            .into_iter()
-            .map(|row: PgRow| {
-                MediaBlob {
-                    id: row.get("id"),
-                    data: None, // Don't include data in query results
-                    sha256: row.get("sha256"),
-                    size: row.get("size"),
-                    mime: row.get("mime"),
-                    source_client_id: row.get("source_client_id"),
-                    local_path: row.get("local_path"),
-                    metadata: row
-                        .get::<Option<serde_json::Value>, _>("metadata")
-                        .unwrap_or_else(|| serde_json::json!({})),
-                    created_at: row
-                        .get::<Option<OffsetDateTime>, _>("created_at")
-                        .unwrap_or_else(OffsetDateTime::now_utc),
-                    updated_at: row
-                        .get::<Option<OffsetDateTime>, _>("updated_at")
-                        .unwrap_or_else(OffsetDateTime::now_utc),
-                }
-            })
-            .collect();
+    /// A user's trashed blobs still within `trash_days`, most recently
+    /// deleted first
+    pub async fn list_trash(
+        &self,
+        owner_user_id: Uuid,
+        trash_days: i32,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<TrashedMediaBlob>, WebauthnError> {
+        let blobs = sqlx::query_as::<_, TrashedMediaBlob>(&format!(
+            "{} WHERE owner_user_id = $1 AND deleted_at > now() - make_interval(days => $2) \
+             ORDER BY deleted_at DESC, id LIMIT $3 OFFSET $4",
+            TRASH_SELECT
+        ))
+        .bind(owner_user_id)
+        .bind(trash_days)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(self.db.pool())
+        .await?;
 
         Ok(blobs)
     }
 
-    /// Get media blob statistics
-    pub async fn get_stats(&self) -> Result<MediaBlobStats, WebauthnError> {
-        debug!("Getting media blob statistics");
-
-        // TODO: Uncomment after running sqlx prepare
-        /*
-        // Get total count and size
-        let totals = sqlx::query!(
+    /// IDs of blobs that have been in the trash for `trash_days` or longer
+    pub async fn expired_trash_ids(
+        &self,
+        trash_days: i32,
+        limit: i64,
+    ) -> Result<Vec<Uuid>, WebauthnError> {
+        let ids = sqlx::query_scalar!(
             r#"
-            SELECT
-                COUNT(*) as total_count,
-                SUM(size) as total_size,
-                COUNT(DISTINCT sha256) as unique_sha256_count
-            FROM media_blobs
-            "#
+            SELECT id FROM media_blobs
+            WHERE deleted_at <= now() - make_interval(days => $1)
+            ORDER BY deleted_at
+            LIMIT $2
+            "#,
+            trash_days,
+            limit
         )
-        .fetch_one(self.db.pool())
+        .fetch_all(self.db.pool())
         .await?;
 
-        // Get MIME type distribution
-        let mime_rows = sqlx::query!(
+        Ok(ids)
+    }
+
+    /// IDs of live blobs older than the retention that applies to them
+    ///
+    /// `mime_patterns` are `LIKE` patterns matched against the blob's MIME
+    /// type, each with its retention in `mime_days`; collections contribute
+    /// their own retention. The longest applicable retention wins, and blobs
+    /// no retention applies to are kept.
+    pub async fn expired_blob_ids(
+        &self,
+        mime_patterns: &[String],
+        mime_days: &[i32],
+        limit: i64,
+    ) -> Result<Vec<Uuid>, WebauthnError> {
+        let ids = sqlx::query_scalar!(
             r#"
-            SELECT mime, COUNT(*) as count
-            FROM media_blobs
-            GROUP BY mime
-            ORDER BY count DESC
-            "#
+            SELECT b.id as "id!"
+            FROM media_blobs b
+            CROSS JOIN LATERAL (
+                SELECT MAX(days) as days FROM (
+                    SELECT p.days
+                    FROM unnest($1::text[], $2::int[]) AS p(pattern, days)
+                    WHERE lower(trim(split_part(COALESCE(b.mime, ''), ';', 1))) LIKE p.pattern
+                    UNION ALL
+                    SELECT c.retention_days
+                    FROM media_collection_items i
+                    JOIN media_collections c ON c.id = i.collection_id
+                    WHERE i.blob_id = b.id AND c.retention_days IS NOT NULL
+                ) policies
+            ) retention
+            WHERE b.parent_blob_id IS NULL AND b.deleted_at IS NULL
+              AND b.created_at < now() - make_interval(days => retention.days)
+            ORDER BY b.created_at
+            LIMIT $3
+            "#,
+            mime_patterns,
+            mime_days,
+            limit
         )
         .fetch_all(self.db.pool())
         .await?;
 
-        let mime_type_distribution = mime_rows
-            .into_iter()
-            .map(|r| MimeTypeCount {
-                mime_type: r.mime,
-                count: r.count.unwrap_or(0),
-            })
-            .collect();
-
-        Ok(MediaBlobStats {
-            total_count: totals.total_count.unwrap_or(0),
-            total_size: totals
-                .total_size
-                .map(|s| s.to_string().parse::<i64>().unwrap_or(0)),
-            unique_sha256_count: totals.unique_sha256_count.unwrap_or(0),
-            mime_type_distribution,
-        })
-        */
-
-        // Temporary mock implementation
-        Ok(MediaBlobStats {
-            total_count: 0,
-            total_size: None,
-            unique_sha256_count: 0,
-            mime_type_distribution: vec![],
-        })
+        Ok(ids)
     }
 
-    /// Update blob metadata
-    pub async fn update_metadata(
+    /// Record an audit log entry for each of the blobs, which must still exist
+    pub async fn record_audit_in<'e, E>(
         &self,
-        id: Uuid,
-        _metadata: serde_json::Value,
-    ) -> Result<MediaBlob, WebauthnError> {
-        debug!("Updating metadata for media blob: {}", id);
-
-        // TODO: Uncomment after running sqlx prepare
-        /*
-        let updated_at = OffsetDateTime::now_utc();
-
+        executor: E,
+        action: MediaAuditAction,
+        reason: MediaAuditReason,
+        blob_ids: &[Uuid],
+        actor_user_id: Option<Uuid>,
+    ) -> Result<(), WebauthnError>
+    where
+        E: PgExecutor<'e>,
+    {
         sqlx::query!(
             r#"
-            UPDATE media_blobs
-            SET metadata = $1, updated_at = $2
-            WHERE id = $3
+            INSERT INTO media_audit_log
+                (action, reason, blob_id, sha256, size, mime, owner_user_id, actor_user_id)
+            SELECT $1, $2, id, sha256, COALESCE(size, 0), mime, owner_user_id, $4
+            FROM media_blobs
+            WHERE id = ANY($3)
             "#,
-            metadata,
-            updated_at,
-            id
+            action as MediaAuditAction,
+            reason as MediaAuditReason,
+            blob_ids,
+            actor_user_id
         )
-        .execute(self.db.pool())
+        .execute(executor)
         .await?;
 
-        // Return the updated blob
-        self.find_by_id(id).await
-        */
-
-        // Temporary mock implementation
-        Err(WebauthnError::UserNotFound)
+        Ok(())
     }
 
-    /// Delete a media blob by ID
-    pub async fn delete(&self, id: Uuid) -> Result<bool, WebauthnError> {
-        info!("Deleting media blob: {}", id);
-
-        let result = sqlx::query!("DELETE FROM media_blobs WHERE id = $1", id)
-            .execute(self.db.pool())
-            .await?;
+    /// Audit log entries, newest first, optionally only for one blob
+    pub async fn list_audit(
+        &self,
+        blob_id: Option<Uuid>,
+        limit: i64,
+    ) -> Result<Vec<MediaAuditEntry>, WebauthnError> {
+        let entries = sqlx::query_as!(
+            MediaAuditEntry,
+            r#"
+            SELECT id, action as "action: MediaAuditAction", reason as "reason: MediaAuditReason",
+                   blob_id, sha256, size, mime, owner_user_id, actor_user_id, created_at
+            FROM media_audit_log
+            WHERE $1::uuid IS NULL OR blob_id = $1
+            ORDER BY created_at DESC, id DESC
+            LIMIT $2
+            "#,
+            blob_id,
+            limit
+        )
+        .fetch_all(self.db.pool())
+        .await?;
 
-        Ok(result.rows_affected() > 0)
+        Ok(entries)
     }
 
-    /// Clean up old media blobs (older than specified days)
-    pub async fn cleanup_old_blobs(&self, days: i32) -> Result<u64, WebauthnError> {
-        info!("Cleaning up media blobs older than {} days", days);
+    /// Lock objects no media blob references any more for garbage collection
+    ///
+    /// With `sha256` set only that object is considered. Objects locked by an
+    /// in-flight upload are skipped and picked up by a later pass.
+    pub async fn lock_unreferenced_objects_in(
+        &self,
+        conn: &mut PgConnection,
+        sha256: Option<&str>,
+    ) -> Result<Vec<MediaObject>, WebauthnError> {
+        let objects = sqlx::query_as!(
+            MediaObject,
+            r#"
+            SELECT sha256, size, storage_backend as "storage_backend: MediaStorageBackend",
+                   storage_key, ref_count, content_type, created_at
+            FROM media_objects
+            WHERE ref_count = 0 AND ($1::text IS NULL OR sha256 = $1)
+            FOR UPDATE SKIP LOCKED
+            "#,
+            sha256
+        )
+        .fetch_all(conn)
+        .await?;
 
-        let cutoff_date = OffsetDateTime::now_utc() - time::Duration::days(days as i64);
+        Ok(objects)
+    }
 
-        let result = sqlx::query!("DELETE FROM media_blobs WHERE created_at < $1", cutoff_date)
-            .execute(self.db.pool())
-            .await?;
+    /// Delete unreferenced objects by hash
+    pub async fn delete_objects_in<'e, E>(
+        &self,
+        executor: E,
+        sha256s: &[String],
+    ) -> Result<u64, WebauthnError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let result = sqlx::query!(
+            "DELETE FROM media_objects WHERE sha256 = ANY($1) AND ref_count = 0",
+            sha256s
+        )
+        .execute(executor)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
 
-        let deleted_count = result.rows_affected();
-        info!("Deleted {} old media blobs", deleted_count);
+    /// Move blobs created before `cutoff` to the trash, returning their IDs
+    pub async fn trash_created_before_in<'e, E>(
+        &self,
+        executor: E,
+        cutoff: OffsetDateTime,
+    ) -> Result<Vec<Uuid>, WebauthnError>
+    where
+        E: PgExecutor<'e>,
+    {
+        info!("Moving media blobs created before {} to the trash", cutoff);
+
+        let ids = sqlx::query_scalar!(
+            r#"
+            UPDATE media_blobs
+            SET deleted_at = now(), deleted_by = NULL
+            WHERE created_at < $1 AND parent_blob_id IS NULL AND deleted_at IS NULL
+            RETURNING id
+            "#,
+            cutoff
+        )
+        .fetch_all(executor)
+        .await?;
 
-        Ok(deleted_count)
+        Ok(ids)
     }
 
     /// Get media blobs by client ID
@@ -437,25 +1637,28 @@ impl<'a> MediaRepository<'a> {
         Ok(result.is_some())
     }
 
-    /// Get recent media blobs (within last N days)
+    /// Get recent media blobs (within last N days), newest first
     pub async fn get_recent(
         &self,
         days: i32,
-        _limit: Option<i64>,
+        limit: Option<i64>,
     ) -> Result<Vec<MediaBlob>, WebauthnError> {
         debug!("Getting recent media blobs from last {} days", days);
 
-        // TODO: Uncomment after running sqlx prepare
-        /*
         let cutoff_date = OffsetDateTime::now_utc() - time::Duration::days(days as i64);
         let limit = limit.unwrap_or(50).min(1000);
 
-        let rows = sqlx::query!(
+        let blobs = sqlx::query_as!(
+            MediaBlob,
             r#"
-            SELECT id, sha256, size, mime, source_client_id,
-                   local_path, metadata, created_at, updated_at
+            SELECT id, NULL::bytea as "data?", sha256, size, mime, source_client_id,
+                   owner_user_id, visibility as "visibility: MediaVisibility",
+                   local_path, metadata, scan_status as "scan_status: ScanStatus",
+                   COALESCE((SELECT t.tags FROM media_blob_tags t WHERE t.blob_id = media_blobs.id),
+                            '{}') as "tags!",
+                   created_at, updated_at
             FROM media_blobs
-            WHERE created_at >= $1
+            WHERE created_at >= $1 AND parent_blob_id IS NULL AND deleted_at IS NULL
             ORDER BY created_at DESC
             LIMIT $2
             "#,
@@ -465,27 +1668,49 @@ impl<'a> MediaRepository<'a> {
         .fetch_all(self.db.pool())
         .await?;
 
-        let blobs = rows
-            .into_iter()
-            .map(|r| MediaBlob {
-                id: r.id,
-                data: None,
-                sha256: r.sha256,
-                size: r.size,
-                mime: r.mime,
-                source_client_id: r.source_client_id,
-                local_path: r.local_path,
-                metadata: r.metadata.unwrap_or_else(|| serde_json::json!({})),
-                created_at: r.created_at.unwrap_or_else(OffsetDateTime::now_utc),
-                updated_at: r.updated_at.unwrap_or_else(OffsetDateTime::now_utc),
-            })
-            .collect();
-
         Ok(blobs)
-        */
+    }
+}
 
-        // Temporary mock implementation
-        Ok(vec![])
+/// Columns of a trashed blob, with its purge time for a trash window bound
+/// at `$2`
+const TRASH_SELECT: &str = "SELECT id, NULL::bytea AS data, sha256, size, mime, source_client_id, \
+     owner_user_id, visibility, local_path, metadata, scan_status, \
+     COALESCE((SELECT t.tags FROM media_blob_tags t WHERE t.blob_id = media_blobs.id), \
+     '{}') AS tags, created_at, updated_at, deleted_at, deleted_by, \
+     deleted_at + make_interval(days => $2) AS purge_at \
+     FROM media_blobs";
+
+/// A search result with its text search rank
+#[derive(sqlx::FromRow)]
+struct SearchRow {
+    #[sqlx(flatten)]
+    blob: MediaBlob,
+    rank: f32,
+}
+
+/// Filter to blobs the user bound at `param` may view
+fn visible_to_filter(param: usize) -> String {
+    format!(
+        " AND (owner_user_id = ${p} OR visibility = 'members' OR (visibility = 'shared' \
+         AND EXISTS (SELECT 1 FROM media_blob_shares s \
+         WHERE s.blob_id = media_blobs.id AND s.user_id = ${p})))",
+        p = param
+    )
+}
+
+/// Escape `LIKE` wildcards so `text` matches literally
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Decode a role stored in the users table
+fn parse_role(role: &str) -> UserRole {
+    match role {
+        "admin" => UserRole::Admin,
+        _ => UserRole::Member,
     }
 }
 
@@ -499,6 +1724,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[allow(clippy::assertions_on_constants)]
     async fn test_create_media_blob() {
         // let db = setup_test_db().await;
         // let repo = MediaRepository::new(&db);
@@ -517,4 +1743,10 @@ mod tests {
         // assert!(result.is_ok());
         assert!(true); // Placeholder test
     }
+
+    #[test]
+    fn test_escape_like() {
+        assert_eq!(super::escape_like("50%_off\\"), "50\\%\\_off\\\\");
+        assert_eq!(super::escape_like("holiday"), "holiday");
+    }
 }
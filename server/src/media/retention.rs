@@ -0,0 +1,171 @@
+//! Purging of expired trash and of blobs past their retention
+//!
+//! Deleted blobs wait in the trash for the configured window before they
+//! are purged. Retention policies, set per MIME type in the configuration or
+//! per collection, purge live blobs once they are older than the longest
+//! retention that applies to them. Purges remove the record and, once no
+//! other blob shares it, the stored content, and are recorded in the media
+//! audit log. Purges of live blobs are published as deletions to the event
+//! hub, when given one.
+
+use std::time::Duration;
+
+use tracing::{error, info};
+
+use crate::config::{MediaConfig, MediaRetentionRule};
+use crate::database::DatabaseConnection;
+use crate::error::WebauthnError;
+
+use super::events::MediaEvents;
+use super::models::{MediaAuditReason, MediaRetentionReport};
+use super::storage::MediaStorage;
+use super::{MediaRepository, MediaService};
+
+/// Blobs purged per database round trip
+const BATCH_SIZE: i64 = 100;
+
+/// Longest retention or trash window, about a hundred years
+pub const MAX_RETENTION_DAYS: u32 = 36_500;
+
+/// A configured number of days as used in queries
+pub(crate) fn window_days(days: u32) -> i32 {
+    days.min(MAX_RETENTION_DAYS) as i32
+}
+
+/// Check a collection retention; 0 stands for no retention
+pub(crate) fn check_collection_retention(days: i32) -> Result<i32, String> {
+    if !(0..=MAX_RETENTION_DAYS as i32).contains(&days) {
+        return Err(format!(
+            "Retention must be between 1 and {} days, or 0 for none",
+            MAX_RETENTION_DAYS
+        ));
+    }
+    Ok(days)
+}
+
+/// `LIKE` pattern matching the MIME types a retention rule covers
+fn like_pattern(rule: &MediaRetentionRule) -> String {
+    let pattern = rule.pattern.trim().to_ascii_lowercase();
+    let escape = |text: &str| {
+        text.replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    };
+
+    match pattern.strip_suffix("/*") {
+        _ if pattern == "*" => "%".to_string(),
+        Some(kind) => format!("{}/%", escape(kind)),
+        None => escape(&pattern),
+    }
+}
+
+/// Purge blobs whose trash window or retention has passed
+pub async fn run_retention(
+    db: &DatabaseConnection,
+    storage: &MediaStorage,
+    config: &MediaConfig,
+    events: Option<&MediaEvents>,
+) -> Result<MediaRetentionReport, WebauthnError> {
+    let repository = MediaRepository::new(db);
+    let mut service = MediaService::new(MediaRepository::new(db), storage);
+    if let Some(events) = events {
+        service = service.with_events(events);
+    }
+    let mut report = MediaRetentionReport::default();
+
+    let trash_days = window_days(config.retention.trash_days);
+    loop {
+        let ids = repository.expired_trash_ids(trash_days, BATCH_SIZE).await?;
+        for id in &ids {
+            if service
+                .purge(*id, MediaAuditReason::TrashExpired, None)
+                .await?
+            {
+                report.trash_purged += 1;
+            }
+        }
+        if (ids.len() as i64) < BATCH_SIZE {
+            break;
+        }
+    }
+
+    let rules = &config.retention.mime_types;
+    let patterns: Vec<String> = rules.iter().map(like_pattern).collect();
+    let days: Vec<i32> = rules.iter().map(|rule| window_days(rule.days)).collect();
+    loop {
+        let ids = repository
+            .expired_blob_ids(&patterns, &days, BATCH_SIZE)
+            .await?;
+        for id in &ids {
+            if service
+                .purge(*id, MediaAuditReason::Retention, None)
+                .await?
+            {
+                report.expired_purged += 1;
+            }
+        }
+        if (ids.len() as i64) < BATCH_SIZE {
+            break;
+        }
+    }
+
+    if report.trash_purged > 0 || report.expired_purged > 0 {
+        info!(
+            "Purged {} media blobs from the trash and {} past their retention",
+            report.trash_purged, report.expired_purged
+        );
+    }
+
+    Ok(report)
+}
+
+/// Periodically purge expired media in the background
+pub fn spawn_media_retention(
+    db: DatabaseConnection,
+    storage: MediaStorage,
+    config: MediaConfig,
+    events: MediaEvents,
+) {
+    let interval = Duration::from_secs(config.retention.interval_minutes.max(1) * 60);
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+
+            if let Err(e) = run_retention(&db, &storage, &config, Some(&events)).await {
+                error!("Failed to purge expired media: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_like_pattern() {
+        let rule = |pattern: &str| MediaRetentionRule {
+            pattern: pattern.to_string(),
+            days: 1,
+        };
+
+        assert_eq!(like_pattern(&rule("*")), "%");
+        assert_eq!(like_pattern(&rule("Video/*")), "video/%");
+        assert_eq!(
+            like_pattern(&rule("application/x_raw")),
+            "application/x\\_raw"
+        );
+    }
+
+    #[test]
+    fn test_windows() {
+        assert_eq!(window_days(30), 30);
+        assert_eq!(window_days(u32::MAX), MAX_RETENTION_DAYS as i32);
+        assert_eq!(check_collection_retention(0), Ok(0));
+        assert_eq!(check_collection_retention(90), Ok(90));
+        assert!(check_collection_retention(-1).is_err());
+        assert!(check_collection_retention(MAX_RETENTION_DAYS as i32 + 1).is_err());
+    }
+}
@@ -0,0 +1,187 @@
+//! ClamAV daemon client
+//!
+//! Content is streamed to clamd with the `INSTREAM` command: length-prefixed
+//! chunks ending with a zero-length chunk. clamd answers `stream: OK`,
+//! `stream: <signature> FOUND` or an error ending in `ERROR`, for instance
+//! when the content exceeds its `StreamMaxLength`.
+
+use std::path::Path;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::{MalwareScanner, ScanError, ScanVerdict};
+use crate::config::ClamdScannerConfig;
+
+/// Largest chunk sent to clamd at once
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Scans content with a ClamAV daemon reached over TCP
+pub struct ClamdScanner {
+    address: String,
+    timeout_seconds: u64,
+}
+
+impl ClamdScanner {
+    /// Create a scanner for the clamd described by the configuration
+    pub fn new(config: &ClamdScannerConfig) -> Self {
+        Self {
+            address: config.address.clone(),
+            timeout_seconds: config.timeout_seconds,
+        }
+    }
+
+    async fn instream<R>(&self, reader: R) -> Result<ScanVerdict, ScanError>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let timeout = Duration::from_secs(self.timeout_seconds.max(1));
+        tokio::time::timeout(timeout, self.exchange(reader))
+            .await
+            .map_err(|_| ScanError::Timeout(self.timeout_seconds))?
+    }
+
+    async fn exchange<R>(&self, reader: R) -> Result<ScanVerdict, ScanError>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut stream = TcpStream::connect(&self.address).await?;
+
+        // clamd stops reading and replies once content exceeds its limit, so
+        // a failed send may still come with an answer worth reporting
+        let sent = send_stream(&mut stream, reader).await;
+
+        let mut reply = Vec::new();
+        let received = stream.read_to_end(&mut reply).await;
+        match (sent, received) {
+            (Err(e), _) if reply.is_empty() => Err(e.into()),
+            (_, Err(e)) if reply.is_empty() => Err(e.into()),
+            _ => parse_reply(&reply),
+        }
+    }
+}
+
+#[async_trait]
+impl MalwareScanner for ClamdScanner {
+    fn name(&self) -> &'static str {
+        "clamd"
+    }
+
+    async fn scan_file(&self, path: &Path) -> Result<ScanVerdict, ScanError> {
+        let file = tokio::fs::File::open(path).await?;
+        self.instream(file).await
+    }
+
+    async fn scan_bytes(&self, bytes: &[u8]) -> Result<ScanVerdict, ScanError> {
+        self.instream(bytes).await
+    }
+}
+
+/// Send the `INSTREAM` command followed by the content in chunks
+async fn send_stream<R>(stream: &mut TcpStream, mut reader: R) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    stream.write_all(b"zINSTREAM\0").await?;
+
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        stream.write_all(&(read as u32).to_be_bytes()).await?;
+        stream.write_all(&buffer[..read]).await?;
+    }
+
+    stream.write_all(&0u32.to_be_bytes()).await?;
+    stream.flush().await
+}
+
+/// Interpret clamd's answer to a scan
+fn parse_reply(reply: &[u8]) -> Result<ScanVerdict, ScanError> {
+    let reply = String::from_utf8_lossy(reply);
+    let reply = reply.trim_end_matches(['\0', '\n']).trim();
+    let result = reply.strip_prefix("stream:").unwrap_or(reply).trim();
+
+    if result == "OK" {
+        Ok(ScanVerdict::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(ScanVerdict::Infected {
+            signature: signature.trim().to_string(),
+        })
+    } else {
+        Err(ScanError::Scanner(format!("clamd replied: {}", reply)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_parse_clamd_reply() {
+        assert_eq!(parse_reply(b"stream: OK\0").unwrap(), ScanVerdict::Clean);
+        assert_eq!(
+            parse_reply(b"stream: Win.Test.EICAR_HDB-1 FOUND\0").unwrap(),
+            ScanVerdict::Infected {
+                signature: "Win.Test.EICAR_HDB-1".to_string()
+            }
+        );
+        assert!(matches!(
+            parse_reply(b"INSTREAM size limit exceeded. ERROR\0"),
+            Err(ScanError::Scanner(message)) if message.contains("size limit")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_clamd_instream_protocol() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        // A fake clamd that reassembles the chunks and flags "malware"
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut command = [0u8; 10];
+            socket.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+
+            let mut content = Vec::new();
+            loop {
+                let len = socket.read_u32().await.unwrap() as usize;
+                if len == 0 {
+                    break;
+                }
+                assert!(len <= CHUNK_SIZE);
+                let mut chunk = vec![0u8; len];
+                socket.read_exact(&mut chunk).await.unwrap();
+                content.extend_from_slice(&chunk);
+            }
+
+            let reply: &[u8] = if content.ends_with(b"malware") {
+                b"stream: Test.Malware FOUND\0"
+            } else {
+                b"stream: OK\0"
+            };
+            socket.write_all(reply).await.unwrap();
+            content.len()
+        });
+
+        let scanner = ClamdScanner::new(&ClamdScannerConfig {
+            address,
+            timeout_seconds: 10,
+        });
+        let mut content = vec![b'a'; CHUNK_SIZE * 2 + 100];
+        content.extend_from_slice(b"malware");
+        assert_eq!(
+            scanner.scan_bytes(&content).await.unwrap(),
+            ScanVerdict::Infected {
+                signature: "Test.Malware".to_string()
+            }
+        );
+        assert_eq!(server.await.unwrap(), content.len());
+    }
+}
@@ -0,0 +1,148 @@
+//! Adapter for scanners run as an external program
+//!
+//! The program is run once per file. Its exit code gives the verdict, as
+//! with `clamscan`: `0` for clean, one of the configured infected codes for
+//! malware, anything else for an error. A `<path>: <signature> FOUND` line on
+//! standard output names the malware.
+
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::process::Command;
+
+use super::{MalwareScanner, ScanError, ScanVerdict};
+use crate::config::CommandScannerConfig;
+
+/// Placeholder in the arguments replaced by the file to scan
+const PATH_PLACEHOLDER: &str = "{path}";
+
+/// Scans files by running an external program
+pub struct CommandScanner {
+    config: CommandScannerConfig,
+}
+
+impl CommandScanner {
+    /// Create a scanner running the configured program
+    pub fn new(config: &CommandScannerConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+
+    /// Arguments for scanning `path`
+    fn args(&self, path: &Path) -> Vec<String> {
+        let path = path.to_string_lossy();
+        let mut args: Vec<String> = self
+            .config
+            .args
+            .iter()
+            .map(|arg| arg.replace(PATH_PLACEHOLDER, &path))
+            .collect();
+        if !self
+            .config
+            .args
+            .iter()
+            .any(|arg| arg.contains(PATH_PLACEHOLDER))
+        {
+            args.push(path.into_owned());
+        }
+        args
+    }
+}
+
+#[async_trait]
+impl MalwareScanner for CommandScanner {
+    fn name(&self) -> &'static str {
+        "command"
+    }
+
+    async fn scan_file(&self, path: &Path) -> Result<ScanVerdict, ScanError> {
+        let output = Command::new(&self.config.program)
+            .args(self.args(path))
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output();
+        let timeout = Duration::from_secs(self.config.timeout_seconds.max(1));
+        let output = tokio::time::timeout(timeout, output)
+            .await
+            .map_err(|_| ScanError::Timeout(self.config.timeout_seconds))??;
+
+        match output.status.code() {
+            Some(0) => Ok(ScanVerdict::Clean),
+            Some(code) if self.config.infected_exit_codes.contains(&code) => {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                Ok(ScanVerdict::Infected {
+                    signature: signature_from_output(&stdout)
+                        .unwrap_or_else(|| format!("unknown (exit code {})", code)),
+                })
+            }
+            _ => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                Err(ScanError::Scanner(format!(
+                    "{} failed with {}: {}",
+                    self.config.program,
+                    output.status,
+                    stderr.trim()
+                )))
+            }
+        }
+    }
+}
+
+/// Malware name from a `<path>: <signature> FOUND` report line
+fn signature_from_output(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let report = line.trim().strip_suffix(" FOUND")?;
+        let signature = report.rsplit_once(": ").map_or(report, |(_, name)| name);
+        Some(signature.trim().to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_from_output() {
+        assert_eq!(
+            signature_from_output(
+                "/tmp/upload: Eicar-Test-Signature FOUND\n\n----------- SCAN SUMMARY -----------\n"
+            ),
+            Some("Eicar-Test-Signature".to_string())
+        );
+        assert_eq!(signature_from_output("/tmp/upload: OK\n"), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command_scanner_exit_codes() {
+        let scanner = CommandScanner::new(&CommandScannerConfig {
+            program: "sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                r#"grep -q bad "$0" && echo "$0: Bad.Thing FOUND" && exit 1; grep -q broken "$0" && exit 2; exit 0"#
+                    .to_string(),
+                "{path}".to_string(),
+            ],
+            infected_exit_codes: vec![1],
+            timeout_seconds: 10,
+        });
+
+        assert_eq!(
+            scanner.scan_bytes(b"something bad").await.unwrap(),
+            ScanVerdict::Infected {
+                signature: "Bad.Thing".to_string()
+            }
+        );
+        assert_eq!(
+            scanner.scan_bytes(b"fine").await.unwrap(),
+            ScanVerdict::Clean
+        );
+        assert!(matches!(
+            scanner.scan_bytes(b"broken").await,
+            Err(ScanError::Scanner(_))
+        ));
+    }
+}
@@ -0,0 +1,70 @@
+//! Scanner that only detects the EICAR anti-virus test file
+//!
+//! Useful for tests and for trying out quarantine without a real scanner;
+//! it reports every other file as clean.
+
+use std::path::Path;
+
+use async_trait::async_trait;
+
+use super::{MalwareScanner, ScanError, ScanVerdict};
+
+/// The EICAR test file, split so this source file does not trip scanners
+pub const EICAR_TEST_FILE: &str = concat!(
+    "X5O!P%@AP[4\\PZX54(P^)7CC)7}$",
+    "EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*"
+);
+
+/// Signature reported for the test file, as ClamAV names it
+const EICAR_SIGNATURE: &str = "Eicar-Test-Signature";
+
+/// Detects the EICAR test file anywhere in the content
+pub struct EicarScanner;
+
+impl EicarScanner {
+    fn verdict(bytes: &[u8]) -> ScanVerdict {
+        let needle = EICAR_TEST_FILE.as_bytes();
+        if bytes.windows(needle.len()).any(|window| window == needle) {
+            ScanVerdict::Infected {
+                signature: EICAR_SIGNATURE.to_string(),
+            }
+        } else {
+            ScanVerdict::Clean
+        }
+    }
+}
+
+#[async_trait]
+impl MalwareScanner for EicarScanner {
+    fn name(&self) -> &'static str {
+        "eicar"
+    }
+
+    async fn scan_file(&self, path: &Path) -> Result<ScanVerdict, ScanError> {
+        Ok(Self::verdict(&tokio::fs::read(path).await?))
+    }
+
+    async fn scan_bytes(&self, bytes: &[u8]) -> Result<ScanVerdict, ScanError> {
+        Ok(Self::verdict(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_eicar_scanner() {
+        let infected = format!("prefix {} suffix", EICAR_TEST_FILE);
+        assert_eq!(
+            EicarScanner.scan_bytes(infected.as_bytes()).await.unwrap(),
+            ScanVerdict::Infected {
+                signature: "Eicar-Test-Signature".to_string()
+            }
+        );
+        assert_eq!(
+            EicarScanner.scan_bytes(b"harmless").await.unwrap(),
+            ScanVerdict::Clean
+        );
+    }
+}
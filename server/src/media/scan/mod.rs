@@ -0,0 +1,84 @@
+//! Malware scanning of uploaded media
+//!
+//! Scanners implement [`MalwareScanner`]. While scanning is enabled, new
+//! uploads are created `pending` and a scan job is queued for them; the
+//! verdict is recorded on every blob sharing the content, so content that is
+//! uploaded again is released or quarantined without another scan.
+//!
+//! Three scanners are built in: a ClamAV daemon client speaking clamd's
+//! `INSTREAM` protocol over TCP, an adapter running an external program such
+//! as `clamscan`, and a stub that only detects the EICAR test file.
+
+mod clamd;
+mod command;
+mod eicar;
+
+pub use clamd::ClamdScanner;
+pub use command::CommandScanner;
+pub use eicar::{EicarScanner, EICAR_TEST_FILE};
+
+use std::path::Path;
+
+use async_trait::async_trait;
+
+use crate::config::{MediaScannerKind, MediaScanningConfig};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ScanError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Scanner timed out after {0} seconds")]
+    Timeout(u64),
+    #[error("Scanner error: {0}")]
+    Scanner(String),
+    #[error("Scanner not configured: {0}")]
+    NotConfigured(String),
+}
+
+/// What a scanner found in some content
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanVerdict {
+    Clean,
+    /// Malware was found; `signature` names it as the scanner reported it
+    Infected {
+        signature: String,
+    },
+}
+
+/// Something that can check content for malware
+#[async_trait]
+pub trait MalwareScanner: Send + Sync {
+    /// Short name recorded with scan results, e.g. `clamd`
+    fn name(&self) -> &'static str;
+
+    /// Scan a local file
+    async fn scan_file(&self, path: &Path) -> Result<ScanVerdict, ScanError>;
+
+    /// Scan content held in memory
+    ///
+    /// The default writes the bytes to a temporary file for
+    /// [`scan_file`](Self::scan_file).
+    async fn scan_bytes(&self, bytes: &[u8]) -> Result<ScanVerdict, ScanError> {
+        let path = std::env::temp_dir().join(format!("media-scan-{}", uuid::Uuid::new_v4()));
+        tokio::fs::write(&path, bytes).await?;
+        let verdict = self.scan_file(&path).await;
+        let _ = tokio::fs::remove_file(&path).await;
+        verdict
+    }
+}
+
+/// Build the scanner named by the scanning configuration
+pub fn scanner_from_config(
+    config: &MediaScanningConfig,
+) -> Result<Box<dyn MalwareScanner>, ScanError> {
+    Ok(match config.scanner {
+        MediaScannerKind::Clamd => Box::new(ClamdScanner::new(&config.clamd)),
+        MediaScannerKind::Command => {
+            let command = config.command.as_ref().ok_or_else(|| {
+                ScanError::NotConfigured("media.scanning.command is not set".to_string())
+            })?;
+            Box::new(CommandScanner::new(command))
+        }
+        MediaScannerKind::Eicar => Box::new(EicarScanner),
+    })
+}
@@ -0,0 +1,469 @@
+//! Media search
+//!
+//! Blobs are searched by free text over their file name, tags, MIME type and
+//! the strings in their metadata, using the `search_document` that database
+//! triggers keep current. Text search is combined with tag, MIME type, size,
+//! date and metadata filters. Metadata filters such as `av.duration > 60` are
+//! compiled to SQL/JSON path expressions.
+//!
+//! Results are paged with an opaque cursor naming the last blob returned, so
+//! pages stay stable while blobs are added.
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use super::models::{normalize_tags, MediaBlob};
+
+/// Most metadata filters one search may use
+pub const MAX_METADATA_FILTERS: usize = 16;
+/// Deepest metadata path a filter may name
+pub const MAX_METADATA_PATH_DEPTH: usize = 8;
+/// Longest free-text query, in characters
+pub const MAX_QUERY_LENGTH: usize = 512;
+
+/// A search request was malformed
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SearchError {
+    #[error("Invalid search: {0}")]
+    Invalid(String),
+    #[error("Invalid metadata filter {filter:?}: {reason}")]
+    InvalidFilter { filter: String, reason: String },
+    #[error("Invalid search cursor")]
+    InvalidCursor,
+}
+
+/// Search parameters, as sent over HTTP and WebSocket
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MediaSearch {
+    /// Free text; supports quoted phrases, `or` and `-word` exclusions
+    pub q: Option<String>,
+    /// Only blobs carrying all of these tags
+    pub tags: Vec<String>,
+    /// MIME type, or a prefix ending in `/` such as `image/`
+    pub mime: Option<String>,
+    pub min_size: Option<i64>,
+    pub max_size: Option<i64>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub created_after: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub created_before: Option<OffsetDateTime>,
+    /// Metadata filters such as `av.duration > 60` or `image.format = "png"`
+    pub metadata: Vec<String>,
+    pub limit: Option<i64>,
+    /// Cursor from the previous page
+    pub cursor: Option<String>,
+}
+
+/// A page of search results
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaSearchPage {
+    pub blobs: Vec<MediaBlob>,
+    /// Cursor for the next page; `None` on the last page
+    pub next_cursor: Option<String>,
+}
+
+/// Comparison in a metadata filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataOp {
+    Exists,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl MetadataOp {
+    /// Operators by their filter syntax; longer ones first so `>=` wins over `>`
+    const SYNTAX: [(&'static str, MetadataOp); 6] = [
+        ("!=", MetadataOp::Ne),
+        (">=", MetadataOp::Ge),
+        ("<=", MetadataOp::Le),
+        ("=", MetadataOp::Eq),
+        (">", MetadataOp::Gt),
+        ("<", MetadataOp::Lt),
+    ];
+
+    fn jsonpath(self) -> &'static str {
+        match self {
+            MetadataOp::Exists => "",
+            MetadataOp::Eq => "==",
+            MetadataOp::Ne => "!=",
+            MetadataOp::Lt => "<",
+            MetadataOp::Le => "<=",
+            MetadataOp::Gt => ">",
+            MetadataOp::Ge => ">=",
+        }
+    }
+}
+
+/// A condition on a blob's metadata
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetadataFilter {
+    /// Keys from the top of the metadata object down
+    pub path: Vec<String>,
+    pub op: MetadataOp,
+    /// Value compared against; `Null` for `Exists`
+    pub value: serde_json::Value,
+}
+
+impl MetadataFilter {
+    /// Parse a filter such as `av.duration > 60`
+    ///
+    /// The path may start with `metadata.`. Values are JSON literals; other
+    /// text is taken as a string, so `image.format = png` works. A path on its
+    /// own matches blobs where it exists.
+    pub fn parse(filter: &str) -> Result<Self, SearchError> {
+        let invalid = |reason: &str| SearchError::InvalidFilter {
+            filter: filter.to_string(),
+            reason: reason.to_string(),
+        };
+
+        let (path, op, value) = match filter
+            .find(['=', '!', '<', '>'])
+            .map(|at| filter.split_at(at))
+        {
+            Some((path, rest)) => {
+                let (syntax, op) = MetadataOp::SYNTAX
+                    .iter()
+                    .find(|(syntax, _)| rest.starts_with(syntax))
+                    .ok_or_else(|| invalid("unknown operator"))?;
+                let value = rest[syntax.len()..].trim();
+                if value.is_empty() {
+                    return Err(invalid("missing value"));
+                }
+                let value = serde_json::from_str(value)
+                    .unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+                (path, *op, value)
+            }
+            None => (filter, MetadataOp::Exists, serde_json::Value::Null),
+        };
+
+        let path = path.trim();
+        let path = path.strip_prefix("metadata.").unwrap_or(path);
+        let path: Vec<String> = path.split('.').map(str::to_string).collect();
+        if path.len() > MAX_METADATA_PATH_DEPTH {
+            return Err(invalid("path is too deep"));
+        }
+        if path.iter().any(|key| {
+            key.is_empty()
+                || !key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        }) {
+            return Err(invalid(
+                "keys may only contain letters, digits, '_' and '-'",
+            ));
+        }
+
+        match (&value, op) {
+            (serde_json::Value::Array(_) | serde_json::Value::Object(_), _) => {
+                return Err(invalid("values must be strings, numbers, booleans or null"))
+            }
+            (
+                serde_json::Value::Bool(_) | serde_json::Value::Null,
+                MetadataOp::Lt | MetadataOp::Le | MetadataOp::Gt | MetadataOp::Ge,
+            ) => return Err(invalid("only numbers and strings can be ordered")),
+            _ => {}
+        }
+
+        Ok(Self { path, op, value })
+    }
+
+    /// SQL/JSON path matching blobs that satisfy the filter
+    ///
+    /// Keys are validated by [`MetadataFilter::parse`], and values are
+    /// serialized JSON scalars, which are valid path literals.
+    pub fn jsonpath(&self) -> String {
+        let mut path = String::from("$");
+        for key in &self.path {
+            path.push_str(&format!(".\"{}\"", key));
+        }
+        if self.op != MetadataOp::Exists {
+            path.push_str(&format!(" ? (@ {} {})", self.op.jsonpath(), self.value));
+        }
+        path
+    }
+}
+
+/// Position after the last blob of a page, in search order
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SearchCursor {
+    /// Text search rank; 0 when searching without text
+    pub rank: f32,
+    pub created_at: OffsetDateTime,
+    pub id: Uuid,
+}
+
+impl SearchCursor {
+    /// Encode as an opaque string
+    pub fn encode(&self) -> String {
+        format!(
+            "{}:{}:{}",
+            self.rank,
+            self.created_at.unix_timestamp_nanos(),
+            self.id.simple()
+        )
+    }
+
+    /// Decode a cursor returned by [`SearchCursor::encode`]
+    pub fn decode(cursor: &str) -> Result<Self, SearchError> {
+        let mut parts = cursor.splitn(3, ':');
+        let (Some(rank), Some(created_at), Some(id)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(SearchError::InvalidCursor);
+        };
+
+        let rank: f32 = rank.parse().map_err(|_| SearchError::InvalidCursor)?;
+        let created_at = created_at
+            .parse::<i128>()
+            .ok()
+            .and_then(|nanos| OffsetDateTime::from_unix_timestamp_nanos(nanos).ok())
+            .ok_or(SearchError::InvalidCursor)?;
+        let id = Uuid::parse_str(id).map_err(|_| SearchError::InvalidCursor)?;
+        if !rank.is_finite() {
+            return Err(SearchError::InvalidCursor);
+        }
+
+        Ok(Self {
+            rank,
+            created_at,
+            id,
+        })
+    }
+}
+
+/// A validated search, ready to run
+#[derive(Debug, Clone)]
+pub struct PreparedSearch {
+    pub text: Option<String>,
+    pub tags: Vec<String>,
+    pub mime: Option<String>,
+    pub min_size: Option<i64>,
+    pub max_size: Option<i64>,
+    pub created_after: Option<OffsetDateTime>,
+    pub created_before: Option<OffsetDateTime>,
+    /// SQL/JSON paths every blob's metadata must match
+    pub metadata_paths: Vec<String>,
+    pub limit: i64,
+    pub after: Option<SearchCursor>,
+    /// Only return blobs this user may view (None = no visibility filtering)
+    pub visible_to: Option<Uuid>,
+}
+
+impl MediaSearch {
+    /// Validate the search and compile its filters
+    pub fn prepare(&self) -> Result<PreparedSearch, SearchError> {
+        let text = self
+            .q
+            .as_deref()
+            .map(str::trim)
+            .filter(|q| !q.is_empty())
+            .map(str::to_string);
+        if text
+            .as_ref()
+            .is_some_and(|q| q.chars().count() > MAX_QUERY_LENGTH)
+        {
+            return Err(SearchError::Invalid(format!(
+                "queries must be at most {} characters long",
+                MAX_QUERY_LENGTH
+            )));
+        }
+
+        let tags = normalize_tags(&self.tags).map_err(SearchError::Invalid)?;
+
+        if self.min_size.is_some_and(|size| size < 0) || self.max_size.is_some_and(|size| size < 0)
+        {
+            return Err(SearchError::Invalid(
+                "sizes must not be negative".to_string(),
+            ));
+        }
+        if let (Some(min), Some(max)) = (self.min_size, self.max_size) {
+            if min > max {
+                return Err(SearchError::Invalid(
+                    "min_size must not exceed max_size".to_string(),
+                ));
+            }
+        }
+        if let (Some(after), Some(before)) = (self.created_after, self.created_before) {
+            if after > before {
+                return Err(SearchError::Invalid(
+                    "created_after must not be later than created_before".to_string(),
+                ));
+            }
+        }
+
+        if self.metadata.len() > MAX_METADATA_FILTERS {
+            return Err(SearchError::Invalid(format!(
+                "at most {} metadata filters are allowed",
+                MAX_METADATA_FILTERS
+            )));
+        }
+        let metadata_paths = self
+            .metadata
+            .iter()
+            .map(|filter| MetadataFilter::parse(filter).map(|filter| filter.jsonpath()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let after = self
+            .cursor
+            .as_deref()
+            .map(SearchCursor::decode)
+            .transpose()?;
+
+        Ok(PreparedSearch {
+            text: text
+                .as_deref()
+                .map(search_words)
+                .filter(|words| !words.trim_matches(['-', '"', ' ']).is_empty()),
+            tags,
+            mime: self
+                .mime
+                .as_deref()
+                .map(str::trim)
+                .filter(|mime| !mime.is_empty())
+                .map(str::to_lowercase),
+            min_size: self.min_size,
+            max_size: self.max_size,
+            created_after: self.created_after,
+            created_before: self.created_before,
+            metadata_paths,
+            limit: self.limit.unwrap_or(50).clamp(1, 1000),
+            after,
+            visible_to: None,
+        })
+    }
+}
+
+/// Split query words the way file names are split in the search document
+///
+/// Punctuation inside words becomes a space, so `beach.jpg` finds a file
+/// named `IMG_01-beach.jpg`. Quotes and a leading `-` keep their meaning.
+fn search_words(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| {
+            let (exclude, word) = match word.strip_prefix('-') {
+                Some(word) => ("-", word),
+                None => ("", word),
+            };
+            let word: String = word
+                .chars()
+                .map(|c| {
+                    if c.is_alphanumeric() || c == '"' {
+                        c
+                    } else {
+                        ' '
+                    }
+                })
+                .collect();
+            format!("{}{}", exclude, word.trim())
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_filter_parse() {
+        let filter = MetadataFilter::parse("metadata.av.duration > 60").unwrap();
+        assert_eq!(filter.path, vec!["av", "duration"]);
+        assert_eq!(filter.op, MetadataOp::Gt);
+        assert_eq!(filter.jsonpath(), r#"$."av"."duration" ? (@ > 60)"#);
+
+        let filter = MetadataFilter::parse("image.format=png").unwrap();
+        assert_eq!(filter.jsonpath(), r#"$."image"."format" ? (@ == "png")"#);
+
+        let filter = MetadataFilter::parse(r#"camera != "a \"b\"""#).unwrap();
+        assert_eq!(filter.value, serde_json::json!("a \"b\""));
+        assert_eq!(filter.jsonpath(), r#"$."camera" ? (@ != "a \"b\"")"#);
+
+        assert_eq!(
+            MetadataFilter::parse("av.width >= 1920").unwrap().op,
+            MetadataOp::Ge
+        );
+        assert_eq!(MetadataFilter::parse("av").unwrap().jsonpath(), r#"$."av""#);
+    }
+
+    #[test]
+    fn test_metadata_filter_rejects() {
+        for filter in [
+            "",
+            "av..duration > 1",
+            "av.\"x\" = 1",
+            "av.duration >",
+            "av.duration ! 1",
+            "av.duration > [1]",
+            "flag > true",
+            "a.b.c.d.e.f.g.h.i = 1",
+        ] {
+            assert!(
+                MetadataFilter::parse(filter).is_err(),
+                "accepted {:?}",
+                filter
+            );
+        }
+    }
+
+    #[test]
+    fn test_search_words() {
+        assert_eq!(search_words("IMG_01-beach.jpg"), "IMG 01 beach jpg");
+        assert_eq!(
+            search_words(r#""sunset beach" -video or photo"#),
+            r#""sunset beach" -video or photo"#
+        );
+        assert_eq!(search_words("-old_file"), "-old file");
+    }
+
+    #[test]
+    fn test_cursor_roundtrip() {
+        let cursor = SearchCursor {
+            rank: 0.0607927,
+            created_at: OffsetDateTime::from_unix_timestamp_nanos(1_760_789_000_123_456_000)
+                .unwrap(),
+            id: Uuid::new_v4(),
+        };
+        assert_eq!(SearchCursor::decode(&cursor.encode()).unwrap(), cursor);
+
+        for cursor in ["", "1:2", "x:1:00000000000000000000000000000000", "NaN:1:0"] {
+            assert_eq!(
+                SearchCursor::decode(cursor),
+                Err(SearchError::InvalidCursor)
+            );
+        }
+    }
+
+    #[test]
+    fn test_prepare_validates_ranges() {
+        let search = MediaSearch {
+            q: Some("  ".to_string()),
+            tags: vec!["Beach".to_string()],
+            mime: Some("Image/".to_string()),
+            limit: Some(5000),
+            ..Default::default()
+        };
+        let prepared = search.prepare().unwrap();
+        assert_eq!(prepared.text, None);
+        assert_eq!(prepared.tags, vec!["beach"]);
+        assert_eq!(prepared.mime.as_deref(), Some("image/"));
+        assert_eq!(prepared.limit, 1000);
+
+        let search = MediaSearch {
+            min_size: Some(10),
+            max_size: Some(5),
+            ..Default::default()
+        };
+        assert!(search.prepare().is_err());
+
+        let search = MediaSearch {
+            metadata: vec!["x".to_string(); MAX_METADATA_FILTERS + 1],
+            ..Default::default()
+        };
+        assert!(search.prepare().is_err());
+    }
+}
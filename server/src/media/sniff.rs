@@ -0,0 +1,564 @@
+//! Content type detection from magic bytes
+//!
+//! Uploads declare a MIME type, or have one inferred from the filename; both
+//! come from the client. The leading bytes of the content are checked against
+//! known signatures, and uploads whose declared type names a different kind
+//! of content are rejected before they are stored.
+//!
+//! Types are compared by family, so common aliases such as `audio/x-wav` for
+//! `audio/wav`, or `audio/mp4` for an MP4 container, are accepted.
+
+use crate::config::MimeTypeRules;
+
+/// How many leading bytes are inspected
+pub const SNIFF_LEN: usize = 4096;
+
+/// Recorded for content that matches no known signature
+pub const UNRECOGNISED: &str = "application/octet-stream";
+
+/// Detected types and the declared types accepted for them
+///
+/// Families marked weak have signatures that may not appear at the very start
+/// of valid files, so unrecognised content declared as one is accepted.
+const FAMILIES: &[(&[&str], bool)] = &[
+    (&["image/png", "image/apng"], false),
+    (&["image/jpeg", "image/jpg", "image/pjpeg"], false),
+    (&["image/gif"], false),
+    (&["image/webp"], false),
+    (&["image/bmp", "image/x-bmp", "image/x-ms-bmp"], false),
+    (&["image/tiff"], false),
+    (&["image/x-icon", "image/vnd.microsoft.icon"], false),
+    (
+        &[
+            "image/heic",
+            "image/heif",
+            "image/heic-sequence",
+            "image/heif-sequence",
+        ],
+        false,
+    ),
+    (&["image/avif"], false),
+    (
+        &[
+            "video/mp4",
+            "video/quicktime",
+            "video/x-m4v",
+            "video/3gpp",
+            "video/3gpp2",
+            "audio/mp4",
+            "audio/m4a",
+            "audio/x-m4a",
+            "audio/3gpp",
+            "application/mp4",
+        ],
+        false,
+    ),
+    (
+        &[
+            "video/webm",
+            "audio/webm",
+            "video/x-matroska",
+            "audio/x-matroska",
+            "video/matroska",
+            "audio/matroska",
+        ],
+        false,
+    ),
+    (
+        &[
+            "audio/ogg",
+            "video/ogg",
+            "application/ogg",
+            "audio/opus",
+            "audio/vorbis",
+            "audio/x-ogg",
+        ],
+        false,
+    ),
+    (
+        &["audio/wav", "audio/x-wav", "audio/wave", "audio/vnd.wave"],
+        false,
+    ),
+    (&["video/x-msvideo", "video/avi", "video/msvideo"], false),
+    (&["audio/flac", "audio/x-flac"], false),
+    (
+        &[
+            "audio/mpeg",
+            "audio/mp3",
+            "audio/x-mp3",
+            "audio/mpeg3",
+            "audio/x-mpeg",
+        ],
+        true,
+    ),
+    (&["audio/aac", "audio/x-aac", "audio/aacp"], true),
+    (&["application/pdf", "application/x-pdf"], false),
+    (
+        &[
+            "application/zip",
+            "application/x-zip-compressed",
+            "application/epub+zip",
+            "application/java-archive",
+            "application/vnd.android.package-archive",
+        ],
+        false,
+    ),
+    (&["application/gzip", "application/x-gzip"], false),
+    (&["application/x-7z-compressed"], false),
+    (
+        &["application/vnd.rar", "application/x-rar-compressed"],
+        false,
+    ),
+    (&["application/x-xz"], false),
+    (
+        &[
+            "application/x-msdownload",
+            "application/vnd.microsoft.portable-executable",
+            "application/x-dosexec",
+            "application/x-msdos-program",
+        ],
+        false,
+    ),
+    (
+        &[
+            "application/x-executable",
+            "application/x-elf",
+            "application/x-sharedlib",
+        ],
+        false,
+    ),
+    (&["application/x-mach-binary"], false),
+    (&["application/wasm"], false),
+];
+
+/// Detected types that are text; any textual declared type is accepted for them
+const TEXT_TYPES: [&str; 3] = ["text/plain", "text/html", "image/svg+xml"];
+
+/// Declared types outside `text/` that hold text
+const TEXTUAL_APPLICATION_TYPES: [&str; 10] = [
+    "application/json",
+    "application/xml",
+    "application/javascript",
+    "application/ecmascript",
+    "application/x-ndjson",
+    "application/yaml",
+    "application/x-yaml",
+    "application/toml",
+    "application/sql",
+    "application/x-sh",
+];
+
+/// Why an upload's content type was refused
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ContentTypeError {
+    #[error("Declared type {declared} does not match the content ({detected})")]
+    Mismatch { declared: String, detected: String },
+    #[error("Content type {0} is not allowed")]
+    NotAllowed(String),
+}
+
+/// What is known about the actual type of some content
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Detection<'a> {
+    /// Type recognised from the leading bytes, or `None` if unrecognised
+    Sniffed(Option<&'a str>),
+    /// Content stored before types were recorded; only the declared type is checked
+    Unchecked,
+}
+
+impl<'a> Detection<'a> {
+    /// Detection from a type recorded on a stored object
+    pub fn from_recorded(content_type: Option<&'a str>) -> Self {
+        match content_type {
+            Some(UNRECOGNISED) => Detection::Sniffed(None),
+            Some(content_type) => Detection::Sniffed(Some(content_type)),
+            None => Detection::Unchecked,
+        }
+    }
+}
+
+/// Recognise content from its leading bytes
+pub fn detect_content_type(head: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| head.get(offset..offset + magic.len()) == Some(magic);
+
+    let detected = if at(0, b"\x89PNG\r\n\x1a\n") {
+        "image/png"
+    } else if at(0, b"\xff\xd8\xff") {
+        "image/jpeg"
+    } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        "image/gif"
+    } else if at(0, b"RIFF") && at(8, b"WEBP") {
+        "image/webp"
+    } else if at(0, b"RIFF") && at(8, b"WAVE") {
+        "audio/wav"
+    } else if at(0, b"RIFF") && at(8, b"AVI ") {
+        "video/x-msvideo"
+    } else if at(0, b"II*\0") || at(0, b"MM\0*") {
+        "image/tiff"
+    } else if at(0, b"BM") && at(6, &[0, 0, 0, 0]) {
+        "image/bmp"
+    } else if at(0, &[0, 0, 1, 0]) && head.get(4).is_some_and(|count| *count > 0) {
+        "image/x-icon"
+    } else if at(4, b"ftyp") {
+        detect_ftyp(head)
+    } else if at(0, b"\x1a\x45\xdf\xa3") {
+        if contains(head, b"\x42\x82\x84webm") {
+            "video/webm"
+        } else {
+            "video/x-matroska"
+        }
+    } else if at(0, b"OggS") {
+        if contains(head, b"\x80theora") {
+            "video/ogg"
+        } else {
+            "audio/ogg"
+        }
+    } else if at(0, b"fLaC") {
+        "audio/flac"
+    } else if at(0, b"ID3") {
+        "audio/mpeg"
+    } else if head.len() >= 2 && head[0] == 0xff && head[1] & 0xe0 == 0xe0 {
+        // MPEG audio frame sync; layer bits of zero mark AAC in ADTS framing
+        match (head[1] >> 1) & 0x3 {
+            0 if head[1] & 0xf6 == 0xf0 => "audio/aac",
+            0 => return None,
+            _ => "audio/mpeg",
+        }
+    } else if at(0, b"%PDF-") {
+        "application/pdf"
+    } else if at(0, b"PK\x03\x04") || at(0, b"PK\x05\x06") {
+        "application/zip"
+    } else if at(0, b"\x1f\x8b") {
+        "application/gzip"
+    } else if at(0, b"7z\xbc\xaf\x27\x1c") {
+        "application/x-7z-compressed"
+    } else if at(0, b"Rar!\x1a\x07") {
+        "application/vnd.rar"
+    } else if at(0, b"\xfd7zXZ\0") {
+        "application/x-xz"
+    } else if at(0, b"MZ") {
+        "application/x-msdownload"
+    } else if at(0, b"\x7fELF") {
+        "application/x-executable"
+    } else if [
+        b"\xfe\xed\xfa\xce",
+        b"\xfe\xed\xfa\xcf",
+        b"\xce\xfa\xed\xfe",
+        b"\xcf\xfa\xed\xfe",
+    ]
+    .iter()
+    .any(|magic| at(0, *magic))
+    {
+        "application/x-mach-binary"
+    } else if at(0, b"\0asm") {
+        "application/wasm"
+    } else {
+        return detect_text(head);
+    };
+
+    Some(detected)
+}
+
+/// Tell ISO base media files apart by their major and compatible brands
+fn detect_ftyp(head: &[u8]) -> &'static str {
+    let len = u32::from_be_bytes(head[0..4].try_into().unwrap()) as usize;
+    let brands = head.get(8..len.clamp(8, head.len())).unwrap_or_default();
+    let has = |brand: &[u8; 4]| brands.chunks(4).any(|chunk| chunk == brand);
+
+    if has(b"avif") || has(b"avis") {
+        "image/avif"
+    } else if has(b"heic") || has(b"heix") || has(b"mif1") || has(b"msf1") {
+        "image/heic"
+    } else if brands.starts_with(b"qt  ") {
+        "video/quicktime"
+    } else if brands.starts_with(b"M4A ") {
+        "audio/mp4"
+    } else {
+        "video/mp4"
+    }
+}
+
+/// Recognise text: no NUL bytes and few control characters
+fn detect_text(head: &[u8]) -> Option<&'static str> {
+    // UTF-16 text is full of NUL bytes, but has a byte order mark
+    if head.starts_with(b"\xff\xfe") || head.starts_with(b"\xfe\xff") {
+        return Some("text/plain");
+    }
+
+    let text = head.strip_prefix(b"\xef\xbb\xbf").unwrap_or(head);
+    if text.is_empty() {
+        return None;
+    }
+
+    let control = text
+        .iter()
+        .filter(|byte| **byte < 0x20 && !matches!(byte, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b))
+        .count();
+    if text.contains(&0) || control * 100 > text.len() {
+        return None;
+    }
+
+    let start = String::from_utf8_lossy(&text[..text.len().min(512)]).to_ascii_lowercase();
+    let start = start.trim_start();
+    if start.starts_with("<svg") || (start.starts_with("<?xml") && start.contains("<svg")) {
+        Some("image/svg+xml")
+    } else if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        Some("text/html")
+    } else {
+        Some("text/plain")
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+/// Lowercase a MIME type and drop its parameters
+pub fn normalize_mime(mime: &str) -> String {
+    mime.split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Whether content detected as `detected` may be declared as `declared`
+pub fn content_type_matches(declared: &str, detected: Option<&str>) -> bool {
+    let declared = normalize_mime(declared);
+    if declared == UNRECOGNISED {
+        return true;
+    }
+
+    match detected {
+        Some(detected) if TEXT_TYPES.contains(&detected) => is_textual(&declared),
+        Some(detected) => {
+            family(detected).is_some_and(|(types, _)| types.contains(&declared.as_str()))
+                || (detected == "application/zip" && is_zip_based(&declared))
+        }
+        // Content we cannot recognise may be anything we have no signature for
+        None => match family(&declared) {
+            Some((_, weak)) => weak,
+            None => !is_textual(&declared) && !is_zip_based(&declared),
+        },
+    }
+}
+
+fn family(mime: &str) -> Option<(&'static [&'static str], bool)> {
+    FAMILIES
+        .iter()
+        .find(|(types, _)| types.contains(&mime))
+        .copied()
+}
+
+fn is_textual(mime: &str) -> bool {
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || TEXTUAL_APPLICATION_TYPES.contains(&mime)
+}
+
+/// Office documents and other formats packaged as ZIP archives
+fn is_zip_based(mime: &str) -> bool {
+    mime.ends_with("+zip")
+        || mime.starts_with("application/vnd.openxmlformats-officedocument.")
+        || mime.starts_with("application/vnd.oasis.opendocument.")
+        || family("application/zip").is_some_and(|(types, _)| types.contains(&mime))
+}
+
+/// Check an upload's declared type against its content and the allow/deny rules
+///
+/// Returns the type to record: the declared one, or the detected one when
+/// nothing specific was declared.
+pub fn check_content_type(
+    declared: Option<&str>,
+    detection: Detection<'_>,
+    rules: &MimeTypeRules,
+) -> Result<Option<String>, ContentTypeError> {
+    let declared = declared
+        .map(normalize_mime)
+        .filter(|mime| !mime.is_empty() && mime != UNRECOGNISED);
+    let detected = match detection {
+        Detection::Sniffed(detected) => detected,
+        Detection::Unchecked => None,
+    };
+
+    if let (Some(declared), Detection::Sniffed(_)) = (&declared, detection) {
+        if !content_type_matches(declared, detected) {
+            return Err(ContentTypeError::Mismatch {
+                declared: declared.clone(),
+                detected: detected.unwrap_or("unrecognised content").to_string(),
+            });
+        }
+    }
+
+    // A harmless declared type must not get denied content past the rules
+    if let Some(detected) = detected.filter(|detected| rules.denies(detected)) {
+        return Err(ContentTypeError::NotAllowed(detected.to_string()));
+    }
+
+    let effective = declared.or_else(|| detected.map(str::to_string));
+    let checked = effective.as_deref().unwrap_or(UNRECOGNISED);
+    if !rules.permits(checked) {
+        return Err(ContentTypeError::NotAllowed(checked.to_string()));
+    }
+
+    Ok(effective)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(allow: &[&str], deny: &[&str]) -> MimeTypeRules {
+        MimeTypeRules {
+            allow: allow.iter().map(|s| s.to_string()).collect(),
+            deny: deny.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_detect_content_type() {
+        let cases: [(&[u8], Option<&str>); 14] = [
+            (b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", Some("image/png")),
+            (b"\xff\xd8\xff\xe0\0\x10JFIF", Some("image/jpeg")),
+            (b"RIFF\0\0\0\0WEBPVP8 ", Some("image/webp")),
+            (b"RIFF\0\0\0\0WAVEfmt ", Some("audio/wav")),
+            (b"\0\0\0\x1cftypisom\0\0\x02\0isomavc1", Some("video/mp4")),
+            (b"\0\0\0\x14ftypqt  \0\0\x02\0qt  ", Some("video/quicktime")),
+            (b"\0\0\0\x18ftypmif1\0\0\0\0mif1heic", Some("image/heic")),
+            (
+                b"\x1a\x45\xdf\xa3\x9f\x42\x86\x81\x01\x42\x82\x84webm",
+                Some("video/webm"),
+            ),
+            (b"ID3\x04\0\0\0\0\0\0", Some("audio/mpeg")),
+            (b"%PDF-1.7\n", Some("application/pdf")),
+            (b"MZ\x90\0\x03\0\0\0", Some("application/x-msdownload")),
+            (
+                b"  <svg xmlns=\"http://www.w3.org/2000/svg\">",
+                Some("image/svg+xml"),
+            ),
+            (b"hello, world\n", Some("text/plain")),
+            (b"\0\x01\x02\x03binary", None),
+        ];
+
+        for (head, expected) in cases {
+            assert_eq!(detect_content_type(head), expected, "{:?}", head);
+        }
+    }
+
+    #[test]
+    fn test_content_type_matches() {
+        assert!(content_type_matches("image/png", Some("image/png")));
+        assert!(content_type_matches("IMAGE/JPG", Some("image/jpeg")));
+        assert!(content_type_matches("audio/x-wav", Some("audio/wav")));
+        assert!(content_type_matches("audio/mp4", Some("video/mp4")));
+        assert!(content_type_matches(
+            "application/json; charset=utf-8",
+            Some("text/plain")
+        ));
+        assert!(content_type_matches(
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+            Some("application/zip")
+        ));
+        assert!(content_type_matches(
+            "application/octet-stream",
+            Some("application/pdf")
+        ));
+
+        assert!(!content_type_matches(
+            "image/png",
+            Some("application/x-msdownload")
+        ));
+        assert!(!content_type_matches("text/plain", Some("image/png")));
+        assert!(!content_type_matches("image/svg+xml", Some("image/png")));
+
+        // Unrecognised content only fits types without a reliable signature
+        assert!(!content_type_matches("image/png", None));
+        assert!(!content_type_matches("text/plain", None));
+        assert!(content_type_matches("audio/mpeg", None));
+        assert!(content_type_matches("application/x-custom", None));
+    }
+
+    #[test]
+    fn test_check_content_type() {
+        let open = MimeTypeRules::default();
+        let png = Detection::Sniffed(Some("image/png"));
+
+        assert_eq!(
+            check_content_type(Some("image/png"), png, &open),
+            Ok(Some("image/png".to_string()))
+        );
+        // Without a declared type the detected one is recorded
+        assert_eq!(
+            check_content_type(None, png, &open),
+            Ok(Some("image/png".to_string()))
+        );
+        assert_eq!(
+            check_content_type(Some("application/octet-stream"), png, &open),
+            Ok(Some("image/png".to_string()))
+        );
+        assert_eq!(
+            check_content_type(None, Detection::Sniffed(None), &open),
+            Ok(None)
+        );
+        assert_eq!(
+            check_content_type(Some("image/gif"), png, &open),
+            Err(ContentTypeError::Mismatch {
+                declared: "image/gif".to_string(),
+                detected: "image/png".to_string()
+            })
+        );
+
+        // Content stored before detection is only checked against the rules
+        assert_eq!(
+            check_content_type(Some("image/gif"), Detection::Unchecked, &open),
+            Ok(Some("image/gif".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_check_content_type_rules() {
+        let images = rules(&["image/*"], &["image/svg+xml"]);
+        let png = Detection::Sniffed(Some("image/png"));
+
+        assert!(check_content_type(Some("image/png"), png, &images).is_ok());
+        assert_eq!(
+            check_content_type(
+                Some("image/svg+xml"),
+                Detection::Sniffed(Some("image/svg+xml")),
+                &images
+            ),
+            Err(ContentTypeError::NotAllowed("image/svg+xml".to_string()))
+        );
+        assert_eq!(
+            check_content_type(None, Detection::Sniffed(None), &images),
+            Err(ContentTypeError::NotAllowed(UNRECOGNISED.to_string()))
+        );
+
+        // Denied content is caught whatever type it is declared as
+        let no_executables = rules(&[], &["application/x-msdownload"]);
+        assert_eq!(
+            check_content_type(
+                Some("application/x-custom"),
+                Detection::Sniffed(Some("application/x-msdownload")),
+                &no_executables
+            ),
+            Err(ContentTypeError::Mismatch {
+                declared: "application/x-custom".to_string(),
+                detected: "application/x-msdownload".to_string()
+            })
+        );
+        assert_eq!(
+            check_content_type(
+                Some("application/octet-stream"),
+                Detection::Sniffed(Some("application/x-msdownload")),
+                &no_executables
+            ),
+            Err(ContentTypeError::NotAllowed(
+                "application/x-msdownload".to_string()
+            ))
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Media statistics in the Prometheus text format
+//!
+//! Only totals are exported: per-user and per-client breakdowns would give
+//! every user and client a time series of its own. MIME types beyond the
+//! most common are added up under `other`.
+
+use std::fmt::Write;
+
+use super::models::MediaBlobStats;
+
+/// MIME types exported with their own label
+pub const PROMETHEUS_MIME_TYPES: usize = 20;
+
+/// Render media statistics as Prometheus gauges
+pub fn render_prometheus(stats: &MediaBlobStats) -> String {
+    let mut out = String::new();
+
+    gauge(
+        &mut out,
+        "webauthn_media_blobs",
+        "Uploaded media blobs",
+        &[(String::new(), stats.total_count)],
+    );
+    gauge(
+        &mut out,
+        "webauthn_media_blob_bytes",
+        "Total size of uploaded media blobs",
+        &[(String::new(), stats.total_size.unwrap_or(0))],
+    );
+    gauge(
+        &mut out,
+        "webauthn_media_unique_contents",
+        "Distinct contents among uploaded media blobs",
+        &[(String::new(), stats.unique_sha256_count)],
+    );
+    gauge(
+        &mut out,
+        "webauthn_media_derived_blobs",
+        "Derived media blobs such as thumbnails",
+        &[(String::new(), stats.derived_count)],
+    );
+    gauge(
+        &mut out,
+        "webauthn_media_derived_bytes",
+        "Total size of derived media blobs",
+        &[(String::new(), stats.derived_size)],
+    );
+
+    let backend = |value: fn(&super::models::StorageBackendStats) -> i64| {
+        stats
+            .storage
+            .iter()
+            .map(|backend| (label("backend", &backend.backend), value(backend)))
+            .collect::<Vec<_>>()
+    };
+    gauge(
+        &mut out,
+        "webauthn_media_stored_objects",
+        "Stored media contents by storage backend",
+        &backend(|backend| backend.object_count),
+    );
+    gauge(
+        &mut out,
+        "webauthn_media_stored_bytes",
+        "Stored media bytes by storage backend",
+        &backend(|backend| backend.size),
+    );
+    gauge(
+        &mut out,
+        "webauthn_media_duplicate_savings_bytes",
+        "Bytes not stored twice because media blobs share content",
+        &[(String::new(), stats.duplicate_savings)],
+    );
+    gauge(
+        &mut out,
+        "webauthn_media_unreferenced_bytes",
+        "Stored media bytes awaiting garbage collection",
+        &[(String::new(), stats.unreferenced_size)],
+    );
+
+    let mut by_mime: Vec<(String, i64)> = stats
+        .mime_type_distribution
+        .iter()
+        .take(PROMETHEUS_MIME_TYPES)
+        .map(|mime| {
+            (
+                label("mime", mime.mime_type.as_deref().unwrap_or("unknown")),
+                mime.count,
+            )
+        })
+        .collect();
+    let other: i64 = stats
+        .mime_type_distribution
+        .iter()
+        .skip(PROMETHEUS_MIME_TYPES)
+        .map(|mime| mime.count)
+        .sum();
+    if other > 0 {
+        by_mime.push((label("mime", "other"), other));
+    }
+    gauge(
+        &mut out,
+        "webauthn_media_blobs_by_mime",
+        "Uploaded media blobs by MIME type",
+        &by_mime,
+    );
+
+    out
+}
+
+/// Write one gauge with a sample per label set
+fn gauge(out: &mut String, name: &str, help: &str, samples: &[(String, i64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+/// Format a single label, escaping its value
+fn label(name: &str, value: &str) -> String {
+    let value = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("{{{}=\"{}\"}}", name, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::models::{MimeTypeCount, StorageBackendStats};
+
+    #[test]
+    fn test_render_prometheus() {
+        let mut mime_type_distribution: Vec<MimeTypeCount> = (0..PROMETHEUS_MIME_TYPES + 2)
+            .map(|i| MimeTypeCount {
+                mime_type: Some(format!("type/{}", i)),
+                count: 1,
+                size: 10,
+            })
+            .collect();
+        mime_type_distribution[0].mime_type = None;
+        mime_type_distribution[1].mime_type = Some("odd\"type".to_string());
+
+        let stats = MediaBlobStats {
+            total_count: 22,
+            total_size: Some(220),
+            unique_sha256_count: 20,
+            mime_type_distribution,
+            derived_count: 0,
+            derived_size: 0,
+            storage: vec![StorageBackendStats {
+                backend: "filesystem".to_string(),
+                object_count: 20,
+                size: 200,
+            }],
+            duplicate_savings: 20,
+            unreferenced_size: 0,
+            top_users: vec![],
+            top_clients: vec![],
+            growth: vec![],
+        };
+
+        let text = render_prometheus(&stats);
+        assert!(text.contains("# TYPE webauthn_media_blobs gauge\nwebauthn_media_blobs 22\n"));
+        assert!(text.contains("webauthn_media_stored_bytes{backend=\"filesystem\"} 200\n"));
+        assert!(text.contains("webauthn_media_duplicate_savings_bytes 20\n"));
+        assert!(text.contains("webauthn_media_blobs_by_mime{mime=\"unknown\"} 1\n"));
+        assert!(text.contains("webauthn_media_blobs_by_mime{mime=\"odd\\\"type\"} 1\n"));
+        assert!(text.contains("webauthn_media_blobs_by_mime{mime=\"other\"} 2\n"));
+    }
+}
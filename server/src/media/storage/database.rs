@@ -0,0 +1,126 @@
+//! Database storage backend keeping content in the `media_object_data` table
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures_util::stream::{self, StreamExt};
+
+use super::{BlobStorage, BlobStorageError, ContentStream};
+use crate::config::MediaStorageBackend;
+use crate::database::DatabaseConnection;
+
+/// Content is read in pieces of this size, so large objects are never held
+/// in memory whole
+const READ_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Stores content in PostgreSQL, keyed by SHA256
+pub struct DatabaseBlobStorage {
+    db: DatabaseConnection,
+}
+
+impl DatabaseBlobStorage {
+    pub fn new(db: DatabaseConnection) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait]
+impl BlobStorage for DatabaseBlobStorage {
+    fn backend(&self) -> MediaStorageBackend {
+        MediaStorageBackend::Database
+    }
+
+    fn key_for(&self, sha256: &str) -> String {
+        sha256.to_string()
+    }
+
+    async fn put(&self, key: &str, bytes: &[u8]) -> Result<(), BlobStorageError> {
+        sqlx::query!(
+            r#"
+            INSERT INTO media_object_data (sha256, data)
+            VALUES ($1, $2)
+            ON CONFLICT (sha256) DO UPDATE SET data = EXCLUDED.data
+            "#,
+            key,
+            bytes
+        )
+        .execute(self.db.pool())
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, BlobStorageError> {
+        let data = sqlx::query_scalar!("SELECT data FROM media_object_data WHERE sha256 = $1", key)
+            .fetch_optional(self.db.pool())
+            .await?;
+
+        Ok(data)
+    }
+
+    async fn get_range(
+        &self,
+        key: &str,
+        start: u64,
+        length: u64,
+    ) -> Result<Option<ContentStream>, BlobStorageError> {
+        if !self.exists(key).await? {
+            return Ok(None);
+        }
+
+        let db = self.db.clone();
+        let key = key.to_string();
+        let chunks = stream::try_unfold(0, move |read| {
+            let db = db.clone();
+            let key = key.clone();
+            async move {
+                if read >= length {
+                    return Ok(None);
+                }
+                let len = (length - read).min(READ_CHUNK_SIZE);
+
+                // Positions in bytea values count from 1
+                let chunk = sqlx::query_scalar!(
+                    r#"
+                    SELECT substring(data FROM $2 FOR $3) AS "chunk!"
+                    FROM media_object_data
+                    WHERE sha256 = $1
+                    "#,
+                    key,
+                    (start + read + 1) as i32,
+                    len as i32
+                )
+                .fetch_optional(db.pool())
+                .await?;
+
+                match chunk {
+                    Some(chunk) if !chunk.is_empty() => {
+                        let read = read + chunk.len() as u64;
+                        Ok(Some((Bytes::from(chunk), read)))
+                    }
+                    _ => Err(BlobStorageError::Missing(key)),
+                }
+            }
+        });
+
+        Ok(Some(chunks.boxed()))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), BlobStorageError> {
+        sqlx::query!("DELETE FROM media_object_data WHERE sha256 = $1", key)
+            .execute(self.db.pool())
+            .await?;
+
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, BlobStorageError> {
+        let exists = sqlx::query_scalar!(
+            r#"SELECT EXISTS (SELECT 1 FROM media_object_data WHERE sha256 = $1) AS "exists!""#,
+            key
+        )
+        .fetch_one(self.db.pool())
+        .await?;
+
+        Ok(exists)
+    }
+}
@@ -0,0 +1,165 @@
+//! Filesystem storage backend writing content into sharded directories
+
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use tokio::io::AsyncWriteExt;
+
+use super::{sharded_key, BlobStorage, BlobStorageError};
+use crate::config::MediaStorageBackend;
+
+/// Stores content under `root/ab/cd/<sha256>`
+///
+/// Keys are full paths (including the root), so files written before the
+/// root was changed, or by older releases, are still found.
+pub struct FilesystemBlobStorage {
+    root: PathBuf,
+}
+
+impl FilesystemBlobStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Directory new content is written below
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+#[async_trait]
+impl BlobStorage for FilesystemBlobStorage {
+    fn backend(&self) -> MediaStorageBackend {
+        MediaStorageBackend::Filesystem
+    }
+
+    fn key_for(&self, sha256: &str) -> String {
+        self.root
+            .join(sharded_key(sha256))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    async fn put(&self, key: &str, bytes: &[u8]) -> Result<(), BlobStorageError> {
+        let path = Path::new(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // Write to a temporary file first so readers never see partial content
+        let temp_path = path.with_extension(format!("{}.partial", uuid::Uuid::new_v4().simple()));
+        let result = async {
+            let mut file = tokio::fs::File::create(&temp_path).await?;
+            file.write_all(bytes).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&temp_path, path).await
+        }
+        .await;
+
+        if let Err(e) = result {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+
+    async fn put_file(
+        &self,
+        key: &str,
+        path: &Path,
+        _sha256: &str,
+    ) -> Result<(), BlobStorageError> {
+        let target = Path::new(key);
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // Linking avoids copying and leaves the file to the caller; it fails
+        // across filesystems or if the key is taken, in which case the
+        // content is copied instead
+        match tokio::fs::hard_link(path, target).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                tracing::debug!("Linking into {} failed ({}), copying instead", key, e);
+                let temp_path =
+                    target.with_extension(format!("{}.partial", uuid::Uuid::new_v4().simple()));
+                let result = async {
+                    tokio::fs::copy(path, &temp_path).await?;
+                    tokio::fs::File::open(&temp_path).await?.sync_all().await?;
+                    tokio::fs::rename(&temp_path, target).await
+                }
+                .await;
+
+                if let Err(e) = result {
+                    let _ = tokio::fs::remove_file(&temp_path).await;
+                    return Err(e.into());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, BlobStorageError> {
+        match tokio::fs::read(key).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), BlobStorageError> {
+        match tokio::fs::remove_file(key).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, BlobStorageError> {
+        Ok(tokio::fs::try_exists(key).await?)
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(PathBuf::from(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_put_get_delete() {
+        let root = std::env::temp_dir().join(format!("media-fs-{}", uuid::Uuid::new_v4()));
+        let storage = FilesystemBlobStorage::new(&root);
+        let sha256 = "0123456789abcdef".repeat(4);
+        let key = storage.key_for(&sha256);
+
+        assert!(key.ends_with(&format!("01/23/{}", sha256)));
+        assert!(!storage.exists(&key).await.unwrap());
+        assert_eq!(storage.get(&key).await.unwrap(), None);
+
+        storage.put(&key, b"hello").await.unwrap();
+        assert!(storage.exists(&key).await.unwrap());
+        assert_eq!(storage.get(&key).await.unwrap(), Some(b"hello".to_vec()));
+
+        storage.delete(&key).await.unwrap();
+        assert!(!storage.exists(&key).await.unwrap());
+        storage.delete(&key).await.unwrap();
+
+        // Files are linked into place and left for the caller
+        let staged = root.join("staged");
+        std::fs::write(&staged, b"linked").unwrap();
+        storage.put_file(&key, &staged, &sha256).await.unwrap();
+        assert!(staged.exists());
+        assert_eq!(storage.get(&key).await.unwrap(), Some(b"linked".to_vec()));
+
+        // Replacing stored content falls back to copying
+        std::fs::write(&staged, b"replaced").unwrap();
+        storage.put_file(&key, &staged, &sha256).await.unwrap();
+        assert_eq!(storage.get(&key).await.unwrap(), Some(b"replaced".to_vec()));
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+}
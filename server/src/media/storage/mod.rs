@@ -0,0 +1,312 @@
+//! Pluggable storage backends for media content
+//!
+//! Media objects are content-addressed by SHA256. Each object records the
+//! backend holding its bytes and the key they are stored under, so content
+//! written by one backend stays readable after the configured backend changes.
+//! [`MediaStorage`] writes new content to the configured backend and routes
+//! reads and deletes to whichever backend an object names.
+
+mod database;
+mod filesystem;
+mod s3;
+mod staging;
+
+pub use database::DatabaseBlobStorage;
+pub use filesystem::FilesystemBlobStorage;
+pub use s3::S3BlobStorage;
+pub use staging::{StagedFile, StagingError};
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures_util::stream::{self, BoxStream, StreamExt};
+
+use crate::config::{MediaStorageBackend, MediaStorageConfig};
+use crate::database::DatabaseConnection;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BlobStorageError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Object store returned {status}: {message}")]
+    Remote { status: u16, message: String },
+    #[error("Storage backend not configured: {0}")]
+    NotConfigured(MediaStorageBackend),
+    #[error("Invalid storage configuration: {0}")]
+    InvalidConfig(String),
+    #[error("No content stored under {0}")]
+    Missing(String),
+    #[error("Storage unavailable: {0}")]
+    Unavailable(String),
+}
+
+/// Content read from a backend as it arrives
+pub type ContentStream = BoxStream<'static, Result<Bytes, BlobStorageError>>;
+
+/// A place media content can be written to and read back from
+///
+/// Keys are chosen by the backend from the content's SHA256 and recorded on
+/// the media object, so a backend may change its layout without losing
+/// access to content it wrote earlier.
+#[async_trait]
+pub trait BlobStorage: Send + Sync {
+    /// Backend recorded on objects written by this storage
+    fn backend(&self) -> MediaStorageBackend;
+
+    /// Key new content with the given SHA256 is stored under
+    fn key_for(&self, sha256: &str) -> String;
+
+    /// Store content under `key`, replacing anything already there
+    async fn put(&self, key: &str, bytes: &[u8]) -> Result<(), BlobStorageError>;
+
+    /// Store the content of a local file whose SHA256 is `sha256` under `key`
+    ///
+    /// The file is left in place, so a caller can store it again if the
+    /// record referencing it is not created. The default reads the whole
+    /// file into memory and calls [`put`](Self::put).
+    async fn put_file(
+        &self,
+        key: &str,
+        path: &Path,
+        _sha256: &str,
+    ) -> Result<(), BlobStorageError> {
+        let bytes = tokio::fs::read(path).await?;
+        self.put(key, &bytes).await
+    }
+
+    /// Read content stored under `key`, or `None` if nothing is stored there
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, BlobStorageError>;
+
+    /// Stream `length` bytes of the content stored under `key`, starting at
+    /// `start`, or `None` if nothing is stored there
+    ///
+    /// The default reads the whole content with [`get`](Self::get); remote
+    /// backends fetch only the requested range, piece by piece.
+    async fn get_range(
+        &self,
+        key: &str,
+        start: u64,
+        length: u64,
+    ) -> Result<Option<ContentStream>, BlobStorageError> {
+        Ok(self.get(key).await?.map(|bytes| {
+            let bytes = Bytes::from(bytes);
+            let start = (start as usize).min(bytes.len());
+            let end = start.saturating_add(length as usize).min(bytes.len());
+            stream::once(async move { Ok(bytes.slice(start..end)) }).boxed()
+        }))
+    }
+
+    /// Remove content stored under `key`; missing content is not an error
+    async fn delete(&self, key: &str) -> Result<(), BlobStorageError>;
+
+    /// Check whether content is stored under `key`
+    async fn exists(&self, key: &str) -> Result<bool, BlobStorageError>;
+
+    /// Local file holding the content stored under `key`, for backends that
+    /// keep content on this machine; such content can be streamed directly
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// Spread content-addressed keys over two directory levels, e.g. `ab/cd/abcd...`
+pub fn sharded_key(sha256: &str) -> String {
+    match (sha256.get(0..2), sha256.get(2..4)) {
+        (Some(first), Some(second)) => format!("{}/{}/{}", first, second, sha256),
+        _ => sha256.to_string(),
+    }
+}
+
+/// Content kept by a backend that does not keep local files
+///
+/// Nothing is read until a range is opened, so the content can be served
+/// without holding it in memory.
+#[derive(Clone)]
+pub struct StoredContent {
+    backend: Arc<dyn BlobStorage>,
+    key: String,
+    size: u64,
+}
+
+impl StoredContent {
+    pub fn new(backend: Arc<dyn BlobStorage>, key: impl Into<String>, size: u64) -> Self {
+        Self {
+            backend,
+            key: key.into(),
+            size,
+        }
+    }
+
+    /// Size of the content in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Stream `length` bytes of the content starting at `start`
+    pub async fn open(&self, start: u64, length: u64) -> Result<ContentStream, BlobStorageError> {
+        self.backend
+            .get_range(&self.key, start, length)
+            .await?
+            .ok_or_else(|| BlobStorageError::Missing(self.key.clone()))
+    }
+
+    /// Read the whole content into memory
+    pub async fn read(&self) -> Result<Vec<u8>, BlobStorageError> {
+        self.backend
+            .get(&self.key)
+            .await?
+            .ok_or_else(|| BlobStorageError::Missing(self.key.clone()))
+    }
+}
+
+/// The set of configured storage backends
+#[derive(Clone)]
+pub struct MediaStorage {
+    default_backend: MediaStorageBackend,
+    database: Arc<DatabaseBlobStorage>,
+    filesystem: Arc<FilesystemBlobStorage>,
+    s3: Option<Arc<S3BlobStorage>>,
+}
+
+impl MediaStorage {
+    /// Build the storage backends described by the media configuration
+    pub fn from_config(
+        config: &MediaStorageConfig,
+        db: &DatabaseConnection,
+    ) -> Result<Self, BlobStorageError> {
+        let s3 = config
+            .s3
+            .as_ref()
+            .map(S3BlobStorage::new)
+            .transpose()?
+            .map(Arc::new);
+
+        if config.backend == MediaStorageBackend::S3 && s3.is_none() {
+            return Err(BlobStorageError::NotConfigured(MediaStorageBackend::S3));
+        }
+
+        Ok(Self {
+            default_backend: config.backend,
+            database: Arc::new(DatabaseBlobStorage::new(db.clone())),
+            filesystem: Arc::new(FilesystemBlobStorage::new(&config.directory)),
+            s3,
+        })
+    }
+
+    /// Directory for content still being received
+    ///
+    /// It lives under the filesystem backend's root so staged files can be
+    /// linked into place without copying.
+    pub fn staging_directory(&self) -> PathBuf {
+        self.filesystem.root().join(".staging")
+    }
+
+    /// Directory the filesystem backend stores new content in
+    pub fn filesystem_root(&self) -> &Path {
+        self.filesystem.root()
+    }
+
+    /// Use a different backend for new content
+    pub fn with_default_backend(
+        mut self,
+        backend: MediaStorageBackend,
+    ) -> Result<Self, BlobStorageError> {
+        self.backend(backend)?;
+        self.default_backend = backend;
+        Ok(self)
+    }
+
+    /// Backend new content is written to
+    pub fn default_backend(&self) -> &dyn BlobStorage {
+        self.backend(self.default_backend)
+            .expect("default backend is checked at construction")
+    }
+
+    /// Look up a backend by kind
+    pub fn backend(&self, kind: MediaStorageBackend) -> Result<&dyn BlobStorage, BlobStorageError> {
+        match kind {
+            MediaStorageBackend::Database => Ok(self.database.as_ref()),
+            MediaStorageBackend::Filesystem => Ok(self.filesystem.as_ref()),
+            MediaStorageBackend::S3 => self
+                .s3
+                .as_deref()
+                .map(|s3| s3 as &dyn BlobStorage)
+                .ok_or(BlobStorageError::NotConfigured(kind)),
+        }
+    }
+
+    /// Content of `size` bytes stored under `key` in a backend, read lazily
+    pub fn stored(
+        &self,
+        kind: MediaStorageBackend,
+        key: &str,
+        size: u64,
+    ) -> Result<StoredContent, BlobStorageError> {
+        let backend: Arc<dyn BlobStorage> = match kind {
+            MediaStorageBackend::Database => self.database.clone(),
+            MediaStorageBackend::Filesystem => self.filesystem.clone(),
+            MediaStorageBackend::S3 => self
+                .s3
+                .clone()
+                .ok_or(BlobStorageError::NotConfigured(kind))?,
+        };
+        Ok(StoredContent::new(backend, key, size))
+    }
+}
+
+// Stored as TEXT with a CHECK constraint rather than a Postgres enum type
+impl sqlx::Type<sqlx::Postgres> for MediaStorageBackend {
+    fn type_info() -> sqlx::postgres::PgTypeInfo {
+        <String as sqlx::Type<sqlx::Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &sqlx::postgres::PgTypeInfo) -> bool {
+        <String as sqlx::Type<sqlx::Postgres>>::compatible(ty)
+    }
+}
+
+impl<'r> sqlx::Decode<'r, sqlx::Postgres> for MediaStorageBackend {
+    fn decode(value: sqlx::postgres::PgValueRef<'r>) -> Result<Self, sqlx::error::BoxDynError> {
+        let s = <&str as sqlx::Decode<sqlx::Postgres>>::decode(value)?;
+        Ok(s.parse()?)
+    }
+}
+
+impl sqlx::Encode<'_, sqlx::Postgres> for MediaStorageBackend {
+    fn encode_by_ref(
+        &self,
+        buf: &mut sqlx::postgres::PgArgumentBuffer,
+    ) -> Result<sqlx::encode::IsNull, sqlx::error::BoxDynError> {
+        <&str as sqlx::Encode<sqlx::Postgres>>::encode(self.as_str(), buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sharded_key() {
+        let sha256 = "abcdef".repeat(10) + "abcd";
+        assert_eq!(sharded_key(&sha256), format!("ab/cd/{}", sha256));
+        assert_eq!(sharded_key("abc"), "abc");
+    }
+
+    #[test]
+    fn test_backend_names_round_trip() {
+        for backend in [
+            MediaStorageBackend::Database,
+            MediaStorageBackend::Filesystem,
+            MediaStorageBackend::S3,
+        ] {
+            assert_eq!(backend.as_str().parse::<MediaStorageBackend>(), Ok(backend));
+        }
+        assert!("tape".parse::<MediaStorageBackend>().is_err());
+    }
+}
@@ -0,0 +1,208 @@
+//! Temporary files for content that is still being received
+//!
+//! A [`StagedFile`] is written chunk by chunk while its SHA256 and size are
+//! tracked, so large uploads never have to be held in memory. The leading
+//! bytes are kept for content type detection. The file is removed when the
+//! value is dropped unless a backend has moved it away.
+
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::media::sniff::SNIFF_LEN;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StagingError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Content exceeds the limit of {limit} bytes")]
+    TooLarge { limit: u64 },
+}
+
+/// Content being written to a temporary file
+pub struct StagedFile {
+    path: PathBuf,
+    file: Option<tokio::fs::File>,
+    hasher: Sha256,
+    head: Vec<u8>,
+    size: u64,
+    limit: u64,
+    sha256: Option<String>,
+}
+
+impl StagedFile {
+    /// Create an empty staging file in `directory` accepting at most `limit` bytes
+    pub async fn create(directory: &Path, limit: u64) -> Result<Self, StagingError> {
+        tokio::fs::create_dir_all(directory).await?;
+
+        let path = directory.join(format!("{}.partial", uuid::Uuid::new_v4().simple()));
+        let file = tokio::fs::File::create(&path).await?;
+
+        Ok(Self {
+            path,
+            file: Some(file),
+            hasher: Sha256::new(),
+            head: Vec::new(),
+            size: 0,
+            limit,
+            sha256: None,
+        })
+    }
+
+    /// Take over a fully written file, hashing it in chunks
+    ///
+    /// Used for content received over several requests, whose hash state
+    /// could not be kept. The file is removed when the value is dropped.
+    pub async fn from_file(path: PathBuf) -> Result<Self, StagingError> {
+        let mut file = tokio::fs::File::open(&path).await?;
+        let mut hasher = Sha256::new();
+        let mut head = Vec::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut size = 0u64;
+
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            keep_head(&mut head, &buffer[..read]);
+            size += read as u64;
+        }
+
+        Ok(Self {
+            path,
+            file: None,
+            hasher: Sha256::new(),
+            head,
+            size,
+            limit: size,
+            sha256: Some(format!("{:x}", hasher.finalize())),
+        })
+    }
+
+    /// Append a chunk, failing as soon as the size limit is exceeded
+    pub async fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), StagingError> {
+        let file = self
+            .file
+            .as_mut()
+            .expect("chunks are only written before finish");
+
+        if self.size + chunk.len() as u64 > self.limit {
+            return Err(StagingError::TooLarge { limit: self.limit });
+        }
+
+        file.write_all(chunk).await?;
+        self.hasher.update(chunk);
+        keep_head(&mut self.head, chunk);
+        self.size += chunk.len() as u64;
+
+        Ok(())
+    }
+
+    /// Flush the content to disk and fix its hash; no more chunks may be written
+    pub async fn finish(&mut self) -> Result<(), StagingError> {
+        if let Some(mut file) = self.file.take() {
+            file.flush().await?;
+            file.sync_all().await?;
+            self.sha256 = Some(format!("{:x}", std::mem::take(&mut self.hasher).finalize()));
+        }
+
+        Ok(())
+    }
+
+    /// Path of the temporary file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of bytes written so far
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Hex-encoded SHA256 of the content, available once finished
+    pub fn sha256(&self) -> Option<&str> {
+        self.sha256.as_deref()
+    }
+
+    /// The first bytes of the content, for detecting its type
+    pub fn head(&self) -> &[u8] {
+        &self.head
+    }
+}
+
+/// Append the part of `chunk` that falls within the first `SNIFF_LEN` bytes
+fn keep_head(head: &mut Vec<u8>, chunk: &[u8]) {
+    let wanted = SNIFF_LEN.saturating_sub(head.len()).min(chunk.len());
+    head.extend_from_slice(&chunk[..wanted]);
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        // Nothing is left to clean up if the file is gone already
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => tracing::warn!(
+                "Failed to remove staging file {}: {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_staged_file_hashes_and_cleans_up() {
+        let dir = std::env::temp_dir().join(format!("media-staging-{}", uuid::Uuid::new_v4()));
+        let mut staged = StagedFile::create(&dir, 11).await.unwrap();
+
+        staged.write_chunk(b"hello ").await.unwrap();
+        staged.write_chunk(b"world").await.unwrap();
+        assert!(staged.sha256().is_none());
+        staged.finish().await.unwrap();
+
+        assert_eq!(staged.size(), 11);
+        assert_eq!(
+            staged.sha256(),
+            Some(format!("{:x}", Sha256::digest(b"hello world")).as_str())
+        );
+        assert_eq!(std::fs::read(staged.path()).unwrap(), b"hello world");
+
+        let path = staged.path().to_path_buf();
+        let sha256 = staged.sha256().map(str::to_string);
+        std::mem::forget(staged);
+
+        // Adopting the written file yields the same hash and size
+        let adopted = StagedFile::from_file(path.clone()).await.unwrap();
+        assert_eq!(adopted.sha256().map(str::to_string), sha256);
+        assert_eq!(adopted.size(), 11);
+
+        drop(adopted);
+        assert!(!path.exists());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_staged_file_enforces_limit() {
+        let dir = std::env::temp_dir().join(format!("media-staging-{}", uuid::Uuid::new_v4()));
+        let mut staged = StagedFile::create(&dir, 4).await.unwrap();
+
+        staged.write_chunk(b"abcd").await.unwrap();
+        assert!(matches!(
+            staged.write_chunk(b"e").await,
+            Err(StagingError::TooLarge { limit: 4 })
+        ));
+        assert_eq!(staged.size(), 4);
+
+        drop(staged);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,356 @@
+//! Rooms for messaging over the WebSocket
+//!
+//! Rooms are named and either public, for anyone to join, or private, for
+//! members added by the room's owner or moderators. Members post text and
+//! share media blobs; messages are kept in Postgres so members can load the
+//! history when they join. Admins may see, join and manage every room.
+//!
+//! Shared blobs keep their own access rules: a message names its blob for
+//! everyone in the room, but only readers who may see the blob are given it.
+
+pub mod models;
+pub mod repository;
+
+pub use models::{Room, RoomHistory, RoomMember, RoomMessage, RoomRole, RoomVisibility};
+pub use repository::{RoomError, RoomRepository};
+
+use std::collections::HashMap;
+
+use tracing::info;
+use uuid::Uuid;
+
+use crate::auth::User;
+use crate::error::WebauthnError;
+use crate::media::MediaService;
+
+/// Messages loaded when no limit is given
+pub const DEFAULT_HISTORY_LIMIT: u32 = 50;
+
+/// Most messages loaded at once
+pub const MAX_HISTORY_LIMIT: u32 = 200;
+
+/// What happened in a room
+#[derive(Debug, Clone)]
+pub enum RoomEventKind {
+    /// A message was posted; `blob_shared_with` lists the users its blob is
+    /// shared with, so readers can be checked without the database
+    Message {
+        message: Box<RoomMessage>,
+        blob_shared_with: Vec<Uuid>,
+    },
+    /// A user joined or was added, or their role changed
+    MemberJoined(RoomMember),
+    /// A user left or was removed
+    MemberLeft { user_id: Uuid },
+    /// The room was deleted
+    Deleted,
+}
+
+/// A change in a room, as broadcast to connections
+#[derive(Debug, Clone)]
+pub struct RoomEvent {
+    pub room_id: Uuid,
+    /// Connection that caused the event and was answered directly
+    pub origin: Option<String>,
+    pub kind: RoomEventKind,
+}
+
+impl RoomEvent {
+    /// Whether the event is about a user's own membership
+    pub fn concerns(&self, user_id: Uuid) -> bool {
+        match &self.kind {
+            RoomEventKind::MemberJoined(member) => member.user_id == user_id,
+            RoomEventKind::MemberLeft { user_id: left } => *left == user_id,
+            _ => false,
+        }
+    }
+
+    /// Whether the event takes a user out of the room
+    pub fn removes(&self, user_id: Uuid) -> bool {
+        match &self.kind {
+            RoomEventKind::MemberLeft { user_id: left } => *left == user_id,
+            RoomEventKind::Deleted => true,
+            _ => false,
+        }
+    }
+}
+
+/// A posted message, with the users its blob is shared with
+pub struct PostedMessage {
+    pub message: RoomMessage,
+    pub blob_shared_with: Vec<Uuid>,
+}
+
+/// Room service combining repository operations with access rules
+///
+/// Roles are checked against the caller's membership: moderators and owners
+/// manage members, and only owners (and admins) appoint moderators or
+/// delete the room. Private rooms are hidden from non-members, so they are
+/// reported as not found.
+pub struct RoomService<'a> {
+    repository: RoomRepository<'a>,
+    media: MediaService<'a>,
+}
+
+impl<'a> RoomService<'a> {
+    /// Create a service; the media service looks up shared blobs
+    pub fn new(repository: RoomRepository<'a>, media: MediaService<'a>) -> Self {
+        Self { repository, media }
+    }
+
+    /// Rooms the viewer can see, by name
+    pub async fn list(&self, viewer: &User) -> Result<Vec<Room>, WebauthnError> {
+        self.repository
+            .list_for_user(viewer.id, viewer.is_admin())
+            .await
+    }
+
+    /// IDs of the rooms the viewer is a member of
+    pub async fn joined_ids(&self, viewer: &User) -> Result<Vec<Uuid>, WebauthnError> {
+        self.repository.ids_for_user(viewer.id).await
+    }
+
+    /// A room the viewer may see
+    pub async fn get(&self, id: Uuid, viewer: &User) -> Result<Room, WebauthnError> {
+        match self.repository.find(id, viewer.id).await? {
+            Some(room)
+                if room.visibility == RoomVisibility::Public
+                    || room.role.is_some()
+                    || viewer.is_admin() =>
+            {
+                Ok(room)
+            }
+            _ => Err(RoomError::NotFound.into()),
+        }
+    }
+
+    /// Create a room owned by the viewer
+    pub async fn create(
+        &self,
+        name: &str,
+        topic: Option<&str>,
+        visibility: RoomVisibility,
+        viewer: &User,
+    ) -> Result<Room, WebauthnError> {
+        let name = Room::normalize_name(name).map_err(RoomError::Validation)?;
+        let topic = topic
+            .map(Room::normalize_topic)
+            .transpose()
+            .map_err(RoomError::Validation)?;
+
+        self.repository
+            .create(&name, topic.as_deref(), visibility, viewer.id)
+            .await
+    }
+
+    /// Join a room, returning it and the viewer's membership
+    ///
+    /// Anyone may join a public room; private rooms only admit members
+    /// already added, and admins. Joining again changes nothing. The flag
+    /// tells whether the viewer became a member just now.
+    pub async fn join(
+        &self,
+        id: Uuid,
+        viewer: &User,
+    ) -> Result<(Room, RoomMember, bool), WebauthnError> {
+        let room = self.get(id, viewer).await?;
+        if let Some(member) = self.repository.member(id, viewer.id).await? {
+            return Ok((room, member, false));
+        }
+
+        info!("User {} joining room {}", viewer.id, id);
+        let member = self
+            .repository
+            .set_member(id, viewer.id, RoomRole::Member)
+            .await?;
+        let room = self.get(id, viewer).await?;
+        Ok((room, member, true))
+    }
+
+    /// Leave a room; false if the viewer was not a member
+    ///
+    /// Owners cannot leave, as the room would be left without one; they
+    /// delete it instead.
+    pub async fn leave(&self, id: Uuid, viewer: &User) -> Result<bool, WebauthnError> {
+        self.get(id, viewer).await?;
+        match self.repository.member(id, viewer.id).await? {
+            Some(member) if member.role == RoomRole::Owner => Err(RoomError::Validation(
+                "Owners cannot leave their room; delete it instead".to_string(),
+            )
+            .into()),
+            Some(_) => self.repository.remove_member(id, viewer.id).await,
+            None => Ok(false),
+        }
+    }
+
+    /// Add a user to a room, or change their role
+    ///
+    /// Moderators add members; owners also appoint and demote moderators.
+    /// The owner's role cannot change, and there is only one owner. The
+    /// flag tells whether the membership changed.
+    pub async fn add_member(
+        &self,
+        id: Uuid,
+        user_id: Uuid,
+        role: RoomRole,
+        viewer: &User,
+    ) -> Result<(RoomMember, bool), WebauthnError> {
+        let acting = self.acting_role(id, viewer).await?;
+        if acting < RoomRole::Moderator {
+            return Err(RoomError::Forbidden.into());
+        }
+        if role == RoomRole::Owner {
+            return Err(RoomError::Validation("A room has only one owner".to_string()).into());
+        }
+
+        let existing = self.repository.member(id, user_id).await?;
+        let current = existing.as_ref().map(|member| member.role);
+        if current == Some(RoomRole::Owner) {
+            return Err(RoomError::Validation("The owner's role cannot change".to_string()).into());
+        }
+        // Only owners appoint or demote moderators
+        if (role == RoomRole::Moderator || current == Some(RoomRole::Moderator))
+            && acting < RoomRole::Owner
+        {
+            return Err(RoomError::Forbidden.into());
+        }
+        if current == Some(role) {
+            return Ok((existing.unwrap(), false));
+        }
+
+        let member = self.repository.set_member(id, user_id, role).await?;
+        Ok((member, true))
+    }
+
+    /// Remove a user from a room; false if they were not a member
+    ///
+    /// Moderators remove members, and owners also remove moderators. The
+    /// owner cannot be removed.
+    pub async fn remove_member(
+        &self,
+        id: Uuid,
+        user_id: Uuid,
+        viewer: &User,
+    ) -> Result<bool, WebauthnError> {
+        let acting = self.acting_role(id, viewer).await?;
+        if acting < RoomRole::Moderator {
+            return Err(RoomError::Forbidden.into());
+        }
+
+        match self.repository.member(id, user_id).await? {
+            Some(member) if member.role == RoomRole::Owner => Err(RoomError::Validation(
+                "The owner cannot be removed; delete the room instead".to_string(),
+            )
+            .into()),
+            Some(member) if member.role >= acting => Err(RoomError::Forbidden.into()),
+            Some(_) => self.repository.remove_member(id, user_id).await,
+            None => Ok(false),
+        }
+    }
+
+    /// Members of a room the viewer is in
+    pub async fn members(&self, id: Uuid, viewer: &User) -> Result<Vec<RoomMember>, WebauthnError> {
+        self.acting_role(id, viewer).await?;
+        self.repository.members(id).await
+    }
+
+    /// Delete a room; only its owner and admins may
+    pub async fn delete(&self, id: Uuid, viewer: &User) -> Result<(), WebauthnError> {
+        if self.acting_role(id, viewer).await? < RoomRole::Owner {
+            return Err(RoomError::Forbidden.into());
+        }
+
+        if !self.repository.delete(id).await? {
+            return Err(RoomError::NotFound.into());
+        }
+        Ok(())
+    }
+
+    /// Post a message in a room the viewer is a member of
+    ///
+    /// A shared blob must be one the viewer may see.
+    pub async fn post(
+        &self,
+        id: Uuid,
+        body: Option<&str>,
+        blob_id: Option<Uuid>,
+        viewer: &User,
+    ) -> Result<PostedMessage, WebauthnError> {
+        self.acting_role(id, viewer).await?;
+        let body = RoomMessage::normalize_body(body).map_err(RoomError::Validation)?;
+        if body.is_none() && blob_id.is_none() {
+            return Err(RoomError::Validation("Messages need text or a blob".to_string()).into());
+        }
+
+        let (blob, blob_shared_with) = match blob_id {
+            Some(blob_id) => {
+                let blob = self.media.get_blob(blob_id, false, viewer).await?;
+                (Some(blob), self.media.shared_with(blob_id).await?)
+            }
+            None => (None, Vec::new()),
+        };
+
+        let mut message = self
+            .repository
+            .post(id, viewer.id, body.as_deref(), blob_id)
+            .await?;
+        message.blob = blob;
+
+        Ok(PostedMessage {
+            message,
+            blob_shared_with,
+        })
+    }
+
+    /// A page of a room's history, oldest first, for a member
+    ///
+    /// Messages come before the message with ID `before`, or are the newest
+    /// without it. Blobs are included where the viewer may see them.
+    pub async fn history(
+        &self,
+        id: Uuid,
+        before: Option<i64>,
+        limit: Option<u32>,
+        viewer: &User,
+    ) -> Result<RoomHistory, WebauthnError> {
+        self.acting_role(id, viewer).await?;
+        let limit = limit
+            .unwrap_or(DEFAULT_HISTORY_LIMIT)
+            .clamp(1, MAX_HISTORY_LIMIT) as i64;
+
+        let mut messages = self.repository.messages(id, before, limit + 1).await?;
+        let has_more = messages.len() as i64 > limit;
+        messages.truncate(limit as usize);
+        messages.reverse();
+
+        let mut blobs = HashMap::new();
+        for blob_id in messages.iter().filter_map(|message| message.blob_id) {
+            if let std::collections::hash_map::Entry::Vacant(entry) = blobs.entry(blob_id) {
+                entry.insert(self.media.get_blob(blob_id, false, viewer).await.ok());
+            }
+        }
+        for message in &mut messages {
+            message.blob = message
+                .blob_id
+                .and_then(|blob_id| blobs.get(&blob_id).cloned().flatten());
+        }
+
+        Ok(RoomHistory {
+            room_id: id,
+            messages,
+            has_more,
+        })
+    }
+
+    /// The viewer's role in a room they may act in
+    ///
+    /// Admins act as owners everywhere. Private rooms the viewer is not in
+    /// are not found; public rooms refuse non-members.
+    pub async fn acting_role(&self, id: Uuid, viewer: &User) -> Result<RoomRole, WebauthnError> {
+        let room = self.get(id, viewer).await?;
+        if viewer.is_admin() {
+            return Ok(RoomRole::Owner);
+        }
+        room.role.ok_or_else(|| RoomError::Forbidden.into())
+    }
+}
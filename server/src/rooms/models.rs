@@ -0,0 +1,288 @@
+//! Room data models
+
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::auth::User;
+use crate::media::MediaBlob;
+
+/// Longest room name, in characters
+pub const MAX_ROOM_NAME_LENGTH: usize = 100;
+
+/// Longest room topic, in characters
+pub const MAX_ROOM_TOPIC_LENGTH: usize = 500;
+
+/// Longest text of a room message, in characters
+pub const MAX_MESSAGE_LENGTH: usize = 4000;
+
+/// Who may join a room
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomVisibility {
+    /// Listed for every user, and anyone may join
+    #[default]
+    Public,
+    /// Only listed for its members, who are added by the owner or moderators
+    Private,
+}
+
+impl RoomVisibility {
+    /// Database representation of this visibility
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RoomVisibility::Public => "public",
+            RoomVisibility::Private => "private",
+        }
+    }
+}
+
+impl std::str::FromStr for RoomVisibility {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "public" => Ok(RoomVisibility::Public),
+            "private" => Ok(RoomVisibility::Private),
+            _ => Err(format!("Invalid room visibility: {}", s)),
+        }
+    }
+}
+
+// Stored as TEXT with a CHECK constraint rather than a Postgres enum type
+impl sqlx::Type<sqlx::Postgres> for RoomVisibility {
+    fn type_info() -> sqlx::postgres::PgTypeInfo {
+        <String as sqlx::Type<sqlx::Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &sqlx::postgres::PgTypeInfo) -> bool {
+        <String as sqlx::Type<sqlx::Postgres>>::compatible(ty)
+    }
+}
+
+impl<'r> sqlx::Decode<'r, sqlx::Postgres> for RoomVisibility {
+    fn decode(value: sqlx::postgres::PgValueRef<'r>) -> Result<Self, sqlx::error::BoxDynError> {
+        let s = <&str as sqlx::Decode<sqlx::Postgres>>::decode(value)?;
+        Ok(s.parse()?)
+    }
+}
+
+impl sqlx::Encode<'_, sqlx::Postgres> for RoomVisibility {
+    fn encode_by_ref(
+        &self,
+        buf: &mut sqlx::postgres::PgArgumentBuffer,
+    ) -> Result<sqlx::encode::IsNull, sqlx::error::BoxDynError> {
+        <&str as sqlx::Encode<sqlx::Postgres>>::encode(self.as_str(), buf)
+    }
+}
+
+/// What a member may do in a room
+///
+/// Roles are ordered: each may do everything the ones before it may.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomRole {
+    /// Reads and posts messages
+    Member,
+    /// Also adds and removes members
+    Moderator,
+    /// Also appoints moderators and deletes the room; the room's creator
+    Owner,
+}
+
+impl RoomRole {
+    /// Database representation of this role
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RoomRole::Member => "member",
+            RoomRole::Moderator => "moderator",
+            RoomRole::Owner => "owner",
+        }
+    }
+}
+
+impl std::str::FromStr for RoomRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "member" => Ok(RoomRole::Member),
+            "moderator" => Ok(RoomRole::Moderator),
+            "owner" => Ok(RoomRole::Owner),
+            _ => Err(format!("Invalid room role: {}", s)),
+        }
+    }
+}
+
+impl sqlx::Type<sqlx::Postgres> for RoomRole {
+    fn type_info() -> sqlx::postgres::PgTypeInfo {
+        <String as sqlx::Type<sqlx::Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &sqlx::postgres::PgTypeInfo) -> bool {
+        <String as sqlx::Type<sqlx::Postgres>>::compatible(ty)
+    }
+}
+
+impl<'r> sqlx::Decode<'r, sqlx::Postgres> for RoomRole {
+    fn decode(value: sqlx::postgres::PgValueRef<'r>) -> Result<Self, sqlx::error::BoxDynError> {
+        let s = <&str as sqlx::Decode<sqlx::Postgres>>::decode(value)?;
+        Ok(s.parse()?)
+    }
+}
+
+impl sqlx::Encode<'_, sqlx::Postgres> for RoomRole {
+    fn encode_by_ref(
+        &self,
+        buf: &mut sqlx::postgres::PgArgumentBuffer,
+    ) -> Result<sqlx::encode::IsNull, sqlx::error::BoxDynError> {
+        <&str as sqlx::Encode<sqlx::Postgres>>::encode(self.as_str(), buf)
+    }
+}
+
+/// A named room users post messages in
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Room {
+    pub id: Uuid,
+    pub name: String,
+    pub topic: Option<String>,
+    pub visibility: RoomVisibility,
+    pub created_by: Option<Uuid>,
+    /// Number of members
+    pub member_count: i64,
+    /// The viewing user's role, or `None` if they are not a member
+    #[serde(default)]
+    pub role: Option<RoomRole>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub updated_at: OffsetDateTime,
+}
+
+impl Room {
+    /// Trim a room name and check its length
+    pub fn normalize_name(name: &str) -> Result<String, String> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err("Room name must not be empty".to_string());
+        }
+        if name.chars().count() > MAX_ROOM_NAME_LENGTH {
+            return Err(format!(
+                "Room name must be at most {} characters long",
+                MAX_ROOM_NAME_LENGTH
+            ));
+        }
+        Ok(name.to_string())
+    }
+
+    /// Trim a room topic and check its length; an empty topic clears it
+    pub fn normalize_topic(topic: &str) -> Result<String, String> {
+        let topic = topic.trim();
+        if topic.chars().count() > MAX_ROOM_TOPIC_LENGTH {
+            return Err(format!(
+                "Room topic must be at most {} characters long",
+                MAX_ROOM_TOPIC_LENGTH
+            ));
+        }
+        Ok(topic.to_string())
+    }
+}
+
+/// A user's membership of a room
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+pub struct RoomMember {
+    pub room_id: Uuid,
+    pub user_id: Uuid,
+    pub username: String,
+    pub role: RoomRole,
+    #[serde(with = "time::serde::rfc3339")]
+    pub joined_at: OffsetDateTime,
+}
+
+/// A message posted in a room: text, a shared blob, or both
+///
+/// IDs grow with every message, so they order a room's history and serve
+/// as cursors for loading older messages.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomMessage {
+    pub id: i64,
+    pub room_id: Uuid,
+    /// `None` once the author's account is deleted
+    pub author_user_id: Option<Uuid>,
+    pub author_username: Option<String>,
+    pub body: Option<String>,
+    pub blob_id: Option<Uuid>,
+    /// The shared blob without data, for readers who may see it
+    #[serde(default)]
+    pub blob: Option<MediaBlob>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+impl RoomMessage {
+    /// Trim the text of a message and check its length
+    ///
+    /// Empty text becomes `None`; a message then needs a blob instead.
+    pub fn normalize_body(body: Option<&str>) -> Result<Option<String>, String> {
+        let Some(body) = body.map(str::trim).filter(|body| !body.is_empty()) else {
+            return Ok(None);
+        };
+        if body.chars().count() > MAX_MESSAGE_LENGTH {
+            return Err(format!(
+                "Messages must be at most {} characters long",
+                MAX_MESSAGE_LENGTH
+            ));
+        }
+        Ok(Some(body.to_string()))
+    }
+
+    /// The message as a reader sees it, without a blob they may not see
+    pub fn for_reader(&self, blob_shared_with: &[Uuid], reader: &User) -> RoomMessage {
+        let mut message = self.clone();
+        if let Some(blob) = &message.blob {
+            if !blob.visible_to(reader, blob_shared_with) {
+                message.blob = None;
+            }
+        }
+        message
+    }
+}
+
+/// A page of a room's history, oldest first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomHistory {
+    pub room_id: Uuid,
+    pub messages: Vec<RoomMessage>,
+    /// Whether older messages exist; load them with the first message's ID
+    /// as `before`
+    pub has_more: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(Room::normalize_name("  lobby ").unwrap(), "lobby");
+        assert!(Room::normalize_name("   ").is_err());
+        assert!(Room::normalize_name(&"x".repeat(MAX_ROOM_NAME_LENGTH + 1)).is_err());
+        assert_eq!(Room::normalize_topic("  ").unwrap(), "");
+
+        assert_eq!(RoomMessage::normalize_body(Some("  ")).unwrap(), None);
+        assert_eq!(
+            RoomMessage::normalize_body(Some(" hi ")).unwrap(),
+            Some("hi".to_string())
+        );
+        assert!(RoomMessage::normalize_body(Some(&"x".repeat(MAX_MESSAGE_LENGTH + 1))).is_err());
+    }
+
+    #[test]
+    fn test_roles_are_ordered() {
+        assert!(RoomRole::Owner > RoomRole::Moderator);
+        assert!(RoomRole::Moderator > RoomRole::Member);
+        assert_eq!("moderator".parse::<RoomRole>(), Ok(RoomRole::Moderator));
+        assert!("admin".parse::<RoomRole>().is_err());
+    }
+}
@@ -0,0 +1,344 @@
+//! Room repository for database operations
+
+use sqlx::error::ErrorKind;
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use crate::database::DatabaseConnection;
+use crate::error::WebauthnError;
+
+use super::models::{Room, RoomMember, RoomMessage, RoomRole, RoomVisibility};
+
+#[derive(Debug, thiserror::Error)]
+pub enum RoomError {
+    #[error("Room not found")]
+    NotFound,
+    #[error("A room with this name already exists")]
+    DuplicateName,
+    #[error("Validation error: {0}")]
+    Validation(String),
+    #[error("Access to room denied")]
+    Forbidden,
+}
+
+impl From<RoomError> for WebauthnError {
+    fn from(err: RoomError) -> Self {
+        match err {
+            RoomError::NotFound => WebauthnError::UserNotFound,
+            RoomError::DuplicateName => WebauthnError::BadRequest,
+            RoomError::Validation(_) => WebauthnError::BadRequest,
+            RoomError::Forbidden => WebauthnError::Forbidden,
+        }
+    }
+}
+
+/// Room repository for database operations
+pub struct RoomRepository<'a> {
+    db: &'a DatabaseConnection,
+}
+
+impl<'a> RoomRepository<'a> {
+    /// Create a new repository instance
+    pub fn new(db: &'a DatabaseConnection) -> Self {
+        Self { db }
+    }
+
+    /// Create a room with its creator as owner; names are unique
+    pub async fn create(
+        &self,
+        name: &str,
+        topic: Option<&str>,
+        visibility: RoomVisibility,
+        created_by: Uuid,
+    ) -> Result<Room, WebauthnError> {
+        info!("Creating room {:?} for {}", name, created_by);
+
+        let mut tx = self.db.begin().await?;
+        let id = Uuid::new_v4();
+
+        sqlx::query!(
+            r#"
+            INSERT INTO rooms (id, name, topic, visibility, created_by)
+            VALUES ($1, $2, NULLIF($3, ''), $4, $5)
+            "#,
+            id,
+            name,
+            topic,
+            visibility as RoomVisibility,
+            created_by
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(duplicate_name)?;
+
+        sqlx::query!(
+            "INSERT INTO room_members (room_id, user_id, role) VALUES ($1, $2, $3)",
+            id,
+            created_by,
+            RoomRole::Owner as RoomRole
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        self.find(id, created_by)
+            .await?
+            .ok_or(WebauthnError::UserNotFound)
+    }
+
+    /// Find a room by ID, with the viewer's role in it
+    pub async fn find(&self, id: Uuid, viewer_id: Uuid) -> Result<Option<Room>, WebauthnError> {
+        let room = sqlx::query_as!(
+            Room,
+            r#"
+            SELECT r.id, r.name, r.topic, r.visibility as "visibility: RoomVisibility",
+                   r.created_by,
+                   (SELECT COUNT(*) FROM room_members m WHERE m.room_id = r.id) as "member_count!",
+                   (SELECT m.role FROM room_members m
+                    WHERE m.room_id = r.id AND m.user_id = $2) as "role: RoomRole",
+                   r.created_at, r.updated_at
+            FROM rooms r
+            WHERE r.id = $1
+            "#,
+            id,
+            viewer_id
+        )
+        .fetch_optional(self.db.pool())
+        .await?;
+
+        Ok(room)
+    }
+
+    /// List rooms by name: public rooms and those the viewer is a member
+    /// of, or every room with `include_private`
+    pub async fn list_for_user(
+        &self,
+        viewer_id: Uuid,
+        include_private: bool,
+    ) -> Result<Vec<Room>, WebauthnError> {
+        let rooms = sqlx::query_as!(
+            Room,
+            r#"
+            SELECT r.id, r.name, r.topic, r.visibility as "visibility: RoomVisibility",
+                   r.created_by,
+                   (SELECT COUNT(*) FROM room_members m WHERE m.room_id = r.id) as "member_count!",
+                   v.role as "role?: RoomRole",
+                   r.created_at, r.updated_at
+            FROM rooms r
+            LEFT JOIN room_members v ON v.room_id = r.id AND v.user_id = $1
+            WHERE r.visibility = 'public' OR v.user_id IS NOT NULL OR $2
+            ORDER BY r.name
+            "#,
+            viewer_id,
+            include_private
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(rooms)
+    }
+
+    /// IDs of the rooms a user is a member of
+    pub async fn ids_for_user(&self, user_id: Uuid) -> Result<Vec<Uuid>, WebauthnError> {
+        let ids = sqlx::query_scalar!(
+            "SELECT room_id FROM room_members WHERE user_id = $1",
+            user_id
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(ids)
+    }
+
+    /// Delete a room with its members and messages
+    pub async fn delete(&self, id: Uuid) -> Result<bool, WebauthnError> {
+        info!("Deleting room: {}", id);
+
+        let result = sqlx::query!("DELETE FROM rooms WHERE id = $1", id)
+            .execute(self.db.pool())
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// A user's membership of a room
+    pub async fn member(
+        &self,
+        room_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Option<RoomMember>, WebauthnError> {
+        let member = sqlx::query_as!(
+            RoomMember,
+            r#"
+            SELECT m.room_id, m.user_id, u.username, m.role as "role: RoomRole", m.joined_at
+            FROM room_members m
+            JOIN users u ON u.id = m.user_id
+            WHERE m.room_id = $1 AND m.user_id = $2
+            "#,
+            room_id,
+            user_id
+        )
+        .fetch_optional(self.db.pool())
+        .await?;
+
+        Ok(member)
+    }
+
+    /// Members of a room, in the order they joined
+    pub async fn members(&self, room_id: Uuid) -> Result<Vec<RoomMember>, WebauthnError> {
+        let members = sqlx::query_as!(
+            RoomMember,
+            r#"
+            SELECT m.room_id, m.user_id, u.username, m.role as "role: RoomRole", m.joined_at
+            FROM room_members m
+            JOIN users u ON u.id = m.user_id
+            WHERE m.room_id = $1
+            ORDER BY m.joined_at, u.username
+            "#,
+            room_id
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(members)
+    }
+
+    /// Add a member, or change the role of an existing one
+    pub async fn set_member(
+        &self,
+        room_id: Uuid,
+        user_id: Uuid,
+        role: RoomRole,
+    ) -> Result<RoomMember, WebauthnError> {
+        let member = sqlx::query_as!(
+            RoomMember,
+            r#"
+            WITH m AS (
+                INSERT INTO room_members (room_id, user_id, role)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (room_id, user_id) DO UPDATE SET role = EXCLUDED.role
+                RETURNING room_id, user_id, role, joined_at
+            )
+            SELECT m.room_id, m.user_id, u.username, m.role as "role: RoomRole", m.joined_at
+            FROM m
+            JOIN users u ON u.id = m.user_id
+            "#,
+            room_id,
+            user_id,
+            role as RoomRole
+        )
+        .fetch_one(self.db.pool())
+        .await?;
+
+        debug!(
+            "Room {} member {} is now {}",
+            room_id,
+            user_id,
+            role.as_str()
+        );
+        Ok(member)
+    }
+
+    /// Remove a member from a room
+    pub async fn remove_member(&self, room_id: Uuid, user_id: Uuid) -> Result<bool, WebauthnError> {
+        let result = sqlx::query!(
+            "DELETE FROM room_members WHERE room_id = $1 AND user_id = $2",
+            room_id,
+            user_id
+        )
+        .execute(self.db.pool())
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Post a message in a room
+    pub async fn post(
+        &self,
+        room_id: Uuid,
+        author_user_id: Uuid,
+        body: Option<&str>,
+        blob_id: Option<Uuid>,
+    ) -> Result<RoomMessage, WebauthnError> {
+        let row = sqlx::query!(
+            r#"
+            WITH m AS (
+                INSERT INTO room_messages (room_id, author_user_id, body, blob_id)
+                VALUES ($1, $2, $3, $4)
+                RETURNING id, room_id, author_user_id, body, blob_id, created_at
+            )
+            SELECT m.id, m.room_id, m.author_user_id, u.username as "author_username?",
+                   m.body, m.blob_id, m.created_at
+            FROM m
+            LEFT JOIN users u ON u.id = m.author_user_id
+            "#,
+            room_id,
+            author_user_id,
+            body,
+            blob_id
+        )
+        .fetch_one(self.db.pool())
+        .await?;
+
+        Ok(RoomMessage {
+            id: row.id,
+            room_id: row.room_id,
+            author_user_id: row.author_user_id,
+            author_username: row.author_username,
+            body: row.body,
+            blob_id: row.blob_id,
+            blob: None,
+            created_at: row.created_at,
+        })
+    }
+
+    /// Up to `limit` messages older than `before` (or the newest), newest
+    /// first
+    pub async fn messages(
+        &self,
+        room_id: Uuid,
+        before: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<RoomMessage>, WebauthnError> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT m.id, m.room_id, m.author_user_id, u.username as "author_username?",
+                   m.body, m.blob_id, m.created_at
+            FROM room_messages m
+            LEFT JOIN users u ON u.id = m.author_user_id
+            WHERE m.room_id = $1 AND ($2::bigint IS NULL OR m.id < $2)
+            ORDER BY m.id DESC
+            LIMIT $3
+            "#,
+            room_id,
+            before,
+            limit
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| RoomMessage {
+                id: row.id,
+                room_id: row.room_id,
+                author_user_id: row.author_user_id,
+                author_username: row.author_username,
+                body: row.body,
+                blob_id: row.blob_id,
+                blob: None,
+                created_at: row.created_at,
+            })
+            .collect())
+    }
+}
+
+/// Report a room name taken by another room
+fn duplicate_name(err: sqlx::Error) -> WebauthnError {
+    match &err {
+        sqlx::Error::Database(e) if e.kind() == ErrorKind::UniqueViolation => {
+            RoomError::DuplicateName.into()
+        }
+        _ => err.into(),
+    }
+}
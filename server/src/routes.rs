@@ -9,6 +9,7 @@ use crate::analytics::build_analytics_routes;
 use crate::auth::build_auth_routes;
 use crate::config::AppConfig;
 use crate::health::build_health_routes;
+use crate::media::build_media_routes;
 use crate::static_filez::{build_enhanced_private_routes, build_enhanced_public_routes};
 use crate::upload::build_upload_routes;
 use crate::websocket::build_websocket_routes;
@@ -21,6 +22,7 @@ pub fn build_routes(config: &AppConfig) -> Router {
         .merge(build_enhanced_private_routes(config))
         .merge(build_enhanced_public_routes(config))
         .merge(build_health_routes())
+        .merge(build_media_routes(config))
         .merge(build_websocket_routes())
         .merge(build_upload_routes(config))
 }
@@ -1,5 +1,6 @@
 use crate::config::{AppConfig, StorageBackend};
 use crate::database::DatabaseConnection;
+use crate::media::{LinkSigner, MediaEvents, MediaStorage};
 use crate::storage::{AnalyticsService, SessionStore};
 use crate::wordlist::{initialize_wordlist, WordlistConfig};
 
@@ -29,6 +30,12 @@ pub struct AppState {
     pub analytics: AnalyticsService,
     // Session store for tower-sessions
     pub session_store: SessionStore,
+    // Storage backends for media content
+    pub media_storage: MediaStorage,
+    // Signs and verifies media download links
+    pub media_links: LinkSigner,
+    // Broadcasts media blob changes to WebSocket subscribers
+    pub media_events: MediaEvents,
     // Application configuration
     pub config: AppConfig,
 }
@@ -80,6 +87,15 @@ impl AppState {
             StorageBackend::Postgres => SessionStore::new_postgres(pool.clone()).await?,
         };
 
+        // Set up media storage backends
+        let media_storage = MediaStorage::from_config(&config.media.storage, &database)?;
+        tracing::info!(
+            "Media storage backend: {}",
+            media_storage.default_backend().backend()
+        );
+
+        let media_links = LinkSigner::from_config(&config.media.links);
+
         // Run migrations if enabled
         if config.database.migrations.auto_run {
             database.migrate().await?;
@@ -114,6 +130,9 @@ impl AppState {
             database,
             analytics,
             session_store,
+            media_storage,
+            media_links,
+            media_events: MediaEvents::default(),
             config,
         })
     }
@@ -25,6 +25,7 @@ use tower_http::trace::TraceLayer;
 
 use crate::auth::require_authentication;
 use crate::config::AppConfig;
+use crate::media::handlers::block_quarantined_files;
 
 /// Media file extensions that benefit from range requests
 const MEDIA_EXTENSIONS: &[&str] = &[
@@ -95,6 +96,8 @@ pub fn build_enhanced_private_routes(config: &AppConfig) -> Router {
             HeaderValue::from_static("private, max-age=3600, must-revalidate"), // 1 hour for private content
         ))
         .layer(TraceLayer::new_for_http())
+        // Uploads stored below /private are withheld while quarantined
+        .layer(axum_middleware::from_fn(block_quarantined_files))
         .layer(axum_middleware::from_fn(require_authentication))
 }
 
@@ -4,15 +4,20 @@
 //! - HTTP Range requests (RFC 7233) for video/audio streaming
 //! - Proper MIME type detection
 //! - Content-Length and ETag headers
-//! - Conditional requests (If-Range, If-Modified-Since)
+//! - Conditional requests (If-None-Match, If-Modified-Since, If-Range)
+//!
+//! Besides files below a base directory, [`RangeHandler::serve`] can serve
+//! already authorized files, in-memory content or a [`RangeSource`] with
+//! caller-provided validators, e.g. media blobs keyed by their SHA256.
 
+use async_trait::async_trait;
 use axum::{
-    body::Body,
+    body::{Body, Bytes},
     extract::Request,
     http::{
         header::{
             ACCEPT_RANGES, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
-            IF_RANGE, LAST_MODIFIED, RANGE,
+            IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE,
         },
         HeaderMap, HeaderValue, StatusCode,
     },
@@ -22,19 +27,18 @@ use std::{
     fs::Metadata,
     io::SeekFrom,
     path::{Path, PathBuf},
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 use tokio::{
     fs::File,
     io::{AsyncReadExt, AsyncSeekExt},
 };
-use tower::ServiceExt;
-use tower_http::services::ServeFile;
+use tokio_util::io::ReaderStream;
 
 /// Range request handler that supports partial content delivery
 pub struct RangeHandler {
     base_path: PathBuf,
-    cache_max_age: u32,
+    cache_control: String,
 }
 
 /// Represents a parsed Range header
@@ -44,6 +48,32 @@ pub struct ByteRange {
     pub end: Option<u64>,
 }
 
+/// Where the bytes of a response come from
+pub enum RangeContent {
+    /// A file on disk; only the requested range is read
+    File(PathBuf),
+    /// Content already held in memory
+    Bytes(Bytes),
+    /// Content opened on demand, e.g. from remote storage
+    Source(Box<dyn RangeSource>),
+}
+
+/// Content that can be streamed from an offset, such as a remote object
+#[async_trait]
+pub trait RangeSource: Send {
+    /// Body with `length` bytes of the content starting at `start`
+    async fn open(self: Box<Self>, start: u64, length: u64) -> std::io::Result<Body>;
+}
+
+/// Validators and type of the content being served
+#[derive(Debug, Clone)]
+pub struct ContentInfo {
+    pub size: u64,
+    pub etag: String,
+    pub last_modified: Option<SystemTime>,
+    pub content_type: String,
+}
+
 /// Error types for range handling
 #[derive(Debug)]
 pub enum RangeError {
@@ -57,66 +87,104 @@ impl RangeHandler {
     pub fn new(base_path: impl Into<PathBuf>, cache_max_age: u32) -> Self {
         Self {
             base_path: base_path.into(),
-            cache_max_age,
+            cache_control: format!("public, max-age={}", cache_max_age),
         }
     }
 
+    /// Replace the `Cache-Control` value sent with every response
+    pub fn with_cache_control(mut self, cache_control: impl Into<String>) -> Self {
+        self.cache_control = cache_control.into();
+        self
+    }
+
     /// Main handler for static file requests with range support
     pub async fn handle_request(&self, req: Request) -> Result<Response, RangeError> {
-        let path = req.uri().path();
-        let file_path = self.resolve_path(path)?;
+        let file_path = self.resolve_path(req.uri().path())?;
+        self.serve_file(req.headers(), file_path).await
+    }
 
+    /// Serve a file that has already been resolved and authorized
+    pub async fn serve_file(
+        &self,
+        headers: &HeaderMap,
+        file_path: PathBuf,
+    ) -> Result<Response, RangeError> {
         // Check if file exists and get metadata
         let metadata = match tokio::fs::metadata(&file_path).await {
-            Ok(meta) => meta,
-            Err(_) => return Err(RangeError::NotFound),
+            Ok(meta) if meta.is_file() => meta,
+            _ => return Err(RangeError::NotFound),
         };
 
-        // Generate ETag from file metadata
-        let etag = self.generate_etag(&metadata);
+        let info = ContentInfo {
+            size: metadata.len(),
+            etag: self.generate_etag(&metadata),
+            last_modified: metadata.modified().ok(),
+            content_type: self.detect_mime_type(&file_path),
+        };
 
-        // Check if this is a range request
-        if let Some(_range_header) = req.headers().get(RANGE) {
-            self.handle_range_request(req, file_path, metadata, etag)
-                .await
-        } else {
-            self.handle_full_request(req, file_path, metadata, etag)
-                .await
-        }
+        self.serve(headers, RangeContent::File(file_path), &info)
+            .await
     }
 
-    /// Handle a full file request (no ranges)
-    async fn handle_full_request(
+    /// Respond with `content`, honouring conditional and range headers
+    ///
+    /// Matching `If-None-Match`/`If-Modified-Since` validators yield
+    /// `304 Not Modified`, a single satisfiable `Range` yields
+    /// `206 Partial Content` (unless `If-Range` no longer matches), and
+    /// anything else the full content. Files are streamed, not buffered.
+    pub async fn serve(
         &self,
-        req: Request,
-        file_path: PathBuf,
-        metadata: Metadata,
-        etag: String,
-    ) -> Result<Response<Body>, RangeError> {
-        // Use tower-http's ServeFile for full requests as it's optimized
-        let serve_file = ServeFile::new(&file_path);
-
-        match serve_file.oneshot(req).await {
-            Ok(response) => {
-                let (parts, _body) = response.into_parts();
-                let mut response = Response::from_parts(parts, Body::empty());
-                self.add_common_headers(response.headers_mut(), &metadata, &etag);
+        headers: &HeaderMap,
+        content: RangeContent,
+        info: &ContentInfo,
+    ) -> Result<Response, RangeError> {
+        if self.is_not_modified(headers, info) {
+            let mut response = StatusCode::NOT_MODIFIED.into_response();
+            self.add_common_headers(response.headers_mut(), info);
+            return Ok(response);
+        }
+
+        // If-Range validation failing means the client's copy is stale, so
+        // the full content is served instead of the requested range
+        let range_header = headers.get(RANGE).filter(|_| {
+            headers
+                .get(IF_RANGE)
+                .is_none_or(|if_range| self.validate_if_range(if_range, info))
+        });
+
+        let Some(range_header) = range_header else {
+            let body = self.body(content, 0, info.size).await?;
+            let mut response = Response::new(body);
+            self.add_common_headers(response.headers_mut(), info);
+            response
+                .headers_mut()
+                .insert(CONTENT_LENGTH, HeaderValue::from(info.size));
+            return Ok(response);
+        };
+
+        match self.handle_range_request(range_header, content, info).await {
+            // Tell the client the actual size so it can retry (RFC 7233)
+            Err(RangeError::UnsatisfiableRange) => {
+                let mut response = RangeError::UnsatisfiableRange.into_response();
+                self.add_common_headers(response.headers_mut(), info);
+                response.headers_mut().insert(
+                    CONTENT_RANGE,
+                    HeaderValue::from_str(&format!("bytes */{}", info.size)).unwrap(),
+                );
                 Ok(response)
             }
-            Err(_) => Err(RangeError::NotFound),
+            result => result,
         }
     }
 
     /// Handle a range request for partial content
     async fn handle_range_request(
         &self,
-        req: Request,
-        file_path: PathBuf,
-        metadata: Metadata,
-        etag: String,
+        range_header: &HeaderValue,
+        content: RangeContent,
+        info: &ContentInfo,
     ) -> Result<Response<Body>, RangeError> {
-        let file_size = metadata.len();
-        let range_header = req.headers().get(RANGE).unwrap();
+        let file_size = info.size;
 
         // Parse the range header
         let ranges = self.parse_range_header(range_header, file_size)?;
@@ -136,40 +204,19 @@ impl RangeHandler {
             return Err(RangeError::UnsatisfiableRange);
         }
 
-        // Check If-Range header for conditional requests
-        if let Some(if_range) = req.headers().get(IF_RANGE) {
-            if !self.validate_if_range(if_range, &etag, &metadata) {
-                // If-Range validation failed, serve full content
-                return self
-                    .handle_full_request(req, file_path, metadata, etag)
-                    .await;
-            }
-        }
-
-        // Open file and seek to start position
-        let mut file = File::open(&file_path).await.map_err(RangeError::IoError)?;
-        file.seek(SeekFrom::Start(start))
-            .await
-            .map_err(RangeError::IoError)?;
-
-        // Read the requested range
         let content_length = end - start + 1;
-        let mut buffer = vec![0; content_length as usize];
-        file.read_exact(&mut buffer)
-            .await
-            .map_err(RangeError::IoError)?;
+        let body = self.body(content, start, content_length).await?;
 
         // Build response
         let mut response = Response::builder()
             .status(StatusCode::PARTIAL_CONTENT)
-            .body(Body::from(buffer))
+            .body(body)
             .unwrap();
 
         let headers = response.headers_mut();
-        self.add_common_headers(headers, &metadata, &etag);
+        self.add_common_headers(headers, info);
 
         // Add range-specific headers
-        headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
         headers.insert(CONTENT_LENGTH, HeaderValue::from(content_length));
         headers.insert(
             CONTENT_RANGE,
@@ -179,6 +226,58 @@ impl RangeHandler {
         Ok(response)
     }
 
+    /// Body with `length` bytes of the content starting at `start`
+    async fn body(
+        &self,
+        content: RangeContent,
+        start: u64,
+        length: u64,
+    ) -> Result<Body, RangeError> {
+        match content {
+            RangeContent::Bytes(bytes) => {
+                let start = (start as usize).min(bytes.len());
+                let end = (start + length as usize).min(bytes.len());
+                Ok(Body::from(bytes.slice(start..end)))
+            }
+            RangeContent::File(path) => {
+                let mut file = File::open(&path).await.map_err(RangeError::IoError)?;
+                file.seek(SeekFrom::Start(start))
+                    .await
+                    .map_err(RangeError::IoError)?;
+                Ok(Body::from_stream(ReaderStream::new(file.take(length))))
+            }
+            RangeContent::Source(source) => source
+                .open(start, length)
+                .await
+                .map_err(RangeError::IoError),
+        }
+    }
+
+    /// Check `If-None-Match` and `If-Modified-Since` against the content
+    fn is_not_modified(&self, headers: &HeaderMap, info: &ContentInfo) -> bool {
+        // If-None-Match takes precedence over If-Modified-Since (RFC 7232)
+        if let Some(if_none_match) = headers.get(IF_NONE_MATCH) {
+            let Ok(value) = if_none_match.to_str() else {
+                return false;
+            };
+            return value.trim() == "*"
+                || value
+                    .split(',')
+                    .map(|tag| tag.trim().trim_start_matches("W/"))
+                    .any(|tag| tag == info.etag);
+        }
+
+        let if_modified_since = headers
+            .get(IF_MODIFIED_SINCE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| httpdate::parse_http_date(value).ok());
+
+        match (if_modified_since, info.last_modified) {
+            (Some(since), Some(modified)) => truncate_to_seconds(modified) <= since,
+            _ => false,
+        }
+    }
+
     /// Parse Range header into ByteRange structs
     fn parse_range_header(
         &self,
@@ -199,11 +298,9 @@ impl RangeHandler {
         for range_part in ranges_str.split(',') {
             let range_part = range_part.trim();
 
-            if range_part.starts_with('-') {
+            if let Some(suffix) = range_part.strip_prefix('-') {
                 // Suffix range: -500 (last 500 bytes)
-                let suffix_length: u64 = range_part[1..]
-                    .parse()
-                    .map_err(|_| RangeError::InvalidRange)?;
+                let suffix_length: u64 = suffix.parse().map_err(|_| RangeError::InvalidRange)?;
 
                 if suffix_length > 0 && suffix_length <= file_size {
                     ranges.push(ByteRange {
@@ -211,11 +308,9 @@ impl RangeHandler {
                         end: Some(file_size - 1),
                     });
                 }
-            } else if range_part.ends_with('-') {
+            } else if let Some(prefix) = range_part.strip_suffix('-') {
                 // Prefix range: 500- (from byte 500 to end)
-                let start: u64 = range_part[..range_part.len() - 1]
-                    .parse()
-                    .map_err(|_| RangeError::InvalidRange)?;
+                let start: u64 = prefix.parse().map_err(|_| RangeError::InvalidRange)?;
 
                 if start < file_size {
                     ranges.push(ByteRange {
@@ -249,22 +344,24 @@ impl RangeHandler {
     }
 
     /// Add common headers to all responses
-    fn add_common_headers(&self, headers: &mut HeaderMap, metadata: &Metadata, etag: &str) {
+    fn add_common_headers(&self, headers: &mut HeaderMap, info: &ContentInfo) {
         // MIME type detection
-        let mime_type = self.detect_mime_type(&self.base_path);
-        headers.insert(CONTENT_TYPE, HeaderValue::from_str(&mime_type).unwrap());
+        if let Ok(content_type) = HeaderValue::from_str(&info.content_type) {
+            headers.insert(CONTENT_TYPE, content_type);
+        }
 
         // Cache headers
-        headers.insert(
-            CACHE_CONTROL,
-            HeaderValue::from_str(&format!("public, max-age={}", self.cache_max_age)).unwrap(),
-        );
+        if let Ok(cache_control) = HeaderValue::from_str(&self.cache_control) {
+            headers.insert(CACHE_CONTROL, cache_control);
+        }
 
         // ETag for caching
-        headers.insert(ETAG, HeaderValue::from_str(etag).unwrap());
+        if let Ok(etag) = HeaderValue::from_str(&info.etag) {
+            headers.insert(ETAG, etag);
+        }
 
         // Last-Modified
-        if let Ok(modified) = metadata.modified() {
+        if let Some(modified) = info.last_modified {
             let http_date = httpdate::fmt_http_date(modified);
             headers.insert(LAST_MODIFIED, HeaderValue::from_str(&http_date).unwrap());
         }
@@ -294,22 +391,19 @@ impl RangeHandler {
     }
 
     /// Validate If-Range header
-    fn validate_if_range(&self, if_range: &HeaderValue, etag: &str, metadata: &Metadata) -> bool {
+    fn validate_if_range(&self, if_range: &HeaderValue, info: &ContentInfo) -> bool {
         let if_range_str = if_range.to_str().unwrap_or("");
 
         // Check if it's an ETag
         if if_range_str.starts_with('"') && if_range_str.ends_with('"') {
-            if_range_str == etag
+            if_range_str == info.etag
         } else {
             // Check if it's a date
-            if let Ok(if_range_date) = httpdate::parse_http_date(if_range_str) {
-                if let Ok(last_modified) = metadata.modified() {
-                    last_modified <= if_range_date
-                } else {
-                    false
+            match (httpdate::parse_http_date(if_range_str), info.last_modified) {
+                (Ok(if_range_date), Some(last_modified)) => {
+                    truncate_to_seconds(last_modified) <= if_range_date
                 }
-            } else {
-                false
+                _ => false,
             }
         }
     }
@@ -323,9 +417,13 @@ impl RangeHandler {
 
         // Normalize path to prevent directory traversal
         let canonical = full_path.canonicalize().map_err(|_| RangeError::NotFound)?;
+        let base = self
+            .base_path
+            .canonicalize()
+            .map_err(|_| RangeError::NotFound)?;
 
         // Ensure the canonical path is still within base_path
-        if !canonical.starts_with(&self.base_path) {
+        if !canonical.starts_with(&base) {
             return Err(RangeError::NotFound);
         }
 
@@ -348,4 +446,109 @@ impl IntoResponse for RangeError {
     }
 }
 
-use httpdate;
+/// HTTP dates have second precision, so compare modification times likewise
+fn truncate_to_seconds(time: SystemTime) -> SystemTime {
+    let seconds = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(size: u64) -> ContentInfo {
+        ContentInfo {
+            size,
+            etag: "\"abc\"".to_string(),
+            last_modified: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            content_type: "text/plain".to_string(),
+        }
+    }
+
+    async fn body_bytes(response: Response) -> Vec<u8> {
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_serve_full_range_and_not_modified() {
+        let handler = RangeHandler::new(PathBuf::new(), 60);
+        let content = || RangeContent::Bytes(Bytes::from_static(b"0123456789"));
+
+        let response = handler
+            .serve(&HeaderMap::new(), content(), &info(10))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_LENGTH], "10");
+        assert_eq!(body_bytes(response).await, b"0123456789");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(RANGE, HeaderValue::from_static("bytes=-3"));
+        let response = handler.serve(&headers, content(), &info(10)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 7-9/10");
+        assert_eq!(body_bytes(response).await, b"789");
+
+        // A stale If-Range validator gets the full content instead
+        headers.insert(IF_RANGE, HeaderValue::from_static("\"other\""));
+        let response = handler.serve(&headers, content(), &info(10)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        headers.insert(RANGE, HeaderValue::from_static("bytes=10-"));
+        headers.remove(IF_RANGE);
+        let response = handler.serve(&headers, content(), &info(10)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes */10");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static("\"abc\""));
+        let response = handler.serve(&headers, content(), &info(10)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            IF_MODIFIED_SINCE,
+            HeaderValue::from_str(&httpdate::fmt_http_date(
+                SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            ))
+            .unwrap(),
+        );
+        let response = handler.serve(&headers, content(), &info(10)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn test_handle_request_streams_files_below_base() {
+        let base = std::env::temp_dir().join(format!("range-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&base).unwrap();
+        std::fs::write(base.join("clip.mp4"), b"not really a video").unwrap();
+        let handler = RangeHandler::new(&base, 60);
+
+        let request = Request::get("/clip.mp4")
+            .header(RANGE, "bytes=4-9")
+            .body(Body::empty())
+            .unwrap();
+        let response = handler.handle_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_TYPE], "video/mp4");
+        assert_eq!(body_bytes(response).await, b"really");
+
+        let request = Request::get("/clip.mp4").body(Body::empty()).unwrap();
+        let response = handler.handle_request(request).await.unwrap();
+        assert_eq!(body_bytes(response).await, b"not really a video");
+
+        let request = Request::get("/../etc/passwd").body(Body::empty()).unwrap();
+        assert!(matches!(
+            handler.handle_request(request).await,
+            Err(RangeError::NotFound)
+        ));
+
+        let _ = std::fs::remove_dir_all(base);
+    }
+}
@@ -8,19 +8,14 @@ use time::OffsetDateTime;
 use uuid::Uuid;
 
 /// Storage backend configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum StorageBackend {
+    #[default]
     Memory,
     Postgres,
 }
 
-impl Default for StorageBackend {
-    fn default() -> Self {
-        Self::Memory
-    }
-}
-
 /// Configuration for storage backends
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StorageConfig {
@@ -187,7 +182,7 @@ impl MemoryAnalyticsStore {
             })
             .collect();
 
-        result.sort_by(|a, b| b.request_count.cmp(&a.request_count));
+        result.sort_by_key(|p| std::cmp::Reverse(p.request_count));
         result.truncate(limit as usize);
 
         Ok(result)
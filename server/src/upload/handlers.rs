@@ -1,51 +1,50 @@
 //! Upload handlers for large file operations
 //!
-//! This module contains HTTP handlers for uploading large files (>10MB)
-//! that are stored to disk and referenced via local_path in the database.
+//! This module contains HTTP handlers for uploading large files (>10MB).
+//! File fields are streamed to a staging file while being hashed, then handed
+//! to the configured media storage backend; when that is the filesystem under
+//! `assets/`, the record's local_path points at the file.
 
 use axum::{extract::Multipart, http::StatusCode, response::Json, Extension};
-use sha2::{Digest, Sha256};
-use tokio::fs;
-use tokio::io::AsyncWriteExt;
 use tracing::{error, info, warn};
 use uuid::Uuid;
 
-use crate::auth::{models::User, AuthenticatedUser};
-use crate::config::AppConfig;
+use crate::auth::AuthenticatedUser;
+use crate::config::{AppConfig, MediaConfig, MediaStorageBackend};
 use crate::database::DatabaseConnection;
-use crate::error::AppError;
+use crate::error::{AppError, WebauthnError};
 use crate::media::models::{CreateMediaBlob, MediaBlob};
 use crate::media::repository::MediaRepository;
+use crate::media::{MediaEvents, MediaService, MediaStorage, StagedFile, StagingError};
 
-use super::models::{UploadConfig, UploadRequest, UploadResponse};
+use super::models::{UploadConfig, UploadError, UploadRequest, UploadResponse};
 
 /// Upload a large file (admin only)
+///
+/// The `metadata` field must come before the `file` field. If content with the
+/// same SHA256 is already stored, a new record referencing it is returned as
+/// soon as the metadata is read and the file field is never transferred.
+/// Otherwise the file is streamed to a staging file, so memory use does not
+/// grow with the upload size. Either way the declared type must match the
+/// content and pass the HTTP MIME type rules, or the upload fails with `415`.
 pub async fn upload_large_file(
     Extension(db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<MediaStorage>,
     Extension(config): Extension<AppConfig>,
+    Extension(events): Extension<MediaEvents>,
     Extension(user): Extension<AuthenticatedUser>,
     mut multipart: Multipart,
 ) -> Result<Json<UploadResponse>, AppError> {
-    let upload_config = UploadConfig {
-        upload_directory: config.static_files.upload_directory.clone().into(),
-        ..Default::default()
-    };
-
-    // Ensure upload directory exists
-    if let Err(e) = fs::create_dir_all(&upload_config.upload_directory).await {
-        error!("Failed to create upload directory: {}", e);
-        return Err(AppError::InternalServerError(
-            "Failed to prepare upload directory".to_string(),
-        ));
-    }
-
+    let upload_config = UploadConfig::from_media_config(&config.media);
+    let media_config = upload_limits(&config.media);
+    let service = MediaService::new(MediaRepository::new(&db), &storage).with_events(&events);
     let mut upload_request: Option<UploadRequest> = None;
-    let mut file_data: Option<Vec<u8>> = None;
+    let mut staged: Option<StagedFile> = None;
 
     // Process multipart form data
     info!("Starting multipart processing for upload");
 
-    while let Some(field) = multipart.next_field().await.map_err(|e| {
+    while let Some(mut field) = multipart.next_field().await.map_err(|e| {
         error!("Failed to read multipart field: {}", e);
         AppError::BadRequest(format!("Error parsing multipart request: {}", e))
     })? {
@@ -60,20 +59,89 @@ pub async fn upload_large_file(
                 })?;
 
                 info!("Received metadata field, {} bytes", data.len());
-                upload_request = Some(serde_json::from_slice(&data).map_err(|e| {
+                let request: UploadRequest = serde_json::from_slice(&data).map_err(|e| {
                     error!("Failed to parse metadata JSON: {}", e);
                     AppError::BadRequest(format!("Invalid metadata JSON: {}", e))
-                })?);
+                })?;
+
+                request
+                    .validate(&upload_config)
+                    .map_err(upload_validation_error)?;
+                service
+                    .check_quota(user.user().id, request.size as i64, &config.media.quotas)
+                    .await
+                    .map_err(|e| upload_create_error(&request.sha256, e))?;
+
+                // Content the user can already see: reference it without
+                // reading the file. Other stored content must be sent like
+                // new content, so the reply does not reveal it is stored.
+                let referenced = service
+                    .can_reference(&request.sha256, user.user())
+                    .await
+                    .map_err(|e| upload_create_error(&request.sha256, e))?;
+                let stored = match referenced {
+                    true => service
+                        .find_object(&request.sha256)
+                        .await
+                        .map_err(|e| upload_create_error(&request.sha256, e))?,
+                    false => None,
+                };
+                if let Some(object) = stored {
+                    if object.size as u64 != request.size {
+                        return Err(AppError::BadRequest(format!(
+                            "File size mismatch: expected {}, stored content is {}",
+                            request.size, object.size
+                        )));
+                    }
+
+                    let mime = service
+                        .check_content_type(
+                            request.infer_mime_type().as_deref(),
+                            None,
+                            &request.sha256,
+                            &upload_config.mime_types,
+                        )
+                        .await
+                        .map_err(|e| upload_create_error(&request.sha256, e))?;
+                    let local_path = served_path(object.storage_backend, &object.storage_key);
+                    let params = upload_blob_params(&request, &user, mime, local_path);
+                    let media_blob = service
+                        .create_upload(params, user.user(), &media_config)
+                        .await
+                        .map_err(|e| upload_create_error(&request.sha256, e))?;
+
+                    info!(
+                        "Upload of {} matched stored content, created record {}",
+                        request.filename, media_blob.id
+                    );
+                    return Ok(Json(upload_response(media_blob, request)));
+                }
+
+                upload_request = Some(request);
             }
             "file" => {
-                info!("Processing file field");
-                let file_bytes = field.bytes().await.map_err(|e| {
+                let Some(request) = upload_request.as_ref() else {
+                    return Err(AppError::BadRequest(
+                        "The metadata field must precede the file field".to_string(),
+                    ));
+                };
+
+                // Stream the field to disk, never accepting more than declared
+                info!("Streaming file field to staging");
+                let mut file = StagedFile::create(&storage.staging_directory(), request.size)
+                    .await
+                    .map_err(staging_error)?;
+
+                while let Some(chunk) = field.chunk().await.map_err(|e| {
                     error!("Failed to read file data: {}", e);
                     AppError::BadRequest(format!("Failed to read file data: {}", e))
-                })?;
+                })? {
+                    file.write_chunk(&chunk).await.map_err(staging_error)?;
+                }
+                file.finish().await.map_err(staging_error)?;
 
-                info!("Received file data, {} bytes", file_bytes.len());
-                file_data = Some(file_bytes.to_vec());
+                info!("Received file data, {} bytes", file.size());
+                staged = Some(file);
             }
             _ => {
                 warn!("Unexpected multipart field: {}", name);
@@ -85,35 +153,26 @@ pub async fn upload_large_file(
         AppError::BadRequest("Missing metadata field in multipart request".to_string())
     })?;
 
-    let file_data = file_data.ok_or_else(|| {
+    let staged = staged.ok_or_else(|| {
         AppError::BadRequest("Missing file field in multipart request".to_string())
     })?;
 
     // Validate file size matches request
-    if file_data.len() as u64 != upload_request.size {
+    if staged.size() != upload_request.size {
         return Err(AppError::BadRequest(format!(
             "File size mismatch: expected {}, got {}",
             upload_request.size,
-            file_data.len()
+            staged.size()
         )));
     }
 
     // Validate SHA256 hash
-    let mut hasher = Sha256::new();
-    hasher.update(&file_data);
-    let computed_hash = format!("{:x}", hasher.finalize());
-
-    if computed_hash != upload_request.sha256 {
+    if staged.sha256() != Some(upload_request.sha256.as_str()) {
         return Err(AppError::BadRequest(
             "SHA256 hash verification failed".to_string(),
         ));
     }
 
-    // Validate upload request
-    upload_request
-        .validate(&upload_config)
-        .map_err(|e| AppError::BadRequest(format!("Upload validation failed: {}", e)))?;
-
     info!(
         "Processing large file upload: {} ({} bytes) for user {}",
         upload_request.filename,
@@ -121,113 +180,158 @@ pub async fn upload_large_file(
         user.user().username
     );
 
-    // Check for duplicate files if enabled
-    if upload_config.check_duplicates {
-        let repo = MediaRepository::new(&db);
-        if let Ok(existing) = repo.get_by_sha256(&upload_request.sha256).await {
-            info!("File with hash {} already exists", upload_request.sha256);
-            return Err(AppError::Conflict(format!(
-                "File already exists with ID: {}",
-                existing.id
-            )));
-        }
-    }
+    let mime = service
+        .check_content_type(
+            upload_request.infer_mime_type().as_deref(),
+            Some(staged.head()),
+            &upload_request.sha256,
+            &upload_config.mime_types,
+        )
+        .await
+        .map_err(|e| upload_create_error(&upload_request.sha256, e))?;
 
-    // Generate storage filename and path
-    let storage_filename = upload_request.generate_storage_filename();
-    let file_path = upload_config.upload_directory.join(&storage_filename);
+    // Content is only written if no other upload has stored it in the meantime
+    let backend = storage.default_backend();
+    let local_path = served_path(backend.backend(), &backend.key_for(&upload_request.sha256));
 
-    println!(
-        "ZOMG GOT AN UPLOAD!!! storage_filename:{}, and then file_path:{:?}",
-        &storage_filename, &file_path
-    );
+    let params = upload_blob_params(&upload_request, &user, mime, local_path);
+    let media_blob = service
+        .create_blob_from_file(params, &staged, &media_config)
+        .await
+        .map_err(|e| upload_create_error(&upload_request.sha256, e))?;
 
-    // Write file to disk
-    let mut file = fs::File::create(&file_path).await.map_err(|e| {
-        error!("Failed to create file {}: {}", file_path.display(), e);
-        AppError::InternalServerError("Failed to save file".to_string())
-    })?;
+    info!(
+        "Successfully uploaded large file: {} (ID: {})",
+        upload_request.filename, media_blob.id
+    );
 
-    file.write_all(&file_data).await.map_err(|e| {
-        error!("Failed to write file data: {}", e);
-        AppError::InternalServerError("Failed to write file data".to_string())
-    })?;
+    Ok(Json(upload_response(media_blob, upload_request)))
+}
 
-    file.sync_all().await.map_err(|e| {
-        error!("Failed to sync file: {}", e);
-        AppError::InternalServerError("Failed to sync file to disk".to_string())
-    })?;
+/// Media limits for uploads, which may use the full filesystem size limit
+pub(crate) fn upload_limits(media: &MediaConfig) -> MediaConfig {
+    MediaConfig {
+        max_blob_file_size: media.max_fs_file_size,
+        ..media.clone()
+    }
+}
 
-    info!("File saved to: {}", file_path.display());
+/// Path below `assets/` content is served from, if it is stored there
+pub(crate) fn served_path(backend: MediaStorageBackend, storage_key: &str) -> Option<String> {
+    match backend {
+        MediaStorageBackend::Filesystem => storage_key
+            .strip_prefix("assets/")
+            .map(|path| path.to_string()),
+        _ => None,
+    }
+}
 
-    // Create relative path for database storage
-    let relative_path = format!(
-        "{}/{}",
-        config
-            .static_files
-            .upload_directory
-            .trim_start_matches("assets/"),
-        storage_filename
-    );
+/// File a local path returned by [`served_path`] refers to
+pub(crate) fn served_file(local_path: &str) -> std::path::PathBuf {
+    std::path::Path::new("assets").join(local_path)
+}
 
-    // Create media blob record in database
-    let media_blob_params = CreateMediaBlob {
-        data: None, // No binary data for large files
-        sha256: upload_request.sha256.clone(),
-        size: Some(upload_request.size as i64),
-        mime: upload_request.infer_mime_type(),
+/// Build the media record parameters for an upload
+///
+/// `mime` is the checked type returned by [`MediaService::check_content_type`].
+/// The original filename is kept in the metadata as `filename`, where search
+/// finds it, unless the client set one there itself.
+pub(crate) fn upload_blob_params(
+    request: &UploadRequest,
+    user: &AuthenticatedUser,
+    mime: Option<String>,
+    local_path: Option<String>,
+) -> CreateMediaBlob {
+    CreateMediaBlob {
+        data: None,
+        sha256: request.sha256.clone(),
+        size: Some(request.size as i64),
+        mime,
         source_client_id: Some(format!("admin_upload_{}", user.user().id)),
-        local_path: Some(relative_path.clone()),
-        metadata: upload_request.metadata,
-    };
-
-    let repo = MediaRepository::new(&db);
-    let media_blob = repo
-        .create(media_blob_params, &config.media)
-        .await
-        .map_err(|e| {
-            error!("Failed to create media blob record: {}", e);
-            // Clean up the file if database insert fails
-            if let Err(cleanup_err) = std::fs::remove_file(&file_path) {
-                error!(
-                    "Failed to cleanup file after database error: {}",
-                    cleanup_err
-                );
-            }
-            AppError::InternalServerError("Failed to create media record".to_string())
-        })?;
+        owner_user_id: Some(user.user().id),
+        visibility: request.visibility,
+        local_path,
+        metadata: upload_metadata(request),
+    }
+}
 
-    info!(
-        "Successfully uploaded large file: {} (ID: {})",
-        upload_request.filename, media_blob.id
-    );
+/// The request's metadata with the upload's filename added
+fn upload_metadata(request: &UploadRequest) -> serde_json::Value {
+    let mut metadata = match &request.metadata {
+        serde_json::Value::Object(metadata) => metadata.clone(),
+        serde_json::Value::Null => serde_json::Map::new(),
+        other => return other.clone(),
+    };
+    metadata
+        .entry("filename")
+        .or_insert_with(|| serde_json::Value::String(request.filename.clone()));
+    serde_json::Value::Object(metadata)
+}
 
-    let response = UploadResponse {
+/// Build the response for a completed upload
+pub(crate) fn upload_response(media_blob: MediaBlob, request: UploadRequest) -> UploadResponse {
+    UploadResponse {
         id: media_blob.id,
-        local_path: relative_path,
-        sha256: upload_request.sha256,
-        size: upload_request.size,
+        local_path: media_blob.local_path.unwrap_or_default(),
+        sha256: request.sha256,
+        size: request.size,
         mime_type: media_blob.mime,
         created_at: media_blob.created_at,
-    };
+    }
+}
 
-    Ok(Json(response))
+/// Map a rejected upload request onto an HTTP error
+pub(crate) fn upload_validation_error(err: UploadError) -> AppError {
+    match err {
+        UploadError::UnsupportedMimeType(_) => AppError::UnsupportedMediaType(err.to_string()),
+        e => AppError::BadRequest(format!("Upload validation failed: {}", e)),
+    }
+}
+
+/// Map a failure to stage an uploaded file onto an HTTP error
+fn staging_error(err: StagingError) -> AppError {
+    match err {
+        StagingError::TooLarge { limit } => AppError::PayloadTooLarge(format!(
+            "File is larger than the declared size of {} bytes",
+            limit
+        )),
+        StagingError::Io(e) => {
+            error!("Failed to stage uploaded file: {}", e);
+            AppError::InternalServerError("Failed to store uploaded file".to_string())
+        }
+    }
+}
+
+/// Map a failure to store an upload onto an HTTP error
+pub(crate) fn upload_create_error(sha256: &str, err: WebauthnError) -> AppError {
+    match err {
+        WebauthnError::BadRequest => {
+            AppError::BadRequest("Upload rejected by media validation".to_string())
+        }
+        WebauthnError::MediaContentType(e) => AppError::UnsupportedMediaType(e.to_string()),
+        WebauthnError::MediaQuota(e) => AppError::InsufficientStorage(e.to_string()),
+        e => {
+            error!("Failed to create media record for {}: {}", sha256, e);
+            AppError::InternalServerError("Failed to create media record".to_string())
+        }
+    }
 }
 
 /// Get upload status/info by media blob ID (authenticated users)
 pub async fn get_upload_info(
     Extension(db): Extension<DatabaseConnection>,
-    Extension(_user): Extension<User>,
+    Extension(storage): Extension<MediaStorage>,
+    Extension(user): Extension<AuthenticatedUser>,
     axum::extract::Path(id): axum::extract::Path<Uuid>,
 ) -> Result<Json<MediaBlob>, AppError> {
-    let repo = MediaRepository::new(&db);
-    let media_blob = repo.find_by_id(id).await.map_err(|e| {
-        error!("Failed to find media blob {}: {}", id, e);
-        AppError::NotFound("Upload not found".to_string())
-    })?;
+    let service = MediaService::new(MediaRepository::new(&db), &storage);
+    let media_blob = service
+        .get_blob(id, false, user.user())
+        .await
+        .map_err(|e| upload_access_error(id, e))?;
 
-    // Only return blobs that have local_path (uploaded files)
-    if media_blob.local_path.is_some() {
+    // Only return uploaded files
+    if media_blob.is_upload() {
         Ok(Json(media_blob.without_data()))
     } else {
         Err(AppError::NotFound("Upload not found".to_string()))
@@ -237,28 +341,25 @@ pub async fn get_upload_info(
 /// List all uploaded files (authenticated users)
 pub async fn list_uploads(
     Extension(db): Extension<DatabaseConnection>,
-    Extension(_user): Extension<User>,
+    Extension(storage): Extension<MediaStorage>,
+    Extension(user): Extension<AuthenticatedUser>,
     axum::extract::Query(params): axum::extract::Query<ListUploadsQuery>,
 ) -> Result<Json<ListUploadsResponse>, AppError> {
-    let repo = MediaRepository::new(&db);
+    let service = MediaService::new(MediaRepository::new(&db), &storage);
 
-    // Query only blobs with local_path (uploaded files)
     let query = crate::media::models::MediaBlobQuery {
         limit: params.limit.map(|l| l.min(100)), // Cap at 100
         offset: params.offset,
         ..Default::default()
     };
 
-    let blobs = repo.query(query).await.map_err(|e| {
+    let blobs = service.list_blobs(query, user.user()).await.map_err(|e| {
         error!("Failed to list uploads: {}", e);
         AppError::InternalServerError("Failed to list uploads".to_string())
     })?;
 
-    // Filter to only include blobs with local_path
-    let upload_blobs: Vec<_> = blobs
-        .into_iter()
-        .filter(|b| b.local_path.is_some())
-        .collect();
+    // Filter to only include uploaded files
+    let upload_blobs: Vec<_> = blobs.into_iter().filter(|b| b.is_upload()).collect();
     let total_count = upload_blobs.len() as i64;
 
     let response = ListUploadsResponse {
@@ -287,45 +388,53 @@ pub struct ListUploadsResponse {
     pub offset: i64,
 }
 
-/// Delete an uploaded file (admin only)
+/// Delete an uploaded file (owner or admin)
 pub async fn delete_upload(
     Extension(db): Extension<DatabaseConnection>,
-    Extension(_config): Extension<AppConfig>,
-    Extension(_user): Extension<AuthenticatedUser>,
+    Extension(storage): Extension<MediaStorage>,
+    Extension(events): Extension<MediaEvents>,
+    Extension(user): Extension<AuthenticatedUser>,
     axum::extract::Path(id): axum::extract::Path<Uuid>,
 ) -> Result<StatusCode, AppError> {
-    let repo = MediaRepository::new(&db);
-    let media_blob = repo.find_by_id(id).await.map_err(|e| {
-        error!("Failed to find media blob {}: {}", id, e);
-        AppError::NotFound("Upload not found".to_string())
-    })?;
+    let service = MediaService::new(MediaRepository::new(&db), &storage).with_events(&events);
+    let media_blob = service
+        .get_blob(id, false, user.user())
+        .await
+        .map_err(|e| upload_access_error(id, e))?;
 
-    // Only delete if it has a local_path (is an uploaded file)
-    let local_path = media_blob
-        .local_path
-        .ok_or_else(|| AppError::BadRequest("Cannot delete non-uploaded media blob".to_string()))?;
-
-    // Construct full file path
-    let full_path = std::path::Path::new("assets").join(&local_path);
-
-    // Delete file from disk
-    if full_path.exists() {
-        if let Err(e) = fs::remove_file(&full_path).await {
-            warn!("Failed to delete file {}: {}", full_path.display(), e);
-            // Continue with database deletion even if file deletion fails
-        } else {
-            info!("Deleted file: {}", full_path.display());
-        }
-    } else {
-        warn!("File not found on disk: {}", full_path.display());
+    if !MediaService::can_manage(&media_blob, user.user()) {
+        return Err(AppError::Forbidden(
+            "Only the owner of an upload can delete it".to_string(),
+        ));
     }
 
-    // Delete database record
-    repo.delete(id).await.map_err(|e| {
+    // Only delete uploaded files
+    if !media_blob.is_upload() {
+        return Err(AppError::BadRequest(
+            "Cannot delete non-uploaded media blob".to_string(),
+        ));
+    }
+
+    // Move the record to the trash; the content is purged with it later
+    service.trash_blob(id, user.user()).await.map_err(|e| {
         error!("Failed to delete media blob record {}: {}", id, e);
         AppError::InternalServerError("Failed to delete media record".to_string())
     })?;
 
-    info!("Successfully deleted upload: {} (ID: {})", local_path, id);
+    info!("Successfully deleted upload: {}", id);
     Ok(StatusCode::NO_CONTENT)
 }
+
+/// Map a media access failure onto an HTTP error for upload endpoints
+fn upload_access_error(id: Uuid, err: WebauthnError) -> AppError {
+    match err {
+        WebauthnError::UserNotFound => AppError::NotFound("Upload not found".to_string()),
+        WebauthnError::Forbidden => {
+            AppError::Forbidden("Only the owner of an upload can manage it".to_string())
+        }
+        e => {
+            error!("Failed to load media blob {}: {}", id, e);
+            AppError::InternalServerError("Failed to load upload".to_string())
+        }
+    }
+}
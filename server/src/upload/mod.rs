@@ -1,13 +1,17 @@
 //! Upload module
 //!
-//! This module handles large file uploads (>10MB) for admin users.
-//! Files are stored to disk in the configured upload directory and
-//! referenced via local_path in the media_blobs table.
+//! This module handles large file uploads (>10MB) for admin users, either as
+//! a single multipart request or as a resumable session sent in chunks.
+//! Content is stored through the media service like any other media blob.
 
 pub mod handlers;
 pub mod models;
+pub mod repository;
 pub mod routes;
+pub mod sessions;
 
 pub use handlers::*;
 pub use models::*;
+pub use repository::UploadSessionRepository;
 pub use routes::*;
+pub use sessions::*;
@@ -6,6 +6,9 @@
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
+use crate::config::{MediaConfig, MimeTypeRules};
+use crate::media::MediaVisibility;
+
 /// Request payload for large file upload
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UploadRequest {
@@ -20,6 +23,9 @@ pub struct UploadRequest {
     /// Additional metadata as JSON
     #[serde(default)]
     pub metadata: serde_json::Value,
+    /// Who may access the uploaded file (defaults to private)
+    #[serde(default)]
+    pub visibility: MediaVisibility,
 }
 
 /// Response after successful file upload
@@ -40,6 +46,55 @@ pub struct UploadResponse {
     pub created_at: time::OffsetDateTime,
 }
 
+/// A resumable upload in progress
+///
+/// Chunks are appended to `staging_path` at `upload_offset`; once the offset
+/// reaches `size` the session can be completed into a media blob.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct UploadSession {
+    pub id: uuid::Uuid,
+    pub owner_user_id: uuid::Uuid,
+    pub filename: String,
+    pub mime_type: Option<String>,
+    pub sha256: String,
+    pub size: i64,
+    pub upload_offset: i64,
+    pub visibility: MediaVisibility,
+    pub metadata: serde_json::Value,
+    #[serde(skip_serializing)]
+    pub staging_path: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: time::OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub updated_at: time::OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub expires_at: time::OffsetDateTime,
+}
+
+impl UploadSession {
+    /// Check whether all declared bytes have been received
+    pub fn is_complete(&self) -> bool {
+        self.upload_offset == self.size
+    }
+
+    /// Check whether the session has expired
+    pub fn is_expired(&self, now: time::OffsetDateTime) -> bool {
+        self.expires_at <= now
+    }
+
+    /// The upload request this session was created from
+    pub fn request(&self) -> UploadRequest {
+        UploadRequest {
+            filename: self.filename.clone(),
+            mime_type: self.mime_type.clone(),
+            sha256: self.sha256.clone(),
+            size: self.size as u64,
+            metadata: self.metadata.clone(),
+            visibility: self.visibility,
+        }
+    }
+}
+
 /// Upload validation error types
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum UploadError {
@@ -111,10 +166,8 @@ pub struct UploadConfig {
     pub max_file_size: u64,
     /// Upload directory path
     pub upload_directory: PathBuf,
-    /// Allowed MIME types (None = allow all)
-    pub allowed_mime_types: Option<Vec<String>>,
-    /// Whether to check for duplicate files by hash
-    pub check_duplicates: bool,
+    /// MIME types that may be uploaded
+    pub mime_types: MimeTypeRules,
 }
 
 impl Default for UploadConfig {
@@ -123,8 +176,17 @@ impl Default for UploadConfig {
             min_file_size: 10 * 1024 * 1024,   // 10MB
             max_file_size: 1024 * 1024 * 1024, // 1GB
             upload_directory: PathBuf::from("assets/private/uploads"),
-            allowed_mime_types: None, // Allow all MIME types
-            check_duplicates: true,
+            mime_types: MimeTypeRules::default(), // Allow all MIME types
+        }
+    }
+}
+
+impl UploadConfig {
+    /// Defaults with the HTTP MIME type rules from the media configuration
+    pub fn from_media_config(media: &MediaConfig) -> Self {
+        Self {
+            mime_types: media.mime_types.http.clone(),
+            ..Self::default()
         }
     }
 }
@@ -177,12 +239,11 @@ impl UploadRequest {
             ));
         }
 
-        // Check MIME type if restrictions are configured
-        if let Some(ref allowed_types) = config.allowed_mime_types {
-            if let Some(ref mime) = self.mime_type {
-                if !allowed_types.contains(mime) {
-                    return Err(UploadError::UnsupportedMimeType(mime.clone()));
-                }
+        // Check the declared or inferred MIME type; the content itself is
+        // checked once it has been received
+        if let Some(mime) = self.infer_mime_type() {
+            if !config.mime_types.permits(&mime) {
+                return Err(UploadError::UnsupportedMimeType(mime));
             }
         }
 
@@ -204,8 +265,8 @@ impl UploadRequest {
     /// Extract file extension from filename
     pub fn get_file_extension(&self) -> Option<&str> {
         self.filename
-            .split('.')
-            .last()
+            .rsplit('.')
+            .next()
             .filter(|ext| !ext.is_empty())
     }
 
@@ -249,6 +310,7 @@ mod tests {
             sha256: "a".repeat(64),
             size: 20 * 1024 * 1024, // 20MB
             metadata: serde_json::Value::Null,
+            visibility: MediaVisibility::default(),
         };
         assert!(valid_request.validate(&config).is_ok());
 
@@ -301,6 +363,7 @@ mod tests {
             sha256: "abcdef1234567890".repeat(4), // 64 chars
             size: 20 * 1024 * 1024,
             metadata: serde_json::Value::Null,
+            visibility: MediaVisibility::default(),
         };
 
         let storage_name = request.generate_storage_filename();
@@ -316,8 +379,48 @@ mod tests {
             sha256: "a".repeat(64),
             size: 20 * 1024 * 1024,
             metadata: serde_json::Value::Null,
+            visibility: MediaVisibility::default(),
         };
 
         assert_eq!(request.infer_mime_type(), Some("image/png".to_string()));
     }
+
+    #[test]
+    fn test_mime_type_rules_validation() {
+        let config = UploadConfig {
+            mime_types: MimeTypeRules {
+                allow: vec!["image/*".to_string()],
+                deny: vec!["image/svg+xml".to_string()],
+            },
+            ..UploadConfig::default()
+        };
+        let request = UploadRequest {
+            filename: "test.png".to_string(),
+            mime_type: None,
+            sha256: "a".repeat(64),
+            size: 20 * 1024 * 1024,
+            metadata: serde_json::Value::Null,
+            visibility: MediaVisibility::default(),
+        };
+        assert!(request.validate(&config).is_ok());
+
+        // The inferred type is checked when none is declared
+        let pdf = UploadRequest {
+            filename: "test.pdf".to_string(),
+            ..request.clone()
+        };
+        assert!(matches!(
+            pdf.validate(&config),
+            Err(UploadError::UnsupportedMimeType(mime)) if mime == "application/pdf"
+        ));
+
+        let svg = UploadRequest {
+            mime_type: Some("image/svg+xml".to_string()),
+            ..request
+        };
+        assert!(matches!(
+            svg.validate(&config),
+            Err(UploadError::UnsupportedMimeType(_))
+        ));
+    }
 }
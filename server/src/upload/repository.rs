@@ -0,0 +1,195 @@
+//! Upload session repository for database operations
+//!
+//! Resumable upload sessions are stored in Postgres so a client can continue
+//! an upload after a dropped connection or a server restart.
+
+use sqlx::{PgConnection, PgExecutor, Postgres, Transaction};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::database::DatabaseConnection;
+use crate::error::WebauthnError;
+use crate::media::MediaVisibility;
+
+use super::models::UploadSession;
+
+/// Upload session repository for database operations
+pub struct UploadSessionRepository<'a> {
+    db: &'a DatabaseConnection,
+}
+
+impl<'a> UploadSessionRepository<'a> {
+    /// Create a new repository instance
+    pub fn new(db: &'a DatabaseConnection) -> Self {
+        Self { db }
+    }
+
+    /// Start a transaction for work on a locked session
+    pub async fn begin(&self) -> Result<Transaction<'static, Postgres>, WebauthnError> {
+        Ok(self.db.begin().await?)
+    }
+
+    /// Insert a new upload session
+    pub async fn create(&self, session: &UploadSession) -> Result<(), WebauthnError> {
+        sqlx::query!(
+            r#"
+            INSERT INTO media_upload_sessions (
+                id, owner_user_id, filename, mime_type, sha256, size, upload_offset,
+                visibility, metadata, staging_path, created_at, updated_at, expires_at
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+            "#,
+            session.id,
+            session.owner_user_id,
+            session.filename,
+            session.mime_type,
+            session.sha256,
+            session.size,
+            session.upload_offset,
+            session.visibility as MediaVisibility,
+            session.metadata,
+            session.staging_path,
+            session.created_at,
+            session.updated_at,
+            session.expires_at
+        )
+        .execute(self.db.pool())
+        .await?;
+
+        Ok(())
+    }
+
+    /// Find an upload session by ID
+    pub async fn find(&self, id: Uuid) -> Result<Option<UploadSession>, WebauthnError> {
+        let session = sqlx::query_as!(
+            UploadSession,
+            r#"
+            SELECT id, owner_user_id, filename, mime_type, sha256, size, upload_offset,
+                   visibility as "visibility: MediaVisibility", metadata, staging_path,
+                   created_at, updated_at, expires_at
+            FROM media_upload_sessions
+            WHERE id = $1
+            "#,
+            id
+        )
+        .fetch_optional(self.db.pool())
+        .await?;
+
+        Ok(session)
+    }
+
+    /// Lock an upload session for the rest of the transaction
+    ///
+    /// Returns `None` if the session does not exist or another request holds
+    /// the lock; only one request may write to a session at a time.
+    pub async fn lock_in(
+        &self,
+        conn: &mut PgConnection,
+        id: Uuid,
+    ) -> Result<Option<UploadSession>, WebauthnError> {
+        let session = sqlx::query_as!(
+            UploadSession,
+            r#"
+            SELECT id, owner_user_id, filename, mime_type, sha256, size, upload_offset,
+                   visibility as "visibility: MediaVisibility", metadata, staging_path,
+                   created_at, updated_at, expires_at
+            FROM media_upload_sessions
+            WHERE id = $1
+            FOR UPDATE SKIP LOCKED
+            "#,
+            id
+        )
+        .fetch_optional(conn)
+        .await?;
+
+        Ok(session)
+    }
+
+    /// Record received bytes and extend the session's expiry
+    ///
+    /// Only applies if the session is still at `expected_offset`; returns
+    /// whether it was. A session deleted or advanced in the meantime is left
+    /// alone.
+    pub async fn advance_offset(
+        &self,
+        id: Uuid,
+        expected_offset: i64,
+        upload_offset: i64,
+        expires_at: OffsetDateTime,
+    ) -> Result<bool, WebauthnError> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE media_upload_sessions
+            SET upload_offset = $3, expires_at = $4, updated_at = now()
+            WHERE id = $1 AND upload_offset = $2
+            "#,
+            id,
+            expected_offset,
+            upload_offset,
+            expires_at
+        )
+        .execute(self.db.pool())
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Total declared size of a user's unfinished upload sessions
+    pub async fn reserved_bytes(&self, owner_user_id: Uuid) -> Result<i64, WebauthnError> {
+        let bytes = sqlx::query_scalar!(
+            r#"
+            SELECT COALESCE(SUM(size), 0)::bigint as "bytes!"
+            FROM media_upload_sessions
+            WHERE owner_user_id = $1 AND expires_at > now()
+            "#,
+            owner_user_id
+        )
+        .fetch_one(self.db.pool())
+        .await?;
+
+        Ok(bytes)
+    }
+
+    /// Delete an upload session
+    pub async fn delete_in<'e, E>(&self, executor: E, id: Uuid) -> Result<bool, WebauthnError>
+    where
+        E: PgExecutor<'e>,
+    {
+        let result = sqlx::query!("DELETE FROM media_upload_sessions WHERE id = $1", id)
+            .execute(executor)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Partial file paths of all open sessions
+    pub async fn staging_paths(&self) -> Result<Vec<String>, WebauthnError> {
+        let paths = sqlx::query_scalar!("SELECT staging_path FROM media_upload_sessions")
+            .fetch_all(self.db.pool())
+            .await?;
+
+        Ok(paths)
+    }
+
+    /// Delete sessions that expired before `now`, returning their partial file paths
+    ///
+    /// Sessions currently being written to are skipped.
+    pub async fn delete_expired(&self, now: OffsetDateTime) -> Result<Vec<String>, WebauthnError> {
+        let paths = sqlx::query_scalar!(
+            r#"
+            DELETE FROM media_upload_sessions
+            WHERE id IN (
+                SELECT id FROM media_upload_sessions
+                WHERE expires_at <= $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING staging_path
+            "#,
+            now
+        )
+        .fetch_all(self.db.pool())
+        .await?;
+
+        Ok(paths)
+    }
+}
@@ -6,14 +6,18 @@
 use axum::{
     extract::DefaultBodyLimit,
     middleware,
-    routing::{delete, get, post},
-    Router,
+    routing::{delete, get, head, post},
+    Extension, Router,
 };
 
 use crate::auth::{require_admin, require_authentication};
 use crate::config::AppConfig;
 
 use super::handlers::{delete_upload, get_upload_info, list_uploads, upload_large_file};
+use super::sessions::{
+    append_upload_chunk, complete_upload_session, create_upload_session, delete_upload_session,
+    get_upload_offset, UploadWriters,
+};
 
 /// Build upload routes for large file operations
 pub fn build_upload_routes(_config: &AppConfig) -> Router {
@@ -23,6 +27,20 @@ pub fn build_upload_routes(_config: &AppConfig) -> Router {
         .route("/api/upload", post(upload_large_file))
         // Delete an uploaded file (DELETE /api/upload/{id}) - Admin only
         .route("/api/upload/{id}", delete(delete_upload))
+        // Resumable uploads: create a session, then HEAD/PATCH/DELETE it and
+        // complete it once all bytes have been sent - Admin only
+        .route("/api/upload/sessions", post(create_upload_session))
+        .route(
+            "/api/upload/sessions/{id}",
+            head(get_upload_offset)
+                .patch(append_upload_chunk)
+                .delete(delete_upload_session),
+        )
+        .route(
+            "/api/upload/sessions/{id}/complete",
+            post(complete_upload_session),
+        )
+        .layer(Extension(UploadWriters::default()))
         .layer(DefaultBodyLimit::max(1024 * 1024 * 1024)) // 1GB limit for uploads
         .layer(middleware::from_fn(require_admin))
         .layer(middleware::from_fn(require_authentication));
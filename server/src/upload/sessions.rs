@@ -0,0 +1,585 @@
+//! Resumable upload sessions (tus-style)
+//!
+//! A client creates a session with the declared size and SHA256, appends
+//! chunks with `PATCH` at the current offset, asks for the offset with `HEAD`
+//! after a dropped connection, and completes the session into a media blob.
+//! Bytes are written straight to a partial file in the media staging
+//! directory and sessions are kept in Postgres, so uploads survive restarts.
+//! A chunk is streamed with no database transaction open; [`UploadWriters`]
+//! keeps two requests from writing to a session at once.
+//! Sessions without activity expire and are swept with their partial files.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    response::{IntoResponse, Json, Response},
+    Extension,
+};
+use futures_util::StreamExt;
+use sqlx::{Postgres, Transaction};
+use time::OffsetDateTime;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::auth::AuthenticatedUser;
+use crate::config::AppConfig;
+use crate::database::DatabaseConnection;
+use crate::error::AppError;
+use crate::media::{MediaEvents, MediaRepository, MediaService, MediaStorage, StagedFile};
+
+use super::handlers::{
+    served_path, upload_blob_params, upload_create_error, upload_limits, upload_response,
+    upload_validation_error,
+};
+use super::models::{UploadConfig, UploadRequest, UploadResponse, UploadSession};
+use super::repository::UploadSessionRepository;
+
+/// Offset at which a chunk starts, and the offset reached afterwards
+pub const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+/// Declared size of the whole upload
+pub const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+/// Content type chunks must be sent with
+pub const CHUNK_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+/// Upload sessions a request is writing a chunk to
+///
+/// Partial files live in this server's staging directory, so claiming a
+/// session in-process is enough to keep writers apart. Cloning shares the
+/// claims.
+#[derive(Clone, Default)]
+pub struct UploadWriters(Arc<Mutex<HashSet<Uuid>>>);
+
+impl UploadWriters {
+    /// Claim a session for writing, or `None` if another request holds it
+    ///
+    /// The claim is released when the returned guard is dropped.
+    pub fn claim(&self, id: Uuid) -> Option<UploadWriter> {
+        self.0.lock().unwrap().insert(id).then(|| UploadWriter {
+            writers: self.clone(),
+            id,
+        })
+    }
+}
+
+/// A claim on an upload session, released on drop
+pub struct UploadWriter {
+    writers: UploadWriters,
+    id: Uuid,
+}
+
+impl Drop for UploadWriter {
+    fn drop(&mut self) {
+        self.writers.0.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Create a resumable upload session (admin only)
+///
+/// Responds with `201 Created`, the session and its URL in `Location`.
+pub async fn create_upload_session(
+    Extension(db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<MediaStorage>,
+    Extension(config): Extension<AppConfig>,
+    Extension(user): Extension<AuthenticatedUser>,
+    Json(request): Json<UploadRequest>,
+) -> Result<Response, AppError> {
+    request
+        .validate(&UploadConfig::from_media_config(&config.media))
+        .map_err(upload_validation_error)?;
+
+    // Unfinished sessions hold on to their share of the quota
+    let reserved = UploadSessionRepository::new(&db)
+        .reserved_bytes(user.user().id)
+        .await
+        .map_err(|e| upload_create_error(&request.sha256, e))?;
+    MediaService::new(MediaRepository::new(&db), &storage)
+        .check_quota(
+            user.user().id,
+            reserved + request.size as i64,
+            &config.media.quotas,
+        )
+        .await
+        .map_err(|e| upload_create_error(&request.sha256, e))?;
+
+    let id = Uuid::new_v4();
+    let staging_path = session_staging_path(&storage, id);
+    create_partial_file(&staging_path).await.map_err(|e| {
+        error!(
+            "Failed to create partial file for upload session {}: {}",
+            id, e
+        );
+        AppError::InternalServerError("Failed to prepare upload".to_string())
+    })?;
+
+    let now = OffsetDateTime::now_utc();
+    let session = UploadSession {
+        id,
+        owner_user_id: user.user().id,
+        filename: request.filename,
+        mime_type: request.mime_type,
+        sha256: request.sha256,
+        size: request.size as i64,
+        upload_offset: 0,
+        visibility: request.visibility,
+        metadata: request.metadata,
+        staging_path: staging_path.to_string_lossy().into_owned(),
+        created_at: now,
+        updated_at: now,
+        expires_at: session_expiry(&config, now),
+    };
+
+    if let Err(e) = UploadSessionRepository::new(&db).create(&session).await {
+        error!("Failed to create upload session {}: {}", id, e);
+        let _ = tokio::fs::remove_file(&staging_path).await;
+        return Err(AppError::InternalServerError(
+            "Failed to create upload session".to_string(),
+        ));
+    }
+
+    info!(
+        "Created upload session {} for {} ({} bytes) by user {}",
+        id,
+        session.filename,
+        session.size,
+        user.user().username
+    );
+
+    Ok((
+        StatusCode::CREATED,
+        [
+            (header::LOCATION, format!("/api/upload/sessions/{}", id)),
+            (UPLOAD_OFFSET, "0".to_string()),
+        ],
+        Json(session),
+    )
+        .into_response())
+}
+
+/// Report how many bytes of an upload session have been received
+pub async fn get_upload_offset(
+    Extension(db): Extension<DatabaseConnection>,
+    Extension(user): Extension<AuthenticatedUser>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+) -> Result<Response, AppError> {
+    let session = UploadSessionRepository::new(&db)
+        .find(id)
+        .await
+        .map_err(|e| session_load_error(id, e))?;
+    let session = owned_session(session, &user)?;
+
+    Ok((
+        StatusCode::OK,
+        [
+            (UPLOAD_OFFSET, session.upload_offset.to_string()),
+            (UPLOAD_LENGTH, session.size.to_string()),
+            (header::CACHE_CONTROL, "no-store".to_string()),
+        ],
+    )
+        .into_response())
+}
+
+/// Append a chunk to an upload session
+///
+/// The `Upload-Offset` header must equal the session's current offset;
+/// otherwise `409 Conflict` is returned and the client should ask for the
+/// offset with `HEAD`. Bytes received before a dropped connection are kept.
+pub async fn append_upload_chunk(
+    Extension(db): Extension<DatabaseConnection>,
+    Extension(config): Extension<AppConfig>,
+    Extension(writers): Extension<UploadWriters>,
+    Extension(user): Extension<AuthenticatedUser>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, AppError> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    if content_type != Some(CHUNK_CONTENT_TYPE) {
+        return Err(AppError::BadRequest(format!(
+            "Chunks must be sent as {}",
+            CHUNK_CONTENT_TYPE
+        )));
+    }
+
+    let offset = headers
+        .get(&UPLOAD_OFFSET)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<i64>().ok())
+        .ok_or_else(|| AppError::BadRequest("Missing or invalid Upload-Offset header".into()))?;
+
+    let repository = UploadSessionRepository::new(&db);
+    let (_writer, session) = claim_session(&repository, &writers, id, &user).await?;
+
+    if offset != session.upload_offset {
+        return Err(AppError::Conflict(format!(
+            "Upload offset is {}, not {}",
+            session.upload_offset, offset
+        )));
+    }
+
+    let written = write_chunk(&session, body).await;
+    let (new_offset, result) = match written {
+        Ok(new_offset) => (new_offset, Ok(())),
+        Err((new_offset, e)) => (new_offset, Err(e)),
+    };
+
+    // Keep whatever reached the disk, even if the request failed part way
+    if new_offset != session.upload_offset {
+        let expires_at = session_expiry(&config, OffsetDateTime::now_utc());
+        let advanced = repository
+            .advance_offset(id, session.upload_offset, new_offset, expires_at)
+            .await
+            .map_err(|e| session_load_error(id, e))?;
+        if !advanced {
+            warn!("Upload session {} changed while a chunk was written", id);
+            return Err(AppError::Conflict(
+                "Upload session changed while the chunk was written".to_string(),
+            ));
+        }
+    }
+
+    result?;
+
+    Ok((
+        StatusCode::NO_CONTENT,
+        [(UPLOAD_OFFSET, new_offset.to_string())],
+    )
+        .into_response())
+}
+
+/// Finish an upload session, turning the received file into a media blob
+///
+/// If the content does not match the declared size and SHA256 the session is
+/// discarded and the upload has to start over; the same goes for content that
+/// does not match its declared type or is refused by the HTTP MIME type rules.
+pub async fn complete_upload_session(
+    Extension(db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<MediaStorage>,
+    Extension(config): Extension<AppConfig>,
+    Extension(events): Extension<MediaEvents>,
+    Extension(user): Extension<AuthenticatedUser>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+) -> Result<Json<UploadResponse>, AppError> {
+    let repository = UploadSessionRepository::new(&db);
+    let mut tx = repository
+        .begin()
+        .await
+        .map_err(|e| session_load_error(id, e))?;
+    let session = match lock_session(&repository, &mut tx, id, &user).await {
+        Ok(session) => session,
+        Err(e) => return abort(tx, e).await,
+    };
+
+    if !session.is_complete() {
+        let conflict = AppError::Conflict(format!(
+            "Upload incomplete: {} of {} bytes received",
+            session.upload_offset, session.size
+        ));
+        return abort(tx, conflict).await;
+    }
+
+    // From here on the partial file belongs to the staged file and the
+    // session is discarded whatever the outcome
+    let staged = StagedFile::from_file(PathBuf::from(&session.staging_path))
+        .await
+        .map_err(|e| {
+            error!(
+                "Failed to read partial file of upload session {}: {}",
+                id, e
+            );
+            AppError::InternalServerError("Failed to read uploaded file".to_string())
+        });
+    if let Err(e) = repository.delete_in(&mut *tx, id).await {
+        return abort(tx, session_load_error(id, e)).await;
+    }
+    tx.commit()
+        .await
+        .map_err(|e| session_load_error(id, e.into()))?;
+    let staged = match staged {
+        Ok(staged) => staged,
+        Err(e) => {
+            remove_partial_file(&session.staging_path).await;
+            return Err(e);
+        }
+    };
+
+    let request = session.request();
+    if staged.size() != request.size || staged.sha256() != Some(request.sha256.as_str()) {
+        warn!(
+            "Upload session {} content does not match its declared hash",
+            id
+        );
+        return Err(AppError::BadRequest(
+            "SHA256 hash verification failed".to_string(),
+        ));
+    }
+
+    let service = MediaService::new(MediaRepository::new(&db), &storage).with_events(&events);
+    let mime = service
+        .check_content_type(
+            request.infer_mime_type().as_deref(),
+            Some(staged.head()),
+            &request.sha256,
+            &config.media.mime_types.http,
+        )
+        .await
+        .map_err(|e| upload_create_error(&request.sha256, e))?;
+    let backend = storage.default_backend();
+    let local_path = served_path(backend.backend(), &backend.key_for(&request.sha256));
+    let params = upload_blob_params(&request, &user, mime, local_path);
+    let media_blob = service
+        .create_blob_from_file(params, &staged, &upload_limits(&config.media))
+        .await
+        .map_err(|e| upload_create_error(&request.sha256, e))?;
+
+    info!(
+        "Completed upload session {}: {} (ID: {})",
+        id, request.filename, media_blob.id
+    );
+
+    Ok(Json(upload_response(media_blob, request)))
+}
+
+/// Abandon an upload session and remove its partial file
+pub async fn delete_upload_session(
+    Extension(db): Extension<DatabaseConnection>,
+    Extension(user): Extension<AuthenticatedUser>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+) -> Result<StatusCode, AppError> {
+    let repository = UploadSessionRepository::new(&db);
+    let mut tx = repository
+        .begin()
+        .await
+        .map_err(|e| session_load_error(id, e))?;
+    let session = match lock_session(&repository, &mut tx, id, &user).await {
+        Ok(session) => session,
+        Err(e) => return abort(tx, e).await,
+    };
+
+    if let Err(e) = repository.delete_in(&mut *tx, id).await {
+        return abort(tx, session_load_error(id, e)).await;
+    }
+    tx.commit()
+        .await
+        .map_err(|e| session_load_error(id, e.into()))?;
+    remove_partial_file(&session.staging_path).await;
+
+    info!("Deleted upload session {}", id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Remove expired upload sessions and their partial files
+pub async fn cleanup_expired_upload_sessions(db: &DatabaseConnection) -> Result<u64, AppError> {
+    let paths = UploadSessionRepository::new(db)
+        .delete_expired(OffsetDateTime::now_utc())
+        .await?;
+
+    for path in &paths {
+        remove_partial_file(path).await;
+    }
+
+    if !paths.is_empty() {
+        info!("Removed {} expired upload sessions", paths.len());
+    }
+
+    Ok(paths.len() as u64)
+}
+
+/// Periodically remove expired upload sessions in the background
+pub fn spawn_upload_session_cleanup(db: DatabaseConnection, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = cleanup_expired_upload_sessions(&db).await {
+                error!("Failed to clean up expired upload sessions: {}", e);
+            }
+        }
+    });
+}
+
+/// Partial file for an upload session
+fn session_staging_path(storage: &MediaStorage, id: Uuid) -> PathBuf {
+    storage
+        .staging_directory()
+        .join("sessions")
+        .join(format!("{}.partial", id))
+}
+
+/// When a session last active at `now` expires
+fn session_expiry(config: &AppConfig, now: OffsetDateTime) -> OffsetDateTime {
+    now + time::Duration::seconds(config.media.upload_sessions.expiry_seconds as i64)
+}
+
+async fn create_partial_file(path: &Path) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::File::create(path).await?;
+    Ok(())
+}
+
+async fn remove_partial_file(path: &str) {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!("Failed to remove partial upload file {}: {}", path, e),
+    }
+}
+
+/// Only the user who created a session may see or use it
+fn owned_session(
+    session: Option<UploadSession>,
+    user: &AuthenticatedUser,
+) -> Result<UploadSession, AppError> {
+    session
+        .filter(|session| session.owner_user_id == user.user().id)
+        .filter(|session| !session.is_expired(OffsetDateTime::now_utc()))
+        .ok_or_else(|| AppError::NotFound("Upload session not found".to_string()))
+}
+
+/// Claim a session for writing a chunk, telling a busy session apart from a
+/// missing one
+///
+/// The session is read after claiming it, so its offset is the one the
+/// chunk starts at.
+async fn claim_session(
+    repository: &UploadSessionRepository<'_>,
+    writers: &UploadWriters,
+    id: Uuid,
+    user: &AuthenticatedUser,
+) -> Result<(UploadWriter, UploadSession), AppError> {
+    let writer = writers.claim(id);
+    let session = repository
+        .find(id)
+        .await
+        .map_err(|e| session_load_error(id, e))?;
+    let session = owned_session(session, user)?;
+    match writer {
+        Some(writer) => Ok((writer, session)),
+        None => Err(AppError::Conflict(
+            "Another request is writing to this upload session".to_string(),
+        )),
+    }
+}
+
+/// Lock a session to complete or delete it, telling a busy session apart
+/// from a missing one
+async fn lock_session(
+    repository: &UploadSessionRepository<'_>,
+    conn: &mut sqlx::PgConnection,
+    id: Uuid,
+    user: &AuthenticatedUser,
+) -> Result<UploadSession, AppError> {
+    match repository
+        .lock_in(conn, id)
+        .await
+        .map_err(|e| session_load_error(id, e))?
+    {
+        Some(session) => owned_session(Some(session), user),
+        None => {
+            let session = repository
+                .find(id)
+                .await
+                .map_err(|e| session_load_error(id, e))?;
+            owned_session(session, user)?;
+            Err(AppError::Conflict(
+                "Another request is writing to this upload session".to_string(),
+            ))
+        }
+    }
+}
+
+/// Roll back a session transaction and return `err`
+///
+/// A dropped transaction is only rolled back once its pooled connection is
+/// used again, which would leave the session locked until then.
+async fn abort<T>(tx: Transaction<'static, Postgres>, err: AppError) -> Result<T, AppError> {
+    if let Err(e) = tx.rollback().await {
+        warn!("Failed to roll back upload session transaction: {}", e);
+    }
+    Err(err)
+}
+
+/// Write a request body to the session's partial file at its current offset
+///
+/// Returns the new offset, or the offset reached so far with the error.
+async fn write_chunk(session: &UploadSession, body: Body) -> Result<i64, (i64, AppError)> {
+    let start = session.upload_offset;
+    let io_error = |offset: i64, e: std::io::Error| {
+        error!(
+            "Failed to write chunk to upload session {}: {}",
+            session.id, e
+        );
+        (
+            offset,
+            AppError::InternalServerError("Failed to store chunk".to_string()),
+        )
+    };
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(&session.staging_path)
+        .await
+        .map_err(|e| io_error(start, e))?;
+
+    // Drop any bytes written after the last recorded offset, e.g. by a
+    // request that failed before its progress was saved
+    file.set_len(start as u64)
+        .await
+        .map_err(|e| io_error(start, e))?;
+    file.seek(std::io::SeekFrom::Start(start as u64))
+        .await
+        .map_err(|e| io_error(start, e))?;
+
+    let mut offset = start;
+    let mut stream = body.into_data_stream();
+    let mut failure = None;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                warn!("Upload session {} chunk interrupted: {}", session.id, e);
+                failure = Some(AppError::BadRequest(format!("Failed to read chunk: {}", e)));
+                break;
+            }
+        };
+
+        if offset + chunk.len() as i64 > session.size {
+            failure = Some(AppError::PayloadTooLarge(format!(
+                "Chunk extends past the declared size of {} bytes",
+                session.size
+            )));
+            break;
+        }
+
+        if let Err(e) = file.write_all(&chunk).await {
+            failure = Some(io_error(offset, e).1);
+            break;
+        }
+        offset += chunk.len() as i64;
+    }
+
+    // Only report progress that is durable
+    if let Err(e) = file.sync_all().await {
+        return Err(io_error(start, e));
+    }
+
+    match failure {
+        Some(e) => Err((offset, e)),
+        None => Ok(offset),
+    }
+}
+
+fn session_load_error(id: Uuid, err: crate::error::WebauthnError) -> AppError {
+    error!("Upload session {} database error: {}", id, err);
+    AppError::InternalServerError("Failed to access upload session".to_string())
+}
@@ -0,0 +1,292 @@
+//! Drawing board messages over the WebSocket
+//!
+//! Like room requests, board requests are answered directly and published
+//! as [`BoardEvent`]s for the other connections. Operations only go to the
+//! connections that have the board open; new boards are announced to every
+//! connection in the board's room.
+
+use std::collections::HashMap;
+
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::auth::User;
+use crate::boards::{
+    Board, BoardEvent, BoardEventKind, BoardOperation, BoardRepository, BoardService,
+};
+use crate::database::DatabaseConnection;
+use crate::error::WebauthnError;
+use crate::media::{MediaRepository, MediaService, MediaStorage};
+use crate::rooms::{RoomRepository, RoomService};
+use crate::websocket::messages::{WebSocketMessage, WebSocketResponse};
+use crate::websocket::presence::ConnectionManager;
+use crate::websocket::protocol::ErrorCode;
+use crate::websocket::rooms::JoinedRooms;
+
+/// The boards a connection has open, with the rooms they are in
+#[derive(Debug, Default)]
+pub struct OpenBoards(HashMap<Uuid, Uuid>);
+
+impl OpenBoards {
+    /// Whether a board is open
+    pub fn contains(&self, board_id: Uuid) -> bool {
+        self.0.contains_key(&board_id)
+    }
+
+    /// Close the boards of a room the user is no longer in
+    pub fn close_room(&mut self, room_id: Uuid) {
+        self.0.retain(|_, board_room_id| *board_room_id != room_id);
+    }
+}
+
+/// Connection handling a board message
+pub struct BoardConnection<'a> {
+    pub connection_id: &'a str,
+    pub user: &'a User,
+    pub boards: &'a mut OpenBoards,
+    pub connection_manager: &'a ConnectionManager,
+}
+
+impl BoardConnection<'_> {
+    /// Tell the other connections about a change this one made
+    fn publish(&self, board_id: Uuid, room_id: Uuid, kind: BoardEventKind) {
+        self.connection_manager.publish_board_event(BoardEvent {
+            board_id,
+            room_id,
+            origin: Some(self.connection_id.to_string()),
+            kind,
+        });
+    }
+}
+
+fn board_service<'a>(db: &'a DatabaseConnection, storage: &'a MediaStorage) -> BoardService<'a> {
+    BoardService::new(
+        BoardRepository::new(db),
+        RoomService::new(
+            RoomRepository::new(db),
+            MediaService::new(MediaRepository::new(db), storage),
+        ),
+    )
+}
+
+/// Handle a board message; `None` for messages about anything else
+pub async fn handle_board_message(
+    message: &WebSocketMessage,
+    connection: BoardConnection<'_>,
+    db: &DatabaseConnection,
+    storage: &MediaStorage,
+) -> Option<WebSocketResponse> {
+    let user = connection.user;
+    let service = board_service(db, storage);
+
+    let response = match message {
+        WebSocketMessage::ListBoards { room_id } => match service.list(*room_id, user).await {
+            Ok(boards) => WebSocketResponse::Boards {
+                room_id: *room_id,
+                boards,
+            },
+            Err(e) => board_error(e, "Failed to list boards"),
+        },
+        WebSocketMessage::CreateBoard { room_id, name } => {
+            if let Err(message) = Board::normalize_name(name) {
+                return Some(WebSocketResponse::error(ErrorCode::BadRequest, message));
+            }
+
+            match service.create(*room_id, name, user).await {
+                Ok(board) => {
+                    info!(
+                        "User {} created board {} in room {}",
+                        user.id, board.id, room_id
+                    );
+                    connection.publish(
+                        board.id,
+                        board.room_id,
+                        BoardEventKind::Created(board.clone()),
+                    );
+                    WebSocketResponse::BoardCreated { board }
+                }
+                Err(WebauthnError::BadRequest) => WebSocketResponse::error(
+                    ErrorCode::Conflict,
+                    "A board with this name already exists in the room",
+                ),
+                Err(e) => board_error(e, "Failed to create board"),
+            }
+        }
+        WebSocketMessage::OpenBoard { board_id, since } => {
+            match service.open(*board_id, *since, user).await {
+                Ok(replay) => {
+                    connection
+                        .boards
+                        .0
+                        .insert(replay.board.id, replay.board.room_id);
+                    WebSocketResponse::BoardOpened {
+                        board: replay.board,
+                        snapshot: replay.snapshot,
+                        ops: replay.ops,
+                    }
+                }
+                Err(e) => board_error(e, "Failed to open board"),
+            }
+        }
+        WebSocketMessage::CloseBoard { board_id } => {
+            connection.boards.0.remove(board_id);
+            WebSocketResponse::BoardClosed {
+                board_id: *board_id,
+            }
+        }
+        WebSocketMessage::ApplyBoardOp {
+            board_id,
+            client_op_id,
+            op,
+        } => {
+            if !connection.boards.contains(*board_id) {
+                return Some(WebSocketResponse::error(
+                    ErrorCode::BadRequest,
+                    "Open the board before drawing on it",
+                ));
+            }
+            if let Err(message) = op.validate() {
+                return Some(WebSocketResponse::error(ErrorCode::BadRequest, message));
+            }
+            if let Some(Err(message)) = client_op_id
+                .as_deref()
+                .map(BoardOperation::check_client_op_id)
+            {
+                return Some(WebSocketResponse::error(ErrorCode::BadRequest, message));
+            }
+
+            match service
+                .apply(*board_id, client_op_id.as_deref(), op, user)
+                .await
+            {
+                Ok((op, applied)) => {
+                    if applied {
+                        let room_id = connection.boards.0[board_id];
+                        connection.publish(*board_id, room_id, BoardEventKind::Op(op.clone()));
+                    }
+                    WebSocketResponse::BoardOp { op }
+                }
+                Err(e) => board_error(e, "Failed to apply board operation"),
+            }
+        }
+        WebSocketMessage::DeleteBoard { board_id } => match service.delete(*board_id, user).await {
+            Ok(board) => {
+                info!("User {} deleted board {}", user.id, board_id);
+                connection.boards.0.remove(board_id);
+                connection.publish(board.id, board.room_id, BoardEventKind::Deleted);
+                WebSocketResponse::BoardDeleted {
+                    board_id: *board_id,
+                }
+            }
+            Err(e) => board_error(e, "Failed to delete board"),
+        },
+        _ => return None,
+    };
+
+    Some(response)
+}
+
+/// Build the push for a board event another connection caused
+///
+/// Operations go to connections with the board open, and new boards to the
+/// members of the room. Deleted boards are closed.
+pub fn board_event_response(
+    event: &BoardEvent,
+    connection_id: &str,
+    rooms: &JoinedRooms,
+    boards: &mut OpenBoards,
+) -> Option<WebSocketResponse> {
+    if event.origin.as_deref() == Some(connection_id) {
+        return None;
+    }
+
+    match &event.kind {
+        BoardEventKind::Created(board) => {
+            rooms
+                .contains(event.room_id)
+                .then(|| WebSocketResponse::BoardCreated {
+                    board: board.clone(),
+                })
+        }
+        BoardEventKind::Op(op) => boards
+            .contains(event.board_id)
+            .then(|| WebSocketResponse::BoardOp { op: op.clone() }),
+        BoardEventKind::Deleted => {
+            let open = boards.0.remove(&event.board_id).is_some();
+            (open || rooms.contains(event.room_id)).then_some(WebSocketResponse::BoardDeleted {
+                board_id: event.board_id,
+            })
+        }
+    }
+}
+
+fn board_error(err: WebauthnError, context: &str) -> WebSocketResponse {
+    match err {
+        WebauthnError::UserNotFound => {
+            WebSocketResponse::error(ErrorCode::NotFound, "Board not found")
+        }
+        WebauthnError::Forbidden => WebSocketResponse::error(
+            ErrorCode::Forbidden,
+            "Your role in this room does not allow that",
+        ),
+        e => {
+            error!("{}: {}", context, e);
+            WebSocketResponse::error(ErrorCode::from(&e), context)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boards::{BoardOp, BoardOperation};
+    use time::OffsetDateTime;
+
+    #[test]
+    fn test_board_events_reach_open_boards() {
+        let board_id = Uuid::new_v4();
+        let room_id = Uuid::new_v4();
+        let rooms = JoinedRooms::default();
+        let mut boards = OpenBoards::default();
+        let event = |origin: &str, kind| BoardEvent {
+            board_id,
+            room_id,
+            origin: Some(origin.to_string()),
+            kind,
+        };
+        let op = BoardEventKind::Op(BoardOp {
+            board_id,
+            seq: 1,
+            author_user_id: None,
+            client_op_id: None,
+            op: BoardOperation::Delete { id: Uuid::new_v4() },
+            created_at: OffsetDateTime::now_utc(),
+        });
+
+        assert!(board_event_response(&event("b", op.clone()), "a", &rooms, &mut boards).is_none());
+
+        boards.0.insert(board_id, room_id);
+        assert!(matches!(
+            board_event_response(&event("b", op.clone()), "a", &rooms, &mut boards),
+            Some(WebSocketResponse::BoardOp { .. })
+        ));
+        // The connection that drew it was answered directly
+        assert!(board_event_response(&event("a", op.clone()), "a", &rooms, &mut boards).is_none());
+
+        // Leaving the room closes its boards
+        boards.close_room(room_id);
+        assert!(board_event_response(&event("b", op), "a", &rooms, &mut boards).is_none());
+
+        boards.0.insert(board_id, room_id);
+        assert!(matches!(
+            board_event_response(
+                &event("b", BoardEventKind::Deleted),
+                "a",
+                &rooms,
+                &mut boards
+            ),
+            Some(WebSocketResponse::BoardDeleted { .. })
+        ));
+        assert!(!boards.contains(board_id));
+    }
+}
@@ -3,131 +3,230 @@
 //! Provides authenticated WebSocket endpoints that integrate with the existing
 //! auth system and handle real-time communication for media blob sharing.
 
+use crate::auth::{AuthRepository, User};
 use crate::config::AppConfig;
 use crate::database::DatabaseConnection;
-use crate::media::{CreateMediaBlob, MediaBlobQuery, MediaRepository, MediaService};
-use crate::websocket::messages::{WebSocketMessage, WebSocketResponse};
+use crate::error::AppError;
+use crate::error::WebauthnError;
+use crate::media::links::client_ip;
+use crate::media::{
+    normalize_tags, retention, CreateMediaBlob, MediaBlobQuery, MediaCollection, MediaEvent,
+    MediaEvents, MediaRepository, MediaService, MediaStorage, MediaSubscription,
+};
+use crate::websocket::boards::{self, BoardConnection, OpenBoards};
+use crate::websocket::messages::{
+    ClientEnvelope, ServerEnvelope, WebSocketMessage, WebSocketResponse,
+};
+use crate::websocket::presence::{ConnectionManager, LiveConnection};
+use crate::websocket::protocol::{
+    negotiate_protocol_version, ErrorCode, RequestId, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::websocket::rooms::{self, JoinedRooms, RoomConnection};
+use crate::websocket::transfer::{self, Transfers, UploadRequest, UploadStep};
 use axum::{
-    extract::{ws::WebSocket, WebSocketUpgrade},
+    extract::{
+        ws::{close_code, CloseFrame, WebSocket},
+        Path, Query, WebSocketUpgrade,
+    },
+    http::{Extensions, HeaderMap, StatusCode},
     response::Response,
-    Extension,
+    Extension, Json,
 };
 // use futures_util::{sink::SinkExt, stream::StreamExt}; // TODO: Uncomment when needed
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use time::OffsetDateTime;
+use serde::Deserialize;
+use std::net::IpAddr;
+use tokio::sync::broadcast::error::RecvError;
 use tower_sessions::Session;
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
-/// Connection manager to track active WebSocket connections
-#[derive(Clone)]
-pub struct ConnectionManager {
-    connections: Arc<Mutex<HashMap<String, ConnectionInfo>>>,
-}
-
-/// Information about an active WebSocket connection
-#[derive(Debug, Clone)]
-struct ConnectionInfo {
-    _user_id: Option<Uuid>,
-    _connected_at: OffsetDateTime,
+/// State kept for the lifetime of a single WebSocket connection
+struct ConnectionState {
+    /// Media events the client wants; `None` once it unsubscribed
+    subscription: Option<MediaSubscription>,
+    /// Blob content being sent or received in binary frames
+    transfers: Transfers,
+    /// Rooms the user is in, whose events are passed on
+    rooms: JoinedRooms,
+    /// Boards the client draws on, whose operations are passed on
+    boards: OpenBoards,
 }
 
-impl ConnectionManager {
-    pub fn new() -> Self {
-        Self {
-            connections: Arc::new(Mutex::new(HashMap::new())),
-        }
-    }
-
-    fn add_connection(&self, connection_id: String, user_id: Option<Uuid>) {
-        if let Ok(mut connections) = self.connections.lock() {
-            connections.insert(
-                connection_id,
-                ConnectionInfo {
-                    _user_id: user_id,
-                    _connected_at: OffsetDateTime::now_utc(),
-                },
-            );
-        }
-    }
-
-    fn remove_connection(&self, connection_id: &str) {
-        if let Ok(mut connections) = self.connections.lock() {
-            connections.remove(connection_id);
-        }
-    }
-
-    fn get_user_count(&self) -> u32 {
-        self.connections
-            .lock()
-            .map(|connections| connections.len() as u32)
-            .unwrap_or(0)
-    }
+/// Query parameters of the WebSocket upgrade
+#[derive(Deserialize)]
+pub struct WebSocketParams {
+    /// Newest protocol version the client speaks
+    protocol_version: Option<u32>,
 }
 
 /// WebSocket upgrade handler - this gets called on GET /ws
+#[allow(clippy::too_many_arguments)]
 pub async fn websocket_handler(
     ws: WebSocketUpgrade,
     session: Session,
+    Query(params): Query<WebSocketParams>,
+    headers: HeaderMap,
+    extensions: Extensions,
     Extension(connection_manager): Extension<ConnectionManager>,
     Extension(db): Extension<DatabaseConnection>,
+    Extension(storage): Extension<MediaStorage>,
+    Extension(events): Extension<MediaEvents>,
     Extension(config): Extension<AppConfig>,
 ) -> Response {
     // Check if user is authenticated by looking for user_id in session
     let user_id = session.get::<Uuid>("user_id").await.ok().flatten();
 
-    if user_id.is_none() {
+    let Some(user_id) = user_id else {
         // Not authenticated - reject the WebSocket upgrade
         warn!("WebSocket connection attempt without authentication");
         return axum::http::Response::builder()
             .status(401)
             .body("Authentication required for WebSocket connection".into())
             .unwrap();
-    }
+    };
 
-    info!("WebSocket upgrade for authenticated user: {:?}", user_id);
+    // Load the user so media access checks know their role
+    let user = match AuthRepository::new(&db).get_user_by_id(user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            warn!(
+                "WebSocket user {} found in session but not in database",
+                user_id
+            );
+            return axum::http::Response::builder()
+                .status(401)
+                .body("Authentication required for WebSocket connection".into())
+                .unwrap();
+        }
+        Err(e) => {
+            error!("Failed to load WebSocket user {}: {}", user_id, e);
+            return axum::http::Response::builder()
+                .status(500)
+                .body("Failed to load user".into())
+                .unwrap();
+        }
+    };
+
+    info!(
+        "WebSocket upgrade for authenticated user: {}",
+        user.username
+    );
+
+    let ip = client_ip(&headers, &extensions, &config.server.trusted_proxies);
 
     // Upgrade to WebSocket and handle the connection
     ws.on_upgrade(move |socket| {
-        handle_websocket_connection(socket, user_id, connection_manager, db, config)
+        handle_websocket_connection(
+            socket,
+            user,
+            connection_manager,
+            db,
+            storage,
+            events,
+            config,
+            ip,
+            params.protocol_version,
+        )
     })
 }
 
 /// Handle an individual WebSocket connection after upgrade
+///
+/// Besides answering the client's messages, the connection carries:
+/// - media blob events the user may see and that match its subscription
+/// - binary frames with blob content for chunked uploads and downloads
+/// - presence: users coming online or going offline, and connection counts
+/// - what happens in the rooms the user is in
+/// - operations on the boards the client has open
+///
+/// Admins may close the connection through the [`ConnectionManager`].
+/// Clients that only speak protocol versions the server no longer supports
+/// are sent an error and disconnected.
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_websocket_connection(
     mut socket: WebSocket,
-    user_id: Option<Uuid>,
+    user: User,
     connection_manager: ConnectionManager,
     db: DatabaseConnection,
+    storage: MediaStorage,
+    events: MediaEvents,
     config: AppConfig,
+    ip: Option<IpAddr>,
+    requested_protocol_version: Option<u32>,
 ) {
+    let user_id = Some(user.id);
     let connection_id = format!("conn_{}", Uuid::new_v4());
+
+    let Some(protocol_version) = negotiate_protocol_version(requested_protocol_version) else {
+        warn!(
+            "Refusing WebSocket protocol version {:?} for user {}",
+            requested_protocol_version, user.id
+        );
+        let refusal = WebSocketResponse::error(
+            ErrorCode::UnsupportedProtocolVersion,
+            format!(
+                "Protocol versions {} to {} are supported",
+                MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+        );
+        if let Ok(refusal_json) = refusal.to_json() {
+            let _ = socket
+                .send(axum::extract::ws::Message::Text(refusal_json.into()))
+                .await;
+        }
+        let _ = socket.send(axum::extract::ws::Message::Close(None)).await;
+        return;
+    };
+
     info!(
-        "WebSocket connection established: {} (user: {:?})",
-        connection_id, user_id
+        "WebSocket connection established: {} (user: {:?}, protocol: {})",
+        connection_id, user_id, protocol_version
     );
 
+    // Subscribe before loading the user's rooms so no event falls between
+    let mut room_events = connection_manager.subscribe_rooms();
+    let mut board_events = connection_manager.subscribe_boards();
+    let rooms = match JoinedRooms::load(&db, &storage, &user).await {
+        Ok(rooms) => rooms,
+        Err(e) => {
+            error!("Failed to load rooms for user {}: {}", user.id, e);
+            JoinedRooms::default()
+        }
+    };
+
+    // Connections start out subscribed to everything the user can see
+    let mut state = ConnectionState {
+        subscription: Some(MediaSubscription::default()),
+        transfers: Transfers::default(),
+        rooms,
+        boards: OpenBoards::default(),
+    };
+    let mut media_events = events.subscribe();
+
     // Add to connection manager
-    connection_manager.add_connection(connection_id.clone(), user_id);
+    let mut presence = connection_manager.subscribe();
+    let disconnect =
+        connection_manager.add_connection(connection_id.clone(), &user, ip, protocol_version);
 
     // Send welcome message
-    let welcome = WebSocketResponse::welcome(user_id, connection_id.clone());
+    let welcome = WebSocketResponse::welcome(user_id, connection_id.clone(), protocol_version);
     if let Ok(welcome_json) = welcome.to_json() {
         if let Err(e) = socket
             .send(axum::extract::ws::Message::Text(welcome_json.into()))
             .await
         {
             error!("Failed to send welcome message: {}", e);
+            connection_manager.remove_connection(&connection_id);
             return;
         }
     }
 
     // Send connection status
-    let user_count = connection_manager.get_user_count();
+    let (user_count, connection_count) = connection_manager.counts();
     let status = WebSocketResponse::ConnectionStatus {
         connected: true,
         user_count,
+        connection_count,
     };
     if let Ok(status_json) = status.to_json() {
         let _ = socket
@@ -135,59 +234,196 @@ pub async fn handle_websocket_connection(
             .await;
     }
 
-    // Handle messages in a loop
-    while let Some(msg) = socket.recv().await {
-        match msg {
-            Ok(axum::extract::ws::Message::Text(text)) => {
-                debug!("Received WebSocket message: {}", text);
+    // Handle messages and broadcasts in a loop
+    loop {
+        // The senders outlive the connection, as it holds clones of both,
+        // so the broadcast channels never close here
+        let response = tokio::select! {
+            msg = socket.recv() => {
+                let Some(msg) = msg else {
+                    break;
+                };
+                connection_manager.touch(&connection_id);
 
-                match WebSocketMessage::from_json(&text) {
-                    Ok(parsed_msg) => {
-                        if let Some(response) =
-                            handle_message(parsed_msg, user_id, &db, &config, &connection_manager)
+                match msg {
+                    Ok(axum::extract::ws::Message::Text(text)) => {
+                        debug!("Received WebSocket message: {}", text);
+
+                        match ClientEnvelope::from_json(&text) {
+                            Ok(ClientEnvelope { request_id, message }) => handle_message(
+                                message,
+                                &user,
+                                &db,
+                                &storage,
+                                &events,
+                                &config,
+                                &mut state,
+                                &connection_id,
+                                &connection_manager,
+                            )
+                            .await
+                            .map(|response| ServerEnvelope::reply(request_id, response)),
+                            Err(e) => {
+                                warn!("Failed to parse WebSocket message: {}", e);
+                                Some(ServerEnvelope::reply(
+                                    RequestId::find(&text),
+                                    WebSocketResponse::error(ErrorCode::InvalidMessage, "Invalid message format"),
+                                ))
+                            }
+                        }
+                    }
+                    Ok(axum::extract::ws::Message::Binary(frame)) => {
+                        Some(
+                            handle_frame(&frame, &mut state.transfers, &db, &storage, &events, &config)
                                 .await
-                        {
-                            if let Ok(response_json) = response.to_json() {
-                                if let Err(e) = socket
-                                    .send(axum::extract::ws::Message::Text(response_json.into()))
-                                    .await
-                                {
-                                    error!("Failed to send response: {}", e);
+                                .into(),
+                        )
+                    }
+                    Ok(axum::extract::ws::Message::Close(_)) => {
+                        info!("WebSocket connection closed: {}", connection_id);
+                        break;
+                    }
+                    Ok(axum::extract::ws::Message::Ping(data)) => {
+                        debug!("Received ping, sending pong");
+                        if let Err(e) = socket.send(axum::extract::ws::Message::Pong(data)).await {
+                            error!("Failed to send pong: {}", e);
+                            break;
+                        }
+                        None
+                    }
+                    Ok(_) => {
+                        // Ignore other message types (Pong)
+                        None
+                    }
+                    Err(e) => {
+                        error!("WebSocket error: {}", e);
+                        break;
+                    }
+                }
+            }
+            event = media_events.recv() => match event {
+                Ok(event) => media_event_response(&event, &user, state.subscription.as_ref())
+                    .map(Into::into),
+                Err(RecvError::Lagged(missed)) => {
+                    warn!(
+                        "WebSocket connection {} missed {} media events",
+                        connection_id, missed
+                    );
+                    state.subscription.as_ref().map(|_| {
+                        WebSocketResponse::error(ErrorCode::EventsLagged, format!("Missed {} media events; reload to catch up", missed))
+                            .into()
+                    })
+                }
+                Err(RecvError::Closed) => None,
+            },
+            event = room_events.recv() => match event {
+                Ok(event) => {
+                    if event.removes(user.id) {
+                        state.boards.close_room(event.room_id);
+                    }
+                    rooms::room_event_response(&event, &connection_id, &user, &mut state.rooms)
+                        .map(Into::into)
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    warn!(
+                        "WebSocket connection {} missed {} room events",
+                        connection_id, missed
+                    );
+                    Some(
+                        WebSocketResponse::error(ErrorCode::EventsLagged, format!("Missed {} room events; rejoin rooms to catch up", missed))
+                            .into(),
+                    )
+                }
+                Err(RecvError::Closed) => None,
+            },
+            event = board_events.recv() => match event {
+                Ok(event) => boards::board_event_response(&event, &connection_id, &state.rooms, &mut state.boards)
+                    .map(Into::into),
+                Err(RecvError::Lagged(missed)) => {
+                    warn!(
+                        "WebSocket connection {} missed {} board events",
+                        connection_id, missed
+                    );
+                    Some(
+                        WebSocketResponse::error(ErrorCode::EventsLagged, format!("Missed {} board events; reopen boards to catch up", missed))
+                            .into(),
+                    )
+                }
+                Err(RecvError::Closed) => None,
+            },
+            change = presence.recv() => {
+                let (user_count, connection_count) = match change {
+                    Ok(change) => {
+                        if let Some(event) = change.event {
+                            let event = WebSocketResponse::PresenceChanged {
+                                kind: event.kind,
+                                user: event.user,
+                            };
+                            if let Ok(event_json) = event.to_json() {
+                                if let Err(e) = socket.send(axum::extract::ws::Message::Text(event_json.into())).await {
+                                    error!("Failed to send presence change: {}", e);
                                     break;
                                 }
                             }
                         }
+                        (change.user_count, change.connection_count)
                     }
-                    Err(e) => {
-                        warn!("Failed to parse WebSocket message: {}", e);
-                        let error_response = WebSocketResponse::error("Invalid message format");
-                        if let Ok(error_json) = error_response.to_json() {
-                            let _ = socket
-                                .send(axum::extract::ws::Message::Text(error_json.into()))
-                                .await;
-                        }
+                    // Clients can ask for the users online; only the latest
+                    // counts matter
+                    Err(_) => connection_manager.counts(),
+                };
+                Some(
+                    WebSocketResponse::ConnectionStatus {
+                        connected: true,
+                        user_count,
+                        connection_count,
                     }
-                }
+                    .into(),
+                )
             }
-            Ok(axum::extract::ws::Message::Close(_)) => {
-                info!("WebSocket connection closed: {}", connection_id);
+            _ = disconnect.notified() => {
+                info!("WebSocket connection {} disconnected by an admin", connection_id);
+                let _ = socket
+                    .send(axum::extract::ws::Message::Close(Some(CloseFrame {
+                        code: close_code::POLICY,
+                        reason: "Disconnected by an administrator".into(),
+                    })))
+                    .await;
                 break;
             }
-            Ok(axum::extract::ws::Message::Ping(data)) => {
-                debug!("Received ping, sending pong");
-                if let Err(e) = socket.send(axum::extract::ws::Message::Pong(data)).await {
-                    error!("Failed to send pong: {}", e);
+        };
+
+        if let Some(response) = response {
+            if let Ok(response_json) = response.to_json() {
+                if let Err(e) = socket
+                    .send(axum::extract::ws::Message::Text(response_json.into()))
+                    .await
+                {
+                    error!("Failed to send response: {}", e);
                     break;
                 }
             }
-            Ok(_) => {
-                // Ignore other message types (Binary, Pong)
-            }
-            Err(e) => {
-                error!("WebSocket error: {}", e);
+        }
+
+        // Send the download chunks the clients' windows allow
+        let mut sent = true;
+        for frame in state.transfers.next_frames().await {
+            let message = match frame {
+                Ok(frame) => axum::extract::ws::Message::Binary(frame.into()),
+                Err(response) => match response.to_json() {
+                    Ok(json) => axum::extract::ws::Message::Text(json.into()),
+                    Err(_) => continue,
+                },
+            };
+            if let Err(e) = socket.send(message).await {
+                error!("Failed to send media chunk: {}", e);
+                sent = false;
                 break;
             }
         }
+        if !sent {
+            break;
+        }
     }
 
     // Clean up connection
@@ -198,14 +434,39 @@ pub async fn handle_websocket_connection(
     );
 }
 
+/// Build the push for a media event, if the user may see it and it matches
+/// their subscription
+fn media_event_response(
+    event: &MediaEvent,
+    user: &User,
+    subscription: Option<&MediaSubscription>,
+) -> Option<WebSocketResponse> {
+    let subscription = subscription?;
+    if !event.visible_to(user) || !subscription.matches(event) {
+        return None;
+    }
+
+    Some(WebSocketResponse::MediaBlobEvent {
+        kind: event.kind,
+        blob: event.blob.clone(),
+    })
+}
+
 /// Handle a parsed WebSocket message and return optional response
+#[allow(clippy::too_many_arguments)]
 async fn handle_message(
     message: WebSocketMessage,
-    user_id: Option<Uuid>,
+    user: &User,
     db: &DatabaseConnection,
+    storage: &MediaStorage,
+    events: &MediaEvents,
     config: &AppConfig,
-    _connection_manager: &ConnectionManager,
+    state: &mut ConnectionState,
+    connection_id: &str,
+    connection_manager: &ConnectionManager,
 ) -> Option<WebSocketResponse> {
+    let user_id = user.id;
+
     match &message {
         WebSocketMessage::UploadMediaBlob { blob } => {
             info!(
@@ -225,59 +486,101 @@ async fn handle_message(
             debug!("Ping received, responding with pong");
             Some(WebSocketResponse::Pong)
         }
-        WebSocketMessage::GetMediaBlobs { limit, offset } => {
+        WebSocketMessage::GetPresence => Some(WebSocketResponse::Presence {
+            users: connection_manager.online_users(),
+        }),
+        WebSocketMessage::GetMediaBlobs {
+            limit,
+            offset,
+            collection_id,
+            tags,
+        } => {
             info!(
-                "GetMediaBlobs request (limit: {:?}, offset: {:?}) from user: {:?}",
+                "GetMediaBlobs request (limit: {:?}, offset: {:?}) from user: {}",
                 limit, offset, user_id
             );
 
+            let tags = match normalize_tags(&tags) {
+                Ok(tags) => tags,
+                Err(message) => {
+                    return Some(WebSocketResponse::error(ErrorCode::BadRequest, message))
+                }
+            };
+
             let repository = MediaRepository::new(db);
-            let service = MediaService::new(repository);
+            let service = MediaService::new(repository, storage).with_events(events);
 
             let query = MediaBlobQuery {
                 limit: limit.map(|l| l as i64),
                 offset: offset.map(|o| o as i64),
+                collection_id,
+                tags,
                 ..Default::default()
             };
 
-            match service.list_blobs(query).await {
+            match service.list_blobs(query, user).await {
                 Ok(blobs) => {
                     let total_count = blobs.len() as u32;
                     Some(WebSocketResponse::MediaBlobs { blobs, total_count })
                 }
+                Err(WebauthnError::UserNotFound) => Some(WebSocketResponse::error(
+                    ErrorCode::NotFound,
+                    "Media collection not found",
+                )),
                 Err(e) => {
                     error!("Failed to fetch media blobs: {}", e);
-                    Some(WebSocketResponse::error("Failed to fetch media blobs"))
+                    Some(WebSocketResponse::error(
+                        ErrorCode::from(&e),
+                        "Failed to fetch media blobs",
+                    ))
+                }
+            }
+        }
+        WebSocketMessage::SearchMedia { search } => {
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+
+            match service.search(&search, user).await {
+                Ok(page) => Some(WebSocketResponse::MediaSearchResults { page }),
+                Err(WebauthnError::MediaSearch(e)) => Some(WebSocketResponse::error(
+                    ErrorCode::InvalidSearch,
+                    e.to_string(),
+                )),
+                Err(e) => {
+                    error!("Failed to search media: {}", e);
+                    Some(WebSocketResponse::error(
+                        ErrorCode::from(&e),
+                        "Failed to search media",
+                    ))
                 }
             }
         }
         WebSocketMessage::GetMediaBlob { id } => {
-            info!(
-                "GetMediaBlob request for ID: {} from user: {:?}",
-                id, user_id
-            );
+            info!("GetMediaBlob request for ID: {} from user: {}", id, user_id);
 
             let repository = MediaRepository::new(db);
-            let service = MediaService::new(repository);
+            let service = MediaService::new(repository, storage).with_events(events);
 
-            match service.get_blob(id, false).await {
+            match service.get_blob(id, false, user).await {
                 Ok(blob) => Some(WebSocketResponse::MediaBlob { blob }),
                 Err(e) => {
                     warn!("Media blob not found: {} - {}", id, e);
-                    Some(WebSocketResponse::error("Media blob not found"))
+                    Some(WebSocketResponse::error(
+                        ErrorCode::NotFound,
+                        "Media blob not found",
+                    ))
                 }
             }
         }
         WebSocketMessage::GetMediaBlobData { id } => {
             info!(
-                "GetMediaBlobData request for ID: {} from user: {:?}",
+                "GetMediaBlobData request for ID: {} from user: {}",
                 id, user_id
             );
 
             let repository = MediaRepository::new(db);
-            let service = MediaService::new(repository);
+            let service = MediaService::new(repository, storage).with_events(events);
 
-            match service.get_blob(id, true).await {
+            match service.get_blob(id, true, user).await {
                 Ok(blob) => {
                     if let Some(data) = blob.data {
                         Some(WebSocketResponse::MediaBlobData {
@@ -287,44 +590,662 @@ async fn handle_message(
                         })
                     } else {
                         warn!("Media blob {} has no data", id);
-                        Some(WebSocketResponse::error("Media blob has no data"))
+                        Some(WebSocketResponse::error(
+                            ErrorCode::ContentMissing,
+                            "Media blob has no data",
+                        ))
                     }
                 }
+                Err(WebauthnError::MediaQuarantined(status)) => Some(WebSocketResponse::error(
+                    ErrorCode::Quarantined,
+                    format!("Media blob is quarantined ({})", status),
+                )),
                 Err(e) => {
                     warn!("Media blob not found: {} - {}", id, e);
-                    Some(WebSocketResponse::error("Media blob not found"))
+                    Some(WebSocketResponse::error(
+                        ErrorCode::NotFound,
+                        "Media blob not found",
+                    ))
                 }
             }
         }
         WebSocketMessage::UploadMediaBlob { blob } => {
             info!(
-                "UploadMediaBlob request (size: {:?}, mime: {:?}) from user: {:?}",
+                "UploadMediaBlob request (size: {:?}, mime: {:?}) from user: {}",
                 blob.size, blob.mime, user_id
             );
 
             let repository = MediaRepository::new(db);
-            let service = MediaService::new(repository);
+            let service = MediaService::new(repository, storage).with_events(events);
+
+            // Without data, only content the user can see may be referenced;
+            // anything else is answered as if it were not stored
+            if blob.data.as_ref().is_none_or(|data| data.is_empty()) {
+                match service.can_reference(&blob.sha256, user).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        return Some(WebSocketResponse::error(
+                            ErrorCode::ContentMissing,
+                            "Content not stored yet; resend the upload with data",
+                        ));
+                    }
+                    Err(e) => {
+                        error!("Failed to look up media content: {}", e);
+                        return Some(WebSocketResponse::error(
+                            ErrorCode::from(&e),
+                            "Failed to upload media blob",
+                        ));
+                    }
+                }
+            }
 
+            let mime = match service
+                .check_content_type(
+                    blob.mime.as_deref(),
+                    blob.data.as_deref(),
+                    &blob.sha256,
+                    &config.media.mime_types.websocket,
+                )
+                .await
+            {
+                Ok(mime) => mime,
+                Err(WebauthnError::MediaContentType(e)) => {
+                    return Some(WebSocketResponse::error(
+                        ErrorCode::UnsupportedMediaType,
+                        e.to_string(),
+                    ));
+                }
+                Err(e) => {
+                    error!("Failed to check media blob content type: {}", e);
+                    return Some(WebSocketResponse::error(
+                        ErrorCode::from(&e),
+                        "Failed to upload media blob",
+                    ));
+                }
+            };
+
+            // Ownership always comes from the session, never from the client
             let create_params = CreateMediaBlob {
                 data: blob.data,
                 sha256: blob.sha256,
                 size: blob.size,
-                mime: blob.mime,
+                mime,
                 source_client_id: blob.source_client_id,
+                owner_user_id: Some(user_id),
+                visibility: blob.visibility,
                 local_path: blob.local_path,
                 metadata: blob.metadata,
             };
 
-            match service.create_blob(create_params, &config.media).await {
+            match service
+                .create_upload(create_params, user, &config.media)
+                .await
+            {
                 Ok(created_blob) => {
                     info!("Successfully created media blob: {}", created_blob.id);
                     Some(WebSocketResponse::MediaBlob { blob: created_blob })
                 }
+                Err(WebauthnError::MediaContentMissing) => Some(WebSocketResponse::error(
+                    ErrorCode::ContentMissing,
+                    "Content not stored yet; resend the upload with data",
+                )),
+                Err(WebauthnError::MediaQuota(e)) => Some(WebSocketResponse::error(
+                    ErrorCode::QuotaExceeded,
+                    e.to_string(),
+                )),
                 Err(e) => {
                     error!("Failed to create media blob: {}", e);
-                    Some(WebSocketResponse::error("Failed to upload media blob"))
+                    Some(WebSocketResponse::error(
+                        ErrorCode::from(&e),
+                        "Failed to upload media blob",
+                    ))
+                }
+            }
+        }
+        WebSocketMessage::DeleteMediaBlob { id } => {
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+
+            match service.trash_blob(id, user).await {
+                Ok(_) => Some(WebSocketResponse::MediaBlobDeleted { id }),
+                Err(e) => Some(media_access_error(id, e)),
+            }
+        }
+        WebSocketMessage::GetMediaTrash { limit, offset } => {
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+
+            match service
+                .list_trash(
+                    user,
+                    &config.media,
+                    limit.map(i64::from),
+                    offset.map(i64::from),
+                )
+                .await
+            {
+                Ok(blobs) => Some(WebSocketResponse::MediaTrash { blobs }),
+                Err(e) => {
+                    error!("Failed to fetch media trash: {}", e);
+                    Some(WebSocketResponse::error(
+                        ErrorCode::from(&e),
+                        "Failed to fetch media trash",
+                    ))
+                }
+            }
+        }
+        WebSocketMessage::RestoreMediaBlob { id } => {
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+
+            match service.restore_blob(id, user, &config.media).await {
+                Ok(blob) => Some(WebSocketResponse::MediaBlob { blob }),
+                Err(e) => Some(media_trash_error(id, e)),
+            }
+        }
+        WebSocketMessage::PurgeMediaBlob { id } => {
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+
+            match service.purge_trashed_blob(id, user, &config.media).await {
+                Ok(_) => Some(WebSocketResponse::MediaBlobPurged { id }),
+                Err(e) => Some(media_trash_error(id, e)),
+            }
+        }
+        WebSocketMessage::EmptyMediaTrash => {
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+
+            match service.empty_trash(user, &config.media).await {
+                Ok(purged) => Some(WebSocketResponse::MediaTrashEmptied { purged }),
+                Err(e) => {
+                    error!("Failed to empty media trash: {}", e);
+                    Some(WebSocketResponse::error(
+                        ErrorCode::from(&e),
+                        "Failed to empty media trash",
+                    ))
+                }
+            }
+        }
+        WebSocketMessage::SetMediaBlobVisibility { id, visibility } => {
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+
+            match service.set_visibility(id, visibility, user).await {
+                Ok(blob) => Some(WebSocketResponse::MediaBlob { blob }),
+                Err(e) => Some(media_access_error(id, e)),
+            }
+        }
+        WebSocketMessage::ShareMediaBlob { id, usernames } => {
+            let auth_repo = AuthRepository::new(db);
+            let mut user_ids = Vec::with_capacity(usernames.len());
+
+            for username in &usernames {
+                match auth_repo.get_user_by_username(username).await {
+                    Ok(Some(target)) => user_ids.push(target.id),
+                    Ok(None) => {
+                        return Some(WebSocketResponse::error(
+                            ErrorCode::NotFound,
+                            format!("User not found: {}", username),
+                        ))
+                    }
+                    Err(e) => {
+                        error!("Failed to look up user {}: {}", username, e);
+                        return Some(WebSocketResponse::error(
+                            ErrorCode::Internal,
+                            "Failed to share media blob",
+                        ));
+                    }
+                }
+            }
+
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+            match service.share_blob(id, &user_ids, user).await {
+                Ok(shares) => Some(WebSocketResponse::MediaBlobShares { id, shares }),
+                Err(e) => Some(media_access_error(id, e)),
+            }
+        }
+        WebSocketMessage::UnshareMediaBlob { id, username } => {
+            let target = match AuthRepository::new(db)
+                .get_user_by_username(&username)
+                .await
+            {
+                Ok(Some(target)) => target,
+                Ok(None) => {
+                    return Some(WebSocketResponse::error(
+                        ErrorCode::NotFound,
+                        format!("User not found: {}", username),
+                    ))
+                }
+                Err(e) => {
+                    error!("Failed to look up user {}: {}", username, e);
+                    return Some(WebSocketResponse::error(
+                        ErrorCode::Internal,
+                        "Failed to unshare media blob",
+                    ));
+                }
+            };
+
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+            match service.unshare_blob(id, target.id, user).await {
+                Ok(shares) => Some(WebSocketResponse::MediaBlobShares { id, shares }),
+                Err(e) => Some(media_access_error(id, e)),
+            }
+        }
+        WebSocketMessage::GetMediaBlobShares { id } => {
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+
+            match service.list_shares(id, user).await {
+                Ok(shares) => Some(WebSocketResponse::MediaBlobShares { id, shares }),
+                Err(e) => Some(media_access_error(id, e)),
+            }
+        }
+        WebSocketMessage::SetMediaBlobTags { id, tags } => {
+            if let Err(message) = normalize_tags(&tags) {
+                return Some(WebSocketResponse::error(ErrorCode::BadRequest, message));
+            }
+
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+            match service.set_tags(id, &tags, user).await {
+                Ok(blob) => Some(WebSocketResponse::MediaBlob { blob }),
+                Err(e) => Some(media_access_error(id, e)),
+            }
+        }
+        WebSocketMessage::GetMediaTags { prefix, limit } => {
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+
+            match service
+                .list_tags(prefix.as_deref(), limit.unwrap_or(100) as i64, user)
+                .await
+            {
+                Ok(tags) => Some(WebSocketResponse::MediaTags { tags }),
+                Err(e) => {
+                    error!("Failed to fetch media tags: {}", e);
+                    Some(WebSocketResponse::error(
+                        ErrorCode::from(&e),
+                        "Failed to fetch media tags",
+                    ))
+                }
+            }
+        }
+        WebSocketMessage::GetMediaCollections => {
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+
+            match service.list_collections(user).await {
+                Ok(collections) => Some(WebSocketResponse::MediaCollections { collections }),
+                Err(e) => {
+                    error!("Failed to fetch media collections: {}", e);
+                    Some(WebSocketResponse::error(
+                        ErrorCode::from(&e),
+                        "Failed to fetch media collections",
+                    ))
+                }
+            }
+        }
+        WebSocketMessage::GetMediaCollection { id } => {
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+
+            match service.get_collection(id, user).await {
+                Ok(collection) => Some(collection_response(&service, collection, user).await),
+                Err(e) => Some(media_collection_error(id, e)),
+            }
+        }
+        WebSocketMessage::CreateMediaCollection {
+            name,
+            description,
+            retention_days,
+        } => {
+            if let Err(message) = MediaCollection::normalize_name(&name) {
+                return Some(WebSocketResponse::error(ErrorCode::BadRequest, message));
+            }
+            if let Some(Err(message)) = retention_days.map(retention::check_collection_retention) {
+                return Some(WebSocketResponse::error(ErrorCode::BadRequest, message));
+            }
+
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+            match service
+                .create_collection(&name, description.as_deref(), retention_days, user)
+                .await
+            {
+                Ok(collection) => Some(WebSocketResponse::MediaCollection {
+                    collection,
+                    blob_ids: Vec::new(),
+                }),
+                Err(WebauthnError::BadRequest) => Some(WebSocketResponse::error(
+                    ErrorCode::Conflict,
+                    "A collection with this name already exists",
+                )),
+                Err(e) => {
+                    error!("Failed to create media collection: {}", e);
+                    Some(WebSocketResponse::error(
+                        ErrorCode::from(&e),
+                        "Failed to create media collection",
+                    ))
+                }
+            }
+        }
+        WebSocketMessage::UpdateMediaCollection {
+            id,
+            name,
+            description,
+            retention_days,
+        } => {
+            if let Some(Err(message)) = name.as_deref().map(MediaCollection::normalize_name) {
+                return Some(WebSocketResponse::error(ErrorCode::BadRequest, message));
+            }
+            if let Some(Err(message)) = retention_days.map(retention::check_collection_retention) {
+                return Some(WebSocketResponse::error(ErrorCode::BadRequest, message));
+            }
+
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+            match service
+                .update_collection(
+                    id,
+                    name.as_deref(),
+                    description.as_deref(),
+                    retention_days,
+                    user,
+                )
+                .await
+            {
+                Ok(collection) => Some(collection_response(&service, collection, user).await),
+                Err(WebauthnError::BadRequest) => Some(WebSocketResponse::error(
+                    ErrorCode::Conflict,
+                    "A collection with this name already exists",
+                )),
+                Err(e) => Some(media_collection_error(id, e)),
+            }
+        }
+        WebSocketMessage::DeleteMediaCollection { id } => {
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+
+            match service.delete_collection(id, user).await {
+                Ok(_) => Some(WebSocketResponse::MediaCollectionDeleted { id }),
+                Err(e) => Some(media_collection_error(id, e)),
+            }
+        }
+        WebSocketMessage::AddToMediaCollection { id, blob_ids } => {
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+
+            match service.add_to_collection(id, &blob_ids, user).await {
+                Ok(collection) => Some(collection_response(&service, collection, user).await),
+                Err(WebauthnError::UserNotFound) => Some(WebSocketResponse::error(
+                    ErrorCode::NotFound,
+                    "Media collection or blob not found",
+                )),
+                Err(e) => Some(media_collection_error(id, e)),
+            }
+        }
+        WebSocketMessage::RemoveFromMediaCollection { id, blob_id } => {
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+
+            match service.remove_from_collection(id, blob_id, user).await {
+                Ok(collection) => Some(collection_response(&service, collection, user).await),
+                Err(e) => Some(media_collection_error(id, e)),
+            }
+        }
+        WebSocketMessage::ReorderMediaCollection { id, blob_ids } => {
+            let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+
+            match service.reorder_collection(id, &blob_ids, user).await {
+                Ok(_) => match service.get_collection(id, user).await {
+                    Ok(collection) => Some(collection_response(&service, collection, user).await),
+                    Err(e) => Some(media_collection_error(id, e)),
+                },
+                Err(WebauthnError::BadRequest) => Some(WebSocketResponse::error(
+                    ErrorCode::BadRequest,
+                    "Reordering must list every blob in the collection exactly once",
+                )),
+                Err(e) => Some(media_collection_error(id, e)),
+            }
+        }
+        WebSocketMessage::SubscribeMedia {
+            collection_ids,
+            mime_prefixes,
+        } => {
+            // Only the user's own collections can be followed
+            let service = MediaService::new(MediaRepository::new(db), storage);
+            for id in &collection_ids {
+                if let Err(e) = service.get_collection(*id, user).await {
+                    return Some(media_collection_error(*id, e));
+                }
+            }
+
+            let subscription = MediaSubscription::new(collection_ids, mime_prefixes);
+            state.subscription = Some(subscription.clone());
+            Some(WebSocketResponse::MediaSubscribed { subscription })
+        }
+        WebSocketMessage::UnsubscribeMedia => {
+            state.subscription = None;
+            Some(WebSocketResponse::MediaUnsubscribed)
+        }
+        WebSocketMessage::DownloadMediaBlob {
+            transfer_id,
+            id,
+            chunk_size,
+            window,
+        } => {
+            if let Err(e) = state.transfers.check_available(transfer_id) {
+                return Some(e.to_response());
+            }
+
+            let service = MediaService::new(MediaRepository::new(db), storage);
+            let content = async {
+                let blob = service.get_blob(id, false, user).await?;
+                MediaService::ensure_released(&blob)?;
+                let content = service.content(&blob).await?;
+                Ok::<_, WebauthnError>((blob, content))
+            }
+            .await;
+
+            match content {
+                Ok((blob, content)) => Some(
+                    state
+                        .transfers
+                        .start_download(transfer_id, blob, content, chunk_size, window)
+                        .await
+                        .unwrap_or_else(|e| {
+                            error!("Failed to start download of media blob {}: {}", id, e);
+                            e.to_response()
+                        }),
+                ),
+                Err(WebauthnError::MediaQuarantined(status)) => Some(WebSocketResponse::error(
+                    ErrorCode::Quarantined,
+                    format!("Media blob is quarantined ({})", status),
+                )),
+                Err(e) => {
+                    warn!("Media blob not found: {} - {}", id, e);
+                    Some(WebSocketResponse::error(
+                        ErrorCode::NotFound,
+                        "Media blob not found",
+                    ))
                 }
             }
         }
+        WebSocketMessage::AckMediaChunk {
+            transfer_id,
+            chunk_index,
+        } => match state.transfers.acknowledge(transfer_id, chunk_index) {
+            Ok(()) => None,
+            Err(e) => Some(e.to_response()),
+        },
+        WebSocketMessage::BeginMediaUpload {
+            transfer_id,
+            sha256,
+            size,
+            mime,
+            visibility,
+            source_client_id,
+            metadata,
+            chunk_size,
+            window,
+        } => {
+            let request = UploadRequest {
+                transfer_id,
+                sha256,
+                size,
+                mime,
+                visibility,
+                source_client_id,
+                metadata,
+                chunk_size,
+                window,
+            };
+            Some(
+                transfer::begin_upload(
+                    &mut state.transfers,
+                    request,
+                    user,
+                    db,
+                    storage,
+                    events,
+                    config,
+                )
+                .await,
+            )
+        }
+        WebSocketMessage::CancelMediaTransfer { transfer_id } => {
+            if state.transfers.cancel(transfer_id) {
+                Some(WebSocketResponse::MediaTransferCancelled { transfer_id })
+            } else {
+                Some(transfer::TransferError::Unknown(transfer_id).to_response())
+            }
+        }
+        WebSocketMessage::ListRooms
+        | WebSocketMessage::CreateRoom { .. }
+        | WebSocketMessage::JoinRoom { .. }
+        | WebSocketMessage::LeaveRoom { .. }
+        | WebSocketMessage::AddRoomMember { .. }
+        | WebSocketMessage::RemoveRoomMember { .. }
+        | WebSocketMessage::GetRoomMembers { .. }
+        | WebSocketMessage::PostRoomMessage { .. }
+        | WebSocketMessage::GetRoomHistory { .. }
+        | WebSocketMessage::DeleteRoom { .. } => {
+            let connection = RoomConnection {
+                connection_id,
+                user,
+                rooms: &mut state.rooms,
+                connection_manager,
+            };
+            let response = rooms::handle_room_message(&message, connection, db, storage).await;
+            if let Some(
+                WebSocketResponse::RoomLeft { room_id }
+                | WebSocketResponse::RoomDeleted { room_id },
+            ) = &response
+            {
+                state.boards.close_room(*room_id);
+            }
+            response
+        }
+        WebSocketMessage::ListBoards { .. }
+        | WebSocketMessage::CreateBoard { .. }
+        | WebSocketMessage::OpenBoard { .. }
+        | WebSocketMessage::CloseBoard { .. }
+        | WebSocketMessage::ApplyBoardOp { .. }
+        | WebSocketMessage::DeleteBoard { .. } => {
+            let connection = BoardConnection {
+                connection_id,
+                user,
+                boards: &mut state.boards,
+                connection_manager,
+            };
+            boards::handle_board_message(&message, connection, db, storage).await
+        }
+    }
+}
+
+/// Handle a binary frame carrying an upload chunk
+async fn handle_frame(
+    frame: &[u8],
+    transfers: &mut Transfers,
+    db: &DatabaseConnection,
+    storage: &MediaStorage,
+    events: &MediaEvents,
+    config: &AppConfig,
+) -> WebSocketResponse {
+    match transfers.receive(frame).await {
+        Ok(UploadStep::Progress(progress)) => progress.to_response(),
+        Ok(UploadStep::Complete(upload)) => {
+            transfer::finish_upload(*upload, db, storage, events, config).await
+        }
+        Err(e) => {
+            warn!("Refused WebSocket upload chunk: {}", e);
+            e.to_response()
+        }
+    }
+}
+
+/// Build the response describing a collection and its blob order
+async fn collection_response(
+    service: &MediaService<'_>,
+    collection: MediaCollection,
+    user: &User,
+) -> WebSocketResponse {
+    match service.collection_blob_ids(collection.id, user).await {
+        Ok(blob_ids) => WebSocketResponse::MediaCollection {
+            collection,
+            blob_ids,
+        },
+        Err(e) => media_collection_error(collection.id, e),
+    }
+}
+
+/// Build the error response for a failed media collection operation
+fn media_collection_error(id: Uuid, err: WebauthnError) -> WebSocketResponse {
+    match err {
+        WebauthnError::UserNotFound => {
+            WebSocketResponse::error(ErrorCode::NotFound, "Media collection not found")
+        }
+        e => {
+            error!("Media collection operation failed for {}: {}", id, e);
+            WebSocketResponse::error(ErrorCode::from(&e), "Media collection operation failed")
+        }
+    }
+}
+
+/// Build the error response for a failed operation on a trashed media blob
+fn media_trash_error(id: Uuid, err: WebauthnError) -> WebSocketResponse {
+    match err {
+        WebauthnError::UserNotFound => {
+            WebSocketResponse::error(ErrorCode::NotFound, "Media blob not found in trash")
+        }
+        e => {
+            error!("Media trash operation failed for {}: {}", id, e);
+            WebSocketResponse::error(ErrorCode::from(&e), "Media trash operation failed")
+        }
+    }
+}
+
+/// Build the error response for a failed media blob management operation
+fn media_access_error(id: Uuid, err: WebauthnError) -> WebSocketResponse {
+    match err {
+        WebauthnError::Forbidden => WebSocketResponse::error(
+            ErrorCode::Forbidden,
+            "Only the owner of a media blob can manage it",
+        ),
+        WebauthnError::UserNotFound => {
+            WebSocketResponse::error(ErrorCode::NotFound, "Media blob not found")
+        }
+        e => {
+            error!("Media blob operation failed for {}: {}", id, e);
+            WebSocketResponse::error(ErrorCode::from(&e), "Media blob operation failed")
+        }
     }
 }
+
+/// List live WebSocket connections (admin only)
+pub async fn list_connections(
+    Extension(connection_manager): Extension<ConnectionManager>,
+) -> Json<Vec<LiveConnection>> {
+    Json(connection_manager.connections())
+}
+
+/// Close a live WebSocket connection (admin only)
+pub async fn disconnect_connection(
+    Extension(connection_manager): Extension<ConnectionManager>,
+    Path(connection_id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    if !connection_manager.disconnect(&connection_id) {
+        return Err(AppError::NotFound(format!(
+            "No connection {}",
+            connection_id
+        )));
+    }
+
+    info!("Admin disconnected WebSocket connection {}", connection_id);
+    Ok(StatusCode::NO_CONTENT)
+}
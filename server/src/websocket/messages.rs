@@ -3,7 +3,14 @@
 //! Defines the message format for WebSocket communication between
 //! client and server, with serde for JSON serialization.
 
-use crate::media::MediaBlob;
+use crate::boards::{Board, BoardOp, BoardOperation, BoardState};
+use crate::media::{
+    MediaBlob, MediaBlobShare, MediaCollection, MediaEventKind, MediaSearch, MediaSearchPage,
+    MediaSubscription, MediaTagCount, MediaVisibility, TrashedMediaBlob,
+};
+use crate::rooms::{Room, RoomHistory, RoomMember, RoomMessage, RoomRole, RoomVisibility};
+use crate::websocket::presence::{OnlineUser, PresenceEventKind};
+use crate::websocket::protocol::{ErrorCode, RequestId};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -14,27 +21,223 @@ pub enum WebSocketMessage {
     /// Client sends a ping to check connection
     Ping,
     /// Client requests list of media blobs
+    ///
+    /// With `collection_id` the blobs come in collection order; `tags`
+    /// keeps only blobs carrying every listed tag.
     GetMediaBlobs {
         limit: Option<u32>,
         offset: Option<u32>,
+        #[serde(default)]
+        collection_id: Option<Uuid>,
+        #[serde(default)]
+        tags: Vec<String>,
     },
     /// Client uploads a new media blob
+    ///
+    /// `data` may be omitted when the content is already stored; the server
+    /// then answers with an error coded `content_missing` if it is not.
     UploadMediaBlob { blob: MediaBlob },
+    /// Client searches the media blobs it can see
+    SearchMedia { search: MediaSearch },
     /// Client requests specific media blob by ID
     GetMediaBlob { id: Uuid },
     /// Client requests media blob data by ID
     GetMediaBlobData { id: Uuid },
+    /// Client moves a media blob it owns to the trash
+    DeleteMediaBlob { id: Uuid },
+    /// Client requests the media blobs in its trash
+    GetMediaTrash {
+        limit: Option<u32>,
+        offset: Option<u32>,
+    },
+    /// Client takes a media blob out of the trash
+    RestoreMediaBlob { id: Uuid },
+    /// Client deletes a media blob in its trash for good
+    PurgeMediaBlob { id: Uuid },
+    /// Client deletes everything in its trash for good
+    EmptyMediaTrash,
+    /// Client changes who may access a media blob it owns
+    SetMediaBlobVisibility {
+        id: Uuid,
+        visibility: MediaVisibility,
+    },
+    /// Client grants users access to a media blob it owns
+    ShareMediaBlob { id: Uuid, usernames: Vec<String> },
+    /// Client revokes a user's access to a media blob it owns
+    UnshareMediaBlob { id: Uuid, username: String },
+    /// Client requests the sharing grants of a media blob it owns
+    GetMediaBlobShares { id: Uuid },
+    /// Client replaces the tags of a media blob it owns
+    SetMediaBlobTags { id: Uuid, tags: Vec<String> },
+    /// Client requests the tags in use on its media blobs
+    GetMediaTags {
+        prefix: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Client requests its media collections
+    GetMediaCollections,
+    /// Client requests a media collection and its blob IDs
+    GetMediaCollection { id: Uuid },
+    /// Client creates a media collection
+    ///
+    /// With `retention_days` the collection's blobs are purged once older.
+    CreateMediaCollection {
+        name: String,
+        description: Option<String>,
+        #[serde(default)]
+        retention_days: Option<i32>,
+    },
+    /// Client renames a media collection or changes its description or
+    /// retention; a retention of 0 days removes it
+    UpdateMediaCollection {
+        id: Uuid,
+        name: Option<String>,
+        description: Option<String>,
+        #[serde(default)]
+        retention_days: Option<i32>,
+    },
+    /// Client deletes a media collection, keeping its blobs
+    DeleteMediaCollection { id: Uuid },
+    /// Client appends media blobs to a collection
+    AddToMediaCollection { id: Uuid, blob_ids: Vec<Uuid> },
+    /// Client removes a media blob from a collection
+    RemoveFromMediaCollection { id: Uuid, blob_id: Uuid },
+    /// Client reorders a collection; `blob_ids` lists every blob once
+    ReorderMediaCollection { id: Uuid, blob_ids: Vec<Uuid> },
+    /// Client chooses which media blob events it is sent
+    ///
+    /// Empty lists do not filter; connections start subscribed to every
+    /// event about blobs they can see.
+    SubscribeMedia {
+        #[serde(default)]
+        collection_ids: Vec<Uuid>,
+        #[serde(default)]
+        mime_prefixes: Vec<String>,
+    },
+    /// Client stops receiving media blob events
+    UnsubscribeMedia,
+    /// Client requests the users currently online
+    GetPresence,
+    /// Client asks for a media blob's content in binary frames
+    ///
+    /// `transfer_id` names the transfer in its frames and later messages,
+    /// and is separate from the envelope's `request_id`. `chunk_size` and `window`, the chunks sent ahead of acknowledgement,
+    /// are adjusted to the server's limits.
+    DownloadMediaBlob {
+        transfer_id: u32,
+        id: Uuid,
+        #[serde(default)]
+        chunk_size: Option<u32>,
+        #[serde(default)]
+        window: Option<u32>,
+    },
+    /// Client confirms it received every download chunk up to `chunk_index`
+    AckMediaChunk { transfer_id: u32, chunk_index: u32 },
+    /// Client announces a media blob whose content follows in binary frames
+    BeginMediaUpload {
+        transfer_id: u32,
+        sha256: String,
+        size: u64,
+        #[serde(default)]
+        mime: Option<String>,
+        #[serde(default)]
+        visibility: MediaVisibility,
+        #[serde(default)]
+        source_client_id: Option<String>,
+        #[serde(default)]
+        metadata: serde_json::Value,
+        #[serde(default)]
+        chunk_size: Option<u32>,
+        #[serde(default)]
+        window: Option<u32>,
+    },
+    /// Client abandons a download or upload
+    CancelMediaTransfer { transfer_id: u32 },
+    /// Client requests the rooms it can see
+    ListRooms,
+    /// Client creates a room it owns
+    CreateRoom {
+        name: String,
+        #[serde(default)]
+        topic: Option<String>,
+        #[serde(default)]
+        visibility: RoomVisibility,
+    },
+    /// Client joins a room and loads up to `limit` recent messages
+    JoinRoom {
+        room_id: Uuid,
+        #[serde(default)]
+        limit: Option<u32>,
+    },
+    /// Client leaves a room
+    LeaveRoom { room_id: Uuid },
+    /// Client adds a user to a room, or changes their role
+    AddRoomMember {
+        room_id: Uuid,
+        username: String,
+        #[serde(default)]
+        role: Option<RoomRole>,
+    },
+    /// Client removes a user from a room
+    RemoveRoomMember { room_id: Uuid, username: String },
+    /// Client requests the members of a room
+    GetRoomMembers { room_id: Uuid },
+    /// Client posts text, a media blob, or both in a room
+    PostRoomMessage {
+        room_id: Uuid,
+        #[serde(default)]
+        body: Option<String>,
+        #[serde(default)]
+        blob_id: Option<Uuid>,
+    },
+    /// Client loads messages older than the message with ID `before`
+    GetRoomHistory {
+        room_id: Uuid,
+        #[serde(default)]
+        before: Option<i64>,
+        #[serde(default)]
+        limit: Option<u32>,
+    },
+    /// Client deletes a room it owns
+    DeleteRoom { room_id: Uuid },
+    /// Client requests the drawing boards of a room
+    ListBoards { room_id: Uuid },
+    /// Client creates a drawing board in a room
+    CreateBoard { room_id: Uuid, name: String },
+    /// Client opens a board to draw on it and receive others' operations
+    ///
+    /// `since` is the sequence number of the last operation the client
+    /// applied, when reopening a board it already has.
+    OpenBoard {
+        board_id: Uuid,
+        #[serde(default)]
+        since: Option<i64>,
+    },
+    /// Client stops receiving a board's operations
+    CloseBoard { board_id: Uuid },
+    /// Client applies an operation to an open board
+    ///
+    /// A resent operation with the same `client_op_id` is applied once.
+    ApplyBoardOp {
+        board_id: Uuid,
+        #[serde(default)]
+        client_op_id: Option<String>,
+        op: BoardOperation,
+    },
+    /// Client deletes a board
+    DeleteBoard { board_id: Uuid },
 }
 
 /// Messages sent from server to client
 #[derive(Clone, Serialize, Deserialize)]
 #[serde(tag = "type", content = "data")]
 pub enum WebSocketResponse {
-    /// Server greeting on connection
+    /// Server greeting on connection, with the protocol version agreed on
     Welcome {
         message: String,
         user_id: Option<Uuid>,
         connection_id: String,
+        protocol_version: u32,
     },
     /// Server responds to ping
     Pong,
@@ -43,21 +246,144 @@ pub enum WebSocketResponse {
         blobs: Vec<MediaBlob>,
         total_count: u32,
     },
+    /// Server sends a page of search results
+    MediaSearchResults { page: MediaSearchPage },
     /// Server sends single media blob
     MediaBlob { blob: MediaBlob },
     /// Server sends media blob data (binary content)
+    ///
+    /// The data is a JSON array of numbers; `DownloadMediaBlob` sends it in
+    /// binary frames instead.
     MediaBlobData {
         id: Uuid,
         data: Vec<u8>,
         mime: Option<String>,
     },
+    /// Server confirms a media blob was moved to the trash
+    MediaBlobDeleted { id: Uuid },
+    /// Server sends the media blobs in the client's trash
+    MediaTrash { blobs: Vec<TrashedMediaBlob> },
+    /// Server confirms a media blob was deleted for good
+    MediaBlobPurged { id: Uuid },
+    /// Server confirms the trash was emptied
+    MediaTrashEmptied { purged: u64 },
+    /// Server sends the sharing grants of a media blob
+    MediaBlobShares {
+        id: Uuid,
+        shares: Vec<MediaBlobShare>,
+    },
+    /// Server sends tags with the number of blobs carrying each
+    MediaTags { tags: Vec<MediaTagCount> },
+    /// Server sends the client's media collections
+    MediaCollections { collections: Vec<MediaCollection> },
+    /// Server sends a media collection and its blob IDs in order
+    MediaCollection {
+        collection: MediaCollection,
+        blob_ids: Vec<Uuid>,
+    },
+    /// Server confirms a media collection was deleted
+    MediaCollectionDeleted { id: Uuid },
     /// Server sends error message
-    Error {
-        message: String,
-        code: Option<String>,
+    Error { message: String, code: ErrorCode },
+    /// Server sends connection status update, also whenever a client
+    /// connects or disconnects; users with several connections count once
+    /// in `user_count`
+    ConnectionStatus {
+        connected: bool,
+        user_count: u32,
+        connection_count: u32,
+    },
+    /// Server sends the users currently online, by username
+    Presence { users: Vec<OnlineUser> },
+    /// Server pushes a user coming online or going offline
+    PresenceChanged {
+        kind: PresenceEventKind,
+        user: OnlineUser,
+    },
+    /// Server confirms the client's media event subscription
+    MediaSubscribed { subscription: MediaSubscription },
+    /// Server confirms the client no longer receives media events
+    MediaUnsubscribed,
+    /// Server pushes a change to a media blob the client can see
+    MediaBlobEvent {
+        kind: MediaEventKind,
+        blob: MediaBlob,
+    },
+    /// Server starts sending a media blob's content in binary frames
+    MediaDownloadStarted {
+        transfer_id: u32,
+        blob: MediaBlob,
+        size: u64,
+        chunk_size: u32,
+        chunk_count: u32,
+        window: u32,
     },
-    /// Server sends connection status update
-    ConnectionStatus { connected: bool, user_count: u32 },
+    /// Server is ready for the binary frames of an upload, sent with `id`
+    MediaUploadReady {
+        transfer_id: u32,
+        id: Uuid,
+        chunk_size: u32,
+        chunk_count: u32,
+        window: u32,
+    },
+    /// Server acknowledges an upload chunk
+    MediaTransferProgress {
+        transfer_id: u32,
+        id: Uuid,
+        chunk_index: u32,
+        bytes: u64,
+        size: u64,
+    },
+    /// Server stored an uploaded media blob
+    MediaUploadComplete { transfer_id: u32, blob: MediaBlob },
+    /// Server confirms a transfer was abandoned
+    MediaTransferCancelled { transfer_id: u32 },
+    /// Server sends the rooms the client can see, by name
+    Rooms { rooms: Vec<Room> },
+    /// Server sends a room the client created
+    RoomCreated { room: Room },
+    /// Server sends a room the client joined, with its recent messages and
+    /// the members currently online
+    RoomJoined {
+        room: Room,
+        members: Vec<RoomMember>,
+        history: RoomHistory,
+        online: Vec<Uuid>,
+    },
+    /// Server confirms the client left a room, or was removed from it
+    RoomLeft { room_id: Uuid },
+    /// Server sends the members of a room
+    RoomMembers {
+        room_id: Uuid,
+        members: Vec<RoomMember>,
+    },
+    /// Server sends a message posted in a room the client is in
+    RoomMessage { message: RoomMessage },
+    /// Server sends a page of a room's history
+    RoomHistory { history: RoomHistory },
+    /// Server pushes a user joining a room, or their role changing
+    RoomMemberJoined { room_id: Uuid, member: RoomMember },
+    /// Server pushes a user leaving or being removed from a room
+    RoomMemberLeft { room_id: Uuid, user_id: Uuid },
+    /// Server confirms, or pushes, a room being deleted
+    RoomDeleted { room_id: Uuid },
+    /// Server sends the drawing boards of a room, by name
+    Boards { room_id: Uuid, boards: Vec<Board> },
+    /// Server confirms, or pushes, a board being created
+    BoardCreated { board: Board },
+    /// Server sends a board the client opened: the drawing to start from,
+    /// if any, and the operations to apply to it
+    BoardOpened {
+        board: Board,
+        snapshot: Option<BoardState>,
+        ops: Vec<BoardOp>,
+    },
+    /// Server confirms the client closed a board
+    BoardClosed { board_id: Uuid },
+    /// Server confirms, or pushes, an operation applied to an open board
+    BoardOp { op: BoardOp },
+    /// Server confirms, or pushes, a board being deleted
+    BoardDeleted { board_id: Uuid },
 }
 
 impl WebSocketMessage {
@@ -76,10 +402,17 @@ impl std::fmt::Debug for WebSocketMessage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             WebSocketMessage::Ping => f.debug_struct("Ping").finish(),
-            WebSocketMessage::GetMediaBlobs { limit, offset } => f
+            WebSocketMessage::GetMediaBlobs {
+                limit,
+                offset,
+                collection_id,
+                tags,
+            } => f
                 .debug_struct("GetMediaBlobs")
                 .field("limit", limit)
                 .field("offset", offset)
+                .field("collection_id", collection_id)
+                .field("tags", tags)
                 .finish(),
             WebSocketMessage::UploadMediaBlob { blob } => f
                 .debug_struct("UploadMediaBlob")
@@ -88,12 +421,245 @@ impl std::fmt::Debug for WebSocketMessage {
                 .field("blob_mime", &blob.mime)
                 .field("blob_sha256_prefix", &format!("{}...", &blob.sha256[..8]))
                 .finish(),
+            WebSocketMessage::SearchMedia { search } => f
+                .debug_struct("SearchMedia")
+                .field("search", search)
+                .finish(),
             WebSocketMessage::GetMediaBlob { id } => {
                 f.debug_struct("GetMediaBlob").field("id", id).finish()
             }
             WebSocketMessage::GetMediaBlobData { id } => {
                 f.debug_struct("GetMediaBlobData").field("id", id).finish()
             }
+            WebSocketMessage::DeleteMediaBlob { id } => {
+                f.debug_struct("DeleteMediaBlob").field("id", id).finish()
+            }
+            WebSocketMessage::GetMediaTrash { limit, offset } => f
+                .debug_struct("GetMediaTrash")
+                .field("limit", limit)
+                .field("offset", offset)
+                .finish(),
+            WebSocketMessage::RestoreMediaBlob { id } => {
+                f.debug_struct("RestoreMediaBlob").field("id", id).finish()
+            }
+            WebSocketMessage::PurgeMediaBlob { id } => {
+                f.debug_struct("PurgeMediaBlob").field("id", id).finish()
+            }
+            WebSocketMessage::EmptyMediaTrash => f.debug_struct("EmptyMediaTrash").finish(),
+            WebSocketMessage::SetMediaBlobVisibility { id, visibility } => f
+                .debug_struct("SetMediaBlobVisibility")
+                .field("id", id)
+                .field("visibility", visibility)
+                .finish(),
+            WebSocketMessage::ShareMediaBlob { id, usernames } => f
+                .debug_struct("ShareMediaBlob")
+                .field("id", id)
+                .field("usernames", usernames)
+                .finish(),
+            WebSocketMessage::UnshareMediaBlob { id, username } => f
+                .debug_struct("UnshareMediaBlob")
+                .field("id", id)
+                .field("username", username)
+                .finish(),
+            WebSocketMessage::GetMediaBlobShares { id } => f
+                .debug_struct("GetMediaBlobShares")
+                .field("id", id)
+                .finish(),
+            WebSocketMessage::SetMediaBlobTags { id, tags } => f
+                .debug_struct("SetMediaBlobTags")
+                .field("id", id)
+                .field("tags", tags)
+                .finish(),
+            WebSocketMessage::GetMediaTags { prefix, limit } => f
+                .debug_struct("GetMediaTags")
+                .field("prefix", prefix)
+                .field("limit", limit)
+                .finish(),
+            WebSocketMessage::GetMediaCollections => f.debug_struct("GetMediaCollections").finish(),
+            WebSocketMessage::GetMediaCollection { id } => f
+                .debug_struct("GetMediaCollection")
+                .field("id", id)
+                .finish(),
+            WebSocketMessage::CreateMediaCollection {
+                name,
+                description,
+                retention_days,
+            } => f
+                .debug_struct("CreateMediaCollection")
+                .field("name", name)
+                .field("description", description)
+                .field("retention_days", retention_days)
+                .finish(),
+            WebSocketMessage::UpdateMediaCollection {
+                id,
+                name,
+                description,
+                retention_days,
+            } => f
+                .debug_struct("UpdateMediaCollection")
+                .field("id", id)
+                .field("name", name)
+                .field("description", description)
+                .field("retention_days", retention_days)
+                .finish(),
+            WebSocketMessage::DeleteMediaCollection { id } => f
+                .debug_struct("DeleteMediaCollection")
+                .field("id", id)
+                .finish(),
+            WebSocketMessage::AddToMediaCollection { id, blob_ids } => f
+                .debug_struct("AddToMediaCollection")
+                .field("id", id)
+                .field("blob_count", &blob_ids.len())
+                .finish(),
+            WebSocketMessage::RemoveFromMediaCollection { id, blob_id } => f
+                .debug_struct("RemoveFromMediaCollection")
+                .field("id", id)
+                .field("blob_id", blob_id)
+                .finish(),
+            WebSocketMessage::ReorderMediaCollection { id, blob_ids } => f
+                .debug_struct("ReorderMediaCollection")
+                .field("id", id)
+                .field("blob_count", &blob_ids.len())
+                .finish(),
+            WebSocketMessage::SubscribeMedia {
+                collection_ids,
+                mime_prefixes,
+            } => f
+                .debug_struct("SubscribeMedia")
+                .field("collection_ids", collection_ids)
+                .field("mime_prefixes", mime_prefixes)
+                .finish(),
+            WebSocketMessage::UnsubscribeMedia => f.debug_struct("UnsubscribeMedia").finish(),
+            WebSocketMessage::GetPresence => f.debug_struct("GetPresence").finish(),
+            WebSocketMessage::DownloadMediaBlob {
+                transfer_id,
+                id,
+                chunk_size,
+                window,
+            } => f
+                .debug_struct("DownloadMediaBlob")
+                .field("transfer_id", transfer_id)
+                .field("id", id)
+                .field("chunk_size", chunk_size)
+                .field("window", window)
+                .finish(),
+            WebSocketMessage::AckMediaChunk {
+                transfer_id,
+                chunk_index,
+            } => f
+                .debug_struct("AckMediaChunk")
+                .field("transfer_id", transfer_id)
+                .field("chunk_index", chunk_index)
+                .finish(),
+            WebSocketMessage::BeginMediaUpload {
+                transfer_id,
+                sha256,
+                size,
+                mime,
+                ..
+            } => f
+                .debug_struct("BeginMediaUpload")
+                .field("transfer_id", transfer_id)
+                .field("sha256", &sha256.get(..8).unwrap_or(sha256))
+                .field("size", size)
+                .field("mime", mime)
+                .finish(),
+            WebSocketMessage::CancelMediaTransfer { transfer_id } => f
+                .debug_struct("CancelMediaTransfer")
+                .field("transfer_id", transfer_id)
+                .finish(),
+            WebSocketMessage::ListRooms => f.debug_struct("ListRooms").finish(),
+            WebSocketMessage::CreateRoom {
+                name, visibility, ..
+            } => f
+                .debug_struct("CreateRoom")
+                .field("name", name)
+                .field("visibility", visibility)
+                .finish(),
+            WebSocketMessage::JoinRoom { room_id, limit } => f
+                .debug_struct("JoinRoom")
+                .field("room_id", room_id)
+                .field("limit", limit)
+                .finish(),
+            WebSocketMessage::LeaveRoom { room_id } => f
+                .debug_struct("LeaveRoom")
+                .field("room_id", room_id)
+                .finish(),
+            WebSocketMessage::AddRoomMember {
+                room_id,
+                username,
+                role,
+            } => f
+                .debug_struct("AddRoomMember")
+                .field("room_id", room_id)
+                .field("username", username)
+                .field("role", role)
+                .finish(),
+            WebSocketMessage::RemoveRoomMember { room_id, username } => f
+                .debug_struct("RemoveRoomMember")
+                .field("room_id", room_id)
+                .field("username", username)
+                .finish(),
+            WebSocketMessage::GetRoomMembers { room_id } => f
+                .debug_struct("GetRoomMembers")
+                .field("room_id", room_id)
+                .finish(),
+            WebSocketMessage::PostRoomMessage {
+                room_id,
+                body,
+                blob_id,
+            } => f
+                .debug_struct("PostRoomMessage")
+                .field("room_id", room_id)
+                .field("body_len", &body.as_ref().map(|body| body.len()))
+                .field("blob_id", blob_id)
+                .finish(),
+            WebSocketMessage::GetRoomHistory {
+                room_id,
+                before,
+                limit,
+            } => f
+                .debug_struct("GetRoomHistory")
+                .field("room_id", room_id)
+                .field("before", before)
+                .field("limit", limit)
+                .finish(),
+            WebSocketMessage::DeleteRoom { room_id } => f
+                .debug_struct("DeleteRoom")
+                .field("room_id", room_id)
+                .finish(),
+            WebSocketMessage::ListBoards { room_id } => f
+                .debug_struct("ListBoards")
+                .field("room_id", room_id)
+                .finish(),
+            WebSocketMessage::CreateBoard { room_id, name } => f
+                .debug_struct("CreateBoard")
+                .field("room_id", room_id)
+                .field("name", name)
+                .finish(),
+            WebSocketMessage::OpenBoard { board_id, since } => f
+                .debug_struct("OpenBoard")
+                .field("board_id", board_id)
+                .field("since", since)
+                .finish(),
+            WebSocketMessage::CloseBoard { board_id } => f
+                .debug_struct("CloseBoard")
+                .field("board_id", board_id)
+                .finish(),
+            WebSocketMessage::ApplyBoardOp {
+                board_id,
+                client_op_id,
+                op,
+            } => f
+                .debug_struct("ApplyBoardOp")
+                .field("board_id", board_id)
+                .field("client_op_id", client_op_id)
+                .field("op", &board_operation_name(op))
+                .finish(),
+            WebSocketMessage::DeleteBoard { board_id } => f
+                .debug_struct("DeleteBoard")
+                .field("board_id", board_id)
+                .finish(),
         }
     }
 }
@@ -105,29 +671,80 @@ impl WebSocketResponse {
     }
 
     /// Create a welcome message
-    pub fn welcome(user_id: Option<Uuid>, connection_id: String) -> Self {
+    pub fn welcome(user_id: Option<Uuid>, connection_id: String, protocol_version: u32) -> Self {
         Self::Welcome {
             message: "Connected to WebSocket server".to_string(),
             user_id,
             connection_id,
+            protocol_version,
         }
     }
 
     /// Create an error response
-    pub fn error(message: impl Into<String>) -> Self {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
         Self::Error {
             message: message.into(),
-            code: None,
+            code,
         }
     }
+}
 
-    /// Create an error response with code
-    pub fn error_with_code(message: impl Into<String>, code: impl Into<String>) -> Self {
-        Self::Error {
-            message: message.into(),
-            code: Some(code.into()),
+/// A client message with the ID the client may attach to it
+///
+/// On the wire the ID sits next to `type` and `data`:
+/// `{"type": "GetMediaBlob", "data": {"id": "…"}, "request_id": 7}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientEnvelope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<RequestId>,
+    #[serde(flatten)]
+    pub message: WebSocketMessage,
+}
+
+impl ClientEnvelope {
+    /// Parse a client message and its request ID from JSON text
+    pub fn from_json(text: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(text)
+    }
+
+    /// Serialize the message and its request ID to JSON text
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+}
+
+/// A server message, echoing the request ID of the client message it
+/// answers
+///
+/// Pushes the client did not ask for, such as media events, have no
+/// request ID.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerEnvelope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<RequestId>,
+    #[serde(flatten)]
+    pub response: WebSocketResponse,
+}
+
+impl ServerEnvelope {
+    /// Wrap the response to a client message
+    pub fn reply(request_id: Option<RequestId>, response: WebSocketResponse) -> Self {
+        Self {
+            request_id,
+            response,
         }
     }
+
+    /// Serialize the response and its request ID to JSON text
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+}
+
+impl From<WebSocketResponse> for ServerEnvelope {
+    fn from(response: WebSocketResponse) -> Self {
+        Self::reply(None, response)
+    }
 }
 
 impl std::fmt::Debug for WebSocketResponse {
@@ -137,11 +754,13 @@ impl std::fmt::Debug for WebSocketResponse {
                 message,
                 user_id,
                 connection_id,
+                protocol_version,
             } => f
                 .debug_struct("Welcome")
                 .field("message", message)
                 .field("user_id", user_id)
                 .field("connection_id", connection_id)
+                .field("protocol_version", protocol_version)
                 .finish(),
             WebSocketResponse::Pong => f.debug_struct("Pong").finish(),
             WebSocketResponse::MediaBlobs { blobs, total_count } => f
@@ -149,6 +768,11 @@ impl std::fmt::Debug for WebSocketResponse {
                 .field("blob_count", &blobs.len())
                 .field("total_count", total_count)
                 .finish(),
+            WebSocketResponse::MediaSearchResults { page } => f
+                .debug_struct("MediaSearchResults")
+                .field("blob_count", &page.blobs.len())
+                .field("next_cursor", &page.next_cursor)
+                .finish(),
             WebSocketResponse::MediaBlob { blob } => f
                 .debug_struct("MediaBlob")
                 .field("blob_id", &blob.id)
@@ -162,6 +786,46 @@ impl std::fmt::Debug for WebSocketResponse {
                 .field("data_size", &data.len())
                 .field("mime", mime)
                 .finish(),
+            WebSocketResponse::MediaBlobDeleted { id } => {
+                f.debug_struct("MediaBlobDeleted").field("id", id).finish()
+            }
+            WebSocketResponse::MediaTrash { blobs } => f
+                .debug_struct("MediaTrash")
+                .field("blob_count", &blobs.len())
+                .finish(),
+            WebSocketResponse::MediaBlobPurged { id } => {
+                f.debug_struct("MediaBlobPurged").field("id", id).finish()
+            }
+            WebSocketResponse::MediaTrashEmptied { purged } => f
+                .debug_struct("MediaTrashEmptied")
+                .field("purged", purged)
+                .finish(),
+            WebSocketResponse::MediaBlobShares { id, shares } => f
+                .debug_struct("MediaBlobShares")
+                .field("id", id)
+                .field("share_count", &shares.len())
+                .finish(),
+            WebSocketResponse::MediaTags { tags } => f
+                .debug_struct("MediaTags")
+                .field("tag_count", &tags.len())
+                .finish(),
+            WebSocketResponse::MediaCollections { collections } => f
+                .debug_struct("MediaCollections")
+                .field("collection_count", &collections.len())
+                .finish(),
+            WebSocketResponse::MediaCollection {
+                collection,
+                blob_ids,
+            } => f
+                .debug_struct("MediaCollection")
+                .field("id", &collection.id)
+                .field("name", &collection.name)
+                .field("blob_count", &blob_ids.len())
+                .finish(),
+            WebSocketResponse::MediaCollectionDeleted { id } => f
+                .debug_struct("MediaCollectionDeleted")
+                .field("id", id)
+                .finish(),
             WebSocketResponse::Error { message, code } => f
                 .debug_struct("Error")
                 .field("message", message)
@@ -170,19 +834,191 @@ impl std::fmt::Debug for WebSocketResponse {
             WebSocketResponse::ConnectionStatus {
                 connected,
                 user_count,
+                connection_count,
             } => f
                 .debug_struct("ConnectionStatus")
                 .field("connected", connected)
                 .field("user_count", user_count)
+                .field("connection_count", connection_count)
+                .finish(),
+            WebSocketResponse::Presence { users } => f
+                .debug_struct("Presence")
+                .field("user_count", &users.len())
+                .finish(),
+            WebSocketResponse::PresenceChanged { kind, user } => f
+                .debug_struct("PresenceChanged")
+                .field("kind", kind)
+                .field("user_id", &user.user_id)
+                .finish(),
+            WebSocketResponse::MediaSubscribed { subscription } => f
+                .debug_struct("MediaSubscribed")
+                .field("subscription", subscription)
+                .finish(),
+            WebSocketResponse::MediaUnsubscribed => f.debug_struct("MediaUnsubscribed").finish(),
+            WebSocketResponse::MediaBlobEvent { kind, blob } => f
+                .debug_struct("MediaBlobEvent")
+                .field("kind", kind)
+                .field("id", &blob.id)
+                .finish(),
+            WebSocketResponse::MediaDownloadStarted {
+                transfer_id,
+                blob,
+                size,
+                chunk_count,
+                ..
+            } => f
+                .debug_struct("MediaDownloadStarted")
+                .field("transfer_id", transfer_id)
+                .field("id", &blob.id)
+                .field("size", size)
+                .field("chunk_count", chunk_count)
+                .finish(),
+            WebSocketResponse::MediaUploadReady {
+                transfer_id,
+                id,
+                chunk_count,
+                ..
+            } => f
+                .debug_struct("MediaUploadReady")
+                .field("transfer_id", transfer_id)
+                .field("id", id)
+                .field("chunk_count", chunk_count)
+                .finish(),
+            WebSocketResponse::MediaTransferProgress {
+                transfer_id,
+                chunk_index,
+                bytes,
+                size,
+                ..
+            } => f
+                .debug_struct("MediaTransferProgress")
+                .field("transfer_id", transfer_id)
+                .field("chunk_index", chunk_index)
+                .field("bytes", bytes)
+                .field("size", size)
+                .finish(),
+            WebSocketResponse::MediaUploadComplete { transfer_id, blob } => f
+                .debug_struct("MediaUploadComplete")
+                .field("transfer_id", transfer_id)
+                .field("id", &blob.id)
+                .finish(),
+            WebSocketResponse::MediaTransferCancelled { transfer_id } => f
+                .debug_struct("MediaTransferCancelled")
+                .field("transfer_id", transfer_id)
+                .finish(),
+            WebSocketResponse::Rooms { rooms } => f
+                .debug_struct("Rooms")
+                .field("room_count", &rooms.len())
+                .finish(),
+            WebSocketResponse::RoomCreated { room } => f
+                .debug_struct("RoomCreated")
+                .field("id", &room.id)
+                .field("name", &room.name)
+                .finish(),
+            WebSocketResponse::RoomJoined {
+                room,
+                members,
+                history,
+                online,
+            } => f
+                .debug_struct("RoomJoined")
+                .field("id", &room.id)
+                .field("member_count", &members.len())
+                .field("message_count", &history.messages.len())
+                .field("online_count", &online.len())
+                .finish(),
+            WebSocketResponse::RoomLeft { room_id } => f
+                .debug_struct("RoomLeft")
+                .field("room_id", room_id)
+                .finish(),
+            WebSocketResponse::RoomMembers { room_id, members } => f
+                .debug_struct("RoomMembers")
+                .field("room_id", room_id)
+                .field("member_count", &members.len())
+                .finish(),
+            WebSocketResponse::RoomMessage { message } => f
+                .debug_struct("RoomMessage")
+                .field("room_id", &message.room_id)
+                .field("id", &message.id)
+                .finish(),
+            WebSocketResponse::RoomHistory { history } => f
+                .debug_struct("RoomHistory")
+                .field("room_id", &history.room_id)
+                .field("message_count", &history.messages.len())
+                .field("has_more", &history.has_more)
+                .finish(),
+            WebSocketResponse::RoomMemberJoined { room_id, member } => f
+                .debug_struct("RoomMemberJoined")
+                .field("room_id", room_id)
+                .field("user_id", &member.user_id)
+                .field("role", &member.role)
+                .finish(),
+            WebSocketResponse::RoomMemberLeft { room_id, user_id } => f
+                .debug_struct("RoomMemberLeft")
+                .field("room_id", room_id)
+                .field("user_id", user_id)
+                .finish(),
+            WebSocketResponse::RoomDeleted { room_id } => f
+                .debug_struct("RoomDeleted")
+                .field("room_id", room_id)
+                .finish(),
+            WebSocketResponse::Boards { room_id, boards } => f
+                .debug_struct("Boards")
+                .field("room_id", room_id)
+                .field("board_count", &boards.len())
+                .finish(),
+            WebSocketResponse::BoardCreated { board } => f
+                .debug_struct("BoardCreated")
+                .field("id", &board.id)
+                .field("name", &board.name)
+                .finish(),
+            WebSocketResponse::BoardOpened {
+                board,
+                snapshot,
+                ops,
+            } => f
+                .debug_struct("BoardOpened")
+                .field("id", &board.id)
+                .field("seq", &board.seq)
+                .field(
+                    "snapshot_seq",
+                    &snapshot.as_ref().map(|snapshot| snapshot.seq),
+                )
+                .field("op_count", &ops.len())
+                .finish(),
+            WebSocketResponse::BoardClosed { board_id } => f
+                .debug_struct("BoardClosed")
+                .field("board_id", board_id)
+                .finish(),
+            WebSocketResponse::BoardOp { op } => f
+                .debug_struct("BoardOp")
+                .field("board_id", &op.board_id)
+                .field("seq", &op.seq)
+                .field("op", &board_operation_name(&op.op))
+                .finish(),
+            WebSocketResponse::BoardDeleted { board_id } => f
+                .debug_struct("BoardDeleted")
+                .field("board_id", board_id)
                 .finish(),
         }
     }
 }
 
+/// Name of a board operation, for logs without its possibly long props
+fn board_operation_name(op: &BoardOperation) -> &'static str {
+    match op {
+        BoardOperation::Add { .. } => "add",
+        BoardOperation::Update { .. } => "update",
+        BoardOperation::Delete { .. } => "delete",
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use time::OffsetDateTime;
 
+    use crate::media::ScanStatus;
+
     use super::*;
 
     #[test]
@@ -197,7 +1033,7 @@ mod tests {
 
     #[test]
     fn test_websocket_response_serialization() {
-        let response = WebSocketResponse::welcome(None, "test-123".to_string());
+        let response = WebSocketResponse::welcome(None, "test-123".to_string(), 1);
         let json = response.to_json().unwrap();
         assert!(json.contains("Welcome"));
         assert!(json.contains("test-123"));
@@ -212,8 +1048,12 @@ mod tests {
             size: Some(1024),
             mime: Some("image/png".to_string()),
             source_client_id: Some("client-1".to_string()),
+            owner_user_id: Some(Uuid::new_v4()),
+            visibility: MediaVisibility::Shared,
             local_path: Some("/path/to/file.png".to_string()),
             metadata: serde_json::json!({"width": 800, "height": 600}),
+            scan_status: Some(ScanStatus::Clean),
+            tags: vec!["holiday".to_string()],
             created_at: OffsetDateTime::now_utc(),
             updated_at: OffsetDateTime::now_utc(),
         };
@@ -222,5 +1062,141 @@ mod tests {
         let parsed: MediaBlob = serde_json::from_str(&json).unwrap();
         assert_eq!(blob.sha256, parsed.sha256);
         assert_eq!(blob.size, parsed.size);
+        assert_eq!(blob.owner_user_id, parsed.owner_user_id);
+        assert_eq!(parsed.visibility, MediaVisibility::Shared);
+        assert_eq!(parsed.scan_status, Some(ScanStatus::Clean));
+        assert_eq!(parsed.tags, vec!["holiday".to_string()]);
+    }
+
+    #[test]
+    fn test_get_media_blobs_filters_default() {
+        match WebSocketMessage::from_json(r#"{"type":"GetMediaBlobs","data":{"limit":10}}"#)
+            .unwrap()
+        {
+            WebSocketMessage::GetMediaBlobs {
+                limit,
+                collection_id,
+                tags,
+                ..
+            } => {
+                assert_eq!(limit, Some(10));
+                assert!(collection_id.is_none());
+                assert!(tags.is_empty());
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subscribe_media_parsing() {
+        match WebSocketMessage::from_json(
+            r#"{"type":"SubscribeMedia","data":{"mime_prefixes":["image/"]}}"#,
+        )
+        .unwrap()
+        {
+            WebSocketMessage::SubscribeMedia {
+                collection_ids,
+                mime_prefixes,
+            } => {
+                assert!(collection_ids.is_empty());
+                assert_eq!(mime_prefixes, vec!["image/".to_string()]);
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_room_message_parsing() {
+        match WebSocketMessage::from_json(r#"{"type":"CreateRoom","data":{"name":"design"}}"#)
+            .unwrap()
+        {
+            WebSocketMessage::CreateRoom {
+                name,
+                topic,
+                visibility,
+            } => {
+                assert_eq!(name, "design");
+                assert_eq!(topic, None);
+                assert_eq!(visibility, RoomVisibility::Public);
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        let room_id = Uuid::new_v4();
+        match WebSocketMessage::from_json(&format!(
+            r#"{{"type":"AddRoomMember","data":{{"room_id":"{}","username":"bob","role":"moderator"}}}}"#,
+            room_id
+        ))
+        .unwrap()
+        {
+            WebSocketMessage::AddRoomMember { role, .. } => {
+                assert_eq!(role, Some(RoomRole::Moderator))
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_set_visibility_message_parsing() {
+        let id = Uuid::new_v4();
+        let json = format!(
+            r#"{{"type":"SetMediaBlobVisibility","data":{{"id":"{}","visibility":"members"}}}}"#,
+            id
+        );
+
+        match WebSocketMessage::from_json(&json).unwrap() {
+            WebSocketMessage::SetMediaBlobVisibility {
+                id: parsed_id,
+                visibility,
+            } => {
+                assert_eq!(parsed_id, id);
+                assert_eq!(visibility, MediaVisibility::Members);
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_envelope_request_ids() {
+        let envelope =
+            ClientEnvelope::from_json(r#"{"type":"Ping","request_id":"ping-1"}"#).unwrap();
+        assert_eq!(
+            envelope.request_id,
+            Some(RequestId::String("ping-1".to_string()))
+        );
+        assert!(matches!(envelope.message, WebSocketMessage::Ping));
+
+        let id = Uuid::new_v4();
+        let envelope = ClientEnvelope::from_json(&format!(
+            r#"{{"type":"GetMediaBlob","data":{{"id":"{}"}},"request_id":42}}"#,
+            id
+        ))
+        .unwrap();
+        assert_eq!(envelope.request_id, Some(RequestId::Number(42)));
+        assert!(matches!(
+            envelope.message,
+            WebSocketMessage::GetMediaBlob { id: parsed } if parsed == id
+        ));
+
+        let plain = ClientEnvelope::from_json(r#"{"type":"Ping"}"#).unwrap();
+        assert_eq!(plain.request_id, None);
+        assert_eq!(plain.to_json().unwrap(), r#"{"type":"Ping"}"#);
+
+        let reply = ServerEnvelope::reply(
+            envelope.request_id,
+            WebSocketResponse::error(ErrorCode::NotFound, "Media blob not found"),
+        );
+        let json: serde_json::Value = serde_json::from_str(&reply.to_json().unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "type": "Error",
+                "data": {"message": "Media blob not found", "code": "not_found"},
+                "request_id": 42
+            })
+        );
+
+        let push = ServerEnvelope::reply(None, WebSocketResponse::Pong);
+        assert_eq!(push.to_json().unwrap(), r#"{"type":"Pong"}"#);
     }
 }
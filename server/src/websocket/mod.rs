@@ -3,23 +3,47 @@
 //! Provides WebSocket endpoints with authentication and message handling
 //! for media blob sharing and real-time updates.
 
+pub mod boards;
 pub mod handlers;
 pub mod messages;
+pub mod presence;
+pub mod protocol;
+pub mod rooms;
+pub mod transfer;
 
-use axum::{routing::get, Extension, Router};
-use handlers::ConnectionManager;
+use axum::{
+    middleware,
+    routing::{delete, get},
+    Extension, Router,
+};
+use handlers::{disconnect_connection, list_connections};
+
+use crate::auth::{require_admin, require_authentication};
 
 // Re-export commonly used types
 pub use crate::media::MediaBlob;
 pub use handlers::{handle_websocket_connection, websocket_handler};
-pub use messages::{WebSocketMessage, WebSocketResponse};
+pub use messages::{ClientEnvelope, ServerEnvelope, WebSocketMessage, WebSocketResponse};
+pub use presence::{ConnectionManager, OnlineUser, PresenceEventKind};
+pub use protocol::{ErrorCode, RequestId, PROTOCOL_VERSION};
 
 /// Build WebSocket routes
 pub fn build_websocket_routes() -> Router {
     // Create connection manager singleton
     let connection_manager = ConnectionManager::new();
 
+    // Live connections, and closing one (DELETE /api/admin/connections/{id})
+    let admin_routes = Router::new()
+        .route("/api/admin/connections", get(list_connections))
+        .route(
+            "/api/admin/connections/{connection_id}",
+            delete(disconnect_connection),
+        )
+        .layer(middleware::from_fn(require_admin))
+        .layer(middleware::from_fn(require_authentication));
+
     Router::new()
         .route("/ws", get(websocket_handler))
+        .merge(admin_routes)
         .layer(Extension(connection_manager))
 }
@@ -0,0 +1,414 @@
+//! Presence and tracking of live WebSocket connections
+//!
+//! The [`ConnectionManager`] knows every open connection, whose it is and
+//! when it was last used. Users count as online while they have at least
+//! one connection, however many tabs they have open. Whenever a connection
+//! opens or closes, a [`PresenceChange`] is broadcast so connections can
+//! pass the new counts on to their clients, along with a [`PresenceEvent`]
+//! when a user comes online or goes offline.
+//!
+//! The manager also carries [`RoomEvent`]s and [`BoardEvent`]s between
+//! connections, so a message posted or a stroke drawn over one reaches the
+//! members of its room on all others.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::sync::{broadcast, Notify};
+use uuid::Uuid;
+
+use crate::auth::User;
+use crate::boards::BoardEvent;
+use crate::rooms::RoomEvent;
+
+/// A user with at least one open connection
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OnlineUser {
+    pub user_id: Uuid,
+    pub username: String,
+    /// Open connections, such as one per browser tab; 0 once offline
+    pub connections: u32,
+    /// When the user's oldest open connection was made
+    #[serde(with = "time::serde::rfc3339")]
+    pub connected_at: OffsetDateTime,
+    /// When the user last sent anything over any connection
+    #[serde(with = "time::serde::rfc3339")]
+    pub last_active_at: OffsetDateTime,
+}
+
+/// Whether a user came online or went offline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceEventKind {
+    /// The user opened their first connection
+    Joined,
+    /// The user closed their last connection
+    Left,
+}
+
+/// A user coming online or going offline
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PresenceEvent {
+    pub kind: PresenceEventKind,
+    pub user: OnlineUser,
+}
+
+/// A connection opened or closed
+#[derive(Debug, Clone)]
+pub struct PresenceChange {
+    /// Set when the change took a user online or offline
+    pub event: Option<PresenceEvent>,
+    /// Users online after the change
+    pub user_count: u32,
+    /// Connections open after the change
+    pub connection_count: u32,
+}
+
+/// A live connection, as listed for admins
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LiveConnection {
+    pub connection_id: String,
+    pub user_id: Uuid,
+    pub username: String,
+    /// Client address, from proxy headers or the peer address
+    pub ip: Option<IpAddr>,
+    pub protocol_version: u32,
+    #[serde(with = "time::serde::rfc3339")]
+    pub connected_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub last_active_at: OffsetDateTime,
+}
+
+/// Connection manager to track active WebSocket connections
+///
+/// Cloning shares the connections.
+#[derive(Clone)]
+pub struct ConnectionManager {
+    connections: Arc<Mutex<HashMap<String, ConnectionInfo>>>,
+    changes: broadcast::Sender<PresenceChange>,
+    rooms: broadcast::Sender<Arc<RoomEvent>>,
+    boards: broadcast::Sender<Arc<BoardEvent>>,
+}
+
+/// Information about an active WebSocket connection
+struct ConnectionInfo {
+    user_id: Uuid,
+    username: String,
+    ip: Option<IpAddr>,
+    protocol_version: u32,
+    connected_at: OffsetDateTime,
+    last_active_at: OffsetDateTime,
+    /// Woken to make the connection close itself
+    disconnect: Arc<Notify>,
+}
+
+impl Default for ConnectionManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectionManager {
+    pub fn new() -> Self {
+        let (changes, _) = broadcast::channel(16);
+        let (rooms, _) = broadcast::channel(256);
+        // Drawing sends operations quickly, so allow for bursts
+        let (boards, _) = broadcast::channel(1024);
+        Self {
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            changes,
+            rooms,
+            boards,
+        }
+    }
+
+    /// Track a new connection
+    ///
+    /// The returned signal fires when an admin disconnects the connection.
+    pub fn add_connection(
+        &self,
+        connection_id: String,
+        user: &User,
+        ip: Option<IpAddr>,
+        protocol_version: u32,
+    ) -> Arc<Notify> {
+        let disconnect = Arc::new(Notify::new());
+        let Ok(mut connections) = self.connections.lock() else {
+            return disconnect;
+        };
+
+        let now = OffsetDateTime::now_utc();
+        connections.insert(
+            connection_id,
+            ConnectionInfo {
+                user_id: user.id,
+                username: user.username.clone(),
+                ip,
+                protocol_version,
+                connected_at: now,
+                last_active_at: now,
+                disconnect: disconnect.clone(),
+            },
+        );
+
+        let event = online_user(&connections, user.id)
+            .filter(|online| online.connections == 1)
+            .map(|user| PresenceEvent {
+                kind: PresenceEventKind::Joined,
+                user,
+            });
+        self.announce(&connections, event);
+        disconnect
+    }
+
+    /// Stop tracking a connection
+    pub fn remove_connection(&self, connection_id: &str) {
+        let Ok(mut connections) = self.connections.lock() else {
+            return;
+        };
+        let Some(user_id) = connections.get(connection_id).map(|info| info.user_id) else {
+            return;
+        };
+
+        // The user's details as of their last connection, for the event
+        let before = online_user(&connections, user_id);
+        connections.remove(connection_id);
+
+        let event = before
+            .filter(|online| online.connections == 1)
+            .map(|user| PresenceEvent {
+                kind: PresenceEventKind::Left,
+                user: OnlineUser {
+                    connections: 0,
+                    ..user
+                },
+            });
+        self.announce(&connections, event);
+    }
+
+    /// Note that a connection's client sent something
+    pub fn touch(&self, connection_id: &str) {
+        if let Ok(mut connections) = self.connections.lock() {
+            if let Some(info) = connections.get_mut(connection_id) {
+                info.last_active_at = OffsetDateTime::now_utc();
+            }
+        }
+    }
+
+    /// Ask a connection to close; false if there is no such connection
+    pub fn disconnect(&self, connection_id: &str) -> bool {
+        let Ok(connections) = self.connections.lock() else {
+            return false;
+        };
+        match connections.get(connection_id) {
+            Some(info) => {
+                info.disconnect.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Receive every change from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<PresenceChange> {
+        self.changes.subscribe()
+    }
+
+    /// Pass a room event on to every connection
+    pub fn publish_room_event(&self, event: RoomEvent) {
+        // Only fails when there are no subscribers
+        let _ = self.rooms.send(Arc::new(event));
+    }
+
+    /// Receive every room event from now on
+    pub fn subscribe_rooms(&self) -> broadcast::Receiver<Arc<RoomEvent>> {
+        self.rooms.subscribe()
+    }
+
+    /// Pass a board event on to every connection
+    pub fn publish_board_event(&self, event: BoardEvent) {
+        // Only fails when there are no subscribers
+        let _ = self.boards.send(Arc::new(event));
+    }
+
+    /// Receive every board event from now on
+    pub fn subscribe_boards(&self) -> broadcast::Receiver<Arc<BoardEvent>> {
+        self.boards.subscribe()
+    }
+
+    /// Users with at least one connection, by username
+    pub fn online_users(&self) -> Vec<OnlineUser> {
+        let Ok(connections) = self.connections.lock() else {
+            return Vec::new();
+        };
+
+        let mut user_ids: Vec<Uuid> = connections.values().map(|info| info.user_id).collect();
+        user_ids.sort();
+        user_ids.dedup();
+
+        let mut users: Vec<OnlineUser> = user_ids
+            .into_iter()
+            .filter_map(|user_id| online_user(&connections, user_id))
+            .collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        users
+    }
+
+    /// Every live connection, oldest first
+    pub fn connections(&self) -> Vec<LiveConnection> {
+        let Ok(connections) = self.connections.lock() else {
+            return Vec::new();
+        };
+
+        let mut live: Vec<LiveConnection> = connections
+            .iter()
+            .map(|(connection_id, info)| LiveConnection {
+                connection_id: connection_id.clone(),
+                user_id: info.user_id,
+                username: info.username.clone(),
+                ip: info.ip,
+                protocol_version: info.protocol_version,
+                connected_at: info.connected_at,
+                last_active_at: info.last_active_at,
+            })
+            .collect();
+        live.sort_by_key(|connection| connection.connected_at);
+        live
+    }
+
+    /// Online users and open connections
+    pub fn counts(&self) -> (u32, u32) {
+        self.connections
+            .lock()
+            .map(|connections| counts(&connections))
+            .unwrap_or((0, 0))
+    }
+
+    fn announce(
+        &self,
+        connections: &HashMap<String, ConnectionInfo>,
+        event: Option<PresenceEvent>,
+    ) {
+        let (user_count, connection_count) = counts(connections);
+        // Only fails when there are no subscribers
+        let _ = self.changes.send(PresenceChange {
+            event,
+            user_count,
+            connection_count,
+        });
+    }
+}
+
+/// A user's presence across all their connections
+fn online_user(connections: &HashMap<String, ConnectionInfo>, user_id: Uuid) -> Option<OnlineUser> {
+    connections
+        .values()
+        .filter(|info| info.user_id == user_id)
+        .fold(None, |online: Option<OnlineUser>, info| {
+            Some(match online {
+                None => OnlineUser {
+                    user_id,
+                    username: info.username.clone(),
+                    connections: 1,
+                    connected_at: info.connected_at,
+                    last_active_at: info.last_active_at,
+                },
+                Some(online) => OnlineUser {
+                    connections: online.connections + 1,
+                    connected_at: online.connected_at.min(info.connected_at),
+                    last_active_at: online.last_active_at.max(info.last_active_at),
+                    ..online
+                },
+            })
+        })
+}
+
+fn counts(connections: &HashMap<String, ConnectionInfo>) -> (u32, u32) {
+    let mut user_ids: Vec<Uuid> = connections.values().map(|info| info.user_id).collect();
+    user_ids.sort();
+    user_ids.dedup();
+    (user_ids.len() as u32, connections.len() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::UserRole;
+
+    fn user(username: &str) -> User {
+        User {
+            id: Uuid::new_v4(),
+            username: username.to_string(),
+            role: UserRole::Member,
+            created_at: OffsetDateTime::now_utc(),
+            invite_code_used: None,
+        }
+    }
+
+    #[test]
+    fn test_presence_across_tabs() {
+        let manager = ConnectionManager::new();
+        let mut changes = manager.subscribe();
+        let alice = user("alice");
+        let bob = user("bob");
+
+        manager.add_connection("a1".to_string(), &alice, None, 1);
+        let change = changes.try_recv().unwrap();
+        assert_eq!(change.event.unwrap().kind, PresenceEventKind::Joined);
+        assert_eq!((change.user_count, change.connection_count), (1, 1));
+
+        // A second tab is not a new user
+        manager.add_connection("a2".to_string(), &alice, None, 1);
+        let change = changes.try_recv().unwrap();
+        assert!(change.event.is_none());
+        assert_eq!((change.user_count, change.connection_count), (1, 2));
+
+        manager.add_connection("b1".to_string(), &bob, "10.0.0.1".parse().ok(), 1);
+        changes.try_recv().unwrap();
+
+        let online = manager.online_users();
+        assert_eq!(
+            online
+                .iter()
+                .map(|user| (user.username.as_str(), user.connections))
+                .collect::<Vec<_>>(),
+            vec![("alice", 2), ("bob", 1)]
+        );
+        assert_eq!(manager.counts(), (2, 3));
+
+        manager.remove_connection("a1");
+        assert!(changes.try_recv().unwrap().event.is_none());
+        manager.remove_connection("a2");
+        let event = changes.try_recv().unwrap().event.unwrap();
+        assert_eq!(event.kind, PresenceEventKind::Left);
+        assert_eq!((event.user.user_id, event.user.connections), (alice.id, 0));
+
+        // Removing twice changes nothing
+        manager.remove_connection("a2");
+        assert!(changes.try_recv().is_err());
+        assert_eq!(manager.counts(), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_activity_and_disconnect() {
+        let manager = ConnectionManager::new();
+        let alice = user("alice");
+        let signal = manager.add_connection("a1".to_string(), &alice, None, 1);
+
+        let before = manager.connections()[0].last_active_at;
+        manager.touch("a1");
+        let connection = &manager.connections()[0];
+        assert!(connection.last_active_at >= before);
+        assert_eq!(connection.username, "alice");
+
+        assert!(!manager.disconnect("missing"));
+        assert!(manager.disconnect("a1"));
+        // The signal is kept until the connection waits for it
+        tokio::time::timeout(std::time::Duration::from_secs(1), signal.notified())
+            .await
+            .unwrap();
+    }
+}
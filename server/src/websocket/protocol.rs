@@ -0,0 +1,237 @@
+//! WebSocket protocol versioning, request correlation and error codes
+//!
+//! Clients choose a protocol version with the `protocol_version` query
+//! parameter of `/ws`, and the server confirms the version it speaks in the
+//! `Welcome` message. Every client message may carry a `request_id`, which
+//! the server echoes on the response to that message, and every `Error`
+//! response carries an [`ErrorCode`] clients can match on.
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::WebauthnError;
+use crate::media::MediaError;
+
+/// Newest protocol version the server speaks
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version the server still speaks
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Pick the protocol version for a connection
+///
+/// Clients name the newest version they speak, or nothing for the server's
+/// newest. The connection uses the newest version both sides speak, and
+/// fails when the client only speaks versions older than the server's
+/// oldest.
+pub fn negotiate_protocol_version(requested: Option<u32>) -> Option<u32> {
+    let version = requested.map_or(PROTOCOL_VERSION, |v| v.min(PROTOCOL_VERSION));
+    (version >= MIN_PROTOCOL_VERSION).then_some(version)
+}
+
+/// ID chosen by a client to match responses to its messages
+///
+/// Numbers and strings are both accepted and echoed back as they came.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RequestId {
+    Number(u64),
+    String(String),
+}
+
+impl RequestId {
+    /// Find the request ID of a message that could not be parsed otherwise,
+    /// so the error can still be matched to it
+    pub fn find(text: &str) -> Option<Self> {
+        #[derive(Deserialize)]
+        struct Probe {
+            request_id: Option<RequestId>,
+        }
+
+        serde_json::from_str::<Probe>(text).ok()?.request_id
+    }
+}
+
+/// Machine-readable reason for an `Error` response
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The message is not valid JSON or not a known message
+    InvalidMessage,
+    /// The client asked for a protocol version the server does not speak
+    UnsupportedProtocolVersion,
+    /// The message is well-formed but its contents are not acceptable
+    BadRequest,
+    /// The blob, collection, user or transfer does not exist, or the
+    /// client may not see it
+    NotFound,
+    /// The client may see the blob but not manage it
+    Forbidden,
+    /// The change clashes with existing data, such as a taken name
+    Conflict,
+    /// The upload would take the owner over their quota
+    QuotaExceeded,
+    /// The content type is not allowed or does not match the content
+    UnsupportedMediaType,
+    /// The blob is waiting for or failed a malware scan
+    Quarantined,
+    /// An upload without data named content the server does not have
+    ContentMissing,
+    /// The search query could not be understood
+    InvalidSearch,
+    /// The connection fell behind and missed media events
+    EventsLagged,
+    /// A binary frame was malformed, out of order or too large
+    InvalidFrame,
+    /// No running transfer has the given transfer ID
+    UnknownTransfer,
+    /// The transfer could not start, as its transfer ID is in use or too
+    /// many transfers are running
+    TransferRefused,
+    /// Uploaded content does not match its declared size and SHA256
+    HashMismatch,
+    /// Reading or staging transferred content failed
+    TransferFailed,
+    /// Something went wrong on the server
+    Internal,
+}
+
+impl ErrorCode {
+    /// The code as sent on the wire
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidMessage => "invalid_message",
+            ErrorCode::UnsupportedProtocolVersion => "unsupported_protocol_version",
+            ErrorCode::BadRequest => "bad_request",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::Conflict => "conflict",
+            ErrorCode::QuotaExceeded => "quota_exceeded",
+            ErrorCode::UnsupportedMediaType => "unsupported_media_type",
+            ErrorCode::Quarantined => "quarantined",
+            ErrorCode::ContentMissing => "content_missing",
+            ErrorCode::InvalidSearch => "invalid_search",
+            ErrorCode::EventsLagged => "events_lagged",
+            ErrorCode::InvalidFrame => "invalid_frame",
+            ErrorCode::UnknownTransfer => "unknown_transfer",
+            ErrorCode::TransferRefused => "transfer_refused",
+            ErrorCode::HashMismatch => "hash_mismatch",
+            ErrorCode::TransferFailed => "transfer_failed",
+            ErrorCode::Internal => "internal",
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<&WebauthnError> for ErrorCode {
+    fn from(err: &WebauthnError) -> Self {
+        match err {
+            WebauthnError::UserNotFound => ErrorCode::NotFound,
+            WebauthnError::Forbidden => ErrorCode::Forbidden,
+            WebauthnError::UserAlreadyExists => ErrorCode::Conflict,
+            WebauthnError::BadRequest
+            | WebauthnError::CorruptSession
+            | WebauthnError::UserHasNoCredentials
+            | WebauthnError::InvalidInviteCode
+            | WebauthnError::InvalidSessionState(_) => ErrorCode::BadRequest,
+            WebauthnError::MediaContentMissing => ErrorCode::ContentMissing,
+            WebauthnError::MediaContentType(_) => ErrorCode::UnsupportedMediaType,
+            WebauthnError::MediaQuarantined(_) => ErrorCode::Quarantined,
+            WebauthnError::MediaQuota(_) => ErrorCode::QuotaExceeded,
+            WebauthnError::MediaSearch(_) => ErrorCode::InvalidSearch,
+            WebauthnError::Unknown
+            | WebauthnError::DatabaseError
+            | WebauthnError::MediaStorage(_)
+            | WebauthnError::MediaScan(_)
+            | WebauthnError::SqlxError(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+impl From<&MediaError> for ErrorCode {
+    fn from(err: &MediaError) -> Self {
+        match err {
+            MediaError::NotFound => ErrorCode::NotFound,
+            MediaError::Forbidden => ErrorCode::Forbidden,
+            MediaError::InvalidHash | MediaError::Validation(_) => ErrorCode::BadRequest,
+            MediaError::Duplicate | MediaError::DuplicateCollection => ErrorCode::Conflict,
+            MediaError::Database(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::{ScanStatus, SearchError};
+
+    #[test]
+    fn test_negotiate_protocol_version() {
+        assert_eq!(negotiate_protocol_version(None), Some(PROTOCOL_VERSION));
+        assert_eq!(
+            negotiate_protocol_version(Some(PROTOCOL_VERSION + 1)),
+            Some(PROTOCOL_VERSION)
+        );
+        assert_eq!(
+            negotiate_protocol_version(Some(MIN_PROTOCOL_VERSION)),
+            Some(MIN_PROTOCOL_VERSION)
+        );
+        assert_eq!(negotiate_protocol_version(Some(0)), None);
+    }
+
+    #[test]
+    fn test_request_id() {
+        assert_eq!(
+            RequestId::find(r#"{"type":"Nope","request_id":7}"#),
+            Some(RequestId::Number(7))
+        );
+        assert_eq!(
+            RequestId::find(r#"{"request_id":"a-1","data":[}"#),
+            None,
+            "invalid JSON has no request ID"
+        );
+        assert_eq!(
+            RequestId::find(r#"{"type":"Ping","request_id":"a-1"}"#),
+            Some(RequestId::String("a-1".to_string()))
+        );
+        assert_eq!(RequestId::find(r#"{"type":"Ping"}"#), None);
+    }
+
+    #[test]
+    fn test_error_codes() {
+        for code in [
+            ErrorCode::InvalidMessage,
+            ErrorCode::UnsupportedProtocolVersion,
+        ] {
+            assert_eq!(
+                serde_json::to_value(code).unwrap(),
+                serde_json::json!(code.as_str())
+            );
+        }
+
+        assert_eq!(
+            ErrorCode::from(&WebauthnError::UserNotFound),
+            ErrorCode::NotFound
+        );
+        assert_eq!(
+            ErrorCode::from(&WebauthnError::MediaQuarantined(ScanStatus::Pending)),
+            ErrorCode::Quarantined
+        );
+        assert_eq!(
+            ErrorCode::from(&WebauthnError::MediaSearch(SearchError::InvalidCursor)),
+            ErrorCode::InvalidSearch
+        );
+        assert_eq!(
+            ErrorCode::from(&WebauthnError::DatabaseError),
+            ErrorCode::Internal
+        );
+        assert_eq!(
+            ErrorCode::from(&MediaError::DuplicateCollection),
+            ErrorCode::Conflict
+        );
+    }
+}
@@ -0,0 +1,465 @@
+//! Room messages over the WebSocket
+//!
+//! Requests are answered directly to the connection that sent them. The
+//! change each one makes is also published as a [`RoomEvent`] through the
+//! [`ConnectionManager`], so that every other connection of the room's
+//! members, including the sender's other tabs, is told about it.
+//!
+//! Each connection keeps the IDs of the rooms its user is in. Events about
+//! the user's own membership update that set, so a tab starts or stops
+//! receiving a room's messages as soon as another tab joins or leaves it.
+
+use std::collections::HashSet;
+
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::auth::{AuthRepository, User};
+use crate::database::DatabaseConnection;
+use crate::error::WebauthnError;
+use crate::media::{MediaRepository, MediaService, MediaStorage};
+use crate::rooms::{
+    Room, RoomEvent, RoomEventKind, RoomMessage, RoomRepository, RoomRole, RoomService,
+};
+use crate::websocket::messages::{WebSocketMessage, WebSocketResponse};
+use crate::websocket::presence::ConnectionManager;
+use crate::websocket::protocol::ErrorCode;
+
+/// The rooms a connection's user is a member of
+#[derive(Debug, Default)]
+pub struct JoinedRooms(HashSet<Uuid>);
+
+impl JoinedRooms {
+    /// Load the rooms a user is in when their connection opens
+    pub async fn load(
+        db: &DatabaseConnection,
+        storage: &MediaStorage,
+        user: &User,
+    ) -> Result<Self, WebauthnError> {
+        let ids = room_service(db, storage).joined_ids(user).await?;
+        Ok(Self(ids.into_iter().collect()))
+    }
+
+    /// Whether the user is in a room
+    pub fn contains(&self, room_id: Uuid) -> bool {
+        self.0.contains(&room_id)
+    }
+}
+
+/// Connection handling a room message
+pub struct RoomConnection<'a> {
+    pub connection_id: &'a str,
+    pub user: &'a User,
+    pub rooms: &'a mut JoinedRooms,
+    pub connection_manager: &'a ConnectionManager,
+}
+
+impl RoomConnection<'_> {
+    /// Tell the other connections about a change this one made
+    fn publish(&self, room_id: Uuid, kind: RoomEventKind) {
+        self.connection_manager.publish_room_event(RoomEvent {
+            room_id,
+            origin: Some(self.connection_id.to_string()),
+            kind,
+        });
+    }
+}
+
+fn room_service<'a>(db: &'a DatabaseConnection, storage: &'a MediaStorage) -> RoomService<'a> {
+    RoomService::new(
+        RoomRepository::new(db),
+        MediaService::new(MediaRepository::new(db), storage),
+    )
+}
+
+/// Handle a room message; `None` for messages about anything else
+pub async fn handle_room_message(
+    message: &WebSocketMessage,
+    connection: RoomConnection<'_>,
+    db: &DatabaseConnection,
+    storage: &MediaStorage,
+) -> Option<WebSocketResponse> {
+    let user = connection.user;
+    let service = room_service(db, storage);
+
+    let response = match message {
+        WebSocketMessage::ListRooms => match service.list(user).await {
+            Ok(rooms) => WebSocketResponse::Rooms { rooms },
+            Err(e) => room_error(e, "Failed to list rooms"),
+        },
+        WebSocketMessage::CreateRoom {
+            name,
+            topic,
+            visibility,
+        } => {
+            if let Err(message) = Room::normalize_name(name) {
+                return Some(WebSocketResponse::error(ErrorCode::BadRequest, message));
+            }
+            if let Some(Err(message)) = topic.as_deref().map(Room::normalize_topic) {
+                return Some(WebSocketResponse::error(ErrorCode::BadRequest, message));
+            }
+
+            match service
+                .create(name, topic.as_deref(), *visibility, user)
+                .await
+            {
+                Ok(room) => {
+                    info!("User {} created room {} ({})", user.id, room.id, room.name);
+                    connection.rooms.0.insert(room.id);
+                    if let Ok(Some(owner)) = RoomRepository::new(db).member(room.id, user.id).await
+                    {
+                        connection.publish(room.id, RoomEventKind::MemberJoined(owner));
+                    }
+                    WebSocketResponse::RoomCreated { room }
+                }
+                Err(WebauthnError::BadRequest) => WebSocketResponse::error(
+                    ErrorCode::Conflict,
+                    "A room with this name already exists",
+                ),
+                Err(e) => room_error(e, "Failed to create room"),
+            }
+        }
+        WebSocketMessage::JoinRoom { room_id, limit } => {
+            let (room, member, newly) = match service.join(*room_id, user).await {
+                Ok(joined) => joined,
+                Err(e) => return Some(room_error(e, "Failed to join room")),
+            };
+            connection.rooms.0.insert(room.id);
+            if newly {
+                connection.publish(room.id, RoomEventKind::MemberJoined(member));
+            }
+
+            let members = match service.members(room.id, user).await {
+                Ok(members) => members,
+                Err(e) => return Some(room_error(e, "Failed to load room members")),
+            };
+            let history = match service.history(room.id, None, *limit, user).await {
+                Ok(history) => history,
+                Err(e) => return Some(room_error(e, "Failed to load room history")),
+            };
+            let online_ids: HashSet<Uuid> = connection
+                .connection_manager
+                .online_users()
+                .into_iter()
+                .map(|online| online.user_id)
+                .collect();
+            let online = members
+                .iter()
+                .map(|member| member.user_id)
+                .filter(|user_id| online_ids.contains(user_id))
+                .collect();
+
+            WebSocketResponse::RoomJoined {
+                room,
+                members,
+                history,
+                online,
+            }
+        }
+        WebSocketMessage::LeaveRoom { room_id } => match service.leave(*room_id, user).await {
+            Ok(left) => {
+                connection.rooms.0.remove(room_id);
+                if left {
+                    connection.publish(*room_id, RoomEventKind::MemberLeft { user_id: user.id });
+                }
+                WebSocketResponse::RoomLeft { room_id: *room_id }
+            }
+            Err(WebauthnError::BadRequest) => WebSocketResponse::error(
+                ErrorCode::BadRequest,
+                "Owners cannot leave their room; delete it instead",
+            ),
+            Err(e) => room_error(e, "Failed to leave room"),
+        },
+        WebSocketMessage::AddRoomMember {
+            room_id,
+            username,
+            role,
+        } => {
+            let role = role.unwrap_or(RoomRole::Member);
+            if role == RoomRole::Owner {
+                return Some(WebSocketResponse::error(
+                    ErrorCode::BadRequest,
+                    "A room has only one owner",
+                ));
+            }
+            let target = match find_user(db, username).await {
+                Ok(target) => target,
+                Err(response) => return Some(response),
+            };
+
+            match service.add_member(*room_id, target.id, role, user).await {
+                Ok((member, changed)) => {
+                    if changed {
+                        connection.publish(*room_id, RoomEventKind::MemberJoined(member.clone()));
+                    }
+                    WebSocketResponse::RoomMemberJoined {
+                        room_id: *room_id,
+                        member,
+                    }
+                }
+                Err(WebauthnError::BadRequest) => WebSocketResponse::error(
+                    ErrorCode::BadRequest,
+                    "The owner's role cannot change",
+                ),
+                Err(e) => room_error(e, "Failed to add room member"),
+            }
+        }
+        WebSocketMessage::RemoveRoomMember { room_id, username } => {
+            let target = match find_user(db, username).await {
+                Ok(target) => target,
+                Err(response) => return Some(response),
+            };
+
+            match service.remove_member(*room_id, target.id, user).await {
+                Ok(removed) => {
+                    if target.id == user.id {
+                        connection.rooms.0.remove(room_id);
+                    }
+                    if removed {
+                        connection
+                            .publish(*room_id, RoomEventKind::MemberLeft { user_id: target.id });
+                    }
+                    WebSocketResponse::RoomMemberLeft {
+                        room_id: *room_id,
+                        user_id: target.id,
+                    }
+                }
+                Err(WebauthnError::BadRequest) => WebSocketResponse::error(
+                    ErrorCode::BadRequest,
+                    "The owner cannot be removed; delete the room instead",
+                ),
+                Err(e) => room_error(e, "Failed to remove room member"),
+            }
+        }
+        WebSocketMessage::GetRoomMembers { room_id } => {
+            match service.members(*room_id, user).await {
+                Ok(members) => WebSocketResponse::RoomMembers {
+                    room_id: *room_id,
+                    members,
+                },
+                Err(e) => room_error(e, "Failed to load room members"),
+            }
+        }
+        WebSocketMessage::PostRoomMessage {
+            room_id,
+            body,
+            blob_id,
+        } => {
+            match RoomMessage::normalize_body(body.as_deref()) {
+                Err(message) => {
+                    return Some(WebSocketResponse::error(ErrorCode::BadRequest, message))
+                }
+                Ok(None) if blob_id.is_none() => {
+                    return Some(WebSocketResponse::error(
+                        ErrorCode::BadRequest,
+                        "Messages need text or a media blob",
+                    ))
+                }
+                Ok(_) => {}
+            }
+
+            match service
+                .post(*room_id, body.as_deref(), *blob_id, user)
+                .await
+            {
+                Ok(posted) => {
+                    let message = posted.message.clone();
+                    connection.publish(
+                        *room_id,
+                        RoomEventKind::Message {
+                            message: Box::new(posted.message),
+                            blob_shared_with: posted.blob_shared_with,
+                        },
+                    );
+                    WebSocketResponse::RoomMessage { message }
+                }
+                Err(WebauthnError::UserNotFound) => {
+                    WebSocketResponse::error(ErrorCode::NotFound, "Room or media blob not found")
+                }
+                Err(e) => room_error(e, "Failed to post message"),
+            }
+        }
+        WebSocketMessage::GetRoomHistory {
+            room_id,
+            before,
+            limit,
+        } => match service.history(*room_id, *before, *limit, user).await {
+            Ok(history) => WebSocketResponse::RoomHistory { history },
+            Err(e) => room_error(e, "Failed to load room history"),
+        },
+        WebSocketMessage::DeleteRoom { room_id } => match service.delete(*room_id, user).await {
+            Ok(()) => {
+                info!("User {} deleted room {}", user.id, room_id);
+                connection.rooms.0.remove(room_id);
+                connection.publish(*room_id, RoomEventKind::Deleted);
+                WebSocketResponse::RoomDeleted { room_id: *room_id }
+            }
+            Err(e) => room_error(e, "Failed to delete room"),
+        },
+        _ => return None,
+    };
+
+    Some(response)
+}
+
+/// Build the push for a room event another connection caused, if this
+/// connection's user is in the room
+///
+/// Joining and leaving update the connection's rooms: users are told about
+/// being added to a room they were not in, and about being removed from
+/// one, and stop hearing from deleted rooms.
+pub fn room_event_response(
+    event: &RoomEvent,
+    connection_id: &str,
+    user: &User,
+    rooms: &mut JoinedRooms,
+) -> Option<WebSocketResponse> {
+    if event.origin.as_deref() == Some(connection_id) {
+        return None;
+    }
+    if matches!(event.kind, RoomEventKind::MemberJoined(_)) && event.concerns(user.id) {
+        rooms.0.insert(event.room_id);
+    }
+    if !rooms.contains(event.room_id) {
+        return None;
+    }
+
+    let room_id = event.room_id;
+    Some(match &event.kind {
+        RoomEventKind::Message {
+            message,
+            blob_shared_with,
+        } => WebSocketResponse::RoomMessage {
+            message: message.for_reader(blob_shared_with, user),
+        },
+        RoomEventKind::MemberJoined(member) => WebSocketResponse::RoomMemberJoined {
+            room_id,
+            member: member.clone(),
+        },
+        RoomEventKind::MemberLeft { user_id } => {
+            if *user_id == user.id {
+                rooms.0.remove(&room_id);
+            }
+            WebSocketResponse::RoomMemberLeft {
+                room_id,
+                user_id: *user_id,
+            }
+        }
+        RoomEventKind::Deleted => {
+            rooms.0.remove(&room_id);
+            WebSocketResponse::RoomDeleted { room_id }
+        }
+    })
+}
+
+/// Look up the user a room member request names
+async fn find_user(db: &DatabaseConnection, username: &str) -> Result<User, WebSocketResponse> {
+    match AuthRepository::new(db).get_user_by_username(username).await {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(WebSocketResponse::error(
+            ErrorCode::NotFound,
+            format!("User not found: {}", username),
+        )),
+        Err(e) => {
+            error!("Failed to look up user {}: {}", username, e);
+            Err(WebSocketResponse::error(
+                ErrorCode::Internal,
+                "Failed to look up user",
+            ))
+        }
+    }
+}
+
+fn room_error(err: WebauthnError, context: &str) -> WebSocketResponse {
+    match err {
+        WebauthnError::UserNotFound => {
+            WebSocketResponse::error(ErrorCode::NotFound, "Room not found")
+        }
+        WebauthnError::Forbidden => WebSocketResponse::error(
+            ErrorCode::Forbidden,
+            "Your role in this room does not allow that",
+        ),
+        e => {
+            error!("{}: {}", context, e);
+            WebSocketResponse::error(ErrorCode::from(&e), context)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::UserRole;
+    use crate::rooms::RoomMember;
+    use time::OffsetDateTime;
+
+    fn user(username: &str) -> User {
+        User {
+            id: Uuid::new_v4(),
+            username: username.to_string(),
+            role: UserRole::Member,
+            created_at: OffsetDateTime::now_utc(),
+            invite_code_used: None,
+        }
+    }
+
+    fn event(room_id: Uuid, origin: &str, kind: RoomEventKind) -> RoomEvent {
+        RoomEvent {
+            room_id,
+            origin: Some(origin.to_string()),
+            kind,
+        }
+    }
+
+    #[test]
+    fn test_room_events_follow_membership() {
+        let alice = user("alice");
+        let room_id = Uuid::new_v4();
+        let mut rooms = JoinedRooms::default();
+        let left = RoomEventKind::MemberLeft { user_id: alice.id };
+
+        // Not in the room yet
+        assert!(
+            room_event_response(&event(room_id, "b", left.clone()), "a", &alice, &mut rooms)
+                .is_none()
+        );
+
+        // Added from another connection
+        let joined = RoomEventKind::MemberJoined(RoomMember {
+            room_id,
+            user_id: alice.id,
+            username: alice.username.clone(),
+            role: RoomRole::Member,
+            joined_at: OffsetDateTime::now_utc(),
+        });
+        assert!(matches!(
+            room_event_response(
+                &event(room_id, "b", joined.clone()),
+                "a",
+                &alice,
+                &mut rooms
+            ),
+            Some(WebSocketResponse::RoomMemberJoined { .. })
+        ));
+        assert!(rooms.contains(room_id));
+
+        // Events this connection caused were answered directly
+        assert!(
+            room_event_response(&event(room_id, "a", joined), "a", &alice, &mut rooms).is_none()
+        );
+
+        // Removed: told once, then nothing more from the room
+        assert!(matches!(
+            room_event_response(&event(room_id, "b", left), "a", &alice, &mut rooms),
+            Some(WebSocketResponse::RoomMemberLeft { .. })
+        ));
+        assert!(!rooms.contains(room_id));
+        assert!(room_event_response(
+            &event(room_id, "b", RoomEventKind::Deleted),
+            "a",
+            &alice,
+            &mut rooms
+        )
+        .is_none());
+    }
+}
@@ -0,0 +1,940 @@
+//! Chunked transfer of blob content in binary WebSocket frames
+//!
+//! JSON messages start and steer transfers; the content itself travels in
+//! binary frames made of a fixed header followed by the raw bytes of one
+//! chunk. All header fields are big-endian:
+//!
+//! | Offset | Size | Field |
+//! | ------ | ---- | ----- |
+//! | 0 | 1 | Frame format version, currently 1 |
+//! | 1 | 1 | Kind: 1 for an upload chunk, 2 for a download chunk |
+//! | 2 | 4 | Transfer ID chosen by the client when starting the transfer |
+//! | 6 | 16 | Blob ID for downloads, upload ID for uploads |
+//! | 22 | 4 | Chunk index, counting from 0 |
+//!
+//! Chunks are sent in order. Downloads are flow controlled by the client,
+//! which acknowledges chunks; the server never has more than the window of
+//! chunks unacknowledged. Uploads are acknowledged by the server with a
+//! progress message per chunk, and clients keep at most the window of
+//! chunks unacknowledged.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+
+use futures_util::TryStreamExt;
+use tokio::io::AsyncReadExt;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::auth::User;
+use crate::config::AppConfig;
+use crate::database::DatabaseConnection;
+use crate::error::WebauthnError;
+use crate::media::{
+    BlobStorageError, ContentStream, CreateMediaBlob, MediaBlob, MediaContent, MediaEvents,
+    MediaRepository, MediaService, MediaStorage, MediaVisibility, StagedFile, StagingError,
+};
+use crate::upload::handlers::upload_limits;
+use crate::websocket::messages::WebSocketResponse;
+use crate::websocket::protocol::ErrorCode;
+
+/// Version of the binary frame format
+pub const FRAME_VERSION: u8 = 1;
+
+/// Length of the binary frame header
+pub const HEADER_LEN: usize = 26;
+
+/// Chunk size used when the client does not ask for one
+pub const DEFAULT_CHUNK_SIZE: u32 = 256 * 1024;
+
+/// Smallest chunk size a client may ask for
+pub const MIN_CHUNK_SIZE: u32 = 4 * 1024;
+
+/// Largest chunk size a client may ask for
+pub const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Unacknowledged chunks allowed when the client does not ask for a window
+pub const DEFAULT_WINDOW: u32 = 8;
+
+/// Largest window a client may ask for
+pub const MAX_WINDOW: u32 = 64;
+
+/// Transfers a single connection may have running at once
+pub const MAX_TRANSFERS: usize = 8;
+
+/// What a binary frame carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// Client to server
+    UploadChunk = 1,
+    /// Server to client
+    DownloadChunk = 2,
+}
+
+/// A binary frame carrying one chunk of blob content
+#[derive(Debug, PartialEq, Eq)]
+pub struct ChunkFrame<'a> {
+    pub kind: FrameKind,
+    pub transfer_id: u32,
+    pub id: Uuid,
+    pub chunk_index: u32,
+    pub payload: &'a [u8],
+}
+
+impl<'a> ChunkFrame<'a> {
+    /// Serialize the frame, header first
+    pub fn encode(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        frame.push(FRAME_VERSION);
+        frame.push(self.kind as u8);
+        frame.extend_from_slice(&self.transfer_id.to_be_bytes());
+        frame.extend_from_slice(self.id.as_bytes());
+        frame.extend_from_slice(&self.chunk_index.to_be_bytes());
+        frame.extend_from_slice(self.payload);
+        frame
+    }
+
+    /// Parse a frame, borrowing its payload
+    pub fn decode(frame: &'a [u8]) -> Result<Self, TransferError> {
+        if frame.len() < HEADER_LEN {
+            return Err(TransferError::Frame(format!(
+                "frame is shorter than its {} byte header",
+                HEADER_LEN
+            )));
+        }
+        if frame[0] != FRAME_VERSION {
+            return Err(TransferError::Frame(format!(
+                "unsupported frame version {}",
+                frame[0]
+            )));
+        }
+        let kind = match frame[1] {
+            1 => FrameKind::UploadChunk,
+            2 => FrameKind::DownloadChunk,
+            other => {
+                return Err(TransferError::Frame(format!(
+                    "unknown frame kind {}",
+                    other
+                )))
+            }
+        };
+
+        let u32_at = |at: usize| u32::from_be_bytes(frame[at..at + 4].try_into().unwrap());
+        Ok(Self {
+            kind,
+            transfer_id: u32_at(2),
+            id: Uuid::from_slice(&frame[6..22]).unwrap(),
+            chunk_index: u32_at(22),
+            payload: &frame[HEADER_LEN..],
+        })
+    }
+}
+
+/// Why a transfer could not start or continue
+#[derive(Debug, thiserror::Error)]
+pub enum TransferError {
+    #[error("Invalid frame: {0}")]
+    Frame(String),
+    #[error("No transfer with ID {0}")]
+    Unknown(u32),
+    #[error("Transfer ID {0} is already in use by another transfer")]
+    InUse(u32),
+    #[error("At most {MAX_TRANSFERS} transfers may run at once")]
+    TooMany,
+    #[error("Expected chunk {expected}, received chunk {received}")]
+    OutOfOrder { expected: u32, received: u32 },
+    #[error("Chunk is larger than the agreed {0} bytes")]
+    ChunkTooLarge(u32),
+    #[error("Content does not match the declared size and SHA256")]
+    Mismatch,
+    #[error("Failed to read media content: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to read media content: {0}")]
+    Storage(#[from] BlobStorageError),
+    #[error("Failed to stage upload: {0}")]
+    Staging(#[from] StagingError),
+}
+
+impl TransferError {
+    /// Error code reported to the client
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            TransferError::Frame(_)
+            | TransferError::OutOfOrder { .. }
+            | TransferError::ChunkTooLarge(_) => ErrorCode::InvalidFrame,
+            TransferError::Unknown(_) => ErrorCode::UnknownTransfer,
+            TransferError::InUse(_) | TransferError::TooMany => ErrorCode::TransferRefused,
+            TransferError::Mismatch => ErrorCode::HashMismatch,
+            TransferError::Io(_) | TransferError::Storage(_) | TransferError::Staging(_) => {
+                ErrorCode::TransferFailed
+            }
+        }
+    }
+
+    /// The error response for the client
+    pub fn to_response(&self) -> WebSocketResponse {
+        WebSocketResponse::error(self.code(), self.to_string())
+    }
+}
+
+/// Where download chunks are read from
+enum DownloadSource {
+    File(tokio::fs::File),
+    /// Content streamed from a storage backend, with what was received
+    /// beyond the last chunk
+    Stream(ContentStream, Vec<u8>),
+}
+
+/// A blob being sent to the client
+struct Download {
+    id: Uuid,
+    source: DownloadSource,
+    size: u64,
+    chunk_size: u32,
+    chunk_count: u32,
+    window: u32,
+    /// Chunks sent so far
+    sent: u32,
+    /// Chunks the client has acknowledged
+    acknowledged: u32,
+}
+
+impl Download {
+    /// Read the next chunk, which is never empty
+    async fn read_chunk(&mut self) -> Result<Vec<u8>, TransferError> {
+        let offset = self.sent as u64 * self.chunk_size as u64;
+        let len = (self.size - offset).min(self.chunk_size as u64) as usize;
+
+        match &mut self.source {
+            DownloadSource::File(file) => {
+                let mut chunk = vec![0u8; len];
+                file.read_exact(&mut chunk).await?;
+                Ok(chunk)
+            }
+            DownloadSource::Stream(stream, pending) => {
+                while pending.len() < len {
+                    match stream.try_next().await? {
+                        Some(bytes) => pending.extend_from_slice(&bytes),
+                        None => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+                    }
+                }
+                let rest = pending.split_off(len);
+                Ok(std::mem::replace(pending, rest))
+            }
+        }
+    }
+}
+
+/// A blob being received from the client
+struct Upload {
+    id: Uuid,
+    params: CreateMediaBlob,
+    staged: StagedFile,
+    size: u64,
+    chunk_size: u32,
+    /// Chunks received so far
+    received: u32,
+}
+
+/// Progress after receiving an upload chunk
+pub enum UploadStep {
+    /// More chunks are expected
+    Progress(UploadProgress),
+    /// All content arrived and is ready to be stored
+    Complete(Box<FinishedUpload>),
+}
+
+/// How far an upload has come, acknowledging its latest chunk
+pub struct UploadProgress {
+    pub transfer_id: u32,
+    pub id: Uuid,
+    pub chunk_index: u32,
+    pub bytes: u64,
+    pub size: u64,
+}
+
+impl UploadProgress {
+    /// The progress message for the client
+    pub fn to_response(&self) -> WebSocketResponse {
+        WebSocketResponse::MediaTransferProgress {
+            transfer_id: self.transfer_id,
+            id: self.id,
+            chunk_index: self.chunk_index,
+            bytes: self.bytes,
+            size: self.size,
+        }
+    }
+}
+
+/// An upload whose content has fully arrived
+pub struct FinishedUpload {
+    pub transfer_id: u32,
+    pub params: CreateMediaBlob,
+    pub staged: StagedFile,
+}
+
+/// The transfers of one WebSocket connection
+#[derive(Default)]
+pub struct Transfers {
+    downloads: HashMap<u32, Download>,
+    uploads: HashMap<u32, Upload>,
+}
+
+impl Transfers {
+    /// Check that another transfer may start under `transfer_id`
+    pub fn check_available(&self, transfer_id: u32) -> Result<(), TransferError> {
+        if self.downloads.contains_key(&transfer_id) || self.uploads.contains_key(&transfer_id) {
+            return Err(TransferError::InUse(transfer_id));
+        }
+        if self.downloads.len() + self.uploads.len() >= MAX_TRANSFERS {
+            return Err(TransferError::TooMany);
+        }
+        Ok(())
+    }
+
+    /// Start sending a blob's content; chunks follow from [`next_frames`](Self::next_frames)
+    pub async fn start_download(
+        &mut self,
+        transfer_id: u32,
+        blob: MediaBlob,
+        content: MediaContent,
+        chunk_size: Option<u32>,
+        window: Option<u32>,
+    ) -> Result<WebSocketResponse, TransferError> {
+        self.check_available(transfer_id)?;
+
+        let (source, size) = match content {
+            MediaContent::File(path) => {
+                let file = tokio::fs::File::open(&path).await?;
+                let size = file.metadata().await?.len();
+                (DownloadSource::File(file), size)
+            }
+            MediaContent::Stored(content) => {
+                let size = content.size();
+                let stream = content.open(0, size).await?;
+                (DownloadSource::Stream(stream, Vec::new()), size)
+            }
+        };
+        let chunk_size = negotiate_chunk_size(chunk_size);
+        let window = negotiate_window(window);
+        let chunk_count = size.div_ceil(chunk_size as u64) as u32;
+
+        if chunk_count > 0 {
+            self.downloads.insert(
+                transfer_id,
+                Download {
+                    id: blob.id,
+                    source,
+                    size,
+                    chunk_size,
+                    chunk_count,
+                    window,
+                    sent: 0,
+                    acknowledged: 0,
+                },
+            );
+        }
+
+        Ok(WebSocketResponse::MediaDownloadStarted {
+            transfer_id,
+            blob,
+            size,
+            chunk_size,
+            chunk_count,
+            window,
+        })
+    }
+
+    /// Record that the client has received every chunk up to `chunk_index`
+    ///
+    /// A download ends once its last chunk is acknowledged.
+    pub fn acknowledge(&mut self, transfer_id: u32, chunk_index: u32) -> Result<(), TransferError> {
+        let download = self
+            .downloads
+            .get_mut(&transfer_id)
+            .ok_or(TransferError::Unknown(transfer_id))?;
+
+        if chunk_index >= download.sent {
+            return Err(TransferError::OutOfOrder {
+                expected: download.sent.saturating_sub(1),
+                received: chunk_index,
+            });
+        }
+        download.acknowledged = download.acknowledged.max(chunk_index + 1);
+
+        if download.acknowledged == download.chunk_count {
+            self.downloads.remove(&transfer_id);
+        }
+        Ok(())
+    }
+
+    /// Download chunks the windows allow to be sent now, as encoded frames
+    ///
+    /// Downloads that fail to read are dropped and reported instead.
+    pub async fn next_frames(&mut self) -> Vec<Result<Vec<u8>, WebSocketResponse>> {
+        let mut frames = Vec::new();
+        let mut failed = Vec::new();
+
+        for (transfer_id, download) in &mut self.downloads {
+            while download.sent < download.chunk_count
+                && download.sent - download.acknowledged < download.window
+            {
+                match download.read_chunk().await {
+                    Ok(chunk) => {
+                        frames.push(Ok(ChunkFrame {
+                            kind: FrameKind::DownloadChunk,
+                            transfer_id: *transfer_id,
+                            id: download.id,
+                            chunk_index: download.sent,
+                            payload: &chunk,
+                        }
+                        .encode()));
+                        download.sent += 1;
+                    }
+                    Err(e) => {
+                        error!("Failed to read media blob {}: {}", download.id, e);
+                        frames.push(Err(e.to_response()));
+                        failed.push(*transfer_id);
+                        break;
+                    }
+                }
+            }
+        }
+
+        for transfer_id in failed {
+            self.downloads.remove(&transfer_id);
+        }
+        frames
+    }
+
+    /// Start receiving content into a staging file
+    pub fn start_upload(
+        &mut self,
+        transfer_id: u32,
+        params: CreateMediaBlob,
+        staged: StagedFile,
+        chunk_size: Option<u32>,
+        window: Option<u32>,
+    ) -> WebSocketResponse {
+        let id = Uuid::new_v4();
+        let chunk_size = negotiate_chunk_size(chunk_size);
+        let window = negotiate_window(window);
+        let size = params.size.unwrap_or_default() as u64;
+
+        self.uploads.insert(
+            transfer_id,
+            Upload {
+                id,
+                params,
+                staged,
+                size,
+                chunk_size,
+                received: 0,
+            },
+        );
+
+        WebSocketResponse::MediaUploadReady {
+            transfer_id,
+            id,
+            chunk_size,
+            chunk_count: size.div_ceil(chunk_size as u64) as u32,
+            window,
+        }
+    }
+
+    /// Take in an upload chunk
+    ///
+    /// A bad chunk ends its upload, removing what was received.
+    pub async fn receive(&mut self, frame: &[u8]) -> Result<UploadStep, TransferError> {
+        let frame = ChunkFrame::decode(frame)?;
+        if frame.kind != FrameKind::UploadChunk {
+            return Err(TransferError::Frame("expected an upload chunk".to_string()));
+        }
+        let transfer_id = frame.transfer_id;
+
+        let result = match self.uploads.get_mut(&transfer_id) {
+            Some(upload) if upload.id == frame.id => receive_chunk(upload, &frame).await,
+            _ => return Err(TransferError::Unknown(transfer_id)),
+        };
+        let done = match result {
+            Ok(done) => done,
+            Err(e) => {
+                self.uploads.remove(&transfer_id);
+                return Err(e);
+            }
+        };
+
+        let upload = &self.uploads[&transfer_id];
+        if !done {
+            return Ok(UploadStep::Progress(UploadProgress {
+                transfer_id,
+                id: upload.id,
+                chunk_index: frame.chunk_index,
+                bytes: upload.staged.size(),
+                size: upload.size,
+            }));
+        }
+
+        let mut upload = self.uploads.remove(&transfer_id).unwrap();
+        upload.staged.finish().await?;
+        if upload.staged.sha256() != Some(upload.params.sha256.as_str()) {
+            warn!(
+                "Upload {} content does not match SHA256 {}",
+                upload.id, upload.params.sha256
+            );
+            return Err(TransferError::Mismatch);
+        }
+
+        Ok(UploadStep::Complete(Box::new(FinishedUpload {
+            transfer_id,
+            params: upload.params,
+            staged: upload.staged,
+        })))
+    }
+
+    /// Stop a transfer, dropping anything received for it
+    pub fn cancel(&mut self, transfer_id: u32) -> bool {
+        self.downloads.remove(&transfer_id).is_some() || self.uploads.remove(&transfer_id).is_some()
+    }
+}
+
+/// Write a chunk to its upload, returning whether the upload is complete
+async fn receive_chunk(upload: &mut Upload, frame: &ChunkFrame<'_>) -> Result<bool, TransferError> {
+    if frame.chunk_index != upload.received {
+        return Err(TransferError::OutOfOrder {
+            expected: upload.received,
+            received: frame.chunk_index,
+        });
+    }
+    if frame.payload.is_empty() || frame.payload.len() > upload.chunk_size as usize {
+        return Err(TransferError::ChunkTooLarge(upload.chunk_size));
+    }
+
+    upload.staged.write_chunk(frame.payload).await?;
+    upload.received += 1;
+    Ok(upload.staged.size() == upload.size)
+}
+
+/// The chunk size to use for a client's request
+fn negotiate_chunk_size(requested: Option<u32>) -> u32 {
+    requested
+        .unwrap_or(DEFAULT_CHUNK_SIZE)
+        .clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE)
+}
+
+/// The window to use for a client's request
+fn negotiate_window(requested: Option<u32>) -> u32 {
+    requested.unwrap_or(DEFAULT_WINDOW).clamp(1, MAX_WINDOW)
+}
+
+/// A blob upload as announced by the client before sending its chunks
+pub struct UploadRequest {
+    pub transfer_id: u32,
+    pub sha256: String,
+    pub size: u64,
+    pub mime: Option<String>,
+    pub visibility: MediaVisibility,
+    pub source_client_id: Option<String>,
+    pub metadata: serde_json::Value,
+    pub chunk_size: Option<u32>,
+    pub window: Option<u32>,
+}
+
+/// Start a chunked upload for the user
+///
+/// Content that is already stored is never transferred: the blob is
+/// created at once, as with uploads over HTTP.
+#[allow(clippy::too_many_arguments)]
+pub async fn begin_upload(
+    transfers: &mut Transfers,
+    request: UploadRequest,
+    user: &User,
+    db: &DatabaseConnection,
+    storage: &MediaStorage,
+    events: &MediaEvents,
+    config: &AppConfig,
+) -> WebSocketResponse {
+    if let Err(e) = transfers.check_available(request.transfer_id) {
+        return e.to_response();
+    }
+
+    let limits = upload_limits(&config.media);
+    let params = CreateMediaBlob {
+        data: None,
+        sha256: request.sha256.to_lowercase(),
+        size: Some(request.size as i64),
+        mime: request.mime,
+        source_client_id: request.source_client_id,
+        owner_user_id: Some(user.id),
+        visibility: request.visibility,
+        local_path: None,
+        metadata: request.metadata,
+    };
+    if request.size == 0 {
+        return WebSocketResponse::error(ErrorCode::BadRequest, "Uploads must not be empty");
+    }
+    if let Err(message) =
+        params.validate_reference(limits.max_blob_file_size, limits.max_fs_file_size)
+    {
+        return WebSocketResponse::error(ErrorCode::BadRequest, message);
+    }
+
+    let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+    if let Err(e) = service
+        .check_quota(user.id, request.size as i64, &config.media.quotas)
+        .await
+    {
+        return upload_error(e);
+    }
+
+    // Only content the user can already see is referenced without being
+    // transferred; other stored content is sent like new content
+    match service.can_reference(&params.sha256, user).await {
+        Ok(true) => {
+            let transfer_id = request.transfer_id;
+            let mut params = params;
+            params.mime = match service
+                .check_content_type(
+                    params.mime.as_deref(),
+                    None,
+                    &params.sha256,
+                    &config.media.mime_types.websocket,
+                )
+                .await
+            {
+                Ok(mime) => mime,
+                Err(e) => return upload_error(e),
+            };
+            match service.create_upload(params, user, &limits).await {
+                Ok(blob) => WebSocketResponse::MediaUploadComplete { transfer_id, blob },
+                Err(e) => upload_error(e),
+            }
+        }
+        Ok(false) => match StagedFile::create(&storage.staging_directory(), request.size).await {
+            Ok(staged) => transfers.start_upload(
+                request.transfer_id,
+                params,
+                staged,
+                request.chunk_size,
+                request.window,
+            ),
+            Err(e) => {
+                error!("Failed to stage WebSocket upload: {}", e);
+                TransferError::from(e).to_response()
+            }
+        },
+        Err(e) => upload_error(e),
+    }
+}
+
+/// Store an upload whose content has fully arrived
+pub async fn finish_upload(
+    upload: FinishedUpload,
+    db: &DatabaseConnection,
+    storage: &MediaStorage,
+    events: &MediaEvents,
+    config: &AppConfig,
+) -> WebSocketResponse {
+    let FinishedUpload {
+        transfer_id,
+        mut params,
+        staged,
+    } = upload;
+    let service = MediaService::new(MediaRepository::new(db), storage).with_events(events);
+
+    params.mime = match service
+        .check_content_type(
+            params.mime.as_deref(),
+            Some(staged.head()),
+            &params.sha256,
+            &config.media.mime_types.websocket,
+        )
+        .await
+    {
+        Ok(mime) => mime,
+        Err(e) => return upload_error(e),
+    };
+
+    match service
+        .create_blob_from_file(params, &staged, &upload_limits(&config.media))
+        .await
+    {
+        Ok(blob) => {
+            info!("Received media blob {} over WebSocket frames", blob.id);
+            WebSocketResponse::MediaUploadComplete { transfer_id, blob }
+        }
+        Err(e) => upload_error(e),
+    }
+}
+
+/// Build the error response for a failed chunked upload
+fn upload_error(err: WebauthnError) -> WebSocketResponse {
+    let code = ErrorCode::from(&err);
+    match err {
+        WebauthnError::MediaContentType(e) => WebSocketResponse::error(code, e.to_string()),
+        WebauthnError::MediaQuota(e) => WebSocketResponse::error(code, e.to_string()),
+        WebauthnError::BadRequest => WebSocketResponse::error(code, "Invalid media blob upload"),
+        e => {
+            error!("Failed to upload media blob: {}", e);
+            WebSocketResponse::error(code, "Failed to upload media blob")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::Bytes;
+    use futures_util::StreamExt;
+
+    use super::*;
+    use crate::config::MediaStorageBackend;
+    use crate::media::{BlobStorage, StoredContent};
+
+    #[test]
+    fn test_frame_round_trip() {
+        let id = Uuid::new_v4();
+        let frame = ChunkFrame {
+            kind: FrameKind::UploadChunk,
+            transfer_id: 7,
+            id,
+            chunk_index: 3,
+            payload: b"chunk",
+        };
+        let encoded = frame.encode();
+        assert_eq!(encoded.len(), HEADER_LEN + 5);
+        assert_eq!(&encoded[2..6], &[0, 0, 0, 7]);
+        assert_eq!(ChunkFrame::decode(&encoded).unwrap(), frame);
+
+        assert!(ChunkFrame::decode(&encoded[..HEADER_LEN - 1]).is_err());
+        let mut bad = encoded.clone();
+        bad[0] = 9;
+        assert!(ChunkFrame::decode(&bad).is_err());
+        bad[0] = FRAME_VERSION;
+        bad[1] = 0;
+        assert!(ChunkFrame::decode(&bad).is_err());
+    }
+
+    #[test]
+    fn test_negotiation() {
+        assert_eq!(negotiate_chunk_size(None), DEFAULT_CHUNK_SIZE);
+        assert_eq!(negotiate_chunk_size(Some(1)), MIN_CHUNK_SIZE);
+        assert_eq!(negotiate_chunk_size(Some(u32::MAX)), MAX_CHUNK_SIZE);
+        assert_eq!(negotiate_window(Some(0)), 1);
+        assert_eq!(negotiate_window(Some(1000)), MAX_WINDOW);
+    }
+
+    fn blob(size: usize) -> MediaBlob {
+        MediaBlob::new(CreateMediaBlob {
+            data: None,
+            sha256: "0".repeat(64),
+            size: Some(size as i64),
+            mime: None,
+            source_client_id: None,
+            owner_user_id: None,
+            visibility: MediaVisibility::Private,
+            local_path: None,
+            metadata: serde_json::Value::Null,
+        })
+    }
+
+    /// Read-only content held in memory, streamed in pieces that do not line
+    /// up with chunks
+    struct MemoryStorage(Vec<u8>);
+
+    #[async_trait::async_trait]
+    impl BlobStorage for MemoryStorage {
+        fn backend(&self) -> MediaStorageBackend {
+            MediaStorageBackend::Database
+        }
+
+        fn key_for(&self, sha256: &str) -> String {
+            sha256.to_string()
+        }
+
+        async fn put(&self, _key: &str, _bytes: &[u8]) -> Result<(), BlobStorageError> {
+            Err(read_only())
+        }
+
+        async fn get(&self, _key: &str) -> Result<Option<Vec<u8>>, BlobStorageError> {
+            Ok(Some(self.0.clone()))
+        }
+
+        async fn get_range(
+            &self,
+            _key: &str,
+            start: u64,
+            length: u64,
+        ) -> Result<Option<ContentStream>, BlobStorageError> {
+            let range = &self.0[start as usize..(start + length) as usize];
+            let pieces: Vec<_> = range
+                .chunks(3000)
+                .map(|piece| Ok(Bytes::copy_from_slice(piece)))
+                .collect();
+            Ok(Some(futures_util::stream::iter(pieces).boxed()))
+        }
+
+        async fn delete(&self, _key: &str) -> Result<(), BlobStorageError> {
+            Err(read_only())
+        }
+
+        async fn exists(&self, _key: &str) -> Result<bool, BlobStorageError> {
+            Ok(true)
+        }
+    }
+
+    fn read_only() -> BlobStorageError {
+        BlobStorageError::Unavailable("test storage is read-only".to_string())
+    }
+
+    #[tokio::test]
+    async fn test_download_flow_control() {
+        let content: Vec<u8> = (0..10 * 1024).map(|i| i as u8).collect();
+        let stored = StoredContent::new(
+            Arc::new(MemoryStorage(content.clone())),
+            "memory",
+            content.len() as u64,
+        );
+        let mut transfers = Transfers::default();
+        transfers
+            .start_download(
+                1,
+                blob(content.len()),
+                MediaContent::Stored(stored),
+                Some(MIN_CHUNK_SIZE),
+                Some(2),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            transfers.check_available(1),
+            Err(TransferError::InUse(1))
+        ));
+
+        // Two chunks fit the window; the third waits for an acknowledgement
+        let frames = transfers.next_frames().await;
+        assert_eq!(frames.len(), 2);
+        assert!(transfers.next_frames().await.is_empty());
+        assert!(transfers.acknowledge(1, 2).is_err());
+
+        transfers.acknowledge(1, 0).unwrap();
+        let mut frames: Vec<_> = frames.into_iter().map(Result::unwrap).collect();
+        frames.extend(
+            transfers
+                .next_frames()
+                .await
+                .into_iter()
+                .map(Result::unwrap),
+        );
+        assert_eq!(frames.len(), 3);
+
+        let mut received = Vec::new();
+        for (index, frame) in frames.iter().enumerate() {
+            let frame = ChunkFrame::decode(frame).unwrap();
+            assert_eq!(frame.kind, FrameKind::DownloadChunk);
+            assert_eq!(frame.chunk_index, index as u32);
+            received.extend_from_slice(frame.payload);
+        }
+        assert_eq!(received, content);
+
+        // The download ends with the last acknowledgement
+        transfers.acknowledge(1, 2).unwrap();
+        assert!(matches!(
+            transfers.acknowledge(1, 2),
+            Err(TransferError::Unknown(1))
+        ));
+    }
+
+    fn upload_params(content: &[u8]) -> CreateMediaBlob {
+        use sha2::{Digest, Sha256};
+
+        CreateMediaBlob {
+            data: None,
+            sha256: format!("{:x}", Sha256::digest(content)),
+            size: Some(content.len() as i64),
+            mime: None,
+            source_client_id: None,
+            owner_user_id: None,
+            visibility: MediaVisibility::Private,
+            local_path: None,
+            metadata: serde_json::Value::Null,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upload_chunks() {
+        let directory = std::env::temp_dir().join(format!("ws-transfer-{}", Uuid::new_v4()));
+        let content = vec![7u8; MIN_CHUNK_SIZE as usize + 10];
+        let mut transfers = Transfers::default();
+
+        let start =
+            |transfers: &mut Transfers, transfer_id: u32, staged: StagedFile| match transfers
+                .start_upload(
+                    transfer_id,
+                    upload_params(&content),
+                    staged,
+                    Some(MIN_CHUNK_SIZE),
+                    None,
+                ) {
+                WebSocketResponse::MediaUploadReady {
+                    id, chunk_count, ..
+                } => {
+                    assert_eq!(chunk_count, 2);
+                    id
+                }
+                other => panic!("unexpected response: {:?}", other),
+            };
+        let chunk = |transfer_id: u32, id: Uuid, chunk_index: u32, payload: &[u8]| {
+            ChunkFrame {
+                kind: FrameKind::UploadChunk,
+                transfer_id,
+                id,
+                chunk_index,
+                payload,
+            }
+            .encode()
+        };
+        let (first, second) = content.split_at(MIN_CHUNK_SIZE as usize);
+
+        let staged = StagedFile::create(&directory, content.len() as u64)
+            .await
+            .unwrap();
+        let id = start(&mut transfers, 5, staged);
+        match transfers.receive(&chunk(5, id, 0, first)).await.unwrap() {
+            UploadStep::Progress(progress) => {
+                assert_eq!(progress.chunk_index, 0);
+                assert_eq!(progress.bytes, MIN_CHUNK_SIZE as u64);
+            }
+            _ => panic!("expected progress"),
+        }
+        match transfers.receive(&chunk(5, id, 1, second)).await.unwrap() {
+            UploadStep::Complete(finished) => {
+                assert_eq!(finished.transfer_id, 5);
+                assert_eq!(finished.staged.size(), content.len() as u64);
+            }
+            _ => panic!("expected a complete upload"),
+        }
+
+        // Chunks for another upload ID or out of order are refused; the
+        // latter ends the upload
+        let staged = StagedFile::create(&directory, content.len() as u64)
+            .await
+            .unwrap();
+        let id = start(&mut transfers, 6, staged);
+        assert!(matches!(
+            transfers.receive(&chunk(6, Uuid::nil(), 0, first)).await,
+            Err(TransferError::Unknown(6))
+        ));
+        assert!(matches!(
+            transfers.receive(&chunk(6, id, 1, second)).await,
+            Err(TransferError::OutOfOrder {
+                expected: 0,
+                received: 1
+            })
+        ));
+        assert!(!transfers.cancel(6));
+
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
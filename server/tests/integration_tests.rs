@@ -1,4 +1,50 @@
-use tokio;
+mod common;
+
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode};
+use axum::response::Response;
+use axum::{Extension, Router};
+use common::{sha256_hex, TestContext};
+use server::auth::{AuthenticatedUser, User, UserRole};
+use server::config::MediaStorageBackend;
+use server::error::WebauthnError;
+use server::media::{CreateMediaBlob, MediaBlob, MediaBlobQuery, MediaStorage, MediaVisibility};
+use tower::ServiceExt;
+
+/// Held by tests that run queued media jobs or look at jobs still waiting,
+/// since a run picks up the jobs of every test
+static MEDIA_JOBS: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Consistency checks look at, and repair, every test's content
+static MEDIA_FSCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+async fn body_of(response: Response) -> Vec<u8> {
+    axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap()
+        .to_vec()
+}
+
+/// Create a blob through the service, as the upload handlers do
+async fn create_blob(
+    ctx: &TestContext,
+    storage: &MediaStorage,
+    params: CreateMediaBlob,
+) -> MediaBlob {
+    ctx.media(storage)
+        .create_blob(params, &ctx.config.media)
+        .await
+        .unwrap()
+}
+
+/// Run queued media jobs until none are left
+async fn run_jobs(ctx: &TestContext, storage: &MediaStorage) {
+    while server::media::run_pending_jobs(&ctx.db, storage, &ctx.config.media)
+        .await
+        .unwrap()
+        > 0
+    {}
+}
 
 #[tokio::test]
 async fn test_server_compiles() {
@@ -14,9 +60,8 @@ async fn test_database_connection() {
 
     if let Ok(pool) = sqlx::postgres::PgPool::connect(&database_url).await {
         // Simple query to verify connection
-        let result: Result<(i32,), sqlx::Error> = sqlx::query_as("SELECT 1 as test")
-            .fetch_one(&pool)
-            .await;
+        let result: Result<(i32,), sqlx::Error> =
+            sqlx::query_as("SELECT 1 as test").fetch_one(&pool).await;
 
         assert!(result.is_ok());
         pool.close().await;
@@ -25,3 +70,4380 @@ async fn test_database_connection() {
         println!("Warning: Could not connect to database for testing");
     }
 }
+
+#[tokio::test]
+async fn test_invite_code_lock_prevents_double_redemption() {
+    use server::auth::AuthRepository;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let repo = AuthRepository::new(&ctx.db);
+    let code = ctx.invite_code().await;
+
+    // The first transaction locks and redeems the code but has not committed yet
+    let mut first = ctx.db.begin().await.unwrap();
+    let locked = repo.lock_invite_code_in(&mut first, &code).await.unwrap();
+    assert!(locked.unwrap().is_valid_for_use());
+    assert!(repo
+        .use_invite_code_in(&mut *first, &code, uuid::Uuid::new_v4())
+        .await
+        .unwrap());
+
+    // The second transaction blocks on the row lock until the first commits,
+    // then observes the code as already used
+    let second_db = ctx.db.clone();
+    let second_code = code.clone();
+    let second = tokio::spawn(async move {
+        let repo = AuthRepository::new(&second_db);
+        let mut tx = second_db.begin().await.unwrap();
+        let invite = repo
+            .lock_invite_code_in(&mut tx, &second_code)
+            .await
+            .unwrap()
+            .unwrap();
+        invite.is_valid_for_use()
+    });
+
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    first.commit().await.unwrap();
+
+    assert!(!second.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_registration_role_lock_serializes_first_users() {
+    use server::auth::AuthRepository;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let repo = AuthRepository::new(&ctx.db);
+
+    // The first registration holds the lock while deciding its role
+    let mut first = ctx.db.begin().await.unwrap();
+    repo.lock_registration_role_in(&mut first).await.unwrap();
+
+    // A second registration waits for it before looking at the users
+    let second_db = ctx.db.clone();
+    let second = tokio::spawn(async move {
+        let repo = AuthRepository::new(&second_db);
+        let mut tx = second_db.begin().await.unwrap();
+        repo.lock_registration_role_in(&mut tx).await.unwrap();
+        std::time::Instant::now()
+    });
+
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let released = std::time::Instant::now();
+    first.commit().await.unwrap();
+
+    assert!(second.await.unwrap() >= released);
+}
+
+#[tokio::test]
+async fn test_failed_registration_rolls_back_the_user_and_invite_code() {
+    use server::auth::{AuthError, AuthRepository};
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let repo = AuthRepository::new(&ctx.db);
+    let credential = common::passkey();
+    let first = repo
+        .register_user(
+            &format!("first-{}", &ctx.suffix[..8]),
+            Some(&ctx.invite_code().await),
+            &credential,
+        )
+        .await
+        .unwrap();
+    ctx.track_user(first.id);
+
+    // Saving the credential fails after the user row is inserted
+    let username = format!("second-{}", &ctx.suffix[..8]);
+    let code = ctx.invite_code().await;
+    assert!(matches!(
+        repo.register_user(&username, Some(&code), &credential)
+            .await,
+        Err(AuthError::CredentialAlreadyRegistered)
+    ));
+
+    let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE username = $1")
+        .bind(&username)
+        .fetch_one(&ctx.pool)
+        .await
+        .unwrap();
+    assert_eq!(users, 0);
+    let invite = repo.get_invite_code(&code).await.unwrap().unwrap();
+    assert!(invite.used_at.is_none());
+    assert!(invite.is_valid_for_use());
+}
+
+/// A private blob of `owner`'s, stored in the database
+async fn private_blob(ctx: &TestContext, storage: &MediaStorage, owner: &User) -> MediaBlob {
+    let data = format!("private {} {}", owner.username, ctx.suffix).into_bytes();
+    create_blob(ctx, storage, common::blob(owner, data)).await
+}
+
+#[tokio::test]
+async fn test_private_blobs_are_hidden_from_other_members() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("owner").await;
+    let other = ctx.user("other").await;
+    let storage = ctx.storage_with(MediaStorageBackend::Database);
+    let service = ctx.media(&storage);
+    let blob = private_blob(&ctx, &storage, &owner).await;
+
+    assert!(service.get_blob(blob.id, false, &owner).await.is_ok());
+    assert!(service.get_blob(blob.id, false, &other).await.is_err());
+    let listed = service
+        .list_blobs(MediaBlobQuery::default(), &other)
+        .await
+        .unwrap();
+    assert!(!listed.iter().any(|b| b.id == blob.id));
+}
+
+#[tokio::test]
+async fn test_shared_blobs_are_visible_to_grantees_but_not_managed_by_them() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("owner").await;
+    let other = ctx.user("other").await;
+    let storage = ctx.storage_with(MediaStorageBackend::Database);
+    let service = ctx.media(&storage);
+    let blob = private_blob(&ctx, &storage, &owner).await;
+
+    service
+        .set_visibility(blob.id, MediaVisibility::Shared, &owner)
+        .await
+        .unwrap();
+    service
+        .share_blob(blob.id, &[other.id], &owner)
+        .await
+        .unwrap();
+    assert!(service.get_blob(blob.id, false, &other).await.is_ok());
+    assert!(service.delete_blob(blob.id, &other).await.is_err());
+    assert!(service.delete_blob(blob.id, &owner).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_revoking_a_share_hides_the_blob_again() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("owner").await;
+    let other = ctx.user("other").await;
+    let storage = ctx.storage_with(MediaStorageBackend::Database);
+    let service = ctx.media(&storage);
+    let blob = private_blob(&ctx, &storage, &owner).await;
+
+    service
+        .set_visibility(blob.id, MediaVisibility::Shared, &owner)
+        .await
+        .unwrap();
+    service
+        .share_blob(blob.id, &[other.id], &owner)
+        .await
+        .unwrap();
+    service
+        .unshare_blob(blob.id, other.id, &owner)
+        .await
+        .unwrap();
+    assert!(service.get_blob(blob.id, false, &other).await.is_err());
+}
+
+/// Backends that need no outside service
+const LOCAL_BACKENDS: [MediaStorageBackend; 2] = [
+    MediaStorageBackend::Database,
+    MediaStorageBackend::Filesystem,
+];
+
+/// Referencing unknown content, or sending content that does not match its
+/// hash, is refused
+async fn check_unknown_content_refused(ctx: &TestContext, storage: &MediaStorage) {
+    let owner = ctx
+        .user(&format!("refuse-{}", storage.default_backend().backend()))
+        .await;
+    let service = ctx.media(storage);
+    let content = format!("unknown content {}", ctx.suffix).into_bytes();
+
+    let params = CreateMediaBlob {
+        data: None,
+        ..common::blob(&owner, content.clone())
+    };
+    assert!(matches!(
+        service.create_blob(params, &ctx.config.media).await,
+        Err(WebauthnError::MediaContentMissing)
+    ));
+
+    let params = CreateMediaBlob {
+        data: Some(b"tampered".to_vec()),
+        ..common::blob(&owner, content)
+    };
+    assert!(service
+        .create_blob(params, &ctx.config.media)
+        .await
+        .is_err());
+}
+
+/// Content stored twice is kept once, and collected with its last reference
+async fn check_content_deduplicated(ctx: &TestContext, storage: &MediaStorage) {
+    let owner = ctx
+        .user(&format!("dedup-{}", storage.default_backend().backend()))
+        .await;
+    let service = ctx.media(storage);
+    let content = format!(
+        "dedup content {} {}",
+        storage.default_backend().backend(),
+        ctx.suffix
+    )
+    .into_bytes();
+    let sha256 = sha256_hex(&content);
+
+    let first = create_blob(ctx, storage, common::blob(&owner, content.clone())).await;
+    let second = create_blob(
+        ctx,
+        storage,
+        CreateMediaBlob {
+            data: None,
+            ..common::blob(&owner, content.clone())
+        },
+    )
+    .await;
+    assert_ne!(first.id, second.id);
+    assert_eq!(second.size, Some(content.len() as i64));
+
+    let object = service.find_object(&sha256).await.unwrap().unwrap();
+    assert_eq!(object.ref_count, 2);
+    assert_eq!(object.storage_backend, storage.default_backend().backend());
+
+    // Both records read the single stored copy
+    let fetched = service.get_blob(second.id, true, &owner).await.unwrap();
+    assert_eq!(fetched.data.as_deref(), Some(content.as_slice()));
+
+    assert!(service.delete_blob(first.id, &owner).await.unwrap());
+    let object = service.find_object(&sha256).await.unwrap().unwrap();
+    assert_eq!(object.ref_count, 1);
+
+    // Deleting the last reference collects the object and its stored bytes
+    assert!(service.delete_blob(second.id, &owner).await.unwrap());
+    assert!(service.find_object(&sha256).await.unwrap().is_none());
+    let backend = storage.backend(object.storage_backend).unwrap();
+    assert!(!backend.exists(&object.storage_key).await.unwrap());
+}
+
+/// Content can be stored from a staging file, which is left to the caller
+async fn check_staged_content_stored(ctx: &TestContext, storage: &MediaStorage) {
+    use server::media::StagedFile;
+
+    let owner = ctx
+        .user(&format!("staged-{}", storage.default_backend().backend()))
+        .await;
+    let service = ctx.media(storage);
+    let content = format!(
+        "staged content {} {}",
+        storage.default_backend().backend(),
+        ctx.suffix
+    )
+    .into_bytes();
+
+    let mut staged = StagedFile::create(&storage.staging_directory(), 1024)
+        .await
+        .unwrap();
+    for chunk in content.chunks(7) {
+        staged.write_chunk(chunk).await.unwrap();
+    }
+    staged.finish().await.unwrap();
+    let staged_path = staged.path().to_path_buf();
+
+    let params = CreateMediaBlob {
+        data: None,
+        ..common::blob(&owner, content.clone())
+    };
+    let blob = service
+        .create_blob_from_file(params, &staged, &ctx.config.media)
+        .await
+        .unwrap();
+    // The staging file is removed when dropped
+    assert!(staged_path.exists());
+    drop(staged);
+    assert!(!staged_path.exists());
+    let fetched = service.get_blob(blob.id, true, &owner).await.unwrap();
+    assert_eq!(fetched.data.as_deref(), Some(content.as_slice()));
+}
+
+#[tokio::test]
+async fn test_media_unknown_content_refused() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    for backend in LOCAL_BACKENDS {
+        check_unknown_content_refused(&ctx, &ctx.storage_with(backend)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_media_content_deduplication_and_gc() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    for backend in LOCAL_BACKENDS {
+        check_content_deduplicated(&ctx, &ctx.storage_with(backend)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_media_content_stored_from_staged_files() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    for backend in LOCAL_BACKENDS {
+        check_staged_content_stored(&ctx, &ctx.storage_with(backend)).await;
+    }
+}
+
+// Runs against an S3-compatible server (e.g. MinIO) when MEDIA_S3_TEST_ENDPOINT is set
+#[tokio::test]
+async fn test_media_s3_storage() {
+    use server::config::S3StorageConfig;
+
+    let Ok(endpoint) = std::env::var("MEDIA_S3_TEST_ENDPOINT") else {
+        common::skip("MEDIA_S3_TEST_ENDPOINT not set");
+        return;
+    };
+
+    let Some(mut ctx) = TestContext::new().await else {
+        return;
+    };
+
+    let env = |name: &str, default: &str| std::env::var(name).unwrap_or_else(|_| default.into());
+    ctx.config.media.storage.s3 = Some(S3StorageConfig {
+        endpoint,
+        bucket: env("MEDIA_S3_TEST_BUCKET", "media"),
+        region: env("MEDIA_S3_TEST_REGION", "us-east-1"),
+        access_key_id: env("MEDIA_S3_TEST_ACCESS_KEY_ID", "minioadmin"),
+        secret_access_key: env("MEDIA_S3_TEST_SECRET_ACCESS_KEY", "minioadmin"),
+        prefix: format!("test-{}", uuid::Uuid::new_v4().simple()),
+        path_style: true,
+    });
+
+    let storage = ctx.storage_with(MediaStorageBackend::S3);
+    check_unknown_content_refused(&ctx, &storage).await;
+    check_content_deduplicated(&ctx, &storage).await;
+    check_staged_content_stored(&ctx, &storage).await;
+}
+
+#[tokio::test]
+async fn test_media_database_range_reads() {
+    use futures_util::TryStreamExt;
+    use server::media::BlobStorageError;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let backend = storage.backend(MediaStorageBackend::Database).unwrap();
+
+    // Content larger than one read, so ranges span several of them
+    let content: Vec<u8> = (0..(5 << 19) + 7).map(|i| (i % 251) as u8).collect();
+    let key = backend.key_for(&sha256_hex(&content));
+    backend.put(&key, &content).await.unwrap();
+
+    let stored = storage
+        .stored(MediaStorageBackend::Database, &key, content.len() as u64)
+        .unwrap();
+    for (start, length) in [(0, content.len()), ((1 << 20) - 10, (1 << 20) + 20), (5, 0)] {
+        let chunks: Vec<_> = stored
+            .open(start as u64, length as u64)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks.concat(), &content[start..start + length]);
+    }
+
+    backend.delete(&key).await.unwrap();
+    assert!(matches!(
+        stored.open(0, 1).await,
+        Err(BlobStorageError::Missing(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_media_objects_move_between_backends() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("move").await;
+    let storage = ctx.storage_with(MediaStorageBackend::Filesystem);
+    let service = ctx.media(&storage);
+    let content: Vec<u8> = ctx.suffix.bytes().cycle().take((1 << 20) + 3).collect();
+    let blob = create_blob(&ctx, &storage, common::blob(&owner, content.clone())).await;
+
+    // From a local file, then streamed back from the database
+    for (target, before) in [
+        (
+            MediaStorageBackend::Database,
+            MediaStorageBackend::Filesystem,
+        ),
+        (
+            MediaStorageBackend::Filesystem,
+            MediaStorageBackend::Database,
+        ),
+    ] {
+        let old_key = service
+            .find_object(&blob.sha256)
+            .await
+            .unwrap()
+            .unwrap()
+            .storage_key;
+        assert!(service.move_object(&blob.sha256, target).await.unwrap());
+        let object = service.find_object(&blob.sha256).await.unwrap().unwrap();
+        assert_eq!(object.storage_backend, target);
+        assert_eq!(service.read_object(&object).await.unwrap(), content);
+        let source = storage.backend(before).unwrap();
+        assert!(!source.exists(&old_key).await.unwrap());
+    }
+    assert!(!service
+        .move_object(&blob.sha256, MediaStorageBackend::Filesystem)
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+async fn test_media_objects_not_matching_their_hash_stay_put() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("move-bad").await;
+    let storage = ctx.storage_with(MediaStorageBackend::Database);
+    let service = ctx.media(&storage);
+    let content = format!("move {}", ctx.suffix).into_bytes();
+    let blob = create_blob(&ctx, &storage, common::blob(&owner, content)).await;
+    sqlx::query("UPDATE media_object_data SET data = 'damaged' WHERE sha256 = $1")
+        .bind(&blob.sha256)
+        .execute(&ctx.pool)
+        .await
+        .unwrap();
+
+    assert!(service
+        .move_object(&blob.sha256, MediaStorageBackend::Filesystem)
+        .await
+        .is_err());
+    let object = service.find_object(&blob.sha256).await.unwrap().unwrap();
+    assert_eq!(object.storage_backend, MediaStorageBackend::Database);
+}
+
+/// Content uploaded by its owner, and parameters referencing it by hash
+struct HashReference {
+    owner: User,
+    other: User,
+    content: Vec<u8>,
+}
+
+impl HashReference {
+    async fn new(ctx: &TestContext, storage: &MediaStorage) -> Self {
+        let owner = ctx.user("hashown").await;
+        let other = ctx.user("hashguess").await;
+        let content = format!("secret content for {}", ctx.suffix).into_bytes();
+        ctx.media(storage)
+            .create_upload(
+                common::blob(&owner, content.clone()),
+                &owner,
+                &ctx.config.media,
+            )
+            .await
+            .unwrap();
+        Self {
+            owner,
+            other,
+            content,
+        }
+    }
+
+    /// Parameters for `user`'s blob of the content, sending `data` if any
+    fn params(&self, user: &User, data: Option<Vec<u8>>) -> CreateMediaBlob {
+        CreateMediaBlob {
+            data,
+            mime: Some("text/plain".to_string()),
+            ..common::blob(user, self.content.clone())
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_media_owner_references_own_content_by_hash() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage_with(MediaStorageBackend::Database);
+    let service = ctx.media(&storage);
+    let fixture = HashReference::new(&ctx, &storage).await;
+
+    let sha256 = sha256_hex(&fixture.content);
+    assert!(service
+        .can_reference(&sha256, &fixture.owner)
+        .await
+        .unwrap());
+    service
+        .create_upload(
+            fixture.params(&fixture.owner, None),
+            &fixture.owner,
+            &ctx.config.media,
+        )
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_media_others_cannot_reference_content_by_hash() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage_with(MediaStorageBackend::Database);
+    let service = ctx.media(&storage);
+    let fixture = HashReference::new(&ctx, &storage).await;
+    let other = &fixture.other;
+
+    // Others are told the content is missing, as if it were not stored
+    let sha256 = sha256_hex(&fixture.content);
+    assert!(!service.can_reference(&sha256, other).await.unwrap());
+    let guessed = service
+        .create_upload(fixture.params(other, None), other, &ctx.config.media)
+        .await;
+    assert!(matches!(guessed, Err(WebauthnError::MediaContentMissing)));
+
+    let mut unknown = fixture.params(other, None);
+    unknown.sha256 = sha256_hex(b"content nobody stored");
+    let unknown = service
+        .create_upload(unknown, other, &ctx.config.media)
+        .await;
+    assert!(matches!(unknown, Err(WebauthnError::MediaContentMissing)));
+}
+
+#[tokio::test]
+async fn test_media_sent_content_must_match_stored_hash() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage_with(MediaStorageBackend::Database);
+    let service = ctx.media(&storage);
+    let fixture = HashReference::new(&ctx, &storage).await;
+    let other = &fixture.other;
+
+    let forged = service
+        .create_upload(
+            fixture.params(other, Some(b"other bytes".to_vec())),
+            other,
+            &ctx.config.media,
+        )
+        .await;
+    assert!(matches!(forged, Err(WebauthnError::BadRequest)));
+
+    // Sending the content itself grants the right to reference it
+    service
+        .create_upload(
+            fixture.params(other, Some(fixture.content.clone())),
+            other,
+            &ctx.config.media,
+        )
+        .await
+        .unwrap();
+    let sha256 = sha256_hex(&fixture.content);
+    assert!(service.can_reference(&sha256, other).await.unwrap());
+}
+
+/// Resumable upload routes, acting as `user`
+fn upload_app(
+    ctx: &TestContext,
+    user: &User,
+    storage: &MediaStorage,
+    events: &server::media::MediaEvents,
+) -> Router {
+    use axum::routing::{head, post};
+    use server::upload::{
+        append_upload_chunk, complete_upload_session, create_upload_session, delete_upload_session,
+        get_upload_offset,
+    };
+
+    Router::new()
+        .route("/api/upload/sessions", post(create_upload_session))
+        .route(
+            "/api/upload/sessions/{id}",
+            head(get_upload_offset)
+                .patch(append_upload_chunk)
+                .delete(delete_upload_session),
+        )
+        .route(
+            "/api/upload/sessions/{id}/complete",
+            post(complete_upload_session),
+        )
+        .layer(Extension(server::upload::UploadWriters::default()))
+        .layer(Extension(AuthenticatedUser(user.clone())))
+        .layer(Extension(ctx.db.clone()))
+        .layer(Extension(storage.clone()))
+        .layer(Extension(events.clone()))
+        .layer(Extension(ctx.config.clone()))
+}
+
+/// Content large enough to pass the minimum size for large uploads
+fn large_upload_content(ctx: &TestContext) -> Vec<u8> {
+    let mut content = vec![0u8; 10 * 1024 * 1024 + 123];
+    content[..ctx.suffix.len()].copy_from_slice(ctx.suffix.as_bytes());
+    content
+}
+
+/// Start an upload session for `content`, returning its location
+async fn start_upload(app: &Router, filename: &str, content: &[u8]) -> String {
+    let response = app
+        .clone()
+        .oneshot(
+            Request::post("/api/upload/sessions")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    serde_json::json!({
+                        "filename": filename,
+                        "sha256": sha256_hex(content),
+                        "size": content.len(),
+                    })
+                    .to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    response.headers()["location"].to_str().unwrap().to_string()
+}
+
+async fn patch_upload(app: &Router, location: &str, offset: usize, bytes: &[u8]) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::PATCH)
+                .uri(location)
+                .header("content-type", "application/offset+octet-stream")
+                .header("upload-offset", offset.to_string())
+                .body(Body::from(bytes.to_vec()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+async fn head_upload(app: &Router, location: &str) -> Response {
+    app.clone()
+        .oneshot(Request::head(location).body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+}
+
+async fn complete_upload(app: &Router, location: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::post(format!("{}/complete", location))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+fn upload_offset(response: &Response) -> usize {
+    response.headers()["upload-offset"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+/// Send `content` in two chunks and complete the upload, returning the blob's ID
+async fn upload_in_two_chunks(app: &Router, location: &str, content: &[u8]) -> uuid::Uuid {
+    let split = 6 * 1024 * 1024;
+    let response = patch_upload(app, location, 0, &content[..split]).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(upload_offset(&response), split);
+    let response = patch_upload(app, location, split, &content[split..]).await;
+    assert_eq!(upload_offset(&response), content.len());
+
+    let response = complete_upload(app, location).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let uploaded: serde_json::Value = serde_json::from_slice(&body_of(response).await).unwrap();
+    uploaded["id"].as_str().unwrap().parse().unwrap()
+}
+
+#[tokio::test]
+async fn test_resumable_upload_continues_from_reported_offset() {
+    use server::media::MediaEvents;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let admin = ctx.user_with_role("resume", UserRole::Admin).await;
+    let storage = ctx.storage_with(MediaStorageBackend::Filesystem);
+    let app = upload_app(&ctx, &admin, &storage, &MediaEvents::default());
+    let content = large_upload_content(&ctx);
+    let location = start_upload(&app, "resumable.bin", &content).await;
+
+    let split = 6 * 1024 * 1024;
+    let response = patch_upload(&app, &location, 0, &content[..split]).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    // A resuming client asks for the offset and continues from there
+    let response = head_upload(&app, &location).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let offset = upload_offset(&response);
+    assert_eq!(offset, split);
+    let response = patch_upload(&app, &location, offset, &content[offset..]).await;
+    assert_eq!(upload_offset(&response), content.len());
+
+    let response = complete_upload(&app, &location).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let uploaded: serde_json::Value = serde_json::from_slice(&body_of(response).await).unwrap();
+    let blob_id: uuid::Uuid = uploaded["id"].as_str().unwrap().parse().unwrap();
+    let blob = ctx
+        .media(&storage)
+        .get_blob(blob_id, true, &admin)
+        .await
+        .unwrap();
+    assert_eq!(blob.data.as_deref(), Some(content.as_slice()));
+
+    // The session is gone once completed
+    let response = head_upload(&app, &location).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_resumable_upload_refuses_early_completion_and_wrong_offsets() {
+    use server::media::MediaEvents;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let admin = ctx.user_with_role("early", UserRole::Admin).await;
+    let storage = ctx.storage_with(MediaStorageBackend::Filesystem);
+    let app = upload_app(&ctx, &admin, &storage, &MediaEvents::default());
+    let content = large_upload_content(&ctx);
+    let location = start_upload(&app, "early.bin", &content).await;
+
+    let response = patch_upload(&app, &location, 0, &content[..1024]).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = complete_upload(&app, &location).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = patch_upload(&app, &location, 0, &content[..10]).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = head_upload(&app, &location).await;
+    assert_eq!(upload_offset(&response), 1024);
+}
+
+#[tokio::test]
+async fn test_resumable_upload_refuses_a_second_writer() {
+    use server::media::MediaEvents;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let admin = ctx.user_with_role("writers", UserRole::Admin).await;
+    let storage = ctx.storage_with(MediaStorageBackend::Filesystem);
+    let app = upload_app(&ctx, &admin, &storage, &MediaEvents::default());
+    let content = large_upload_content(&ctx);
+    let location = start_upload(&app, "writers.bin", &content).await;
+
+    // The first chunk is still being sent
+    let (sender, receiver) = tokio::sync::mpsc::channel::<Vec<u8>>(1);
+    let body = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let bytes = receiver.recv().await?;
+        Some((Ok::<_, std::io::Error>(bytes), receiver))
+    });
+    let first = tokio::spawn(
+        app.clone().oneshot(
+            Request::builder()
+                .method(Method::PATCH)
+                .uri(&location)
+                .header("content-type", "application/offset+octet-stream")
+                .header("upload-offset", "0")
+                .body(Body::from_stream(body))
+                .unwrap(),
+        ),
+    );
+    sender.send(content[..512].to_vec()).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    let response = patch_upload(&app, &location, 0, &content[..10]).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    sender.send(content[512..1024].to_vec()).await.unwrap();
+    drop(sender);
+    let response = first.await.unwrap().unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = head_upload(&app, &location).await;
+    assert_eq!(upload_offset(&response), 1024);
+    let response = patch_upload(&app, &location, 1024, &content[1024..2048]).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+}
+
+#[tokio::test]
+async fn test_completed_uploads_are_announced() {
+    use server::media::{MediaEventKind, MediaEvents};
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let admin = ctx.user_with_role("announce", UserRole::Admin).await;
+    let storage = ctx.storage_with(MediaStorageBackend::Filesystem);
+    let events = MediaEvents::default();
+    let mut received = events.subscribe();
+    let app = upload_app(&ctx, &admin, &storage, &events);
+    let content = large_upload_content(&ctx);
+    let location = start_upload(&app, "announced.bin", &content).await;
+
+    let blob_id = upload_in_two_chunks(&app, &location, &content).await;
+
+    let event = received.try_recv().unwrap();
+    assert_eq!(event.kind, MediaEventKind::Created);
+    assert_eq!(event.blob.id, blob_id);
+    assert!(event.blob.data.is_none());
+}
+
+#[tokio::test]
+async fn test_expired_upload_sessions_are_swept_with_their_files() {
+    use server::media::MediaEvents;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let admin = ctx.user_with_role("sweep", UserRole::Admin).await;
+    let storage = ctx.storage_with(MediaStorageBackend::Filesystem);
+    let app = upload_app(&ctx, &admin, &storage, &MediaEvents::default());
+    let content = large_upload_content(&ctx);
+    let location = start_upload(&app, "abandoned.bin", &content).await;
+    let session_id: uuid::Uuid = location.rsplit('/').next().unwrap().parse().unwrap();
+
+    let response = patch_upload(&app, &location, 0, &content[..1024]).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let staging_path: String =
+        sqlx::query_scalar("SELECT staging_path FROM media_upload_sessions WHERE id = $1")
+            .bind(session_id)
+            .fetch_one(&ctx.pool)
+            .await
+            .unwrap();
+    assert!(std::path::Path::new(&staging_path).exists());
+    sqlx::query(
+        "UPDATE media_upload_sessions SET expires_at = now() - interval '1 minute' WHERE id = $1",
+    )
+    .bind(session_id)
+    .execute(&ctx.pool)
+    .await
+    .unwrap();
+    assert!(
+        server::upload::cleanup_expired_upload_sessions(&ctx.db)
+            .await
+            .unwrap()
+            >= 1
+    );
+    assert!(!std::path::Path::new(&staging_path).exists());
+}
+
+/// The peer address of the reverse proxy requests arrive through
+const LINK_PROXY: &str = "10.0.0.1:443";
+
+/// A private blob and a private file to mint signed links for
+struct SignedLinks {
+    ctx: TestContext,
+    storage: MediaStorage,
+    signer: server::media::LinkSigner,
+    owner: User,
+    other: User,
+    blob: MediaBlob,
+    content: Vec<u8>,
+}
+
+impl SignedLinks {
+    async fn new() -> Option<Self> {
+        let mut ctx = TestContext::new().await?;
+        let owner = ctx.user("linker").await;
+        let other = ctx.user("linkee").await;
+
+        let private_dir = ctx.directory("private-test");
+        std::fs::create_dir_all(private_dir.join("docs")).unwrap();
+        std::fs::write(private_dir.join("docs/report.txt"), b"quarterly numbers").unwrap();
+        ctx.config.static_files.private_directory = private_dir.to_string_lossy().into_owned();
+        ctx.config.server.trusted_proxies =
+            vec![LINK_PROXY.parse::<std::net::SocketAddr>().unwrap().ip()];
+
+        let storage = ctx.storage_with(MediaStorageBackend::Database);
+        let content = format!("signed link content {}", ctx.suffix).into_bytes();
+        let blob = create_blob(
+            &ctx,
+            &storage,
+            CreateMediaBlob {
+                mime: Some("text/plain".to_string()),
+                ..common::blob(&owner, content.clone())
+            },
+        )
+        .await;
+
+        Some(Self {
+            signer: server::media::LinkSigner::new(
+                b"integration-test-link-secret-0123456789".to_vec(),
+            ),
+            ctx,
+            storage,
+            owner,
+            other,
+            blob,
+            content,
+        })
+    }
+
+    fn app(&self, user: &User) -> Router {
+        use axum::routing::{get, post};
+        use server::media::handlers::{
+            create_media_link, serve_signed_blob, serve_signed_private_file,
+        };
+
+        Router::new()
+            .route("/api/media/links", post(create_media_link))
+            .route("/api/media/signed/blob/{id}", get(serve_signed_blob))
+            .route(
+                "/api/media/signed/private/{*path}",
+                get(serve_signed_private_file),
+            )
+            .layer(Extension(AuthenticatedUser(user.clone())))
+            .layer(Extension(self.ctx.db.clone()))
+            .layer(Extension(self.storage.clone()))
+            .layer(Extension(self.ctx.config.clone()))
+            .layer(Extension(self.signer.clone()))
+    }
+
+    /// Ask for a link as `user`, on behalf of a client at 192.0.2.1
+    async fn create_link(&self, user: &User, request: serde_json::Value) -> Response {
+        use axum::extract::ConnectInfo;
+
+        let proxy: std::net::SocketAddr = LINK_PROXY.parse().unwrap();
+        self.app(user)
+            .oneshot(
+                Request::post("/api/media/links")
+                    .header("content-type", "application/json")
+                    .header("x-real-ip", "192.0.2.1")
+                    .extension(ConnectInfo(proxy))
+                    .body(Body::from(request.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+    }
+
+    /// The path of a link `user` asked for
+    async fn link_path(&self, user: &User, request: serde_json::Value) -> String {
+        let response = self.create_link(user, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let link: serde_json::Value = serde_json::from_slice(&body_of(response).await).unwrap();
+        link["url"]
+            .as_str()
+            .unwrap()
+            .strip_prefix(self.ctx.config.webauthn.rp_origin.trim_end_matches('/'))
+            .unwrap()
+            .to_string()
+    }
+
+    /// Follow a link as someone other than the blob's owner
+    async fn fetch_from(
+        &self,
+        peer: std::net::SocketAddr,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> Response {
+        use axum::extract::ConnectInfo;
+
+        let mut request = Request::get(path).extension(ConnectInfo(peer));
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        self.app(&self.other)
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn fetch(&self, path: &str, headers: &[(&str, &str)]) -> Response {
+        self.fetch_from(LINK_PROXY.parse().unwrap(), path, headers)
+            .await
+    }
+}
+
+#[tokio::test]
+async fn test_signed_links_only_for_visible_blobs() {
+    let Some(links) = SignedLinks::new().await else {
+        return;
+    };
+    let response = links
+        .create_link(
+            &links.other,
+            serde_json::json!({ "blob_id": links.blob.id }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_signed_links_serve_content_with_ranges_and_etags() {
+    let Some(links) = SignedLinks::new().await else {
+        return;
+    };
+    let path = links
+        .link_path(
+            &links.owner,
+            serde_json::json!({ "blob_id": links.blob.id }),
+        )
+        .await;
+
+    let response = links.fetch(&path, &[]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "text/plain");
+    let etag = response.headers()["etag"].to_str().unwrap().to_string();
+    assert_eq!(etag, format!("\"{}\"", links.blob.sha256));
+    assert_eq!(body_of(response).await, links.content);
+
+    let response = links.fetch(&path, &[("range", "bytes=7-10")]).await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        response.headers()["content-range"],
+        format!("bytes 7-10/{}", links.content.len()).as_str()
+    );
+    assert_eq!(body_of(response).await, &links.content[7..=10]);
+
+    let response = links
+        .fetch(&path, &[("if-none-match", etag.as_str())])
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+}
+
+#[tokio::test]
+async fn test_signed_links_refused_when_tampered_or_expired() {
+    use server::media::links::LinkParams;
+    use server::media::LinkTarget;
+
+    let Some(links) = SignedLinks::new().await else {
+        return;
+    };
+    let path = links
+        .link_path(
+            &links.owner,
+            serde_json::json!({ "blob_id": links.blob.id }),
+        )
+        .await;
+
+    // Any change to the signed parameters invalidates the link
+    let tampered = path.replacen("expires=", "expires=9", 1);
+    let response = links.fetch(&tampered, &[]).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let target = LinkTarget::Blob(links.blob.id);
+    let expired = links.signer.sign(
+        &target,
+        LinkParams {
+            expires: time::OffsetDateTime::now_utc().unix_timestamp() - 1,
+            nonce: None,
+            ip: None,
+            sig: String::new(),
+        },
+    );
+    let response = links.fetch(&links.signer.url(&target, &expired), &[]).await;
+    assert_eq!(response.status(), StatusCode::GONE);
+}
+
+#[tokio::test]
+async fn test_single_use_links_work_once() {
+    let Some(links) = SignedLinks::new().await else {
+        return;
+    };
+    let path = links
+        .link_path(
+            &links.owner,
+            serde_json::json!({ "blob_id": links.blob.id, "single_use": true }),
+        )
+        .await;
+
+    let response = links.fetch(&path, &[]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["cache-control"], "private, no-store");
+    let response = links.fetch(&path, &[]).await;
+    assert_eq!(response.status(), StatusCode::GONE);
+}
+
+#[tokio::test]
+async fn test_single_use_links_serve_ranges_but_not_the_whole_file_again() {
+    let Some(links) = SignedLinks::new().await else {
+        return;
+    };
+    let path = links
+        .link_path(
+            &links.owner,
+            serde_json::json!({ "blob_id": links.blob.id, "single_use": true }),
+        )
+        .await;
+
+    // A player reading the link in parts may keep going for a while
+    let response = links.fetch(&path, &[("range", "bytes=0-3")]).await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body_of(response).await, &links.content[..4]);
+    let response = links.fetch(&path, &[("range", "bytes=4-")]).await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body_of(response).await, &links.content[4..]);
+    let response = links.fetch(&path, &[]).await;
+    assert_eq!(response.status(), StatusCode::GONE);
+}
+
+#[tokio::test]
+async fn test_ip_bound_links_only_work_from_the_requesting_address() {
+    let Some(links) = SignedLinks::new().await else {
+        return;
+    };
+    let path = links
+        .link_path(
+            &links.owner,
+            serde_json::json!({ "blob_id": links.blob.id, "bind_ip": true }),
+        )
+        .await;
+
+    let response = links.fetch(&path, &[("x-real-ip", "192.0.2.2")]).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = links.fetch(&path, &[("x-real-ip", "192.0.2.1")]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Clients reaching the server directly cannot claim another address
+    let direct: std::net::SocketAddr = "198.51.100.9:50000".parse().unwrap();
+    let response = links
+        .fetch_from(direct, &path, &[("x-real-ip", "192.0.2.1")])
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_private_file_links_stay_inside_the_private_directory() {
+    let Some(links) = SignedLinks::new().await else {
+        return;
+    };
+
+    // Any member may link private files
+    let path = links
+        .link_path(
+            &links.other,
+            serde_json::json!({ "path": "/docs/report.txt" }),
+        )
+        .await;
+    assert!(path.starts_with("/api/media/signed/private/docs/report.txt?"));
+    let response = links.fetch(&path, &[("range", "bytes=0-8")]).await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body_of(response).await, b"quarterly");
+
+    let response = links
+        .create_link(
+            &links.other,
+            serde_json::json!({ "path": "../../etc/passwd" }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+/// An audio blob of `owner`'s stored in `backend`
+async fn content_blob(
+    ctx: &TestContext,
+    backend: MediaStorageBackend,
+    owner: &User,
+) -> (MediaStorage, MediaBlob, Vec<u8>) {
+    let storage = ctx.storage_with(backend);
+    let content = format!("{} content for {}", backend, ctx.suffix).into_bytes();
+    let blob = create_blob(
+        ctx,
+        &storage,
+        CreateMediaBlob {
+            mime: Some("audio/ogg".to_string()),
+            ..common::blob(owner, content.clone())
+        },
+    )
+    .await;
+    (storage, blob, content)
+}
+
+async fn fetch_content(
+    ctx: &TestContext,
+    storage: &MediaStorage,
+    user: &User,
+    blob: &MediaBlob,
+    headers: &[(&str, &str)],
+) -> Response {
+    use axum::routing::get;
+    use server::media::handlers::get_media_content;
+
+    let app = Router::new()
+        .route("/api/media/{id}/content", get(get_media_content))
+        .layer(Extension(AuthenticatedUser(user.clone())))
+        .layer(Extension(ctx.db.clone()))
+        .layer(Extension(storage.clone()));
+    let mut request = Request::get(format!("/api/media/{}/content", blob.id));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    app.oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_media_content_hidden_from_users_who_cannot_see_the_blob() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("content").await;
+    let other = ctx.user("nocontent").await;
+    for backend in LOCAL_BACKENDS {
+        let (storage, blob, _) = content_blob(&ctx, backend, &owner).await;
+        let response = fetch_content(&ctx, &storage, &other, &blob, &[]).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", backend);
+    }
+}
+
+#[tokio::test]
+async fn test_media_content_served_with_caching_headers() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("content").await;
+    for backend in LOCAL_BACKENDS {
+        let (storage, blob, content) = content_blob(&ctx, backend, &owner).await;
+        let response = fetch_content(&ctx, &storage, &owner, &blob, &[]).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", backend);
+        assert_eq!(response.headers()["content-type"], "audio/ogg");
+        assert_eq!(response.headers()["accept-ranges"], "bytes");
+        assert_eq!(
+            response.headers()["content-length"],
+            content.len().to_string().as_str()
+        );
+        assert_eq!(
+            response.headers()["etag"],
+            format!("\"{}\"", blob.sha256).as_str()
+        );
+        assert!(response.headers().contains_key("last-modified"));
+        assert_eq!(body_of(response).await, content);
+    }
+}
+
+#[tokio::test]
+async fn test_media_content_ranges() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("ranges").await;
+    for backend in LOCAL_BACKENDS {
+        let (storage, blob, content) = content_blob(&ctx, backend, &owner).await;
+        let fetch = |headers| fetch_content(&ctx, &storage, &owner, &blob, headers);
+
+        let response = fetch(&[("range", "bytes=0-3")]).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::PARTIAL_CONTENT,
+            "{}",
+            backend
+        );
+        assert_eq!(body_of(response).await, &content[..4]);
+
+        let response = fetch(&[("range", "bytes=-7")]).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body_of(response).await, &content[content.len() - 7..]);
+
+        // A range is only honoured while If-Range still matches
+        let response = fetch(&[("range", "bytes=0-3"), ("if-range", "\"stale\"")]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = fetch(&[("range", "bytes=100000-")]).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    }
+}
+
+#[tokio::test]
+async fn test_media_content_conditional_requests() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("conditional").await;
+    for backend in LOCAL_BACKENDS {
+        let (storage, blob, _) = content_blob(&ctx, backend, &owner).await;
+        let response = fetch_content(&ctx, &storage, &owner, &blob, &[]).await;
+        let last_modified = response.headers()["last-modified"]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        // Answered without content
+        let etag = format!("\"{}\"", blob.sha256);
+        let response = fetch_content(
+            &ctx,
+            &storage,
+            &owner,
+            &blob,
+            &[("if-none-match", etag.as_str())],
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{}", backend);
+        let response = fetch_content(
+            &ctx,
+            &storage,
+            &owner,
+            &blob,
+            &[("if-modified-since", last_modified.as_str())],
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+}
+
+#[tokio::test]
+async fn test_media_content_not_served_as_static_files() {
+    use axum::middleware::{from_fn, Next};
+    use server::config::AppConfig;
+    use server::media::storage::sharded_key;
+    use server::static_filez::enhanced::build_enhanced_private_routes;
+    use tower_sessions::{MemoryStore, Session, SessionManagerLayer};
+
+    let Some(mut ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("static").await;
+
+    // The default layout, below a scratch directory
+    let root = ctx.directory("static-test");
+    let defaults = AppConfig::default();
+    let under_root = |path: &str| root.join(path).to_string_lossy().into_owned();
+    ctx.config.static_files.assets_directory = under_root(&defaults.static_files.assets_directory);
+    ctx.config.static_files.public_directory = under_root(&defaults.static_files.public_directory);
+    ctx.config.static_files.private_directory =
+        under_root(&defaults.static_files.private_directory);
+    ctx.config.media.storage.directory = under_root(&defaults.media.storage.directory);
+    ctx.config.validate().unwrap();
+
+    std::fs::create_dir_all(&ctx.config.static_files.private_directory).unwrap();
+    std::fs::write(
+        root.join(&defaults.static_files.private_directory)
+            .join("page.txt"),
+        "private page",
+    )
+    .unwrap();
+
+    let storage = ctx.storage_with(MediaStorageBackend::Filesystem);
+    let content = format!("private content for {}", ctx.suffix).into_bytes();
+    let blob = create_blob(
+        &ctx,
+        &storage,
+        CreateMediaBlob {
+            mime: Some("text/plain".to_string()),
+            ..common::blob(&owner, content)
+        },
+    )
+    .await;
+    assert!(blob.local_path.is_none());
+
+    // Signed in as the blob's owner, who may see its content
+    let owner_id = owner.id;
+    let app = build_enhanced_private_routes(&ctx.config)
+        .layer(from_fn(
+            move |session: Session, request: Request<Body>, next: Next| async move {
+                session.insert("user_id", owner_id).await.unwrap();
+                next.run(request).await
+            },
+        ))
+        .layer(Extension(ctx.db.clone()))
+        .layer(Extension(storage.clone()))
+        .layer(SessionManagerLayer::new(MemoryStore::default()));
+    let status_of = |path: String| {
+        let app = app.clone();
+        async move {
+            app.oneshot(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status()
+        }
+    };
+
+    assert_eq!(
+        status_of("/private/page.txt".to_string()).await,
+        StatusCode::OK
+    );
+
+    let key = sharded_key(&blob.sha256);
+    for path in [
+        format!("/private/{}", key),
+        format!("/private/uploads/{}", key),
+        format!(
+            "/private/../../{}/{}",
+            defaults.media.storage.directory, key
+        ),
+        format!(
+            "/private/..%2F..%2F{}%2F{}",
+            defaults.media.storage.directory.replace('/', "%2F"),
+            key.replace('/', "%2F")
+        ),
+    ] {
+        assert_ne!(status_of(path.clone()).await, StatusCode::OK, "{}", path);
+    }
+}
+
+/// An image, an undecodable image and an audio blob, with their jobs run
+struct ProcessedImages {
+    owner: User,
+    image: MediaBlob,
+    broken: MediaBlob,
+    audio: MediaBlob,
+}
+
+impl ProcessedImages {
+    /// Runs the queued jobs, so the caller must hold `MEDIA_JOBS`
+    async fn new(ctx: &TestContext, storage: &MediaStorage) -> Self {
+        let owner = ctx.user("images").await;
+
+        // A unique image, so its content is not shared with other tests
+        let mut image = image::RgbImage::from_pixel(800, 400, image::Rgb([10, 120, 200]));
+        image.put_pixel(0, 0, image::Rgb([ctx.suffix.as_bytes()[0], 0, 0]));
+        image.put_pixel(1, 0, image::Rgb([ctx.suffix.as_bytes()[1], 0, 0]));
+        let mut png = Vec::new();
+        image
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+
+        let create = |data: Vec<u8>, mime: &str| CreateMediaBlob {
+            mime: Some(mime.to_string()),
+            metadata: serde_json::json!({"title": "sunset"}),
+            ..common::blob(&owner, data)
+        };
+        let image = create_blob(ctx, storage, create(png, "image/png")).await;
+        let broken = create_blob(
+            ctx,
+            storage,
+            create(
+                format!("not a png {}", ctx.suffix).into_bytes(),
+                "image/png",
+            ),
+        )
+        .await;
+        let audio = create_blob(
+            ctx,
+            storage,
+            create(format!("audio {}", ctx.suffix).into_bytes(), "audio/ogg"),
+        )
+        .await;
+        run_jobs(ctx, storage).await;
+
+        Self {
+            owner,
+            image,
+            broken,
+            audio,
+        }
+    }
+}
+
+fn image_test_context(mut ctx: TestContext) -> TestContext {
+    ctx.config.media.processing.thumbnail_sizes = vec![64, 256];
+    ctx
+}
+
+#[tokio::test]
+async fn test_image_jobs_are_only_queued_for_images() {
+    use server::media::{MediaJobKind, MediaJobRepository};
+
+    let _jobs = MEDIA_JOBS.lock().await;
+    let Some(ctx) = TestContext::new().await.map(image_test_context) else {
+        return;
+    };
+    let owner = ctx.user("queued").await;
+    let storage = ctx.storage();
+    let jobs = MediaJobRepository::new(&ctx.db);
+
+    let create = |data: String, mime: &str| CreateMediaBlob {
+        mime: Some(mime.to_string()),
+        ..common::blob(&owner, data.into_bytes())
+    };
+    let image = create_blob(
+        &ctx,
+        &storage,
+        create(format!("png {}", ctx.suffix), "image/png"),
+    )
+    .await;
+    let audio = create_blob(
+        &ctx,
+        &storage,
+        create(format!("ogg {}", ctx.suffix), "audio/ogg"),
+    )
+    .await;
+
+    let job = jobs
+        .find(image.id, MediaJobKind::ImageDerivatives)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(job.status, "pending");
+    assert!(jobs
+        .find(audio.id, MediaJobKind::ImageDerivatives)
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_image_derivatives_are_recorded() {
+    use server::media::{MediaJobKind, MediaJobRepository};
+
+    let _jobs = MEDIA_JOBS.lock().await;
+    let Some(ctx) = TestContext::new().await.map(image_test_context) else {
+        return;
+    };
+    let storage = ctx.storage();
+    let images = ProcessedImages::new(&ctx, &storage).await;
+
+    let job = MediaJobRepository::new(&ctx.db)
+        .find(images.image.id, MediaJobKind::ImageDerivatives)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(job.status, "done");
+    assert_eq!(job.attempts, 1);
+
+    let processed = ctx
+        .media(&storage)
+        .get_blob(images.image.id, false, &images.owner)
+        .await
+        .unwrap();
+    assert_eq!(processed.metadata["title"], "sunset");
+    let info = processed.image_metadata().unwrap();
+    assert_eq!((info.width, info.height, info.orientation), (800, 400, 1));
+    assert_eq!(info.format, "image/png");
+
+    let thumbnails = processed.thumbnails();
+    assert_eq!(thumbnails.len(), 2);
+    assert_eq!(
+        (
+            thumbnails[0].size,
+            thumbnails[0].width,
+            thumbnails[0].height
+        ),
+        (64, 64, 32)
+    );
+    assert_eq!(thumbnails[1].size, 256);
+    assert_eq!(thumbnails[1].mime, "image/jpeg");
+}
+
+#[tokio::test]
+async fn test_undecodable_images_fail_without_retrying() {
+    use server::media::{MediaJobKind, MediaJobRepository};
+
+    let _jobs = MEDIA_JOBS.lock().await;
+    let Some(ctx) = TestContext::new().await.map(image_test_context) else {
+        return;
+    };
+    let storage = ctx.storage();
+    let images = ProcessedImages::new(&ctx, &storage).await;
+
+    let job = MediaJobRepository::new(&ctx.db)
+        .find(images.broken.id, MediaJobKind::ImageDerivatives)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(job.status, "failed");
+    assert!(job.last_error.unwrap().contains("decode"));
+}
+
+#[tokio::test]
+async fn test_thumbnails_belong_to_the_owner_but_are_not_listed() {
+    let _jobs = MEDIA_JOBS.lock().await;
+    let Some(ctx) = TestContext::new().await.map(image_test_context) else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let images = ProcessedImages::new(&ctx, &storage).await;
+    let owner = &images.owner;
+
+    let processed = service.find_blob(images.image.id).await.unwrap();
+    let thumbnail = service
+        .get_blob(processed.thumbnails()[0].blob_id, false, owner)
+        .await
+        .unwrap();
+    assert_eq!(thumbnail.owner_user_id, Some(owner.id));
+    let listed = service
+        .list_blobs(
+            MediaBlobQuery {
+                limit: Some(1000),
+                ..Default::default()
+            },
+            owner,
+        )
+        .await
+        .unwrap();
+    assert_eq!(listed.len(), 3);
+    assert!(listed.iter().all(|b| b.id != thumbnail.id));
+}
+
+#[tokio::test]
+async fn test_image_previews_pick_the_smallest_covering_thumbnail() {
+    use axum::routing::get;
+    use server::media::handlers::get_media_preview;
+
+    let _jobs = MEDIA_JOBS.lock().await;
+    let Some(ctx) = TestContext::new().await.map(image_test_context) else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let images = ProcessedImages::new(&ctx, &storage).await;
+
+    let preview = |id: uuid::Uuid, query: &str| {
+        let app = Router::new()
+            .route("/api/media/{id}/preview", get(get_media_preview))
+            .layer(Extension(AuthenticatedUser(images.owner.clone())))
+            .layer(Extension(ctx.db.clone()))
+            .layer(Extension(storage.clone()));
+        app.oneshot(
+            Request::get(format!("/api/media/{}/preview{}", id, query))
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    let response = preview(images.image.id, "").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "image/jpeg");
+    let decoded = image::load_from_memory(&body_of(response).await).unwrap();
+    assert_eq!((decoded.width(), decoded.height()), (64, 32));
+
+    let thumbnails = service
+        .find_blob(images.image.id)
+        .await
+        .unwrap()
+        .thumbnails();
+    let larger = service.find_blob(thumbnails[1].blob_id).await.unwrap();
+    let response = preview(images.image.id, "?size=100").await.unwrap();
+    assert_eq!(
+        response.headers()["etag"],
+        format!("\"{}\"", larger.sha256).as_str()
+    );
+
+    // Large enough boxes get the image itself
+    let response = preview(images.image.id, "?size=1000").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "image/png");
+
+    // Nothing to preview for other media and failed images
+    let response = preview(images.audio.id, "").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = preview(images.broken.id, "").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_thumbnails_are_deleted_with_their_image() {
+    let _jobs = MEDIA_JOBS.lock().await;
+    let Some(ctx) = TestContext::new().await.map(image_test_context) else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let images = ProcessedImages::new(&ctx, &storage).await;
+
+    let processed = service.find_blob(images.image.id).await.unwrap();
+    let thumbnail = service
+        .find_blob(processed.thumbnails()[0].blob_id)
+        .await
+        .unwrap();
+    assert!(service
+        .find_object(&thumbnail.sha256)
+        .await
+        .unwrap()
+        .is_some());
+
+    assert!(service
+        .delete_blob(images.image.id, &images.owner)
+        .await
+        .unwrap());
+    assert!(service.find_blob(thumbnail.id).await.is_err());
+    assert!(service
+        .find_object(&thumbnail.sha256)
+        .await
+        .unwrap()
+        .is_none());
+}
+
+/// A WAV file and content that is not audio, with their jobs run
+struct ProcessedAudio {
+    audio: MediaBlob,
+    broken: MediaBlob,
+}
+
+impl ProcessedAudio {
+    /// Runs the queued jobs, so the caller must hold `MEDIA_JOBS`
+    async fn new(ctx: &TestContext, storage: &MediaStorage) -> Self {
+        let owner = ctx.user("av").await;
+
+        // Two seconds of 16-bit mono silence at 8kHz, made unique by a trailing chunk
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF\0\0\0\0WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&[1, 0, 1, 0]);
+        wav.extend_from_slice(&8000u32.to_le_bytes());
+        wav.extend_from_slice(&16000u32.to_le_bytes());
+        wav.extend_from_slice(&[2, 0, 16, 0]);
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&32000u32.to_le_bytes());
+        wav.resize(wav.len() + 32000, 0);
+        wav.extend_from_slice(b"note");
+        wav.extend_from_slice(&32u32.to_le_bytes());
+        wav.extend_from_slice(ctx.suffix.as_bytes());
+
+        let create = |data: Vec<u8>, mime: &str| CreateMediaBlob {
+            mime: Some(mime.to_string()),
+            metadata: serde_json::json!({"title": "silence"}),
+            ..common::blob(&owner, data)
+        };
+        let audio = create_blob(ctx, storage, create(wav, "audio/wav")).await;
+        let broken = create_blob(
+            ctx,
+            storage,
+            create(
+                format!("not audio {}", ctx.suffix).into_bytes(),
+                "video/mp4",
+            ),
+        )
+        .await;
+        run_jobs(ctx, storage).await;
+
+        Self { audio, broken }
+    }
+}
+
+#[tokio::test]
+async fn test_av_metadata_extracted_from_audio() {
+    use server::media::{AvTrackKind, MediaJobKind, MediaJobRepository};
+
+    let _jobs = MEDIA_JOBS.lock().await;
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let processed = ProcessedAudio::new(&ctx, &storage).await;
+
+    let job = MediaJobRepository::new(&ctx.db)
+        .find(processed.audio.id, MediaJobKind::AvMetadata)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(job.status, "done");
+
+    let audio = ctx
+        .media(&storage)
+        .find_blob(processed.audio.id)
+        .await
+        .unwrap();
+    assert_eq!(audio.metadata["title"], "silence");
+    let av = audio.av_metadata().unwrap();
+    assert_eq!(av.container, "wav");
+    assert_eq!(av.duration, Some(2.0));
+    assert_eq!(av.bitrate, Some(128_000));
+    assert_eq!(av.tracks.len(), 1);
+    assert_eq!(av.tracks[0].kind, AvTrackKind::Audio);
+    assert_eq!(av.tracks[0].sample_rate, Some(8000));
+    assert_eq!(av.tracks[0].channels, Some(1));
+}
+
+#[tokio::test]
+async fn test_unrecognised_av_content_fails_without_retrying() {
+    use server::media::{MediaJobKind, MediaJobRepository};
+
+    let _jobs = MEDIA_JOBS.lock().await;
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let processed = ProcessedAudio::new(&ctx, &storage).await;
+
+    let job = MediaJobRepository::new(&ctx.db)
+        .find(processed.broken.id, MediaJobKind::AvMetadata)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(job.status, "failed");
+    assert!(ctx
+        .media(&storage)
+        .find_blob(processed.broken.id)
+        .await
+        .unwrap()
+        .av_metadata()
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_failed_jobs_can_be_queued_again() {
+    use server::media::{MediaJobKind, MediaJobRepository};
+
+    let _jobs = MEDIA_JOBS.lock().await;
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let processed = ProcessedAudio::new(&ctx, &storage).await;
+    let jobs = MediaJobRepository::new(&ctx.db);
+
+    // With a fresh attempt count
+    let requeued = jobs
+        .requeue(MediaJobKind::AvMetadata, Some(processed.broken.id), true)
+        .await
+        .unwrap();
+    assert_eq!(requeued, 1);
+    let job = jobs
+        .find(processed.broken.id, MediaJobKind::AvMetadata)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!((job.status.as_str(), job.attempts), ("pending", 0));
+}
+
+#[tokio::test]
+async fn test_finished_jobs_are_only_queued_again_when_asked_for() {
+    use server::media::{MediaJobKind, MediaJobRepository};
+
+    let _jobs = MEDIA_JOBS.lock().await;
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let processed = ProcessedAudio::new(&ctx, &storage).await;
+    let jobs = MediaJobRepository::new(&ctx.db);
+    let audio = Some(processed.audio.id);
+
+    let requeued = jobs
+        .requeue(MediaJobKind::AvMetadata, audio, true)
+        .await
+        .unwrap();
+    assert_eq!(requeued, 0);
+    let requeued = jobs
+        .requeue(MediaJobKind::AvMetadata, audio, false)
+        .await
+        .unwrap();
+    assert_eq!(requeued, 1);
+
+    // Jobs only apply to matching MIME types
+    let requeued = jobs
+        .requeue(MediaJobKind::ImageDerivatives, audio, false)
+        .await
+        .unwrap();
+    assert_eq!(requeued, 0);
+}
+
+/// A PDF stored with its detected type, and its hash
+async fn stored_pdf(ctx: &TestContext, storage: &MediaStorage) -> String {
+    let owner = ctx.user("sniff").await;
+    let pdf = format!("%PDF-1.7\n% {}\n", ctx.suffix).into_bytes();
+    let sha256 = sha256_hex(&pdf);
+    create_blob(
+        ctx,
+        storage,
+        CreateMediaBlob {
+            mime: Some("application/pdf".to_string()),
+            ..common::blob(&owner, pdf)
+        },
+    )
+    .await;
+    sha256
+}
+
+#[tokio::test]
+async fn test_new_content_is_typed_by_its_bytes() {
+    use server::config::MimeTypeRules;
+    use server::media::ContentTypeError;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("sniff").await;
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let rules = MimeTypeRules::default();
+    let pdf = format!("%PDF-1.7\n% {}\n", ctx.suffix).into_bytes();
+    let sha256 = sha256_hex(&pdf);
+
+    let mismatch = service
+        .check_content_type(Some("image/png"), Some(&pdf), &sha256, &rules)
+        .await;
+    assert!(matches!(
+        mismatch,
+        Err(WebauthnError::MediaContentType(
+            ContentTypeError::Mismatch { .. }
+        ))
+    ));
+    let mime = service
+        .check_content_type(None, Some(&pdf), &sha256, &rules)
+        .await
+        .unwrap();
+    assert_eq!(mime.as_deref(), Some("application/pdf"));
+
+    // The detected type is recorded with the content
+    create_blob(
+        &ctx,
+        &storage,
+        CreateMediaBlob {
+            mime,
+            ..common::blob(&owner, pdf)
+        },
+    )
+    .await;
+    let object = service.find_object(&sha256).await.unwrap().unwrap();
+    assert_eq!(object.content_type.as_deref(), Some("application/pdf"));
+}
+
+#[tokio::test]
+async fn test_stored_content_is_typed_by_its_recorded_type() {
+    use server::config::MimeTypeRules;
+    use server::media::ContentTypeError;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let sha256 = stored_pdf(&ctx, &storage).await;
+
+    // Without the bytes
+    let mismatch = service
+        .check_content_type(Some("image/png"), None, &sha256, &MimeTypeRules::default())
+        .await;
+    assert!(matches!(
+        mismatch,
+        Err(WebauthnError::MediaContentType(
+            ContentTypeError::Mismatch { .. }
+        ))
+    ));
+    let denied = MimeTypeRules {
+        allow: Vec::new(),
+        deny: vec!["application/pdf".to_string()],
+    };
+    let not_allowed = service
+        .check_content_type(Some("application/octet-stream"), None, &sha256, &denied)
+        .await;
+    assert!(matches!(
+        not_allowed,
+        Err(WebauthnError::MediaContentType(ContentTypeError::NotAllowed(mime)))
+            if mime == "application/pdf"
+    ));
+}
+
+#[tokio::test]
+async fn test_content_stored_before_detection_is_only_checked_against_rules() {
+    use server::config::MimeTypeRules;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let sha256 = stored_pdf(&ctx, &storage).await;
+
+    sqlx::query("UPDATE media_objects SET content_type = NULL WHERE sha256 = $1")
+        .bind(&sha256)
+        .execute(&ctx.pool)
+        .await
+        .unwrap();
+    let mime = service
+        .check_content_type(Some("image/png"), None, &sha256, &MimeTypeRules::default())
+        .await
+        .unwrap();
+    assert_eq!(mime.as_deref(), Some("image/png"));
+}
+
+/// Infected and clean uploads, scanned by the EICAR test scanner
+struct ScannedUploads {
+    owner: User,
+    infected: MediaBlob,
+    clean: MediaBlob,
+    eicar: Vec<u8>,
+}
+
+impl ScannedUploads {
+    /// Uploads both without running their scans
+    async fn new(ctx: &TestContext, storage: &MediaStorage) -> Self {
+        use server::media::scan::EICAR_TEST_FILE;
+
+        let owner = ctx.user("scan").await;
+        let eicar = format!("{}\n{}\n", EICAR_TEST_FILE, ctx.suffix).into_bytes();
+        let clean = format!("nothing to see here {}\n", ctx.suffix).into_bytes();
+        let create = |data: Vec<u8>| CreateMediaBlob {
+            mime: Some("text/plain".to_string()),
+            ..common::blob(&owner, data)
+        };
+        let infected = create_blob(ctx, storage, create(eicar.clone())).await;
+        let clean = create_blob(ctx, storage, create(clean)).await;
+        Self {
+            owner,
+            infected,
+            clean,
+            eicar,
+        }
+    }
+}
+
+fn scanning_test_context(mut ctx: TestContext) -> TestContext {
+    use server::config::MediaScannerKind;
+
+    ctx.config.media.scanning.enabled = true;
+    ctx.config.media.scanning.scanner = MediaScannerKind::Eicar;
+    ctx
+}
+
+#[tokio::test]
+async fn test_uploads_are_withheld_until_scanned_clean() {
+    use server::media::ScanStatus;
+
+    let _jobs = MEDIA_JOBS.lock().await;
+    let Some(ctx) = TestContext::new().await.map(scanning_test_context) else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let uploads = ScannedUploads::new(&ctx, &storage).await;
+    assert_eq!(uploads.clean.scan_status, Some(ScanStatus::Pending));
+
+    assert!(matches!(
+        service
+            .get_blob(uploads.clean.id, true, &uploads.owner)
+            .await,
+        Err(WebauthnError::MediaQuarantined(ScanStatus::Pending))
+    ));
+
+    run_jobs(&ctx, &storage).await;
+    let blob = service
+        .get_blob(uploads.clean.id, true, &uploads.owner)
+        .await
+        .unwrap();
+    assert_eq!(blob.scan_status, Some(ScanStatus::Clean));
+    assert!(blob.data.is_some());
+    assert_eq!(blob.metadata["scan"]["scanner"], "eicar");
+}
+
+#[tokio::test]
+async fn test_infected_uploads_are_quarantined() {
+    use server::media::{MediaRepository, ScanStatus};
+
+    let _jobs = MEDIA_JOBS.lock().await;
+    let Some(ctx) = TestContext::new().await.map(scanning_test_context) else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let repository = MediaRepository::new(&ctx.db);
+    let uploads = ScannedUploads::new(&ctx, &storage).await;
+    assert_eq!(uploads.infected.scan_status, Some(ScanStatus::Pending));
+    run_jobs(&ctx, &storage).await;
+
+    assert!(matches!(
+        service
+            .get_blob(uploads.infected.id, true, &uploads.owner)
+            .await,
+        Err(WebauthnError::MediaQuarantined(ScanStatus::Infected))
+    ));
+    let blob = repository
+        .get_by_id_without_data(uploads.infected.id)
+        .await
+        .unwrap();
+    assert_eq!(blob.scan_status, Some(ScanStatus::Infected));
+    assert_eq!(blob.metadata["scan"]["signature"], "Eicar-Test-Signature");
+    assert_eq!(
+        repository.quarantine_status(&blob.sha256).await.unwrap(),
+        Some(ScanStatus::Infected)
+    );
+    assert_eq!(
+        repository
+            .quarantine_status(&uploads.clean.sha256)
+            .await
+            .unwrap(),
+        None
+    );
+}
+
+#[tokio::test]
+async fn test_known_content_inherits_its_scan_verdict() {
+    use server::media::ScanStatus;
+
+    let _jobs = MEDIA_JOBS.lock().await;
+    let Some(ctx) = TestContext::new().await.map(scanning_test_context) else {
+        return;
+    };
+    let storage = ctx.storage();
+    let uploads = ScannedUploads::new(&ctx, &storage).await;
+    run_jobs(&ctx, &storage).await;
+
+    // Without a new scan
+    let again = create_blob(
+        &ctx,
+        &storage,
+        CreateMediaBlob {
+            mime: Some("text/plain".to_string()),
+            ..common::blob(&uploads.owner, uploads.eicar.clone())
+        },
+    )
+    .await;
+    assert_eq!(again.scan_status, Some(ScanStatus::Infected));
+}
+
+/// Members get 100 bytes
+fn quota_test_context(mut ctx: TestContext) -> TestContext {
+    ctx.config.media.quotas.member_bytes = Some(100);
+    ctx
+}
+
+/// Content of `len` bytes unique to the test, padded with `tag`
+fn quota_content(ctx: &TestContext, len: usize, tag: u8) -> Vec<u8> {
+    let mut data = ctx.suffix.as_bytes().to_vec();
+    data.resize(len, tag);
+    data
+}
+
+#[tokio::test]
+async fn test_media_usage_counts_the_owners_blobs() {
+    let Some(ctx) = TestContext::new().await.map(quota_test_context) else {
+        return;
+    };
+    let owner = ctx.user("quota").await;
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let quotas = &ctx.config.media.quotas;
+
+    let usage = service.usage(owner.id, quotas).await.unwrap();
+    assert_eq!((usage.bytes, usage.blob_count), (0, 0));
+    assert_eq!(usage.quota_bytes, Some(100));
+
+    create_blob(
+        &ctx,
+        &storage,
+        common::blob(&owner, quota_content(&ctx, 60, b'a')),
+    )
+    .await;
+    let usage = service.usage(owner.id, quotas).await.unwrap();
+    assert_eq!((usage.bytes, usage.blob_count), (60, 1));
+    assert_eq!(usage.remaining(), Some(40));
+}
+
+#[tokio::test]
+async fn test_media_over_quota_is_refused() {
+    use server::media::QuotaExceeded;
+
+    let Some(ctx) = TestContext::new().await.map(quota_test_context) else {
+        return;
+    };
+    let owner = ctx.user("quota").await;
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let quotas = &ctx.config.media.quotas;
+    create_blob(
+        &ctx,
+        &storage,
+        common::blob(&owner, quota_content(&ctx, 60, b'a')),
+    )
+    .await;
+
+    // Whether checked up front or when the blob is created
+    assert!(matches!(
+        service.check_quota(owner.id, 41, quotas).await,
+        Err(WebauthnError::MediaQuota(QuotaExceeded {
+            used: 60,
+            requested: 41,
+            quota: 100
+        }))
+    ));
+    let refused = service
+        .create_blob(
+            common::blob(&owner, quota_content(&ctx, 50, b'b')),
+            &ctx.config.media,
+        )
+        .await;
+    assert!(matches!(refused, Err(WebauthnError::MediaQuota(_))));
+    let usage = service.usage(owner.id, quotas).await.unwrap();
+    assert_eq!((usage.bytes, usage.blob_count), (60, 1));
+}
+
+#[tokio::test]
+async fn test_media_user_quota_overrides_the_roles() {
+    let Some(ctx) = TestContext::new().await.map(quota_test_context) else {
+        return;
+    };
+    let owner = ctx.user("quota").await;
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let quotas = &ctx.config.media.quotas;
+    create_blob(
+        &ctx,
+        &storage,
+        common::blob(&owner, quota_content(&ctx, 60, b'a')),
+    )
+    .await;
+
+    let usage = service
+        .set_quota(owner.id, Some(1000), quotas)
+        .await
+        .unwrap();
+    assert_eq!(usage.quota_override, Some(1000));
+    assert_eq!(usage.quota_bytes, Some(1000));
+    create_blob(
+        &ctx,
+        &storage,
+        common::blob(&owner, quota_content(&ctx, 50, b'b')),
+    )
+    .await;
+    let usage = service.usage(owner.id, quotas).await.unwrap();
+    assert_eq!((usage.bytes, usage.blob_count), (110, 2));
+    assert!(service
+        .list_usage(quotas)
+        .await
+        .unwrap()
+        .iter()
+        .any(|usage| usage.user_id == owner.id && usage.bytes == 110));
+
+    // Clearing the override restores the role's quota
+    let usage = service.set_quota(owner.id, None, quotas).await.unwrap();
+    assert_eq!(usage.quota_bytes, Some(100));
+}
+
+#[tokio::test]
+async fn test_media_deleting_blobs_frees_their_share() {
+    let Some(ctx) = TestContext::new().await.map(quota_test_context) else {
+        return;
+    };
+    let owner = ctx.user("quota").await;
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let quotas = &ctx.config.media.quotas;
+    let first = create_blob(
+        &ctx,
+        &storage,
+        common::blob(&owner, quota_content(&ctx, 60, b'a')),
+    )
+    .await;
+    create_blob(
+        &ctx,
+        &storage,
+        common::blob(&owner, quota_content(&ctx, 40, b'b')),
+    )
+    .await;
+
+    assert!(service.delete_blob(first.id, &owner).await.unwrap());
+    let usage = service.usage(owner.id, quotas).await.unwrap();
+    assert_eq!((usage.bytes, usage.blob_count), (40, 1));
+}
+
+/// Three blobs of `owner`'s, unique to the test
+async fn three_blobs(ctx: &TestContext, storage: &MediaStorage, owner: &User) -> Vec<uuid::Uuid> {
+    let mut blobs = Vec::new();
+    for tag in [b'a', b'b', b'c'] {
+        let mut data = ctx.suffix.as_bytes().to_vec();
+        data.push(tag);
+        blobs.push(
+            create_blob(ctx, storage, common::blob(owner, data))
+                .await
+                .id,
+        );
+    }
+    blobs
+}
+
+#[tokio::test]
+async fn test_media_tags_are_normalised_and_set_by_the_owner() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("album").await;
+    let other = ctx.user("album-o").await;
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let blobs = three_blobs(&ctx, &storage, &owner).await;
+
+    let tagged = service
+        .set_tags(
+            blobs[0],
+            &[
+                " Holiday ".to_string(),
+                "beach".to_string(),
+                "holiday".to_string(),
+            ],
+            &owner,
+        )
+        .await
+        .unwrap();
+    assert_eq!(tagged.tags, vec!["holiday", "beach"]);
+    assert!(service
+        .set_tags(blobs[2], &["x".to_string()], &other)
+        .await
+        .is_err());
+    assert_eq!(
+        service
+            .get_blob(blobs[0], false, &owner)
+            .await
+            .unwrap()
+            .tags,
+        vec!["holiday", "beach"]
+    );
+}
+
+#[tokio::test]
+async fn test_media_tags_are_listed_and_filtered_per_user() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("album").await;
+    let other = ctx.user("album-o").await;
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let blobs = three_blobs(&ctx, &storage, &owner).await;
+    service
+        .set_tags(
+            blobs[0],
+            &["holiday".to_string(), "beach".to_string()],
+            &owner,
+        )
+        .await
+        .unwrap();
+    service
+        .set_tags(blobs[1], &["holiday".to_string()], &owner)
+        .await
+        .unwrap();
+
+    let tags = service.list_tags(Some("HOL"), 10, &owner).await.unwrap();
+    assert_eq!(tags.len(), 1);
+    assert_eq!((tags[0].tag.as_str(), tags[0].count), ("holiday", 2));
+    assert!(service
+        .list_tags(None, 10, &other)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Listing by tags matches blobs carrying all of them
+    let listed = service
+        .list_blobs(
+            MediaBlobQuery {
+                tags: vec!["HOLIDAY".to_string(), "beach".to_string()],
+                ..Default::default()
+            },
+            &owner,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        listed.iter().map(|b| b.id).collect::<Vec<_>>(),
+        vec![blobs[0]]
+    );
+}
+
+#[tokio::test]
+async fn test_media_collections_keep_blobs_in_order_and_are_private() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("album").await;
+    let other = ctx.user("album-o").await;
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let blobs = three_blobs(&ctx, &storage, &owner).await;
+
+    let collection = service
+        .create_collection(" Summer ", Some("Trip photos"), None, &owner)
+        .await
+        .unwrap();
+    assert_eq!(collection.name, "Summer");
+    assert!(matches!(
+        service
+            .create_collection("Summer", None, None, &owner)
+            .await,
+        Err(WebauthnError::BadRequest)
+    ));
+
+    let collection = service
+        .add_to_collection(
+            collection.id,
+            &[blobs[2], blobs[0], blobs[2], blobs[1]],
+            &owner,
+        )
+        .await
+        .unwrap();
+    assert_eq!(collection.blob_count, 3);
+    assert_eq!(
+        service
+            .collection_blob_ids(collection.id, &owner)
+            .await
+            .unwrap(),
+        vec![blobs[2], blobs[0], blobs[1]]
+    );
+    assert!(service.get_collection(collection.id, &other).await.is_err());
+    assert!(service
+        .list_blobs(
+            MediaBlobQuery {
+                collection_id: Some(collection.id),
+                ..Default::default()
+            },
+            &other,
+        )
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_media_collections_are_reordered_as_a_whole() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("album").await;
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let blobs = three_blobs(&ctx, &storage, &owner).await;
+    let collection = service
+        .create_collection("Summer", None, None, &owner)
+        .await
+        .unwrap();
+    service
+        .add_to_collection(collection.id, &[blobs[2], blobs[0], blobs[1]], &owner)
+        .await
+        .unwrap();
+
+    let order = service
+        .reorder_collection(collection.id, &blobs, &owner)
+        .await
+        .unwrap();
+    assert_eq!(order, blobs);
+    assert!(matches!(
+        service
+            .reorder_collection(collection.id, &blobs[..2], &owner)
+            .await,
+        Err(WebauthnError::BadRequest)
+    ));
+}
+
+#[tokio::test]
+async fn test_media_collections_are_listed_and_updated() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("album").await;
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let blobs = three_blobs(&ctx, &storage, &owner).await;
+    let collection = service
+        .create_collection("Summer", Some("Trip photos"), None, &owner)
+        .await
+        .unwrap();
+    service
+        .add_to_collection(collection.id, &blobs, &owner)
+        .await
+        .unwrap();
+    service
+        .set_tags(blobs[0], &["holiday".to_string()], &owner)
+        .await
+        .unwrap();
+    service
+        .set_tags(blobs[1], &["holiday".to_string()], &owner)
+        .await
+        .unwrap();
+
+    // Listing combines the collection with tags
+    service
+        .remove_from_collection(collection.id, blobs[1], &owner)
+        .await
+        .unwrap();
+    let listed = service
+        .list_blobs(
+            MediaBlobQuery {
+                collection_id: Some(collection.id),
+                tags: vec!["holiday".to_string()],
+                ..Default::default()
+            },
+            &owner,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        listed.iter().map(|b| b.id).collect::<Vec<_>>(),
+        vec![blobs[0]]
+    );
+
+    // An empty description clears it
+    let renamed = service
+        .update_collection(collection.id, Some("Summer 2026"), Some(""), None, &owner)
+        .await
+        .unwrap();
+    assert_eq!(renamed.name, "Summer 2026");
+    assert_eq!(renamed.description, None);
+    assert_eq!(service.list_collections(&owner).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_media_collections_and_blobs_are_deleted_independently() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("album").await;
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let blobs = three_blobs(&ctx, &storage, &owner).await;
+    let collection = service
+        .create_collection("Summer", None, None, &owner)
+        .await
+        .unwrap();
+    service
+        .add_to_collection(collection.id, &blobs[..2], &owner)
+        .await
+        .unwrap();
+
+    // Deleting a blob drops it from collections; deleting a collection keeps blobs
+    assert!(service.delete_blob(blobs[1], &owner).await.unwrap());
+    assert_eq!(
+        service
+            .get_collection(collection.id, &owner)
+            .await
+            .unwrap()
+            .blob_count,
+        1
+    );
+    assert!(service
+        .delete_collection(collection.id, &owner)
+        .await
+        .unwrap());
+    assert!(service.get_blob(blobs[0], false, &owner).await.is_ok());
+}
+
+/// Three named blobs to search for, behind a word unique to the test
+struct SearchableMedia {
+    owner: User,
+    blobs: Vec<uuid::Uuid>,
+    word: String,
+}
+
+impl SearchableMedia {
+    async fn new(ctx: &TestContext, storage: &MediaStorage) -> Self {
+        let owner = ctx.user("search").await;
+        // Keeps other tests' blobs out of the results
+        let word = format!("w{}", &ctx.suffix[..12]);
+        let mut blobs = Vec::new();
+        for (i, (filename, mime, metadata)) in [
+            (
+                format!("{}_beach-01.jpg", word),
+                "image/jpeg",
+                serde_json::json!({"image": {"format": "jpeg", "width": 800}}),
+            ),
+            (
+                format!("{}-concert.mp4", word),
+                "video/mp4",
+                serde_json::json!({"av": {"container": "mp4", "duration": 95.5}}),
+            ),
+            (
+                format!("{} podcast.mp3", word),
+                "audio/mpeg",
+                serde_json::json!({"av": {"container": "mp3", "duration": 30}}),
+            ),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let mut metadata = metadata;
+            metadata["filename"] = serde_json::json!(filename);
+            let mut data = ctx.suffix.as_bytes().to_vec();
+            data.resize(100 * (i + 1), b'x');
+            let params = CreateMediaBlob {
+                mime: Some(mime.to_string()),
+                metadata,
+                ..common::blob(&owner, data)
+            };
+            blobs.push(create_blob(ctx, storage, params).await.id);
+        }
+        ctx.media(storage)
+            .set_tags(blobs[2], &["Sunset".to_string()], &owner)
+            .await
+            .unwrap();
+        Self { owner, blobs, word }
+    }
+
+    /// A search for `q` among the test's blobs
+    fn search(&self, q: &str) -> server::media::MediaSearch {
+        server::media::MediaSearch {
+            q: Some(format!("{} {}", self.word, q)),
+            ..Default::default()
+        }
+    }
+}
+
+fn search_ids(page: server::media::MediaSearchPage) -> Vec<uuid::Uuid> {
+    page.blobs.into_iter().map(|b| b.id).collect()
+}
+
+#[tokio::test]
+async fn test_media_search_matches_names_tags_and_metadata() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let media = SearchableMedia::new(&ctx, &storage).await;
+    let found = |q: &'static str| {
+        let service = &service;
+        let media = &media;
+        async move {
+            search_ids(
+                service
+                    .search(&media.search(q), &media.owner)
+                    .await
+                    .unwrap(),
+            )
+        }
+    };
+
+    assert_eq!(found("beach.jpg").await, vec![media.blobs[0]]);
+    assert_eq!(found("sunset").await, vec![media.blobs[2]]);
+    assert_eq!(found("mp4").await, vec![media.blobs[1]]);
+    assert_eq!(found("-beach").await.len(), 2);
+}
+
+#[tokio::test]
+async fn test_media_search_filters_by_metadata_type_and_size() {
+    use server::media::MediaSearch;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let media = SearchableMedia::new(&ctx, &storage).await;
+
+    let page = service
+        .search(
+            &MediaSearch {
+                metadata: vec!["metadata.av.duration > 60".to_string()],
+                ..media.search("")
+            },
+            &media.owner,
+        )
+        .await
+        .unwrap();
+    assert_eq!(search_ids(page), vec![media.blobs[1]]);
+    let page = service
+        .search(
+            &MediaSearch {
+                mime: Some("image/".to_string()),
+                metadata: vec!["image.format = jpeg".to_string()],
+                ..media.search("")
+            },
+            &media.owner,
+        )
+        .await
+        .unwrap();
+    assert_eq!(search_ids(page), vec![media.blobs[0]]);
+    let page = service
+        .search(
+            &MediaSearch {
+                min_size: Some(150),
+                max_size: Some(250),
+                ..media.search("")
+            },
+            &media.owner,
+        )
+        .await
+        .unwrap();
+    assert_eq!(search_ids(page), vec![media.blobs[1]]);
+    assert!(matches!(
+        service
+            .search(
+                &MediaSearch {
+                    metadata: vec!["av.duration > [1]".to_string()],
+                    ..media.search("")
+                },
+                &media.owner,
+            )
+            .await,
+        Err(WebauthnError::MediaSearch(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_media_search_pages_visit_every_match_once() {
+    use server::media::MediaSearch;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let media = SearchableMedia::new(&ctx, &storage).await;
+
+    let mut seen = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = service
+            .search(
+                &MediaSearch {
+                    limit: Some(2),
+                    cursor: cursor.take(),
+                    ..media.search("")
+                },
+                &media.owner,
+            )
+            .await
+            .unwrap();
+        cursor = page.next_cursor.clone();
+        seen.extend(search_ids(page));
+        if cursor.is_none() {
+            break;
+        }
+    }
+    seen.sort();
+    let mut expected = media.blobs.clone();
+    expected.sort();
+    assert_eq!(seen, expected);
+}
+
+#[tokio::test]
+async fn test_media_search_skips_other_users_private_blobs() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let other = ctx.user("search-o").await;
+    let storage = ctx.storage();
+    let media = SearchableMedia::new(&ctx, &storage).await;
+
+    assert!(ctx
+        .media(&storage)
+        .search(&media.search(""), &other)
+        .await
+        .unwrap()
+        .blobs
+        .is_empty());
+}
+
+/// Two blobs of the same content, from a client and of a MIME type unique
+/// to the test
+struct CountedMedia {
+    owner: User,
+    blobs: Vec<uuid::Uuid>,
+    client: String,
+    mime: String,
+}
+
+impl CountedMedia {
+    async fn new(ctx: &TestContext, storage: &MediaStorage) -> Self {
+        let owner = ctx.user("stats").await;
+        let client = format!("stats-client-{}", ctx.suffix);
+        let mime = format!("application/x-stats-{}", &ctx.suffix[..8]);
+        let mut data = ctx.suffix.as_bytes().to_vec();
+        data.resize(100, b's');
+
+        let mut blobs = Vec::new();
+        for _ in 0..2 {
+            let params = CreateMediaBlob {
+                mime: Some(mime.clone()),
+                source_client_id: Some(client.clone()),
+                ..common::blob(&owner, data.clone())
+            };
+            blobs.push(create_blob(ctx, storage, params).await.id);
+        }
+        Self {
+            owner,
+            blobs,
+            client,
+            mime,
+        }
+    }
+}
+
+// Other tests run alongside, so only this test's client, user and MIME type
+// are checked exactly
+#[tokio::test]
+async fn test_media_stats_break_down_usage() {
+    use server::media::MediaStatsQuery;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let media = CountedMedia::new(&ctx, &storage).await;
+
+    let stats = ctx
+        .media(&storage)
+        .get_stats(MediaStatsQuery {
+            days: 1,
+            top: 100_000,
+        })
+        .await
+        .unwrap();
+
+    assert!(stats.total_count >= 2);
+    assert!(stats.total_size.unwrap() >= 200);
+    assert!(stats.duplicate_savings >= 100);
+    let by_mime = stats
+        .mime_type_distribution
+        .iter()
+        .find(|m| m.mime_type.as_deref() == Some(media.mime.as_str()))
+        .unwrap();
+    assert_eq!((by_mime.count, by_mime.size), (2, 200));
+    let by_client = stats
+        .top_clients
+        .iter()
+        .find(|c| c.source_client_id.as_deref() == Some(media.client.as_str()))
+        .unwrap();
+    assert_eq!((by_client.blob_count, by_client.size), (2, 200));
+    let by_user = stats
+        .top_users
+        .iter()
+        .find(|u| u.user_id == media.owner.id)
+        .unwrap();
+    assert_eq!((by_user.blob_count, by_user.size), (2, 200));
+    assert!(stats
+        .storage
+        .iter()
+        .any(|backend| backend.backend == "filesystem" && backend.size >= 100));
+    assert_eq!(stats.growth.len(), 1);
+    assert!(stats.growth[0].blob_count >= 2);
+}
+
+#[tokio::test]
+async fn test_media_stats_totals_skip_the_breakdowns() {
+    use server::media::{render_prometheus, MediaStatsQuery};
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    CountedMedia::new(&ctx, &storage).await;
+
+    let totals = ctx
+        .media(&storage)
+        .get_stats(MediaStatsQuery::totals())
+        .await
+        .unwrap();
+    assert!(totals.top_users.is_empty() && totals.top_clients.is_empty());
+    assert!(totals.growth.is_empty());
+    assert!(
+        render_prometheus(&totals).contains("webauthn_media_stored_bytes{backend=\"filesystem\"}")
+    );
+}
+
+#[tokio::test]
+async fn test_recent_media_includes_new_blobs() {
+    use server::media::MediaRepository;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let media = CountedMedia::new(&ctx, &storage).await;
+
+    let recent = MediaRepository::new(&ctx.db)
+        .get_recent(1, Some(1000))
+        .await
+        .unwrap();
+    assert!(media
+        .blobs
+        .iter()
+        .all(|id| recent.iter().any(|b| b.id == *id)));
+}
+
+/// Blobs damaged in every way the consistency checker knows about
+struct DamagedMedia {
+    blobs: Vec<MediaBlob>,
+    orphan: std::path::PathBuf,
+    options: server::media::FsckOptions,
+}
+
+impl DamagedMedia {
+    async fn new(ctx: &TestContext, storage: &MediaStorage) -> Self {
+        use server::media::FsckOptions;
+        use std::time::Duration;
+
+        let owner = ctx.user("fsck").await;
+        let service = ctx.media(storage);
+
+        // Local paths below this directory are checked; it holds no files
+        let served_directory = format!("private/fsck-{}", ctx.suffix);
+
+        let mut blobs = Vec::new();
+        for label in ["intact", "missing", "corrupt", "truncated", "moved"] {
+            let data = format!("fsck {} {}", label, ctx.suffix).into_bytes();
+            let params = CreateMediaBlob {
+                mime: Some("text/plain".to_string()),
+                local_path: (label == "moved").then(|| format!("{}/gone", served_directory)),
+                ..common::blob(&owner, data)
+            };
+            blobs.push(create_blob(ctx, storage, params).await);
+        }
+
+        let key = |blob: &MediaBlob| {
+            let service = &service;
+            let sha256 = blob.sha256.clone();
+            async move {
+                service
+                    .find_object(&sha256)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .storage_key
+            }
+        };
+        std::fs::remove_file(key(&blobs[1]).await).unwrap();
+        let corrupt_key = key(&blobs[2]).await;
+        let mut bytes = std::fs::read(&corrupt_key).unwrap();
+        bytes[0] ^= 1;
+        std::fs::write(&corrupt_key, bytes).unwrap();
+        std::fs::write(key(&blobs[3]).await, b"short").unwrap();
+        let orphan =
+            std::path::Path::new(&ctx.config.media.storage.directory).join("ab/cd/orphan.partial");
+        std::fs::create_dir_all(orphan.parent().unwrap()).unwrap();
+        std::fs::write(&orphan, b"left behind").unwrap();
+
+        let options = FsckOptions {
+            verify_hashes: false,
+            repair: false,
+            min_orphan_age: Duration::from_secs(3600),
+            directories: vec![
+                ctx.config.media.storage.directory.clone().into(),
+                std::path::Path::new("assets").join(&served_directory),
+            ],
+        };
+        Self {
+            blobs,
+            orphan,
+            options,
+        }
+    }
+
+    /// The issues found with this test's blobs and directory
+    fn issues(
+        &self,
+        ctx: &TestContext,
+        report: &server::media::FsckReport,
+    ) -> Vec<(server::media::FsckIssueKind, bool)> {
+        use server::media::FsckIssueKind;
+
+        let root = std::fs::canonicalize(&ctx.config.media.storage.directory).unwrap();
+        let mut issues: Vec<(FsckIssueKind, bool)> = report
+            .issues
+            .iter()
+            .filter(|issue| {
+                self.blobs
+                    .iter()
+                    .any(|blob| issue.sha256.as_ref() == Some(&blob.sha256))
+                    || issue
+                        .path
+                        .as_ref()
+                        .is_some_and(|path| path.starts_with(root.to_str().unwrap()))
+                        && issue.kind == FsckIssueKind::OrphanFile
+            })
+            .map(|issue| (issue.kind, issue.repaired))
+            .collect();
+        issues.sort_by_key(|(kind, _)| *kind as u8);
+        issues
+    }
+}
+
+#[tokio::test]
+async fn test_media_fsck_without_hashing() {
+    use server::media::{run_fsck, FsckIssueKind};
+
+    let _fsck = MEDIA_FSCK.lock().await;
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let damaged = DamagedMedia::new(&ctx, &storage).await;
+
+    // The corrupt content goes unnoticed, and the orphan is too young to be
+    // reported
+    let report = run_fsck(&ctx.db, &storage, &damaged.options).await.unwrap();
+    assert_eq!(
+        damaged.issues(&ctx, &report),
+        vec![
+            (FsckIssueKind::MissingContent, false),
+            (FsckIssueKind::SizeMismatch, false),
+            (FsckIssueKind::StaleLocalPath, false),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_media_fsck_verifying_hashes_and_orphans() {
+    use server::media::{run_fsck, FsckIssueKind};
+    use std::time::Duration;
+
+    let _fsck = MEDIA_FSCK.lock().await;
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let mut damaged = DamagedMedia::new(&ctx, &storage).await;
+    damaged.options.verify_hashes = true;
+    damaged.options.min_orphan_age = Duration::ZERO;
+
+    let report = run_fsck(&ctx.db, &storage, &damaged.options).await.unwrap();
+    assert_eq!(
+        damaged.issues(&ctx, &report),
+        vec![
+            (FsckIssueKind::OrphanFile, false),
+            (FsckIssueKind::MissingContent, false),
+            (FsckIssueKind::SizeMismatch, false),
+            (FsckIssueKind::HashMismatch, false),
+            (FsckIssueKind::StaleLocalPath, false),
+        ]
+    );
+    assert!(damaged.orphan.exists());
+}
+
+#[tokio::test]
+async fn test_media_fsck_repairs_what_it_finds() {
+    use server::media::{run_fsck, FsckIssueKind};
+    use std::time::Duration;
+
+    let _fsck = MEDIA_FSCK.lock().await;
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let mut damaged = DamagedMedia::new(&ctx, &storage).await;
+    damaged.options.verify_hashes = true;
+    damaged.options.min_orphan_age = Duration::ZERO;
+    damaged.options.repair = true;
+
+    let report = run_fsck(&ctx.db, &storage, &damaged.options).await.unwrap();
+    assert_eq!(
+        damaged.issues(&ctx, &report),
+        vec![
+            (FsckIssueKind::OrphanFile, true),
+            (FsckIssueKind::MissingContent, false),
+            (FsckIssueKind::SizeMismatch, true),
+            (FsckIssueKind::HashMismatch, true),
+            (FsckIssueKind::StaleLocalPath, true),
+        ]
+    );
+    assert!(!damaged.orphan.exists());
+    let [intact, missing, corrupt, truncated, moved] = &damaged.blobs[..] else {
+        unreachable!()
+    };
+    for blob in [corrupt, truncated] {
+        assert!(service.find_blob(blob.id).await.is_err());
+        assert!(service.find_object(&blob.sha256).await.unwrap().is_none());
+    }
+    assert!(service.find_blob(intact.id).await.is_ok());
+    assert_eq!(service.find_blob(moved.id).await.unwrap().local_path, None);
+
+    // Missing content is only reported
+    assert!(service.find_blob(missing.id).await.is_ok());
+    assert!(service
+        .find_object(&missing.sha256)
+        .await
+        .unwrap()
+        .is_some());
+    let report = run_fsck(&ctx.db, &storage, &damaged.options).await.unwrap();
+    assert_eq!(
+        damaged.issues(&ctx, &report),
+        vec![(FsckIssueKind::MissingContent, false)]
+    );
+}
+
+#[tokio::test]
+async fn test_media_fsck_verifies_database_content_in_chunks() {
+    use server::media::{run_fsck, FsckIssueKind};
+
+    let _fsck = MEDIA_FSCK.lock().await;
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("fsck-db").await;
+    let storage = ctx.storage_with(MediaStorageBackend::Database);
+    let mut blobs = Vec::new();
+    for label in ["corrupt", "truncated"] {
+        // Larger than a read, so the content arrives in several chunks
+        let mut data = vec![b'x'; 1024 * 1024];
+        data.extend_from_slice(format!("fsck {} {}", label, ctx.suffix).as_bytes());
+        blobs.push(create_blob(&ctx, &storage, common::blob(&owner, data)).await);
+    }
+    sqlx::query("UPDATE media_object_data SET data = overlay(data PLACING '\\x79' FROM 1) WHERE sha256 = $1")
+        .bind(&blobs[0].sha256)
+        .execute(&ctx.pool)
+        .await
+        .unwrap();
+    sqlx::query(
+        "UPDATE media_object_data SET data = substring(data FROM 1 FOR 10) WHERE sha256 = $1",
+    )
+    .bind(&blobs[1].sha256)
+    .execute(&ctx.pool)
+    .await
+    .unwrap();
+
+    let options = server::media::FsckOptions {
+        verify_hashes: true,
+        repair: false,
+        min_orphan_age: std::time::Duration::from_secs(3600),
+        directories: Vec::new(),
+    };
+    let report = run_fsck(&ctx.db, &storage, &options).await.unwrap();
+    let kind = |blob: &MediaBlob| {
+        report
+            .issues
+            .iter()
+            .find(|issue| issue.sha256.as_ref() == Some(&blob.sha256))
+            .map(|issue| issue.kind)
+    };
+    assert_eq!(kind(&blobs[0]), Some(FsckIssueKind::HashMismatch));
+    assert_eq!(kind(&blobs[1]), Some(FsckIssueKind::SizeMismatch));
+}
+
+#[tokio::test]
+async fn test_media_fsck_fails_without_the_storage_root() {
+    use server::media::{run_fsck, BlobStorageError};
+
+    let _fsck = MEDIA_FSCK.lock().await;
+    let Some(mut ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let mut damaged = DamagedMedia::new(&ctx, &storage).await;
+    damaged.options.repair = true;
+
+    // As if the media directory were not mounted
+    let unmounted = ctx.directory("media-unmounted").join("media");
+    ctx.config.media.storage.directory = unmounted.to_string_lossy().into_owned();
+    let result = run_fsck(&ctx.db, &ctx.storage(), &damaged.options).await;
+    assert!(matches!(
+        result,
+        Err(WebauthnError::MediaStorage(BlobStorageError::Unavailable(
+            _
+        )))
+    ));
+
+    let service = ctx.media(&storage);
+    for blob in &damaged.blobs {
+        assert!(service.find_blob(blob.id).await.is_ok());
+    }
+    assert!(damaged.orphan.exists());
+}
+
+/// Six blobs of a MIME type unique to the test, so retention policies leave
+/// other tests alone
+async fn retained_blobs(ctx: &TestContext, storage: &MediaStorage, owner: &User) -> Vec<MediaBlob> {
+    let mime = format!("application/x-trash-{}", &ctx.suffix[..8]);
+    let mut blobs = Vec::new();
+    for tag in b'a'..=b'f' {
+        let mut data = ctx.suffix.as_bytes().to_vec();
+        data.push(tag);
+        let params = CreateMediaBlob {
+            mime: Some(mime.clone()),
+            ..common::blob(owner, data)
+        };
+        blobs.push(create_blob(ctx, storage, params).await);
+    }
+    blobs
+}
+
+async fn blob_exists(ctx: &TestContext, id: uuid::Uuid) -> bool {
+    sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM media_blobs WHERE id = $1)")
+        .bind(id)
+        .fetch_one(&ctx.pool)
+        .await
+        .unwrap()
+}
+
+/// Age blobs as if they were created `days` ago
+async fn age_blobs(ctx: &TestContext, ids: &[uuid::Uuid], days: i32) {
+    sqlx::query(
+        "UPDATE media_blobs SET created_at = now() - make_interval(days => $2) WHERE id = ANY($1)",
+    )
+    .bind(ids)
+    .bind(days)
+    .execute(&ctx.pool)
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_trashed_blobs_are_restored_by_their_owner() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("trash").await;
+    let other = ctx.user("trash-o").await;
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let media = &ctx.config.media;
+    let id = retained_blobs(&ctx, &storage, &owner).await[0].id;
+
+    // Trashed blobs disappear
+    assert!(service.trash_blob(id, &other).await.is_err());
+    assert!(service.trash_blob(id, &owner).await.unwrap());
+    assert!(service.get_blob(id, false, &owner).await.is_err());
+    let listed = service
+        .list_blobs(MediaBlobQuery::default(), &owner)
+        .await
+        .unwrap();
+    assert!(!listed.iter().any(|blob| blob.id == id));
+
+    let trash = service.list_trash(&owner, media, None, None).await.unwrap();
+    assert_eq!(trash.len(), 1);
+    assert_eq!(trash[0].blob.id, id);
+    assert_eq!(trash[0].deleted_by, Some(owner.id));
+    assert_eq!(
+        trash[0].purge_at - trash[0].deleted_at,
+        time::Duration::days(30)
+    );
+    assert!(service
+        .list_trash(&other, media, None, None)
+        .await
+        .unwrap()
+        .is_empty());
+
+    assert!(service.restore_blob(id, &other, media).await.is_err());
+    let restored = service.restore_blob(id, &owner, media).await.unwrap();
+    assert_eq!(restored.id, id);
+    assert!(service.get_blob(id, false, &owner).await.is_ok());
+    assert!(service.restore_blob(id, &owner, media).await.is_err());
+}
+
+#[tokio::test]
+async fn test_purging_trash_deletes_blobs_for_good() {
+    use server::media::{MediaAuditAction, MediaAuditReason};
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("purge").await;
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let media = &ctx.config.media;
+    let blobs = retained_blobs(&ctx, &storage, &owner).await;
+    let (first, second) = (&blobs[0], &blobs[1]);
+
+    assert!(service.trash_blob(first.id, &owner).await.unwrap());
+    service.restore_blob(first.id, &owner, media).await.unwrap();
+    assert!(service.trash_blob(first.id, &owner).await.unwrap());
+    assert!(service.trash_blob(second.id, &owner).await.unwrap());
+
+    // With its content
+    assert!(service
+        .purge_trashed_blob(first.id, &owner, media)
+        .await
+        .unwrap());
+    assert!(!blob_exists(&ctx, first.id).await);
+    assert!(service.find_object(&first.sha256).await.unwrap().is_none());
+    assert_eq!(service.empty_trash(&owner, media).await.unwrap(), 1);
+    assert!(!blob_exists(&ctx, second.id).await);
+
+    let audit = service.list_audit(Some(first.id), 10).await.unwrap();
+    assert_eq!(
+        audit.iter().map(|entry| entry.action).collect::<Vec<_>>(),
+        vec![
+            MediaAuditAction::Purged,
+            MediaAuditAction::Trashed,
+            MediaAuditAction::Restored,
+            MediaAuditAction::Trashed,
+        ]
+    );
+    assert!(audit
+        .iter()
+        .all(|entry| entry.reason == MediaAuditReason::User
+            && entry.actor_user_id == Some(owner.id)
+            && entry.sha256 == first.sha256));
+}
+
+#[tokio::test]
+async fn test_trash_past_its_window_is_purged() {
+    use server::media::{run_retention, MediaAuditAction, MediaAuditReason};
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("expired").await;
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let media = &ctx.config.media;
+    let id = retained_blobs(&ctx, &storage, &owner).await[0].id;
+
+    // Moved back in time rather than shrinking the window, which would purge
+    // the trash of every test
+    assert!(service.trash_blob(id, &owner).await.unwrap());
+    sqlx::query("UPDATE media_blobs SET deleted_at = now() - interval '31 days' WHERE id = $1")
+        .bind(id)
+        .execute(&ctx.pool)
+        .await
+        .unwrap();
+
+    // It can no longer be restored
+    assert!(service
+        .list_trash(&owner, media, None, None)
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(service.restore_blob(id, &owner, media).await.is_err());
+
+    let report = run_retention(&ctx.db, &storage, media, None).await.unwrap();
+    assert!(report.trash_purged >= 1);
+    assert!(!blob_exists(&ctx, id).await);
+    let audit = service.list_audit(Some(id), 10).await.unwrap();
+    assert_eq!(
+        (audit[0].action, audit[0].reason, audit[0].actor_user_id),
+        (
+            MediaAuditAction::Purged,
+            MediaAuditReason::TrashExpired,
+            None
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_retention_keeps_blobs_for_their_longest_collection_policy() {
+    use server::media::{run_retention, MediaAuditAction, MediaAuditReason};
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("retain").await;
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let ids: Vec<_> = retained_blobs(&ctx, &storage, &owner)
+        .await
+        .iter()
+        .map(|blob| blob.id)
+        .collect();
+
+    assert!(matches!(
+        service
+            .create_collection("Broken", None, Some(-1), &owner)
+            .await,
+        Err(WebauthnError::BadRequest)
+    ));
+    let short = service
+        .create_collection("Short", None, Some(7), &owner)
+        .await
+        .unwrap();
+    assert_eq!(short.retention_days, Some(7));
+    let long = service
+        .create_collection("Long", None, Some(30), &owner)
+        .await
+        .unwrap();
+    service
+        .add_to_collection(short.id, &ids[..2], &owner)
+        .await
+        .unwrap();
+    service
+        .add_to_collection(long.id, &ids[1..2], &owner)
+        .await
+        .unwrap();
+    age_blobs(&ctx, &ids[..3], 10).await;
+
+    let report = run_retention(&ctx.db, &storage, &ctx.config.media, None)
+        .await
+        .unwrap();
+    assert!(report.expired_purged >= 1);
+    assert!(!blob_exists(&ctx, ids[0]).await);
+    assert!(blob_exists(&ctx, ids[1]).await);
+    assert!(blob_exists(&ctx, ids[2]).await);
+    let audit = service.list_audit(Some(ids[0]), 10).await.unwrap();
+    assert_eq!(
+        (audit[0].action, audit[0].reason),
+        (MediaAuditAction::Purged, MediaAuditReason::Retention)
+    );
+}
+
+#[tokio::test]
+async fn test_retention_applies_mime_type_rules() {
+    use server::config::MediaRetentionRule;
+    use server::media::run_retention;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("mime").await;
+    let storage = ctx.storage();
+    let blobs = retained_blobs(&ctx, &storage, &owner).await;
+    let service = ctx.media(&storage);
+    let ids: Vec<_> = blobs.iter().map(|blob| blob.id).collect();
+    let long = service
+        .create_collection("Long", None, Some(30), &owner)
+        .await
+        .unwrap();
+    service
+        .add_to_collection(long.id, &ids[..1], &owner)
+        .await
+        .unwrap();
+    age_blobs(&ctx, &ids[..2], 10).await;
+
+    // Unless a collection keeps the blob longer
+    let mut media = ctx.config.media.clone();
+    media.retention.mime_types = vec![MediaRetentionRule {
+        pattern: blobs[0].mime.as_deref().unwrap().to_uppercase(),
+        days: 5,
+    }];
+    run_retention(&ctx.db, &storage, &media, None)
+        .await
+        .unwrap();
+    assert!(blob_exists(&ctx, ids[0]).await);
+    assert!(!blob_exists(&ctx, ids[1]).await);
+}
+
+#[tokio::test]
+async fn test_retention_follows_collection_policy_changes() {
+    use server::media::run_retention;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("change").await;
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let id = retained_blobs(&ctx, &storage, &owner).await[0].id;
+    let short = service
+        .create_collection("Short", None, Some(7), &owner)
+        .await
+        .unwrap();
+    let long = service
+        .create_collection("Long", None, Some(30), &owner)
+        .await
+        .unwrap();
+    service
+        .add_to_collection(short.id, &[id], &owner)
+        .await
+        .unwrap();
+    service
+        .add_to_collection(long.id, &[id], &owner)
+        .await
+        .unwrap();
+    age_blobs(&ctx, &[id], 10).await;
+
+    run_retention(&ctx.db, &storage, &ctx.config.media, None)
+        .await
+        .unwrap();
+    assert!(blob_exists(&ctx, id).await);
+
+    // A retention of zero clears the collection's policy
+    let long = service
+        .update_collection(long.id, None, None, Some(0), &owner)
+        .await
+        .unwrap();
+    assert_eq!(long.retention_days, None);
+    run_retention(&ctx.db, &storage, &ctx.config.media, None)
+        .await
+        .unwrap();
+    assert!(!blob_exists(&ctx, id).await);
+}
+
+/// A plain text blob of `owner`'s, unique to the test
+fn text_blob(ctx: &TestContext, owner: &User) -> CreateMediaBlob {
+    CreateMediaBlob {
+        mime: Some("text/plain".to_string()),
+        ..common::blob(owner, format!("events {}", ctx.suffix).into_bytes())
+    }
+}
+
+#[tokio::test]
+async fn test_media_created_events_are_visible_to_viewers_only() {
+    use server::media::{MediaEventKind, MediaEvents};
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("events").await;
+    let other = ctx.user("events-o").await;
+    let storage = ctx.storage();
+    let events = MediaEvents::default();
+    let service = ctx.media(&storage).with_events(&events);
+    let mut received = events.subscribe();
+
+    let blob = service
+        .create_blob(text_blob(&ctx, &owner), &ctx.config.media)
+        .await
+        .unwrap();
+    let event = received.try_recv().unwrap();
+    assert_eq!(event.kind, MediaEventKind::Created);
+    assert_eq!(event.blob.id, blob.id);
+    assert!(event.visible_to(&owner));
+    assert!(!event.visible_to(&other));
+}
+
+#[tokio::test]
+async fn test_media_sharing_makes_events_visible() {
+    use server::media::{MediaEventKind, MediaEvents};
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("events").await;
+    let other = ctx.user("events-o").await;
+    let storage = ctx.storage();
+    let blob = create_blob(&ctx, &storage, text_blob(&ctx, &owner)).await;
+    let events = MediaEvents::default();
+    let service = ctx.media(&storage).with_events(&events);
+    let mut received = events.subscribe();
+
+    service
+        .set_visibility(blob.id, MediaVisibility::Shared, &owner)
+        .await
+        .unwrap();
+    let event = received.try_recv().unwrap();
+    assert_eq!(event.kind, MediaEventKind::Updated);
+    assert!(!event.visible_to(&other));
+    service
+        .share_blob(blob.id, &[other.id], &owner)
+        .await
+        .unwrap();
+    let event = received.try_recv().unwrap();
+    assert_eq!(event.shared_with, vec![other.id]);
+    assert!(event.visible_to(&other));
+}
+
+#[tokio::test]
+async fn test_media_events_carry_collections_and_tags() {
+    use server::media::{MediaEvents, MediaSubscription};
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("events").await;
+    let storage = ctx.storage();
+    let blob = create_blob(&ctx, &storage, text_blob(&ctx, &owner)).await;
+    let events = MediaEvents::default();
+    let service = ctx.media(&storage).with_events(&events);
+    let collection = service
+        .create_collection("Events", None, None, &owner)
+        .await
+        .unwrap();
+    service
+        .add_to_collection(collection.id, &[blob.id], &owner)
+        .await
+        .unwrap();
+    let mut received = events.subscribe();
+
+    service
+        .set_tags(blob.id, &["news".to_string()], &owner)
+        .await
+        .unwrap();
+    let event = received.try_recv().unwrap();
+    assert_eq!(event.blob.tags, vec!["news".to_string()]);
+    assert_eq!(event.collection_ids, vec![collection.id]);
+    assert!(MediaSubscription::new(vec![collection.id], Vec::new()).matches(&event));
+    assert!(!MediaSubscription::new(Vec::new(), vec!["image/".to_string()]).matches(&event));
+}
+
+#[tokio::test]
+async fn test_media_trash_events() {
+    use server::media::{MediaEventKind, MediaEvents};
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("events").await;
+    let storage = ctx.storage();
+    let blob = create_blob(&ctx, &storage, text_blob(&ctx, &owner)).await;
+    let events = MediaEvents::default();
+    let service = ctx.media(&storage).with_events(&events);
+    let mut received = events.subscribe();
+
+    // Trashing deletes, restoring creates and purging the trash is silent
+    service.trash_blob(blob.id, &owner).await.unwrap();
+    assert_eq!(received.try_recv().unwrap().kind, MediaEventKind::Deleted);
+    service
+        .restore_blob(blob.id, &owner, &ctx.config.media)
+        .await
+        .unwrap();
+    assert_eq!(received.try_recv().unwrap().kind, MediaEventKind::Created);
+    service.trash_blob(blob.id, &owner).await.unwrap();
+    assert_eq!(received.try_recv().unwrap().kind, MediaEventKind::Deleted);
+    service
+        .purge_trashed_blob(blob.id, &owner, &ctx.config.media)
+        .await
+        .unwrap();
+    assert!(received.try_recv().is_err());
+}
+
+/// Three blobs of `owner`'s for members, two of them sharing their content
+struct ExportedMedia {
+    owner: User,
+    blobs: Vec<MediaBlob>,
+}
+
+impl ExportedMedia {
+    async fn new(ctx: &TestContext, storage: &MediaStorage) -> Self {
+        let owner = ctx.user("export").await;
+        let mut blobs = Vec::new();
+        for (tag, metadata) in [
+            (
+                b'a',
+                serde_json::json!({"title": "first", "image": {"width": 1}}),
+            ),
+            (b'b', serde_json::json!({"title": "second"})),
+            (b'a', serde_json::json!({})),
+        ] {
+            let params = CreateMediaBlob {
+                visibility: MediaVisibility::Members,
+                metadata,
+                ..common::blob(&owner, Self::content(ctx, tag))
+            };
+            blobs.push(create_blob(ctx, storage, params).await);
+        }
+        ctx.media(storage)
+            .set_tags(blobs[0].id, &["export".to_string()], &owner)
+            .await
+            .unwrap();
+        Self { owner, blobs }
+    }
+
+    fn content(ctx: &TestContext, tag: u8) -> Vec<u8> {
+        [ctx.suffix.as_bytes(), &[tag]].concat()
+    }
+}
+
+async fn export(ctx: &TestContext, storage: &MediaStorage, query: MediaBlobQuery) -> Vec<u8> {
+    let mut archive = Vec::new();
+    server::media::export_media(&ctx.db, storage, query, &mut archive)
+        .await
+        .unwrap();
+    archive
+}
+
+async fn import(
+    ctx: &TestContext,
+    storage: &MediaStorage,
+    archive: &[u8],
+    options: &server::media::ImportOptions,
+) -> Result<server::media::ImportReport, server::media::ArchiveError> {
+    server::media::import_media(&ctx.db, storage, &ctx.config.media, archive, options).await
+}
+
+/// Delete every blob of `user`'s holding `sha256`
+async fn delete_content(ctx: &TestContext, storage: &MediaStorage, sha256: &str, user: &User) {
+    let service = ctx.media(storage);
+    let found = server::media::MediaRepository::new(&ctx.db)
+        .query(MediaBlobQuery {
+            sha256: Some(sha256.to_string()),
+            owner_user_id: Some(user.id),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    for blob in found {
+        assert!(service.delete_blob(blob.id, user).await.unwrap());
+    }
+}
+
+/// The content of `owner`'s only blob holding `sha256`
+async fn owned_content(
+    ctx: &TestContext,
+    storage: &MediaStorage,
+    sha256: &str,
+    owner: &User,
+) -> Vec<u8> {
+    let found = server::media::MediaRepository::new(&ctx.db)
+        .query(MediaBlobQuery {
+            sha256: Some(sha256.to_string()),
+            owner_user_id: Some(owner.id),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    ctx.media(storage)
+        .get_blob(found[0].id, true, owner)
+        .await
+        .unwrap()
+        .data
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_media_export_counts_blobs_and_contents() {
+    use server::media::export_media;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let media = ExportedMedia::new(&ctx, &storage).await;
+
+    let report = export_media(
+        &ctx.db,
+        &storage,
+        MediaBlobQuery {
+            owner_user_id: Some(media.owner.id),
+            ..Default::default()
+        },
+        &mut Vec::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!((report.blobs, report.contents), (3, 2));
+}
+
+#[tokio::test]
+async fn test_media_export_streams_content_from_the_database() {
+    use std::io::Read;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("export-db").await;
+    let storage = ctx.storage_with(MediaStorageBackend::Database);
+    // Larger than a read, so the content arrives in several chunks
+    let mut data = vec![b'x'; 1024 * 1024];
+    data.extend_from_slice(ctx.suffix.as_bytes());
+    let blob = create_blob(&ctx, &storage, common::blob(&owner, data.clone())).await;
+
+    let archive = export(
+        &ctx,
+        &storage,
+        MediaBlobQuery {
+            owner_user_id: Some(owner.id),
+            ..Default::default()
+        },
+    )
+    .await;
+    let mut entries = tar::Archive::new(archive.as_slice());
+    let mut entry = entries
+        .entries()
+        .unwrap()
+        .map(Result::unwrap)
+        .find(|entry| entry.path().unwrap().ends_with(&blob.sha256))
+        .unwrap();
+    let mut exported = Vec::new();
+    entry.read_to_end(&mut exported).unwrap();
+    assert_eq!(exported, data);
+}
+
+#[tokio::test]
+async fn test_media_import_reuses_stored_content_for_a_new_owner() {
+    use server::media::ImportOptions;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let target = ctx.user("import").await;
+    let storage = ctx.storage();
+    let media = ExportedMedia::new(&ctx, &storage).await;
+    let archive = export(
+        &ctx,
+        &storage,
+        MediaBlobQuery {
+            owner_user_id: Some(media.owner.id),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    let options = ImportOptions {
+        owner_user_id: Some(target.id),
+    };
+    let report = import(&ctx, &storage, &archive, &options).await.unwrap();
+    assert_eq!(
+        (report.imported, report.skipped, report.contents_stored),
+        (3, 0, 0)
+    );
+    assert!(report.failed.is_empty());
+
+    let imported = ctx
+        .media(&storage)
+        .list_blobs(
+            MediaBlobQuery {
+                owner_user_id: Some(target.id),
+                tags: vec!["export".to_string()],
+                ..Default::default()
+            },
+            &target,
+        )
+        .await
+        .unwrap();
+    assert_eq!(imported.len(), 1);
+    assert_eq!(imported[0].sha256, media.blobs[0].sha256);
+    assert_eq!(imported[0].visibility, MediaVisibility::Members);
+    assert_eq!(imported[0].metadata, serde_json::json!({"title": "first"}));
+}
+
+#[tokio::test]
+async fn test_media_import_skips_blobs_the_owner_has() {
+    use server::media::ImportOptions;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let target = ctx.user("import").await;
+    let storage = ctx.storage();
+    let media = ExportedMedia::new(&ctx, &storage).await;
+    let archive = export(
+        &ctx,
+        &storage,
+        MediaBlobQuery {
+            owner_user_id: Some(media.owner.id),
+            ..Default::default()
+        },
+    )
+    .await;
+    let options = ImportOptions {
+        owner_user_id: Some(target.id),
+    };
+    import(&ctx, &storage, &archive, &options).await.unwrap();
+
+    let report = import(&ctx, &storage, &archive, &options).await.unwrap();
+    assert_eq!((report.imported, report.skipped), (0, 3));
+}
+
+#[tokio::test]
+async fn test_media_import_verifies_and_stores_missing_content() {
+    use server::media::ImportOptions;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let media = ExportedMedia::new(&ctx, &storage).await;
+    let sha256 = &media.blobs[1].sha256;
+    let archive = export(
+        &ctx,
+        &storage,
+        MediaBlobQuery {
+            sha256: Some(sha256.clone()),
+            owner_user_id: Some(media.owner.id),
+            ..Default::default()
+        },
+    )
+    .await;
+    delete_content(&ctx, &storage, sha256, &media.owner).await;
+    assert!(service.find_object(sha256).await.unwrap().is_none());
+
+    let content = ExportedMedia::content(&ctx, b'b');
+    let mut corrupt = archive.clone();
+    let at = corrupt
+        .windows(content.len())
+        .position(|window| window == content.as_slice())
+        .unwrap();
+    corrupt[at] ^= 1;
+    let report = import(&ctx, &storage, &corrupt, &ImportOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(report.imported, 0);
+    assert_eq!(report.failed.len(), 1);
+    assert!(service.find_object(sha256).await.unwrap().is_none());
+
+    let report = import(&ctx, &storage, &archive, &ImportOptions::default())
+        .await
+        .unwrap();
+    assert_eq!((report.imported, report.contents_stored), (1, 1));
+    assert_eq!(
+        owned_content(&ctx, &storage, sha256, &media.owner).await,
+        content
+    );
+}
+
+#[tokio::test]
+async fn test_media_import_stores_shared_content_when_a_blob_is_refused() {
+    use server::media::ImportOptions;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let limited = ctx.user("limited").await;
+    let storage = ctx.storage();
+    let service = ctx.media(&storage);
+    let media = ExportedMedia::new(&ctx, &storage).await;
+    let sha256 = &media.blobs[1].sha256;
+    let shared = create_blob(
+        &ctx,
+        &storage,
+        CreateMediaBlob {
+            sha256: sha256.clone(),
+            data: None,
+            ..common::blob(&limited, Vec::new())
+        },
+    )
+    .await;
+    let archive = export(
+        &ctx,
+        &storage,
+        MediaBlobQuery {
+            sha256: Some(sha256.clone()),
+            ..Default::default()
+        },
+    )
+    .await;
+    delete_content(&ctx, &storage, sha256, &limited).await;
+    delete_content(&ctx, &storage, sha256, &media.owner).await;
+    service
+        .set_quota(limited.id, Some(1), &ctx.config.media.quotas)
+        .await
+        .unwrap();
+
+    // The newest blob, refused by its owner's quota, is listed first
+    let report = import(&ctx, &storage, &archive, &ImportOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(report.failed[0].id, shared.id);
+    assert_eq!((report.imported, report.contents_stored), (1, 1));
+    assert_eq!(
+        owned_content(&ctx, &storage, sha256, &media.owner).await,
+        ExportedMedia::content(&ctx, b'b')
+    );
+}
+
+#[tokio::test]
+async fn test_media_import_stops_at_unknown_owners() {
+    use server::media::{ArchiveError, ImportOptions};
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let media = ExportedMedia::new(&ctx, &storage).await;
+    let archive = export(
+        &ctx,
+        &storage,
+        MediaBlobQuery {
+            owner_user_id: Some(media.owner.id),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // Owners are matched by username
+    sqlx::query("UPDATE users SET username = username || '-gone' WHERE id = $1")
+        .bind(media.owner.id)
+        .execute(&ctx.pool)
+        .await
+        .unwrap();
+    assert!(matches!(
+        import(&ctx, &storage, &archive, &ImportOptions::default()).await,
+        Err(ArchiveError::UnknownOwners(names)) if names.len() == 1
+    ));
+}
+
+fn rooms<'a>(ctx: &'a TestContext, storage: &'a MediaStorage) -> server::rooms::RoomService<'a> {
+    server::rooms::RoomService::new(
+        server::rooms::RoomRepository::new(&ctx.db),
+        ctx.media(storage),
+    )
+}
+
+/// A private room of `owner`'s with `member` added, and `outsider` left out
+struct PrivateRoom {
+    owner: User,
+    member: User,
+    outsider: User,
+    room_id: uuid::Uuid,
+}
+
+impl PrivateRoom {
+    async fn new(ctx: &TestContext, storage: &MediaStorage) -> Self {
+        use server::rooms::{RoomRole, RoomVisibility};
+
+        let owner = ctx.user("rooms").await;
+        let member = ctx.user("rooms-m").await;
+        let outsider = ctx.user("rooms-o").await;
+        let service = rooms(ctx, storage);
+        let room = service
+            .create(
+                &format!("private-{}", &ctx.suffix[..8]),
+                Some("plans"),
+                RoomVisibility::Private,
+                &owner,
+            )
+            .await
+            .unwrap();
+        service
+            .add_member(room.id, member.id, RoomRole::Member, &owner)
+            .await
+            .unwrap();
+        Self {
+            owner,
+            member,
+            outsider,
+            room_id: room.id,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_private_rooms_are_hidden_from_outsiders() {
+    use server::rooms::{RoomRole, RoomVisibility};
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let owner = ctx.user("rooms").await;
+    let other = ctx.user("rooms-o").await;
+    let storage = ctx.storage();
+    let service = rooms(&ctx, &storage);
+
+    let name = format!("private-{}", &ctx.suffix[..8]);
+    let room = service
+        .create(&name, Some("plans"), RoomVisibility::Private, &owner)
+        .await
+        .unwrap();
+    assert_eq!(room.role, Some(RoomRole::Owner));
+    assert!(matches!(
+        service
+            .create(&name, None, RoomVisibility::Public, &other)
+            .await,
+        Err(WebauthnError::BadRequest)
+    ));
+    assert!(matches!(
+        service.join(room.id, &other).await,
+        Err(WebauthnError::UserNotFound)
+    ));
+    assert!(!service
+        .list(&other)
+        .await
+        .unwrap()
+        .iter()
+        .any(|listed| listed.id == room.id));
+}
+
+#[tokio::test]
+async fn test_room_members_are_added_by_the_owner() {
+    use server::rooms::RoomRole;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = rooms(&ctx, &storage);
+    let room = PrivateRoom::new(&ctx, &storage).await;
+
+    // Joining after being added changes nothing
+    let (_, room_member, newly) = service.join(room.room_id, &room.member).await.unwrap();
+    assert!(!newly);
+    assert_eq!(room_member.role, RoomRole::Member);
+    assert!(matches!(
+        service
+            .add_member(
+                room.room_id,
+                room.outsider.id,
+                RoomRole::Member,
+                &room.member
+            )
+            .await,
+        Err(WebauthnError::Forbidden)
+    ));
+    assert!(matches!(
+        service.leave(room.room_id, &room.owner).await,
+        Err(WebauthnError::BadRequest)
+    ));
+}
+
+#[tokio::test]
+async fn test_room_blobs_are_attached_for_readers_who_see_them() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = rooms(&ctx, &storage);
+    let room = PrivateRoom::new(&ctx, &storage).await;
+    let blob = create_blob(&ctx, &storage, text_blob(&ctx, &room.owner)).await;
+
+    assert!(matches!(
+        service
+            .post(room.room_id, Some("mine"), Some(blob.id), &room.member)
+            .await,
+        Err(WebauthnError::UserNotFound)
+    ));
+    let posted = service
+        .post(room.room_id, Some("  look  "), Some(blob.id), &room.owner)
+        .await
+        .unwrap();
+    assert_eq!(posted.message.body.as_deref(), Some("look"));
+    assert!(posted
+        .message
+        .for_reader(&posted.blob_shared_with, &room.owner)
+        .blob
+        .is_some());
+    assert!(posted
+        .message
+        .for_reader(&posted.blob_shared_with, &room.member)
+        .blob
+        .is_none());
+
+    let history = service
+        .history(room.room_id, None, None, &room.member)
+        .await
+        .unwrap();
+    assert_eq!(history.messages[0].blob_id, Some(blob.id));
+    assert!(history.messages[0].blob.is_none());
+}
+
+#[tokio::test]
+async fn test_room_history_pages_backwards_from_a_cursor() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = rooms(&ctx, &storage);
+    let room = PrivateRoom::new(&ctx, &storage).await;
+    for n in 0..4 {
+        service
+            .post(
+                room.room_id,
+                Some(&format!("hello {}", n)),
+                None,
+                &room.member,
+            )
+            .await
+            .unwrap();
+    }
+
+    // Pages run oldest first
+    let history = service
+        .history(room.room_id, None, Some(2), &room.member)
+        .await
+        .unwrap();
+    assert!(history.has_more);
+    let bodies: Vec<_> = history
+        .messages
+        .iter()
+        .map(|message| message.body.as_deref().unwrap())
+        .collect();
+    assert_eq!(bodies, vec!["hello 2", "hello 3"]);
+    let older = service
+        .history(
+            room.room_id,
+            Some(history.messages[0].id),
+            None,
+            &room.member,
+        )
+        .await
+        .unwrap();
+    assert!(!older.has_more);
+    assert_eq!(older.messages.len(), 2);
+    assert!(matches!(
+        service
+            .history(room.room_id, None, None, &room.outsider)
+            .await,
+        Err(WebauthnError::UserNotFound)
+    ));
+}
+
+#[tokio::test]
+async fn test_rooms_are_deleted_by_their_owner_only() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = rooms(&ctx, &storage);
+    let room = PrivateRoom::new(&ctx, &storage).await;
+
+    assert!(service.leave(room.room_id, &room.member).await.unwrap());
+    assert!(matches!(
+        service.delete(room.room_id, &room.member).await,
+        Err(WebauthnError::UserNotFound)
+    ));
+    service.delete(room.room_id, &room.owner).await.unwrap();
+    assert!(service.get(room.room_id, &room.owner).await.is_err());
+}
+
+fn boards<'a>(ctx: &'a TestContext, storage: &'a MediaStorage) -> server::boards::BoardService<'a> {
+    server::boards::BoardService::new(
+        server::boards::BoardRepository::new(&ctx.db),
+        rooms(ctx, storage),
+    )
+}
+
+/// A stroke drawn by `room.member` on a new board, then recoloured by
+/// `room.owner`
+struct DrawnBoard {
+    board_id: uuid::Uuid,
+    element_id: uuid::Uuid,
+    add: server::boards::BoardOperation,
+}
+
+impl DrawnBoard {
+    async fn new(ctx: &TestContext, storage: &MediaStorage, room: &PrivateRoom) -> Self {
+        use server::boards::{BoardElement, BoardOperation, ElementKind};
+
+        let service = boards(ctx, storage);
+        let board = service
+            .create(room.room_id, "sketch", &room.member)
+            .await
+            .unwrap();
+        let element_id = uuid::Uuid::new_v4();
+        let add = BoardOperation::Add {
+            element: BoardElement {
+                id: element_id,
+                kind: ElementKind::Stroke,
+                props: serde_json::json!({ "points": [[0, 0], [5, 5]], "color": "#000" })
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+            },
+        };
+        service
+            .apply(board.id, Some("m-1"), &add, &room.member)
+            .await
+            .unwrap();
+        let recolor = BoardOperation::Update {
+            id: element_id,
+            props: serde_json::json!({ "color": "#f00" })
+                .as_object()
+                .unwrap()
+                .clone(),
+        };
+        service
+            .apply(board.id, Some("o-1"), &recolor, &room.owner)
+            .await
+            .unwrap();
+        Self {
+            board_id: board.id,
+            element_id,
+            add,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_boards_are_for_room_members() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = boards(&ctx, &storage);
+    let room = PrivateRoom::new(&ctx, &storage).await;
+
+    let board = service
+        .create(room.room_id, " sketch ", &room.member)
+        .await
+        .unwrap();
+    assert_eq!(board.name, "sketch");
+    assert_eq!(board.seq, 0);
+    assert!(matches!(
+        service.create(room.room_id, "sketch", &room.owner).await,
+        Err(WebauthnError::BadRequest)
+    ));
+    assert!(matches!(
+        service.open(board.id, None, &room.outsider).await,
+        Err(WebauthnError::UserNotFound)
+    ));
+}
+
+#[tokio::test]
+async fn test_board_operations_are_numbered_and_applied_once() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = boards(&ctx, &storage);
+    let room = PrivateRoom::new(&ctx, &storage).await;
+    let drawn = DrawnBoard::new(&ctx, &storage, &room).await;
+
+    // Resent operations are answered with the one already applied
+    let (again, applied) = service
+        .apply(drawn.board_id, Some("m-1"), &drawn.add, &room.member)
+        .await
+        .unwrap();
+    assert!(!applied);
+    assert_eq!(again.seq, 1);
+    let replay = service
+        .open(drawn.board_id, None, &room.owner)
+        .await
+        .unwrap();
+    assert_eq!(replay.board.seq, 2);
+    assert_eq!(
+        replay.ops.iter().map(|op| op.seq).collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+}
+
+#[tokio::test]
+async fn test_opening_a_board_replays_its_log() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = boards(&ctx, &storage);
+    let room = PrivateRoom::new(&ctx, &storage).await;
+    let drawn = DrawnBoard::new(&ctx, &storage, &room).await;
+
+    // Onto an empty drawing
+    let replay = service
+        .open(drawn.board_id, None, &room.owner)
+        .await
+        .unwrap();
+    assert_eq!(replay.snapshot.unwrap().seq, 0);
+    assert_eq!(replay.ops.len(), 2);
+}
+
+#[tokio::test]
+async fn test_opening_a_board_starts_from_its_snapshot() {
+    use server::boards::BoardOperation;
+
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = boards(&ctx, &storage);
+    let room = PrivateRoom::new(&ctx, &storage).await;
+    let drawn = DrawnBoard::new(&ctx, &storage, &room).await;
+
+    let snapshot = service.snapshot(drawn.board_id).await.unwrap();
+    assert_eq!(snapshot.seq, 2);
+    assert_eq!(snapshot.elements[0].props["color"], "#f00");
+    service
+        .apply(
+            drawn.board_id,
+            None,
+            &BoardOperation::Delete {
+                id: drawn.element_id,
+            },
+            &room.member,
+        )
+        .await
+        .unwrap();
+    let replay = service
+        .open(drawn.board_id, None, &room.member)
+        .await
+        .unwrap();
+    assert_eq!(replay.snapshot.as_ref().unwrap(), &snapshot);
+    assert_eq!(replay.ops.len(), 1);
+
+    // Reopening catches up from what the client has, unless the snapshot
+    // has moved past it
+    let replay = service
+        .open(drawn.board_id, Some(2), &room.member)
+        .await
+        .unwrap();
+    assert!(replay.snapshot.is_none());
+    assert_eq!(replay.ops[0].seq, 3);
+    let replay = service
+        .open(drawn.board_id, Some(1), &room.member)
+        .await
+        .unwrap();
+    assert_eq!(replay.snapshot.unwrap().seq, 2);
+}
+
+#[tokio::test]
+async fn test_boards_are_deleted_by_their_creator_or_moderators() {
+    let Some(ctx) = TestContext::new().await else {
+        return;
+    };
+    let storage = ctx.storage();
+    let service = boards(&ctx, &storage);
+    let room = PrivateRoom::new(&ctx, &storage).await;
+    let drawn = DrawnBoard::new(&ctx, &storage, &room).await;
+
+    let other = service
+        .create(room.room_id, "plans", &room.owner)
+        .await
+        .unwrap();
+    assert!(matches!(
+        service.delete(other.id, &room.member).await,
+        Err(WebauthnError::Forbidden)
+    ));
+    service.delete(drawn.board_id, &room.member).await.unwrap();
+    service.delete(other.id, &room.owner).await.unwrap();
+}